@@ -1,9 +1,49 @@
 DROP TABLE access_tokens;
 DROP TABLE account_data;
+DROP TABLE broadcasts;
+DROP TABLE default_push_rule_settings;
+DROP TABLE delayed_events;
+DROP TABLE device_keys;
+DROP TABLE one_time_keys;
+DROP TABLE device_list_changes;
+DROP TABLE devices;
+DROP TABLE event_expirations;
 DROP TABLE events;
+DROP TABLE filters;
+DROP TABLE lazy_loaded_members;
+DROP TABLE media;
 DROP TABLE room_account_data;
+DROP TABLE presence;
 DROP TABLE profiles;
+DROP TABLE push_rules;
+DROP TABLE pushers;
+DROP TABLE receipts;
 DROP TABLE room_aliases;
 DROP TABLE room_memberships;
+DROP TABLE room_tags;
 DROP TABLE rooms;
+DROP TABLE server_notices_rooms;
+DROP TABLE stream_positions;
+DROP TABLE threepid_validations;
+DROP TABLE to_device_messages;
+DROP TABLE to_device_transactions;
+DROP TABLE transactions;
+DROP TABLE uia_sessions;
+DROP TABLE url_previews;
+DROP TABLE user_threepids;
+DROP TABLE user_velocity_limits;
 DROP TABLE users;
+DROP TABLE velocity_actions;
+DROP FUNCTION event_relates_to(TEXT);
+DROP FUNCTION event_relation_type(TEXT);
+DROP FUNCTION event_search_matches(TEXT, TEXT);
+DROP FUNCTION event_search_rank(TEXT, TEXT);
+DROP FUNCTION event_search_document(TEXT);
+DROP FUNCTION next_stream_id(TEXT);
+DROP FUNCTION public_room_joined_members(TEXT);
+DROP FUNCTION public_room_rank(TEXT, TEXT);
+DROP FUNCTION room_state_field(TEXT, TEXT, TEXT);
+DROP FUNCTION set_replaces_state();
+DROP FUNCTION user_directory_matches(TEXT, TEXT);
+DROP FUNCTION user_directory_rank(TEXT, TEXT);
+DROP EXTENSION pg_trgm;
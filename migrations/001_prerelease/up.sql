@@ -1,32 +1,235 @@
+-- Trigram matching, for fuzzy user directory searches.
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+-- Hands out the next ID in a stream, such as the ordering of events. The row lock taken by the
+-- UPDATE is held until the calling transaction commits, so IDs always become visible in the order
+-- they were handed out, even when several processes write to the same stream.
+CREATE FUNCTION next_stream_id(stream_name TEXT) RETURNS BIGINT AS $$
+DECLARE
+  next_id BIGINT;
+BEGIN
+  UPDATE stream_positions SET position = position + 1 WHERE stream = stream_name
+    RETURNING position INTO next_id;
+
+  RETURN next_id;
+END;
+$$ LANGUAGE plpgsql;
+
 CREATE TABLE access_tokens (
   id BIGSERIAL PRIMARY KEY,
   user_id TEXT NOT NULL,
+  device_id TEXT NOT NULL,
   value TEXT NOT NULL,
+  value_digest TEXT NOT NULL UNIQUE,
   revoked BOOLEAN NOT NULL DEFAULT FALSE,
   created_at TIMESTAMP NOT NULL DEFAULT now(),
-  updated_at TIMESTAMP NOT NULL DEFAULT now()
+  updated_at TIMESTAMP NOT NULL DEFAULT now(),
+  last_used_at BIGINT,
+  last_used_ip TEXT
 );
 
+CREATE INDEX access_tokens_user_id ON access_tokens (user_id);
+
 CREATE TABLE account_data (
     id BIGSERIAL PRIMARY KEY,
     user_id TEXT NOT NULL,
     data_type TEXT NOT NULL,
     content TEXT NOT NULL,
+    ordering BIGINT NOT NULL UNIQUE DEFAULT next_stream_id('events'),
     UNIQUE (user_id, data_type)
 );
 
+CREATE TABLE broadcasts (
+  id BIGSERIAL PRIMARY KEY,
+  sender TEXT NOT NULL,
+  content TEXT NOT NULL,
+  last_user_id TEXT,
+  completed BOOLEAN NOT NULL DEFAULT FALSE,
+  created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+-- Changes users have made to the server's default push rules, which aren't stored themselves.
+CREATE TABLE default_push_rule_settings (
+  user_id TEXT NOT NULL,
+  rule_id TEXT NOT NULL,
+  enabled BOOLEAN NOT NULL,
+  actions TEXT NOT NULL,
+  PRIMARY KEY (user_id, rule_id)
+);
+
+CREATE TABLE delayed_events (
+  id TEXT NOT NULL PRIMARY KEY,
+  room_id TEXT NOT NULL,
+  user_id TEXT NOT NULL,
+  event_type TEXT NOT NULL,
+  state_key TEXT,
+  content TEXT NOT NULL,
+  delay BIGINT NOT NULL,
+  send_at BIGINT NOT NULL
+);
+
+CREATE INDEX delayed_events_send_at ON delayed_events (send_at);
+
+CREATE TABLE device_list_changes (
+  id BIGSERIAL PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  ordering BIGINT NOT NULL UNIQUE DEFAULT next_stream_id('events')
+);
+
+CREATE TABLE devices (
+  id BIGSERIAL PRIMARY KEY,
+  device_id TEXT NOT NULL,
+  user_id TEXT NOT NULL,
+  display_name TEXT,
+  created_at TIMESTAMP NOT NULL DEFAULT now(),
+  UNIQUE (user_id, device_id)
+);
+
+-- The identity keys a device uploaded for end-to-end encryption, as signed JSON. Keys go away
+-- with their device.
+CREATE TABLE device_keys (
+  user_id TEXT NOT NULL,
+  device_id TEXT NOT NULL,
+  key_json TEXT NOT NULL,
+  updated_at BIGINT NOT NULL,
+  PRIMARY KEY (user_id, device_id),
+  FOREIGN KEY (user_id, device_id) REFERENCES devices (user_id, device_id) ON DELETE CASCADE
+);
+
+CREATE TABLE event_expirations (
+  event_id TEXT NOT NULL PRIMARY KEY,
+  room_id TEXT NOT NULL,
+  expires_at BIGINT NOT NULL
+);
+
+CREATE INDEX event_expirations_expires_at ON event_expirations (expires_at);
+
 CREATE TABLE events (
   id TEXT NOT NULL PRIMARY KEY,
-  ordering BIGSERIAL NOT NULL,
+  ordering BIGINT NOT NULL UNIQUE DEFAULT next_stream_id('events'),
   room_id TEXT NOT NULL,
   user_id TEXT NOT NULL,
   event_type TEXT NOT NULL,
   state_key TEXT,
   content TEXT NOT NULL,
   extra_content TEXT,
+  replaces_state TEXT,
+  created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX events_room_id_ordering ON events (room_id, ordering);
+
+-- Records the state event each state event replaced, so clients can be sent the previous content
+-- without going through the room's history. It's worked out again when the ordering changes, as
+-- it does for backfilled history.
+CREATE FUNCTION set_replaces_state() RETURNS TRIGGER AS $$
+BEGIN
+  IF NEW.state_key IS NOT NULL THEN
+    SELECT id INTO NEW.replaces_state FROM events
+      WHERE room_id = NEW.room_id
+        AND event_type = NEW.event_type
+        AND state_key = NEW.state_key
+        AND ordering < NEW.ordering
+      ORDER BY ordering DESC
+      LIMIT 1;
+  END IF;
+
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER events_replaces_state BEFORE INSERT OR UPDATE OF ordering ON events
+  FOR EACH ROW EXECUTE PROCEDURE set_replaces_state();
+
+-- The text that full-text message search looks at: the body of messages and the name and topic
+-- of rooms. The search functions below are plain SQL so the planner inlines them and can use the
+-- events_search index.
+CREATE FUNCTION event_search_document(content TEXT) RETURNS TSVECTOR AS $$
+  SELECT to_tsvector(
+    'english',
+    coalesce(content::jsonb ->> 'body', '') || ' ' ||
+    coalesce(content::jsonb ->> 'name', '') || ' ' ||
+    coalesce(content::jsonb ->> 'topic', '')
+  );
+$$ LANGUAGE SQL IMMUTABLE;
+
+CREATE FUNCTION event_search_matches(content TEXT, search_term TEXT) RETURNS BOOLEAN AS $$
+  SELECT event_search_document(content) @@ plainto_tsquery('english', search_term);
+$$ LANGUAGE SQL IMMUTABLE;
+
+CREATE FUNCTION event_search_rank(content TEXT, search_term TEXT) RETURNS REAL AS $$
+  SELECT ts_rank(event_search_document(content), plainto_tsquery('english', search_term));
+$$ LANGUAGE SQL IMMUTABLE;
+
+CREATE INDEX events_search ON events USING GIN (event_search_document(content));
+
+-- The event that an event relates to through m.relates_to, such as the message a reaction is for,
+-- and the type of the relation. Used to limit how many relations one event can collect.
+CREATE FUNCTION event_relates_to(content TEXT) RETURNS TEXT AS $$
+  SELECT content::jsonb -> 'm.relates_to' ->> 'event_id';
+$$ LANGUAGE SQL IMMUTABLE;
+
+CREATE FUNCTION event_relation_type(content TEXT) RETURNS TEXT AS $$
+  SELECT content::jsonb -> 'm.relates_to' ->> 'rel_type';
+$$ LANGUAGE SQL IMMUTABLE;
+
+CREATE INDEX events_relates_to ON events (room_id, event_relates_to(content));
+
+CREATE TABLE filters (
+  id BIGSERIAL PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  content TEXT NOT NULL,
   created_at TIMESTAMP NOT NULL DEFAULT now()
 );
 
+CREATE TABLE lazy_loaded_members (
+  id BIGSERIAL PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  device_id TEXT NOT NULL,
+  room_id TEXT NOT NULL,
+  member_id TEXT NOT NULL,
+  event_id TEXT NOT NULL,
+  UNIQUE (user_id, device_id, room_id, member_id)
+);
+
+-- Files uploaded to the content repository. The content itself is kept by the media storage,
+-- under the media ID. Quarantined files stay stored, and count towards their uploader's quota,
+-- but can't be downloaded.
+CREATE TABLE media (
+  id TEXT NOT NULL PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  content_type TEXT NOT NULL,
+  upload_name TEXT,
+  size BIGINT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT now(),
+  quarantined BOOLEAN NOT NULL DEFAULT FALSE,
+  content_hash TEXT NOT NULL
+);
+
+CREATE INDEX media_content_hash ON media (content_hash);
+CREATE INDEX media_user_id ON media (user_id);
+
+-- One-time keys a device uploaded for other devices to claim. Each key is claimed at most once,
+-- by deleting it.
+CREATE TABLE one_time_keys (
+  id BIGSERIAL PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  device_id TEXT NOT NULL,
+  algorithm TEXT NOT NULL,
+  key_id TEXT NOT NULL,
+  key_json TEXT NOT NULL,
+  UNIQUE (user_id, device_id, algorithm, key_id),
+  FOREIGN KEY (user_id, device_id) REFERENCES devices (user_id, device_id) ON DELETE CASCADE
+);
+
+CREATE TABLE presence (
+  user_id TEXT NOT NULL PRIMARY KEY,
+  state TEXT NOT NULL,
+  status_msg TEXT,
+  last_active_at BIGINT NOT NULL,
+  ordering BIGINT NOT NULL UNIQUE DEFAULT next_stream_id('events')
+);
+
 CREATE TABLE profiles (
     id TEXT NOT NULL PRIMARY KEY,
     avatar_url TEXT,
@@ -34,6 +237,65 @@ CREATE TABLE profiles (
     UNIQUE(id)
 );
 
+-- Whether a user ID or display name contains the search term or is similar to it, ignoring case.
+-- Like the message search functions, these are plain SQL so the planner can use the trigram
+-- indexes on profiles and users.
+CREATE FUNCTION user_directory_matches(value TEXT, search_term TEXT) RETURNS BOOLEAN AS $$
+  SELECT lower(value) LIKE
+    '%' || replace(replace(replace(lower(search_term), '\', '\\'), '%', '\%'), '_', '\_') || '%'
+    OR lower(value) % lower(search_term);
+$$ LANGUAGE SQL IMMUTABLE;
+
+CREATE FUNCTION user_directory_rank(value TEXT, search_term TEXT) RETURNS REAL AS $$
+  SELECT similarity(lower(value), lower(search_term));
+$$ LANGUAGE SQL IMMUTABLE;
+
+CREATE INDEX profiles_displayname_trgm ON profiles USING GIN (lower(displayname) gin_trgm_ops);
+
+-- Push rules users have added. Within each kind, rules with a lower position are tried first.
+CREATE TABLE push_rules (
+  id BIGSERIAL PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  kind TEXT NOT NULL,
+  rule_id TEXT NOT NULL,
+  position BIGINT NOT NULL,
+  conditions TEXT NOT NULL,
+  pattern TEXT,
+  actions TEXT NOT NULL,
+  enabled BOOLEAN NOT NULL DEFAULT TRUE,
+  UNIQUE (user_id, kind, rule_id)
+);
+
+-- Where to send push notifications for a user. Events up to last_ordering have been pushed.
+CREATE TABLE pushers (
+  id BIGSERIAL PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  kind TEXT NOT NULL,
+  app_id TEXT NOT NULL,
+  pushkey TEXT NOT NULL,
+  app_display_name TEXT NOT NULL,
+  device_display_name TEXT NOT NULL,
+  profile_tag TEXT,
+  lang TEXT NOT NULL,
+  data TEXT NOT NULL,
+  updated_at BIGINT NOT NULL,
+  last_ordering BIGINT NOT NULL,
+  UNIQUE (user_id, app_id, pushkey)
+);
+
+CREATE TABLE receipts (
+  id BIGSERIAL PRIMARY KEY,
+  room_id TEXT NOT NULL,
+  user_id TEXT NOT NULL,
+  receipt_type TEXT NOT NULL,
+  event_id TEXT NOT NULL,
+  ts BIGINT NOT NULL,
+  ordering BIGINT NOT NULL UNIQUE DEFAULT next_stream_id('events'),
+  UNIQUE (room_id, user_id, receipt_type)
+);
+
+CREATE INDEX receipts_room_id_ordering ON receipts (room_id, ordering);
+
 CREATE TABLE room_aliases (
   alias TEXT NOT NULL PRIMARY KEY,
   room_id TEXT NOT NULL,
@@ -49,6 +311,7 @@ CREATE TABLE room_account_data (
     room_id TEXT NOT NULL,
     data_type TEXT NOT NULL,
     content TEXT NOT NULL,
+    ordering BIGINT NOT NULL UNIQUE DEFAULT next_stream_id('events'),
     UNIQUE (user_id, room_id, data_type)
 );
 
@@ -59,20 +322,182 @@ CREATE TABLE room_memberships (
     sender TEXT NOT NULL,
     membership TEXT NOT NULL,
     created_at TIMESTAMP NOT NULL DEFAULT now(),
+    forgotten BOOLEAN NOT NULL DEFAULT FALSE,
     UNIQUE(room_id, user_id)
 );
 
+CREATE INDEX room_memberships_user_id ON room_memberships (user_id);
+
+-- Tags users put on rooms, such as m.favourite. The content is the tag's JSON object, which may
+-- give an order.
+CREATE TABLE room_tags (
+  id BIGSERIAL PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  room_id TEXT NOT NULL,
+  tag TEXT NOT NULL,
+  content TEXT NOT NULL,
+  UNIQUE (user_id, room_id, tag)
+);
+
 CREATE TABLE rooms (
   id TEXT NOT NULL PRIMARY KEY,
   user_id TEXT NOT NULL,
   public BOOLEAN NOT NULL,
-  created_at TIMESTAMP NOT NULL DEFAULT now()
+  created_at TIMESTAMP NOT NULL DEFAULT now(),
+  version TEXT NOT NULL
+);
+
+-- The public room directory's ranking, worked out in SQL so only one page of rooms is loaded.
+-- Parameters are referred to by position because column names would take precedence over them.
+CREATE FUNCTION room_state_field(room_id TEXT, event_type TEXT, field TEXT) RETURNS TEXT AS $$
+  SELECT content::jsonb ->> $3 FROM events
+    WHERE events.room_id = $1 AND events.event_type = $2 AND events.state_key = ''
+    ORDER BY ordering DESC
+    LIMIT 1;
+$$ LANGUAGE SQL STABLE;
+
+CREATE FUNCTION public_room_joined_members(room_id TEXT) RETURNS BIGINT AS $$
+  SELECT count(*) FROM room_memberships
+    WHERE room_memberships.room_id = $1 AND room_memberships.membership = 'join';
+$$ LANGUAGE SQL STABLE;
+
+-- How well a room matches a directory search, ignoring case: 3 if one of its aliases is the search
+-- term, with or without the # and server name, 2 if its name starts with the term, 1 if its name,
+-- topic, or aliases contain the term anywhere, and 0 if it doesn't match.
+CREATE FUNCTION public_room_rank(room_id TEXT, search_term TEXT) RETURNS INTEGER AS $$
+  SELECT CASE
+    WHEN EXISTS (
+      SELECT 1 FROM room_aliases
+        WHERE room_aliases.room_id = $1
+          AND (
+            lower(room_aliases.alias) = lower($2)
+            OR lower(split_part(room_aliases.alias, ':', 1)) = '#' || lower(ltrim($2, '#'))
+          )
+    ) THEN 3
+    WHEN position(lower($2) IN lower(room_state_field($1, 'm.room.name', 'name'))) = 1 THEN 2
+    WHEN position(lower($2) IN lower(room_state_field($1, 'm.room.name', 'name'))) > 0
+      OR position(lower($2) IN lower(room_state_field($1, 'm.room.topic', 'topic'))) > 0
+      OR position(
+        lower($2) IN lower(room_state_field($1, 'm.room.canonical_alias', 'alias'))
+      ) > 0
+      OR EXISTS (
+        SELECT 1 FROM room_aliases
+          WHERE room_aliases.room_id = $1 AND position(lower($2) IN lower(room_aliases.alias)) > 0
+      ) THEN 1
+    ELSE 0
+  END;
+$$ LANGUAGE SQL STABLE;
+
+CREATE TABLE server_notices_rooms (
+  user_id TEXT NOT NULL PRIMARY KEY,
+  room_id TEXT NOT NULL
+);
+
+CREATE TABLE stream_positions (
+  stream TEXT NOT NULL PRIMARY KEY,
+  position BIGINT NOT NULL
+);
+
+INSERT INTO stream_positions (stream, position) VALUES ('events', 0);
+
+-- Historical events imported by application services count down from 0, so they sort before every
+-- other event without ever appearing in a stream.
+INSERT INTO stream_positions (stream, position) VALUES ('history', 0);
+
+CREATE TABLE threepid_validations (
+  id TEXT NOT NULL PRIMARY KEY,
+  client_secret TEXT NOT NULL,
+  medium TEXT NOT NULL,
+  address TEXT NOT NULL,
+  token TEXT NOT NULL,
+  send_attempt BIGINT NOT NULL,
+  validated BOOLEAN NOT NULL DEFAULT FALSE,
+  created_at TIMESTAMP NOT NULL DEFAULT now(),
+  UNIQUE (client_secret, medium, address)
+);
+
+CREATE TABLE to_device_messages (
+  id BIGSERIAL PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  device_id TEXT NOT NULL,
+  sender TEXT NOT NULL,
+  event_type TEXT NOT NULL,
+  content TEXT NOT NULL,
+  ordering BIGINT NOT NULL UNIQUE DEFAULT next_stream_id('events')
+);
+
+CREATE INDEX to_device_messages_recipient ON to_device_messages (user_id, device_id, ordering);
+
+CREATE TABLE to_device_transactions (
+  id BIGSERIAL PRIMARY KEY,
+  access_token_id BIGINT NOT NULL,
+  transaction_id TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT now(),
+  UNIQUE (access_token_id, transaction_id)
+);
+
+-- Events sent by application services have no access token; their transaction IDs are scoped
+-- to the application service instead.
+CREATE TABLE transactions (
+  id BIGSERIAL PRIMARY KEY,
+  access_token_id BIGINT,
+  app_service_id TEXT,
+  transaction_id TEXT NOT NULL,
+  event_id TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT now(),
+  UNIQUE (access_token_id, transaction_id),
+  UNIQUE (app_service_id, transaction_id),
+  CHECK ((access_token_id IS NULL) <> (app_service_id IS NULL))
+);
+
+CREATE TABLE uia_sessions (
+  id TEXT NOT NULL PRIMARY KEY,
+  endpoint TEXT NOT NULL,
+  user_id TEXT,
+  completed TEXT[] NOT NULL DEFAULT '{}',
+  expires_at BIGINT NOT NULL
+);
+
+-- Previews of web pages as JSON objects of OpenGraph properties, kept until they expire so a
+-- page that's linked in many rooms is only fetched once.
+CREATE TABLE url_previews (
+  url TEXT NOT NULL PRIMARY KEY,
+  og TEXT NOT NULL,
+  expires_at BIGINT NOT NULL
+);
+
+CREATE TABLE user_threepids (
+  id BIGSERIAL PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  medium TEXT NOT NULL,
+  address TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT now(),
+  UNIQUE (medium, address)
+);
+
+CREATE TABLE user_velocity_limits (
+  user_id TEXT NOT NULL PRIMARY KEY,
+  invites_per_hour BIGINT,
+  rooms_created_per_day BIGINT
 );
 
 CREATE TABLE users (
   id TEXT NOT NULL PRIMARY KEY,
   password_hash TEXT NOT NULL,
   active BOOLEAN NOT NULL DEFAULT TRUE,
+  locked BOOLEAN NOT NULL DEFAULT FALSE,
+  is_guest BOOLEAN NOT NULL DEFAULT FALSE,
   created_at TIMESTAMP NOT NULL DEFAULT now(),
   updated_at TIMESTAMP NOT NULL DEFAULT now()
 );
+
+CREATE INDEX users_id_trgm ON users USING GIN (lower(id) gin_trgm_ops);
+
+CREATE TABLE velocity_actions (
+  id BIGSERIAL PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  action TEXT NOT NULL,
+  occurred_at BIGINT NOT NULL
+);
+
+CREATE INDEX velocity_actions_user_id_action ON velocity_actions (user_id, action, occurred_at);
@@ -1,9 +1,20 @@
 //! User access tokens.
 
 use base64::encode;
-use chrono::{Duration, UTC};
-use diesel::{ExpressionMethods, FilterDsl, LoadDsl, SaveChangesDsl, insert};
+use chrono::{DateTime, Duration, UTC};
+use diesel::{
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LoadDsl,
+    OrderDsl,
+    SaveChangesDsl,
+    insert,
+    update,
+};
 use diesel::pg::PgConnection;
+use diesel::pg::expression::dsl::any;
 use diesel::pg::data_types::PgTimestamp;
 use iron::typemap::Key;
 use macaroons::caveat::Caveat;
@@ -11,9 +22,16 @@ use macaroons::token::Token;
 use macaroons::v1::V1Token;
 use ruma_identifiers::UserId;
 
+use clock::Clock;
+use crypto::{Entropy, secrets_match, sha256_hex};
 use error::ApiError;
+use event::POSTGRES_EPOCH_MILLIS;
 use schema::access_tokens;
 
+/// How often an access token's last use is recorded, in milliseconds. Recording every request
+/// would turn every read into a write.
+const LAST_USED_PRECISION_MILLIS: i64 = 60_000;
+
 /// A User access token.
 #[derive(AsChangeset, Debug, Identifiable, Queryable)]
 #[table_name = "access_tokens"]
@@ -22,14 +40,23 @@ pub struct AccessToken {
     pub id: i64,
     /// The ID of the user who owns the access token.
     pub user_id: UserId,
+    /// The ID of the device the access token was issued to.
+    pub device_id: String,
     /// The value of the access token. This is a Base64-encoded macaroon.
     pub value: String,
+    /// The SHA-256 digest of the value, which tokens are looked up by.
+    pub value_digest: String,
     /// Whether or not the access token has been revoked.
     pub revoked: bool,
     /// The time the access token was created.
     pub created_at: PgTimestamp,
     /// The time the access token was last modified.
     pub updated_at: PgTimestamp,
+    /// When the access token was last used, to the nearest minute, in milliseconds since the Unix
+    /// epoch.
+    pub last_used_at: Option<i64>,
+    /// The IP address the access token was last used from.
+    pub last_used_ip: Option<String>,
 }
 
 /// A new access token, not yet saved.
@@ -38,8 +65,12 @@ pub struct AccessToken {
 pub struct NewAccessToken {
     /// The ID of the user who owns the access token.
     pub user_id: UserId,
+    /// The ID of the device the access token was issued to.
+    pub device_id: String,
     /// The value of the access token. This is a Base64-encoded macaroon.
     pub value: String,
+    /// The SHA-256 digest of the value, which tokens are looked up by.
+    pub value_digest: String,
 }
 
 impl AccessToken {
@@ -47,11 +78,23 @@ impl AccessToken {
     pub fn create(
         connection: &PgConnection,
         user_id: &UserId,
+        device_id: &str,
         macaroon_secret_key: &Vec<u8>,
+        clock: &Clock,
+        entropy: &Entropy,
     ) -> Result<Self, ApiError> {
+        let value = create_macaroon(
+            macaroon_secret_key,
+            &entropy.alphanumeric(16),
+            user_id,
+            clock.now(),
+        )?;
+
         let new_access_token = NewAccessToken {
             user_id: user_id.clone(),
-            value: create_macaroon(macaroon_secret_key, user_id)?,
+            device_id: device_id.to_string(),
+            value_digest: sha256_hex(&value),
+            value: value,
         };
 
         insert(&new_access_token)
@@ -62,17 +105,108 @@ impl AccessToken {
 
     /// Creates an `AccessToken` from an access token string value.
     ///
-    /// The access token cannot be revoked.
+    /// The access token cannot be revoked. Tokens are looked up by digest rather than by value so
+    /// that how long the lookup takes says nothing about how much of a guessed token is right.
     pub fn find_valid_by_token(connection: &PgConnection, token: &str)
     -> Result<AccessToken, ApiError> {
-        access_tokens::table
-            .filter(access_tokens::value.eq(token))
+        let access_token: AccessToken = access_tokens::table
+            .filter(access_tokens::value_digest.eq(sha256_hex(token)))
             .filter(access_tokens::revoked.eq(false))
-            .first(connection)
-            .map(AccessToken::from)
+            .first(connection)?;
+
+        if secrets_match(&access_token.value, token) {
+            Ok(access_token)
+        } else {
+            Err(ApiError::unauthorized(None))
+        }
+    }
+
+    /// Returns all of a user's access tokens, including revoked ones, oldest first.
+    pub fn find_by_user(connection: &PgConnection, user_id: &UserId)
+    -> Result<Vec<AccessToken>, ApiError> {
+        access_tokens::table
+            .filter(access_tokens::user_id.eq(user_id))
+            .order(access_tokens::id.asc())
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Revokes the user's access tokens with the given IDs, returning how many were revoked.
+    ///
+    /// IDs of other users' tokens are ignored.
+    pub fn revoke_by_ids(connection: &PgConnection, user_id: &UserId, ids: &[i64])
+    -> Result<usize, ApiError> {
+        let access_tokens = access_tokens::table
+            .filter(access_tokens::user_id.eq(user_id))
+            .filter(access_tokens::id.eq(any(ids.to_vec())))
+            .filter(access_tokens::revoked.eq(false));
+
+        update(access_tokens)
+            .set(access_tokens::revoked.eq(true))
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Revokes the access tokens issued to one of the user's devices, returning how many were
+    /// revoked.
+    pub fn revoke_by_device(connection: &PgConnection, user_id: &UserId, device_id: &str)
+    -> Result<usize, ApiError> {
+        let access_tokens = access_tokens::table
+            .filter(access_tokens::user_id.eq(user_id))
+            .filter(access_tokens::device_id.eq(device_id))
+            .filter(access_tokens::revoked.eq(false));
+
+        update(access_tokens)
+            .set(access_tokens::revoked.eq(true))
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Revokes all of the user's access tokens, logging them out everywhere, and returns how many
+    /// were revoked.
+    pub fn revoke_by_user(connection: &PgConnection, user_id: &UserId) -> Result<usize, ApiError> {
+        let access_tokens = access_tokens::table
+            .filter(access_tokens::user_id.eq(user_id))
+            .filter(access_tokens::revoked.eq(false));
+
+        update(access_tokens)
+            .set(access_tokens::revoked.eq(true))
+            .execute(connection)
             .map_err(ApiError::from)
     }
 
+    /// Records that the access token was used at `now` from the IP address `ip`, unless a use
+    /// from the same address was already recorded within the last minute.
+    pub fn record_use(&mut self, connection: &PgConnection, now: i64, ip: &str)
+    -> Result<(), ApiError> {
+        if let Some(last_used_at) = self.last_used_at {
+            let same_ip =
+                self.last_used_ip.as_ref().map_or(false, |last_used_ip| last_used_ip == ip);
+
+            if same_ip && now - last_used_at < LAST_USED_PRECISION_MILLIS {
+                return Ok(());
+            }
+        }
+
+        update(access_tokens::table.find(self.id))
+            .set((
+                access_tokens::last_used_at.eq(Some(now)),
+                access_tokens::last_used_ip.eq(Some(ip.to_string())),
+            ))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        self.last_used_at = Some(now);
+        self.last_used_ip = Some(ip.to_string());
+
+        Ok(())
+    }
+
+    /// The time the access token was created, in milliseconds since the Unix epoch.
+    pub fn created_at_millis(&self) -> i64 {
+        self.created_at.0 / 1000 + POSTGRES_EPOCH_MILLIS
+    }
+
     /// Revoke the access token so it cannot be used again.
     pub fn revoke(&mut self, connection: &PgConnection) -> Result<(), ApiError> {
         self.revoked = true;
@@ -88,15 +222,20 @@ impl Key for AccessToken {
     type Value = AccessToken;
 }
 
-fn create_macaroon(macaroon_secret_key: &Vec<u8>, user_id: &UserId) -> Result<String, ApiError> {
-    let expiration = match UTC::now().checked_add(Duration::hours(1)) {
+fn create_macaroon(
+    macaroon_secret_key: &Vec<u8>,
+    identifier: &str,
+    user_id: &UserId,
+    now: DateTime<UTC>,
+) -> Result<String, ApiError> {
+    let expiration = match now.checked_add(Duration::hours(1)) {
         Some(datetime) => datetime,
         None => return Err(
             ApiError::unknown(Some("Failed to generate access token expiration datetime."))
         ),
     };
 
-    let token = V1Token::new(macaroon_secret_key, "key".as_bytes().to_owned(), None)
+    let token = V1Token::new(macaroon_secret_key, identifier.as_bytes().to_owned(), None)
         .add_caveat(&Caveat::first_party(
             format!("user_id = {}", user_id.to_string()).as_bytes().to_owned()
         ))
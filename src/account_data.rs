@@ -1,11 +1,12 @@
 //! Account information stored for a user.
 
 use diesel::{
+    Connection,
     ExecuteDsl,
     ExpressionMethods,
     FilterDsl,
     LoadDsl,
-    SaveChangesDsl,
+    OrderDsl,
     delete,
     insert,
 };
@@ -18,7 +19,7 @@ use error::ApiError;
 use schema::{account_data, room_account_data};
 
 /// Holds personal information/configuration for a user.
-#[derive(AsChangeset, Debug, Clone, Identifiable, Queryable)]
+#[derive(Debug, Clone, Identifiable, Queryable)]
 #[table_name = "account_data"]
 pub struct AccountData {
     /// Entry ID
@@ -29,6 +30,8 @@ pub struct AccountData {
     pub data_type: String,
     /// The contents.
     pub content: String,
+    /// The position of the latest change in the events stream.
+    pub ordering: i64,
 }
 
 /// New account data, not yet saved.
@@ -63,15 +66,6 @@ impl AccountData {
             .map(AccountData::from)
     }
 
-    /// Update an `AccountData` entry with new content.
-    pub fn update(&mut self, connection: &PgConnection, content: String)
-    -> Result<AccountData, ApiError> {
-        self.content = content;
-
-        self.save_changes::<AccountData>(connection)
-            .map_err(ApiError::from)
-    }
-
     /// Delete all account data of a user given a `UserId`.
     pub fn delete_by_uid(connection: &PgConnection, uid: &UserId)
     -> Result<usize, ApiError> {
@@ -83,31 +77,33 @@ impl AccountData {
             .map_err(ApiError::from)
     }
 
-    /// Get all account data given a `UserId`.
-    pub fn get_by_uid(connection: &PgConnection, uid: &UserId)
+    /// Get the account data of a user that changed after the position `after` up to the position
+    /// `up_to`.
+    pub fn get_by_uid(connection: &PgConnection, uid: &UserId, after: Option<i64>, up_to: i64)
     -> Result<Vec<AccountData>, ApiError> {
         account_data::table
             .filter(account_data::user_id.eq(uid))
+            .filter(account_data::ordering.gt(after.unwrap_or(0)))
+            .filter(account_data::ordering.le(up_to))
+            .order(account_data::ordering.asc())
             .load::<AccountData>(connection)
             .map_err(ApiError::from)
     }
 
     /// Update an existing entry or create a new one.
+    ///
+    /// The entry gets a new position in the events stream so that `/sync` picks it up.
     pub fn upsert(connection: &PgConnection, new_data: &NewAccountData)
     -> Result<AccountData, ApiError> {
-        match AccountData::find_by_uid_and_type(
-            connection,
-            &new_data.user_id,
-            &new_data.data_type
-        ) {
-            Ok(mut saved) => saved.update(&connection, new_data.content.clone()),
-            Err(err) => {
-                match err {
-                    DieselError::NotFound => AccountData::create(connection, new_data),
-                    _ => Err(ApiError::from(err))
-                }
-            }
-        }
+        connection.transaction::<AccountData, ApiError, _>(|| {
+            let previous = account_data::table
+                .filter(account_data::user_id.eq(&new_data.user_id))
+                .filter(account_data::data_type.eq(&new_data.data_type));
+
+            delete(previous).execute(connection)?;
+
+            AccountData::create(connection, new_data)
+        }).map_err(ApiError::from)
     }
 }
 
@@ -116,7 +112,7 @@ impl Key for AccountData {
 }
 
 /// Holds user's information/configuration per room.
-#[derive(AsChangeset, Clone, Debug, Identifiable, Queryable)]
+#[derive(Clone, Debug, Identifiable, Queryable)]
 #[table_name = "room_account_data"]
 pub struct RoomAccountData {
     /// Entry ID
@@ -129,6 +125,8 @@ pub struct RoomAccountData {
     pub data_type: String,
     /// The contents.
     pub content: String,
+    /// The position of the latest change in the events stream.
+    pub ordering: i64,
 }
 
 /// New room account data, not yet saved.
@@ -166,12 +164,22 @@ impl RoomAccountData {
             .map(RoomAccountData::from)
     }
 
-    /// Update an `RoomAccountData` entry with new content.
-    pub fn update(&mut self, connection: &PgConnection, content: String)
-    -> Result<RoomAccountData, ApiError> {
-        self.content = content;
-
-        self.save_changes::<RoomAccountData>(connection)
+    /// Get a user's account data for a room that changed after the position `after` up to the
+    /// position `up_to`.
+    pub fn find_by_room(
+        connection: &PgConnection,
+        uid: &UserId,
+        rid: &RoomId,
+        after: Option<i64>,
+        up_to: i64,
+    ) -> Result<Vec<RoomAccountData>, ApiError> {
+        room_account_data::table
+            .filter(room_account_data::user_id.eq(uid))
+            .filter(room_account_data::room_id.eq(rid))
+            .filter(room_account_data::ordering.gt(after.unwrap_or(0)))
+            .filter(room_account_data::ordering.le(up_to))
+            .order(room_account_data::ordering.asc())
+            .load::<RoomAccountData>(connection)
             .map_err(ApiError::from)
     }
 
@@ -187,22 +195,20 @@ impl RoomAccountData {
     }
 
     /// Update an existing entry or create a new one.
+    ///
+    /// The entry gets a new position in the events stream so that `/sync` picks it up.
     pub fn upsert(connection: &PgConnection, new_data: &NewRoomAccountData)
     -> Result<RoomAccountData, ApiError> {
-        match RoomAccountData::find(
-            &connection,
-            &new_data.user_id,
-            &new_data.room_id,
-            &new_data.data_type
-        ) {
-            Ok(mut saved) => saved.update(&connection, new_data.content.clone()),
-            Err(err) => {
-                match err {
-                    DieselError::NotFound => RoomAccountData::create(&connection, &new_data),
-                    _ => Err(ApiError::from(err))
-                }
-            }
-        }
+        connection.transaction::<RoomAccountData, ApiError, _>(|| {
+            let previous = room_account_data::table
+                .filter(room_account_data::user_id.eq(&new_data.user_id))
+                .filter(room_account_data::room_id.eq(&new_data.room_id))
+                .filter(room_account_data::data_type.eq(&new_data.data_type));
+
+            delete(previous).execute(connection)?;
+
+            RoomAccountData::create(connection, new_data)
+        }).map_err(ApiError::from)
     }
 }
 
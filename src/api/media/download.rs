@@ -0,0 +1,160 @@
+//! Endpoints for downloading files.
+
+use iron::{Chain, Handler, IronError, IronResult, Request, Response};
+use iron::status::Status;
+use router::Router;
+use url::percent_encoding::percent_decode;
+
+use db::DB;
+use error::ApiError;
+use media::{Media, ServerMediaStorage};
+use middleware::{MediaIdParam, MiddlewareChain};
+
+/// The `Content-Security-Policy` downloads are served with.
+const CONTENT_SECURITY_POLICY: &'static str = "sandbox; default-src 'none'; script-src 'none'; \
+    plugin-types application/pdf; style-src 'unsafe-inline'; media-src 'self'; object-src 'self';";
+
+/// The GET `/download/:server_name/:media_id` and `/download/:server_name/:media_id/:file_name`
+/// endpoints.
+///
+/// Streams the file's content with the type it was uploaded with. The file is named by the
+/// `file_name` path parameter if given, or else by the name it was uploaded with.
+///
+/// Files are served with a sandboxing `Content-Security-Policy`, so that a browser opening an
+/// uploaded SVG or HTML file directly doesn't run scripts in it with the server's origin.
+pub struct DownloadMedia;
+
+middleware_chain!(DownloadMedia, [MediaIdParam]);
+
+impl Handler for DownloadMedia {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let media_id = request.extensions.get::<MediaIdParam>()
+            .expect("MediaIdParam should ensure a media ID").clone();
+
+        let file_name = request.extensions.get::<Router>()
+            .expect("Params object is missing")
+            .find("file_name")
+            .map(|file_name| percent_decode(file_name.as_bytes()).decode_utf8_lossy().into_owned());
+
+        let connection = DB::from_request(request)?;
+        let storage = ServerMediaStorage::from_request(request)?;
+
+        let media = Media::find(&connection, &media_id)?;
+        let content = match media.as_ref() {
+            Some(media) => media.open(&**storage)?,
+            None => None,
+        };
+
+        let (media, content) = match (media, content) {
+            (Some(media), Some(content)) => (media, content),
+            _ => {
+                let error = ApiError::not_found(
+                    Some(&format!("No file with media ID {} was found.", media_id))
+                );
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let mut response = Response::with((Status::Ok, content));
+
+        response.headers.set_raw(
+            "Content-Security-Policy",
+            vec![CONTENT_SECURITY_POLICY.as_bytes().to_vec()],
+        );
+
+        if let Some(file_name) = file_name.or(media.upload_name) {
+            let file_name: String = file_name.chars().filter(|c| *c != '"' && *c != '\\').collect();
+
+            response.headers.set_raw(
+                "Content-Disposition",
+                vec![format!("inline; filename=\"{}\"", file_name).into_bytes()],
+            );
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    /// Uploads a text file and returns its MXC URI without the `mxc://` prefix.
+    fn upload(test: &Test, access_token: &str) -> String {
+        let response = test.post_with_content_type(
+            &format!("/_matrix/media/r0/upload?filename=hello.txt&access_token={}", access_token),
+            "text/plain",
+            "Hello, world!",
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let content_uri = response.json().find("content_uri").and_then(Value::as_str).unwrap();
+
+        content_uri.trim_left_matches("mxc://").to_string()
+    }
+
+    #[test]
+    fn download_media() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let media = upload(&test, &access_token);
+
+        let response = test.get(&format!("/_matrix/media/r0/download/{}", media));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.body, "Hello, world!");
+        assert_eq!(
+            response.headers.get_raw("Content-Type").unwrap()[0],
+            b"text/plain".to_vec()
+        );
+        assert_eq!(
+            response.headers.get_raw("Content-Disposition").unwrap()[0],
+            b"inline; filename=\"hello.txt\"".to_vec()
+        );
+
+        let policy = &response.headers.get_raw("Content-Security-Policy").unwrap()[0];
+
+        assert!(policy.starts_with(b"sandbox;"));
+    }
+
+    #[test]
+    fn download_media_with_a_file_name() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let media = upload(&test, &access_token);
+
+        let response = test.get(&format!("/_matrix/media/r0/download/{}/greeting.txt", media));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.headers.get_raw("Content-Disposition").unwrap()[0],
+            b"inline; filename=\"greeting.txt\"".to_vec()
+        );
+    }
+
+    #[test]
+    fn unknown_media_is_not_found() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/media/r0/download/ruma.test/nonexistent");
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn media_from_other_servers_is_not_found() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let media = upload(&test, &access_token);
+        let media_id = media.split('/').last().unwrap();
+
+        let response = test.get(&format!("/_matrix/media/r0/download/example.com/{}", media_id));
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+}
@@ -0,0 +1,13 @@
+//! API endpoints for the content repository.
+//!
+//! These are mounted under `/_matrix/media/r0/`.
+
+pub use self::download::DownloadMedia;
+pub use self::preview_url::GetUrlPreview;
+pub use self::thumbnail::GetThumbnail;
+pub use self::upload::UploadMedia;
+
+mod download;
+mod preview_url;
+mod thumbnail;
+mod upload;
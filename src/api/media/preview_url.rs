@@ -0,0 +1,218 @@
+//! Endpoints for previewing web pages.
+
+use iron::{Chain, Handler, IronError, IronResult, Request, Response};
+use iron::status::Status;
+
+use clock::ServerClock;
+use config::Config;
+use crypto::ServerEntropy;
+use db::DB;
+use error::ApiError;
+use media::ServerMediaStorage;
+use middleware::{AccessTokenAuth, MiddlewareChain};
+use modifier::SerializableResponse;
+use url_preview::{ServerUrlFetcher, UrlPreview};
+use user::User;
+
+/// The GET `/preview_url` endpoint.
+///
+/// Responds with the OpenGraph properties of the page at the `url` query parameter, with its image
+/// saved to the content repository and given as an MXC URI. The `ts` query parameter is ignored,
+/// and the most recent preview is always given.
+pub struct GetUrlPreview;
+
+middleware_chain!(GetUrlPreview, [AccessTokenAuth]);
+
+impl Handler for GetUrlPreview {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let config = Config::from_request(request)?;
+
+        if !config.url_preview_enabled {
+            let error = ApiError::unimplemented(
+                Some("URL previews are not enabled on this homeserver.")
+            );
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let url = {
+            let url = request.url.clone().into_generic_url();
+            let mut query_pairs = url.query_pairs();
+
+            query_pairs
+                .find(|&(ref key, _)| key == "url")
+                .map(|(_, value)| value.into_owned())
+        };
+
+        let url = match url {
+            Some(url) => url,
+            None => {
+                let error = ApiError::missing_param("url");
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let pool = DB::pool_from_request(request)?;
+        let clock = ServerClock::from_request(request)?;
+        let entropy = ServerEntropy::from_request(request)?;
+        let storage = ServerMediaStorage::from_request(request)?;
+        let url_fetcher = ServerUrlFetcher::from_request(request)?;
+
+        let preview = UrlPreview::find_or_fetch(
+            &pool,
+            &**url_fetcher,
+            &**storage,
+            &**entropy,
+            &config.domain,
+            &user.id,
+            &url,
+            config.max_upload_size,
+            config.max_image_pixels,
+            clock.now_millis(),
+        )?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(preview))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use image::{DynamicImage, ImageBuffer, ImageFormat, Rgb};
+    use iron::status::Status;
+    use serde_json::Value;
+    use url::form_urlencoded::byte_serialize;
+
+    use test::{Response, Test};
+
+    /// Requests a preview of `url`.
+    fn preview(test: &Test, access_token: &str, url: &str) -> Response {
+        let url: String = byte_serialize(url.as_bytes()).collect();
+
+        test.get(
+            &format!("/_matrix/media/r0/preview_url?url={}&access_token={}", url, access_token)
+        )
+    }
+
+    #[test]
+    fn preview_page_with_image() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(20, 10, Rgb([0, 0, 255])));
+        let mut png = Vec::new();
+
+        image.save(&mut png, ImageFormat::PNG).unwrap();
+
+        test.serve_url("https://example.com/images/logo.png", "image/png", &png);
+        test.serve_url(
+            "https://example.com/about",
+            "text/html; charset=utf-8",
+            br#"<html><head>
+                <meta property="og:title" content="Example">
+                <meta property="og:description" content="An example page.">
+                <meta property="og:image" content="images/logo.png">
+            </head></html>"#,
+        );
+
+        let response = preview(&test, &access_token, "https://example.com/about");
+
+        assert_eq!(response.status, Status::Ok);
+
+        let og = response.json();
+
+        assert_eq!(og.find("og:title").and_then(Value::as_str), Some("Example"));
+        assert_eq!(og.find("og:description").and_then(Value::as_str), Some("An example page."));
+        assert_eq!(og.find("og:image:type").and_then(Value::as_str), Some("image/png"));
+        assert_eq!(og.find("og:image:width").and_then(Value::as_u64), Some(20));
+        assert_eq!(og.find("og:image:height").and_then(Value::as_u64), Some(10));
+        assert_eq!(og.find("matrix:image:size").and_then(Value::as_u64), Some(png.len() as u64));
+
+        let content_uri = og.find("og:image").and_then(Value::as_str).unwrap();
+
+        assert!(content_uri.starts_with("mxc://ruma.test/"));
+
+        let response = test.get(
+            &format!("/_matrix/media/r0/download/{}", content_uri.trim_left_matches("mxc://"))
+        );
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn images_over_the_pixel_limit_are_not_decoded() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        // Only the header of a 100000x100000 PNG.
+        let mut bomb = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+
+        bomb.extend_from_slice(b"\x00\x01\x86\xa0\x00\x01\x86\xa0\x08\x02\x00\x00\x00");
+
+        test.serve_url("https://example.com/bomb.png", "image/png", &bomb);
+
+        let response = preview(&test, &access_token, "https://example.com/bomb.png");
+
+        assert_eq!(response.status, Status::Ok);
+
+        let og = response.json();
+
+        assert!(og.find("og:image").is_some());
+        assert!(og.find("og:image:width").is_none());
+        assert!(og.find("og:image:height").is_none());
+    }
+
+    #[test]
+    fn previews_are_cached_until_they_expire() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let url = "https://example.com/news";
+
+        test.serve_url(url, "text/html", b"<title>Old news</title>");
+
+        let response = preview(&test, &access_token, url);
+
+        assert_eq!(response.json().find("og:title").and_then(Value::as_str), Some("Old news"));
+
+        test.serve_url(url, "text/html", b"<title>New news</title>");
+
+        let response = preview(&test, &access_token, url);
+
+        assert_eq!(response.json().find("og:title").and_then(Value::as_str), Some("Old news"));
+
+        test.advance_clock(Duration::hours(1));
+
+        let response = preview(&test, &access_token, url);
+
+        assert_eq!(response.json().find("og:title").and_then(Value::as_str), Some("New news"));
+    }
+
+    #[test]
+    fn pages_that_cannot_be_fetched_are_not_found() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response = preview(&test, &access_token, "https://example.com/missing");
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn only_http_urls_can_be_previewed() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response = preview(&test, &access_token, "file:///etc/passwd");
+
+        assert_eq!(response.status, Status::BadRequest);
+
+        let response = test.get(
+            &format!("/_matrix/media/r0/preview_url?access_token={}", access_token)
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+}
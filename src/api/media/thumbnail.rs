@@ -0,0 +1,171 @@
+//! Endpoints for thumbnails of images.
+
+use iron::{Chain, Handler, IronError, IronResult, Request, Response};
+use iron::status::Status;
+
+use config::Config;
+use db::DB;
+use error::ApiError;
+use media::{MAX_THUMBNAIL_SIZE, Media, ServerMediaStorage, ThumbnailMethod};
+use middleware::{MediaIdParam, MiddlewareChain};
+
+/// The GET `/thumbnail/:server_name/:media_id` endpoint.
+///
+/// Responds with a thumbnail of an uploaded image no larger than the `width` and `height` query
+/// parameters. The `method` parameter chooses between cropping the image to that size and scaling
+/// it to fit within it, the default. Thumbnails are generated on the first request for each size
+/// and kept for later ones.
+pub struct GetThumbnail;
+
+middleware_chain!(GetThumbnail, [MediaIdParam]);
+
+impl Handler for GetThumbnail {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let media_id = request.extensions.get::<MediaIdParam>()
+            .expect("MediaIdParam should ensure a media ID").clone();
+
+        let url = request.url.clone().into_generic_url();
+        let mut width = None;
+        let mut height = None;
+        let mut method = ThumbnailMethod::Scale;
+
+        for (key, value) in url.query_pairs() {
+            let result = match &key[..] {
+                "height" => parse_size("height", &value).map(|size| height = Some(size)),
+                "method" => match &value[..] {
+                    "crop" | "scale" => {
+                        method = if value == "crop" {
+                            ThumbnailMethod::Crop
+                        } else {
+                            ThumbnailMethod::Scale
+                        };
+
+                        Ok(())
+                    }
+                    _ => Err(ApiError::invalid_param("method", "Must be either crop or scale.")),
+                },
+                "width" => parse_size("width", &value).map(|size| width = Some(size)),
+                _ => Ok(()),
+            };
+
+            if let Err(error) = result {
+                return Err(IronError::new(error.clone(), error));
+            }
+        }
+
+        let (width, height) = match (width, height) {
+            (Some(width), Some(height)) => (width, height),
+            (None, _) => {
+                let error = ApiError::missing_param("width");
+
+                return Err(IronError::new(error.clone(), error));
+            }
+            (_, None) => {
+                let error = ApiError::missing_param("height");
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+        let storage = ServerMediaStorage::from_request(request)?;
+
+        let thumbnail = match Media::find(&connection, &media_id)? {
+            Some(media) => {
+                media.thumbnail(&**storage, width, height, method, config.max_image_pixels)?
+            }
+            None => None,
+        };
+
+        match thumbnail {
+            Some(thumbnail) => Ok(Response::with((Status::Ok, thumbnail))),
+            None => {
+                let error = ApiError::not_found(
+                    Some(&format!("No thumbnail is available for media ID {}.", media_id))
+                );
+
+                Err(IronError::new(error.clone(), error))
+            }
+        }
+    }
+}
+
+/// Parses a thumbnail width or height.
+fn parse_size(param: &str, value: &str) -> Result<u32, ApiError> {
+    match value.parse::<u32>() {
+        Ok(size) if size > 0 && size <= MAX_THUMBNAIL_SIZE => Ok(size),
+        _ => Err(ApiError::invalid_param(
+            param,
+            &format!("Must be a positive integer no larger than {}.", MAX_THUMBNAIL_SIZE),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    /// Uploads a file and returns its MXC URI without the `mxc://` prefix.
+    fn upload(test: &Test, access_token: &str, content_type: &str, content: &str) -> String {
+        let response = test.post_with_content_type(
+            &format!("/_matrix/media/r0/upload?access_token={}", access_token),
+            content_type,
+            content,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let content_uri = response.json().find("content_uri").and_then(Value::as_str).unwrap();
+
+        content_uri.trim_left_matches("mxc://").to_string()
+    }
+
+    #[test]
+    fn files_that_are_not_images_have_no_thumbnails() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let media = upload(&test, &access_token, "text/plain", "Hello, world!");
+
+        let response =
+            test.get(&format!("/_matrix/media/r0/thumbnail/{}?width=32&height=32", media));
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn images_that_cannot_be_decoded_have_no_thumbnails() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let media = upload(&test, &access_token, "image/png", "Not really a PNG");
+
+        let response =
+            test.get(&format!("/_matrix/media/r0/thumbnail/{}?width=32&height=32", media));
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn thumbnail_size_is_required_and_limited() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let media = upload(&test, &access_token, "image/png", "Not really a PNG");
+
+        let queries = ["width=32", "width=0&height=32", "width=32&height=4096", "width=a&height=1"];
+
+        for query in &queries {
+            let response = test.get(&format!("/_matrix/media/r0/thumbnail/{}?{}", media, query));
+
+            assert_eq!(response.status, Status::BadRequest);
+        }
+
+        let response = test.get(
+            &format!("/_matrix/media/r0/thumbnail/{}?width=32&height=32&method=stretch", media)
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+}
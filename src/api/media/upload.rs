@@ -0,0 +1,237 @@
+//! Endpoints for uploading files.
+
+use std::io::Read;
+
+use iron::{Chain, Handler, IronError, IronResult, Request, Response};
+use iron::headers::ContentType;
+use iron::status::Status;
+
+use config::Config;
+use crypto::ServerEntropy;
+use db::DB;
+use error::ApiError;
+use media::{
+    Media,
+    ServerMediaStorage,
+    content_type_allowed,
+    content_type_is_svg,
+    strip_image_metadata,
+};
+use middleware::{AccessTokenAuth, MiddlewareChain};
+use modifier::SerializableResponse;
+use user::User;
+
+/// The POST `/upload` endpoint.
+///
+/// Saves the request body as a new file in the content repository, with the type given by the
+/// `Content-Type` header and the name given by the `filename` query parameter, and responds with
+/// the file's MXC URI. Uploads that would take the user past `max_media_storage_per_user` are
+/// refused, as are SVG images. Metadata is removed from JPEG and PNG images first if
+/// `strip_image_metadata` is set.
+pub struct UploadMedia;
+
+#[derive(Debug, Serialize)]
+struct UploadMediaResponse {
+    content_uri: String,
+}
+
+middleware_chain!(UploadMedia, [AccessTokenAuth]);
+
+impl Handler for UploadMedia {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let config = Config::from_request(request)?;
+
+        let content_type = request.headers.get::<ContentType>()
+            .map(|content_type| content_type.to_string())
+            .unwrap_or("application/octet-stream".to_string());
+
+        if content_type_is_svg(&content_type) {
+            let error = ApiError::invalid_param(
+                "Content-Type",
+                "SVG images may not be uploaded, since they can contain scripts.",
+            );
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        if let Some(ref allowed) = config.upload_content_types {
+            if !content_type_allowed(allowed, &content_type) {
+                let error = ApiError::invalid_param(
+                    "Content-Type",
+                    &format!("Files of type {} may not be uploaded.", content_type),
+                );
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        }
+
+        let upload_name = {
+            let url = request.url.clone().into_generic_url();
+            let mut query_pairs = url.query_pairs();
+
+            query_pairs
+                .find(|&(ref key, _)| key == "filename")
+                .map(|(_, value)| value.into_owned())
+        };
+
+        // Read one byte past the limit to tell whether the body is too large.
+        let mut content = Vec::new();
+
+        request.body
+            .by_ref()
+            .take(config.max_upload_size + 1)
+            .read_to_end(&mut content)
+            .map_err(ApiError::from)?;
+
+        if content.len() as u64 > config.max_upload_size {
+            let error = ApiError::too_large(Some(
+                &format!("Files may be at most {} bytes.", config.max_upload_size)
+            ));
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let content = if config.strip_image_metadata {
+            strip_image_metadata(content)
+        } else {
+            content
+        };
+
+        let connection = DB::from_request(request)?;
+        let entropy = ServerEntropy::from_request(request)?;
+        let storage = ServerMediaStorage::from_request(request)?;
+
+        let media = Media::create(
+            &connection,
+            &**storage,
+            &**entropy,
+            &user.id,
+            content_type,
+            upload_name,
+            &content,
+            config.max_media_storage_per_user,
+        )?;
+
+        let response = UploadMediaResponse {
+            content_uri: media.content_uri(&config.domain),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter::repeat;
+
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    #[test]
+    fn upload_media() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response = test.post_with_content_type(
+            &format!("/_matrix/media/r0/upload?filename=hello.txt&access_token={}", access_token),
+            "text/plain",
+            "Hello, world!",
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let content_uri = response.json().find("content_uri").and_then(Value::as_str).unwrap();
+
+        assert!(content_uri.starts_with("mxc://ruma.test/"));
+    }
+
+    #[test]
+    fn uploads_over_the_size_limit_are_rejected() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let content: String = repeat("a").take(1025).collect();
+
+        let response = test.post_with_content_type(
+            &format!("/_matrix/media/r0/upload?access_token={}", access_token),
+            "text/plain",
+            &content,
+        );
+
+        assert_eq!(response.status, Status::PayloadTooLarge);
+    }
+
+    #[test]
+    fn uploads_over_the_storage_quota_are_rejected() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let content: String = repeat("a").take(1000).collect();
+        let path = format!("/_matrix/media/r0/upload?access_token={}", access_token);
+
+        for _ in 0..2 {
+            let response = test.post_with_content_type(&path, "text/plain", &content);
+
+            assert_eq!(response.status, Status::Ok);
+        }
+
+        let response = test.post_with_content_type(&path, "text/plain", &content);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().find("errcode").and_then(Value::as_str),
+            Some("IO_RUMA_QUOTA_EXCEEDED")
+        );
+
+        // Each user has their own quota.
+        let response = test.post_with_content_type(
+            &format!("/_matrix/media/r0/upload?access_token={}", alice_token),
+            "text/plain",
+            &content,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn uploads_of_disallowed_types_are_rejected() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response = test.post_with_content_type(
+            &format!("/_matrix/media/r0/upload?access_token={}", access_token),
+            "application/x-msdownload",
+            "MZ",
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn svg_uploads_are_rejected() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response = test.post_with_content_type(
+            &format!("/_matrix/media/r0/upload?access_token={}", access_token),
+            "image/svg+xml",
+            r#"<svg xmlns="http://www.w3.org/2000/svg"><script>alert(1)</script></svg>"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn uploading_requires_an_access_token() {
+        let test = Test::new();
+
+        let response =
+            test.post_with_content_type("/_matrix/media/r0/upload", "text/plain", "Hello");
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
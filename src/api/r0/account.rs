@@ -1,22 +1,32 @@
 use bodyparser;
-use diesel::SaveChangesDsl;
+use diesel::{Connection, SaveChangesDsl};
 use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
 use iron::status::Status;
+use ruma_identifiers::RoomId;
 
+use config::Config;
 use crypto::hash_password;
 use db::DB;
+use device::Device;
 use error::ApiError;
+use membership_cache::ServerMembershipCache;
 use middleware::{
     AccessTokenAuth,
+    AccessTokenOrAppServiceAuth,
     DataTypeParam,
     JsonRequest,
     MiddlewareChain,
+    NonGuestAuth,
     RoomIdParam,
+    UIAuth,
     UserIdParam,
 };
+use modifier::SerializableResponse;
+use uia::{AuthType, Flow, InteractiveAuth};
 use user::User;
 use access_token::AccessToken;
-use room_membership::RoomMembership;
+use room_membership::{RoomMembership, RoomMembershipOptions};
+use room_tag::RoomTag;
 use account_data::{
     AccountData,
     NewAccountData,
@@ -25,18 +35,43 @@ use account_data::{
 };
 
 /// The `/account/password` endpoint.
+///
+/// The user has to confirm their current password. Unless `logout_devices` is false, every other
+/// device is logged out and deleted.
 #[derive(Debug)]
 pub struct AccountPassword;
 
 #[derive(Clone, Debug, Deserialize)]
 struct AccountPasswordRequest {
+    pub logout_devices: Option<bool>,
     pub new_password: String,
 }
 
-middleware_chain!(AccountPassword, [AccessTokenAuth]);
+middleware_chain!(
+    AccountPassword,
+    [
+        JsonRequest,
+        AccessTokenAuth,
+        NonGuestAuth,
+        UIAuth::new(InteractiveAuth::new(
+            "account_password",
+            vec![Flow::new(vec![AuthType::Password])],
+        ))
+    ]
+);
 
 impl Handler for AccountPassword {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let config = Config::from_request(request)?;
+
+        if !config.allow_password_changes {
+            let error = ApiError::unauthorized(
+                Some("Password changes are disabled on this server.")
+            );
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
         let account_password_request = match request
             .get::<bodyparser::Struct<AccountPasswordRequest>>()
         {
@@ -52,57 +87,133 @@ impl Handler for AccountPassword {
             .expect("AccessTokenAuth should ensure a user")
             .clone();
 
-        user.password_hash = hash_password(&account_password_request.new_password)?;
+        let device_id = request.extensions.get::<AccessToken>()
+            .expect("AccessTokenAuth should ensure an access token")
+            .device_id
+            .clone();
+
+        user.password_hash =
+            hash_password(&config.argon2_params, &account_password_request.new_password)?;
 
         let connection = DB::from_request(request)?;
 
-        if let Err(_) = user.save_changes::<User>(&*connection) {
-            let error = ApiError::unauthorized(None);
+        connection.transaction::<(), ApiError, _>(|| {
+            if let Err(_) = user.save_changes::<User>(&*connection) {
+                return Err(ApiError::unauthorized(None));
+            }
 
-            return Err(IronError::new(error.clone(), error));
-        }
+            if account_password_request.logout_devices.unwrap_or(true) {
+                Device::delete_others(&connection, &user.id, &device_id)?;
+            }
+
+            Ok(())
+        }).map_err(ApiError::from)?;
 
         Ok(Response::with(Status::Ok))
     }
 }
 
 /// The `/account/deactivate` endpoint.
+///
+/// The user has to confirm their password. Deactivation logs the user out everywhere, leaves
+/// every room they are in or invited to, and deletes their account data. The user ID stays taken.
 #[derive(Debug)]
 pub struct DeactivateAccount;
 
-middleware_chain!(DeactivateAccount, [AccessTokenAuth]);
+middleware_chain!(
+    DeactivateAccount,
+    [
+        JsonRequest,
+        AccessTokenAuth,
+        NonGuestAuth,
+        UIAuth::new(InteractiveAuth::new(
+            "deactivate_account",
+            vec![Flow::new(vec![AuthType::Password])],
+        ))
+    ]
+);
 
 impl Handler for DeactivateAccount {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let mut user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
         let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+        let membership_cache = ServerMembershipCache::from_request(request)?;
 
-        {
-            let token = request.extensions.get_mut::<AccessToken>()
-                .expect("AccessTokenAuth should ensure an access token");
+        let left_room_ids = connection.transaction::<Vec<RoomId>, ApiError, _>(|| {
+            let mut left_room_ids = Vec::new();
 
-            if let Err(error) = token.revoke(&connection) {
-                return Err(IronError::new(error.clone(), error));
-            };
-        }
+            for mut membership in RoomMembership::find_by_uid(&connection, user.id.clone())? {
+                if membership.membership != "join" && membership.membership != "invite" {
+                    continue;
+                }
 
-        let user = request.extensions.get_mut::<User>()
-            .expect("AccessTokenAuth should ensure a user");
+                let options = RoomMembershipOptions {
+                    room_id: membership.room_id.clone(),
+                    user_id: user.id.clone(),
+                    sender: user.id.clone(),
+                    membership: "leave".to_string(),
+                };
 
-        if let Err(error) = user.deactivate(&connection) {
-            return Err(IronError::new(error.clone(), error));
-        };
+                membership.update(&connection, &config.domain, options)?;
 
-        // Delete all the account data associated with the user.
-        AccountData::delete_by_uid(&connection, &user.id)
-            .map_err(IronError::from)?;
+                left_room_ids.push(membership.room_id);
+            }
 
-        RoomAccountData::delete_by_uid(&connection, &user.id)
-            .map_err(IronError::from)?;
+            AccessToken::revoke_by_user(&connection, &user.id)?;
+
+            user.deactivate(&connection)?;
+
+            // Delete all the account data associated with the user.
+            AccountData::delete_by_uid(&connection, &user.id)?;
+            RoomAccountData::delete_by_uid(&connection, &user.id)?;
+            RoomTag::delete_by_user(&connection, &user.id)?;
+
+            Ok(left_room_ids)
+        }).map_err(ApiError::from)?;
+
+        for room_id in &left_room_ids {
+            membership_cache.invalidate(room_id, &user.id);
+        }
 
         Ok(Response::with(Status::Ok))
     }
 }
 
+/// The GET `/account/whoami` endpoint.
+///
+/// Tells the client which user, and which device, its access token belongs to. Application
+/// services have no device.
+#[derive(Debug)]
+pub struct WhoAmI;
+
+#[derive(Debug, Serialize)]
+struct WhoAmIResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    user_id: String,
+}
+
+middleware_chain!(WhoAmI, [AccessTokenOrAppServiceAuth]);
+
+impl Handler for WhoAmI {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenOrAppServiceAuth should ensure a user");
+
+        let response = WhoAmIResponse {
+            device_id: request.extensions.get::<AccessToken>()
+                .map(|access_token| access_token.device_id.clone()),
+            user_id: user.id.to_string(),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
 /// The `/user/:user_id/account_data/:type` endpoint.
 #[derive(Debug)]
 pub struct PutAccountData;
@@ -228,6 +339,9 @@ mod tests {
     use test::Test;
     use iron::status::Status;
 
+    const DEACTIVATE: &'static str =
+        r#"{"auth": {"type": "m.login.password", "user": "carl", "password": "secret"}}"#;
+
     #[test]
     fn change_password() {
         let test = Test::new();
@@ -236,7 +350,10 @@ mod tests {
         assert!(
             test.post(
                 &format!("/_matrix/client/r0/account/password?access_token={}", access_token),
-                r#"{"new_password": "hidden"}"#
+                r#"{
+                    "new_password": "hidden",
+                    "auth": {"type": "m.login.password", "user": "carl", "password": "secret"}
+                }"#
             ).status.is_success()
         );
 
@@ -248,6 +365,131 @@ mod tests {
         )
     }
 
+    #[test]
+    fn changing_password_logs_out_other_devices() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let login = r#"{"auth": {"type": "m.login.password", "user": "carl", "password": "secret"}}"#;
+        let other_access_token = test.post("/_matrix/client/r0/login", login)
+            .json().find("access_token").unwrap().as_str().unwrap().to_string();
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/account/password?access_token={}", access_token),
+            r#"{
+                "new_password": "hidden",
+                "auth": {"type": "m.login.password", "user": "carl", "password": "secret"}
+            }"#
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let sync_path = "/_matrix/client/r0/sync?access_token=";
+
+        assert_eq!(test.get(&format!("{}{}", sync_path, access_token)).status, Status::Ok);
+        assert_eq!(
+            test.get(&format!("{}{}", sync_path, other_access_token)).status,
+            Status::Forbidden
+        );
+    }
+
+    #[test]
+    fn changing_password_can_keep_other_devices() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let login = r#"{"auth": {"type": "m.login.password", "user": "carl", "password": "secret"}}"#;
+        let other_access_token = test.post("/_matrix/client/r0/login", login)
+            .json().find("access_token").unwrap().as_str().unwrap().to_string();
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/account/password?access_token={}", access_token),
+            r#"{
+                "new_password": "hidden",
+                "logout_devices": false,
+                "auth": {"type": "m.login.password", "user": "carl", "password": "secret"}
+            }"#
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let sync_path = format!("/_matrix/client/r0/sync?access_token={}", other_access_token);
+
+        assert_eq!(test.get(&sync_path).status, Status::Ok);
+    }
+
+    #[test]
+    fn change_password_with_an_auth_session() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let path = format!("/_matrix/client/r0/account/password?access_token={}", access_token);
+
+        let response = test.post(&path, r#"{"new_password": "hidden"}"#);
+
+        assert_eq!(response.status, Status::Unauthorized);
+
+        let json = response.json();
+        let session = json.find("session").unwrap().as_str().unwrap();
+
+        assert_eq!(
+            json.find("flows").unwrap().as_array().unwrap()[0]
+                .find("stages").unwrap().as_array().unwrap()[0]
+                .as_str().unwrap(),
+            "m.login.password"
+        );
+        assert!(json.find("completed").unwrap().as_array().unwrap().is_empty());
+
+        let wrong_password = format!(
+            r#"{{
+                "new_password": "hidden",
+                "auth": {{
+                    "type": "m.login.password",
+                    "user": "carl",
+                    "password": "wrong",
+                    "session": "{}"
+                }}
+            }}"#,
+            session
+        );
+        let response = test.post(&path, &wrong_password);
+
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(response.json().find("errcode").unwrap().as_str().unwrap(), "M_FORBIDDEN");
+
+        let right_password = wrong_password.replace("wrong", "secret");
+
+        assert_eq!(test.post(&path, &right_password).status, Status::Ok);
+        assert_eq!(test.post(&path, &right_password).status, Status::BadRequest);
+    }
+
+    #[test]
+    fn auth_sessions_belong_to_one_user() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let path = format!("/_matrix/client/r0/account/password?access_token={}", access_token);
+
+        let response = test.post(&path, r#"{"new_password": "hidden"}"#);
+        let session = response.json().find("session").unwrap().as_str().unwrap().to_string();
+
+        let alice_path = format!(
+            "/_matrix/client/r0/account/password?access_token={}",
+            alice_access_token
+        );
+        let body = format!(
+            r#"{{
+                "new_password": "hidden",
+                "auth": {{
+                    "type": "m.login.password",
+                    "user": "alice",
+                    "password": "secret",
+                    "session": "{}"
+                }}
+            }}"#,
+            session
+        );
+
+        assert_eq!(test.post(&alice_path, &body).status, Status::BadRequest);
+    }
+
     #[test]
     fn deactivate_account() {
         let test = Test::new();
@@ -261,7 +503,7 @@ mod tests {
         );
 
         assert!(
-            test.post(&deactivate, r#"{}"#).status.is_success()
+            test.post(&deactivate, DEACTIVATE).status.is_success()
         );
 
         assert_eq!(
@@ -270,11 +512,68 @@ mod tests {
         );
 
         assert_eq!(
-            test.post(&deactivate, r#"{}"#).status,
+            test.post(&deactivate, DEACTIVATE).status,
             Status::Forbidden
         );
     }
 
+    #[test]
+    fn deactivate_account_requires_password() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let deactivate =
+            format!("/_matrix/client/r0/account/deactivate?access_token={}", access_token);
+
+        let response = test.post(&deactivate, r#"{}"#);
+
+        assert_eq!(response.status, Status::Unauthorized);
+        assert!(response.json().find("session").is_some());
+
+        let wrong_password =
+            r#"{"auth": {"type": "m.login.password", "user": "carl", "password": "wrong"}}"#;
+
+        assert_eq!(test.post(&deactivate, wrong_password).status, Status::Unauthorized);
+
+        let sync = format!("/_matrix/client/r0/sync?access_token={}", access_token);
+
+        assert!(test.get(&sync).status.is_success());
+    }
+
+    #[test]
+    fn deactivate_account_logs_out_and_leaves_rooms() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let other_access_token = test.post("/_matrix/client/r0/login", DEACTIVATE)
+            .json()
+            .find("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&alice_access_token);
+
+        assert_eq!(test.join_room(&access_token, &room_id).status, Status::Ok);
+
+        let deactivate =
+            format!("/_matrix/client/r0/account/deactivate?access_token={}", access_token);
+
+        assert!(test.post(&deactivate, DEACTIVATE).status.is_success());
+
+        let sync = format!("/_matrix/client/r0/sync?access_token={}", other_access_token);
+
+        assert_eq!(test.get(&sync).status, Status::Forbidden);
+
+        let members = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.member/@carl:ruma.test?access_token={}",
+            room_id,
+            alice_access_token
+        );
+        let response = test.get(&members);
+
+        assert_eq!(response.json().find("membership").unwrap().as_str().unwrap(), "leave");
+    }
+
     #[test]
     fn update_account_data() {
         let test = Test::new();
@@ -437,5 +736,57 @@ mod tests {
             "No membership entry was found."
         );
     }
-}
 
+    #[test]
+    fn whoami() {
+        let test = Test::new();
+
+        assert!(test.register_user(
+            r#"{"auth": {"type": "m.login.dummy"}, "username": "carl", "password": "secret"}"#
+        ).status.is_success());
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{
+                "auth": {"type": "m.login.password", "user": "carl", "password": "secret"},
+                "device_id": "PHONE"
+            }"#,
+        );
+        let access_token = response.json().find("access_token").unwrap().as_str().unwrap();
+
+        let response =
+            test.get(&format!("/_matrix/client/r0/account/whoami?access_token={}", access_token));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().find("user_id").unwrap().as_str().unwrap(), "@carl:ruma.test");
+        assert_eq!(response.json().find("device_id").unwrap().as_str().unwrap(), "PHONE");
+    }
+
+    #[test]
+    fn whoami_as_application_service_user() {
+        let test = Test::new();
+        test.create_access_token_with_username("bridge_alice");
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/account/whoami?user_id={}&access_token={}",
+            "@bridge_alice:ruma.test",
+            "bridge_as_token"
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().find("user_id").unwrap().as_str().unwrap(),
+            "@bridge_alice:ruma.test"
+        );
+        assert!(response.json().find("device_id").is_none());
+    }
+
+    #[test]
+    fn whoami_requires_a_valid_access_token() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/r0/account/whoami?access_token=invalid");
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
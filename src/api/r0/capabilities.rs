@@ -0,0 +1,120 @@
+//! Endpoints for the features the server supports.
+
+use std::collections::BTreeMap;
+
+use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::status::Status;
+
+use config::Config;
+use middleware::{AccessTokenAuth, MiddlewareChain};
+use modifier::SerializableResponse;
+
+/// The GET `/capabilities` endpoint.
+///
+/// Tells clients which room versions they can create rooms with, and whether users can change
+/// their passwords and third-party identifiers, as configured on the server.
+pub struct GetCapabilities;
+
+#[derive(Debug, Serialize)]
+struct GetCapabilitiesResponse {
+    capabilities: Capabilities,
+}
+
+#[derive(Debug, Serialize)]
+struct Capabilities {
+    #[serde(rename = "m.change_password")]
+    change_password: BooleanCapability,
+    #[serde(rename = "m.room_versions")]
+    room_versions: RoomVersionsCapability,
+    #[serde(rename = "m.3pid_changes")]
+    threepid_changes: BooleanCapability,
+}
+
+#[derive(Debug, Serialize)]
+struct BooleanCapability {
+    enabled: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct RoomVersionsCapability {
+    available: BTreeMap<String, &'static str>,
+    default: String,
+}
+
+middleware_chain!(GetCapabilities, [AccessTokenAuth]);
+
+impl Handler for GetCapabilities {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let config = Config::from_request(request)?;
+
+        // Every room version Ruma knows is stable.
+        let available = config.supported_room_versions
+            .iter()
+            .map(|version| (version.clone(), "stable"))
+            .collect();
+
+        let response = GetCapabilitiesResponse {
+            capabilities: Capabilities {
+                change_password: BooleanCapability {
+                    enabled: config.allow_password_changes,
+                },
+                room_versions: RoomVersionsCapability {
+                    available: available,
+                    default: config.default_room_version.clone(),
+                },
+                threepid_changes: BooleanCapability {
+                    enabled: config.allow_threepid_changes,
+                },
+            },
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    #[test]
+    fn capabilities() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response =
+            test.get(&format!("/_matrix/client/r0/capabilities?access_token={}", access_token));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let capabilities = response.json().find("capabilities").unwrap();
+
+        assert_eq!(
+            capabilities.find_path(&["m.change_password", "enabled"]).and_then(Value::as_bool),
+            Some(true)
+        );
+        assert_eq!(
+            capabilities.find_path(&["m.3pid_changes", "enabled"]).and_then(Value::as_bool),
+            Some(true)
+        );
+        assert_eq!(
+            capabilities.find_path(&["m.room_versions", "default"]).and_then(Value::as_str),
+            Some("1")
+        );
+        assert_eq!(
+            capabilities.find_path(&["m.room_versions", "available", "2"]).and_then(Value::as_str),
+            Some("stable")
+        );
+    }
+
+    #[test]
+    fn capabilities_require_an_access_token() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/r0/capabilities");
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
@@ -0,0 +1,355 @@
+//! Endpoints for the events surrounding an event.
+
+use std::cmp::min;
+use std::collections::BTreeSet;
+use std::iter::once;
+
+use iron::{Chain, Handler, IronError, IronResult, Request, Response};
+use iron::status::Status;
+use ruma_events::EventType;
+use serde_json::{Value, from_str};
+
+use access_token::AccessToken;
+use clock::ServerClock;
+use db::DB;
+use error::ApiError;
+use event::{Event, UnsignedData};
+use filter::RoomEventFilter;
+use middleware::{AccessTokenAuth, EventIdParam, MiddlewareChain, RoomIdParam};
+use modifier::SerializableResponse;
+use room_membership::RoomMembership;
+use user::User;
+
+/// The number of events returned if the client doesn't give a limit.
+const DEFAULT_LIMIT: i64 = 10;
+
+/// The most events returned around the event.
+const MAX_LIMIT: i64 = 1000;
+
+/// The GET `/rooms/:room_id/context/:event_id` endpoint.
+///
+/// Returns the event with up to `limit` events around it, split evenly before and after, and the
+/// state of the room at the newest event returned. The `start` and `end` tokens can be given to
+/// `/rooms/:room_id/messages` to keep paginating in either direction. Users who have left the
+/// room can only see the events from before they left.
+pub struct GetContext;
+
+#[derive(Debug, Serialize)]
+struct GetContextResponse {
+    end: String,
+    event: Value,
+    events_after: Vec<Value>,
+    events_before: Vec<Value>,
+    start: String,
+    state: Vec<Value>,
+}
+
+middleware_chain!(GetContext, [RoomIdParam, EventIdParam, AccessTokenAuth]);
+
+impl Handler for GetContext {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId").clone();
+
+        let event_id = request.extensions.get::<EventIdParam>()
+            .expect("EventIdParam should ensure an EventId").clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let access_token_id = request.extensions.get::<AccessToken>()
+            .expect("AccessTokenAuth should ensure an access token").id;
+
+        let url = request.url.clone().into_generic_url();
+        let mut limit = DEFAULT_LIMIT;
+        let mut filter = None;
+
+        for (key, value) in url.query_pairs() {
+            let result = match &key[..] {
+                "filter" => from_str::<RoomEventFilter>(&value)
+                    .map(|room_event_filter| filter = Some(room_event_filter))
+                    .map_err(|_| ApiError::invalid_param("filter", "Must be a valid event filter.")),
+                "limit" => match value.parse::<i64>() {
+                    Ok(value) if value >= 0 => {
+                        limit = min(value, MAX_LIMIT);
+
+                        Ok(())
+                    }
+                    _ => Err(ApiError::invalid_param("limit", "Must be a non-negative integer.")),
+                },
+                _ => Ok(()),
+            };
+
+            if let Err(error) = result {
+                return Err(IronError::new(error.clone(), error));
+            }
+        }
+
+        let clock = ServerClock::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        let visible_until = RoomMembership::visible_until(&connection, &room_id, &user.id)?;
+        let event = Event::find_in_room(&connection, &room_id, &event_id)?;
+
+        if visible_until.map(|visible_until| event.ordering > visible_until).unwrap_or(false) {
+            let error = ApiError::not_found(
+                Some(&format!("No event with ID {} was found in the room.", event_id))
+            );
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let before_limit = limit / 2;
+        let after_limit = limit - before_limit;
+
+        let events_before = if before_limit > 0 {
+            Event::find_page_by_room(
+                &connection,
+                &room_id,
+                event.ordering - 1,
+                None,
+                true,
+                before_limit,
+            )?
+        } else {
+            Vec::new()
+        };
+
+        let events_after = if after_limit > 0 {
+            Event::find_page_by_room(
+                &connection,
+                &room_id,
+                event.ordering,
+                visible_until,
+                false,
+                after_limit,
+            )?
+        } else {
+            Vec::new()
+        };
+
+        // Pagination continues from the last events examined, even if the filter removed them.
+        let start = events_before.last().map(|event| event.ordering).unwrap_or(event.ordering) - 1;
+        let end = events_after.last().map(|event| event.ordering).unwrap_or(event.ordering);
+
+        let unsigned_data = UnsignedData::load(
+            &connection,
+            once(&event).chain(&events_before).chain(&events_after),
+            Some(access_token_id),
+            clock.now_millis(),
+        )?;
+
+        let mut senders = BTreeSet::new();
+        senders.insert(event.user_id.to_string());
+
+        let events_before_json =
+            apply_filter(&events_before, filter.as_ref(), &unsigned_data, &mut senders)?;
+        let events_after_json =
+            apply_filter(&events_after, filter.as_ref(), &unsigned_data, &mut senders)?;
+
+        let lazy_load_members = filter
+            .as_ref()
+            .and_then(|filter| filter.lazy_load_members)
+            .unwrap_or(false);
+
+        let mut state_events = Vec::new();
+
+        for state_event in Event::find_state_by_room(&connection, &room_id, None, end + 1)? {
+            let is_member_event = state_event.event_type == EventType::RoomMember.to_string();
+            let is_sender = state_event.state_key
+                .as_ref()
+                .map(|state_key| senders.contains(state_key))
+                .unwrap_or(false);
+
+            if !lazy_load_members || !is_member_event || is_sender {
+                state_events.push(state_event);
+            }
+        }
+
+        let state_unsigned_data = UnsignedData::load(
+            &connection,
+            &state_events,
+            Some(access_token_id),
+            clock.now_millis(),
+        )?;
+
+        let mut state = Vec::new();
+
+        for state_event in &state_events {
+            state.push(state_event.to_client_json(&state_unsigned_data)?);
+        }
+
+        let response = GetContextResponse {
+            end: end.to_string(),
+            event: event.to_client_json(&unsigned_data)?,
+            events_after: events_after_json,
+            events_before: events_before_json,
+            start: start.to_string(),
+            state: state,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// Converts the events that pass the filter to JSON, adding their senders to `senders`.
+fn apply_filter(
+    events: &[Event],
+    filter: Option<&RoomEventFilter>,
+    unsigned_data: &UnsignedData,
+    senders: &mut BTreeSet<String>,
+) -> Result<Vec<Value>, ApiError> {
+    let mut json_events = Vec::new();
+
+    for event in events {
+        let json = event.to_client_json(unsigned_data)?;
+
+        if filter.map(|filter| filter.matches(&json)).unwrap_or(true) {
+            json_events.push(json);
+            senders.insert(event.user_id.to_string());
+        }
+    }
+
+    Ok(json_events)
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    fn send_message(test: &Test, access_token: &str, room_id: &str, txn_id: u32) -> String {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/{}?access_token={}",
+            room_id,
+            txn_id,
+            access_token
+        );
+
+        let body = format!(r#"{{"msgtype":"m.text","body":"Message {}"}}"#, txn_id);
+        let response = test.put(&path, &body);
+
+        assert_eq!(response.status, Status::Ok);
+
+        response.json().find("event_id").unwrap().as_str().unwrap().to_string()
+    }
+
+    fn bodies(events: &Value) -> Vec<String> {
+        events
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| {
+                event.find_path(&["content", "body"]).unwrap().as_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn events_around_an_event() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let mut event_ids = Vec::new();
+
+        for txn_id in 1..6 {
+            event_ids.push(send_message(&test, &access_token, &room_id, txn_id));
+        }
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/context/{}?limit=4&access_token={}",
+            room_id,
+            event_ids[2],
+            access_token
+        );
+        let response = test.get(&path);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+
+        assert_eq!(
+            json.find_path(&["event", "content", "body"]).unwrap().as_str().unwrap(),
+            "Message 3"
+        );
+        assert_eq!(bodies(json.find("events_before").unwrap()), vec!["Message 2", "Message 1"]);
+        assert_eq!(bodies(json.find("events_after").unwrap()), vec!["Message 4", "Message 5"]);
+        assert!(json.find("start").unwrap().is_string());
+        assert!(json.find("end").unwrap().is_string());
+
+        let state = json.find("state").unwrap().as_array().unwrap();
+
+        assert!(state.iter().any(|event| {
+            event.find("type").unwrap().as_str().unwrap() == "m.room.create"
+        }));
+    }
+
+    #[test]
+    fn context_continues_with_messages() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let mut event_ids = Vec::new();
+
+        for txn_id in 1..6 {
+            event_ids.push(send_message(&test, &access_token, &room_id, txn_id));
+        }
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/context/{}?limit=2&access_token={}",
+            room_id,
+            event_ids[3],
+            access_token
+        );
+        let context = test.get(&path).json().clone();
+        let start = context.find("start").unwrap().as_str().unwrap();
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from={}&dir=b&limit=1&access_token={}",
+            room_id,
+            start,
+            access_token
+        );
+        let messages = test.get(&path).json().clone();
+
+        assert_eq!(bodies(context.find("events_before").unwrap()), vec!["Message 3"]);
+        assert_eq!(bodies(messages.find("chunk").unwrap()), vec!["Message 2"]);
+    }
+
+    #[test]
+    fn unknown_event() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/context/$nonexistent:ruma.test?access_token={}",
+            room_id,
+            access_token
+        );
+
+        assert_eq!(test.get(&path).status, Status::NotFound);
+    }
+
+    #[test]
+    fn non_members_cannot_see_context() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_room(&access_token);
+        let event_id = send_message(&test, &access_token, &room_id, 1);
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/context/{}?access_token={}",
+            room_id,
+            event_id,
+            alice_access_token
+        );
+
+        assert_eq!(test.get(&path).status, Status::Forbidden);
+    }
+}
@@ -0,0 +1,353 @@
+//! Endpoints for device management.
+
+use bodyparser;
+use diesel::pg::PgConnection;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+
+use access_token::AccessToken;
+use db::DB;
+use device::Device;
+use error::ApiError;
+use middleware::{AccessTokenAuth, DeviceIdParam, JsonRequest, MiddlewareChain, UIAuth};
+use modifier::SerializableResponse;
+use uia::{AuthType, Flow, InteractiveAuth};
+use user::User;
+
+/// The GET `/devices` endpoint.
+pub struct GetDevices;
+
+/// The GET `/devices/:device_id` endpoint.
+pub struct GetDevice;
+
+/// The PUT `/devices/:device_id` endpoint.
+pub struct PutDevice;
+
+/// The DELETE `/devices/:device_id` endpoint.
+///
+/// Deleting a device logs it out, so the user has to confirm their password.
+pub struct DeleteDevice;
+
+#[derive(Debug, Serialize)]
+struct DeviceResponse {
+    device_id: String,
+    display_name: Option<String>,
+    last_seen_ip: Option<String>,
+    last_seen_ts: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct GetDevicesResponse {
+    devices: Vec<DeviceResponse>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct PutDeviceRequest {
+    display_name: Option<String>,
+}
+
+impl DeviceResponse {
+    /// Describes the device, taking the last time it was seen, and the IP address it was seen
+    /// from, from its most recently used access token.
+    fn new(device: Device, access_tokens: &[AccessToken]) -> DeviceResponse {
+        let last_used = access_tokens
+            .iter()
+            .filter(|access_token| access_token.device_id == device.device_id)
+            .filter(|access_token| access_token.last_used_at.is_some())
+            .max_by_key(|access_token| access_token.last_used_at);
+
+        DeviceResponse {
+            device_id: device.device_id,
+            display_name: device.display_name,
+            last_seen_ip: last_used.and_then(|access_token| access_token.last_used_ip.clone()),
+            last_seen_ts: last_used.and_then(|access_token| access_token.last_used_at),
+        }
+    }
+}
+
+middleware_chain!(GetDevices, [AccessTokenAuth]);
+
+impl Handler for GetDevices {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let access_tokens = AccessToken::find_by_user(&connection, &user.id)?;
+        let devices = Device::find_by_user(&connection, &user.id)?
+            .into_iter()
+            .map(|device| DeviceResponse::new(device, &access_tokens))
+            .collect();
+
+        let response = GetDevicesResponse {
+            devices: devices,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+middleware_chain!(GetDevice, [DeviceIdParam, AccessTokenAuth]);
+
+impl Handler for GetDevice {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let device_id = request.extensions.get::<DeviceIdParam>()
+            .expect("DeviceIdParam should ensure a device ID").clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let device = find_device(&connection, &user, &device_id)?;
+        let access_tokens = AccessToken::find_by_user(&connection, &user.id)?;
+
+        let response = DeviceResponse::new(device, &access_tokens);
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+middleware_chain!(PutDevice, [JsonRequest, DeviceIdParam, AccessTokenAuth]);
+
+impl Handler for PutDevice {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let device_request = match request.get::<bodyparser::Struct<PutDeviceRequest>>() {
+            Ok(Some(device_request)) => device_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let device_id = request.extensions.get::<DeviceIdParam>()
+            .expect("DeviceIdParam should ensure a device ID").clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let mut device = find_device(&connection, &user, &device_id)?;
+
+        device.set_display_name(&connection, device_request.display_name)?;
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
+middleware_chain!(
+    DeleteDevice,
+    [
+        JsonRequest,
+        DeviceIdParam,
+        AccessTokenAuth,
+        UIAuth::new(InteractiveAuth::new(
+            "delete_device",
+            vec![Flow::new(vec![AuthType::Password])],
+        ))
+    ]
+);
+
+impl Handler for DeleteDevice {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let device_id = request.extensions.get::<DeviceIdParam>()
+            .expect("DeviceIdParam should ensure a device ID").clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let device = find_device(&connection, &user, &device_id)?;
+
+        device.delete(&connection)?;
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
+/// Looks up one of the user's devices, failing with `M_NOT_FOUND` if there is no such device.
+fn find_device(connection: &PgConnection, user: &User, device_id: &str)
+-> Result<Device, IronError> {
+    match Device::find(connection, &user.id, device_id)? {
+        Some(device) => Ok(device),
+        None => {
+            let error = ApiError::not_found(Some("No device with that ID exists."));
+
+            Err(IronError::new(error.clone(), error))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use iron::method::Method;
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    /// Logs carl in from the device with the given ID and returns the new access token.
+    fn login(test: &Test, device_id: &str) -> String {
+        let body = format!(
+            r#"{{
+                "auth": {{"type": "m.login.password", "user": "carl", "password": "secret"}},
+                "device_id": "{}",
+                "initial_device_display_name": "Carl's {}"
+            }}"#,
+            device_id,
+            device_id
+        );
+
+        test.post("/_matrix/client/r0/login", &body)
+            .json()
+            .find("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn list_and_get_devices() {
+        let test = Test::new();
+        test.create_access_token();
+        let access_token = login(&test, "PHONE");
+
+        let response =
+            test.get(&format!("/_matrix/client/r0/devices?access_token={}", access_token));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+        let devices = json.find("devices").unwrap().as_array().unwrap();
+
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[1].find("device_id").unwrap().as_str().unwrap(), "PHONE");
+        assert_eq!(devices[1].find("display_name").unwrap().as_str().unwrap(), "Carl's PHONE");
+        assert!(devices[1].find("last_seen_ts").unwrap().is_number());
+        assert_eq!(devices[1].find("last_seen_ip").and_then(Value::as_str), Some("127.0.0.1"));
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/devices/PHONE?access_token={}",
+            access_token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().find("device_id").unwrap().as_str().unwrap(), "PHONE");
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/devices/UNKNOWN?access_token={}",
+            access_token
+        ));
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn last_seen_is_recorded_at_most_once_a_minute() {
+        let test = Test::new();
+        test.create_access_token();
+        let access_token = login(&test, "PHONE");
+        let path = format!("/_matrix/client/r0/devices/PHONE?access_token={}", access_token);
+
+        let last_seen_ts = |test: &Test| {
+            test.get(&path).json().find("last_seen_ts").and_then(Value::as_i64).unwrap()
+        };
+
+        let first_seen = last_seen_ts(&test);
+
+        test.advance_clock(Duration::seconds(30));
+
+        assert_eq!(last_seen_ts(&test), first_seen);
+
+        test.advance_clock(Duration::seconds(30));
+
+        assert_eq!(last_seen_ts(&test), first_seen + 60_000);
+    }
+
+    #[test]
+    fn rename_device() {
+        let test = Test::new();
+        test.create_access_token();
+        let access_token = login(&test, "PHONE");
+        let path = format!("/_matrix/client/r0/devices/PHONE?access_token={}", access_token);
+
+        assert_eq!(test.put(&path, r#"{"display_name": "Work phone"}"#).status, Status::Ok);
+
+        let response = test.get(&path);
+
+        assert_eq!(
+            response.json().find("display_name").and_then(Value::as_str),
+            Some("Work phone")
+        );
+    }
+
+    #[test]
+    fn devices_of_other_users_are_not_found() {
+        let test = Test::new();
+        test.create_access_token();
+        login(&test, "PHONE");
+        let alice_token = test.create_access_token_with_username("alice");
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/devices/PHONE?access_token={}",
+            alice_token
+        ));
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn deleting_a_device_revokes_its_access_token() {
+        let test = Test::new();
+        let laptop_token = test.create_access_token();
+        let phone_token = login(&test, "PHONE");
+        let path = format!("/_matrix/client/r0/devices/PHONE?access_token={}", laptop_token);
+
+        let response = test.request(
+            Method::Delete,
+            &path,
+            r#"{"auth": {"type": "m.login.password", "user": "carl", "password": "wrong"}}"#,
+        );
+
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(response.json().find("errcode").unwrap().as_str().unwrap(), "M_FORBIDDEN");
+
+        let response = test.request(
+            Method::Delete,
+            &path,
+            r#"{"auth": {"type": "m.login.password", "user": "carl", "password": "secret"}}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let sync_path = "/_matrix/client/r0/sync?access_token=";
+
+        assert_eq!(test.get(&format!("{}{}", sync_path, phone_token)).status, Status::Forbidden);
+        assert_eq!(test.get(&format!("{}{}", sync_path, laptop_token)).status, Status::Ok);
+        assert_eq!(test.get(&path).status, Status::NotFound);
+    }
+
+    #[test]
+    fn deleting_a_device_with_another_users_password_fails() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let _ = test.create_access_token_with_username("alice");
+        let path = format!("/_matrix/client/r0/devices/PHONE?access_token={}", access_token);
+
+        login(&test, "PHONE");
+
+        let response = test.request(
+            Method::Delete,
+            &path,
+            r#"{"auth": {"type": "m.login.password", "user": "alice", "password": "secret"}}"#,
+        );
+
+        assert_eq!(response.status, Status::Unauthorized);
+    }
+}
@@ -5,13 +5,24 @@ use std::convert::TryFrom;
 use bodyparser;
 use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
 use iron::status::Status;
+use ruma_events::EventType;
 use ruma_identifiers::RoomId;
 
 use config::Config;
 use db::DB;
 use error::ApiError;
-use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, RoomAliasIdParam};
+use membership_cache::ServerMembershipCache;
+use middleware::{
+    AccessTokenAuth,
+    JsonRequest,
+    MiddlewareChain,
+    NonGuestAuth,
+    RoomAliasIdParam,
+    RoomIdParam,
+};
 use modifier::SerializableResponse;
+use power_levels::ServerPowerLevelsCache;
+use room::Room;
 use room_alias::{RoomAlias, NewRoomAlias};
 use user::User;
 
@@ -45,9 +56,12 @@ impl Handler for GetRoomAlias {
 }
 
 /// The DELETE `/directory/room/:room_alias` endpoint.
+///
+/// An alias can be deleted by the user who created it, by server admins, and by members of the
+/// room with the power to change its `m.room.aliases` state.
 pub struct DeleteRoomAlias;
 
-middleware_chain!(DeleteRoomAlias, [RoomAliasIdParam, AccessTokenAuth]);
+middleware_chain!(DeleteRoomAlias, [RoomAliasIdParam, AccessTokenAuth, NonGuestAuth]);
 
 impl Handler for DeleteRoomAlias {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
@@ -58,18 +72,74 @@ impl Handler for DeleteRoomAlias {
             .expect("AccessTokenAuth should ensure a user").clone();
 
         let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+        let membership_cache = ServerMembershipCache::from_request(request)?;
+        let power_levels_cache = ServerPowerLevelsCache::from_request(request)?;
 
-        let affected_rows = RoomAlias::delete(&connection, &room_alias_id, &user.id)?;
+        let room_alias = RoomAlias::find_by_alias(&connection, &room_alias_id)?;
 
-        if affected_rows > 0 {
-            Ok(Response::with((Status::Ok, "{}")))
-        } else {
-            let error = ApiError::not_found(Some(
-                "Provided room alias did not exist or you do not have access to delete it."
-            ));
+        if room_alias.user_id != user.id && !config.admins.contains(&user.id) {
+            let room = Room::find(&connection, &room_alias.room_id)?;
+
+            room.ensure_can_send(
+                &connection,
+                &membership_cache,
+                &power_levels_cache,
+                &user.id,
+                &EventType::RoomAliases,
+                true,
+            ).map_err(|_| {
+                ApiError::unauthorized(Some("You do not have permission to delete this alias."))
+            })?;
+        }
+
+        room_alias.delete(&connection, &config.domain, &user.id)?;
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
+/// The GET `/rooms/:room_id/aliases` endpoint.
+///
+/// Lists the aliases this server has for the room. Only members of the room can see them.
+pub struct GetRoomAliases;
+
+#[derive(Debug, Serialize)]
+struct GetRoomAliasesResponse {
+    aliases: Vec<String>,
+}
+
+middleware_chain!(GetRoomAliases, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for GetRoomAliases {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId").clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+        let membership_cache = ServerMembershipCache::from_request(request)?;
 
-            Err(IronError::new(error.clone(), error))
+        Room::find(&connection, &room_id)?;
+
+        if !membership_cache.is_joined(&connection, &room_id, &user.id)? {
+            let error = ApiError::unauthorized(Some("You must join the room to see its aliases."));
+
+            return Err(IronError::new(error.clone(), error));
         }
+
+        let aliases = RoomAlias::find_by_room_id(&connection, &room_id)?
+            .into_iter()
+            .map(|room_alias| room_alias.alias.to_string())
+            .collect();
+
+        let response = GetRoomAliasesResponse {
+            aliases: aliases,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
     }
 }
 
@@ -81,7 +151,7 @@ struct PutRoomAliasRequest {
     pub room_id: String,
 }
 
-middleware_chain!(PutRoomAlias, [JsonRequest, RoomAliasIdParam, AccessTokenAuth]);
+middleware_chain!(PutRoomAlias, [JsonRequest, RoomAliasIdParam, AccessTokenAuth, NonGuestAuth]);
 
 impl Handler for PutRoomAlias {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
@@ -117,6 +187,87 @@ impl Handler for PutRoomAlias {
     }
 }
 
+/// The GET `/directory/list/room/:room_id` endpoint.
+pub struct GetRoomVisibility;
+
+/// The PUT `/directory/list/room/:room_id` endpoint.
+///
+/// Only users who may change the room's join rules, or server admins, can list or unlist it.
+pub struct PutRoomVisibility;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct RoomVisibility {
+    visibility: String,
+}
+
+middleware_chain!(GetRoomVisibility, [RoomIdParam]);
+
+impl Handler for GetRoomVisibility {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let room = Room::find(&connection, &room_id)?;
+
+        let response = RoomVisibility {
+            visibility: if room.public { "public" } else { "private" }.to_string(),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+middleware_chain!(PutRoomVisibility, [JsonRequest, RoomIdParam, AccessTokenAuth, NonGuestAuth]);
+
+impl Handler for PutRoomVisibility {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let public = match request.get::<bodyparser::Struct<RoomVisibility>>() {
+            Ok(Some(ref visibility_request)) if visibility_request.visibility == "public" => true,
+            Ok(Some(ref visibility_request)) if visibility_request.visibility == "private" => false,
+            Ok(Some(_)) => {
+                let error = ApiError::invalid_param("visibility", "Must be public or private.");
+
+                return Err(IronError::new(error.clone(), error));
+            }
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId").clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+        let membership_cache = ServerMembershipCache::from_request(request)?;
+        let power_levels_cache = ServerPowerLevelsCache::from_request(request)?;
+
+        let mut room = Room::find(&connection, &room_id)?;
+
+        if !config.admins.contains(&user.id) {
+            room.ensure_can_send(
+                &connection,
+                &membership_cache,
+                &power_levels_cache,
+                &user.id,
+                &EventType::RoomJoinRules,
+                true,
+            )?;
+        }
+
+        room.set_public(&connection, public)?;
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use test::Test;
@@ -203,9 +354,122 @@ mod tests {
 
         let response = test.delete(&delete_room_path);
 
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = test.get("/_matrix/client/r0/directory/room/my_room");
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn delete_unknown_room_alias() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let delete_room_path = format!(
+            "/_matrix/client/r0/directory/room/no_room?access_token={}",
+            access_token
+        );
+
+        assert_eq!(test.delete(&delete_room_path).status, Status::NotFound);
+    }
+
+    #[test]
+    fn delete_room_alias_as_room_moderator() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&access_token);
+
+        assert_eq!(test.join_room(&alice_access_token, &room_id).status, Status::Ok);
+
+        let alice_path = format!(
+            "/_matrix/client/r0/directory/room/alices_room?access_token={}",
+            alice_access_token
+        );
+        let response = test.put(&alice_path, &format!(r#"{{"room_id": "{}"}}"#, room_id));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let delete_room_path = format!(
+            "/_matrix/client/r0/directory/room/alices_room?access_token={}",
+            access_token
+        );
+
+        assert_eq!(test.delete(&delete_room_path).status, Status::Ok);
+
+        let response = test.get("/_matrix/client/r0/directory/room/alices_room");
+
         assert_eq!(response.status, Status::NotFound);
     }
 
+    #[test]
+    fn delete_room_alias_as_server_admin() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let admin_access_token = test.create_access_token_with_username("admin");
+
+        let create_room_path = format!(
+            "/_matrix/client/r0/createRoom?access_token={}",
+            access_token
+        );
+
+        test.post(&create_room_path, r#"{"room_alias_name": "my_room"}"#);
+
+        let delete_room_path = format!(
+            "/_matrix/client/r0/directory/room/my_room?access_token={}",
+            admin_access_token
+        );
+
+        assert_eq!(test.delete(&delete_room_path).status, Status::Ok);
+    }
+
+    #[test]
+    fn get_room_aliases() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+
+        let create_room_path = format!(
+            "/_matrix/client/r0/createRoom?access_token={}",
+            access_token
+        );
+        let response = test.post(&create_room_path, r#"{"room_alias_name": "my_room"}"#);
+        let room_id = response.json().find("room_id").unwrap().as_str().unwrap().to_string();
+
+        let put_room_alias_path = format!(
+            "/_matrix/client/r0/directory/room/other_room?access_token={}", access_token
+        );
+        let response = test.put(&put_room_alias_path, &format!(r#"{{"room_id": "{}"}}"#, room_id));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let aliases_path = format!(
+            "/_matrix/client/r0/rooms/{}/aliases?access_token={}",
+            room_id,
+            access_token
+        );
+        let response = test.get(&aliases_path);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let mut aliases: Vec<&str> = response.json().find("aliases").unwrap().as_array().unwrap()
+            .iter()
+            .map(|alias| alias.as_str().unwrap())
+            .collect();
+        aliases.sort();
+
+        assert_eq!(aliases, vec!["#my_room:ruma.test", "#other_room:ruma.test"]);
+
+        let alice_aliases_path = format!(
+            "/_matrix/client/r0/rooms/{}/aliases?access_token={}",
+            room_id,
+            alice_access_token
+        );
+
+        assert_eq!(test.get(&alice_aliases_path).status, Status::Forbidden);
+    }
+
     #[test]
     fn put_room_alias() {
         let test = Test::new();
@@ -262,4 +526,47 @@ mod tests {
             "IO_RUMA_ALIAS_TAKEN"
         );
     }
+
+    #[test]
+    fn room_visibility_can_be_changed_by_moderators() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&access_token);
+        let get_path = format!("/_matrix/client/r0/directory/list/room/{}", room_id);
+
+        assert_eq!(test.join_room(&alice_access_token, &room_id).status, Status::Ok);
+        assert_eq!(
+            test.get(&get_path).json().find("visibility").unwrap().as_str().unwrap(),
+            "public"
+        );
+
+        let alice_path = format!("{}?access_token={}", get_path, alice_access_token);
+        let response = test.put(&alice_path, r#"{"visibility": "private"}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+
+        let put_path = format!("{}?access_token={}", get_path, access_token);
+        let response = test.put(&put_path, r#"{"visibility": "private"}"#);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            test.get(&get_path).json().find("visibility").unwrap().as_str().unwrap(),
+            "private"
+        );
+    }
+
+    #[test]
+    fn invalid_room_visibility() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let path = format!(
+            "/_matrix/client/r0/directory/list/room/{}?access_token={}",
+            room_id,
+            access_token
+        );
+
+        assert_eq!(test.put(&path, r#"{"visibility": "hidden"}"#).status, Status::BadRequest);
+    }
 }
@@ -1,99 +1,56 @@
 //! Endpoints for creating events.
 
-use std::convert::TryInto;
-
 use bodyparser;
-use diesel::{Connection, ExecuteDsl, FindDsl, LoadDsl, insert};
-use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response, status};
+use diesel::Connection;
+use diesel::pg::PgConnection;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response, status};
 use router::Router;
-use ruma_events::call::answer::AnswerEvent;
-use ruma_events::call::candidates::CandidatesEvent;
-use ruma_events::call::hangup::HangupEvent;
-use ruma_events::call::invite::InviteEvent;
-use ruma_events::room::avatar::AvatarEvent;
-use ruma_events::room::canonical_alias::CanonicalAliasEvent;
-use ruma_events::room::guest_access::GuestAccessEvent;
-use ruma_events::room::history_visibility::HistoryVisibilityEvent;
-use ruma_events::room::join_rules::JoinRulesEvent;
-use ruma_events::room::message::MessageEvent;
-use ruma_events::room::name::NameEvent;
-use ruma_events::room::power_levels::PowerLevelsEvent;
-use ruma_events::room::third_party_invite::ThirdPartyInviteEvent;
-use ruma_events::room::topic::TopicEvent;
-use ruma_events::{CustomRoomEvent, CustomStateEvent, EventType};
+use ruma_events::EventType;
 use ruma_identifiers::EventId;
-use serde::Deserialize;
-use serde_json::{Value, from_value};
+use serde_json::{Value, from_str};
 
+use access_token::AccessToken;
+use app_service::AppService;
+use clock::ServerClock;
 use db::DB;
 use config::Config;
+use crypto::ServerEntropy;
+use delayed_event::{DelayedEvent, MAX_DELAY, NewDelayedEvent};
 use error::{ApiError, MapApiError};
-use event::NewEvent;
+use event::{Event, NewEvent};
+use membership_cache::ServerMembershipCache;
 use middleware::{
-    AccessTokenAuth,
+    AccessTokenOrAppServiceAuth,
     EventTypeParam,
     JsonRequest,
     MiddlewareChain,
+    NonGuestAuth,
     RoomIdParam,
     TransactionIdParam,
 };
 use modifier::SerializableResponse;
+use power_levels::ServerPowerLevelsCache;
 use room::Room;
-use schema::{events, rooms};
+use transaction::{NewTransaction, Transaction};
 use user::User;
 
-macro_rules! room_event {
-    (
-        $ty:ident,
-        $event_content:ident,
-        $event_type:ident,
-        $event_id:ident,
-        $room_id:ident,
-        $user:ident
-    ) => {
-        $ty {
-            content: extract_event_content($event_content, &$event_type)?,
-            event_id: $event_id.clone(),
-            event_type: $event_type.clone(),
-            room_id: $room_id.clone(),
-            unsigned: None,
-            user_id: $user.id.clone(),
-        }.try_into().map_err(ApiError::from)?
-    };
-}
-
-macro_rules! state_event {
-    (
-        $ty:ident,
-        $event_content:ident,
-        $event_type:ident,
-        $event_id:ident,
-        $room_id:ident,
-        $state_key:ident,
-        $user:ident
-    ) => {
-        $ty {
-            content: extract_event_content($event_content, &$event_type)?,
-            event_id: $event_id.clone(),
-            event_type: $event_type.clone(),
-            prev_content: None,
-            room_id: $room_id.clone(),
-            state_key: $state_key.to_string(),
-            unsigned: None,
-            user_id: $user.id.clone(),
-        }.try_into().map_err(ApiError::from)?
-    };
-}
-
 #[derive(Debug, Serialize)]
 struct EventResponse {
     event_id: String,
 }
 
+#[derive(Debug, Serialize)]
+struct DelayedEventResponse {
+    delay_id: String,
+}
+
 /// The `/rooms/:room_id/send/:event_type/:transaction_id` endpoint.
 pub struct SendMessageEvent;
 
-middleware_chain!(SendMessageEvent, [JsonRequest, RoomIdParam, EventTypeParam, TransactionIdParam, AccessTokenAuth]);
+middleware_chain!(
+    SendMessageEvent,
+    [JsonRequest, RoomIdParam, EventTypeParam, TransactionIdParam, AccessTokenOrAppServiceAuth]
+);
 
 impl Handler for SendMessageEvent {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
@@ -103,11 +60,17 @@ impl Handler for SendMessageEvent {
         let event_type = request.extensions.get::<EventTypeParam>()
             .expect("EventTypeParam should ensure an EventType").clone();
 
-        let _transaction_id = request.extensions.get::<TransactionIdParam>()
+        let transaction_id = request.extensions.get::<TransactionIdParam>()
             .expect("TransactionIdParam should ensure a TransactionId").clone();
 
         let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+            .expect("AccessTokenOrAppServiceAuth should ensure a user").clone();
+
+        let access_token_id = request.extensions.get::<AccessToken>()
+            .map(|access_token| access_token.id);
+
+        let app_service_id = request.extensions.get::<AppService>()
+            .map(|app_service| app_service.id.clone());
 
         let event_content = request
             .get::<bodyparser::Json>()
@@ -118,65 +81,73 @@ impl Handler for SendMessageEvent {
             ApiError::unknown(Some("Failed to generated event ID for the new event."))
         })?;
 
-        let room_event: NewEvent = match event_type {
-            EventType::CallAnswer => {
-                room_event!(AnswerEvent, event_content, event_type, event_id, room_id, user)
-            }
-            EventType::CallCandidates => {
-                room_event!(CandidatesEvent, event_content, event_type, event_id, room_id, user)
-            }
-            EventType::CallHangup => {
-                room_event!(HangupEvent, event_content, event_type, event_id, room_id, user)
-            }
-            EventType::CallInvite => {
-                room_event!(InviteEvent, event_content, event_type, event_id, room_id, user)
-            }
-            EventType::RoomMessage => {
-                room_event!(MessageEvent, event_content, event_type, event_id, room_id, user)
-            }
-            EventType::Custom(ref custom_event_type) => {
-                CustomRoomEvent {
-                    content: event_content,
-                    event_id: event_id.clone(),
-                    event_type: EventType::Custom(custom_event_type.clone()),
-                    room_id: room_id.clone(),
-                    unsigned: None,
-                    user_id: user.id.clone(),
-                }.try_into().map_err(ApiError::from)?
-            }
-            _ => {
-                let error = ApiError::bad_event(
-                    Some(&format!("Events of type {} cannot be created with this API.", event_type))
-                );
+        let room_event = NewEvent::message_event(
+            event_id.clone(),
+            room_id.clone(),
+            user.id,
+            event_type,
+            event_content,
+        )?;
+
+        let origin_server_ts = origin_server_ts_param(request)?;
+
+        if let Some(delay) = delay_param(request)? {
+            return schedule_event(request, room_event, delay);
+        }
 
-                return Err(IronError::new(error.clone(), error));
+        let clock = ServerClock::from_request(request)?;
+        let connection = DB::from_request(request)?;
+        let membership_cache = ServerMembershipCache::from_request(request)?;
+        let power_levels_cache = ServerPowerLevelsCache::from_request(request)?;
+
+        // Clients retry requests they didn't get a response to, so a reused transaction ID gets
+        // the original event back instead of sending it again.
+        let previous_transaction = match (access_token_id, app_service_id.as_ref()) {
+            (_, Some(app_service_id)) => {
+                Transaction::find_for_app_service(&connection, app_service_id, &transaction_id)?
+            }
+            (Some(access_token_id), None) => {
+                Transaction::find(&connection, access_token_id, &transaction_id)?
             }
+            (None, None) => unreachable!("AccessTokenOrAppServiceAuth should ensure a token"),
         };
 
-        let connection = DB::from_request(request)?;
+        if let Some(transaction) = previous_transaction {
+            let response = EventResponse {
+                event_id: transaction.event_id.opaque_id().to_string(),
+            };
+
+            return Ok(Response::with((status::Ok, SerializableResponse(response))));
+        }
+
+        let room = Room::find(&connection, &room_id)?;
 
-        connection.transaction(|| {
-            let room = rooms::table.find(room_id.to_string()).first::<Room>(&*connection)?;
-            let power_levels = room.current_power_levels(&*connection)?;
-            let user_power_level = power_levels
-                .users
-                .get(&user.id)
-                .unwrap_or(&power_levels.users_default);
-            let required_power_level = power_levels
-                .events
-                .get(&event_type)
-                .unwrap_or(&power_levels.events_default);
-
-            if required_power_level > user_power_level {
-                return Err(
-                    ApiError::unauthorized(Some("Insufficient power level to create this event."))
-                );
+        ensure_encrypted_if_required(&connection, &config, &room, &room_event)?;
+        ensure_within_relation_limits(&connection, &config, &room_event)?;
+
+        connection.transaction::<(), ApiError, _>(|| {
+            room.send_event(
+                &connection,
+                &membership_cache,
+                &power_levels_cache,
+                &room_event,
+                clock.now_millis(),
+            )?;
+
+            if let Some(origin_server_ts) = origin_server_ts {
+                Event::set_origin_server_ts(&connection, &event_id, origin_server_ts)?;
             }
 
-            insert(&room_event)
-                .into(events::table)
-                .execute(&*connection)
-                .map_err(ApiError::from)
+            let new_transaction = NewTransaction {
+                access_token_id: access_token_id,
+                app_service_id: app_service_id.clone(),
+                transaction_id: transaction_id.clone(),
+                event_id: event_id.clone(),
+            };
+
+            Transaction::create(&connection, &new_transaction)?;
+
+            Ok(())
         }).map_err(ApiError::from)?;
 
         let response = EventResponse {
@@ -191,7 +162,10 @@ impl Handler for SendMessageEvent {
 /// endpoints.
 pub struct StateMessageEvent;
 
-middleware_chain!(StateMessageEvent, [JsonRequest, RoomIdParam, EventTypeParam, AccessTokenAuth]);
+middleware_chain!(
+    StateMessageEvent,
+    [JsonRequest, RoomIdParam, EventTypeParam, AccessTokenOrAppServiceAuth, NonGuestAuth]
+);
 
 impl Handler for StateMessageEvent {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
@@ -209,7 +183,7 @@ impl Handler for StateMessageEvent {
             .unwrap_or("");
 
         let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+            .expect("AccessTokenOrAppServiceAuth should ensure a user").clone();
 
         let event_content = request
             .get::<bodyparser::Json>()
@@ -220,167 +194,42 @@ impl Handler for StateMessageEvent {
             ApiError::unknown(Some("Failed to generated event ID for the new event."))
         })?;
 
-        let state_event: NewEvent = match event_type {
-            EventType::RoomAvatar => {
-                ensure_empty_state_key(state_key)?;
+        let state_event = NewEvent::state_event(
+            event_id.clone(),
+            room_id.clone(),
+            user.id,
+            event_type,
+            state_key,
+            event_content,
+        )?;
 
-                state_event!(
-                    AvatarEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomCanonicalAlias => {
-                ensure_empty_state_key(state_key)?;
-
-                state_event!(
-                    CanonicalAliasEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomGuestAccess => {
-                ensure_empty_state_key(state_key)?;
-
-                state_event!(
-                    GuestAccessEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomHistoryVisibility => {
-                ensure_empty_state_key(state_key)?;
-
-                state_event!(
-                    HistoryVisibilityEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomJoinRules => {
-                ensure_empty_state_key(state_key)?;
-
-                state_event!(
-                    JoinRulesEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomName => {
-                ensure_empty_state_key(state_key)?;
-
-                state_event!(
-                    NameEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomPowerLevels => {
-                ensure_empty_state_key(state_key)?;
-
-                state_event!(
-                    PowerLevelsEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomThirdPartyInvite => {
-                state_event!(
-                    ThirdPartyInviteEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomTopic => {
-                ensure_empty_state_key(state_key)?;
-
-                state_event!(
-                    TopicEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::Custom(ref custom_event_type) => {
-                CustomStateEvent {
-                    content: event_content,
-                    event_id: event_id.clone(),
-                    event_type: EventType::Custom(custom_event_type.clone()),
-                    prev_content: None,
-                    room_id: room_id.clone(),
-                    state_key: state_key.to_string(),
-                    unsigned: None,
-                    user_id: user.id.clone(),
-                }.try_into().map_err(ApiError::from)?
-            }
-            _ => {
-                let error = ApiError::bad_event(
-                    Some(&format!("Events of type {} cannot be created with this API.", event_type))
-                );
+        let origin_server_ts = origin_server_ts_param(request)?;
 
-                return Err(IronError::new(error.clone(), error));
-            }
-        };
+        if let Some(delay) = delay_param(request)? {
+            return schedule_event(request, state_event, delay);
+        }
 
+        let clock = ServerClock::from_request(request)?;
         let connection = DB::from_request(request)?;
-
-        connection.transaction(|| {
-            let room = rooms::table.find(room_id.to_string()).first::<Room>(&*connection)?;
-            let power_levels = room.current_power_levels(&*connection)?;
-            let user_power_level = power_levels
-                .users
-                .get(&user.id)
-                .unwrap_or(&power_levels.users_default);
-            let required_power_level = power_levels
-                .events
-                .get(&event_type)
-                .unwrap_or(&power_levels.state_default);
-
-            if required_power_level > user_power_level {
-                return Err(
-                    ApiError::unauthorized(Some("Insufficient power level to create this event."))
-                );
+        let membership_cache = ServerMembershipCache::from_request(request)?;
+        let power_levels_cache = ServerPowerLevelsCache::from_request(request)?;
+
+        let room = Room::find(&connection, &room_id)?;
+
+        connection.transaction::<(), ApiError, _>(|| {
+            room.send_event(
+                &connection,
+                &membership_cache,
+                &power_levels_cache,
+                &state_event,
+                clock.now_millis(),
+            )?;
+
+            if let Some(origin_server_ts) = origin_server_ts {
+                Event::set_origin_server_ts(&connection, &event_id, origin_server_ts)?;
             }
 
-            insert(&state_event)
-                .into(events::table)
-                .execute(&*connection)
-                .map_err(ApiError::from)
+            Ok(())
         }).map_err(ApiError::from)?;
 
         let response = EventResponse {
@@ -391,35 +240,173 @@ impl Handler for StateMessageEvent {
     }
 }
 
-/// Enforces an empty state key for an event type that requires it.
-fn ensure_empty_state_key(state_key: &str) -> Result<(), IronError> {
-    if state_key == "" {
-        Ok(())
-    } else {
-        let error = ApiError::bad_event(Some("Events of type {} must have an empty state key."));
+/// Extracts the optional `delay` query parameter, in milliseconds.
+fn delay_param(request: &Request) -> Result<Option<i64>, ApiError> {
+    let url = request.url.clone().into_generic_url();
+    let mut query_pairs = url.query_pairs();
+
+    match query_pairs.find(|&(ref key, _)| key == "delay") {
+        Some((_, ref value)) => match value.parse::<i64>() {
+            Ok(delay) if delay >= 0 && delay <= MAX_DELAY => Ok(Some(delay)),
+            _ => Err(ApiError::invalid_param(
+                "delay",
+                &format!("Must be a number of milliseconds between 0 and {}.", MAX_DELAY),
+            )),
+        },
+        None => Ok(None),
+    }
+}
 
-        Err(IronError::new(error.clone(), error))
+/// Extracts the optional `ts` query parameter, the time in milliseconds since the Unix epoch an
+/// application service says the event was sent, e.g. on the network it bridges. Only application
+/// services can choose the time; the parameter is ignored for everyone else.
+fn origin_server_ts_param(request: &Request) -> Result<Option<i64>, ApiError> {
+    if request.extensions.get::<AppService>().is_none() {
+        return Ok(None);
     }
+
+    let url = request.url.clone().into_generic_url();
+    let mut query_pairs = url.query_pairs();
+
+    let origin_server_ts = match query_pairs.find(|&(ref key, _)| key == "ts") {
+        Some((_, ref value)) => match value.parse::<i64>() {
+            Ok(origin_server_ts) if origin_server_ts >= 0 => origin_server_ts,
+            _ => {
+                return Err(ApiError::invalid_param(
+                    "ts",
+                    "Must be a number of milliseconds since the Unix epoch.",
+                ));
+            }
+        },
+        None => return Ok(None),
+    };
+
+    if url.query_pairs().any(|(key, _)| key == "delay") {
+        return Err(ApiError::invalid_param("ts", "Can't be used with delay."));
+    }
+
+    Ok(Some(origin_server_ts))
 }
 
-/// Convert the JSON from the request into the correct type for the event's `content` field.
-fn extract_event_content<T: Deserialize>(event_content: Value, event_type: &EventType)
--> Result<T, ApiError> {
-    from_value(event_content).map_api_err(|_| {
-        ApiError::bad_event(
-            Some(
-                &format!(
-                    "Event content did not match expected structure for event of type {}.",
-                    event_type
-                )
-            )
-        )
-    })
+/// Schedules an event to be sent after `delay` milliseconds instead of sending it now.
+fn schedule_event(request: &mut Request, new_event: NewEvent, delay: i64)
+-> IronResult<Response> {
+    let clock = ServerClock::from_request(request)?;
+    let config = Config::from_request(request)?;
+    let entropy = ServerEntropy::from_request(request)?;
+    let connection = DB::from_request(request)?;
+    let membership_cache = ServerMembershipCache::from_request(request)?;
+    let power_levels_cache = ServerPowerLevelsCache::from_request(request)?;
+
+    let room = Room::find(&connection, &new_event.room_id)?;
+
+    ensure_encrypted_if_required(&connection, &config, &room, &new_event)?;
+
+    room.ensure_can_send(
+        &*connection,
+        &membership_cache,
+        &power_levels_cache,
+        &new_event.user_id,
+        &EventType::from(&new_event.event_type[..]),
+        new_event.state_key.is_some(),
+    )?;
+
+    let delayed_event = DelayedEvent::create(&*connection, &NewDelayedEvent {
+        id: entropy.alphanumeric(24),
+        room_id: new_event.room_id,
+        user_id: new_event.user_id,
+        event_type: new_event.event_type,
+        state_key: new_event.state_key,
+        content: new_event.content,
+        delay: delay,
+        send_at: clock.now_millis() + delay,
+    })?;
+
+    let response = DelayedEventResponse {
+        delay_id: delayed_event.id,
+    };
+
+    Ok(Response::with((status::Ok, SerializableResponse(response))))
+}
+
+/// Refuses plaintext *m.room.message* events in a room that has enabled encryption, unless the
+/// server is configured to allow them, so that a misbehaving client can't leak the conversation.
+fn ensure_encrypted_if_required(
+    connection: &PgConnection,
+    config: &Config,
+    room: &Room,
+    new_event: &NewEvent,
+) -> Result<(), ApiError> {
+    if !config.reject_unencrypted_messages ||
+        new_event.event_type != EventType::RoomMessage.to_string() {
+        return Ok(());
+    }
+
+    if room.is_encrypted(connection)? {
+        return Err(ApiError::bad_event(
+            Some("This room is encrypted, so messages must be sent as m.room.encrypted events.")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Refuses an event that relates to another through `m.relates_to` once the other event has as
+/// many relations as the server allows, or, for a reaction, as many reactions from the sender.
+fn ensure_within_relation_limits(
+    connection: &PgConnection,
+    config: &Config,
+    new_event: &NewEvent,
+) -> Result<(), ApiError> {
+    let content: Value = from_str(&new_event.content)?;
+
+    let relates_to = content.find_path(&["m.relates_to", "event_id"]).and_then(Value::as_str);
+
+    let relates_to = match relates_to {
+        Some(relates_to) => relates_to,
+        None => return Ok(()),
+    };
+
+    if let Some(max_relations) = config.max_relations_per_event {
+        let relations = Event::count_relations(connection, &new_event.room_id, relates_to)?;
+
+        if relations as u64 >= max_relations {
+            return Err(ApiError::relation_limit_exceeded(Some(&format!(
+                "Event {} already has the most relations allowed, {}.",
+                relates_to,
+                max_relations
+            ))));
+        }
+    }
+
+    let rel_type = content.find_path(&["m.relates_to", "rel_type"]).and_then(Value::as_str);
+
+    if let (Some("m.annotation"), Some(max_reactions)) = (rel_type, config.max_reactions_per_user) {
+        let reactions = Event::count_reactions_by_user(
+            connection,
+            &new_event.room_id,
+            relates_to,
+            &new_event.user_id,
+        )?;
+
+        if reactions as u64 >= max_reactions {
+            return Err(ApiError::relation_limit_exceeded(Some(&format!(
+                "Users may react to an event at most {} times.",
+                max_reactions
+            ))));
+        }
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use test::Test;
+    use std::iter::repeat;
+
+    use iron::status::Status;
+
+    use test::{Response, Test};
 
     #[test]
     fn create_message_event() {
@@ -438,6 +425,77 @@ mod tests {
         assert!(response.json().find("event_id").unwrap().as_str().is_some());
     }
 
+    #[test]
+    fn retried_transaction_is_not_sent_twice() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let create_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/1?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let first = test.put(&create_event_path, r#"{"body":"Hi","msgtype":"m.text"}"#);
+        let second = test.put(&create_event_path, r#"{"body":"Hi","msgtype":"m.text"}"#);
+
+        assert_eq!(second.status, Status::Ok);
+        assert_eq!(
+            first.json().find("event_id").unwrap().as_str().unwrap(),
+            second.json().find("event_id").unwrap().as_str().unwrap()
+        );
+
+        let sync_path = format!("/_matrix/client/r0/sync?access_token={}", access_token);
+        let sync = test.get(&sync_path);
+        let timeline = sync
+            .json()
+            .find_path(&["rooms", "join", &room_id[..], "timeline", "events"])
+            .unwrap()
+            .as_array()
+            .unwrap();
+        let messages = timeline
+            .iter()
+            .filter(|event| event.find("type").unwrap().as_str().unwrap() == "m.room.message")
+            .count();
+
+        assert_eq!(messages, 1);
+    }
+
+    #[test]
+    fn transaction_ids_are_scoped_to_the_access_token() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let login = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"auth": {"type": "m.login.password", "user": "carl", "password": "secret"}}"#,
+        );
+        let other_access_token = login.json().find("access_token").unwrap().as_str().unwrap();
+
+        let first = test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/send/m.room.message/1?access_token={}",
+                room_id,
+                access_token
+            ),
+            r#"{"body":"Hi","msgtype":"m.text"}"#,
+        );
+        let second = test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/send/m.room.message/1?access_token={}",
+                room_id,
+                other_access_token
+            ),
+            r#"{"body":"Hi","msgtype":"m.text"}"#,
+        );
+
+        assert_ne!(
+            first.json().find("event_id").unwrap().as_str().unwrap(),
+            second.json().find("event_id").unwrap().as_str().unwrap()
+        );
+    }
+
     #[test]
     fn event_content_does_not_match_event_type() {
         let test = Test::new();
@@ -500,4 +558,530 @@ mod tests {
 
         assert!(response.json().find("event_id").unwrap().as_str().is_some());
     }
+
+    #[test]
+    fn create_state_event() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let state_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.name?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.put(&state_event_path, r#"{"name":"Renamed"}"#);
+
+        assert!(response.json().find("event_id").unwrap().as_str().is_some());
+
+        let response = test.get(&state_event_path);
+
+        assert_eq!(response.json().find("name").unwrap().as_str().unwrap(), "Renamed");
+    }
+
+    #[test]
+    fn room_names_and_topics_have_a_length_limit() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let state_event_path = |event_type: &str| {
+            format!(
+                "/_matrix/client/r0/rooms/{}/state/{}?access_token={}",
+                room_id,
+                event_type,
+                access_token
+            )
+        };
+
+        let name = format!(r#"{{"name":"{}"}}"#, repeat("n").take(256).collect::<String>());
+        let response = test.put(&state_event_path("m.room.name"), &name);
+
+        assert_eq!(response.status, Status::PayloadTooLarge);
+        assert_eq!(response.json().find("errcode").unwrap().as_str().unwrap(), "M_TOO_LARGE");
+
+        let topic = format!(r#"{{"topic":"{}"}}"#, repeat("t").take(4097).collect::<String>());
+
+        assert_eq!(
+            test.put(&state_event_path("m.room.topic"), &topic).status,
+            Status::PayloadTooLarge
+        );
+
+        let name = format!(r#"{{"name":"{}"}}"#, repeat("n").take(255).collect::<String>());
+
+        assert_eq!(test.put(&state_event_path("m.room.name"), &name).status, Status::Ok);
+    }
+
+    #[test]
+    fn custom_state_event_with_state_key() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let state_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/io.ruma.test/key?access_token={}",
+            room_id,
+            access_token
+        );
+
+        assert_eq!(test.put(&state_event_path, r#"{"foo":"bar"}"#).status, Status::Ok);
+
+        let response = test.get(&state_event_path);
+
+        assert_eq!(response.json().find("foo").unwrap().as_str().unwrap(), "bar");
+    }
+
+    #[test]
+    fn state_event_requires_empty_state_key() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let state_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.topic/key?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.put(&state_event_path, r#"{"topic":"Things"}"#);
+
+        assert_eq!(
+            response.json().find("error").unwrap().as_str().unwrap(),
+            "Events of type m.room.topic must have an empty state key."
+        );
+    }
+
+    #[test]
+    fn non_members_cannot_create_state_events() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&access_token);
+
+        let state_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.name?access_token={}",
+            room_id,
+            alice_access_token
+        );
+
+        let response = test.put(&state_event_path, r#"{"name":"Renamed"}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn insufficient_power_level_for_state_event() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&access_token);
+
+        test.join_room(&alice_access_token, &room_id);
+
+        let power_levels_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+            room_id,
+            access_token
+        );
+        let power_levels = r#"{
+            "ban": 50,
+            "events": {},
+            "events_default": 0,
+            "invite": 50,
+            "kick": 50,
+            "redact": 50,
+            "state_default": 50,
+            "users": { "@carl:ruma.test": 100 },
+            "users_default": 0
+        }"#;
+
+        assert_eq!(test.put(&power_levels_path, power_levels).status, Status::Ok);
+
+        let state_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.name?access_token={}",
+            room_id,
+            alice_access_token
+        );
+
+        let response = test.put(&state_event_path, r#"{"name":"Renamed"}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn power_levels_cannot_be_raised_above_your_own() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&access_token);
+        let power_levels = |kick: u64, carl: u64, alice: u64| {
+            format!(
+                r#"{{
+                    "ban": 50,
+                    "events": {{}},
+                    "events_default": 0,
+                    "invite": 50,
+                    "kick": {},
+                    "redact": 50,
+                    "state_default": 50,
+                    "users": {{ "@carl:ruma.test": {}, "@alice:ruma.test": {} }},
+                    "users_default": 0
+                }}"#,
+                kick,
+                carl,
+                alice
+            )
+        };
+        let power_levels_path = |access_token: &str| {
+            format!(
+                "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+                room_id,
+                access_token
+            )
+        };
+
+        test.join_room(&alice_access_token, &room_id);
+
+        let response = test.put(&power_levels_path(&access_token), &power_levels(50, 100, 50));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let alice_path = power_levels_path(&alice_access_token);
+
+        assert_eq!(test.put(&alice_path, &power_levels(50, 100, 100)).status, Status::Forbidden);
+        assert_eq!(test.put(&alice_path, &power_levels(50, 0, 50)).status, Status::Forbidden);
+        assert_eq!(test.put(&alice_path, &power_levels(25, 100, 50)).status, Status::Ok);
+    }
+
+    #[test]
+    fn power_level_changes_apply_to_the_next_event() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&access_token);
+        let power_levels = |alice: u64| {
+            format!(
+                r#"{{
+                    "ban": 50,
+                    "events": {{}},
+                    "events_default": 50,
+                    "invite": 50,
+                    "kick": 50,
+                    "redact": 50,
+                    "state_default": 50,
+                    "users": {{ "@carl:ruma.test": 100, "@alice:ruma.test": {} }},
+                    "users_default": 0
+                }}"#,
+                alice
+            )
+        };
+        let power_levels_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+            room_id,
+            access_token
+        );
+        let send_message = |transaction_id: &str| {
+            test.put(
+                &format!(
+                    "/_matrix/client/r0/rooms/{}/send/m.room.message/{}?access_token={}",
+                    room_id,
+                    transaction_id,
+                    alice_access_token
+                ),
+                r#"{"body":"Hi","msgtype":"m.text"}"#,
+            ).status
+        };
+
+        test.join_room(&alice_access_token, &room_id);
+
+        assert_eq!(send_message("1"), Status::Ok);
+        assert_eq!(test.put(&power_levels_path, &power_levels(0)).status, Status::Ok);
+        assert_eq!(send_message("2"), Status::Forbidden);
+        assert_eq!(test.put(&power_levels_path, &power_levels(50)).status, Status::Ok);
+        assert_eq!(send_message("3"), Status::Ok);
+    }
+
+    #[test]
+    fn encrypted_rooms_reject_plaintext_messages() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let encryption_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.encryption?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.put(&encryption_path, r#"{"algorithm":"m.megolm.v1.aes-sha2"}"#);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let message_path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/1?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.put(&message_path, r#"{"body":"Secret","msgtype":"m.text"}"#);
+
+        assert_eq!(response.status, Status::UnprocessableEntity);
+
+        let encrypted_path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.encrypted/2?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.put(
+            &encrypted_path,
+            r#"{"algorithm":"m.megolm.v1.aes-sha2","ciphertext":"AwgA","session_id":"s"}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn encryption_event_requires_an_algorithm() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let encryption_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.encryption?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.put(&encryption_path, r#"{"rotation_period_ms":604800000}"#);
+
+        assert_eq!(
+            response.json().find("error").unwrap().as_str().unwrap(),
+            "Events of type m.room.encryption must have an algorithm."
+        );
+    }
+
+    /// Returns the `origin_server_ts` and sender of an event in the room.
+    fn event_details(test: &Test, access_token: &str, room_id: &str, event_id: &str)
+    -> (i64, String) {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/context/{}?limit=0&access_token={}",
+            room_id,
+            event_id,
+            access_token
+        );
+        let context = test.get(&path).json().clone();
+        let event = context.find("event").unwrap();
+
+        (
+            event.find("origin_server_ts").unwrap().as_i64().unwrap(),
+            event.find("sender").unwrap().as_str().unwrap().to_string(),
+        )
+    }
+
+    #[test]
+    fn app_services_can_choose_the_timestamp() {
+        let test = Test::new();
+        let access_token = test.create_access_token_with_username("bridge_alice");
+        let room_id = test.create_room(&access_token);
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/1?ts={}&user_id={}&access_token={}",
+            room_id,
+            1262304000000i64,
+            "@bridge_alice:ruma.test",
+            "bridge_as_token"
+        );
+
+        let response = test.put(&path, r#"{"body":"Hi","msgtype":"m.text"}"#);
+        let event_id = response.json().find("event_id").unwrap().as_str().unwrap().to_string();
+
+        assert_eq!(
+            event_details(&test, &access_token, &room_id, &event_id),
+            (1262304000000, "@bridge_alice:ruma.test".to_string())
+        );
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.topic?ts={}&user_id={}&access_token={}",
+            room_id,
+            1262304060000i64,
+            "@bridge_alice:ruma.test",
+            "bridge_as_token"
+        );
+
+        let response = test.put(&path, r#"{"topic":"Bridged"}"#);
+        let event_id = response.json().find("event_id").unwrap().as_str().unwrap().to_string();
+
+        assert_eq!(event_details(&test, &access_token, &room_id, &event_id).0, 1262304060000);
+    }
+
+    #[test]
+    fn users_cannot_choose_the_timestamp() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/1?ts=1262304000000&access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.put(&path, r#"{"body":"Hi","msgtype":"m.text"}"#);
+        let event_id = response.json().find("event_id").unwrap().as_str().unwrap().to_string();
+
+        assert!(event_details(&test, &access_token, &room_id, &event_id).0 != 1262304000000);
+    }
+
+    #[test]
+    fn app_service_transaction_ids_are_scoped_to_the_app_service() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_public_room(&access_token);
+
+        for username in &["bridge_alice", "bridge_bob"] {
+            let bridged_access_token = test.create_access_token_with_username(username);
+
+            test.join_room(&bridged_access_token, &room_id);
+        }
+
+        let send = |user_id: &str| {
+            let path = format!(
+                "/_matrix/client/r0/rooms/{}/send/m.room.message/1?user_id={}&access_token={}",
+                room_id,
+                user_id,
+                "bridge_as_token"
+            );
+
+            test.put(&path, r#"{"body":"Hi","msgtype":"m.text"}"#)
+                .json()
+                .find("event_id")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_string()
+        };
+
+        let first_event_id = send("@bridge_alice:ruma.test");
+        let second_event_id = send("@bridge_bob:ruma.test");
+
+        assert_eq!(first_event_id, second_event_id);
+    }
+
+    #[test]
+    fn app_services_can_only_act_as_their_own_users() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/1?user_id={}&access_token={}",
+            room_id,
+            "@carl:ruma.test",
+            "bridge_as_token"
+        );
+
+        let response = test.put(&path, r#"{"body":"Hi","msgtype":"m.text"}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    /// Sends an event of the given type and returns the response.
+    fn send_event(
+        test: &Test,
+        access_token: &str,
+        room_id: &str,
+        event_type: &str,
+        txn_id: u32,
+        content: &str,
+    ) -> Response {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/{}/{}?access_token={}",
+            room_id,
+            event_type,
+            txn_id,
+            access_token
+        );
+
+        test.put(&path, content)
+    }
+
+    /// Sends a message and returns its full event ID.
+    fn send_message(test: &Test, access_token: &str, room_id: &str) -> String {
+        let response = send_event(
+            test,
+            access_token,
+            room_id,
+            "m.room.message",
+            0,
+            r#"{"body":"Hi","msgtype":"m.text"}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        format!("${}:ruma.test", response.json().find("event_id").unwrap().as_str().unwrap())
+    }
+
+    #[test]
+    fn relations_per_event_are_limited() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let event_id = send_message(&test, &access_token, &room_id);
+        let comment = format!(
+            r#"{{"body":"Nice","m.relates_to":{{"rel_type":"m.reference","event_id":"{}"}}}}"#,
+            event_id
+        );
+
+        for txn_id in 1..6 {
+            let response =
+                send_event(&test, &access_token, &room_id, "org.example.comment", txn_id, &comment);
+
+            assert_eq!(response.status, Status::Ok);
+        }
+
+        let response =
+            send_event(&test, &access_token, &room_id, "org.example.comment", 6, &comment);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().find("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_RELATION_LIMIT_EXCEEDED"
+        );
+    }
+
+    #[test]
+    fn reactions_per_user_are_limited() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&carl_token);
+
+        assert!(test.join_room(&alice_token, &room_id).status.is_success());
+
+        let event_id = send_message(&test, &carl_token, &room_id);
+        let reaction = |key: &str| format!(
+            r#"{{"m.relates_to":{{"rel_type":"m.annotation","event_id":"{}","key":"{}"}}}}"#,
+            event_id,
+            key
+        );
+
+        for (txn_id, key) in (1..3).zip(&["+1", "tada"]) {
+            let response =
+                send_event(&test, &carl_token, &room_id, "m.reaction", txn_id, &reaction(key));
+
+            assert_eq!(response.status, Status::Ok);
+        }
+
+        let response =
+            send_event(&test, &carl_token, &room_id, "m.reaction", 3, &reaction("heart"));
+
+        assert_eq!(response.status, Status::BadRequest);
+
+        // Other users still have their own reactions to give.
+        let response =
+            send_event(&test, &alice_token, &room_id, "m.reaction", 1, &reaction("heart"));
+
+        assert_eq!(response.status, Status::Ok);
+    }
 }
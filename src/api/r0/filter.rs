@@ -0,0 +1,192 @@
+//! Endpoints for uploading and retrieving filters.
+
+use bodyparser;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use serde_json::{Value, from_str, from_value, to_string};
+
+use db::DB;
+use error::ApiError;
+use filter::{Filter, NewStoredFilter, StoredFilter};
+use middleware::{AccessTokenAuth, FilterIdParam, JsonRequest, MiddlewareChain, UserIdParam};
+use modifier::SerializableResponse;
+use user::User;
+
+/// The POST `/user/:user_id/filter` endpoint.
+pub struct PostFilter;
+
+/// The GET `/user/:user_id/filter/:filter_id` endpoint.
+pub struct GetFilter;
+
+#[derive(Debug, Serialize)]
+struct PostFilterResponse {
+    filter_id: String,
+}
+
+middleware_chain!(PostFilter, [JsonRequest, UserIdParam, AccessTokenAuth]);
+
+impl Handler for PostFilter {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let user_id = request.extensions.get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId").clone();
+
+        if user_id != user.id {
+            let error = ApiError::unauthorized(
+                Some("The given user_id does not correspond to the authenticated user")
+            );
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let content = match request.get::<bodyparser::Json>() {
+            Ok(Some(content)) => content,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        if from_value::<Filter>(content.clone()).is_err() {
+            let error = ApiError::bad_json(Some("The filter is not valid."));
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let new_filter = NewStoredFilter {
+            user_id: user.id,
+            content: to_string(&content).map_err(ApiError::from)?,
+        };
+
+        let connection = DB::from_request(request)?;
+
+        let filter = StoredFilter::create(&connection, &new_filter)?;
+
+        let response = PostFilterResponse {
+            filter_id: filter.id.to_string(),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+middleware_chain!(GetFilter, [UserIdParam, FilterIdParam, AccessTokenAuth]);
+
+impl Handler for GetFilter {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let user_id = request.extensions.get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId").clone();
+
+        if user_id != user.id {
+            let error = ApiError::unauthorized(
+                Some("The given user_id does not correspond to the authenticated user")
+            );
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let filter_id = request.extensions.get::<FilterIdParam>()
+            .expect("FilterIdParam should ensure a filter ID").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let filter = match StoredFilter::find(&connection, &user.id, &filter_id)? {
+            Some(filter) => filter,
+            None => {
+                let error = ApiError::not_found(Some("No filter was found with the given ID."));
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let content: Value = from_str(&filter.content).map_err(ApiError::from)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(content))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::Test;
+
+    #[test]
+    fn upload_and_retrieve_filter() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let path = format!(
+            "/_matrix/client/r0/user/@carl:ruma.test/filter?access_token={}",
+            access_token
+        );
+        let body = r#"{"room": {"timeline": {"limit": 10, "types": ["m.room.message"]}}}"#;
+
+        let response = test.post(&path, body);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let filter_id = response.json().find("filter_id").unwrap().as_str().unwrap().to_string();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/user/@carl:ruma.test/filter/{}?access_token={}",
+            filter_id,
+            access_token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().find_path(&["room", "timeline", "limit"]).unwrap().as_u64().unwrap(),
+            10
+        );
+    }
+
+    #[test]
+    fn filters_are_private() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let path = format!(
+            "/_matrix/client/r0/user/@carl:ruma.test/filter?access_token={}",
+            carl_token
+        );
+
+        let response = test.post(&path, r#"{"presence": {"limit": 5}}"#);
+        let filter_id = response.json().find("filter_id").unwrap().as_str().unwrap().to_string();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/user/@carl:ruma.test/filter/{}?access_token={}",
+            filter_id,
+            alice_token
+        ));
+
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/user/@alice:ruma.test/filter/{}?access_token={}",
+            filter_id,
+            alice_token
+        ));
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn invalid_filter_is_rejected() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let path = format!(
+            "/_matrix/client/r0/user/@carl:ruma.test/filter?access_token={}",
+            access_token
+        );
+
+        let response = test.post(&path, r#"{"room": {"timeline": {"limit": "ten"}}}"#);
+
+        assert_eq!(response.status, Status::UnprocessableEntity);
+    }
+}
@@ -1,36 +1,82 @@
-use iron::{Chain, Handler, IronResult, Request, Response, status};
+use bodyparser;
+use diesel::Connection;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response, status};
 
 use access_token::AccessToken;
-use authentication::{AuthType, Flow, InteractiveAuth};
+use clock::ServerClock;
 use config::Config;
+use crypto::ServerEntropy;
 use db::DB;
-use middleware::{JsonRequest, MiddlewareChain, UIAuth};
+use device::{Device, NewDevice};
+use discovery::ClientDiscovery;
+use error::ApiError;
+use middleware::{JsonRequest, LoginAuth, MiddlewareChain};
 use modifier::SerializableResponse;
 use user::User;
 
 /// The `/login` endpoint.
 pub struct Login;
 
+#[derive(Clone, Debug, Deserialize)]
+struct LoginRequest {
+    pub device_id: Option<String>,
+    pub initial_device_display_name: Option<String>,
+}
+
 #[derive(Debug, Serialize)]
 struct LoginResponse {
     pub access_token: String,
+    pub device_id: String,
     pub home_server: String,
     pub user_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub well_known: Option<ClientDiscovery>,
 }
 
-middleware_chain!(Login, [JsonRequest, UIAuth::new(InteractiveAuth::new(vec![Flow::new(vec![AuthType::Password])]))]);
+middleware_chain!(Login, [JsonRequest, LoginAuth]);
 
 impl Handler for Login {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user = request.extensions.get::<User>().expect("UIAuth should ensure a user").clone();
+        let login_request = match request.get::<bodyparser::Struct<LoginRequest>>() {
+            Ok(Some(login_request)) => login_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let user = request.extensions.get::<User>().expect("LoginAuth should ensure a user").clone();
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
-        let access_token = AccessToken::create(&connection, &user.id, &config.macaroon_secret_key)?;
+        let clock = ServerClock::from_request(request)?;
+        let entropy = ServerEntropy::from_request(request)?;
+
+        let new_device = NewDevice {
+            device_id: login_request.device_id.unwrap_or_else(|| Device::generate_id(&**entropy)),
+            user_id: user.id.clone(),
+            display_name: login_request.initial_device_display_name,
+        };
+
+        let access_token = connection.transaction::<AccessToken, ApiError, _>(|| {
+            let device = Device::find_or_create_for_login(&connection, &new_device)?;
+
+            AccessToken::create(
+                &connection,
+                &user.id,
+                &device.device_id,
+                &config.macaroon_secret_key,
+                &**clock,
+                &**entropy,
+            )
+        }).map_err(ApiError::from)?;
 
         let response = LoginResponse {
             access_token: access_token.value,
+            device_id: access_token.device_id,
             home_server: config.domain.clone(),
             user_id: user.id.to_string(),
+            well_known: ClientDiscovery::from_config(&config),
         };
 
         Ok(Response::with((status::Ok, SerializableResponse(response))))
@@ -39,6 +85,8 @@ impl Handler for Login {
 
 #[cfg(test)]
 mod tests {
+    use iron::status::Status;
+
     use test::Test;
 
     #[test]
@@ -46,7 +94,7 @@ mod tests {
         let test = Test::new();
 
         assert!(test.register_user(
-            r#"{"username": "carl", "password": "secret"}"#
+            r#"{"auth": {"type": "m.login.dummy"}, "username": "carl", "password": "secret"}"#
         ).status.is_success());
 
         let response = test.post(
@@ -55,7 +103,176 @@ mod tests {
         );
 
         assert!(response.json().find("access_token").is_some());
+        assert!(response.json().find("device_id").is_some());
         assert_eq!(response.json().find("home_server").unwrap().as_str().unwrap(), "ruma.test");
         assert_eq!(response.json().find("user_id").unwrap().as_str().unwrap(), "@carl:ruma.test");
+        assert_eq!(
+            response
+                .json()
+                .find_path(&["well_known", "m.homeserver", "base_url"])
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "https://matrix.ruma.test"
+        );
+    }
+
+    #[test]
+    fn login_with_existing_device_replaces_its_access_token() {
+        let test = Test::new();
+
+        assert!(test.register_user(
+            r#"{"auth": {"type": "m.login.dummy"}, "username": "carl", "password": "secret"}"#
+        ).status.is_success());
+
+        let login = r#"{
+            "auth": {"type": "m.login.password", "user": "carl", "password": "secret"},
+            "device_id": "PHONE"
+        }"#;
+
+        let first = test.post("/_matrix/client/r0/login", login);
+        let second = test.post("/_matrix/client/r0/login", login);
+
+        assert_eq!(first.json().find("device_id").unwrap().as_str().unwrap(), "PHONE");
+        assert_eq!(second.json().find("device_id").unwrap().as_str().unwrap(), "PHONE");
+
+        let first_sync_path = format!(
+            "/_matrix/client/r0/sync?access_token={}",
+            first.json().find("access_token").unwrap().as_str().unwrap()
+        );
+        let second_sync_path = format!(
+            "/_matrix/client/r0/sync?access_token={}",
+            second.json().find("access_token").unwrap().as_str().unwrap()
+        );
+
+        assert_eq!(test.get(&first_sync_path).status, Status::Forbidden);
+        assert_eq!(test.get(&second_sync_path).status, Status::Ok);
+    }
+
+    #[test]
+    fn each_login_issues_a_distinct_access_token() {
+        let test = Test::new();
+
+        assert!(test.register_user(
+            r#"{"auth": {"type": "m.login.dummy"}, "username": "carl", "password": "secret"}"#
+        ).status.is_success());
+
+        let login = r#"{"auth": {"type": "m.login.password", "user": "carl", "password": "secret"}}"#;
+
+        let first = test.post("/_matrix/client/r0/login", login);
+        let second = test.post("/_matrix/client/r0/login", login);
+
+        assert_ne!(
+            first.json().find("access_token").unwrap().as_str().unwrap(),
+            second.json().find("access_token").unwrap().as_str().unwrap()
+        );
+    }
+
+    #[test]
+    fn login_with_email() {
+        let test = Test::new();
+        let admin_access_token = test.create_access_token_with_username("admin");
+        test.create_access_token();
+
+        let response = test.post(
+            &format!(
+                "/ruma/admin/users/@carl:ruma.test/threepids?access_token={}",
+                admin_access_token
+            ),
+            r#"{"medium":"email","address":"carl@example.com"}"#,
+        );
+
+        assert!(response.status.is_success());
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"auth": {
+                "type": "m.login.password",
+                "identifier": {
+                    "type": "m.id.thirdparty",
+                    "medium": "email",
+                    "address": "Carl@Example.com"
+                },
+                "password": "secret"
+            }}"#,
+        );
+
+        assert_eq!(response.json().find("user_id").unwrap().as_str().unwrap(), "@carl:ruma.test");
+    }
+
+    #[test]
+    fn login_with_unknown_email() {
+        let test = Test::new();
+        test.create_access_token();
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"auth": {
+                "type": "m.login.password",
+                "identifier": {
+                    "type": "m.id.thirdparty",
+                    "medium": "email",
+                    "address": "carl@example.com"
+                },
+                "password": "secret"
+            }}"#,
+        );
+
+        assert!(response.status.is_client_error());
+    }
+
+    #[test]
+    fn login_with_top_level_user_identifier() {
+        let test = Test::new();
+        test.create_access_token();
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{
+                "type": "m.login.password",
+                "identifier": {"type": "m.id.user", "user": "carl"},
+                "password": "secret"
+            }"#,
+        );
+
+        assert_eq!(response.json().find("user_id").unwrap().as_str().unwrap(), "@carl:ruma.test");
+    }
+
+    #[test]
+    fn login_with_top_level_thirdparty_identifier() {
+        let test = Test::new();
+        let admin_access_token = test.create_access_token_with_username("admin");
+        test.create_access_token();
+
+        test.post(
+            &format!(
+                "/ruma/admin/users/@carl:ruma.test/threepids?access_token={}",
+                admin_access_token
+            ),
+            r#"{"medium":"email","address":"carl@example.com"}"#,
+        );
+
+        let login = |password| {
+            let body = format!(
+                r#"{{
+                    "type": "m.login.password",
+                    "identifier": {{
+                        "type": "m.id.thirdparty",
+                        "medium": "email",
+                        "address": "carl@example.com"
+                    }},
+                    "password": "{}"
+                }}"#,
+                password
+            );
+
+            test.post("/_matrix/client/r0/login", &body)
+        };
+
+        assert_eq!(
+            login("secret").json().find("user_id").unwrap().as_str().unwrap(),
+            "@carl:ruma.test"
+        );
+        assert_eq!(login("wrong").status, Status::Forbidden);
     }
 }
@@ -0,0 +1,982 @@
+//! Endpoints for room membership.
+
+use std::convert::TryFrom;
+use std::error::Error;
+
+use bodyparser;
+use diesel::Connection;
+use diesel::pg::PgConnection;
+use iron::status::Status;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use ruma_identifiers::{RoomId, RoomIdOrAliasId, UserId};
+
+use clock::ServerClock;
+use config::Config;
+use db::DB;
+use error::{ApiError, MapApiError};
+use membership_cache::ServerMembershipCache;
+use middleware::{
+    AccessTokenAuth,
+    JsonRequest,
+    MiddlewareChain,
+    NonGuestAuth,
+    RoomIdOrAliasParam,
+    RoomIdParam,
+};
+use modifier::SerializableResponse;
+use power_levels::{PowerLevelsCache, ServerPowerLevelsCache};
+use room::Room;
+use room_alias::RoomAlias;
+use room_membership::{RoomMembership, RoomMembershipOptions};
+use user::User;
+use user_threepid::UserThreepid;
+use velocity_limit::{VelocityAction, VelocityLimits};
+
+/// The `/rooms/:room_id/join` endpoint.
+pub struct JoinRoom;
+
+#[derive(Debug, Serialize)]
+struct JoinRoomResponse {
+    room_id: String,
+}
+
+middleware_chain!(JoinRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
+
+impl Handler for JoinRoom {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+        let power_levels_cache = ServerPowerLevelsCache::from_request(request)?;
+
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+
+        let room_membership = join_room(&connection, &power_levels_cache, &config, user, room_id)?;
+
+        let response = JoinRoomResponse { room_id: room_membership.room_id.to_string() };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The `/join/:room_id_or_alias` endpoint.
+///
+/// Ruma can't join rooms through other servers, so the `server_name` query parameters only decide
+/// the error when the room isn't on this one.
+pub struct JoinRoomByIdOrAlias;
+
+middleware_chain!(JoinRoomByIdOrAlias, [JsonRequest, RoomIdOrAliasParam, AccessTokenAuth]);
+
+impl Handler for JoinRoomByIdOrAlias {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let room_id_or_alias = request.extensions.get::<RoomIdOrAliasParam>()
+            .expect("RoomIdOrAliasParam should ensure a RoomIdOrAliasId").clone();
+
+        let url = request.url.clone().into_generic_url();
+        let servers: Vec<String> = url.query_pairs()
+            .filter(|&(ref key, _)| key == "server_name")
+            .map(|(_, value)| value.into_owned())
+            .collect();
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+        let power_levels_cache = ServerPowerLevelsCache::from_request(request)?;
+
+        let room_id = resolve_room_id(&connection, &config.domain, room_id_or_alias, &servers)?;
+
+        let room_membership = join_room(&connection, &power_levels_cache, &config, user, room_id)?;
+
+        let response = JoinRoomResponse { room_id: room_membership.room_id.to_string() };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// Joins `user` to the room, refusing rooms over the configured complexity limit unless the user
+/// is an admin or already joined, and refusing guests unless the room allows guest access.
+fn join_room(
+    connection: &PgConnection,
+    power_levels_cache: &PowerLevelsCache,
+    config: &Config,
+    user: User,
+    room_id: RoomId,
+) -> Result<RoomMembership, ApiError> {
+    if user.is_guest && !Room::find(connection, &room_id)?.guests_can_join(connection)? {
+        return Err(ApiError::guest_forbidden(Some("This room does not allow guests to join.")));
+    }
+
+    if let Some(limit) = config.room_complexity_limit {
+        let already_joined = match RoomMembership::find(connection, &room_id, &user.id)? {
+            Some(membership) => membership.membership == "join",
+            None => false,
+        };
+
+        if !already_joined && !config.admins.contains(&user.id) {
+            let room = Room::find(connection, &room_id)?;
+
+            if room.complexity(connection)? > limit {
+                return Err(ApiError::unauthorized(
+                    Some("The room is too complex for this server to join.")
+                ));
+            }
+        }
+    }
+
+    let room_membership_options = RoomMembershipOptions {
+        room_id: room_id,
+        user_id: user.id.clone(),
+        sender: user.id,
+        membership: "join".to_string(),
+    };
+
+    RoomMembership::transition(
+        connection,
+        power_levels_cache,
+        &config.domain,
+        room_membership_options,
+    )
+}
+
+/// Finds the ID of a room on this server from a room ID or alias.
+fn resolve_room_id(
+    connection: &PgConnection,
+    homeserver_domain: &str,
+    room_id_or_alias: RoomIdOrAliasId,
+    servers: &[String],
+) -> Result<RoomId, ApiError> {
+    let federation_error = ApiError::unimplemented(
+        Some("Ruma cannot join rooms through other servers.")
+    );
+
+    match room_id_or_alias {
+        RoomIdOrAliasId::RoomAliasId(room_alias_id) => {
+            if room_alias_id.hostname().to_string() != homeserver_domain {
+                return Err(federation_error);
+            }
+
+            Ok(RoomAlias::find_by_alias(connection, &room_alias_id)?.room_id)
+        }
+        RoomIdOrAliasId::RoomId(room_id) => {
+            if let Err(error) = Room::find(connection, &room_id) {
+                if servers.iter().any(|server| server != homeserver_domain) {
+                    return Err(federation_error);
+                }
+
+                return Err(error);
+            }
+
+            Ok(room_id)
+        }
+    }
+}
+
+/// The `/rooms/:room_id/leave` endpoint.
+///
+/// Leaving a room the user was invited to rejects the invitation.
+pub struct LeaveRoom;
+
+middleware_chain!(LeaveRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
+
+impl Handler for LeaveRoom {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a room_id").clone();
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+        let membership_cache = ServerMembershipCache::from_request(request)?;
+        let power_levels_cache = ServerPowerLevelsCache::from_request(request)?;
+
+        let options = RoomMembershipOptions {
+            room_id: room_id.clone(),
+            user_id: user.id.clone(),
+            sender: user.id.clone(),
+            membership: "leave".to_string(),
+        };
+
+        RoomMembership::transition(&connection, &power_levels_cache, &config.domain, options)?;
+
+        membership_cache.invalidate(&room_id, &user.id);
+
+        Ok(Response::with(Status::Ok))
+    }
+}
+
+/// The `/rooms/:room_id/forget` endpoint.
+///
+/// Hides a room the user has left from their sync and history. If they come back to the room,
+/// it reappears.
+pub struct ForgetRoom;
+
+middleware_chain!(ForgetRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
+
+impl Handler for ForgetRoom {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a room_id").clone();
+
+        let connection = DB::from_request(request)?;
+
+        RoomMembership::forget(&connection, &room_id, &user.id)?;
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
+/// The `/rooms/:room_id/invite` endpoint.
+///
+/// Users can also be invited by a third-party identifier bound to their account, given as
+/// `medium` and `address` instead of `user_id`. Ruma doesn't send invites through identity
+/// servers, so identifiers that aren't bound to a user on this server are not found.
+#[derive(Debug)]
+pub struct InviteToRoom;
+
+/// The body of the endpoints that act on another user's membership.
+#[derive(Clone, Debug, Deserialize)]
+struct TargetUserRequest {
+    pub address: Option<String>,
+    pub medium: Option<String>,
+    pub user_id: Option<String>,
+}
+
+middleware_chain!(InviteToRoom, [JsonRequest, RoomIdParam, AccessTokenAuth, NonGuestAuth]);
+
+impl Handler for InviteToRoom {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        change_membership(request, "invite", None)
+    }
+}
+
+/// The `/rooms/:room_id/kick` endpoint.
+pub struct KickFromRoom;
+
+middleware_chain!(KickFromRoom, [JsonRequest, RoomIdParam, AccessTokenAuth, NonGuestAuth]);
+
+impl Handler for KickFromRoom {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        change_membership(
+            request,
+            "leave",
+            Some((&["invite", "join"][..], "The user is not in the room.")),
+        )
+    }
+}
+
+/// The `/rooms/:room_id/ban` endpoint.
+pub struct BanFromRoom;
+
+middleware_chain!(BanFromRoom, [JsonRequest, RoomIdParam, AccessTokenAuth, NonGuestAuth]);
+
+impl Handler for BanFromRoom {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        change_membership(request, "ban", None)
+    }
+}
+
+/// The `/rooms/:room_id/unban` endpoint.
+pub struct UnbanFromRoom;
+
+middleware_chain!(UnbanFromRoom, [JsonRequest, RoomIdParam, AccessTokenAuth, NonGuestAuth]);
+
+impl Handler for UnbanFromRoom {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        change_membership(
+            request,
+            "leave",
+            Some((&["ban"][..], "The user is not banned from the room.")),
+        )
+    }
+}
+
+/// Changes the membership of the user given in the request body on behalf of the requester.
+///
+/// Kicking and unbanning both change the membership to `leave`, so `from` can restrict which
+/// memberships the endpoint may change, along with the error to return for any others.
+fn change_membership(request: &mut Request, membership: &str, from: Option<(&[&str], &str)>)
+-> IronResult<Response> {
+    let room_id = request.extensions.get::<RoomIdParam>()
+        .expect("RoomIdParam should ensure a room_id").clone();
+
+    let sender = request.extensions.get::<User>()
+        .expect("AccessTokenAuth should ensure a user").clone();
+
+    let target = request.get::<bodyparser::Struct<TargetUserRequest>>();
+
+    let connection = DB::from_request(request)?;
+
+    let user_id = match target {
+        Ok(Some(TargetUserRequest { user_id: Some(user_id), .. })) => {
+            UserId::try_from(&user_id).map_api_err(|err| {
+                ApiError::invalid_param("user_id", err.description())
+            })
+        }
+        Ok(Some(TargetUserRequest { medium: Some(medium), address: Some(address), .. }))
+        if membership == "invite" => {
+            match UserThreepid::find_user_id(&connection, &medium, &address)? {
+                Some(user_id) => Ok(user_id),
+                None => Err(ApiError::not_found(
+                    Some("No user on this server has that third-party identifier.")
+                )),
+            }
+        }
+        Ok(_) | Err(_) => Err(ApiError::missing_param("user_id")),
+    }?;
+    let config = Config::from_request(request)?;
+    let clock = ServerClock::from_request(request)?;
+    let membership_cache = ServerMembershipCache::from_request(request)?;
+    let power_levels_cache = ServerPowerLevelsCache::from_request(request)?;
+
+    // Check if the user exists.
+    User::find_by_uid(&connection, &user_id)?;
+
+    if let Some((memberships, message)) = from {
+        ensure_membership_in(&connection, &room_id, &user_id, memberships, message)?;
+    }
+
+    let options = RoomMembershipOptions {
+        room_id: room_id.clone(),
+        user_id: user_id.clone(),
+        sender: sender.id.clone(),
+        membership: membership.to_string(),
+    };
+
+    connection.transaction::<(), ApiError, _>(|| {
+        if membership == "invite" {
+            VelocityLimits::check_and_record(
+                &connection,
+                &config,
+                &sender.id,
+                VelocityAction::Invite,
+                1,
+                clock.now_millis(),
+            )?;
+        }
+
+        RoomMembership::transition(&connection, &power_levels_cache, &config.domain, options)?;
+
+        Ok(())
+    }).map_err(ApiError::from)?;
+
+    membership_cache.invalidate(&room_id, &user_id);
+
+    Ok(Response::with(Status::Ok))
+}
+
+/// Checks that the user's current membership in the room is one of `memberships`.
+fn ensure_membership_in(
+    connection: &PgConnection,
+    room_id: &RoomId,
+    user_id: &UserId,
+    memberships: &[&str],
+    message: &str,
+) -> Result<(), ApiError> {
+    // Check if the room exists.
+    Room::find(connection, room_id)?;
+
+    match RoomMembership::find(connection, room_id, user_id)? {
+        Some(ref entry) if memberships.iter().any(|membership| *membership == entry.membership) => {
+            Ok(())
+        }
+        _ => Err(ApiError::unauthorized(Some(message))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test::{Response, Test};
+    use iron::status::Status;
+
+    #[test]
+    fn join_own_public_room() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_public_room(&access_token);
+
+        let room_join_path = format!(
+            "/_matrix/client/r0/rooms/{}/join?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.post(&room_join_path, r"{}");
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().find("room_id").unwrap().as_str().is_some());
+    }
+
+    #[test]
+    fn join_other_public_room() {
+        let test = Test::new();
+        let carl_token = test.create_access_token_with_username("carl");
+        let mark_token = test.create_access_token_with_username("mark");
+
+        let room_id = test.create_public_room(&carl_token);
+
+        let room_join_path = format!(
+            "/_matrix/client/r0/rooms/{}/join?access_token={}",
+            room_id,
+            mark_token
+        );
+
+        let response = test.post(&room_join_path, r"{}");
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().find("room_id").unwrap().as_str().is_some());
+    }
+
+    #[test]
+    fn join_own_private_room() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_private_room(&access_token);
+
+        let room_join_path = format!(
+            "/_matrix/client/r0/rooms/{}/join?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.post(&room_join_path, r"{}");
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().find("room_id").unwrap().as_str().is_some());
+    }
+
+    #[test]
+    fn join_other_private_room() {
+        let test = Test::new();
+        let carl_token = test.create_access_token_with_username("carl");
+        let mark_token = test.create_access_token_with_username("mark");
+
+        let body = r#"{"visibility": "private", "invite": ["@mark:ruma.test"]}"#;
+        let room_id = test.create_room_with_params(&carl_token, body);
+
+        let room_join_path = format!(
+            "/_matrix/client/r0/rooms/{}/join?access_token={}",
+            room_id,
+            mark_token
+        );
+
+        let response = test.post(&room_join_path, r"{}");
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().find("room_id").unwrap().as_str().is_some());
+    }
+
+    #[test]
+    fn join_other_private_room_without_invite() {
+        let test = Test::new();
+        let bob_token = test.create_access_token_with_username("bob");
+        let alice_token = test.create_access_token_with_username("alice");
+
+        let room_id = test.create_private_room(&bob_token);
+
+        let room_join_path = format!(
+            "/_matrix/client/r0/rooms/{}/join?access_token={}",
+            room_id,
+            alice_token
+        );
+
+        let response = test.post(&room_join_path, r"{}");
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn invite_to_room() {
+        let test = Test::new();
+        let bob_token = test.create_access_token_with_username("bob");
+        let alice_token = test.create_access_token_with_username("alice");
+
+        let room_id = test.create_private_room(&bob_token);
+
+        let response = test.invite(&bob_token, &room_id, "@alice:ruma.test");
+
+        assert_eq!(response.status, Status::Ok);
+
+        assert!(test.join_room(&alice_token, &room_id).status.is_success());
+    }
+
+    #[test]
+    fn invite_before_joining() {
+        let test = Test::new();
+
+        let carl_token = test.create_access_token_with_username("carl");
+        let bob_token = test.create_access_token_with_username("bob");
+        let _ = test.create_access_token_with_username("alice");
+
+        // Carl invites Bob.
+        let body = r#"{"visibility": "private", "invite": ["@bob:ruma.test"]}"#;
+        let room_id = test.create_room_with_params(&carl_token, body);
+
+        // Bob invites Alice before joining.
+        let response = test.invite(&bob_token, &room_id, "@alice:ruma.test");
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().find("error").unwrap().as_str().unwrap(),
+            "The inviter hasn't joined the room yet"
+        );
+    }
+
+    #[test]
+    fn invite_without_user_id() {
+        let test = Test::new();
+        let carl_token = test.create_access_token_with_username("carl");
+
+        let room_id = test.create_private_room(&carl_token);
+        let invite_path = format!(
+            "/_matrix/client/r0/rooms/{}/invite?access_token={}",
+            room_id,
+            carl_token
+        );
+
+        // Empty body.
+        let response = test.post(&invite_path, "{}");
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().find("errcode").unwrap().as_str().unwrap(),
+            "M_MISSING_PARAM"
+        );
+        assert_eq!(
+            response.json().find("error").unwrap().as_str().unwrap(),
+            "Missing value for required parameter: user_id."
+        );
+    }
+
+    #[test]
+    fn invitee_does_not_exist() {
+        let test = Test::new();
+        let carl_token = test.create_access_token_with_username("carl");
+
+        let room_id = test.create_private_room(&carl_token);
+
+        // User 'mark' does not exist.
+        let response = test.invite(&carl_token, &room_id, "@mark:ruma.test");
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(
+            response.json().find("error").unwrap().as_str().unwrap(),
+            "The user @mark:ruma.test was not found on this server"
+        );
+    }
+
+    #[test]
+    fn invitee_is_invalid() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let room_id = test.create_private_room(&carl_token);
+
+        let response = test.invite(&carl_token, &room_id, "mark.ruma.test");
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().find("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn invitee_is_already_invited() {
+        let test = Test::new();
+        let bob_token = test.create_access_token_with_username("bob");
+        let _ = test.create_access_token_with_username("alice");
+
+        let room_id = test.create_room_with_params(
+            &bob_token,
+            r#"{"visibility": "private", "invite": ["@alice:ruma.test"]}"#
+        );
+
+        let response = test.invite(&bob_token, &room_id, "@alice:ruma.test");
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn invitee_has_already_joined() {
+        let test = Test::new();
+        let bob_token = test.create_access_token_with_username("bob");
+        let alice_token = test.create_access_token_with_username("alice");
+
+        let room_id = test.create_room_with_params(
+            &bob_token,
+            r#"{"visibility": "private", "invite": ["@alice:ruma.test"]}"#
+        );
+
+        assert!(test.join_room(&alice_token, &room_id).status.is_success());
+
+        let response = test.invite(&bob_token, &room_id, "@alice:ruma.test");
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().find("error").unwrap().as_str().unwrap(),
+            "The invited user has already joined"
+        );
+    }
+
+    #[test]
+    fn room_does_not_exist() {
+        let test = Test::new();
+        let bob_token = test.create_access_token_with_username("bob");
+        let _ = test.create_access_token_with_username("alice");
+
+        let response = test.invite(&bob_token, "!random:ruma.test", "@alice:ruma.test");
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(
+            response.json().find("error").unwrap().as_str().unwrap(),
+            "The room was not found on this server"
+        );
+    }
+
+    fn moderate(test: &Test, access_token: &str, room_id: &str, action: &str, user_id: &str)
+    -> Response {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/{}?access_token={}",
+            room_id,
+            action,
+            access_token
+        );
+
+        test.post(&path, &format!(r#"{{"user_id": "{}"}}"#, user_id))
+    }
+
+    #[test]
+    fn leave_room() {
+        let test = Test::new();
+        let bob_token = test.create_access_token_with_username("bob");
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&bob_token);
+
+        assert!(test.join_room(&alice_token, &room_id).status.is_success());
+
+        let leave_path = format!(
+            "/_matrix/client/r0/rooms/{}/leave?access_token={}",
+            room_id,
+            alice_token
+        );
+
+        assert_eq!(test.post(&leave_path, "{}").status, Status::Ok);
+        assert_eq!(test.post(&leave_path, "{}").status, Status::Forbidden);
+    }
+
+    #[test]
+    fn forget_room() {
+        let test = Test::new();
+        let bob_token = test.create_access_token_with_username("bob");
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&bob_token);
+
+        assert!(test.join_room(&alice_token, &room_id).status.is_success());
+
+        let forget_path = format!(
+            "/_matrix/client/r0/rooms/{}/forget?access_token={}",
+            room_id,
+            alice_token
+        );
+
+        assert_eq!(test.post(&forget_path, "{}").status, Status::BadRequest);
+
+        let leave_path = format!(
+            "/_matrix/client/r0/rooms/{}/leave?access_token={}",
+            room_id,
+            alice_token
+        );
+
+        assert_eq!(test.post(&leave_path, "{}").status, Status::Ok);
+
+        let sync_path = format!(
+            "/_matrix/client/r0/sync?filter={}&access_token={}",
+            r#"{"room":{"include_leave":true}}"#,
+            alice_token
+        );
+        let response = test.get(&sync_path);
+
+        assert!(response.json().find_path(&["rooms", "leave", &room_id[..]]).is_some());
+        assert_eq!(test.post(&forget_path, "{}").status, Status::Ok);
+
+        let response = test.get(&sync_path);
+
+        assert!(response.json().find_path(&["rooms", "leave", &room_id[..]]).is_none());
+
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from=0&dir=f&access_token={}",
+            room_id,
+            alice_token
+        );
+
+        assert_eq!(test.get(&messages_path).status, Status::Forbidden);
+
+        // Joining again brings the room back.
+        assert!(test.join_room(&alice_token, &room_id).status.is_success());
+        assert_eq!(test.get(&messages_path).status, Status::Ok);
+    }
+
+    #[test]
+    fn reject_invite_by_leaving() {
+        let test = Test::new();
+        let bob_token = test.create_access_token_with_username("bob");
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_private_room(&bob_token);
+
+        assert!(test.invite(&bob_token, &room_id, "@alice:ruma.test").status.is_success());
+
+        let leave_path = format!(
+            "/_matrix/client/r0/rooms/{}/leave?access_token={}",
+            room_id,
+            alice_token
+        );
+
+        assert_eq!(test.post(&leave_path, "{}").status, Status::Ok);
+        assert_eq!(test.join_room(&alice_token, &room_id).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn kick_from_room() {
+        let test = Test::new();
+        let bob_token = test.create_access_token_with_username("bob");
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&bob_token);
+
+        assert!(test.join_room(&alice_token, &room_id).status.is_success());
+
+        let response = moderate(&test, &bob_token, &room_id, "kick", "@alice:ruma.test");
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = moderate(&test, &bob_token, &room_id, "kick", "@alice:ruma.test");
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().find("error").unwrap().as_str().unwrap(),
+            "The user is not in the room."
+        );
+
+        // Kicked users may join again.
+        assert!(test.join_room(&alice_token, &room_id).status.is_success());
+    }
+
+    #[test]
+    fn kicked_users_cannot_send_messages() {
+        let test = Test::new();
+        let bob_token = test.create_access_token_with_username("bob");
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&bob_token);
+
+        assert!(test.join_room(&alice_token, &room_id).status.is_success());
+
+        let send_path = |transaction_id: u64| format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/{}?access_token={}",
+            room_id,
+            transaction_id,
+            alice_token
+        );
+        let body = r#"{"body": "Hi", "msgtype": "m.text"}"#;
+
+        // The first message caches Alice's membership.
+        assert_eq!(test.put(&send_path(1), body).status, Status::Ok);
+
+        let response = moderate(&test, &bob_token, &room_id, "kick", "@alice:ruma.test");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(test.put(&send_path(2), body).status, Status::Forbidden);
+
+        test.run_jobs();
+
+        assert_eq!(test.put(&send_path(3), body).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn kick_without_power() {
+        let test = Test::new();
+        let bob_token = test.create_access_token_with_username("bob");
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&bob_token);
+
+        assert!(test.join_room(&alice_token, &room_id).status.is_success());
+
+        let response = moderate(&test, &alice_token, &room_id, "kick", "@bob:ruma.test");
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn ban_and_unban() {
+        let test = Test::new();
+        let bob_token = test.create_access_token_with_username("bob");
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&bob_token);
+
+        assert!(test.join_room(&alice_token, &room_id).status.is_success());
+
+        let response = moderate(&test, &bob_token, &room_id, "ban", "@alice:ruma.test");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(test.join_room(&alice_token, &room_id).status, Status::Forbidden);
+
+        // Banned users can't be kicked, only unbanned.
+        let response = moderate(&test, &bob_token, &room_id, "kick", "@alice:ruma.test");
+
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = moderate(&test, &bob_token, &room_id, "unban", "@alice:ruma.test");
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(test.join_room(&alice_token, &room_id).status.is_success());
+    }
+
+    #[test]
+    fn unban_user_who_is_not_banned() {
+        let test = Test::new();
+        let bob_token = test.create_access_token_with_username("bob");
+        test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&bob_token);
+
+        let response = moderate(&test, &bob_token, &room_id, "unban", "@alice:ruma.test");
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().find("error").unwrap().as_str().unwrap(),
+            "The user is not banned from the room."
+        );
+    }
+
+    #[test]
+    fn join_room_by_alias() {
+        let test = Test::new();
+        let carl_token = test.create_access_token_with_username("carl");
+        let mark_token = test.create_access_token_with_username("mark");
+        let room_id = test.create_room_with_params(
+            &carl_token,
+            r#"{"visibility": "public", "room_alias_name": "my_room"}"#,
+        );
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/join/%23my_room:ruma.test?access_token={}", mark_token),
+            r"{}",
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().find("room_id").unwrap().as_str().unwrap(), room_id);
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/join/{}?access_token={}", room_id, carl_token),
+            r"{}",
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().find("room_id").unwrap().as_str().unwrap(), room_id);
+    }
+
+    #[test]
+    fn join_unknown_alias() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/join/%23no_room:ruma.test?access_token={}", access_token),
+            r"{}",
+        );
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(response.json().find("errcode").unwrap().as_str().unwrap(), "M_NOT_FOUND");
+    }
+
+    #[test]
+    fn join_room_on_other_server() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/join/%23room:other.test?access_token={}", access_token),
+            r"{}",
+        );
+
+        assert_eq!(
+            response.json().find("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_UNIMPLEMENTED"
+        );
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/join/!room:other.test?server_name=other.test&access_token={}",
+                access_token
+            ),
+            r"{}",
+        );
+
+        assert_eq!(
+            response.json().find("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_UNIMPLEMENTED"
+        );
+    }
+
+    #[test]
+    fn guests_can_only_join_rooms_with_guest_access() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let guest_access_token = test.create_guest_access_token();
+        let room_id = test.create_public_room(&access_token);
+
+        let response = test.join_room(&guest_access_token, &room_id);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().find("errcode").unwrap().as_str().unwrap(),
+            "M_GUEST_ACCESS_FORBIDDEN"
+        );
+
+        let guest_access_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.guest_access?access_token={}",
+            room_id,
+            access_token
+        );
+
+        assert_eq!(
+            test.put(&guest_access_path, r#"{"guest_access": "can_join"}"#).status,
+            Status::Ok
+        );
+        assert_eq!(test.join_room(&guest_access_token, &room_id).status, Status::Ok);
+    }
+
+    #[test]
+    fn guests_cannot_invite() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let guest_access_token = test.create_guest_access_token();
+        let room_id = test.create_public_room(&access_token);
+
+        let guest_access_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.guest_access?access_token={}",
+            room_id,
+            access_token
+        );
+
+        test.put(&guest_access_path, r#"{"guest_access": "can_join"}"#);
+        test.join_room(&guest_access_token, &room_id);
+        test.create_access_token_with_username("alice");
+
+        let response = test.invite(&guest_access_token, &room_id, "@alice:ruma.test");
+
+        assert_eq!(
+            response.json().find("errcode").unwrap().as_str().unwrap(),
+            "M_GUEST_ACCESS_FORBIDDEN"
+        );
+    }
+}
@@ -0,0 +1,393 @@
+//! Endpoints for paginating through room events.
+
+use std::cmp::min;
+use std::collections::BTreeSet;
+
+use iron::{Chain, Handler, IronError, IronResult, Request, Response};
+use iron::status::Status;
+use ruma_events::EventType;
+use serde_json::{Value, from_str};
+
+use access_token::AccessToken;
+use clock::ServerClock;
+use db::DB;
+use error::ApiError;
+use event::{Event, UnsignedData};
+use filter::RoomEventFilter;
+use middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
+use modifier::SerializableResponse;
+use room_membership::RoomMembership;
+use user::User;
+
+/// The number of events returned if the client doesn't give a limit.
+const DEFAULT_LIMIT: i64 = 10;
+
+/// The most events returned in one page.
+const MAX_LIMIT: i64 = 1000;
+
+/// The GET `/rooms/:room_id/messages` endpoint.
+///
+/// Tokens are positions in the `ordering` of events, the same as the `next_batch` and
+/// `prev_batch` tokens from `/sync`. Users who have left the room can only see the events from
+/// before they left. If the filter enables `lazy_load_members`, the membership events of the
+/// chunk's senders are returned in `state`.
+pub struct GetMessages;
+
+#[derive(Debug, Serialize)]
+struct GetMessagesResponse {
+    chunk: Vec<Value>,
+    end: String,
+    start: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    state: Vec<Value>,
+}
+
+middleware_chain!(GetMessages, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for GetMessages {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let access_token_id = request.extensions.get::<AccessToken>()
+            .expect("AccessTokenAuth should ensure an access token").id;
+
+        let url = request.url.clone().into_generic_url();
+        let mut from = None;
+        let mut to = None;
+        let mut backwards = None;
+        let mut limit = DEFAULT_LIMIT;
+        let mut filter = None;
+
+        for (key, value) in url.query_pairs() {
+            let result = match &key[..] {
+                "dir" => match &value[..] {
+                    "b" | "f" => {
+                        backwards = Some(value == "b");
+
+                        Ok(())
+                    }
+                    _ => Err(ApiError::invalid_param("dir", "Must be either b or f.")),
+                },
+                "filter" => from_str::<RoomEventFilter>(&value)
+                    .map(|room_event_filter| filter = Some(room_event_filter))
+                    .map_err(|_| ApiError::invalid_param("filter", "Must be a valid event filter.")),
+                "from" => parse_token("from", &value).map(|token| from = Some(token)),
+                "limit" => match value.parse::<i64>() {
+                    Ok(value) if value > 0 => {
+                        limit = min(value, MAX_LIMIT);
+
+                        Ok(())
+                    }
+                    _ => Err(ApiError::invalid_param("limit", "Must be a positive integer.")),
+                },
+                "to" => parse_token("to", &value).map(|token| to = Some(token)),
+                _ => Ok(()),
+            };
+
+            if let Err(error) = result {
+                return Err(IronError::new(error.clone(), error));
+            }
+        }
+
+        let (from, backwards) = match (from, backwards) {
+            (Some(from), Some(backwards)) => (from, backwards),
+            (None, _) => {
+                let error = ApiError::missing_param("from");
+
+                return Err(IronError::new(error.clone(), error));
+            }
+            (_, None) => {
+                let error = ApiError::missing_param("dir");
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let clock = ServerClock::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        // Users who left can see what happened up to the moment they left.
+        let visible_until = RoomMembership::visible_until(&connection, &room_id, &user.id)?;
+
+        let (from, to) = match visible_until {
+            Some(visible_until) if backwards => (min(from, visible_until), to),
+            Some(visible_until) => {
+                (from, Some(to.map(|to| min(to, visible_until)).unwrap_or(visible_until)))
+            }
+            None => (from, to),
+        };
+
+        let events = Event::find_page_by_room(&connection, &room_id, from, to, backwards, limit)?;
+
+        // Pagination continues from the last event examined, even if the filter removed it.
+        let end = match events.last() {
+            Some(event) if backwards => event.ordering - 1,
+            Some(event) => event.ordering,
+            None => from,
+        };
+
+        let unsigned_data =
+            UnsignedData::load(&connection, &events, Some(access_token_id), clock.now_millis())?;
+
+        let mut chunk = Vec::new();
+        let mut senders = BTreeSet::new();
+
+        for event in &events {
+            let json = event.to_client_json(&unsigned_data)?;
+
+            if filter.as_ref().map(|filter| filter.matches(&json)).unwrap_or(true) {
+                chunk.push(json);
+                senders.insert(event.user_id.to_string());
+            }
+        }
+
+        let lazy_load_members = filter
+            .as_ref()
+            .and_then(|filter| filter.lazy_load_members)
+            .unwrap_or(false);
+        let mut member_events = Vec::new();
+
+        if lazy_load_members {
+            // Memberships as of the newest event in the page.
+            let before = events.iter().map(|event| event.ordering).max().unwrap_or(from) + 1;
+
+            for sender in senders {
+                let member_event = Event::find_state_event(
+                    &connection,
+                    &room_id,
+                    &EventType::RoomMember,
+                    &sender,
+                    before,
+                )?;
+
+                if let Some(member_event) = member_event {
+                    member_events.push(member_event);
+                }
+            }
+        }
+
+        let unsigned_data = UnsignedData::load(
+            &connection,
+            &member_events,
+            Some(access_token_id),
+            clock.now_millis(),
+        )?;
+
+        let mut state = Vec::new();
+
+        for member_event in &member_events {
+            state.push(member_event.to_client_json(&unsigned_data)?);
+        }
+
+        let response = GetMessagesResponse {
+            chunk: chunk,
+            end: end.to_string(),
+            start: from.to_string(),
+            state: state,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// Parses a pagination token.
+fn parse_token(param: &str, token: &str) -> Result<i64, ApiError> {
+    token.parse().map_err(|_| ApiError::invalid_param(param, "Must be a pagination token."))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    fn send_message(test: &Test, access_token: &str, room_id: &str, txn_id: u32) {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/{}?access_token={}",
+            room_id,
+            txn_id,
+            access_token
+        );
+
+        let body = format!(r#"{{"msgtype":"m.text","body":"Message {}"}}"#, txn_id);
+
+        assert_eq!(test.put(&path, &body).status, Status::Ok);
+    }
+
+    fn messages(test: &Test, access_token: &str, room_id: &str, query: &str) -> Value {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?access_token={}&{}",
+            room_id,
+            access_token,
+            query
+        );
+
+        let response = test.get(&path);
+
+        assert_eq!(response.status, Status::Ok);
+
+        response.json().clone()
+    }
+
+    fn bodies(response: &Value) -> Vec<String> {
+        response
+            .find("chunk")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|event| event.find_path(&["content", "body"]))
+            .map(|body| body.as_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn sync_token(test: &Test, access_token: &str) -> String {
+        let response = test.get(&format!("/_matrix/client/r0/sync?access_token={}", access_token));
+
+        response.json().find("next_batch").unwrap().as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn paginate_backwards() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        for txn_id in 0..5 {
+            send_message(&test, &access_token, &room_id, txn_id);
+        }
+
+        let from = sync_token(&test, &access_token);
+        let query = format!("from={}&dir=b&limit=2", from);
+        let first_page = messages(&test, &access_token, &room_id, &query);
+
+        assert_eq!(bodies(&first_page), vec!["Message 4", "Message 3"]);
+
+        let end = first_page.find("end").unwrap().as_str().unwrap();
+        let query = format!("from={}&dir=b&limit=2", end);
+        let second_page = messages(&test, &access_token, &room_id, &query);
+
+        assert_eq!(bodies(&second_page), vec!["Message 2", "Message 1"]);
+    }
+
+    #[test]
+    fn paginate_forwards_to_a_token() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        send_message(&test, &access_token, &room_id, 0);
+
+        let from = sync_token(&test, &access_token);
+
+        send_message(&test, &access_token, &room_id, 1);
+        send_message(&test, &access_token, &room_id, 2);
+
+        let to = sync_token(&test, &access_token);
+
+        send_message(&test, &access_token, &room_id, 3);
+
+        let query = format!("from={}&to={}&dir=f", from, to);
+        let response = messages(&test, &access_token, &room_id, &query);
+
+        assert_eq!(bodies(&response), vec!["Message 1", "Message 2"]);
+        assert_eq!(response.find("end").unwrap().as_str().unwrap(), to);
+    }
+
+    #[test]
+    fn lazy_loaded_members_of_senders() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&access_token);
+
+        assert_eq!(test.join_room(&alice_access_token, &room_id).status, Status::Ok);
+
+        send_message(&test, &alice_access_token, &room_id, 0);
+
+        let from = sync_token(&test, &access_token);
+        let query = format!(r#"from={}&dir=b&limit=1&filter={{"lazy_load_members":true}}"#, from);
+        let response = messages(&test, &access_token, &room_id, &query);
+        let state = response.find("state").unwrap().as_array().unwrap();
+
+        assert_eq!(state.len(), 1);
+        assert_eq!(state[0].find("state_key").unwrap().as_str().unwrap(), "@alice:ruma.test");
+
+        let query = format!("from={}&dir=b&limit=1", from);
+        let response = messages(&test, &access_token, &room_id, &query);
+
+        assert!(response.find("state").is_none());
+    }
+
+    #[test]
+    fn non_members_cannot_read_messages() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&access_token);
+        let from = sync_token(&test, &access_token);
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?access_token={}&from={}&dir=b",
+            room_id,
+            alice_access_token,
+            from
+        );
+
+        assert_eq!(test.get(&path).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn unsigned_age_and_transaction_id() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&carl_token);
+
+        assert!(test.join_room(&alice_token, &room_id).status.is_success());
+
+        send_message(&test, &carl_token, &room_id, 7);
+
+        test.advance_clock(Duration::minutes(1));
+
+        let query = "from=0&dir=f&limit=100";
+        let carl_chunk = messages(&test, &carl_token, &room_id, query);
+        let alice_chunk = messages(&test, &alice_token, &room_id, query);
+        let carl_message = carl_chunk.find("chunk").unwrap().as_array().unwrap().last().unwrap();
+        let alice_message = alice_chunk.find("chunk").unwrap().as_array().unwrap().last().unwrap();
+
+        let age = carl_message.find_path(&["unsigned", "age"]).and_then(Value::as_i64).unwrap();
+
+        assert!(age >= 50_000 && age <= 60_000);
+        assert_eq!(
+            carl_message.find_path(&["unsigned", "transaction_id"]).and_then(Value::as_str),
+            Some("7")
+        );
+
+        // Only the device that sent the event gets its transaction ID back.
+        assert!(alice_message.find_path(&["unsigned", "age"]).is_some());
+        assert!(alice_message.find_path(&["unsigned", "transaction_id"]).is_none());
+    }
+
+    #[test]
+    fn dir_is_required() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?access_token={}&from=1",
+            room_id,
+            access_token
+        );
+
+        assert_eq!(test.get(&path).status, Status::BadRequest);
+    }
+}
@@ -5,26 +5,102 @@ pub use self::account::{
     DeactivateAccount,
     PutAccountData,
     PutRoomAccountData,
+    WhoAmI,
+};
+pub use self::capabilities::GetCapabilities;
+pub use self::context::GetContext;
+pub use self::device::{DeleteDevice, GetDevice, GetDevices, PutDevice};
+pub use self::directory::{
+    DeleteRoomAlias,
+    GetRoomAlias,
+    GetRoomAliases,
+    GetRoomVisibility,
+    PutRoomAlias,
+    PutRoomVisibility,
 };
-pub use self::directory::{GetRoomAlias, DeleteRoomAlias, PutRoomAlias};
 pub use self::event_creation::{SendMessageEvent, StateMessageEvent};
-pub use self::join::{InviteToRoom, JoinRoom};
+pub use self::filter::{GetFilter, PostFilter};
 pub use self::login::Login;
 pub use self::logout::Logout;
 pub use self::members::Members;
+pub use self::membership::{
+    BanFromRoom,
+    ForgetRoom,
+    InviteToRoom,
+    JoinRoom,
+    JoinRoomByIdOrAlias,
+    KickFromRoom,
+    LeaveRoom,
+    UnbanFromRoom,
+};
+pub use self::messages::GetMessages;
+pub use self::notifications::GetNotifications;
+pub use self::presence::{GetPresenceStatus, PutPresenceStatus};
 pub use self::profile::{Profile, GetAvatarUrl, PutAvatarUrl, GetDisplayName, PutDisplayName};
+pub use self::public_rooms::{GetPublicRooms, PostPublicRooms};
+pub use self::push_rules::{
+    DeletePushRule,
+    GetPushRule,
+    GetPushRuleActions,
+    GetPushRuleEnabled,
+    GetPushRules,
+    PutPushRule,
+    PutPushRuleActions,
+    PutPushRuleEnabled,
+};
+pub use self::pushers::{GetPushers, SetPusher};
+pub use self::receipt::{PostReadMarkers, PostReceipt};
+pub use self::redaction::RedactEvent;
 pub use self::registration::Register;
 pub use self::room_creation::CreateRoom;
-pub use self::versions::Versions;
+pub use self::room_state::{GetStateEvent, GetStateEvents};
+pub use self::room_upgrade::UpgradeRoom;
+pub use self::search::Search;
+pub use self::sync::Sync;
+pub use self::tags::{DeleteTag, GetTags, PutTag};
+pub use self::threepid::{
+    AddThreepid,
+    DeleteThreepid,
+    GetThreepids,
+    RequestMsisdnToken,
+    SubmitMsisdnToken,
+};
+pub use self::to_device::SendToDevice;
+pub use self::typing::PutTyping;
+pub use self::user_directory::SearchUserDirectory;
+pub use self::versions::{ServerVersion, Versions};
+pub use self::well_known::GetWellKnownClient;
 
 mod account;
+mod capabilities;
+mod context;
+mod device;
 mod directory;
 mod event_creation;
-mod join;
+mod filter;
 mod login;
 mod logout;
 mod members;
+mod membership;
+mod messages;
+mod notifications;
+mod presence;
 mod profile;
+mod public_rooms;
+mod push_rules;
+mod pushers;
+mod receipt;
+mod redaction;
 mod registration;
 mod room_creation;
+mod room_state;
+mod room_upgrade;
+mod search;
+mod sync;
+mod tags;
+mod threepid;
+mod to_device;
+mod typing;
+mod user_directory;
 mod versions;
+mod well_known;
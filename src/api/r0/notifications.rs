@@ -0,0 +1,336 @@
+//! Endpoints for listing the events a user was notified about.
+
+use std::cmp::min;
+use std::collections::HashMap;
+
+use iron::{Chain, Handler, IronError, IronResult, Request, Response};
+use iron::status::Status;
+use ruma_identifiers::RoomId;
+use serde_json::Value;
+
+use db::DB;
+use error::ApiError;
+use event::Event;
+use middleware::{AccessTokenAuth, MiddlewareChain};
+use modifier::SerializableResponse;
+use push_rule::{NotificationContext, Ruleset, joined_rooms, read_position};
+use user::User;
+
+/// The number of notifications returned if the client doesn't give a limit.
+const DEFAULT_LIMIT: usize = 20;
+
+/// The most notifications returned in one page.
+const MAX_LIMIT: usize = 100;
+
+/// The number of events loaded at a time while looking for notifications.
+const SCAN_BATCH_SIZE: i64 = 100;
+
+/// The most events examined for one page, so that a user who is rarely notified doesn't make
+/// every request scan their whole history.
+const MAX_SCANNED_EVENTS: i64 = 1000;
+
+/// The GET `/notifications` endpoint.
+///
+/// Notifications aren't stored: the user's current push rules are evaluated against the events
+/// in their joined rooms, newest first. Tokens are positions in the `ordering` of events. A page
+/// may hold fewer notifications than the limit and still have a `next_token` if the search
+/// stopped early.
+pub struct GetNotifications;
+
+#[derive(Debug, Serialize)]
+struct GetNotificationsResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_token: Option<String>,
+    notifications: Vec<NotificationResponse>,
+}
+
+#[derive(Debug, Serialize)]
+struct NotificationResponse {
+    actions: Vec<Value>,
+    event: Value,
+    read: bool,
+    room_id: String,
+    ts: i64,
+}
+
+/// What's needed to decide whether the user was notified about events in one room.
+struct RoomState {
+    context: NotificationContext,
+    joined_at: i64,
+    read_up_to: i64,
+}
+
+middleware_chain!(GetNotifications, [AccessTokenAuth]);
+
+impl Handler for GetNotifications {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let url = request.url.clone().into_generic_url();
+        let mut from = None;
+        let mut limit = DEFAULT_LIMIT;
+        let mut only_highlight = false;
+
+        for (key, value) in url.query_pairs() {
+            let result = match &key[..] {
+                "from" => value.parse::<i64>()
+                    .map(|token| from = Some(token))
+                    .map_err(|_| ApiError::invalid_param("from", "Must be a pagination token.")),
+                "limit" => match value.parse::<usize>() {
+                    Ok(value) if value > 0 => {
+                        limit = min(value, MAX_LIMIT);
+
+                        Ok(())
+                    }
+                    _ => Err(ApiError::invalid_param("limit", "Must be a positive integer.")),
+                },
+                "only" => {
+                    only_highlight = value == "highlight";
+
+                    Ok(())
+                }
+                _ => Ok(()),
+            };
+
+            if let Err(error) = result {
+                return Err(IronError::new(error.clone(), error));
+            }
+        }
+
+        let connection = DB::from_request(request)?;
+
+        let ruleset = Ruleset::load(&connection, &user.id)?;
+        let joined_rooms = joined_rooms(&connection, &user.id)?;
+        let room_ids: Vec<RoomId> =
+            joined_rooms.iter().map(|&(ref room_id, _)| room_id.clone()).collect();
+
+        let mut rooms: HashMap<String, RoomState> = HashMap::new();
+        let mut notifications = Vec::new();
+        let mut before = from.unwrap_or(i64::max_value());
+        let mut scanned = 0;
+        let mut next_token = None;
+
+        'scan: loop {
+            if scanned >= MAX_SCANNED_EVENTS {
+                next_token = Some(before);
+
+                break;
+            }
+
+            let events =
+                Event::find_page_by_rooms(&connection, &room_ids, before, SCAN_BATCH_SIZE)?;
+
+            if events.is_empty() {
+                break;
+            }
+
+            for event in &events {
+                scanned += 1;
+                before = event.ordering;
+
+                if event.user_id == user.id {
+                    continue;
+                }
+
+                let room_key = event.room_id.to_string();
+
+                if !rooms.contains_key(&room_key) {
+                    let joined_at = joined_rooms
+                        .iter()
+                        .find(|&&(ref room_id, _)| *room_id == event.room_id)
+                        .map(|&(_, joined_at)| joined_at)
+                        .unwrap_or(i64::max_value());
+
+                    let context = NotificationContext::load(&connection, &event.room_id, &user.id)?;
+                    let read_up_to =
+                        read_position(&connection, &event.room_id, &user.id, joined_at)?;
+
+                    rooms.insert(room_key.clone(), RoomState {
+                        context: context,
+                        joined_at: joined_at,
+                        read_up_to: read_up_to,
+                    });
+                }
+
+                let room = &rooms[&room_key];
+
+                // Only events sent while the user was in the room could have notified them.
+                if event.ordering <= room.joined_at {
+                    continue;
+                }
+
+                let notification = ruleset.evaluate(event, &room.context)?;
+
+                if !notification.notify || (only_highlight && !notification.highlight) {
+                    continue;
+                }
+
+                let mut json = event.to_json()?;
+                let ts = json.find("origin_server_ts").and_then(Value::as_i64).unwrap_or(0);
+
+                if let Value::Object(ref mut map) = json {
+                    map.insert("room_id".to_string(), Value::String(room_key.clone()));
+                }
+
+                notifications.push(NotificationResponse {
+                    actions: notification.actions,
+                    event: json,
+                    read: event.ordering <= room.read_up_to,
+                    room_id: room_key,
+                    ts: ts,
+                });
+
+                if notifications.len() == limit {
+                    next_token = Some(before);
+
+                    break 'scan;
+                }
+            }
+        }
+
+        let response = GetNotificationsResponse {
+            next_token: next_token.map(|token| token.to_string()),
+            notifications: notifications,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    /// Sends a message to the room and returns its event ID.
+    fn send_message(test: &Test, access_token: &str, room_id: &str, txn_id: u32, body: &str)
+    -> String {
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/send/m.room.message/{}?access_token={}",
+                room_id,
+                txn_id,
+                access_token
+            ),
+            &format!(r#"{{"msgtype": "m.text", "body": "{}"}}"#, body),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        response.json().find("event_id").and_then(Value::as_str).unwrap().to_string()
+    }
+
+    /// The bodies of the messages in a page of notifications, and its next token.
+    fn notifications(test: &Test, access_token: &str, query: &str)
+    -> (Vec<String>, Option<String>) {
+        let response = test.get(
+            &format!("/_matrix/client/r0/notifications?access_token={}{}", access_token, query),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+        let bodies = json
+            .find("notifications")
+            .and_then(Value::as_array)
+            .unwrap()
+            .iter()
+            .map(|notification| {
+                notification
+                    .find_path(&["event", "content", "body"])
+                    .and_then(Value::as_str)
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        let next_token = json.find("next_token").and_then(Value::as_str).map(str::to_string);
+
+        (bodies, next_token)
+    }
+
+    #[test]
+    fn notifications_are_listed_newest_first() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&carl_token);
+
+        assert_eq!(test.join_room(&alice_token, &room_id).status, Status::Ok);
+
+        let first_id = send_message(&test, &alice_token, &room_id, 1, "First");
+        send_message(&test, &carl_token, &room_id, 1, "From Carl");
+        send_message(&test, &alice_token, &room_id, 2, "Second");
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/receipt/m.read/{}?access_token={}",
+                room_id,
+                first_id,
+                carl_token
+            ),
+            "{}",
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response =
+            test.get(&format!("/_matrix/client/r0/notifications?access_token={}", carl_token));
+        let json = response.json();
+        let notifications = json.find("notifications").and_then(Value::as_array).unwrap();
+
+        assert_eq!(notifications.len(), 2);
+        assert_eq!(
+            notifications[0].find_path(&["event", "content", "body"]).and_then(Value::as_str),
+            Some("Second")
+        );
+        assert_eq!(notifications[0].find("read").and_then(Value::as_bool), Some(false));
+        assert_eq!(notifications[0].find("room_id").and_then(Value::as_str), Some(&room_id[..]));
+        assert_eq!(notifications[1].find("read").and_then(Value::as_bool), Some(true));
+        assert!(json.find("next_token").is_none());
+
+        let (bodies, next_token) = notifications(&test, &carl_token, "&limit=1");
+
+        assert_eq!(bodies, vec!["Second"]);
+
+        let query = format!("&limit=1&from={}", next_token.unwrap());
+        let (bodies, _) = notifications(&test, &carl_token, &query);
+
+        assert_eq!(bodies, vec!["First"]);
+    }
+
+    #[test]
+    fn only_highlights() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&carl_token);
+
+        assert_eq!(test.join_room(&alice_token, &room_id).status, Status::Ok);
+
+        send_message(&test, &alice_token, &room_id, 1, "Hello carl");
+        send_message(&test, &alice_token, &room_id, 2, "Hello everyone");
+
+        let (bodies, _) = notifications(&test, &carl_token, "");
+
+        assert_eq!(bodies, vec!["Hello everyone", "Hello carl"]);
+
+        let (bodies, _) = notifications(&test, &carl_token, "&only=highlight");
+
+        assert_eq!(bodies, vec!["Hello carl"]);
+    }
+
+    #[test]
+    fn invalid_pagination_tokens() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response = test.get(
+            &format!("/_matrix/client/r0/notifications?access_token={}&from=x", access_token),
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+}
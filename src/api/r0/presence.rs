@@ -0,0 +1,254 @@
+//! Endpoints for presence.
+
+use bodyparser;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+
+use clock::ServerClock;
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, UserIdParam};
+use modifier::SerializableResponse;
+use presence::{NewPresence, PRESENCE_STATES, Presence};
+use user::User;
+
+/// The GET `/presence/:user_id/status` endpoint.
+pub struct GetPresenceStatus;
+
+/// The PUT `/presence/:user_id/status` endpoint.
+pub struct PutPresenceStatus;
+
+#[derive(Clone, Debug, Deserialize)]
+struct PutPresenceStatusRequest {
+    presence: String,
+    status_msg: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct GetPresenceStatusResponse {
+    currently_active: bool,
+    last_active_ago: i64,
+    presence: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status_msg: Option<String>,
+}
+
+middleware_chain!(GetPresenceStatus, [UserIdParam, AccessTokenAuth]);
+
+impl Handler for GetPresenceStatus {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let user_id = request.extensions.get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId").clone();
+
+        let clock = ServerClock::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        if !Presence::is_visible_to(&connection, &user.id, &user_id)? {
+            let error = ApiError::unauthorized(
+                Some("You must share a room with the user to see their presence.")
+            );
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let presence = match Presence::find(&connection, &user_id)? {
+            Some(presence) => presence,
+            None => {
+                let error = ApiError::not_found(Some("The user has not set their presence."));
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let now = clock.now_millis();
+        let response = GetPresenceStatusResponse {
+            currently_active: presence.currently_active(now),
+            last_active_ago: now - presence.last_active_at,
+            presence: presence.state,
+            status_msg: presence.status_msg,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+middleware_chain!(PutPresenceStatus, [JsonRequest, UserIdParam, AccessTokenAuth]);
+
+impl Handler for PutPresenceStatus {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let presence_request = match request.get::<bodyparser::Struct<PutPresenceStatusRequest>>() {
+            Ok(Some(presence_request)) => presence_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let user_id = request.extensions.get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId").clone();
+
+        if user_id != user.id {
+            let error = ApiError::unauthorized(Some("You can only set your own presence."));
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        if !PRESENCE_STATES.contains(&&presence_request.presence[..]) {
+            let error = ApiError::bad_json(
+                Some("presence must be one of offline, online, or unavailable.")
+            );
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let clock = ServerClock::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        let new_presence = NewPresence {
+            user_id: user_id,
+            state: presence_request.presence,
+            status_msg: presence_request.status_msg,
+            last_active_at: clock.now_millis(),
+        };
+
+        Presence::upsert(&connection, &new_presence)?;
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::{Response, Test};
+
+    fn put_presence(test: &Test, access_token: &str, user_id: &str, body: &str) -> Response {
+        let path = format!(
+            "/_matrix/client/r0/presence/{}/status?access_token={}",
+            user_id,
+            access_token
+        );
+
+        test.put(&path, body)
+    }
+
+    fn get_presence(test: &Test, access_token: &str, user_id: &str) -> Response {
+        let path = format!(
+            "/_matrix/client/r0/presence/{}/status?access_token={}",
+            user_id,
+            access_token
+        );
+
+        test.get(&path)
+    }
+
+    #[test]
+    fn set_and_get_own_presence() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response = put_presence(
+            &test,
+            &access_token,
+            "@carl:ruma.test",
+            r#"{"presence": "unavailable", "status_msg": "At lunch"}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        test.advance_clock(Duration::seconds(10));
+
+        let response = get_presence(&test, &access_token, "@carl:ruma.test");
+        let json = response.json();
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(json.find("presence").unwrap().as_str().unwrap(), "unavailable");
+        assert_eq!(json.find("status_msg").unwrap().as_str().unwrap(), "At lunch");
+        assert_eq!(json.find("last_active_ago").unwrap().as_u64().unwrap(), 10_000);
+        assert_eq!(json.find("currently_active").unwrap().as_bool().unwrap(), false);
+    }
+
+    #[test]
+    fn invalid_presence_state() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response = put_presence(
+            &test,
+            &access_token,
+            "@carl:ruma.test",
+            r#"{"presence": "busy"}"#,
+        );
+
+        assert_eq!(response.status, Status::UnprocessableEntity);
+    }
+
+    #[test]
+    fn presence_is_only_visible_to_room_peers() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+
+        let response =
+            put_presence(&test, &carl_token, "@alice:ruma.test", r#"{"presence": "online"}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+
+        put_presence(&test, &alice_token, "@alice:ruma.test", r#"{"presence": "online"}"#);
+
+        let response = get_presence(&test, &carl_token, "@alice:ruma.test");
+
+        assert_eq!(response.status, Status::Forbidden);
+
+        let room_id = test.create_public_room(&carl_token);
+
+        assert!(test.join_room(&alice_token, &room_id).status.is_success());
+
+        let response = get_presence(&test, &carl_token, "@alice:ruma.test");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().find("presence").unwrap().as_str().unwrap(), "online");
+    }
+
+    #[test]
+    fn presence_shows_up_in_sync() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&carl_token);
+
+        assert!(test.join_room(&alice_token, &room_id).status.is_success());
+
+        let sync_path = format!("/_matrix/client/r0/sync?access_token={}", carl_token);
+        let next_batch = test.get(&sync_path).json()
+            .find("next_batch").unwrap().as_str().unwrap().to_string();
+
+        put_presence(&test, &alice_token, "@alice:ruma.test", r#"{"presence": "online"}"#);
+
+        let response = test.get(&format!("{}&since={}", sync_path, next_batch));
+        let events = response.json().find_path(&["presence", "events"]).unwrap()
+            .as_array().unwrap().clone();
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].find("type").unwrap().as_str().unwrap(), "m.presence");
+        assert_eq!(events[0].find("sender").unwrap().as_str().unwrap(), "@alice:ruma.test");
+        assert_eq!(
+            events[0].find_path(&["content", "presence"]).and_then(Value::as_str).unwrap(),
+            "online"
+        );
+        assert_eq!(
+            events[0].find_path(&["content", "currently_active"]).and_then(Value::as_bool),
+            Some(true)
+        );
+    }
+}
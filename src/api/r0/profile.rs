@@ -1,6 +1,7 @@
 //! Endpoints for profile.
 
 use bodyparser;
+use diesel::Connection;
 use iron::{Chain, Handler, IronResult, IronError, Plugin, Request, Response};
 use iron::status::Status;
 
@@ -145,13 +146,15 @@ impl Handler for PutAvatarUrl {
             return Err(IronError::new(error.clone(), error));
         }
 
-        DataProfile::update_avatar_url(
-            &connection,
-            user_id.clone(),
-            avatar_url_request.avatar_url
-        )?;
+        connection.transaction::<(), ApiError, _>(|| {
+            DataProfile::update_avatar_url(
+                &connection,
+                user_id.clone(),
+                avatar_url_request.avatar_url.clone()
+            )?;
 
-        DataProfile::update_memberships(&connection, &config.domain, user_id.clone())?;
+            DataProfile::update_memberships(&connection, &config.domain, user_id.clone())
+        }).map_err(ApiError::from)?;
 
         Ok(Response::with(Status::Ok))
     }
@@ -247,13 +250,15 @@ impl Handler for PutDisplayName {
             return Err(IronError::new(error.clone(), error));
         }
 
-        DataProfile::update_displayname(
-            &connection,
-            user_id.clone(),
-            displayname_request.displayname
-        )?;
+        connection.transaction::<(), ApiError, _>(|| {
+            DataProfile::update_displayname(
+                &connection,
+                user_id.clone(),
+                displayname_request.displayname.clone()
+            )?;
 
-        DataProfile::update_memberships(&connection, &config.domain, user_id.clone())?;
+            DataProfile::update_memberships(&connection, &config.domain, user_id.clone())
+        }).map_err(ApiError::from)?;
 
         Ok(Response::with(Status::Ok))
     }
@@ -450,6 +455,50 @@ mod tests {
         );
     }
 
+    #[test]
+    fn profile_changes_update_joined_rooms() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let joined_room_id = test.create_public_room(&carl_token);
+        let left_room_id = test.create_public_room(&carl_token);
+
+        assert_eq!(test.join_room(&alice_token, &joined_room_id).status, Status::Ok);
+        assert_eq!(test.join_room(&alice_token, &left_room_id).status, Status::Ok);
+
+        let leave_path = format!(
+            "/_matrix/client/r0/rooms/{}/leave?access_token={}",
+            left_room_id,
+            alice_token
+        );
+
+        assert_eq!(test.post(&leave_path, "{}").status, Status::Ok);
+
+        let displayname_path = format!(
+            "/_matrix/client/r0/profile/@alice:ruma.test/displayname?access_token={}",
+            alice_token
+        );
+
+        assert!(test.put(&displayname_path, r#"{"displayname": "Alice"}"#).status.is_success());
+
+        let member_path = |room_id: &str| {
+            format!(
+                "/_matrix/client/r0/rooms/{}/state/m.room.member/@alice:ruma.test?access_token={}",
+                room_id,
+                carl_token
+            )
+        };
+
+        let response = test.get(&member_path(&joined_room_id));
+
+        assert_eq!(response.json().find("membership").unwrap().as_str().unwrap(), "join");
+        assert_eq!(response.json().find("displayname").unwrap().as_str().unwrap(), "Alice");
+
+        let response = test.get(&member_path(&left_room_id));
+
+        assert_eq!(response.json().find("membership").unwrap().as_str().unwrap(), "leave");
+    }
+
     #[test]
     fn get_profile_non_existent_user() {
         let test = Test::new();
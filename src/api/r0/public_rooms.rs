@@ -0,0 +1,285 @@
+//! Endpoints for the public room directory.
+
+use std::cmp::min;
+
+use bodyparser;
+use diesel::pg::PgConnection;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
+use modifier::SerializableResponse;
+use public_room::PublicRoom;
+
+/// The number of rooms returned per page if the client doesn't give a limit.
+const DEFAULT_LIMIT: u64 = 100;
+
+/// The GET `/publicRooms` endpoint.
+///
+/// Lists the public rooms on this server, largest first. The `since` tokens are offsets into the
+/// list.
+pub struct GetPublicRooms;
+
+/// The POST `/publicRooms` endpoint.
+///
+/// Like the GET endpoint, but the list can be narrowed to the rooms whose name, topic, or aliases
+/// contain `filter.generic_search_term`. Rooms with the term as an alias are listed first, then
+/// rooms whose name starts with it, then the rest, each largest first.
+pub struct PostPublicRooms;
+
+#[derive(Clone, Debug, Deserialize)]
+struct PostPublicRoomsRequest {
+    filter: Option<PublicRoomsFilter>,
+    limit: Option<u64>,
+    since: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct PublicRoomsFilter {
+    generic_search_term: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct PublicRoomsResponse {
+    chunk: Vec<PublicRoom>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_batch: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prev_batch: Option<String>,
+    total_room_count_estimate: u64,
+}
+
+middleware_chain!(GetPublicRooms, []);
+
+impl Handler for GetPublicRooms {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let url = request.url.clone().into_generic_url();
+        let mut limit = None;
+        let mut since = None;
+
+        for (key, value) in url.query_pairs() {
+            match &key[..] {
+                "limit" => match value.parse() {
+                    Ok(value) => limit = Some(value),
+                    Err(_) => {
+                        let error = ApiError::invalid_param("limit", "Must be a number of rooms.");
+
+                        return Err(IronError::new(error.clone(), error));
+                    }
+                },
+                "since" => since = Some(value.into_owned()),
+                _ => {}
+            }
+        }
+
+        let connection = DB::from_request(request)?;
+
+        let response = public_rooms(&connection, limit, since, None)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+middleware_chain!(PostPublicRooms, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for PostPublicRooms {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let rooms_request = match request.get::<bodyparser::Struct<PostPublicRoomsRequest>>() {
+            Ok(Some(rooms_request)) => rooms_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let search_term = rooms_request.filter
+            .and_then(|filter| filter.generic_search_term)
+            .and_then(|search_term| {
+                match search_term.trim() {
+                    "" => None,
+                    search_term => Some(search_term.to_string()),
+                }
+            });
+
+        let connection = DB::from_request(request)?;
+
+        let response = public_rooms(
+            &connection,
+            rooms_request.limit,
+            rooms_request.since,
+            search_term,
+        )?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// Builds one page of the directory, starting at the offset in `since`.
+fn public_rooms(
+    connection: &PgConnection,
+    limit: Option<u64>,
+    since: Option<String>,
+    search_term: Option<String>,
+) -> Result<PublicRoomsResponse, ApiError> {
+    let offset: u64 = match since {
+        Some(since) => since.parse().map_err(|_| {
+            ApiError::invalid_param("since", "Must be a next_batch or prev_batch token.")
+        })?,
+        None => 0,
+    };
+
+    let limit = match limit {
+        Some(0) | None => DEFAULT_LIMIT,
+        Some(limit) => limit,
+    };
+
+    let max = i64::max_value() as u64;
+    let (chunk, total) = PublicRoom::find_page(
+        connection,
+        search_term.as_ref().map(String::as_str),
+        min(offset, max) as i64,
+        min(limit, max) as i64,
+    )?;
+
+    let start = min(offset, total);
+    let end = start + chunk.len() as u64;
+
+    Ok(PublicRoomsResponse {
+        chunk: chunk,
+        next_batch: if end < total { Some(end.to_string()) } else { None },
+        prev_batch: if start > 0 { Some(start.saturating_sub(limit).to_string()) } else { None },
+        total_room_count_estimate: total,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    fn room_ids(response: &Value) -> Vec<String> {
+        response
+            .find("chunk")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|room| room.find("room_id").unwrap().as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn only_public_rooms_are_listed() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let public_room_id = test.create_public_room(&access_token);
+        test.create_room(&access_token);
+
+        let response = test.get("/_matrix/client/r0/publicRooms");
+
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+
+        assert_eq!(room_ids(json), vec![public_room_id]);
+        assert_eq!(json.find("total_room_count_estimate").unwrap().as_u64(), Some(1));
+    }
+
+    #[test]
+    fn rooms_are_paginated_largest_first() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let small_room_id = test.create_public_room(&access_token);
+        let large_room_id = test.create_public_room(&access_token);
+
+        assert_eq!(test.join_room(&alice_access_token, &large_room_id).status, Status::Ok);
+
+        let first_page = test.get("/_matrix/client/r0/publicRooms?limit=1").json().clone();
+
+        assert_eq!(room_ids(&first_page), vec![large_room_id.clone()]);
+        assert_eq!(
+            first_page.find_path(&["chunk"]).unwrap().as_array().unwrap()[0]
+                .find("num_joined_members").unwrap().as_u64(),
+            Some(2)
+        );
+
+        let next_batch = first_page.find("next_batch").unwrap().as_str().unwrap();
+        let second_page = test
+            .get(&format!("/_matrix/client/r0/publicRooms?limit=1&since={}", next_batch))
+            .json()
+            .clone();
+
+        assert_eq!(room_ids(&second_page), vec![small_room_id]);
+        assert!(second_page.find("next_batch").is_none());
+        assert_eq!(second_page.find("prev_batch").unwrap().as_str().unwrap(), "0");
+    }
+
+    #[test]
+    fn search_by_name() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let path = format!("/_matrix/client/r0/createRoom?access_token={}", access_token);
+        let response = test.post(&path, r#"{"visibility": "public", "name": "Rust Lovers"}"#);
+        let room_id = response.json().find("room_id").unwrap().as_str().unwrap().to_string();
+
+        test.create_public_room(&access_token);
+
+        let path = format!("/_matrix/client/r0/publicRooms?access_token={}", access_token);
+        let response = test.post(&path, r#"{"filter": {"generic_search_term": "rust"}}"#);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(room_ids(response.json()), vec![room_id]);
+        assert_eq!(
+            response.json().find_path(&["chunk"]).unwrap().as_array().unwrap()[0]
+                .find("name").unwrap().as_str().unwrap(),
+            "Rust Lovers"
+        );
+    }
+
+    #[test]
+    fn search_results_are_ranked() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let create_room = |body: &str| {
+            let path = format!("/_matrix/client/r0/createRoom?access_token={}", access_token);
+            let response = test.post(&path, body);
+
+            response.json().find("room_id").unwrap().as_str().unwrap().to_string()
+        };
+
+        let small_room_id = create_room(r#"{"visibility": "public", "topic": "All about Rust"}"#);
+        let large_room_id = create_room(r#"{"visibility": "public", "topic": "Rust news"}"#);
+        let name_room_id = create_room(r#"{"visibility": "public", "name": "Rustaceans"}"#);
+        let alias_room_id = create_room(r#"{"visibility": "public", "room_alias_name": "rust"}"#);
+
+        create_room(r#"{"visibility": "public", "name": "Go"}"#);
+
+        assert_eq!(test.join_room(&alice_access_token, &large_room_id).status, Status::Ok);
+
+        let path = format!("/_matrix/client/r0/publicRooms?access_token={}", access_token);
+        let first_page = test
+            .post(&path, r#"{"filter": {"generic_search_term": "rust"}, "limit": 2}"#)
+            .json()
+            .clone();
+
+        assert_eq!(room_ids(&first_page), vec![alias_room_id, name_room_id]);
+        assert_eq!(first_page.find("total_room_count_estimate").unwrap().as_u64(), Some(4));
+
+        let next_batch = first_page.find("next_batch").unwrap().as_str().unwrap();
+        let body = format!(
+            r#"{{"filter": {{"generic_search_term": "rust"}}, "limit": 2, "since": "{}"}}"#,
+            next_batch
+        );
+        let second_page = test.post(&path, &body).json().clone();
+
+        assert_eq!(room_ids(&second_page), vec![large_room_id, small_room_id]);
+        assert!(second_page.find("next_batch").is_none());
+    }
+}
@@ -0,0 +1,585 @@
+//! Endpoints for push rules.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use bodyparser;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use router::Router;
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::{Value, to_string};
+
+use db::DB;
+use error::ApiError;
+use middleware::{
+    AccessTokenAuth,
+    JsonRequest,
+    MiddlewareChain,
+    PushRuleIdParam,
+    PushRuleKindParam,
+};
+use modifier::SerializableResponse;
+use push_rule::{NewPushRule, PushRule, Rule, Ruleset, validate_actions};
+use user::User;
+
+/// The GET `/pushrules/` and `/pushrules/:scope/` endpoints.
+///
+/// Without a scope, the rules are returned under the only scope, *global*.
+pub struct GetPushRules;
+
+/// The GET `/pushrules/:scope/:kind/:rule_id` endpoint.
+pub struct GetPushRule;
+
+/// The PUT `/pushrules/:scope/:kind/:rule_id` endpoint.
+///
+/// Adds or replaces one of the user's rules. The `before` and `after` query parameters place it
+/// next to another of the user's rules of the same kind.
+pub struct PutPushRule;
+
+/// The DELETE `/pushrules/:scope/:kind/:rule_id` endpoint.
+pub struct DeletePushRule;
+
+/// The GET `/pushrules/:scope/:kind/:rule_id/enabled` endpoint.
+pub struct GetPushRuleEnabled;
+
+/// The PUT `/pushrules/:scope/:kind/:rule_id/enabled` endpoint.
+pub struct PutPushRuleEnabled;
+
+/// The GET `/pushrules/:scope/:kind/:rule_id/actions` endpoint.
+pub struct GetPushRuleActions;
+
+/// The PUT `/pushrules/:scope/:kind/:rule_id/actions` endpoint.
+pub struct PutPushRuleActions;
+
+#[derive(Clone, Debug, Deserialize)]
+struct PutPushRuleRequest {
+    actions: Vec<Value>,
+    conditions: Option<Vec<Value>>,
+    pattern: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct PushRuleEnabled {
+    enabled: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct PushRuleActions {
+    actions: Vec<Value>,
+}
+
+middleware_chain!(GetPushRules, [AccessTokenAuth]);
+
+impl Handler for GetPushRules {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let scope = request.extensions.get::<Router>()
+            .expect("Params object is missing")
+            .find("scope")
+            .map(str::to_string);
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let ruleset = Ruleset::load(&connection, &user.id)?;
+
+        match scope {
+            Some(ref scope) if scope == "global" => {
+                Ok(Response::with((Status::Ok, SerializableResponse(ruleset))))
+            }
+            Some(_) => {
+                let error = ApiError::invalid_param("scope", "The only scope is global.");
+
+                Err(IronError::new(error.clone(), error))
+            }
+            None => {
+                let mut scopes = BTreeMap::new();
+
+                scopes.insert("global", ruleset);
+
+                Ok(Response::with((Status::Ok, SerializableResponse(scopes))))
+            }
+        }
+    }
+}
+
+middleware_chain!(GetPushRule, [PushRuleKindParam, PushRuleIdParam, AccessTokenAuth]);
+
+impl Handler for GetPushRule {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let rule = find_rule(request)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(rule))))
+    }
+}
+
+middleware_chain!(PutPushRule, [JsonRequest, PushRuleKindParam, PushRuleIdParam, AccessTokenAuth]);
+
+impl Handler for PutPushRule {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let rule_request = match request.get::<bodyparser::Struct<PutPushRuleRequest>>() {
+            Ok(Some(rule_request)) => rule_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let kind = request.extensions.get::<PushRuleKindParam>()
+            .expect("PushRuleKindParam should ensure a kind").clone();
+
+        let rule_id = request.extensions.get::<PushRuleIdParam>()
+            .expect("PushRuleIdParam should ensure a rule ID").clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let new_rule = new_push_rule(user.id, kind, rule_id, rule_request)?;
+        let before = query_param(request, "before");
+        let after = query_param(request, "after");
+
+        let connection = DB::from_request(request)?;
+
+        PushRule::put(
+            &connection,
+            &new_rule,
+            before.as_ref().map(String::as_str),
+            after.as_ref().map(String::as_str),
+        )?;
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
+middleware_chain!(DeletePushRule, [PushRuleKindParam, PushRuleIdParam, AccessTokenAuth]);
+
+impl Handler for DeletePushRule {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let kind = request.extensions.get::<PushRuleKindParam>()
+            .expect("PushRuleKindParam should ensure a kind").clone();
+
+        let rule_id = request.extensions.get::<PushRuleIdParam>()
+            .expect("PushRuleIdParam should ensure a rule ID").clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        if rule_id.starts_with('.') {
+            let error = ApiError::invalid_param("rule_id", "Default rules can't be deleted.");
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let connection = DB::from_request(request)?;
+
+        PushRule::delete(&connection, &user.id, &kind, &rule_id)?;
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
+middleware_chain!(GetPushRuleEnabled, [PushRuleKindParam, PushRuleIdParam, AccessTokenAuth]);
+
+impl Handler for GetPushRuleEnabled {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let rule = find_rule(request)?;
+
+        let response = PushRuleEnabled {
+            enabled: rule.enabled,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+middleware_chain!(
+    PutPushRuleEnabled,
+    [JsonRequest, PushRuleKindParam, PushRuleIdParam, AccessTokenAuth]
+);
+
+impl Handler for PutPushRuleEnabled {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let enabled = match request.get::<bodyparser::Struct<PushRuleEnabled>>() {
+            Ok(Some(enabled_request)) => enabled_request.enabled,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let kind = request.extensions.get::<PushRuleKindParam>()
+            .expect("PushRuleKindParam should ensure a kind").clone();
+
+        let rule_id = request.extensions.get::<PushRuleIdParam>()
+            .expect("PushRuleIdParam should ensure a rule ID").clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        PushRule::set_enabled(&connection, &user.id, &kind, &rule_id, enabled)?;
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
+middleware_chain!(GetPushRuleActions, [PushRuleKindParam, PushRuleIdParam, AccessTokenAuth]);
+
+impl Handler for GetPushRuleActions {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let rule = find_rule(request)?;
+
+        let response = PushRuleActions {
+            actions: rule.actions,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+middleware_chain!(
+    PutPushRuleActions,
+    [JsonRequest, PushRuleKindParam, PushRuleIdParam, AccessTokenAuth]
+);
+
+impl Handler for PutPushRuleActions {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let actions = match request.get::<bodyparser::Struct<PushRuleActions>>() {
+            Ok(Some(actions_request)) => actions_request.actions,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let kind = request.extensions.get::<PushRuleKindParam>()
+            .expect("PushRuleKindParam should ensure a kind").clone();
+
+        let rule_id = request.extensions.get::<PushRuleIdParam>()
+            .expect("PushRuleIdParam should ensure a rule ID").clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        validate_actions(&actions)?;
+
+        let connection = DB::from_request(request)?;
+
+        PushRule::set_actions(&connection, &user.id, &kind, &rule_id, &actions)?;
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
+/// Looks up the rule named by the request's path, failing with `M_NOT_FOUND` if there is no such
+/// rule.
+fn find_rule(request: &mut Request) -> Result<Rule, IronError> {
+    let kind = request.extensions.get::<PushRuleKindParam>()
+        .expect("PushRuleKindParam should ensure a kind").clone();
+
+    let rule_id = request.extensions.get::<PushRuleIdParam>()
+        .expect("PushRuleIdParam should ensure a rule ID").clone();
+
+    let user = request.extensions.get::<User>()
+        .expect("AccessTokenAuth should ensure a user").clone();
+
+    let connection = DB::from_request(request)?;
+
+    let ruleset = Ruleset::load(&connection, &user.id)?;
+
+    match ruleset.find(&kind, &rule_id) {
+        Some(rule) => Ok(rule.clone()),
+        None => {
+            let error = ApiError::not_found(Some("No push rule with that ID exists."));
+
+            Err(IronError::new(error.clone(), error))
+        }
+    }
+}
+
+/// Checks a rule sent by a client, which needs the fields its kind uses.
+///
+/// Room and sender rules are named after the room or user they apply to.
+fn new_push_rule(user_id: UserId, kind: String, rule_id: String, rule_request: PutPushRuleRequest)
+-> Result<NewPushRule, ApiError> {
+    if rule_id.starts_with('.') {
+        return Err(ApiError::invalid_param(
+            "rule_id",
+            "Rule IDs starting with a dot are reserved for default rules.",
+        ));
+    }
+
+    validate_actions(&rule_request.actions)?;
+
+    let conditions = match &kind[..] {
+        "override" | "underride" => rule_request.conditions.unwrap_or_else(Vec::new),
+        _ => Vec::new(),
+    };
+
+    if conditions.iter().any(|condition| !condition.is_object()) {
+        return Err(ApiError::invalid_param("conditions", "Each condition must be an object."));
+    }
+
+    let pattern = match &kind[..] {
+        "content" => match rule_request.pattern {
+            Some(pattern) => Some(pattern),
+            None => return Err(ApiError::missing_param("pattern")),
+        },
+        "room" => {
+            RoomId::try_from(&rule_id[..])
+                .map_err(|_| ApiError::invalid_param("rule_id", "Must be a room ID."))?;
+
+            None
+        }
+        "sender" => {
+            UserId::try_from(&rule_id[..])
+                .map_err(|_| ApiError::invalid_param("rule_id", "Must be a user ID."))?;
+
+            None
+        }
+        _ => None,
+    };
+
+    Ok(NewPushRule {
+        user_id: user_id,
+        kind: kind,
+        rule_id: rule_id,
+        position: 0,
+        conditions: to_string(&conditions).map_err(ApiError::from)?,
+        pattern: pattern,
+        actions: to_string(&rule_request.actions).map_err(ApiError::from)?,
+    })
+}
+
+/// Extracts an optional query parameter.
+fn query_param(request: &Request, name: &str) -> Option<String> {
+    let url = request.url.clone().into_generic_url();
+    let mut query_pairs = url.query_pairs();
+
+    query_pairs.find(|&(ref key, _)| key == name).map(|(_, value)| value.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    /// The IDs of the rules of one kind in a ruleset, in order.
+    fn rule_ids(ruleset: &Value, kind: &str) -> Vec<String> {
+        ruleset
+            .find(kind)
+            .and_then(Value::as_array)
+            .unwrap()
+            .iter()
+            .map(|rule| rule.find("rule_id").and_then(Value::as_str).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn default_rules() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response =
+            test.get(&format!("/_matrix/client/r0/pushrules/?access_token={}", access_token));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let ruleset = response.json().find("global").unwrap().clone();
+        let override_rules = rule_ids(&ruleset, "override");
+
+        assert_eq!(override_rules[0], ".m.rule.master");
+        assert!(override_rules.contains(&".m.rule.suppress_notices".to_string()));
+        assert_eq!(rule_ids(&ruleset, "content"), vec![".m.rule.contains_user_name"]);
+        assert!(rule_ids(&ruleset, "room").is_empty());
+        assert!(rule_ids(&ruleset, "sender").is_empty());
+        assert_eq!(rule_ids(&ruleset, "underride").last().unwrap(), ".m.rule.encrypted");
+
+        let pattern = ruleset.find("content").unwrap().as_array().unwrap()[0]
+            .find("pattern").and_then(Value::as_str).unwrap().to_string();
+
+        assert_eq!(pattern, "carl");
+    }
+
+    #[test]
+    fn add_get_and_delete_rule() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let rule_path = format!(
+            "/_matrix/client/r0/pushrules/global/content/cheese?access_token={}",
+            access_token
+        );
+
+        let response = test.put(
+            &rule_path,
+            r#"{"actions": ["notify"], "pattern": "cheese*"}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&rule_path);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let rule = response.json();
+
+        assert_eq!(rule.find("pattern").and_then(Value::as_str), Some("cheese*"));
+        assert_eq!(rule.find("default").and_then(Value::as_bool), Some(false));
+        assert_eq!(rule.find("enabled").and_then(Value::as_bool), Some(true));
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/pushrules/global/?access_token={}",
+            access_token
+        ));
+
+        assert_eq!(
+            rule_ids(response.json(), "content"),
+            vec!["cheese", ".m.rule.contains_user_name"]
+        );
+
+        assert_eq!(test.delete(&rule_path).status, Status::Ok);
+        assert_eq!(test.get(&rule_path).status, Status::NotFound);
+        assert_eq!(test.delete(&rule_path).status, Status::NotFound);
+    }
+
+    #[test]
+    fn rules_can_be_placed_before_and_after_others() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let rule_path = |rule_id: &str, query: &str| {
+            format!(
+                "/_matrix/client/r0/pushrules/global/override/{}?access_token={}{}",
+                rule_id,
+                access_token,
+                query
+            )
+        };
+        let body = r#"{"actions": ["dont_notify"], "conditions": []}"#;
+
+        assert_eq!(test.put(&rule_path("first", ""), body).status, Status::Ok);
+        assert_eq!(test.put(&rule_path("second", "&after=first"), body).status, Status::Ok);
+        assert_eq!(test.put(&rule_path("zeroth", "&before=first"), body).status, Status::Ok);
+        assert_eq!(test.put(&rule_path("newest", ""), body).status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/pushrules/global/?access_token={}",
+            access_token
+        ));
+        let override_rules = rule_ids(response.json(), "override");
+
+        assert_eq!(
+            override_rules[..5],
+            [".m.rule.master", "newest", "zeroth", "first", "second"][..]
+        );
+
+        let response = test.put(&rule_path("third", "&after=nonexistent"), body);
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn default_rules_cannot_be_added_or_deleted() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let rule_path = format!(
+            "/_matrix/client/r0/pushrules/global/override/.m.rule.master?access_token={}",
+            access_token
+        );
+
+        let response = test.put(&rule_path, r#"{"actions": ["notify"], "conditions": []}"#);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(test.delete(&rule_path).status, Status::BadRequest);
+    }
+
+    #[test]
+    fn enable_default_rule_and_change_its_actions() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let enabled_path = format!(
+            "/_matrix/client/r0/pushrules/global/override/.m.rule.master/enabled?access_token={}",
+            access_token
+        );
+        let actions_path = format!(
+            "/_matrix/client/r0/pushrules/global/override/.m.rule.master/actions?access_token={}",
+            access_token
+        );
+
+        let response = test.get(&enabled_path);
+
+        assert_eq!(response.json().find("enabled").and_then(Value::as_bool), Some(false));
+
+        assert_eq!(test.put(&enabled_path, r#"{"enabled": true}"#).status, Status::Ok);
+        assert_eq!(test.put(&actions_path, r#"{"actions": ["notify"]}"#).status, Status::Ok);
+
+        let response = test.get(&enabled_path);
+
+        assert_eq!(response.json().find("enabled").and_then(Value::as_bool), Some(true));
+
+        let response = test.get(&actions_path);
+        let actions = response.json().find("actions").and_then(Value::as_array).unwrap();
+
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].as_str(), Some("notify"));
+
+        let response = test.put(&actions_path, r#"{"actions": ["explode"]}"#);
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn room_rules_are_named_after_rooms() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/pushrules/global/room/{}?access_token={}",
+                room_id,
+                access_token
+            ),
+            r#"{"actions": ["dont_notify"]}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/pushrules/global/room/not_a_room?access_token={}",
+                access_token
+            ),
+            r#"{"actions": ["dont_notify"]}"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn unknown_scope_or_kind() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/pushrules/device/?access_token={}",
+            access_token
+        ));
+
+        assert_eq!(response.status, Status::BadRequest);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/pushrules/global/everything/rule?access_token={}",
+            access_token
+        ));
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+}
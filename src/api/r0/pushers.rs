@@ -0,0 +1,365 @@
+//! Endpoints for pushers.
+
+use bodyparser;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use serde_json::{Value, from_str, to_string};
+
+use clock::ServerClock;
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
+use modifier::SerializableResponse;
+use pusher::{NewPusher, Pusher};
+use stream_position::{Stream, StreamPosition};
+use user::User;
+
+/// The longest app ID a pusher can have, per the spec.
+const MAX_APP_ID_LENGTH: usize = 64;
+
+/// The longest pushkey a pusher can have, per the spec.
+const MAX_PUSHKEY_LENGTH: usize = 512;
+
+/// The GET `/pushers` endpoint.
+pub struct GetPushers;
+
+/// The POST `/pushers/set` endpoint.
+///
+/// Adds or replaces a pusher, or removes it if `kind` is `null`. New pushers are only sent
+/// notifications about events after they were set.
+pub struct SetPusher;
+
+#[derive(Debug, Serialize)]
+struct GetPushersResponse {
+    pushers: Vec<PusherResponse>,
+}
+
+#[derive(Debug, Serialize)]
+struct PusherResponse {
+    app_display_name: String,
+    app_id: String,
+    data: Value,
+    device_display_name: String,
+    kind: String,
+    lang: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile_tag: Option<String>,
+    pushkey: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct SetPusherRequest {
+    app_display_name: Option<String>,
+    app_id: String,
+    append: Option<bool>,
+    data: Option<Value>,
+    device_display_name: Option<String>,
+    kind: Option<String>,
+    lang: Option<String>,
+    profile_tag: Option<String>,
+    pushkey: String,
+}
+
+middleware_chain!(GetPushers, [AccessTokenAuth]);
+
+impl Handler for GetPushers {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let mut pushers = Vec::new();
+
+        for pusher in Pusher::find_by_user(&connection, &user.id)? {
+            pushers.push(PusherResponse {
+                app_display_name: pusher.app_display_name,
+                app_id: pusher.app_id,
+                data: from_str(&pusher.data).map_err(ApiError::from)?,
+                device_display_name: pusher.device_display_name,
+                kind: pusher.kind,
+                lang: pusher.lang,
+                profile_tag: pusher.profile_tag,
+                pushkey: pusher.pushkey,
+            });
+        }
+
+        let response = GetPushersResponse {
+            pushers: pushers,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+middleware_chain!(SetPusher, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for SetPusher {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let pusher_request = match request.get::<bodyparser::Struct<SetPusherRequest>>() {
+            Ok(Some(pusher_request)) => pusher_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let clock = ServerClock::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        if pusher_request.kind.is_none() {
+            Pusher::delete(
+                &connection,
+                &user.id,
+                &pusher_request.app_id,
+                &pusher_request.pushkey,
+            )?;
+
+            return Ok(Response::with((Status::Ok, "{}")));
+        }
+
+        let append = pusher_request.append.unwrap_or(false);
+        let last_ordering = StreamPosition::current(&connection, Stream::Events)?;
+        let new_pusher = new_pusher(user, pusher_request, clock.now_millis(), last_ordering)?;
+
+        Pusher::set(&connection, &new_pusher, append)?;
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
+/// Checks a pusher sent by a client. Only HTTP pushers are supported, and they need the URL of
+/// their push gateway.
+fn new_pusher(user: User, pusher_request: SetPusherRequest, now: i64, last_ordering: i64)
+-> Result<NewPusher, ApiError> {
+    if pusher_request.kind.as_ref().map(String::as_str) != Some("http") {
+        return Err(ApiError::invalid_param("kind", "Only http pushers are supported."));
+    }
+
+    if pusher_request.app_id.len() > MAX_APP_ID_LENGTH {
+        return Err(ApiError::invalid_param("app_id", "Must be at most 64 bytes."));
+    }
+
+    if pusher_request.pushkey.len() > MAX_PUSHKEY_LENGTH {
+        return Err(ApiError::invalid_param("pushkey", "Must be at most 512 bytes."));
+    }
+
+    let data = pusher_request.data.ok_or(ApiError::missing_param("data"))?;
+
+    match data.find("url").and_then(Value::as_str) {
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => {}
+        Some(_) => return Err(ApiError::invalid_param("data.url", "Must be an HTTP URL.")),
+        None => return Err(ApiError::missing_param("data.url")),
+    }
+
+    Ok(NewPusher {
+        user_id: user.id,
+        kind: "http".to_string(),
+        app_id: pusher_request.app_id,
+        pushkey: pusher_request.pushkey,
+        app_display_name: pusher_request.app_display_name
+            .ok_or(ApiError::missing_param("app_display_name"))?,
+        device_display_name: pusher_request.device_display_name
+            .ok_or(ApiError::missing_param("device_display_name"))?,
+        profile_tag: pusher_request.profile_tag,
+        lang: pusher_request.lang.ok_or(ApiError::missing_param("lang"))?,
+        data: to_string(&data).map_err(ApiError::from)?,
+        updated_at: now,
+        last_ordering: last_ordering,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    /// Sets a pusher for the user with the given pushkey.
+    fn set_pusher(test: &Test, access_token: &str, pushkey: &str, append: bool) {
+        let body = format!(
+            r#"{{
+                "app_display_name": "Example",
+                "app_id": "com.example.app",
+                "append": {},
+                "data": {{"url": "https://push.example.com/_matrix/push/v1/notify"}},
+                "device_display_name": "Phone",
+                "kind": "http",
+                "lang": "en",
+                "pushkey": "{}"
+            }}"#,
+            append,
+            pushkey
+        );
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/pushers/set?access_token={}", access_token),
+            &body,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    /// The pushkeys of the user's pushers.
+    fn pushkeys(test: &Test, access_token: &str) -> Vec<String> {
+        let response =
+            test.get(&format!("/_matrix/client/r0/pushers?access_token={}", access_token));
+
+        assert_eq!(response.status, Status::Ok);
+
+        response
+            .json()
+            .find("pushers")
+            .and_then(Value::as_array)
+            .unwrap()
+            .iter()
+            .map(|pusher| pusher.find("pushkey").and_then(Value::as_str).unwrap().to_string())
+            .collect()
+    }
+
+    /// Sends a message to the room.
+    fn send_message(test: &Test, access_token: &str, room_id: &str, txn_id: u32, body: &str) {
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/send/m.room.message/{}?access_token={}",
+                room_id,
+                txn_id,
+                access_token
+            ),
+            &format!(r#"{{"msgtype": "m.text", "body": "{}"}}"#, body),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn set_get_and_delete_pushers() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        set_pusher(&test, &access_token, "phone", false);
+        set_pusher(&test, &access_token, "tablet", false);
+
+        assert_eq!(pushkeys(&test, &access_token), vec!["phone", "tablet"]);
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/pushers/set?access_token={}", access_token),
+            r#"{"app_id": "com.example.app", "kind": null, "pushkey": "phone"}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(pushkeys(&test, &access_token), vec!["tablet"]);
+    }
+
+    #[test]
+    fn pushkeys_move_between_users_unless_appended() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let bob_token = test.create_access_token_with_username("bob");
+
+        set_pusher(&test, &carl_token, "phone", false);
+        set_pusher(&test, &alice_token, "phone", true);
+
+        assert_eq!(pushkeys(&test, &carl_token), vec!["phone"]);
+        assert_eq!(pushkeys(&test, &alice_token), vec!["phone"]);
+
+        set_pusher(&test, &bob_token, "phone", false);
+
+        assert!(pushkeys(&test, &carl_token).is_empty());
+        assert!(pushkeys(&test, &alice_token).is_empty());
+        assert_eq!(pushkeys(&test, &bob_token), vec!["phone"]);
+    }
+
+    #[test]
+    fn pushers_need_a_push_gateway_url() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/pushers/set?access_token={}", access_token),
+            r#"{
+                "app_display_name": "Example",
+                "app_id": "com.example.app",
+                "data": {},
+                "device_display_name": "Phone",
+                "kind": "http",
+                "lang": "en",
+                "pushkey": "phone"
+            }"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/pushers/set?access_token={}", access_token),
+            r#"{
+                "app_display_name": "Example",
+                "app_id": "com.example.app",
+                "data": {"url": "https://push.example.com"},
+                "device_display_name": "Phone",
+                "kind": "email",
+                "lang": "en",
+                "pushkey": "carl@example.com"
+            }"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn notifications_are_pushed_to_the_gateway() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&carl_token);
+
+        assert_eq!(test.join_room(&alice_token, &room_id).status, Status::Ok);
+
+        send_message(&test, &alice_token, &room_id, 1, "Before the pusher");
+
+        set_pusher(&test, &carl_token, "phone", false);
+
+        send_message(&test, &alice_token, &room_id, 2, "Hello Carl");
+        send_message(&test, &carl_token, &room_id, 1, "Hello Alice");
+
+        test.run_jobs();
+
+        let notifications = test.push_notifications();
+
+        assert_eq!(notifications.len(), 1);
+
+        let (ref url, ref body) = notifications[0];
+        let notification = body.find("notification").unwrap();
+
+        assert_eq!(url, "https://push.example.com/_matrix/push/v1/notify");
+        assert_eq!(notification.find("room_id").and_then(Value::as_str), Some(&room_id[..]));
+        assert_eq!(
+            notification.find_path(&["content", "body"]).and_then(Value::as_str),
+            Some("Hello Carl")
+        );
+        assert_eq!(notification.find("prio").and_then(Value::as_str), Some("high"));
+        assert_eq!(
+            notification.find_path(&["counts", "unread"]).and_then(Value::as_u64),
+            Some(2)
+        );
+
+        let device = &notification.find("devices").and_then(Value::as_array).unwrap()[0];
+
+        assert_eq!(device.find("pushkey").and_then(Value::as_str), Some("phone"));
+        assert!(device.find_path(&["data", "url"]).is_none());
+        assert_eq!(
+            device.find_path(&["tweaks", "highlight"]).and_then(Value::as_bool),
+            Some(true)
+        );
+
+        test.run_jobs();
+
+        assert_eq!(test.push_notifications().len(), 1);
+    }
+}
@@ -0,0 +1,346 @@
+//! Endpoints for receipts and read markers.
+
+use bodyparser;
+use diesel::Connection;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use ruma_identifiers::EventId;
+use serde_json::to_string;
+
+use account_data::{NewRoomAccountData, RoomAccountData};
+use clock::ServerClock;
+use db::DB;
+use error::ApiError;
+use event::Event;
+use membership_cache::ServerMembershipCache;
+use middleware::{
+    AccessTokenAuth,
+    EventIdParam,
+    JsonRequest,
+    MiddlewareChain,
+    ReceiptTypeParam,
+    RoomIdParam,
+};
+use receipt::{NewReceipt, READ_RECEIPT, ServerPendingReceipts};
+use user::User;
+
+/// The room account data type of the fully read marker.
+const FULLY_READ: &'static str = "m.fully_read";
+
+/// The POST `/rooms/:room_id/receipt/:receipt_type/:event_id` endpoint.
+///
+/// Receipts are saved with the next batch, per `ephemeral_batch_millis`.
+pub struct PostReceipt;
+
+/// The POST `/rooms/:room_id/read_markers` endpoint.
+///
+/// Moves the user's fully read marker, which is kept as *m.fully_read* room account data so that
+/// every device sees it, and optionally sends a read receipt in the same request.
+pub struct PostReadMarkers;
+
+#[derive(Clone, Debug, Deserialize)]
+struct PostReadMarkersRequest {
+    #[serde(rename="m.fully_read")]
+    fully_read: EventId,
+    #[serde(rename="m.read")]
+    read: Option<EventId>,
+}
+
+#[derive(Debug, Serialize)]
+struct FullyReadContent {
+    event_id: String,
+}
+
+middleware_chain!(
+    PostReceipt,
+    [JsonRequest, RoomIdParam, ReceiptTypeParam, EventIdParam, AccessTokenAuth]
+);
+
+impl Handler for PostReceipt {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId").clone();
+
+        let receipt_type = request.extensions.get::<ReceiptTypeParam>()
+            .expect("ReceiptTypeParam should ensure a receipt type").clone();
+
+        let event_id = request.extensions.get::<EventIdParam>()
+            .expect("EventIdParam should ensure an EventId").clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        if receipt_type != READ_RECEIPT {
+            let error = ApiError::invalid_param("receipt_type", "Only m.read is supported.");
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let clock = ServerClock::from_request(request)?;
+        let connection = DB::from_request(request)?;
+        let membership_cache = ServerMembershipCache::from_request(request)?;
+        let pending_receipts = ServerPendingReceipts::from_request(request)?;
+
+        if !membership_cache.is_joined(&connection, &room_id, &user.id)? {
+            let error = ApiError::unauthorized(Some("You must join the room to send receipts."));
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        Event::find_in_room(&connection, &room_id, &event_id)?;
+
+        let new_receipt = NewReceipt {
+            room_id: room_id,
+            user_id: user.id,
+            receipt_type: receipt_type,
+            event_id: event_id,
+            ts: clock.now_millis(),
+        };
+
+        pending_receipts.add(&connection, new_receipt)?;
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
+middleware_chain!(PostReadMarkers, [JsonRequest, RoomIdParam, AccessTokenAuth]);
+
+impl Handler for PostReadMarkers {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let markers = match request.get::<bodyparser::Struct<PostReadMarkersRequest>>() {
+            Ok(Some(markers)) => markers,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId").clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let clock = ServerClock::from_request(request)?;
+        let connection = DB::from_request(request)?;
+        let membership_cache = ServerMembershipCache::from_request(request)?;
+        let pending_receipts = ServerPendingReceipts::from_request(request)?;
+
+        if !membership_cache.is_joined(&connection, &room_id, &user.id)? {
+            let error = ApiError::unauthorized(Some("You must join the room to mark it as read."));
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        Event::find_in_room(&connection, &room_id, &markers.fully_read)?;
+
+        if let Some(ref read) = markers.read {
+            Event::find_in_room(&connection, &room_id, read)?;
+        }
+
+        connection.transaction::<(), ApiError, _>(|| {
+            let new_data = NewRoomAccountData {
+                user_id: user.id.clone(),
+                room_id: room_id.clone(),
+                data_type: FULLY_READ.to_string(),
+                content: to_string(&FullyReadContent {
+                    event_id: markers.fully_read.to_string(),
+                })?,
+            };
+
+            RoomAccountData::upsert(&connection, &new_data)?;
+
+            if let Some(ref read) = markers.read {
+                let new_receipt = NewReceipt {
+                    room_id: room_id.clone(),
+                    user_id: user.id.clone(),
+                    receipt_type: READ_RECEIPT.to_string(),
+                    event_id: read.clone(),
+                    ts: clock.now_millis(),
+                };
+
+                pending_receipts.add(&connection, new_receipt)?;
+            }
+
+            Ok(())
+        }).map_err(ApiError::from)?;
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::{Response, Test};
+
+    /// Sends a message and returns its full event ID.
+    fn send_message(test: &Test, access_token: &str, room_id: &str) -> String {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/1?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.put(&path, r#"{"body": "Hi", "msgtype": "m.text"}"#);
+
+        assert_eq!(response.status, Status::Ok);
+
+        format!("${}:ruma.test", response.json().find("event_id").unwrap().as_str().unwrap())
+    }
+
+    fn post_receipt(
+        test: &Test,
+        access_token: &str,
+        room_id: &str,
+        receipt_type: &str,
+        event_id: &str,
+    ) -> Response {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/receipt/{}/{}?access_token={}",
+            room_id,
+            receipt_type,
+            event_id.replace("$", "%24"),
+            access_token
+        );
+
+        test.post(&path, "{}")
+    }
+
+    /// Syncs and returns the room's `m.receipt` event content, if there is one.
+    fn sync_receipts(test: &Test, access_token: &str, room_id: &str, since: Option<&str>)
+    -> (Option<Value>, String) {
+        let since = since.map(|since| format!("&since={}", since)).unwrap_or(String::new());
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?access_token={}{}",
+            access_token,
+            since
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+        let content = json
+            .find_path(&["rooms", "join", room_id, "ephemeral", "events"])
+            .and_then(Value::as_array)
+            .and_then(|events| {
+                events.iter().find(|event| {
+                    event.find("type").unwrap().as_str() == Some("m.receipt")
+                })
+            })
+            .map(|event| event.find("content").unwrap().clone());
+
+        (content, json.find("next_batch").unwrap().as_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn receipts_show_up_in_sync() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let event_id = send_message(&test, &access_token, &room_id);
+
+        let (_, next_batch) = sync_receipts(&test, &access_token, &room_id, None);
+
+        let response = post_receipt(&test, &access_token, &room_id, "m.read", &event_id);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let (content, next_batch) =
+            sync_receipts(&test, &access_token, &room_id, Some(&next_batch));
+        let content = content.unwrap();
+        let receipt = content.find_path(&[&event_id[..], "m.read", "@carl:ruma.test"]).unwrap();
+
+        assert!(receipt.find("ts").unwrap().is_number());
+
+        let (content, _) = sync_receipts(&test, &access_token, &room_id, Some(&next_batch));
+
+        assert!(content.is_none());
+    }
+
+    #[test]
+    fn only_read_receipts_are_supported() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let event_id = send_message(&test, &access_token, &room_id);
+
+        let response = post_receipt(&test, &access_token, &room_id, "m.unknown", &event_id);
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn receipts_need_a_known_event_and_membership() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_room(&carl_token);
+        let event_id = send_message(&test, &carl_token, &room_id);
+
+        let response = post_receipt(&test, &carl_token, &room_id, "m.read", "$unknown:ruma.test");
+
+        assert_eq!(response.status, Status::NotFound);
+
+        let response = post_receipt(&test, &alice_token, &room_id, "m.read", &event_id);
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn read_markers_set_fully_read_and_receipt() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let event_id = send_message(&test, &access_token, &room_id);
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/read_markers?access_token={}",
+            room_id,
+            access_token
+        );
+        let body = format!(r#"{{"m.fully_read": "{0}", "m.read": "{0}"}}"#, event_id);
+
+        assert_eq!(test.post(&path, &body).status, Status::Ok);
+
+        let response = test.get(&format!("/_matrix/client/r0/sync?access_token={}", access_token));
+        let room = response.json().find_path(&["rooms", "join", &room_id[..]]).unwrap().clone();
+        let fully_read = room.find_path(&["account_data", "events"]).unwrap()
+            .as_array().unwrap()
+            .iter()
+            .find(|event| event.find("type").unwrap().as_str() == Some("m.fully_read"))
+            .unwrap()
+            .clone();
+
+        assert_eq!(
+            fully_read.find_path(&["content", "event_id"]).unwrap().as_str().unwrap(),
+            event_id
+        );
+
+        let (content, _) = sync_receipts(&test, &access_token, &room_id, None);
+
+        let content = content.unwrap();
+
+        assert!(content.find_path(&[&event_id[..], "m.read", "@carl:ruma.test"]).is_some());
+    }
+
+    #[test]
+    fn read_markers_need_a_known_event() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/read_markers?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.post(&path, r#"{"m.fully_read": "$unknown:ruma.test"}"#);
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+}
@@ -0,0 +1,272 @@
+//! Endpoints for redacting events.
+
+use bodyparser;
+use diesel::Connection;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use ruma_events::EventType;
+use ruma_identifiers::EventId;
+use serde_json::to_string;
+
+use access_token::AccessToken;
+use clock::ServerClock;
+use config::Config;
+use db::DB;
+use error::{ApiError, MapApiError};
+use event::{Event, NewEvent};
+use membership_cache::ServerMembershipCache;
+use middleware::{
+    AccessTokenAuth,
+    EventIdParam,
+    JsonRequest,
+    MiddlewareChain,
+    RoomIdParam,
+    TransactionIdParam,
+};
+use modifier::SerializableResponse;
+use power_levels::ServerPowerLevelsCache;
+use room::Room;
+use transaction::{NewTransaction, Transaction};
+use user::User;
+
+/// The `/rooms/:room_id/redact/:event_id/:transaction_id` endpoint.
+///
+/// Sends an *m.room.redaction* event and strips the redacted event's content.
+pub struct RedactEvent;
+
+#[derive(Clone, Debug, Deserialize)]
+struct RedactEventRequest {
+    reason: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RedactionContent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RedactionExtraContent {
+    redacts: String,
+}
+
+#[derive(Debug, Serialize)]
+struct RedactEventResponse {
+    event_id: String,
+}
+
+middleware_chain!(
+    RedactEvent,
+    [JsonRequest, RoomIdParam, EventIdParam, TransactionIdParam, AccessTokenAuth]
+);
+
+impl Handler for RedactEvent {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId").clone();
+
+        let redacted_event_id = request.extensions.get::<EventIdParam>()
+            .expect("EventIdParam should ensure an EventId").clone();
+
+        let transaction_id = request.extensions.get::<TransactionIdParam>()
+            .expect("TransactionIdParam should ensure a TransactionId").clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let access_token_id = request.extensions.get::<AccessToken>()
+            .expect("AccessTokenAuth should ensure an access token").id;
+
+        let reason = match request.get::<bodyparser::Struct<RedactEventRequest>>() {
+            Ok(Some(redact_request)) => redact_request.reason,
+            Ok(None) | Err(_) => None,
+        };
+
+        let clock = ServerClock::from_request(request)?;
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+        let membership_cache = ServerMembershipCache::from_request(request)?;
+        let power_levels_cache = ServerPowerLevelsCache::from_request(request)?;
+
+        // As with other events, a retried transaction gets the original redaction back.
+        let previous_transaction = Transaction::find(
+            &connection,
+            access_token_id,
+            &transaction_id,
+        )?;
+
+        if let Some(transaction) = previous_transaction {
+            let response = RedactEventResponse {
+                event_id: transaction.event_id.opaque_id().to_string(),
+            };
+
+            return Ok(Response::with((Status::Ok, SerializableResponse(response))));
+        }
+
+        let room = Room::find(&connection, &room_id)?;
+        let redacted_event = Event::find_in_room(&connection, &room_id, &redacted_event_id)?;
+
+        let event_id = EventId::new(&config.domain).map_api_err(|_| {
+            ApiError::unknown(Some("Failed to generate event ID for the redaction."))
+        })?;
+
+        let redaction = NewEvent {
+            content: to_string(&RedactionContent {
+                reason: reason,
+            }).map_err(ApiError::from)?,
+            event_type: EventType::RoomRedaction.to_string(),
+            extra_content: Some(to_string(&RedactionExtraContent {
+                redacts: redacted_event_id.to_string(),
+            }).map_err(ApiError::from)?),
+            id: event_id.clone(),
+            room_id: room_id,
+            state_key: None,
+            user_id: user.id,
+        };
+
+        connection.transaction::<(), ApiError, _>(|| {
+            room.redact_event(
+                &connection,
+                &membership_cache,
+                &power_levels_cache,
+                &redaction,
+                &redacted_event,
+                clock.now_millis(),
+            )?;
+
+            let new_transaction = NewTransaction {
+                access_token_id: Some(access_token_id),
+                app_service_id: None,
+                transaction_id: transaction_id.clone(),
+                event_id: event_id.clone(),
+            };
+
+            Transaction::create(&connection, &new_transaction)?;
+
+            Ok(())
+        }).map_err(ApiError::from)?;
+
+        let response = RedactEventResponse {
+            event_id: event_id.opaque_id().to_string(),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::{Response, Test};
+
+    /// Sends a message and returns its full event ID.
+    fn send_message(test: &Test, access_token: &str, room_id: &str) -> String {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/1?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.put(&path, r#"{"body": "Oops", "msgtype": "m.text"}"#);
+
+        assert_eq!(response.status, Status::Ok);
+
+        format!("${}:ruma.test", response.json().find("event_id").unwrap().as_str().unwrap())
+    }
+
+    fn redact(test: &Test, access_token: &str, room_id: &str, event_id: &str) -> Response {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/redact/{}/redaction?access_token={}",
+            room_id,
+            event_id.replace("$", "%24"),
+            access_token
+        );
+
+        test.put(&path, r#"{"reason": "Typo"}"#)
+    }
+
+    /// Returns every event in the room, oldest first.
+    fn events(test: &Test, access_token: &str, room_id: &str) -> Vec<Value> {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?access_token={}&from=0&dir=f&limit=100",
+            room_id,
+            access_token
+        );
+
+        test.get(&path).json().find("chunk").unwrap().as_array().unwrap().clone()
+    }
+
+    #[test]
+    fn redact_own_message() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let event_id = send_message(&test, &access_token, &room_id);
+
+        assert_eq!(redact(&test, &access_token, &room_id, &event_id).status, Status::Ok);
+
+        let events = events(&test, &access_token, &room_id);
+        let message = events.iter()
+            .find(|event| event.find("event_id").unwrap().as_str().unwrap() == event_id)
+            .unwrap();
+        let redaction = events.last().unwrap();
+
+        assert_eq!(message.find("content").unwrap().as_object().unwrap().len(), 0);
+        assert_eq!(redaction.find("type").unwrap().as_str().unwrap(), "m.room.redaction");
+        assert_eq!(redaction.find("redacts").unwrap().as_str().unwrap(), event_id);
+        assert_eq!(
+            redaction.find_path(&["content", "reason"]).unwrap().as_str().unwrap(),
+            "Typo"
+        );
+    }
+
+    #[test]
+    fn redacted_events_include_their_redaction() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let event_id = send_message(&test, &access_token, &room_id);
+
+        assert_eq!(redact(&test, &access_token, &room_id, &event_id).status, Status::Ok);
+
+        let events = events(&test, &access_token, &room_id);
+        let message = events.iter()
+            .find(|event| event.find("event_id").unwrap().as_str().unwrap() == event_id)
+            .unwrap();
+        let redacted_because = message.find_path(&["unsigned", "redacted_because"]).unwrap();
+
+        assert_eq!(redacted_because.find("type").unwrap().as_str().unwrap(), "m.room.redaction");
+        assert_eq!(redacted_because.find("redacts").unwrap().as_str().unwrap(), event_id);
+    }
+
+    #[test]
+    fn redacting_others_needs_power() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&carl_token);
+
+        assert!(test.join_room(&alice_token, &room_id).status.is_success());
+
+        let event_id = send_message(&test, &carl_token, &room_id);
+
+        assert_eq!(redact(&test, &alice_token, &room_id, &event_id).status, Status::Forbidden);
+
+        let event_id = send_message(&test, &alice_token, &room_id);
+
+        assert_eq!(redact(&test, &carl_token, &room_id, &event_id).status, Status::Ok);
+    }
+
+    #[test]
+    fn redact_unknown_event() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let response = redact(&test, &access_token, &room_id, "$unknown:ruma.test");
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+}
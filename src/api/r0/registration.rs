@@ -6,23 +6,36 @@ use bodyparser;
 use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response, status};
 use ruma_identifiers::UserId;
 use serde::de::{Deserialize, Deserializer, Visitor, Error as SerdeError};
+use serde_json::Value;
 
+use clock::ServerClock;
 use config::Config;
-use crypto::hash_password;
+use crypto::{ServerEntropy, hash_password};
 use db::DB;
+use device::{Device, NewDevice};
 use error::ApiError;
 use middleware::{JsonRequest, MiddlewareChain};
 use modifier::SerializableResponse;
+use server_notice::SERVER_NOTICES_LOCALPART;
+use uia::{AuthResult, InteractiveAuth};
 use user::{NewUser, User};
 
 /// The `/register` endpoint.
+///
+/// Registering with `"kind": "guest"` creates a guest account with a generated user ID and no
+/// password, if the server allows guest access. Any username or password given is ignored.
+/// Other registrations go through user-interactive authentication, with a reCAPTCHA stage if the
+/// server has reCAPTCHA keys and a dummy stage otherwise.
 pub struct Register;
 
 #[derive(Clone, Debug, Deserialize)]
 struct RegistrationRequest {
+    pub auth: Option<Value>,
     pub bind_email: Option<bool>,
+    pub device_id: Option<String>,
+    pub initial_device_display_name: Option<String>,
     pub kind: Option<RegistrationKind>,
-    pub password: String,
+    pub password: Option<String>,
     pub username: Option<String>,
 }
 
@@ -35,6 +48,7 @@ enum RegistrationKind {
 #[derive(Debug, Serialize)]
 struct RegistrationResponse {
     pub access_token: String,
+    pub device_id: String,
     pub home_server: String,
     pub user_id: String,
 }
@@ -75,40 +89,91 @@ impl Handler for Register {
             }
         };
 
-        if let Some(kind) = registration_request.kind {
-            match kind {
-                RegistrationKind::Guest => {
-                    let error = ApiError::guest_forbidden(None);
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+        let clock = ServerClock::from_request(request)?;
+        let entropy = ServerEntropy::from_request(request)?;
+
+        let is_guest = match registration_request.kind {
+            Some(RegistrationKind::Guest) => true,
+            Some(RegistrationKind::User) | None => false,
+        };
+
+        let new_user = if is_guest {
+            if !config.allow_guest_access {
+                let error = ApiError::guest_forbidden(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+
+            // Guests can't log in, so they have no password to hash.
+            NewUser {
+                id: UserId::new(&config.domain).map_err(ApiError::from)?,
+                password_hash: String::new(),
+                is_guest: true,
+            }
+        } else {
+            let password = match registration_request.password {
+                Some(ref password) => password,
+                None => {
+                    let error = ApiError::missing_param("password");
 
                     return Err(IronError::new(error.clone(), error));
                 }
-                _ => {},
+            };
+
+            if registration_request.username.as_ref().map(String::as_str)
+                == Some(SERVER_NOTICES_LOCALPART) {
+                let error = ApiError::invalid_param("username", "This username is reserved.");
+
+                return Err(IronError::new(error.clone(), error));
             }
-        }
 
-        let config = Config::from_request(request)?;
+            let result = InteractiveAuth::registration(&config).authenticate(
+                &connection,
+                &config,
+                &**entropy,
+                registration_request.auth.as_ref(),
+                None,
+                clock.now_millis(),
+            )?;
+
+            if let AuthResult::Incomplete(challenge) = result {
+                return Err(IronError::new(challenge.clone(), challenge));
+            }
 
-        let new_user = NewUser {
-            id: match registration_request.username {
-                Some(username) => {
-                    UserId::try_from(&format!("@{}:{}", username, &config.domain))
-                        .map_err(ApiError::from)?
-                }
-                None => UserId::new(&config.domain).map_err(ApiError::from)?,
-            },
-            password_hash: hash_password(&registration_request.password)?,
+            NewUser {
+                id: match registration_request.username {
+                    Some(ref username) => {
+                        UserId::try_from(&format!("@{}:{}", username, &config.domain))
+                            .map_err(ApiError::from)?
+                    }
+                    None => UserId::new(&config.domain).map_err(ApiError::from)?,
+                },
+                password_hash: hash_password(&config.argon2_params, password)?,
+                is_guest: false,
+            }
         };
 
-        let connection = DB::from_request(request)?;
+        let new_device = NewDevice {
+            device_id: registration_request.device_id
+                .unwrap_or_else(|| Device::generate_id(&**entropy)),
+            user_id: new_user.id.clone(),
+            display_name: registration_request.initial_device_display_name,
+        };
 
         let (user, access_token) = User::create(
             &connection,
             &new_user,
+            &new_device,
             &config.macaroon_secret_key,
+            &**clock,
+            &**entropy,
         )?;
 
         let response = RegistrationResponse {
             access_token: access_token.value,
+            device_id: access_token.device_id,
             home_server: config.domain.clone(),
             user_id: user.id.to_string(),
         };
@@ -119,6 +184,8 @@ impl Handler for Register {
 
 #[cfg(test)]
 mod tests {
+    use iron::status::Status;
+
     use test::Test;
 
     #[test]
@@ -126,7 +193,7 @@ mod tests {
         let test = Test::new();
 
         let response = test.register_user(
-            r#"{"password": "secret"}"#,
+            r#"{"auth": {"type": "m.login.dummy"}, "password": "secret"}"#,
         );
 
         assert!(response.json().find("access_token").is_some());
@@ -139,7 +206,13 @@ mod tests {
         let test = Test::new();
 
         let response = test.register_user(
-            r#"{"bind_email": true, "kind": "user", "username":"carl", "password": "secret"}"#
+            r#"{
+                "auth": {"type": "m.login.dummy"},
+                "bind_email": true,
+                "kind": "user",
+                "username": "carl",
+                "password": "secret"
+            }"#
         );
 
         assert!(response.json().find("access_token").is_some());
@@ -148,16 +221,89 @@ mod tests {
     }
 
     #[test]
-    fn guest_access_not_supported() {
+    fn registration_requires_auth() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+
+        assert_eq!(response.status, Status::Unauthorized);
+
+        let stages = response.json()
+            .find("flows").unwrap().as_array().unwrap()[0]
+            .find("stages").unwrap().as_array().unwrap()
+            .clone();
+
+        assert_eq!(stages[0].as_str().unwrap(), "m.login.dummy");
+
+        let body = format!(
+            r#"{{
+                "auth": {{"type": "m.login.dummy", "session": "{}"}},
+                "username": "carl",
+                "password": "secret"
+            }}"#,
+            response.json().find("session").unwrap().as_str().unwrap()
+        );
+        let response = test.register_user(&body);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().find("user_id").unwrap().as_str().unwrap(), "@carl:ruma.test");
+    }
+
+    #[test]
+    fn guest_registration() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"kind": "guest"}"#);
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().find("access_token").is_some());
+        assert!(response.json().find("user_id").unwrap().as_str().unwrap().ends_with(":ruma.test"));
+    }
+
+    #[test]
+    fn guest_registration_ignores_username() {
         let test = Test::new();
 
         let response = test.register_user(
-            r#"{"bind_email": true, "kind": "guest", "username":"carl", "password": "secret"}"#
+            r#"{"kind": "guest", "username": "carl", "password": "secret"}"#
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().find("user_id").unwrap().as_str().unwrap() != "@carl:ruma.test");
+    }
+
+    #[test]
+    fn guests_cannot_log_in() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"kind": "guest"}"#);
+        let user_id = response.json().find("user_id").unwrap().as_str().unwrap().to_string();
+
+        let login = format!(
+            r#"{{"auth": {{"type": "m.login.password", "user": "{}", "password": ""}}}}"#,
+            user_id
         );
 
-        assert_eq!(
-            response.json().find("errcode").unwrap().as_str().unwrap(),
-            "M_GUEST_ACCESS_FORBIDDEN"
+        assert_eq!(test.post("/_matrix/client/r0/login", &login).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn user_registration_requires_password() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl"}"#);
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn server_notices_username_is_reserved() {
+        let test = Test::new();
+
+        let response = test.register_user(
+            r#"{"username": "server-notices", "password": "secret"}"#
         );
+
+        assert_eq!(response.status, Status::BadRequest);
     }
 }
@@ -8,14 +8,23 @@ use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
 use iron::status::Status;
 use ruma_identifiers::RoomId;
 
+use clock::ServerClock;
 use config::Config;
 use db::DB;
 use error::ApiError;
-use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, NonGuestAuth};
 use modifier::SerializableResponse;
-use room::{CreationOptions, NewRoom, Room, RoomPreset};
+use room::{
+    CreationOptions,
+    MAX_ROOM_NAME_LENGTH,
+    MAX_ROOM_TOPIC_LENGTH,
+    NewRoom,
+    Room,
+    RoomPreset,
+};
 use room_membership::{RoomMembership, RoomMembershipOptions};
 use user::User;
+use velocity_limit::{VelocityAction, VelocityLimits};
 
 /// The `/createRoom` endpoint.
 pub struct CreateRoom;
@@ -27,6 +36,7 @@ struct CreateRoomRequest {
     pub name: Option<String>,
     pub preset: Option<RoomPreset>,
     pub room_alias_name: Option<String>,
+    pub room_version: Option<String>,
     pub topic: Option<String>,
     pub visibility: Option<String>,
 }
@@ -42,7 +52,7 @@ struct CreateRoomResponse {
     room_id: String,
 }
 
-middleware_chain!(CreateRoom, [JsonRequest, AccessTokenAuth]);
+middleware_chain!(CreateRoom, [JsonRequest, AccessTokenAuth, NonGuestAuth]);
 
 impl CreateRoomRequest {
     pub fn validate(self) -> Result<Self, IronError> {
@@ -54,6 +64,18 @@ impl CreateRoomRequest {
             }
         }
 
+        if self.name.as_ref().map_or(false, |name| name.len() > MAX_ROOM_NAME_LENGTH) {
+            let error = ApiError::too_large(Some("The room name must be at most 255 bytes."));
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        if self.topic.as_ref().map_or(false, |topic| topic.len() > MAX_ROOM_TOPIC_LENGTH) {
+            let error = ApiError::too_large(Some("The room topic must be at most 4096 bytes."));
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
         Ok(self)
     }
 }
@@ -73,11 +95,25 @@ impl Handler for CreateRoom {
 
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
+        let clock = ServerClock::from_request(request)?;
+
+        let version = match create_room_request.room_version {
+            Some(ref version) if !config.supported_room_versions.contains(version) => {
+                let error = ApiError::unsupported_room_version(
+                    Some(&format!("Room version {} is not supported.", version))
+                );
+
+                return Err(IronError::new(error.clone(), error));
+            }
+            Some(ref version) => version.clone(),
+            None => config.default_room_version.clone(),
+        };
 
         let new_room = NewRoom {
             id: RoomId::new(&config.domain).map_err(ApiError::from)?,
             user_id: user.id,
             public: create_room_request.visibility.map_or(false, |v| v == "public"),
+            version: version,
         };
 
         let federate = match create_room_request.creation_content {
@@ -93,16 +129,45 @@ impl Handler for CreateRoom {
             }
         };
 
+        let encrypt = match preset {
+            RoomPreset::PrivateChat | RoomPreset::TrustedPrivateChat => {
+                config.encrypt_private_rooms
+            }
+            RoomPreset::PublicChat => false,
+        };
+
         let creation_options = CreationOptions {
             alias: create_room_request.room_alias_name,
+            encrypt: encrypt,
             federate: federate,
             invite_list: create_room_request.invite,
             name: create_room_request.name,
+            predecessor: None,
             preset: preset,
             topic: create_room_request.topic,
         };
 
+        let invite_count = creation_options.invite_list.as_ref().map_or(0, |invites| invites.len());
+
         let room: Room = connection.transaction::<Room, ApiError, _>(|| {
+            VelocityLimits::check_and_record(
+                &connection,
+                &config,
+                &new_room.user_id,
+                VelocityAction::CreateRoom,
+                1,
+                clock.now_millis(),
+            )?;
+
+            VelocityLimits::check_and_record(
+                &connection,
+                &config,
+                &new_room.user_id,
+                VelocityAction::Invite,
+                invite_count as u64,
+                clock.now_millis(),
+            )?;
+
             let room = Room::create(&connection, &new_room, &config.domain, &creation_options)?;
 
             let options = RoomMembershipOptions {
@@ -129,6 +194,8 @@ impl Handler for CreateRoom {
 
 #[cfg(test)]
 mod tests {
+    use std::iter::repeat;
+
     use test::Test;
 
     #[test]
@@ -198,4 +265,85 @@ mod tests {
             "M_BAD_JSON"
         );
     }
+
+    #[test]
+    fn with_room_version() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}",
+                                       access_token);
+
+        let response = test.post(&create_room_path, r#"{"room_version": "2"}"#);
+        let room_id = response.json().find("room_id").unwrap().as_str().unwrap().to_string();
+
+        let create_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.create?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.get(&create_event_path);
+
+        assert_eq!(response.json().find("room_version").unwrap().as_str().unwrap(), "2");
+
+        let room_id = test.create_room(&access_token);
+        let create_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.create?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.get(&create_event_path);
+
+        assert_eq!(response.json().find("room_version").unwrap().as_str().unwrap(), "1");
+    }
+
+    #[test]
+    fn with_unsupported_room_version() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}",
+                                       access_token);
+
+        let response = test.post(&create_room_path, r#"{"room_version": "42"}"#);
+
+        assert_eq!(
+            response.json().find("errcode").unwrap().as_str().unwrap(),
+            "M_UNSUPPORTED_ROOM_VERSION"
+        );
+    }
+
+    #[test]
+    fn guests_cannot_create_rooms() {
+        let test = Test::new();
+        let access_token = test.create_guest_access_token();
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/createRoom?access_token={}", access_token),
+            "{}",
+        );
+
+        assert_eq!(
+            response.json().find("errcode").unwrap().as_str().unwrap(),
+            "M_GUEST_ACCESS_FORBIDDEN"
+        );
+    }
+
+    #[test]
+    fn room_name_too_large() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/createRoom?access_token={}", access_token),
+            &format!(r#"{{"name": "{}"}}"#, repeat("n").take(256).collect::<String>()),
+        );
+
+        assert_eq!(
+            response.json().find("errcode").unwrap().as_str().unwrap(),
+            "M_TOO_LARGE"
+        );
+    }
 }
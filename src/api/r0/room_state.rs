@@ -0,0 +1,258 @@
+//! Endpoints for getting the state of a room.
+
+use iron::{Chain, Handler, IronError, IronResult, Request, Response};
+use iron::status::Status;
+use router::Router;
+use serde_json::{Value, from_str};
+
+use access_token::AccessToken;
+use clock::ServerClock;
+use db::DB;
+use error::ApiError;
+use event::{Event, UnsignedData};
+use middleware::{AccessTokenAuth, EventTypeParam, MiddlewareChain, RoomIdParam};
+use modifier::SerializableResponse;
+use room_membership::RoomMembership;
+use user::User;
+
+/// The GET `/rooms/:room_id/state` endpoint.
+///
+/// Users who have left the room get the state as it was when they left.
+pub struct GetStateEvents;
+
+middleware_chain!(GetStateEvents, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for GetStateEvents {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let access_token_id = request.extensions.get::<AccessToken>()
+            .expect("AccessTokenAuth should ensure an access token").id;
+
+        let clock = ServerClock::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        let before = state_position(
+            RoomMembership::visible_until(&connection, &room_id, &user.id)?
+        );
+
+        let events = Event::find_state_by_room(&connection, &room_id, None, before)?;
+        let unsigned_data =
+            UnsignedData::load(&connection, &events, Some(access_token_id), clock.now_millis())?;
+
+        let mut state = Vec::new();
+
+        for event in &events {
+            state.push(event.to_client_json(&unsigned_data)?);
+        }
+
+        Ok(Response::with((Status::Ok, SerializableResponse(state))))
+    }
+}
+
+/// The GET `/rooms/:room_id/state/:event_type/:state_key` and
+/// `/rooms/:room_id/state/:event_type` endpoints.
+///
+/// Only the content of the event is returned.
+pub struct GetStateEvent;
+
+middleware_chain!(GetStateEvent, [RoomIdParam, EventTypeParam, AccessTokenAuth]);
+
+impl Handler for GetStateEvent {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let params = request.extensions.get::<Router>().expect("Params object is missing").clone();
+
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+
+        let event_type = request.extensions.get::<EventTypeParam>()
+            .expect("EventTypeParam should ensure an EventType").clone();
+
+        let state_key = params
+            .find("state_key")
+            .unwrap_or("");
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let before = state_position(
+            RoomMembership::visible_until(&connection, &room_id, &user.id)?
+        );
+
+        let event = match Event::find_state_event(
+            &connection,
+            &room_id,
+            &event_type,
+            state_key,
+            before,
+        )? {
+            Some(event) => event,
+            None => {
+                let error = ApiError::not_found(
+                    Some(&format!("The room has no {} state with key {:?}.", event_type, state_key))
+                );
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let content: Value = from_str(&event.content).map_err(ApiError::from)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(content))))
+    }
+}
+
+/// Converts the result of `RoomMembership::visible_until` into the ordering that state must come
+/// before. The membership event of a user who left is itself part of the state they can see.
+fn state_position(visible_until: Option<i64>) -> i64 {
+    visible_until.map(|ordering| ordering + 1).unwrap_or(i64::max_value())
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::Test;
+
+    #[test]
+    fn get_state_events() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room_with_params(
+            &access_token,
+            r#"{"name":"The Room","topic":"Things"}"#,
+        );
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/state?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.get(&path);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let state = response.json().as_array().unwrap();
+
+        for event_type in &["m.room.create", "m.room.name", "m.room.topic", "m.room.member"] {
+            assert!(state.iter().any(|event| {
+                event.find("type").unwrap().as_str().unwrap() == *event_type
+            }));
+        }
+    }
+
+    #[test]
+    fn state_events_include_what_they_replaced() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room_with_params(&access_token, r#"{"topic":"Things"}"#);
+        let topic_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.topic?access_token={}",
+            room_id,
+            access_token
+        );
+
+        assert_eq!(test.put(&topic_path, r#"{"topic":"Other things"}"#).status, Status::Ok);
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/state?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.get(&path);
+        let state = response.json().as_array().unwrap();
+        let event_of_type = |event_type: &str| {
+            state.iter()
+                .find(|event| event.find("type").unwrap().as_str().unwrap() == event_type)
+                .unwrap()
+        };
+
+        assert_eq!(
+            event_of_type("m.room.topic")
+                .find_path(&["unsigned", "prev_content", "topic"])
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Things"
+        );
+        assert!(
+            event_of_type("m.room.topic")
+                .find_path(&["unsigned", "replaces_state"])
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .starts_with("$")
+        );
+
+        let create_event = event_of_type("m.room.create");
+
+        assert!(create_event.find_path(&["unsigned", "prev_content"]).is_none());
+        assert!(create_event.find_path(&["unsigned", "replaces_state"]).is_none());
+    }
+
+    #[test]
+    fn get_state_event_content() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room_with_params(&access_token, r#"{"name":"The Room"}"#);
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.name?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.get(&path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().find("name").unwrap().as_str().unwrap(), "The Room");
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.member/@carl:ruma.test?access_token={}",
+            room_id,
+            access_token
+        );
+
+        let response = test.get(&path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().find("membership").unwrap().as_str().unwrap(), "join");
+    }
+
+    #[test]
+    fn missing_state_event_is_not_found() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.topic?access_token={}",
+            room_id,
+            access_token
+        );
+
+        assert_eq!(test.get(&path).status, Status::NotFound);
+    }
+
+    #[test]
+    fn non_members_cannot_get_state() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&access_token);
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/state?access_token={}",
+            room_id,
+            alice_access_token
+        );
+
+        assert_eq!(test.get(&path).status, Status::Forbidden);
+    }
+}
@@ -0,0 +1,255 @@
+//! Endpoints for upgrading rooms.
+
+use bodyparser;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+
+use clock::ServerClock;
+use config::Config;
+use db::DB;
+use error::ApiError;
+use membership_cache::ServerMembershipCache;
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, NonGuestAuth, RoomIdParam};
+use modifier::SerializableResponse;
+use power_levels::ServerPowerLevelsCache;
+use room::Room;
+use user::User;
+
+/// The POST `/rooms/:room_id/upgrade` endpoint.
+///
+/// Replaces the room with a new room of the requested version. The user needs permission to
+/// send *m.room.tombstone* events in the old room.
+pub struct UpgradeRoom;
+
+#[derive(Clone, Debug, Deserialize)]
+struct UpgradeRoomRequest {
+    new_version: String,
+}
+
+#[derive(Debug, Serialize)]
+struct UpgradeRoomResponse {
+    replacement_room: String,
+}
+
+middleware_chain!(UpgradeRoom, [JsonRequest, RoomIdParam, AccessTokenAuth, NonGuestAuth]);
+
+impl Handler for UpgradeRoom {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let upgrade_request = match request.get::<bodyparser::Struct<UpgradeRoomRequest>>() {
+            Ok(Some(upgrade_request)) => upgrade_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId").clone();
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let config = Config::from_request(request)?;
+
+        if !config.supported_room_versions.contains(&upgrade_request.new_version) {
+            let error = ApiError::unsupported_room_version(
+                Some(&format!("Room version {} is not supported.", upgrade_request.new_version))
+            );
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let clock = ServerClock::from_request(request)?;
+        let connection = DB::from_request(request)?;
+        let membership_cache = ServerMembershipCache::from_request(request)?;
+        let power_levels_cache = ServerPowerLevelsCache::from_request(request)?;
+
+        let mut room = Room::find(&connection, &room_id)?;
+
+        let replacement_room = room.upgrade(
+            &connection,
+            &membership_cache,
+            &power_levels_cache,
+            &config.domain,
+            &user.id,
+            &upgrade_request.new_version,
+            clock.now_millis(),
+        )?;
+
+        let response = UpgradeRoomResponse {
+            replacement_room: replacement_room.id.to_string(),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::{Response, Test};
+
+    /// Upgrades the room to the given version and returns the response.
+    fn upgrade(test: &Test, access_token: &str, room_id: &str, new_version: &str)
+    -> Response {
+        test.post(
+            &format!("/_matrix/client/r0/rooms/{}/upgrade?access_token={}", room_id, access_token),
+            &format!(r#"{{"new_version": "{}"}}"#, new_version),
+        )
+    }
+
+    /// Returns the content of the room's current state event with the given type.
+    fn state(test: &Test, access_token: &str, room_id: &str, event_type: &str) -> Value {
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/{}?access_token={}",
+            room_id,
+            event_type,
+            access_token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+
+        response.json().clone()
+    }
+
+    #[test]
+    fn upgrade_room() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/createRoom?access_token={}", access_token),
+            r#"{"room_alias_name": "upgraded", "name": "Upgraded", "visibility": "public"}"#,
+        );
+        let room_id = response.json().find("room_id").and_then(Value::as_str).unwrap().to_string();
+
+        let response = upgrade(&test, &access_token, &room_id, "2");
+
+        assert_eq!(response.status, Status::Ok);
+
+        let new_room_id = response
+            .json()
+            .find("replacement_room")
+            .and_then(Value::as_str)
+            .unwrap()
+            .to_string();
+
+        let tombstone = state(&test, &access_token, &room_id, "m.room.tombstone");
+
+        assert_eq!(
+            tombstone.find("replacement_room").and_then(Value::as_str),
+            Some(&new_room_id[..])
+        );
+
+        let create = state(&test, &access_token, &new_room_id, "m.room.create");
+
+        assert_eq!(create.find("room_version").and_then(Value::as_str), Some("2"));
+        assert_eq!(
+            create.find_path(&["predecessor", "room_id"]).and_then(Value::as_str),
+            Some(&room_id[..])
+        );
+
+        let name = state(&test, &access_token, &new_room_id, "m.room.name");
+
+        assert_eq!(name.find("name").and_then(Value::as_str), Some("Upgraded"));
+
+        let power_levels = state(&test, &access_token, &room_id, "m.room.power_levels");
+
+        assert_eq!(power_levels.find("events_default").and_then(Value::as_u64), Some(50));
+
+        let response = test.get("/_matrix/client/r0/directory/room/%23upgraded:ruma.test");
+
+        assert_eq!(
+            response.json().find("room_id").and_then(Value::as_str),
+            Some(&new_room_id[..])
+        );
+    }
+
+    #[test]
+    fn unsupported_room_version() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let response = upgrade(&test, &access_token, &room_id, "42");
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn only_users_who_can_send_tombstones_can_upgrade() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&carl_token);
+
+        assert_eq!(test.join_room(&alice_token, &room_id).status, Status::Ok);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+                room_id,
+                carl_token
+            ),
+            r#"{
+                "ban": 50,
+                "events": {},
+                "events_default": 0,
+                "invite": 50,
+                "kick": 50,
+                "redact": 50,
+                "state_default": 50,
+                "users": { "@carl:ruma.test": 100 },
+                "users_default": 0
+            }"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = upgrade(&test, &alice_token, &room_id, "2");
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn power_levels_are_kept_if_the_user_cannot_change_them() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&carl_token);
+
+        assert_eq!(test.join_room(&alice_token, &room_id).status, Status::Ok);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+                room_id,
+                carl_token
+            ),
+            r#"{
+                "ban": 50,
+                "events": { "m.room.power_levels": 100 },
+                "events_default": 0,
+                "invite": 0,
+                "kick": 50,
+                "redact": 50,
+                "state_default": 50,
+                "users": { "@alice:ruma.test": 50, "@carl:ruma.test": 100 },
+                "users_default": 0
+            }"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = upgrade(&test, &alice_token, &room_id, "2");
+
+        assert_eq!(response.status, Status::Ok);
+
+        let power_levels = state(&test, &carl_token, &room_id, "m.room.power_levels");
+
+        assert_eq!(power_levels.find("events_default").and_then(Value::as_u64), Some(0));
+        assert_eq!(power_levels.find("invite").and_then(Value::as_u64), Some(0));
+    }
+}
@@ -0,0 +1,379 @@
+//! Endpoints for server-side search.
+
+use std::cmp::{Ordering, min};
+use std::collections::HashMap;
+
+use bodyparser;
+use diesel::pg::PgConnection;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use ruma_events::EventType;
+use ruma_identifiers::RoomId;
+use serde_json::Value;
+
+use access_token::AccessToken;
+use clock::ServerClock;
+use db::DB;
+use error::ApiError;
+use event::{Event, UnsignedData};
+use filter::RoomEventFilter;
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
+use modifier::SerializableResponse;
+use room_membership::RoomMembership;
+use user::User;
+
+/// The number of results returned per page if the filter doesn't give a limit.
+const DEFAULT_LIMIT: u64 = 10;
+
+/// The POST `/search` endpoint.
+///
+/// Searches the message bodies, room names, and room topics in the rooms the user has joined.
+/// Only the `room_events` category is supported. The `next_batch` tokens are offsets into the
+/// results.
+pub struct Search;
+
+#[derive(Clone, Debug, Deserialize)]
+struct SearchRequest {
+    search_categories: SearchCategories,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct SearchCategories {
+    room_events: Option<RoomEventsCriteria>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RoomEventsCriteria {
+    filter: Option<RoomEventFilter>,
+    keys: Option<Vec<String>>,
+    order_by: Option<String>,
+    search_term: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResponse {
+    search_categories: ResultCategories,
+}
+
+#[derive(Debug, Serialize)]
+struct ResultCategories {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room_events: Option<RoomEventsResults>,
+}
+
+#[derive(Debug, Serialize)]
+struct RoomEventsResults {
+    count: u64,
+    highlights: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_batch: Option<String>,
+    results: Vec<SearchResult>,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResult {
+    rank: f32,
+    result: Value,
+}
+
+middleware_chain!(Search, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for Search {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let search_request = match request.get::<bodyparser::Struct<SearchRequest>>() {
+            Ok(Some(search_request)) => search_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let url = request.url.clone().into_generic_url();
+        let mut next_batch = None;
+
+        for (key, value) in url.query_pairs() {
+            if key == "next_batch" {
+                next_batch = Some(value.into_owned());
+            }
+        }
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let access_token_id = request.extensions.get::<AccessToken>()
+            .expect("AccessTokenAuth should ensure an access token").id;
+
+        let clock = ServerClock::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        let room_events = match search_request.search_categories.room_events {
+            Some(criteria) => Some(search_room_events(
+                &connection,
+                &user,
+                access_token_id,
+                clock.now_millis(),
+                criteria,
+                next_batch,
+            )?),
+            None => None,
+        };
+
+        let response = SearchResponse {
+            search_categories: ResultCategories {
+                room_events: room_events,
+            },
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// Finds one page of the events matching the criteria, starting at the offset in `next_batch`,
+/// as served at `now_millis` to the client with the given access token.
+fn search_room_events(
+    connection: &PgConnection,
+    user: &User,
+    access_token_id: i64,
+    now_millis: i64,
+    criteria: RoomEventsCriteria,
+    next_batch: Option<String>,
+) -> Result<RoomEventsResults, ApiError> {
+    let offset: u64 = match next_batch {
+        Some(next_batch) => next_batch.parse().map_err(|_| {
+            ApiError::invalid_param("next_batch", "Must be a next_batch token.")
+        })?,
+        None => 0,
+    };
+
+    let filter = criteria.filter.unwrap_or(RoomEventFilter::default());
+
+    let recent = match criteria.order_by.as_ref().map(String::as_str) {
+        Some("rank") | None => false,
+        Some("recent") => true,
+        Some(_) => return Err(ApiError::invalid_param("order_by", "Must be rank or recent.")),
+    };
+
+    let keys = criteria.keys.unwrap_or_else(|| {
+        vec!["content.body".to_string(), "content.name".to_string(), "content.topic".to_string()]
+    });
+
+    let mut event_types = Vec::new();
+
+    for key in keys {
+        let event_type = match &key[..] {
+            "content.body" => EventType::RoomMessage,
+            "content.name" => EventType::RoomName,
+            "content.topic" => EventType::RoomTopic,
+            _ => {
+                return Err(ApiError::invalid_param(
+                    "keys",
+                    "Must be content.body, content.name, or content.topic.",
+                ));
+            }
+        };
+
+        if filter.includes_type(&event_type.to_string()) {
+            event_types.push(event_type.to_string());
+        }
+    }
+
+    let room_ids: Vec<RoomId> = RoomMembership::find_by_uid(connection, user.id.clone())?
+        .into_iter()
+        .filter(|membership| membership.membership == "join")
+        .map(|membership| membership.room_id)
+        .filter(|room_id| filter.includes_room(room_id))
+        .collect();
+
+    let mut matches = Event::search(connection, &room_ids, &event_types, &criteria.search_term)?;
+
+    matches.retain(|search_match| filter.includes_sender(&search_match.user_id));
+
+    if recent {
+        matches.sort_by(|a, b| b.ordering.cmp(&a.ordering));
+    } else {
+        matches.sort_by(|a, b| match b.rank.partial_cmp(&a.rank) {
+            Some(Ordering::Equal) | None => b.ordering.cmp(&a.ordering),
+            Some(ordering) => ordering,
+        });
+    }
+
+    let limit = match filter.limit {
+        Some(0) | None => DEFAULT_LIMIT,
+        Some(limit) => limit,
+    };
+
+    let total = matches.len() as u64;
+    let start = min(offset, total);
+    let end = min(start.saturating_add(limit), total);
+
+    let page: Vec<_> = matches.drain(start as usize..end as usize).collect();
+    let event_ids: Vec<_> = page.iter().map(|search_match| search_match.event_id.clone()).collect();
+
+    let found = Event::find_by_ids(connection, &event_ids)?;
+    let unsigned_data = UnsignedData::load(connection, &found, Some(access_token_id), now_millis)?;
+
+    let mut events: HashMap<String, Event> = found
+        .into_iter()
+        .map(|event| (event.id.to_string(), event))
+        .collect();
+
+    let mut results = Vec::with_capacity(page.len());
+
+    for search_match in page {
+        if let Some(event) = events.remove(&search_match.event_id.to_string()) {
+            results.push(SearchResult {
+                rank: search_match.rank,
+                result: event.to_client_json(&unsigned_data)?,
+            });
+        }
+    }
+
+    let mut highlights: Vec<String> = criteria.search_term
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect();
+
+    highlights.sort();
+    highlights.dedup();
+
+    Ok(RoomEventsResults {
+        count: total,
+        highlights: highlights,
+        next_batch: if end < total { Some(end.to_string()) } else { None },
+        results: results,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    fn send_message(test: &Test, access_token: &str, room_id: &str, txn_id: u32, body: &str) {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/{}?access_token={}",
+            room_id,
+            txn_id,
+            access_token
+        );
+        let response = test.put(&path, &format!(r#"{{"msgtype": "m.text", "body": "{}"}}"#, body));
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    fn bodies(response: &Value) -> Vec<String> {
+        response
+            .find_path(&["search_categories", "room_events", "results"])
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| {
+                result.find_path(&["result", "content", "body"]).unwrap().as_str().unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn search_messages_in_joined_rooms() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_room(&access_token);
+        let other_room_id = test.create_room(&alice_access_token);
+
+        send_message(&test, &access_token, &room_id, 1, "The cats are sleeping");
+        send_message(&test, &access_token, &room_id, 2, "Nothing to see here");
+        send_message(&test, &alice_access_token, &other_room_id, 1, "A cat in another room");
+
+        let path = format!("/_matrix/client/r0/search?access_token={}", access_token);
+        let response = test.post(
+            &path,
+            r#"{"search_categories": {"room_events": {"search_term": "cat"}}}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(bodies(response.json()), vec!["The cats are sleeping"]);
+        assert_eq!(
+            response.json()
+                .find_path(&["search_categories", "room_events", "count"])
+                .unwrap()
+                .as_u64(),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn search_by_recency_with_pagination() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        send_message(&test, &access_token, &room_id, 1, "first rust message");
+        send_message(&test, &access_token, &room_id, 2, "second rust message");
+        send_message(&test, &access_token, &room_id, 3, "third rust message");
+
+        let path = format!("/_matrix/client/r0/search?access_token={}", access_token);
+        let body = r#"{"search_categories": {"room_events": {
+            "search_term": "rust",
+            "order_by": "recent",
+            "filter": {"limit": 2}
+        }}}"#;
+
+        let first_page = test.post(&path, body).json().clone();
+
+        assert_eq!(bodies(&first_page), vec!["third rust message", "second rust message"]);
+
+        let next_batch = first_page
+            .find_path(&["search_categories", "room_events", "next_batch"])
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+        let next_path = format!("{}&next_batch={}", path, next_batch);
+        let second_page = test.post(&next_path, body).json().clone();
+
+        assert_eq!(bodies(&second_page), vec!["first rust message"]);
+        assert!(
+            second_page.find_path(&["search_categories", "room_events", "next_batch"]).is_none()
+        );
+    }
+
+    #[test]
+    fn search_with_sender_filter() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&access_token);
+
+        assert_eq!(test.join_room(&alice_access_token, &room_id).status, Status::Ok);
+
+        send_message(&test, &access_token, &room_id, 1, "lunch from carl");
+        send_message(&test, &alice_access_token, &room_id, 1, "lunch from alice");
+
+        let path = format!("/_matrix/client/r0/search?access_token={}", access_token);
+        let body = r#"{"search_categories": {"room_events": {
+            "search_term": "lunch",
+            "filter": {"senders": ["@alice:ruma.test"]}
+        }}}"#;
+
+        assert_eq!(bodies(test.post(&path, body).json()), vec!["lunch from alice"]);
+    }
+
+    #[test]
+    fn invalid_order_by() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let path = format!("/_matrix/client/r0/search?access_token={}", access_token);
+        let body = r#"{"search_categories": {"room_events": {
+            "search_term": "lunch",
+            "order_by": "alphabetical"
+        }}}"#;
+
+        assert_eq!(test.post(&path, body).status, Status::BadRequest);
+    }
+}
@@ -0,0 +1,1674 @@
+//! Endpoints for syncing.
+
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use diesel::pg::PgConnection;
+use iron::{Chain, Handler, IronError, IronResult, Request, Response};
+use iron::status::Status;
+use ruma_events::EventType;
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::{Value, from_str};
+
+use access_token::AccessToken;
+use account_data::{AccountData, RoomAccountData};
+use clock::ServerClock;
+use db::{DB, RequestConnectionPool};
+use device_list::DeviceLists;
+use error::ApiError;
+use event::{Event, UnsignedData};
+use filter::{Filter, StoredFilter};
+use lazy_loaded_member::{LazyLoadedMember, NewLazyLoadedMember};
+use middleware::{AccessTokenAuth, MiddlewareChain};
+use modifier::SerializableResponse;
+use presence::Presence;
+use push_rule::{NotificationCounts, Ruleset};
+use receipt::Receipt;
+use room_membership::RoomMembership;
+use room_tag::RoomTag;
+use stream_position::{Stream, StreamPosition};
+use to_device::ToDeviceMessage;
+use typing::{ServerTyping, Typing};
+use user::User;
+
+/// The number of timeline events returned per room if the filter doesn't set a limit.
+const DEFAULT_TIMELINE_LIMIT: u64 = 10;
+
+/// The longest a client can wait for new events, in milliseconds.
+const MAX_TIMEOUT: u64 = 60_000;
+
+/// The most threads that build room sections for a single sync request.
+const SYNC_WORKERS: usize = 4;
+
+/// How often to check for new events while long-polling.
+const POLL_INTERVAL_MILLIS: u64 = 500;
+
+/// State events included in the stripped state of rooms the user is invited to or has knocked on,
+/// which is enough for a client to show the room before the user joins.
+const STRIPPED_STATE_TYPES: &'static [&'static str] = &[
+    "m.room.avatar",
+    "m.room.canonical_alias",
+    "m.room.create",
+    "m.room.encryption",
+    "m.room.join_rules",
+    "m.room.name",
+    "m.room.topic",
+];
+
+/// The GET `/sync` endpoint.
+///
+/// Without a `since` token, returns the current state and recent timeline of every room the user
+/// is in. With one, returns only what changed after it, waiting up to `timeout` milliseconds for
+/// something to change. The `next_batch` token is the position in the events stream that was
+/// considered, which also covers account data, typing notifications, receipts, presence, device
+/// list changes, and messages sent to the device.
+///
+/// Clients in many rooms can ask for a window of their joined rooms with `room_ranges`, e.g.
+/// `0-19,40-59`. Joined rooms are then ranked by their most recent event, and only those whose
+/// rank falls in one of the inclusive ranges are included, with `room_window` giving the total
+/// number of joined rooms and the rooms in each range. Rooms that moved into the window since the
+/// `since` token are sent with their full state. Invites, knocks, and left rooms are not windowed.
+pub struct Sync;
+
+#[derive(Debug, Serialize)]
+struct SyncResponse {
+    account_data: Events,
+    device_lists: DeviceLists,
+    next_batch: String,
+    presence: Events,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room_window: Option<RoomWindow>,
+    rooms: Rooms,
+    to_device: Events,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Events {
+    events: Vec<Value>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Rooms {
+    invite: BTreeMap<String, InvitedRoom>,
+    join: BTreeMap<String, JoinedRoom>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    knock: BTreeMap<String, KnockedRoom>,
+    leave: BTreeMap<String, LeftRoom>,
+}
+
+#[derive(Debug, Serialize)]
+struct InvitedRoom {
+    invite_state: Events,
+}
+
+#[derive(Debug, Serialize)]
+struct JoinedRoom {
+    account_data: Events,
+    ephemeral: Events,
+    state: Events,
+    timeline: Timeline,
+    unread_notifications: NotificationCounts,
+}
+
+#[derive(Debug, Serialize)]
+struct KnockedRoom {
+    knock_state: Events,
+}
+
+#[derive(Debug, Serialize)]
+struct LeftRoom {
+    state: Events,
+    timeline: Timeline,
+}
+
+/// The joined rooms in the ranges the client asked for.
+#[derive(Debug, Serialize)]
+struct RoomWindow {
+    count: usize,
+    ranges: Vec<RoomRange>,
+}
+
+/// The joined rooms ranked `start` to `end`, most recently active first.
+#[derive(Debug, Serialize)]
+struct RoomRange {
+    range: (usize, usize),
+    room_ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Timeline {
+    events: Vec<Value>,
+    limited: bool,
+    prev_batch: String,
+}
+
+/// A room whose section of the sync response still has to be built.
+struct RoomJob {
+    kind: RoomJobKind,
+    room_id: RoomId,
+}
+
+/// What the user's membership means for a `RoomJob`.
+enum RoomJobKind {
+    /// The user is joined, so the room goes in the `join` section.
+    Join {
+        full_state: bool,
+        joined_at: i64,
+    },
+    /// The user was invited, so the room goes in the `invite` section along with the invite.
+    Invite {
+        member_event: Value,
+        up_to: i64,
+    },
+    /// The user knocked, so the room goes in the `knock` section along with the knock.
+    Knock {
+        member_event: Value,
+        up_to: i64,
+    },
+    /// The user left or was banned at the ordering `up_to`.
+    Leave {
+        up_to: i64,
+    },
+}
+
+/// A finished room section.
+enum RoomSection {
+    Invite(InvitedRoom),
+    Join(JoinedRoom),
+    Knock(KnockedRoom),
+    Leave(LeftRoom),
+}
+
+/// What the sync workers need to know about the request.
+struct RoomContext {
+    access_token_id: i64,
+    device_id: String,
+    filter: Filter,
+    full_state: bool,
+    now: i64,
+    position: i64,
+    push_rules: Ruleset,
+    since: Option<i64>,
+    typing: Arc<Typing>,
+    user_id: UserId,
+}
+
+/// The parsed query parameters of a sync request.
+struct SyncOptions {
+    filter: Filter,
+    full_state: bool,
+    room_ranges: Option<Vec<(usize, usize)>>,
+    since: Option<i64>,
+    timeout: u64,
+}
+
+middleware_chain!(Sync, [AccessTokenAuth]);
+
+impl Handler for Sync {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let (access_token_id, device_id) = {
+            let access_token = request.extensions.get::<AccessToken>()
+                .expect("AccessTokenAuth should ensure an access token");
+
+            (access_token.id, access_token.device_id.clone())
+        };
+
+        let clock = ServerClock::from_request(request)?;
+        let typing = ServerTyping::from_request(request)?;
+
+        let options = {
+            let connection = DB::from_request(request)?;
+
+            let options = match SyncOptions::from_request(request, &connection, &user.id) {
+                Ok(options) => options,
+                Err(error) => return Err(IronError::new(error.clone(), error)),
+            };
+
+            Presence::touch(&connection, &user.id, clock.now_millis())?;
+
+            options
+        };
+
+        let deadline = Instant::now() + Duration::from_millis(options.timeout);
+
+        loop {
+            // Connections are returned to the pool between polls so idle clients don't hold one
+            // for the whole timeout.
+            let response = {
+                let pool = DB::pool_from_request(request)?;
+
+                let now = clock.now_millis();
+
+                sync(&pool, &typing, &user.id, access_token_id, &device_id, &options, now)?
+            };
+
+            let now = Instant::now();
+
+            if options.since.is_none() || options.full_state || !response.rooms.is_empty() ||
+                !response.account_data.events.is_empty() ||
+                !response.presence.events.is_empty() || !response.device_lists.is_empty() ||
+                !response.to_device.events.is_empty() || now >= deadline {
+                return Ok(Response::with((Status::Ok, SerializableResponse(response))));
+            }
+
+            thread::sleep(min(Duration::from_millis(POLL_INTERVAL_MILLIS), deadline - now));
+        }
+    }
+}
+
+impl SyncOptions {
+    /// Reads the options from the request's query string.
+    ///
+    /// The filter can be given either as JSON or as the ID of a filter the user uploaded.
+    fn from_request(request: &Request, connection: &PgConnection, user_id: &UserId)
+    -> Result<SyncOptions, ApiError> {
+        let url = request.url.clone().into_generic_url();
+        let mut options = SyncOptions {
+            filter: Filter::default(),
+            full_state: false,
+            room_ranges: None,
+            since: None,
+            timeout: 0,
+        };
+
+        for (key, value) in url.query_pairs() {
+            match &key[..] {
+                "filter" => {
+                    if !value.starts_with('{') {
+                        options.filter = match StoredFilter::find(connection, user_id, &value)? {
+                            Some(filter) => filter.filter()?,
+                            None => {
+                                return Err(ApiError::invalid_param(
+                                    "filter",
+                                    "No filter was found with the given ID.",
+                                ));
+                            }
+                        };
+
+                        continue;
+                    }
+
+                    options.filter = from_str(&value).map_err(|_| {
+                        ApiError::invalid_param("filter", "Must be a valid filter definition.")
+                    })?;
+                }
+                "full_state" => options.full_state = value == "true",
+                "room_ranges" => options.room_ranges = Some(parse_room_ranges(&value)?),
+                "since" => {
+                    options.since = Some(value.parse().map_err(|_| {
+                        ApiError::invalid_param("since", "Must be a next_batch token from /sync.")
+                    })?);
+                }
+                "timeout" => {
+                    let timeout: u64 = value.parse().map_err(|_| {
+                        ApiError::invalid_param("timeout", "Must be a number of milliseconds.")
+                    })?;
+
+                    options.timeout = min(timeout, MAX_TIMEOUT);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+impl Rooms {
+    /// Whether or not there are no room updates.
+    fn is_empty(&self) -> bool {
+        self.invite.is_empty() && self.join.is_empty() && self.knock.is_empty() &&
+            self.leave.is_empty()
+    }
+}
+
+/// Builds a sync response for the events up to now.
+///
+/// The memberships and account data are read first, then each room's section is built by a
+/// small pool of worker threads, each checking out its own connection for one room at a time.
+fn sync(
+    pool: &RequestConnectionPool,
+    typing: &Arc<Typing>,
+    user_id: &UserId,
+    access_token_id: i64,
+    device_id: &str,
+    options: &SyncOptions,
+    now: i64,
+) -> Result<SyncResponse, ApiError> {
+    let filter = &options.filter;
+    let connection = pool.get()?;
+    let position = StreamPosition::current(&connection, Stream::Events)?;
+    let memberships = RoomMembership::find_by_uid(&connection, user_id.clone())?;
+    let member_event_ids: Vec<_> = memberships.iter()
+        .map(|membership| membership.event_id.clone())
+        .collect();
+    let member_events: HashMap<String, Event> = Event::find_by_ids(&connection, &member_event_ids)?
+        .into_iter()
+        .map(|event| (event.id.to_string(), event))
+        .collect();
+
+    let mut room_jobs = Vec::new();
+
+    for membership in memberships {
+        if membership.forgotten || !filter.includes_room(&membership.room_id) {
+            continue;
+        }
+
+        let member_event = match member_events.get(&membership.event_id.to_string()) {
+            Some(member_event) => member_event,
+            None => continue,
+        };
+
+        // The membership changed after this sync started, so the next sync will report it.
+        if member_event.ordering > position {
+            continue;
+        }
+
+        let changed = options.since.map(|since| member_event.ordering > since).unwrap_or(true);
+
+        let kind = match &membership.membership[..] {
+            "join" => RoomJobKind::Join {
+                // Rooms joined since the last sync need their full state.
+                full_state: options.full_state || (options.since.is_some() && changed),
+                joined_at: member_event.ordering,
+            },
+            "invite" if changed => RoomJobKind::Invite {
+                member_event: stripped(member_event)?,
+                up_to: member_event.ordering,
+            },
+            "knock" if changed => RoomJobKind::Knock {
+                member_event: stripped(member_event)?,
+                up_to: member_event.ordering,
+            },
+            "ban" | "leave" if changed && (options.since.is_some() || filter.includes_leave()) => {
+                // Only show what happened up to the moment the user left.
+                RoomJobKind::Leave {
+                    up_to: member_event.ordering,
+                }
+            }
+            _ => continue,
+        };
+
+        room_jobs.push(RoomJob {
+            kind: kind,
+            room_id: membership.room_id,
+        });
+    }
+
+    let room_window = match options.room_ranges {
+        Some(ref ranges) => {
+            Some(window_joined_rooms(&connection, &mut room_jobs, ranges, options.since, position)?)
+        }
+        None => None,
+    };
+
+    let account_data = {
+        let after = if options.full_state { None } else { options.since };
+        let mut events = Vec::new();
+
+        for account_data in AccountData::get_by_uid(&connection, user_id, after, position)? {
+            events.push(account_data_event(&account_data.data_type, &account_data.content)?);
+        }
+
+        filter.filter_account_data(events)
+    };
+
+    let presence = Presence::find_visible(&connection, user_id, options.since, position)?;
+    let presence_events = presence.iter().map(|presence| presence.to_event(now)).collect();
+
+    // Clients fetch every device list they need after an initial sync.
+    let device_lists = match options.since {
+        Some(since) => DeviceLists::find(&connection, user_id, since, position)?,
+        None => DeviceLists::default(),
+    };
+
+    // Syncing from a position acknowledges the messages up to it, so they can be deleted.
+    let messages = ToDeviceMessage::take(&connection, user_id, device_id, options.since, position)?;
+    let mut to_device_events = Vec::new();
+
+    for message in messages {
+        to_device_events.push(message.to_event()?);
+    }
+
+    // A client starting over has none of the membership events it was sent before.
+    if options.since.is_none() && filter.lazy_loads_members() {
+        LazyLoadedMember::clear(&connection, user_id, device_id)?;
+    }
+
+    let push_rules = Ruleset::load(&connection, user_id)?;
+
+    // The workers may need this connection if the pool is small.
+    drop(connection);
+
+    let context = RoomContext {
+        access_token_id: access_token_id,
+        device_id: device_id.to_string(),
+        filter: filter.clone(),
+        full_state: options.full_state,
+        now: now,
+        position: position,
+        push_rules: push_rules,
+        since: options.since,
+        typing: typing.clone(),
+        user_id: user_id.clone(),
+    };
+
+    Ok(SyncResponse {
+        account_data: Events { events: account_data },
+        device_lists: device_lists,
+        next_batch: position.to_string(),
+        presence: Events { events: filter.filter_presence(presence_events) },
+        room_window: room_window,
+        rooms: build_rooms(pool, context, room_jobs)?,
+        to_device: Events { events: to_device_events },
+    })
+}
+
+/// Parses `room_ranges` from a list of inclusive ranges like `0-19,40-59`.
+fn parse_room_ranges(value: &str) -> Result<Vec<(usize, usize)>, ApiError> {
+    let invalid = || ApiError::invalid_param("room_ranges", "Must be ranges like 0-19,40-59.");
+    let mut ranges = Vec::new();
+
+    for range in value.split(',') {
+        let mut bounds = range.splitn(2, '-');
+
+        let start: usize = bounds.next().and_then(|start| start.parse().ok()).ok_or_else(&invalid)?;
+        let end: usize = bounds.next().and_then(|end| end.parse().ok()).ok_or_else(&invalid)?;
+
+        if start > end {
+            return Err(invalid());
+        }
+
+        ranges.push((start, end));
+    }
+
+    Ok(ranges)
+}
+
+/// Drops the jobs for joined rooms outside the ranges, and marks rooms that weren't in them at
+/// `since` as needing their full state.
+fn window_joined_rooms(
+    connection: &PgConnection,
+    room_jobs: &mut Vec<RoomJob>,
+    ranges: &[(usize, usize)],
+    since: Option<i64>,
+    position: i64,
+) -> Result<RoomWindow, ApiError> {
+    let joined: Vec<(RoomId, i64)> = room_jobs
+        .iter()
+        .filter_map(|room_job| match room_job.kind {
+            RoomJobKind::Join { joined_at, .. } => Some((room_job.room_id.clone(), joined_at)),
+            _ => None,
+        })
+        .collect();
+
+    let room_ids: Vec<RoomId> = joined.iter().map(|&(ref room_id, _)| room_id.clone()).collect();
+    let ranked = rank_by_recency(connection, room_ids, position)?;
+    let window = rooms_in_ranges(&ranked, ranges);
+
+    let in_window: HashSet<RoomId> = window
+        .iter()
+        .flat_map(|range| range.iter().cloned())
+        .collect();
+
+    let previous_window: HashSet<RoomId> = match since {
+        Some(since) => {
+            let room_ids = joined
+                .iter()
+                .filter(|&&(_, joined_at)| joined_at <= since)
+                .map(|&(ref room_id, _)| room_id.clone())
+                .collect();
+            let ranked = rank_by_recency(connection, room_ids, since)?;
+
+            rooms_in_ranges(&ranked, ranges).into_iter().flat_map(Vec::into_iter).collect()
+        }
+        None => HashSet::new(),
+    };
+
+    room_jobs.retain(|room_job| match room_job.kind {
+        RoomJobKind::Join { .. } => in_window.contains(&room_job.room_id),
+        _ => true,
+    });
+
+    if since.is_some() {
+        for room_job in room_jobs.iter_mut() {
+            if let RoomJobKind::Join { ref mut full_state, .. } = room_job.kind {
+                if !previous_window.contains(&room_job.room_id) {
+                    *full_state = true;
+                }
+            }
+        }
+    }
+
+    Ok(RoomWindow {
+        count: ranked.len(),
+        ranges: ranges
+            .iter()
+            .zip(window)
+            .map(|(&range, room_ids)| RoomRange {
+                range: range,
+                room_ids: room_ids.iter().map(RoomId::to_string).collect(),
+            })
+            .collect(),
+    })
+}
+
+/// Orders rooms by their most recent event up to `up_to`, newest first, breaking ties by room ID.
+fn rank_by_recency(connection: &PgConnection, mut room_ids: Vec<RoomId>, up_to: i64)
+-> Result<Vec<RoomId>, ApiError> {
+    let latest = Event::latest_orderings_by_rooms(connection, &room_ids, up_to)?;
+
+    room_ids.sort_by_key(RoomId::to_string);
+    room_ids.sort_by_key(|room_id| -latest.get(room_id).cloned().unwrap_or(0));
+
+    Ok(room_ids)
+}
+
+/// The rooms ranked in each of the inclusive ranges.
+fn rooms_in_ranges(ranked: &[RoomId], ranges: &[(usize, usize)]) -> Vec<Vec<RoomId>> {
+    ranges
+        .iter()
+        .map(|&(start, end)| ranked.iter().skip(start).take(end - start + 1).cloned().collect())
+        .collect()
+}
+
+/// Builds the room sections for `room_jobs` on up to `SYNC_WORKERS` threads.
+fn build_rooms(pool: &RequestConnectionPool, context: RoomContext, room_jobs: Vec<RoomJob>)
+-> Result<Rooms, ApiError> {
+    let job_count = room_jobs.len();
+    let worker_count = min(SYNC_WORKERS, job_count);
+    let context = Arc::new(context);
+    let queue = Arc::new(Mutex::new(room_jobs.into_iter()));
+    let (sender, receiver) = channel();
+
+    for _ in 0..worker_count {
+        let context = context.clone();
+        let pool = pool.clone();
+        let queue = queue.clone();
+        let sender = sender.clone();
+
+        thread::spawn(move || {
+            loop {
+                let room_job = match queue.lock() {
+                    Ok(mut queue) => queue.next(),
+                    Err(_) => None,
+                };
+
+                let room_job = match room_job {
+                    Some(room_job) => room_job,
+                    None => break,
+                };
+
+                let result = pool.get().and_then(|connection| {
+                    room_job.build(&connection, &context)
+                });
+                let failed = result.is_err();
+
+                if sender.send(result).is_err() || failed {
+                    break;
+                }
+            }
+        });
+    }
+
+    drop(sender);
+
+    let mut rooms = Rooms::default();
+    let mut received = 0;
+
+    for result in receiver {
+        received += 1;
+
+        match result? {
+            Some((room_id, RoomSection::Invite(room))) => {
+                rooms.invite.insert(room_id, room);
+            }
+            Some((room_id, RoomSection::Join(room))) => {
+                rooms.join.insert(room_id, room);
+            }
+            Some((room_id, RoomSection::Knock(room))) => {
+                rooms.knock.insert(room_id, room);
+            }
+            Some((room_id, RoomSection::Leave(room))) => {
+                rooms.leave.insert(room_id, room);
+            }
+            None => {}
+        }
+    }
+
+    if received < job_count {
+        return Err(ApiError::unknown(Some("A sync worker stopped unexpectedly.")));
+    }
+
+    Ok(rooms)
+}
+
+impl RoomJob {
+    /// Builds the room's section of the response, or `None` if there's nothing to report.
+    fn build(&self, connection: &PgConnection, context: &RoomContext)
+    -> Result<Option<(String, RoomSection)>, ApiError> {
+        let filter = &context.filter;
+        let user_id = &context.user_id;
+
+        let section = match self.kind {
+            RoomJobKind::Join { full_state, joined_at } => {
+                let (state, timeline) = state_and_timeline(
+                    connection,
+                    context,
+                    &self.room_id,
+                    context.position,
+                    full_state,
+                )?;
+
+                let mut ephemeral = Vec::new();
+
+                if let Some(user_ids) = context.typing.typing_users(&self.room_id, context.since) {
+                    ephemeral.push(typing_event(&user_ids));
+                }
+
+                let receipts = Receipt::find_by_room(
+                    connection,
+                    &self.room_id,
+                    context.since,
+                    context.position,
+                )?;
+
+                if !receipts.is_empty() {
+                    ephemeral.push(receipt_event(&receipts));
+                }
+
+                let ephemeral = filter.filter_ephemeral(&self.room_id, ephemeral);
+
+                let account_data = room_account_data(
+                    connection,
+                    filter,
+                    user_id,
+                    &self.room_id,
+                    if full_state { None } else { context.since },
+                    context.position,
+                )?;
+
+                if context.since.is_some() && !full_state && state.is_empty() &&
+                    timeline.events.is_empty() && ephemeral.is_empty() && account_data.is_empty() {
+                    return Ok(None);
+                }
+
+                let unread_notifications = context.push_rules.count_unread(
+                    connection,
+                    &self.room_id,
+                    user_id,
+                    joined_at,
+                    context.position,
+                )?;
+
+                RoomSection::Join(JoinedRoom {
+                    account_data: Events { events: account_data },
+                    ephemeral: Events { events: ephemeral },
+                    state: Events { events: state },
+                    timeline: timeline,
+                    unread_notifications: unread_notifications,
+                })
+            }
+            RoomJobKind::Invite { ref member_event, up_to } => {
+                let invite_state = stripped_state(connection, &self.room_id, up_to, member_event)?;
+
+                RoomSection::Invite(InvitedRoom {
+                    invite_state: Events { events: invite_state },
+                })
+            }
+            RoomJobKind::Knock { ref member_event, up_to } => {
+                let knock_state = stripped_state(connection, &self.room_id, up_to, member_event)?;
+
+                RoomSection::Knock(KnockedRoom {
+                    knock_state: Events { events: knock_state },
+                })
+            }
+            RoomJobKind::Leave { up_to } => {
+                let (state, timeline) = state_and_timeline(
+                    connection,
+                    context,
+                    &self.room_id,
+                    up_to,
+                    context.full_state,
+                )?;
+
+                RoomSection::Leave(LeftRoom {
+                    state: Events { events: state },
+                    timeline: timeline,
+                })
+            }
+        };
+
+        Ok(Some((self.room_id.to_string(), section)))
+    }
+}
+
+/// Gets a room's timeline after `since` up to the ordering `up_to`, and the state at the start of
+/// the timeline.
+///
+/// Unless `full_state` is set, only state that changed after `since` is included. When the filter
+/// lazy-loads members, the membership events of the timeline's senders are included instead of
+/// every member's, leaving out the ones this device was already sent.
+fn state_and_timeline(
+    connection: &PgConnection,
+    context: &RoomContext,
+    room_id: &RoomId,
+    up_to: i64,
+    full_state: bool,
+) -> Result<(Vec<Value>, Timeline), ApiError> {
+    let filter = &context.filter;
+    let user_id = &context.user_id;
+    let since = context.since;
+    let limit = filter.timeline_limit().unwrap_or(DEFAULT_TIMELINE_LIMIT) as i64;
+
+    // Fetch one extra event to find out whether there are more than fit in the timeline.
+    let mut events = Event::find_recent_by_room(connection, room_id, since, up_to, limit + 1)?;
+    let limited = events.len() as i64 > limit;
+
+    if limited {
+        events.remove(0);
+    }
+
+    let timeline_start = events.first().map(|event| event.ordering).unwrap_or(up_to + 1);
+    let state_since = if full_state { None } else { since };
+    let mut state_events =
+        Event::find_state_by_room(connection, room_id, state_since, timeline_start)?;
+
+    let unsigned_data =
+        UnsignedData::load(connection, &events, Some(context.access_token_id), context.now)?;
+
+    let mut timeline = Vec::new();
+
+    for event in &events {
+        timeline.push(event.to_client_json(&unsigned_data)?);
+    }
+
+    let timeline = filter.filter_timeline(room_id, timeline);
+    let lazy_load_members = filter.lazy_loads_members();
+    let mut sent_members = HashSet::new();
+
+    if lazy_load_members {
+        let mut senders: HashSet<String> = timeline
+            .iter()
+            .filter_map(|event| event.find("sender").and_then(|sender| sender.as_str()))
+            .map(|sender| sender.to_string())
+            .collect();
+
+        senders.insert(user_id.to_string());
+
+        // Senders whose membership didn't change after `since` still need their membership
+        // events, since the client may never have been sent them.
+        for event in &state_events {
+            if event.event_type == "m.room.member" {
+                if let Some(ref state_key) = event.state_key {
+                    senders.remove(state_key);
+                }
+            }
+        }
+
+        for sender in senders {
+            let member_event = Event::find_state_event(
+                connection,
+                room_id,
+                &EventType::RoomMember,
+                &sender,
+                timeline_start,
+            )?;
+
+            if let Some(member_event) = member_event {
+                state_events.push(member_event);
+            }
+        }
+
+        let previously_sent =
+            LazyLoadedMember::find_by_room(connection, user_id, &context.device_id, room_id)?;
+
+        // A member counts as sent only if the client has their current membership event.
+        for event in &state_events {
+            let member_id = match event.state_key {
+                Some(ref member_id) if event.event_type == "m.room.member" => member_id,
+                _ => continue,
+            };
+
+            let already_sent = previously_sent.iter().any(|sent| {
+                sent.member_id == *member_id && sent.event_id == event.id.to_string()
+            });
+
+            if already_sent {
+                sent_members.insert(member_id.clone());
+            }
+        }
+    }
+
+    let unsigned_data =
+        UnsignedData::load(connection, &state_events, Some(context.access_token_id), context.now)?;
+
+    let mut state = Vec::new();
+
+    for event in &state_events {
+        state.push(event.to_client_json(&unsigned_data)?);
+    }
+
+    let state = filter.filter_state(room_id, user_id, state, &timeline, &sent_members);
+
+    if lazy_load_members {
+        let mut new_members = Vec::new();
+
+        for event in &state {
+            let event_type = event.find("type").and_then(|event_type| event_type.as_str());
+
+            if event_type != Some("m.room.member") {
+                continue;
+            }
+
+            let member_id = event.find("state_key").and_then(|state_key| state_key.as_str());
+            let event_id = event.find("event_id").and_then(|event_id| event_id.as_str());
+
+            if let (Some(member_id), Some(event_id)) = (member_id, event_id) {
+                new_members.push(NewLazyLoadedMember {
+                    user_id: user_id.clone(),
+                    device_id: context.device_id.clone(),
+                    room_id: room_id.clone(),
+                    member_id: member_id.to_string(),
+                    event_id: event_id.to_string(),
+                });
+            }
+        }
+
+        LazyLoadedMember::record(connection, &new_members)?;
+    }
+
+    Ok((state, Timeline {
+        events: timeline,
+        limited: limited,
+        prev_batch: (timeline_start - 1).to_string(),
+    }))
+}
+
+/// Gets the user's account data for a room that changed after the position `after` up to the
+/// position `up_to` as events. Full syncs also include their tags on it as an *m.tag* event.
+fn room_account_data(
+    connection: &PgConnection,
+    filter: &Filter,
+    user_id: &UserId,
+    room_id: &RoomId,
+    after: Option<i64>,
+    up_to: i64,
+) -> Result<Vec<Value>, ApiError> {
+    let mut events = Vec::new();
+
+    for account_data in RoomAccountData::find_by_room(connection, user_id, room_id, after, up_to)? {
+        events.push(account_data_event(&account_data.data_type, &account_data.content)?);
+    }
+
+    // Tags don't record when they changed, so they're only sent in full.
+    if after.is_some() {
+        return Ok(filter.filter_room_account_data(room_id, events));
+    }
+
+    let tags = RoomTag::find_content(connection, user_id, room_id)?;
+
+    if tags.find("tags").and_then(Value::as_object).map(|tags| !tags.is_empty()).unwrap_or(false) {
+        let mut event = BTreeMap::new();
+
+        event.insert("content".to_string(), tags);
+        event.insert("type".to_string(), Value::String("m.tag".to_string()));
+
+        events.push(Value::Object(event));
+    }
+
+    Ok(filter.filter_room_account_data(room_id, events))
+}
+
+/// Formats stored account data as an event.
+fn account_data_event(data_type: &str, content: &str) -> Result<Value, ApiError> {
+    let mut event = BTreeMap::new();
+
+    event.insert("content".to_string(), from_str(content)?);
+    event.insert("type".to_string(), Value::String(data_type.to_string()));
+
+    Ok(Value::Object(event))
+}
+
+/// Formats the users typing in a room as an *m.typing* event.
+fn typing_event(user_ids: &[UserId]) -> Value {
+    let mut content = BTreeMap::new();
+
+    content.insert(
+        "user_ids".to_string(),
+        Value::Array(user_ids.iter().map(|user_id| Value::String(user_id.to_string())).collect()),
+    );
+
+    let mut event = BTreeMap::new();
+
+    event.insert("content".to_string(), Value::Object(content));
+    event.insert("type".to_string(), Value::String("m.typing".to_string()));
+
+    Value::Object(event)
+}
+
+/// Formats receipts as a single *m.receipt* event, grouped by event, receipt type and user.
+fn receipt_event(receipts: &[Receipt]) -> Value {
+    let mut content: BTreeMap<String, BTreeMap<String, BTreeMap<String, Value>>> = BTreeMap::new();
+
+    for receipt in receipts {
+        let mut receipt_content = BTreeMap::new();
+
+        receipt_content.insert("ts".to_string(), Value::I64(receipt.ts));
+
+        let by_type = content.entry(receipt.event_id.to_string()).or_insert_with(BTreeMap::new);
+        let by_user = by_type.entry(receipt.receipt_type.clone()).or_insert_with(BTreeMap::new);
+
+        by_user.insert(receipt.user_id.to_string(), Value::Object(receipt_content));
+    }
+
+    let content = content.into_iter()
+        .map(|(event_id, by_type)| {
+            let by_type = by_type.into_iter()
+                .map(|(receipt_type, by_user)| (receipt_type, Value::Object(by_user)))
+                .collect();
+
+            (event_id, Value::Object(by_type))
+        })
+        .collect();
+
+    let mut event = BTreeMap::new();
+
+    event.insert("content".to_string(), Value::Object(content));
+    event.insert("type".to_string(), Value::String("m.receipt".to_string()));
+
+    Value::Object(event)
+}
+
+/// Gets the stripped state of a room before the ordering `up_to`, followed by the user's own
+/// membership event.
+fn stripped_state(connection: &PgConnection, room_id: &RoomId, up_to: i64, member_event: &Value)
+-> Result<Vec<Value>, ApiError> {
+    let mut state = Vec::new();
+
+    for event in Event::find_state_by_room(connection, room_id, None, up_to)? {
+        if STRIPPED_STATE_TYPES.iter().any(|event_type| *event_type == event.event_type) {
+            state.push(stripped(&event)?);
+        }
+    }
+
+    state.push(member_event.clone());
+
+    Ok(state)
+}
+
+/// Formats a state event as stripped state, which only has the fields needed to preview a room.
+fn stripped(event: &Event) -> Result<Value, ApiError> {
+    let mut stripped = BTreeMap::new();
+
+    stripped.insert("content".to_string(), from_str(&event.content)?);
+    stripped.insert("sender".to_string(), Value::String(event.user_id.to_string()));
+    stripped.insert(
+        "state_key".to_string(),
+        Value::String(event.state_key.clone().unwrap_or(String::new())),
+    );
+    stripped.insert("type".to_string(), Value::String(event.event_type.clone()));
+
+    Ok(Value::Object(stripped))
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    fn sync(test: &Test, access_token: &str, query: &str) -> Value {
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?access_token={}{}",
+            access_token,
+            query
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+
+        response.json().clone()
+    }
+
+    fn send_message(test: &Test, access_token: &str, room_id: &str, txn_id: u32, body: &str) {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/{}?access_token={}",
+            room_id,
+            txn_id,
+            access_token
+        );
+
+        let response = test.put(&path, &format!(r#"{{"msgtype":"m.text","body":"{}"}}"#, body));
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    fn timeline_bodies(response: &Value, room_id: &str) -> Vec<String> {
+        response
+            .find_path(&["rooms", "join", room_id, "timeline", "events"])
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|event| event.find_path(&["content", "body"]))
+            .map(|body| body.as_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn member_state_keys(response: &Value, room_id: &str) -> Vec<String> {
+        let mut state_keys: Vec<String> = response
+            .find_path(&["rooms", "join", room_id, "state", "events"])
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|event| event.find("type").unwrap().as_str().unwrap() == "m.room.member")
+            .map(|event| event.find("state_key").unwrap().as_str().unwrap().to_string())
+            .collect();
+
+        state_keys.sort();
+        state_keys
+    }
+
+    fn next_batch(response: &Value) -> i64 {
+        response.find("next_batch").unwrap().as_str().unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn next_batch_has_no_gaps() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let before = next_batch(&sync(&test, &access_token, ""));
+
+        send_message(&test, &access_token, &room_id, 1, "Hello");
+        send_message(&test, &access_token, &room_id, 2, "Goodbye");
+
+        assert_eq!(next_batch(&sync(&test, &access_token, "")), before + 2);
+    }
+
+    #[test]
+    fn unread_notifications_count_events_after_the_read_receipt() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&carl_token);
+
+        assert_eq!(test.join_room(&alice_token, &room_id).status, Status::Ok);
+
+        send_message(&test, &alice_token, &room_id, 1, "Hello");
+        send_message(&test, &alice_token, &room_id, 2, "Are you there, Carl?");
+
+        let response = sync(&test, &carl_token, "");
+        let counts = response
+            .find_path(&["rooms", "join", &room_id, "unread_notifications"])
+            .unwrap();
+
+        assert_eq!(counts.find("notification_count").unwrap().as_u64(), Some(2));
+        assert_eq!(counts.find("highlight_count").unwrap().as_u64(), Some(1));
+
+        let events = response
+            .find_path(&["rooms", "join", &room_id, "timeline", "events"])
+            .unwrap()
+            .as_array()
+            .unwrap();
+        let first_event_id = events[events.len() - 2].find("event_id").unwrap().as_str().unwrap();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/receipt/m.read/{}?access_token={}",
+                room_id,
+                first_event_id,
+                carl_token
+            ),
+            "{}",
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = sync(&test, &carl_token, "");
+        let counts = response
+            .find_path(&["rooms", "join", &room_id, "unread_notifications"])
+            .unwrap();
+
+        assert_eq!(counts.find("notification_count").unwrap().as_u64(), Some(1));
+        assert_eq!(counts.find("highlight_count").unwrap().as_u64(), Some(1));
+    }
+
+    #[test]
+    fn unread_notifications_follow_push_rules() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&carl_token);
+
+        assert_eq!(test.join_room(&alice_token, &room_id).status, Status::Ok);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/pushrules/global/sender/@alice:ruma.test?access_token={}",
+                carl_token
+            ),
+            r#"{"actions": ["dont_notify"]}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        send_message(&test, &alice_token, &room_id, 1, "Hello");
+
+        let response = sync(&test, &carl_token, "");
+        let counts = response
+            .find_path(&["rooms", "join", &room_id, "unread_notifications"])
+            .unwrap();
+
+        assert_eq!(counts.find("notification_count").unwrap().as_u64(), Some(0));
+        assert_eq!(counts.find("highlight_count").unwrap().as_u64(), Some(0));
+    }
+
+    #[test]
+    fn initial_sync_includes_joined_rooms() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        send_message(&test, &access_token, &room_id, 1, "Hello");
+
+        let response = sync(&test, &access_token, "");
+        let room = response.find_path(&["rooms", "join", &room_id]).unwrap();
+        let mut events = room.find_path(&["state", "events"]).unwrap().as_array().unwrap().clone();
+
+        events.extend(room.find_path(&["timeline", "events"]).unwrap().as_array().unwrap().clone());
+
+        let types: Vec<&str> = events
+            .iter()
+            .map(|event| event.find("type").unwrap().as_str().unwrap())
+            .collect();
+
+        assert!(response.find("next_batch").unwrap().as_str().is_some());
+        assert!(types.contains(&"m.room.create"));
+        assert!(types.contains(&"m.room.member"));
+        assert_eq!(timeline_bodies(&response, &room_id), vec!["Hello"]);
+    }
+
+    #[test]
+    fn incremental_sync_only_includes_new_events() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        send_message(&test, &access_token, &room_id, 1, "Before");
+
+        let initial = sync(&test, &access_token, "");
+        let next_batch = initial.find("next_batch").unwrap().as_str().unwrap();
+
+        send_message(&test, &access_token, &room_id, 2, "After");
+
+        let response = sync(&test, &access_token, &format!("&since={}", next_batch));
+
+        assert_eq!(timeline_bodies(&response, &room_id), vec!["After"]);
+        assert!(
+            response
+                .find_path(&["rooms", "join", &room_id, "state", "events"])
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn incremental_sync_without_changes_is_empty() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        test.create_room(&access_token);
+
+        let initial = sync(&test, &access_token, "");
+        let next_batch = initial.find("next_batch").unwrap().as_str().unwrap();
+        let response = sync(&test, &access_token, &format!("&since={}&timeout=0", next_batch));
+
+        assert!(response.find_path(&["rooms", "join"]).unwrap().as_object().unwrap().is_empty());
+        assert_eq!(response.find("next_batch").unwrap().as_str().unwrap(), next_batch);
+    }
+
+    fn put_account_data(
+        test: &Test,
+        access_token: &str,
+        room_id: Option<&str>,
+        data_type: &str,
+        content: &str,
+    ) {
+        let room = room_id.map(|room_id| format!("rooms/{}/", room_id)).unwrap_or(String::new());
+        let path = format!(
+            "/_matrix/client/r0/user/@carl:ruma.test/{}account_data/{}?access_token={}",
+            room,
+            data_type,
+            access_token
+        );
+
+        assert!(test.put(&path, content).status.is_success());
+    }
+
+    #[test]
+    fn incremental_sync_includes_changed_account_data() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        put_account_data(&test, &access_token, None, "org.example.old", r#"{"n":1}"#);
+        put_account_data(&test, &access_token, Some(&room_id), "org.example.old", r#"{"n":1}"#);
+
+        let since = next_batch(&sync(&test, &access_token, ""));
+
+        put_account_data(&test, &access_token, None, "org.example.new", r#"{"n":2}"#);
+        put_account_data(&test, &access_token, Some(&room_id), "org.example.old", r#"{"n":3}"#);
+
+        let response = sync(&test, &access_token, &format!("&since={}&timeout=0", since));
+        let global = response.find_path(&["account_data", "events"]).unwrap().as_array().unwrap();
+        let room = response
+            .find_path(&["rooms", "join", &room_id, "account_data", "events"])
+            .unwrap()
+            .as_array()
+            .unwrap();
+
+        assert_eq!(global.len(), 1);
+        assert_eq!(global[0].find("type").unwrap().as_str(), Some("org.example.new"));
+        assert_eq!(global[0].find_path(&["content", "n"]).unwrap().as_u64(), Some(2));
+        assert_eq!(room.len(), 1);
+        assert_eq!(room[0].find("type").unwrap().as_str(), Some("org.example.old"));
+        assert_eq!(room[0].find_path(&["content", "n"]).unwrap().as_u64(), Some(3));
+
+        let since = next_batch(&response);
+        let response = sync(&test, &access_token, &format!("&since={}&timeout=0", since));
+        let global = response.find_path(&["account_data", "events"]).unwrap().as_array().unwrap();
+
+        assert!(global.is_empty());
+        assert!(response.find_path(&["rooms", "join"]).unwrap().as_object().unwrap().is_empty());
+    }
+
+    #[test]
+    fn timeline_is_limited() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        for txn_id in 0..5 {
+            send_message(&test, &access_token, &room_id, txn_id, &format!("Message {}", txn_id));
+        }
+
+        let filter = r#"{"room":{"timeline":{"limit":2}}}"#;
+        let response = sync(&test, &access_token, &format!("&filter={}", filter));
+        let timeline = response.find_path(&["rooms", "join", &room_id, "timeline"]).unwrap();
+
+        assert_eq!(timeline_bodies(&response, &room_id), vec!["Message 3", "Message 4"]);
+        assert_eq!(timeline.find("limited").unwrap().as_bool(), Some(true));
+    }
+
+    #[test]
+    fn uploaded_filter() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        for txn_id in 0..3 {
+            send_message(&test, &access_token, &room_id, txn_id, &format!("Message {}", txn_id));
+        }
+
+        let path = format!(
+            "/_matrix/client/r0/user/@carl:ruma.test/filter?access_token={}",
+            access_token
+        );
+        let response = test.post(&path, r#"{"room":{"timeline":{"limit":1}}}"#);
+        let filter_id = response.json().find("filter_id").unwrap().as_str().unwrap().to_string();
+        let response = sync(&test, &access_token, &format!("&filter={}", filter_id));
+
+        assert_eq!(timeline_bodies(&response, &room_id), vec!["Message 2"]);
+    }
+
+    #[test]
+    fn lazy_loaded_members() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let bob_access_token = test.create_access_token_with_username("bob");
+        let room_id = test.create_public_room(&access_token);
+
+        assert_eq!(test.join_room(&alice_access_token, &room_id).status, Status::Ok);
+        assert_eq!(test.join_room(&bob_access_token, &room_id).status, Status::Ok);
+
+        send_message(&test, &alice_access_token, &room_id, 0, "Hello");
+
+        let filter = r#"{"room":{"state":{"lazy_load_members":true},"timeline":{"limit":1}}}"#;
+        let initial = sync(&test, &access_token, &format!("&filter={}", filter));
+
+        assert_eq!(
+            member_state_keys(&initial, &room_id),
+            vec!["@alice:ruma.test", "@carl:ruma.test"]
+        );
+
+        send_message(&test, &alice_access_token, &room_id, 1, "Again");
+        send_message(&test, &bob_access_token, &room_id, 0, "Hi");
+
+        let filter = r#"{"room":{"state":{"lazy_load_members":true},"timeline":{"limit":5}}}"#;
+        let response = sync(&test, &access_token, &format!(
+            "&filter={}&since={}",
+            filter,
+            next_batch(&initial)
+        ));
+
+        assert_eq!(timeline_bodies(&response, &room_id), vec!["Again", "Hi"]);
+        assert_eq!(member_state_keys(&response, &room_id), vec!["@bob:ruma.test"]);
+    }
+
+    #[test]
+    fn unknown_filter_id() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?access_token={}&filter=12345",
+            access_token
+        ));
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn invited_rooms_include_invite_state() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_room(&access_token);
+
+        assert_eq!(test.invite(&access_token, &room_id, "@alice:ruma.test").status, Status::Ok);
+
+        let response = sync(&test, &alice_access_token, "");
+        let invite_state = response
+            .find_path(&["rooms", "invite", &room_id, "invite_state", "events"])
+            .unwrap()
+            .as_array()
+            .unwrap();
+        let member_event = invite_state.last().unwrap();
+
+        assert_eq!(member_event.find("state_key").unwrap().as_str(), Some("@alice:ruma.test"));
+        assert_eq!(member_event.find("sender").unwrap().as_str(), Some("@carl:ruma.test"));
+        assert!(response.find_path(&["rooms", "join", &room_id]).is_none());
+    }
+
+    #[test]
+    fn invite_state_only_includes_stripped_state_types() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_room_with_params(
+            &access_token,
+            r#"{"name": "Pending", "topic": "Waiting room"}"#,
+        );
+
+        assert_eq!(test.invite(&access_token, &room_id, "@alice:ruma.test").status, Status::Ok);
+
+        let response = sync(&test, &alice_access_token, "");
+        let invite_state = response
+            .find_path(&["rooms", "invite", &room_id, "invite_state", "events"])
+            .unwrap()
+            .as_array()
+            .unwrap();
+        let event_types: Vec<&str> = invite_state
+            .iter()
+            .map(|event| event.find("type").unwrap().as_str().unwrap())
+            .collect();
+
+        assert!(event_types.contains(&"m.room.create"));
+        assert!(event_types.contains(&"m.room.name"));
+        assert!(event_types.contains(&"m.room.topic"));
+        assert!(!event_types.contains(&"m.room.history_visibility"));
+        assert!(!event_types.contains(&"m.room.power_levels"));
+
+        // Only the invited user's own membership is included.
+        let member_events = event_types.iter().filter(|event_type| **event_type == "m.room.member");
+
+        assert_eq!(member_events.count(), 1);
+
+        for event in invite_state {
+            assert!(event.find("event_id").is_none());
+            assert!(event.find("origin_server_ts").is_none());
+        }
+
+        assert!(response.find_path(&["rooms", "knock"]).is_none());
+    }
+
+    #[test]
+    fn newly_joined_rooms_include_full_state() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&access_token);
+
+        let initial = sync(&test, &alice_access_token, "");
+        let next_batch = initial.find("next_batch").unwrap().as_str().unwrap();
+
+        assert_eq!(test.join_room(&alice_access_token, &room_id).status, Status::Ok);
+
+        let response = sync(&test, &alice_access_token, &format!("&since={}", next_batch));
+        let room = response.find_path(&["rooms", "join", &room_id]).unwrap();
+        let state_count = room.find_path(&["state", "events"]).unwrap().as_array().unwrap().len();
+        let timeline_count = room.find_path(&["timeline", "events"]).unwrap().as_array().unwrap().len();
+
+        assert!(state_count + timeline_count > 1);
+    }
+
+    #[test]
+    fn left_rooms_are_included_with_include_leave() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&carl_token);
+
+        assert_eq!(test.join_room(&alice_token, &room_id).status, Status::Ok);
+
+        send_message(&test, &alice_token, &room_id, 0, "Goodbye");
+
+        let leave_path = format!(
+            "/_matrix/client/r0/rooms/{}/leave?access_token={}",
+            room_id,
+            alice_token
+        );
+
+        assert_eq!(test.post(&leave_path, "{}").status, Status::Ok);
+
+        send_message(&test, &carl_token, &room_id, 0, "After Alice left");
+
+        let response = sync(&test, &alice_token, "");
+
+        assert!(response.find_path(&["rooms", "leave", &room_id[..]]).is_none());
+
+        let filter = r#"{"room":{"include_leave":true}}"#;
+        let response = sync(&test, &alice_token, &format!("&filter={}", filter));
+        let left_room = response.find_path(&["rooms", "leave", &room_id[..]]).unwrap();
+        let timeline: Vec<&Value> = left_room
+            .find_path(&["timeline", "events"])
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .collect();
+        let last_event = timeline.last().unwrap();
+
+        assert!(timeline.iter().any(|event| {
+            event.find_path(&["content", "body"]).and_then(Value::as_str) == Some("Goodbye")
+        }));
+        assert!(timeline.iter().all(|event| {
+            event.find_path(&["content", "body"]).and_then(Value::as_str) !=
+                Some("After Alice left")
+        }));
+        assert_eq!(last_event.find("type").and_then(Value::as_str), Some("m.room.member"));
+        assert_eq!(
+            last_event.find_path(&["content", "membership"]).and_then(Value::as_str),
+            Some("leave")
+        );
+    }
+
+    #[test]
+    fn invalid_since_token() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?access_token={}&since=abc",
+            access_token
+        ));
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn room_ranges_only_include_the_most_recent_rooms() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let first_room_id = test.create_room(&access_token);
+        let second_room_id = test.create_room(&access_token);
+        let third_room_id = test.create_room(&access_token);
+
+        send_message(&test, &access_token, &first_room_id, 1, "Hello");
+
+        let response = sync(&test, &access_token, "&room_ranges=0-0,2-5");
+        let joined = response.find_path(&["rooms", "join"]).and_then(Value::as_object).unwrap();
+        let window = response.find("room_window").unwrap();
+
+        assert_eq!(joined.len(), 2);
+        assert!(joined.contains_key(&first_room_id));
+        assert!(joined.contains_key(&second_room_id));
+        assert!(!joined.contains_key(&third_room_id));
+        assert_eq!(window.find("count").and_then(Value::as_u64), Some(3));
+
+        let ranges = window.find("ranges").and_then(Value::as_array).unwrap();
+        let first_range = ranges[0].find("room_ids").and_then(Value::as_array).unwrap();
+
+        assert_eq!(first_range[0].as_str(), Some(&first_room_id[..]));
+    }
+
+    #[test]
+    fn rooms_moving_into_the_window_include_full_state() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let first_room_id = test.create_room(&access_token);
+        let second_room_id = test.create_room(&access_token);
+
+        let initial = sync(&test, &access_token, "&room_ranges=0-0");
+
+        assert!(initial.find_path(&["rooms", "join", &second_room_id]).is_some());
+        assert!(initial.find_path(&["rooms", "join", &first_room_id]).is_none());
+
+        send_message(&test, &access_token, &first_room_id, 1, "Hello");
+
+        let response = sync(
+            &test,
+            &access_token,
+            &format!("&room_ranges=0-0&since={}", next_batch(&initial)),
+        );
+        let room = response.find_path(&["rooms", "join", &first_room_id]).unwrap();
+        let has_create_event = ["state", "timeline"].iter().any(|section| {
+            room.find_path(&[*section, "events"])
+                .and_then(Value::as_array)
+                .unwrap()
+                .iter()
+                .any(|event| event.find("type").and_then(Value::as_str) == Some("m.room.create"))
+        });
+
+        assert!(has_create_event);
+        assert!(response.find_path(&["rooms", "join", &second_room_id]).is_none());
+    }
+
+    #[test]
+    fn invalid_room_ranges() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?access_token={}&room_ranges=5-1",
+            access_token
+        ));
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn more_rooms_than_workers() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_ids: Vec<String> = (0..super::SYNC_WORKERS * 2 + 1)
+            .map(|_| test.create_room(&access_token))
+            .collect();
+
+        for (i, room_id) in room_ids.iter().enumerate() {
+            send_message(&test, &access_token, room_id, 1, &format!("Room {}", i));
+        }
+
+        let response = sync(&test, &access_token, "");
+
+        assert_eq!(
+            response.find_path(&["rooms", "join"]).unwrap().as_object().unwrap().len(),
+            room_ids.len()
+        );
+
+        for (i, room_id) in room_ids.iter().enumerate() {
+            assert_eq!(timeline_bodies(&response, room_id), vec![format!("Room {}", i)]);
+        }
+    }
+
+    #[test]
+    fn device_list_changes_of_room_peers() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        test.create_access_token_with_username("bob");
+        let room_id = test.create_public_room(&carl_token);
+
+        assert_eq!(test.join_room(&alice_token, &room_id).status, Status::Ok);
+
+        let since = next_batch(&sync(&test, &carl_token, ""));
+
+        for username in &["alice", "bob"] {
+            let login = format!(
+                r#"{{"auth": {{"type": "m.login.password", "user": "{}", "password": "secret"}}}}"#,
+                username
+            );
+
+            assert_eq!(test.post("/_matrix/client/r0/login", &login).status, Status::Ok);
+        }
+
+        let response = sync(&test, &carl_token, &format!("&since={}", since));
+
+        assert_eq!(
+            response.find_path(&["device_lists", "changed"]).unwrap(),
+            &Value::Array(vec![Value::String("@alice:ruma.test".to_string())])
+        );
+
+        let since = next_batch(&response);
+        let leave_path = format!(
+            "/_matrix/client/r0/rooms/{}/leave?access_token={}",
+            room_id,
+            alice_token
+        );
+
+        assert_eq!(test.post(&leave_path, "{}").status, Status::Ok);
+
+        let response = sync(&test, &carl_token, &format!("&since={}", since));
+
+        assert_eq!(
+            response.find_path(&["device_lists", "left"]).unwrap(),
+            &Value::Array(vec![Value::String("@alice:ruma.test".to_string())])
+        );
+        assert!(
+            response.find_path(&["device_lists", "changed"]).unwrap().as_array().unwrap().is_empty()
+        );
+    }
+}
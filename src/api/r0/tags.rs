@@ -0,0 +1,247 @@
+//! Endpoints for tagging rooms.
+
+use bodyparser;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use ruma_identifiers::UserId;
+use serde_json::{Value, to_string};
+
+use db::DB;
+use error::ApiError;
+use middleware::{
+    AccessTokenAuth,
+    JsonRequest,
+    MiddlewareChain,
+    RoomIdParam,
+    TagParam,
+    UserIdParam,
+};
+use modifier::SerializableResponse;
+use room_tag::{MAX_TAG_LENGTH, NewRoomTag, RoomTag};
+use user::User;
+
+/// The GET `/user/:user_id/rooms/:room_id/tags` endpoint.
+pub struct GetTags;
+
+/// The PUT `/user/:user_id/rooms/:room_id/tags/:tag` endpoint.
+///
+/// The body is the tag's information, which may give an `order` between 0 and 1 for sorting
+/// rooms with the same tag.
+pub struct PutTag;
+
+/// The DELETE `/user/:user_id/rooms/:room_id/tags/:tag` endpoint.
+pub struct DeleteTag;
+
+middleware_chain!(GetTags, [UserIdParam, RoomIdParam, AccessTokenAuth]);
+
+impl Handler for GetTags {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user_id = authorized_user_id(request)?;
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let content = RoomTag::find_content(&connection, &user_id, &room_id)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(content))))
+    }
+}
+
+middleware_chain!(PutTag, [JsonRequest, UserIdParam, RoomIdParam, TagParam, AccessTokenAuth]);
+
+impl Handler for PutTag {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user_id = authorized_user_id(request)?;
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId").clone();
+        let tag = request.extensions.get::<TagParam>()
+            .expect("TagParam should ensure a tag").clone();
+
+        if tag.len() > MAX_TAG_LENGTH {
+            let error = ApiError::invalid_param("tag", "Must be at most 255 bytes.");
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let content = match request.get::<bodyparser::Json>() {
+            Ok(Some(content)) => content,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let valid_order = match content.find("order") {
+            Some(order) => order.is_number(),
+            None => true,
+        };
+
+        if !content.is_object() || !valid_order {
+            let error = ApiError::bad_json(
+                Some("The tag must be an object with an optional numeric order.")
+            );
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let connection = DB::from_request(request)?;
+
+        let new_room_tag = NewRoomTag {
+            user_id: user_id,
+            room_id: room_id,
+            tag: tag,
+            content: to_string(&content).map_err(ApiError::from)?,
+        };
+
+        RoomTag::upsert(&connection, &new_room_tag)?;
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
+middleware_chain!(DeleteTag, [UserIdParam, RoomIdParam, TagParam, AccessTokenAuth]);
+
+impl Handler for DeleteTag {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user_id = authorized_user_id(request)?;
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId").clone();
+        let tag = request.extensions.get::<TagParam>()
+            .expect("TagParam should ensure a tag").clone();
+
+        let connection = DB::from_request(request)?;
+
+        RoomTag::delete(&connection, &user_id, &room_id, &tag)?;
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
+/// Returns the user ID in the path, after checking that it's the authenticated user's.
+fn authorized_user_id(request: &Request) -> Result<UserId, ApiError> {
+    let user = request.extensions.get::<User>()
+        .expect("AccessTokenAuth should ensure a user");
+
+    let user_id = request.extensions.get::<UserIdParam>()
+        .expect("UserIdParam should ensure a UserId");
+
+    if *user_id != user.id {
+        return Err(ApiError::unauthorized(
+            Some("The given user_id does not correspond to the authenticated user")
+        ));
+    }
+
+    Ok(user_id.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    /// The path of a user's tags on a room.
+    fn tags_path(localpart: &str, room_id: &str) -> String {
+        format!("/_matrix/client/r0/user/@{}:ruma.test/rooms/{}/tags", localpart, room_id)
+    }
+
+    #[test]
+    fn put_get_and_delete_tags() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let tags_path = tags_path("carl", &room_id);
+
+        let response = test.put(
+            &format!("{}/m.favourite?access_token={}", tags_path, access_token),
+            r#"{"order": 0.5}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.put(
+            &format!("{}/u.work%20stuff?access_token={}", tags_path, access_token),
+            "{}",
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&format!("{}?access_token={}", tags_path, access_token));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().find_path(&["tags", "m.favourite", "order"]).and_then(Value::as_f64),
+            Some(0.5)
+        );
+        assert!(response.json().find_path(&["tags", "u.work stuff"]).is_some());
+
+        let response = test.delete(
+            &format!("{}/m.favourite?access_token={}", tags_path, access_token),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&format!("{}?access_token={}", tags_path, access_token));
+
+        assert!(response.json().find_path(&["tags", "m.favourite"]).is_none());
+        assert!(response.json().find_path(&["tags", "u.work stuff"]).is_some());
+    }
+
+    #[test]
+    fn tags_appear_in_sync() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let response = test.put(
+            &format!("{}/m.lowpriority?access_token={}", tags_path("carl", &room_id), access_token),
+            "{}",
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&format!("/_matrix/client/r0/sync?access_token={}", access_token));
+        let events = response
+            .json()
+            .find_path(&["rooms", "join", &room_id[..], "account_data", "events"])
+            .and_then(Value::as_array)
+            .unwrap();
+        let tag_event = events
+            .iter()
+            .find(|event| event.find("type").and_then(Value::as_str) == Some("m.tag"))
+            .unwrap();
+
+        assert!(tag_event.find_path(&["content", "tags", "m.lowpriority"]).is_some());
+    }
+
+    #[test]
+    fn tags_must_be_objects() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let response = test.put(
+            &format!("{}/m.favourite?access_token={}", tags_path("carl", &room_id), access_token),
+            r#"{"order": "first"}"#,
+        );
+
+        assert_eq!(response.status, Status::UnprocessableEntity);
+    }
+
+    #[test]
+    fn cannot_tag_rooms_for_other_users() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let response = test.put(
+            &format!("{}/m.favourite?access_token={}", tags_path("alice", &room_id), access_token),
+            "{}",
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
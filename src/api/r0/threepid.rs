@@ -0,0 +1,604 @@
+//! Endpoints for verifying third-party identifiers and adding them to accounts.
+
+use bodyparser;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+
+use config::Config;
+use crypto::ServerEntropy;
+use db::DB;
+use error::ApiError;
+use identity_server::ServerIdentityServers;
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, NonGuestAuth};
+use modifier::SerializableResponse;
+use sms::{ServerSms, to_msisdn};
+use threepid_validation::{NewThreepidValidation, ThreepidValidation};
+use user::User;
+use user_threepid::{NewUserThreepid, UserThreepid};
+
+/// The POST `/account/3pid/msisdn/requestToken` endpoint.
+///
+/// Texts a verification code to a phone number. Asking again with the same `client_secret` and a
+/// higher `send_attempt` sends the same code again; otherwise, repeated requests are ignored.
+pub struct RequestMsisdnToken;
+
+#[derive(Clone, Debug, Deserialize)]
+struct RequestMsisdnTokenRequest {
+    client_secret: String,
+    country: String,
+    phone_number: String,
+    send_attempt: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct RequestMsisdnTokenResponse {
+    msisdn: String,
+    sid: String,
+    submit_url: String,
+}
+
+middleware_chain!(RequestMsisdnToken, [JsonRequest]);
+
+impl Handler for RequestMsisdnToken {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let token_request = match request.get::<bodyparser::Struct<RequestMsisdnTokenRequest>>() {
+            Ok(Some(token_request)) => token_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        ensure_valid_client_secret(&token_request.client_secret)?;
+
+        let msisdn = to_msisdn(&token_request.country, &token_request.phone_number)?;
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+        let entropy = ServerEntropy::from_request(request)?;
+        let sms = ServerSms::from_request(request)?;
+
+        if UserThreepid::find_user_id(&connection, "msisdn", &msisdn)?.is_some() {
+            let error = ApiError::invalid_param(
+                "phone_number",
+                "The phone number is already in use.",
+            );
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let existing_validation = ThreepidValidation::find_by_address(
+            &connection,
+            &token_request.client_secret,
+            "msisdn",
+            &msisdn,
+        )?;
+
+        let validation = match existing_validation {
+            Some(mut validation) => {
+                if token_request.send_attempt > validation.send_attempt {
+                    sms.send(&msisdn, &verification_message(&config.domain, &validation.token))?;
+                    validation.set_send_attempt(&connection, token_request.send_attempt)?;
+                }
+
+                validation
+            }
+            None => {
+                let token = entropy.digits(6);
+
+                sms.send(&msisdn, &verification_message(&config.domain, &token))?;
+
+                ThreepidValidation::create(&connection, &NewThreepidValidation {
+                    id: entropy.alphanumeric(24),
+                    client_secret: token_request.client_secret,
+                    medium: "msisdn".to_string(),
+                    address: msisdn.clone(),
+                    token: token,
+                    send_attempt: token_request.send_attempt,
+                })?
+            }
+        };
+
+        let response = RequestMsisdnTokenResponse {
+            msisdn: msisdn,
+            sid: validation.id,
+            submit_url: format!(
+                "https://{}/_matrix/client/r0/account/3pid/msisdn/submitToken",
+                config.domain
+            ),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The POST `/account/3pid/msisdn/submitToken` endpoint.
+///
+/// Proves ownership of a phone number with the code texted to it.
+pub struct SubmitMsisdnToken;
+
+#[derive(Clone, Debug, Deserialize)]
+struct SubmitMsisdnTokenRequest {
+    client_secret: String,
+    sid: String,
+    token: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SubmitMsisdnTokenResponse {
+    success: bool,
+}
+
+middleware_chain!(SubmitMsisdnToken, [JsonRequest]);
+
+impl Handler for SubmitMsisdnToken {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let submit_request = match request.get::<bodyparser::Struct<SubmitMsisdnTokenRequest>>() {
+            Ok(Some(submit_request)) => submit_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let connection = DB::from_request(request)?;
+
+        let mut validation = ThreepidValidation::find(
+            &connection,
+            &submit_request.sid,
+            &submit_request.client_secret,
+        )?;
+
+        validation.validate(&connection, &submit_request.token)?;
+
+        let response = SubmitMsisdnTokenResponse {
+            success: true,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/account/3pid` endpoint.
+///
+/// Lists the third-party identifiers bound to the user's account.
+pub struct GetThreepids;
+
+#[derive(Debug, Serialize)]
+struct GetThreepidsResponse {
+    threepids: Vec<Threepid>,
+}
+
+#[derive(Debug, Serialize)]
+struct Threepid {
+    added_at: i64,
+    address: String,
+    medium: String,
+    validated_at: i64,
+}
+
+middleware_chain!(GetThreepids, [AccessTokenAuth]);
+
+impl Handler for GetThreepids {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let threepids = UserThreepid::find_by_user(&connection, &user.id)?
+            .into_iter()
+            .map(|user_threepid| Threepid {
+                added_at: user_threepid.created_at_millis(),
+                validated_at: user_threepid.created_at_millis(),
+                address: user_threepid.address,
+                medium: user_threepid.medium,
+            })
+            .collect();
+
+        let response = GetThreepidsResponse {
+            threepids: threepids,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The POST `/account/3pid` endpoint.
+///
+/// Adds a validated third-party identifier to the user's account, which lets them log in and be
+/// invited with it. The session is looked up on this server first, and then, if `id_server` is
+/// given, on that identity server. Ruma doesn't publish bindings to identity servers, so `bind`
+/// is ignored.
+pub struct AddThreepid;
+
+#[derive(Clone, Debug, Deserialize)]
+struct AddThreepidRequest {
+    three_pid_creds: ThreepidCredentials,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ThreepidCredentials {
+    client_secret: String,
+    id_server: Option<String>,
+    sid: String,
+}
+
+middleware_chain!(AddThreepid, [JsonRequest, AccessTokenAuth, NonGuestAuth]);
+
+impl Handler for AddThreepid {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        ensure_threepid_changes_allowed(request)?;
+
+        let add_request = match request.get::<bodyparser::Struct<AddThreepidRequest>>() {
+            Ok(Some(add_request)) => add_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let identity_servers = ServerIdentityServers::from_request(request)?;
+
+        let credentials = add_request.three_pid_creds;
+
+        let validation = ThreepidValidation::find(
+            &connection,
+            &credentials.sid,
+            &credentials.client_secret,
+        );
+
+        match (validation, credentials.id_server) {
+            (Ok(validation), _) => {
+                validation.bind(&connection, user.id)?;
+            }
+            (Err(_), Some(id_server)) => {
+                let validated = identity_servers.validated_threepid(
+                    &id_server,
+                    &credentials.sid,
+                    &credentials.client_secret,
+                )?;
+
+                let validated = match validated {
+                    Some(validated) => validated,
+                    None => {
+                        let error = ApiError::threepid_auth_failed(Some(
+                            "The identity server has not validated that session."
+                        ));
+
+                        return Err(IronError::new(error.clone(), error));
+                    }
+                };
+
+                UserThreepid::create(&connection, NewUserThreepid {
+                    user_id: user.id,
+                    medium: validated.medium,
+                    address: validated.address,
+                })?;
+            }
+            (Err(error), None) => return Err(IronError::new(error.clone(), error)),
+        }
+
+        Ok(Response::with(Status::Ok))
+    }
+}
+
+/// The POST `/account/3pid/delete` endpoint.
+///
+/// Removes a third-party identifier from the user's account, so it can no longer be used to log
+/// in or be invited.
+pub struct DeleteThreepid;
+
+#[derive(Clone, Debug, Deserialize)]
+struct DeleteThreepidRequest {
+    address: String,
+    medium: String,
+}
+
+middleware_chain!(DeleteThreepid, [JsonRequest, AccessTokenAuth, NonGuestAuth]);
+
+impl Handler for DeleteThreepid {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        ensure_threepid_changes_allowed(request)?;
+
+        let delete_request = match request.get::<bodyparser::Struct<DeleteThreepidRequest>>() {
+            Ok(Some(delete_request)) => delete_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let deleted = UserThreepid::delete(
+            &connection,
+            &user.id,
+            &delete_request.medium,
+            &delete_request.address,
+        )?;
+
+        if !deleted {
+            let error = ApiError::not_found(
+                Some("That third-party identifier is not bound to your account.")
+            );
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        Ok(Response::with(Status::Ok))
+    }
+}
+
+/// Refuses changes to users' third-party identifiers if the configuration disables them.
+fn ensure_threepid_changes_allowed(request: &mut Request) -> Result<(), ApiError> {
+    let config = Config::from_request(request)?;
+
+    if config.allow_threepid_changes {
+        Ok(())
+    } else {
+        Err(ApiError::unauthorized(
+            Some("Changing email addresses and phone numbers is disabled on this server.")
+        ))
+    }
+}
+
+/// Client secrets may only contain the characters allowed by the spec.
+fn ensure_valid_client_secret(client_secret: &str) -> Result<(), ApiError> {
+    let valid = !client_secret.is_empty() && client_secret.len() <= 255 &&
+        client_secret.chars().all(|c| c.is_digit(36) || ".=_-".contains(c));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(ApiError::invalid_param("client_secret", "The client secret has invalid characters."))
+    }
+}
+
+/// The text of the message carrying a verification code.
+fn verification_message(domain: &str, token: &str) -> String {
+    format!("{} is your verification code for {}.", token, domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::Test;
+
+    /// Requests a code for Carl's phone number and returns the session ID.
+    fn request_token(test: &Test, send_attempt: u64) -> String {
+        let response = test.post(
+            "/_matrix/client/r0/account/3pid/msisdn/requestToken",
+            &format!(
+                r#"{{
+                    "client_secret": "secret",
+                    "country": "GB",
+                    "phone_number": "07700 900123",
+                    "send_attempt": {}
+                }}"#,
+                send_attempt
+            ),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().find("msisdn").unwrap().as_str().unwrap(), "447700900123");
+
+        response.json().find("sid").unwrap().as_str().unwrap().to_string()
+    }
+
+    /// The code in the most recent text message.
+    fn last_token(test: &Test) -> String {
+        let (_, body) = test.sms_messages().pop().expect("No SMS was sent");
+
+        body.split(' ').next().unwrap().to_string()
+    }
+
+    #[test]
+    fn add_phone_number_and_log_in_with_it() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let sid = request_token(&test, 1);
+
+        assert_eq!(test.sms_messages()[0].0, "447700900123");
+
+        let response = test.post(
+            "/_matrix/client/r0/account/3pid/msisdn/submitToken",
+            &format!(
+                r#"{{"sid": "{}", "client_secret": "secret", "token": "{}"}}"#,
+                sid,
+                last_token(&test)
+            ),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/account/3pid?access_token={}", access_token),
+            &format!(r#"{{"three_pid_creds": {{"sid": "{}", "client_secret": "secret"}}}}"#, sid),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"auth": {
+                "type": "m.login.password",
+                "identifier": {
+                    "type": "m.id.phone",
+                    "country": "GB",
+                    "phone": "+44 7700 900123"
+                },
+                "password": "secret"
+            }}"#,
+        );
+
+        assert_eq!(response.json().find("user_id").unwrap().as_str().unwrap(), "@carl:ruma.test");
+    }
+
+    #[test]
+    fn wrong_token_is_rejected() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let sid = request_token(&test, 1);
+        let token = if last_token(&test) == "000000" { "111111" } else { "000000" };
+
+        let response = test.post(
+            "/_matrix/client/r0/account/3pid/msisdn/submitToken",
+            &format!(
+                r#"{{"sid": "{}", "client_secret": "secret", "token": "{}"}}"#,
+                sid,
+                token
+            ),
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().find("errcode").unwrap().as_str().unwrap(),
+            "M_THREEPID_AUTH_FAILED"
+        );
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/account/3pid?access_token={}", access_token),
+            &format!(r#"{{"three_pid_creds": {{"sid": "{}", "client_secret": "secret"}}}}"#, sid),
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn token_is_only_resent_for_new_send_attempts() {
+        let test = Test::new();
+
+        let sid = request_token(&test, 1);
+
+        assert_eq!(request_token(&test, 1), sid);
+        assert_eq!(test.sms_messages().len(), 1);
+
+        assert_eq!(request_token(&test, 2), sid);
+        assert_eq!(test.sms_messages().len(), 2);
+        assert_eq!(test.sms_messages()[0], test.sms_messages()[1]);
+    }
+
+    const IDENTITY_SERVER_CREDS: &'static str = r#"{"three_pid_creds": {
+        "sid": "abc",
+        "client_secret": "secret",
+        "id_server": "id.example.com"
+    }}"#;
+
+    /// Makes id.example.com report that it validated the email address in the session in
+    /// `IDENTITY_SERVER_CREDS`.
+    fn validate_email(test: &Test, address: &str) {
+        test.validate_email_on_identity_server("id.example.com", "abc", "secret", address);
+    }
+
+    #[test]
+    fn add_email_validated_by_identity_server() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let path = format!("/_matrix/client/r0/account/3pid?access_token={}", access_token);
+
+        validate_email(&test, "Carl@Example.com");
+
+        assert_eq!(test.post(&path, IDENTITY_SERVER_CREDS).status, Status::Ok);
+
+        let response = test.get(&path);
+        let threepids = response.json().find("threepids").unwrap().as_array().unwrap();
+
+        assert_eq!(threepids.len(), 1);
+        assert_eq!(threepids[0].find("medium").unwrap().as_str().unwrap(), "email");
+        assert_eq!(threepids[0].find("address").unwrap().as_str().unwrap(), "carl@example.com");
+        assert!(threepids[0].find("added_at").unwrap().is_i64());
+    }
+
+    #[test]
+    fn unvalidated_identity_server_session_is_rejected() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let path = format!("/_matrix/client/r0/account/3pid?access_token={}", access_token);
+
+        let response = test.post(&path, IDENTITY_SERVER_CREDS);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().find("errcode").unwrap().as_str().unwrap(),
+            "M_THREEPID_AUTH_FAILED"
+        );
+    }
+
+    #[test]
+    fn invite_by_email() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_room(&alice_access_token);
+
+        validate_email(&test, "carl@example.com");
+
+        let path = format!("/_matrix/client/r0/account/3pid?access_token={}", access_token);
+
+        assert_eq!(test.post(&path, IDENTITY_SERVER_CREDS).status, Status::Ok);
+
+        let invite_path = format!(
+            "/_matrix/client/r0/rooms/{}/invite?access_token={}",
+            room_id,
+            alice_access_token
+        );
+        let invite = r#"{"medium": "email", "address": "Carl@Example.com"}"#;
+
+        assert_eq!(test.post(&invite_path, invite).status, Status::Ok);
+
+        let sync_path = format!("/_matrix/client/r0/sync?access_token={}", access_token);
+        let sync = test.get(&sync_path).json().clone();
+
+        assert!(sync.find_path(&["rooms", "invite", &room_id]).is_some());
+
+        let unknown = r#"{"medium": "email", "address": "bob@example.com"}"#;
+
+        assert_eq!(test.post(&invite_path, unknown).status, Status::NotFound);
+    }
+
+    #[test]
+    fn delete_threepid() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let path = format!("/_matrix/client/r0/account/3pid?access_token={}", access_token);
+        let delete_path =
+            format!("/_matrix/client/r0/account/3pid/delete?access_token={}", access_token);
+        let delete = r#"{"medium": "email", "address": "carl@example.com"}"#;
+
+        validate_email(&test, "carl@example.com");
+
+        assert_eq!(test.post(&path, IDENTITY_SERVER_CREDS).status, Status::Ok);
+        assert_eq!(test.post(&delete_path, delete).status, Status::Ok);
+
+        let response = test.get(&path);
+
+        assert!(response.json().find("threepids").unwrap().as_array().unwrap().is_empty());
+        assert_eq!(test.post(&delete_path, delete).status, Status::NotFound);
+
+        let login = r#"{"auth": {
+            "type": "m.login.password",
+            "identifier": {
+                "type": "m.id.thirdparty",
+                "medium": "email",
+                "address": "carl@example.com"
+            },
+            "password": "secret"
+        }}"#;
+
+        assert_eq!(test.post("/_matrix/client/r0/login", login).status, Status::Forbidden);
+    }
+}
@@ -0,0 +1,209 @@
+//! Endpoints for sending messages directly to devices.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use bodyparser;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use ruma_identifiers::UserId;
+use serde_json::{Value, to_string};
+
+use access_token::AccessToken;
+use db::DB;
+use device::Device;
+use error::ApiError;
+use middleware::{
+    AccessTokenAuth,
+    EventTypeParam,
+    JsonRequest,
+    MiddlewareChain,
+    TransactionIdParam,
+};
+use to_device::{NewToDeviceMessage, ToDeviceMessage};
+use user::User;
+
+/// The PUT `/sendToDevice/:event_type/:transaction_id` endpoint.
+///
+/// Messages are delivered through the `to_device` section of `/sync`. A device ID of `*` sends
+/// the message to all of the user's devices. Ruma doesn't federate, so only users on this
+/// homeserver have devices to deliver to; messages for anyone else, or for unknown devices, are
+/// dropped.
+pub struct SendToDevice;
+
+#[derive(Clone, Debug, Deserialize)]
+struct SendToDeviceRequest {
+    messages: BTreeMap<String, BTreeMap<String, Value>>,
+}
+
+middleware_chain!(
+    SendToDevice,
+    [JsonRequest, EventTypeParam, TransactionIdParam, AccessTokenAuth]
+);
+
+impl Handler for SendToDevice {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let send_request = match request.get::<bodyparser::Struct<SendToDeviceRequest>>() {
+            Ok(Some(send_request)) => send_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let event_type = request.extensions.get::<EventTypeParam>()
+            .expect("EventTypeParam should ensure an EventType").to_string();
+
+        let transaction_id = request.extensions.get::<TransactionIdParam>()
+            .expect("TransactionIdParam should ensure a TransactionId").clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let access_token_id = request.extensions.get::<AccessToken>()
+            .expect("AccessTokenAuth should ensure an access token").id;
+
+        let connection = DB::from_request(request)?;
+
+        let mut new_messages = Vec::new();
+
+        for (user_id, device_messages) in send_request.messages {
+            let user_id = match UserId::try_from(&user_id[..]) {
+                Ok(user_id) => user_id,
+                Err(_) => {
+                    let error = ApiError::bad_json(Some("messages must be keyed by user ID."));
+
+                    return Err(IronError::new(error.clone(), error));
+                }
+            };
+
+            let devices = Device::find_by_user(&connection, &user_id)?;
+
+            for (device_id, content) in device_messages {
+                if !content.is_object() {
+                    let error = ApiError::bad_json(Some("Message contents must be objects."));
+
+                    return Err(IronError::new(error.clone(), error));
+                }
+
+                let content = to_string(&content).map_err(ApiError::from)?;
+
+                for device in devices.iter() {
+                    if device_id != "*" && device.device_id != device_id {
+                        continue;
+                    }
+
+                    new_messages.push(NewToDeviceMessage {
+                        user_id: user_id.clone(),
+                        device_id: device.device_id.clone(),
+                        sender: user.id.clone(),
+                        event_type: event_type.clone(),
+                        content: content.clone(),
+                    });
+                }
+            }
+        }
+
+        ToDeviceMessage::send(&connection, access_token_id, &transaction_id, &new_messages)?;
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    /// Logs alice in from the device with the given ID and returns the new access token.
+    fn login_alice(test: &Test, device_id: &str) -> String {
+        let body = format!(
+            r#"{{
+                "auth": {{"type": "m.login.password", "user": "alice", "password": "secret"}},
+                "device_id": "{}"
+            }}"#,
+            device_id
+        );
+
+        test.post("/_matrix/client/r0/login", &body)
+            .json()
+            .find("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn to_device_events(test: &Test, access_token: &str, since: Option<&str>)
+    -> (Vec<Value>, String) {
+        let since = since.map(|since| format!("&since={}", since)).unwrap_or(String::new());
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?access_token={}{}",
+            access_token,
+            since
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+        let events = json.find_path(&["to_device", "events"]).unwrap().as_array().unwrap().clone();
+
+        (events, json.find("next_batch").unwrap().as_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn messages_are_delivered_until_acknowledged() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        test.create_access_token_with_username("alice");
+        let phone_token = login_alice(&test, "PHONE");
+        let laptop_token = login_alice(&test, "LAPTOP");
+        let path = format!(
+            "/_matrix/client/r0/sendToDevice/m.room_key_request/1?access_token={}",
+            carl_token
+        );
+        let body = r#"{"messages": {"@alice:ruma.test": {"PHONE": {"action": "request"}}}}"#;
+
+        assert_eq!(test.put(&path, body).status, Status::Ok);
+        assert_eq!(test.put(&path, body).status, Status::Ok);
+
+        let (events, next_batch) = to_device_events(&test, &phone_token, None);
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].find("sender").unwrap().as_str().unwrap(), "@carl:ruma.test");
+        assert_eq!(events[0].find("type").unwrap().as_str().unwrap(), "m.room_key_request");
+        assert_eq!(
+            events[0].find_path(&["content", "action"]).unwrap().as_str().unwrap(),
+            "request"
+        );
+
+        let (events, _) = to_device_events(&test, &laptop_token, None);
+
+        assert!(events.is_empty());
+
+        let (events, _) = to_device_events(&test, &phone_token, Some(&next_batch));
+
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn wildcard_sends_to_all_devices() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        test.create_access_token_with_username("alice");
+        let phone_token = login_alice(&test, "PHONE");
+        let laptop_token = login_alice(&test, "LAPTOP");
+        let path = format!(
+            "/_matrix/client/r0/sendToDevice/m.new_device/1?access_token={}",
+            carl_token
+        );
+        let body = r#"{"messages": {"@alice:ruma.test": {"*": {}}}}"#;
+
+        assert_eq!(test.put(&path, body).status, Status::Ok);
+        assert_eq!(to_device_events(&test, &phone_token, None).0.len(), 1);
+        assert_eq!(to_device_events(&test, &laptop_token, None).0.len(), 1);
+    }
+}
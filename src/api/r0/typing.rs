@@ -0,0 +1,211 @@
+//! Endpoints for typing notifications.
+
+use std::cmp::min;
+
+use bodyparser;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+
+use clock::ServerClock;
+use db::DB;
+use error::ApiError;
+use membership_cache::ServerMembershipCache;
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, RoomIdParam, UserIdParam};
+use typing::ServerTyping;
+use user::User;
+
+/// How long a user is shown as typing if the client doesn't give a timeout, in milliseconds.
+const DEFAULT_TIMEOUT: u64 = 30_000;
+
+/// The longest a user can be shown as typing without another request, in milliseconds.
+const MAX_TIMEOUT: u64 = 120_000;
+
+/// The PUT `/rooms/:room_id/typing/:user_id` endpoint.
+pub struct PutTyping;
+
+#[derive(Clone, Debug, Deserialize)]
+struct PutTypingRequest {
+    timeout: Option<u64>,
+    typing: bool,
+}
+
+middleware_chain!(PutTyping, [JsonRequest, RoomIdParam, UserIdParam, AccessTokenAuth]);
+
+impl Handler for PutTyping {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let typing_request = match request.get::<bodyparser::Struct<PutTypingRequest>>() {
+            Ok(Some(typing_request)) => typing_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let user_id = request.extensions.get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId").clone();
+
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId").clone();
+
+        if user_id != user.id {
+            let error = ApiError::unauthorized(Some("You can only set your own typing status."));
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let clock = ServerClock::from_request(request)?;
+        let connection = DB::from_request(request)?;
+        let membership_cache = ServerMembershipCache::from_request(request)?;
+        let typing = ServerTyping::from_request(request)?;
+
+        if !membership_cache.is_joined(&connection, &room_id, &user_id)? {
+            let error = ApiError::unauthorized(Some("You must join the room to type in it."));
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        if typing_request.typing {
+            let timeout = min(typing_request.timeout.unwrap_or(DEFAULT_TIMEOUT), MAX_TIMEOUT);
+
+            typing.start(&connection, &room_id, &user_id, clock.now_millis() + timeout as i64)?;
+        } else {
+            typing.stop(&connection, &room_id, &user_id)?;
+        }
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::{Response, Test};
+
+    fn put_typing(test: &Test, access_token: &str, room_id: &str, user_id: &str, body: &str)
+    -> Response {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/typing/{}?access_token={}",
+            room_id,
+            user_id,
+            access_token
+        );
+
+        test.put(&path, body)
+    }
+
+    /// Syncs and returns the users in the room's `m.typing` event, if there is one.
+    fn sync_typing(test: &Test, access_token: &str, room_id: &str, since: Option<&str>)
+    -> (Option<Vec<String>>, String) {
+        let since = since.map(|since| format!("&since={}", since)).unwrap_or(String::new());
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?access_token={}{}",
+            access_token,
+            since
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+        let user_ids = json
+            .find_path(&["rooms", "join", room_id, "ephemeral", "events"])
+            .and_then(Value::as_array)
+            .and_then(|events| {
+                events.iter().find(|event| event.find("type").unwrap().as_str() == Some("m.typing"))
+            })
+            .map(|event| {
+                event.find_path(&["content", "user_ids"]).unwrap().as_array().unwrap()
+                    .iter()
+                    .map(|user_id| user_id.as_str().unwrap().to_string())
+                    .collect()
+            });
+
+        (user_ids, json.find("next_batch").unwrap().as_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn typing_shows_up_in_sync() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let (_, next_batch) = sync_typing(&test, &access_token, &room_id, None);
+
+        let response = put_typing(
+            &test,
+            &access_token,
+            &room_id,
+            "@carl:ruma.test",
+            r#"{"typing": true, "timeout": 10000}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let (user_ids, next_batch) = sync_typing(&test, &access_token, &room_id, Some(&next_batch));
+
+        assert_eq!(user_ids, Some(vec!["@carl:ruma.test".to_string()]));
+
+        let response = put_typing(
+            &test,
+            &access_token,
+            &room_id,
+            "@carl:ruma.test",
+            r#"{"typing": false}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let (user_ids, _) = sync_typing(&test, &access_token, &room_id, Some(&next_batch));
+
+        assert_eq!(user_ids, Some(vec![]));
+    }
+
+    #[test]
+    fn typing_expires() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        put_typing(
+            &test,
+            &access_token,
+            &room_id,
+            "@carl:ruma.test",
+            r#"{"typing": true, "timeout": 5000}"#,
+        );
+
+        let (user_ids, next_batch) = sync_typing(&test, &access_token, &room_id, None);
+
+        assert_eq!(user_ids, Some(vec!["@carl:ruma.test".to_string()]));
+
+        test.advance_clock(Duration::seconds(6));
+        test.run_jobs();
+
+        let (user_ids, _) = sync_typing(&test, &access_token, &room_id, Some(&next_batch));
+
+        assert_eq!(user_ids, Some(vec![]));
+    }
+
+    #[test]
+    fn cannot_type_for_others_or_outside_rooms() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_room(&carl_token);
+        let body = r#"{"typing": true}"#;
+
+        let response = put_typing(&test, &carl_token, &room_id, "@alice:ruma.test", body);
+
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = put_typing(&test, &alice_token, &room_id, "@alice:ruma.test", body);
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
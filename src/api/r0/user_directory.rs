@@ -0,0 +1,169 @@
+//! Endpoints for the user directory.
+
+use bodyparser;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
+use modifier::SerializableResponse;
+use user::User;
+use user_directory::UserDirectoryEntry;
+
+/// The number of results returned if the client doesn't give a limit.
+const DEFAULT_LIMIT: u64 = 10;
+
+/// The POST `/user_directory/search` endpoint.
+///
+/// Searches the IDs and display names of the users who share a room with the searcher or who
+/// are in public rooms.
+pub struct SearchUserDirectory;
+
+#[derive(Clone, Debug, Deserialize)]
+struct SearchUserDirectoryRequest {
+    limit: Option<u64>,
+    search_term: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchUserDirectoryResponse {
+    limited: bool,
+    results: Vec<UserDirectoryEntry>,
+}
+
+middleware_chain!(SearchUserDirectory, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for SearchUserDirectory {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let search_request = match request.get::<bodyparser::Struct<SearchUserDirectoryRequest>>() {
+            Ok(Some(search_request)) => search_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let (results, limited) = UserDirectoryEntry::search(
+            &connection,
+            &user.id,
+            &search_request.search_term,
+            search_request.limit.unwrap_or(DEFAULT_LIMIT) as usize,
+        )?;
+
+        let response = SearchUserDirectoryResponse {
+            limited: limited,
+            results: results,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    /// The user IDs found by searching for the term.
+    fn search(test: &Test, access_token: &str, body: &str) -> Vec<String> {
+        let response = test.post(
+            &format!("/_matrix/client/r0/user_directory/search?access_token={}", access_token),
+            body,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        response
+            .json()
+            .find("results")
+            .and_then(Value::as_array)
+            .unwrap()
+            .iter()
+            .map(|result| result.find("user_id").and_then(Value::as_str).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn search_by_user_id_and_display_name() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&carl_token);
+
+        assert_eq!(test.join_room(&alice_token, &room_id).status, Status::Ok);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/profile/@alice:ruma.test/displayname?access_token={}",
+                alice_token
+            ),
+            r#"{"displayname": "Wonderland"}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let results = search(&test, &carl_token, r#"{"search_term": "ALI"}"#);
+
+        assert_eq!(results, vec!["@alice:ruma.test"]);
+
+        let results = search(&test, &carl_token, r#"{"search_term": "wonder"}"#);
+
+        assert_eq!(results, vec!["@alice:ruma.test"]);
+    }
+
+    #[test]
+    fn users_in_private_rooms_are_hidden() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let alice_token = test.create_access_token_with_username("alice");
+        let bob_token = test.create_access_token_with_username("bob");
+        let room_id = test.create_room(&alice_token);
+
+        assert!(search(&test, &carl_token, r#"{"search_term": "alice"}"#).is_empty());
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/rooms/{}/invite?access_token={}", room_id, alice_token),
+            r#"{"user_id": "@bob:ruma.test"}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(test.join_room(&bob_token, &room_id).status, Status::Ok);
+
+        let results = search(&test, &bob_token, r#"{"search_term": "alice"}"#);
+
+        assert_eq!(results, vec!["@alice:ruma.test"]);
+    }
+
+    #[test]
+    fn results_are_limited() {
+        let test = Test::new();
+        let carl_token = test.create_access_token();
+        let room_id = test.create_public_room(&carl_token);
+
+        for username in &["alice1", "alice2", "alice3"] {
+            let access_token = test.create_access_token_with_username(username);
+
+            assert_eq!(test.join_room(&access_token, &room_id).status, Status::Ok);
+        }
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/user_directory/search?access_token={}", carl_token),
+            r#"{"search_term": "alice", "limit": 2}"#,
+        );
+
+        assert_eq!(response.json().find("limited").and_then(Value::as_bool), Some(true));
+        assert_eq!(
+            response.json().find("results").and_then(Value::as_array).map(Vec::len),
+            Some(2)
+        );
+    }
+}
@@ -1,25 +1,114 @@
 //! Endpoints for information about supported versions of the Matrix spec.
 
+use std::collections::BTreeMap;
+
 use iron::{Handler, IronResult, Request, Response, status};
 
 use modifier::SerializableResponse;
 
 /// The /versions endpoint.
+///
+/// Lists the versions of the client-server spec the server supports, and the unstable features
+/// it has enabled, so clients can tell what they can use.
 pub struct Versions {
+    response: VersionsResponse,
+}
+
+/// The federation /version endpoint.
+///
+/// Tells other servers which homeserver implementation this is.
+pub struct ServerVersion;
+
+#[derive(Debug, Serialize)]
+struct VersionsResponse {
+    unstable_features: BTreeMap<&'static str, bool>,
     versions: Vec<&'static str>,
 }
 
+#[derive(Debug, Serialize)]
+struct ServerVersionResponse {
+    server: ServerInformation,
+}
+
+#[derive(Debug, Serialize)]
+struct ServerInformation {
+    name: &'static str,
+    version: &'static str,
+}
+
 impl Versions {
-    /// Create a `Versions` offering support for the specified versions of the Matrix spec.
-    pub fn new(versions: Vec<&'static str>) -> Versions {
+    /// Create a `Versions` offering support for the specified versions of the Matrix spec and
+    /// the specified unstable features.
+    pub fn new(versions: Vec<&'static str>, unstable_features: Vec<&'static str>) -> Versions {
         Versions {
-            versions: versions,
+            response: VersionsResponse {
+                unstable_features: unstable_features
+                    .into_iter()
+                    .map(|feature| (feature, true))
+                    .collect(),
+                versions: versions,
+            },
         }
     }
 }
 
 impl Handler for Versions {
     fn handle(&self, _request: &mut Request) -> IronResult<Response> {
-        Ok(Response::with((status::Ok, SerializableResponse(&self.versions))))
+        Ok(Response::with((status::Ok, SerializableResponse(&self.response))))
+    }
+}
+
+impl Handler for ServerVersion {
+    fn handle(&self, _request: &mut Request) -> IronResult<Response> {
+        let response = ServerVersionResponse {
+            server: ServerInformation {
+                name: "Ruma",
+                version: env!("CARGO_PKG_VERSION"),
+            },
+        };
+
+        Ok(Response::with((status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    #[test]
+    fn versions() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/versions");
+
+        assert_eq!(response.status, Status::Ok);
+
+        let versions = response.json().find("versions").and_then(Value::as_array).unwrap();
+
+        assert!(versions.iter().any(|version| version.as_str() == Some("r0.0.1")));
+        assert_eq!(
+            response
+                .json()
+                .find_path(&["unstable_features", "org.matrix.msc4140"])
+                .and_then(Value::as_bool),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn server_version() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/federation/v1/version");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().find_path(&["server", "name"]).and_then(Value::as_str),
+            Some("Ruma")
+        );
+        assert!(response.json().find_path(&["server", "version"]).is_some());
     }
 }
@@ -0,0 +1,68 @@
+//! Endpoints for discovering the servers clients should use.
+
+use iron::{Handler, IronError, IronResult, Request, Response};
+use iron::status::Status;
+
+use config::Config;
+use discovery::ClientDiscovery;
+use error::ApiError;
+use modifier::SerializableResponse;
+
+/// The GET `/.well-known/matrix/client` endpoint.
+///
+/// Advertises the URLs set with `client_base_url` and `identity_server_url`, or responds with
+/// 404 if `client_base_url` is not set.
+pub struct GetWellKnownClient {
+    discovery: Option<ClientDiscovery>,
+}
+
+impl GetWellKnownClient {
+    /// Create a `GetWellKnownClient` advertising the URLs in the configuration.
+    pub fn new(config: &Config) -> GetWellKnownClient {
+        GetWellKnownClient {
+            discovery: ClientDiscovery::from_config(config),
+        }
+    }
+}
+
+impl Handler for GetWellKnownClient {
+    fn handle(&self, _request: &mut Request) -> IronResult<Response> {
+        match self.discovery {
+            Some(ref discovery) => {
+                Ok(Response::with((Status::Ok, SerializableResponse(discovery))))
+            }
+            None => {
+                let error = ApiError::not_found(
+                    Some("This server does not advertise a client base URL.")
+                );
+
+                Err(IronError::new(error.clone(), error))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    #[test]
+    fn well_known_client() {
+        let test = Test::new();
+
+        let response = test.get("/.well-known/matrix/client");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().find_path(&["m.homeserver", "base_url"]).and_then(Value::as_str),
+            Some("https://matrix.ruma.test")
+        );
+        assert_eq!(
+            response.json().find_path(&["m.identity_server", "base_url"]).and_then(Value::as_str),
+            Some("https://identity.ruma.test")
+        );
+    }
+}
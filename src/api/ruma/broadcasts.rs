@@ -0,0 +1,165 @@
+//! Administrative endpoints for messaging every user.
+
+use bodyparser;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use serde_json::{Value, to_string};
+
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, AdminAuth, JsonRequest, MiddlewareChain};
+use modifier::SerializableResponse;
+use server_notice::{Broadcast, NewBroadcast};
+use user::User;
+
+/// The POST `/admin/broadcasts` endpoint.
+///
+/// Queues an *m.room.message* for every active local user, sent to their server notices room.
+/// Delivery happens in batches in the background, so the message may take a while to reach
+/// everyone on a large server.
+pub struct PostBroadcast;
+
+#[derive(Clone, Debug, Deserialize)]
+struct PostBroadcastRequest {
+    content: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct PostBroadcastResponse {
+    broadcast_id: i64,
+}
+
+middleware_chain!(PostBroadcast, [JsonRequest, AccessTokenAuth, AdminAuth]);
+
+impl Handler for PostBroadcast {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let broadcast_request = match request.get::<bodyparser::Struct<PostBroadcastRequest>>() {
+            Ok(Some(broadcast_request)) => broadcast_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let is_message = ["body", "msgtype"].iter().all(|key| {
+            broadcast_request.content.find(key).map(Value::is_string).unwrap_or(false)
+        });
+
+        if !is_message {
+            let error = ApiError::invalid_param(
+                "content",
+                "Must be m.room.message content with a body and msgtype.",
+            );
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let admin = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let broadcast = Broadcast::create(&connection, &NewBroadcast {
+            sender: admin.id,
+            content: to_string(&broadcast_request.content).map_err(ApiError::from)?,
+        })?;
+
+        let response = PostBroadcastResponse {
+            broadcast_id: broadcast.id,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::Test;
+
+    const MAINTENANCE: &'static str =
+        r#"{"content": {"msgtype": "m.text", "body": "Down for maintenance at 10:00 UTC"}}"#;
+
+    #[test]
+    fn broadcast_reaches_every_user() {
+        let test = Test::new();
+        let admin_access_token = test.create_access_token_with_username("admin");
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+
+        let path = format!("/ruma/admin/broadcasts?access_token={}", admin_access_token);
+        let response = test.post(&path, MAINTENANCE);
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().find("broadcast_id").is_some());
+
+        test.run_jobs();
+
+        for user_access_token in &[access_token, alice_access_token] {
+            let sync_path = format!("/_matrix/client/r0/sync?access_token={}", user_access_token);
+            let sync = test.get(&sync_path).json().clone();
+            let invites = sync.find_path(&["rooms", "invite"]).unwrap().as_object().unwrap();
+
+            assert_eq!(invites.len(), 1);
+
+            let room_id = invites.keys().next().unwrap();
+
+            assert_eq!(test.join_room(user_access_token, room_id).status, Status::Ok);
+
+            let messages_path = format!(
+                "/_matrix/client/r0/rooms/{}/messages?dir=b&limit=1&access_token={}",
+                room_id,
+                user_access_token
+            );
+            let messages = test.get(&messages_path).json().clone();
+            let chunk = messages.find("chunk").unwrap().as_array().unwrap();
+
+            assert_eq!(
+                chunk[0].find_path(&["content", "body"]).unwrap().as_str().unwrap(),
+                "Down for maintenance at 10:00 UTC"
+            );
+            assert_eq!(
+                chunk[0].find("sender").unwrap().as_str().unwrap(),
+                "@server-notices:ruma.test"
+            );
+        }
+    }
+
+    #[test]
+    fn later_broadcasts_reuse_the_notices_room() {
+        let test = Test::new();
+        let admin_access_token = test.create_access_token_with_username("admin");
+        let access_token = test.create_access_token();
+        let path = format!("/ruma/admin/broadcasts?access_token={}", admin_access_token);
+
+        assert_eq!(test.post(&path, MAINTENANCE).status, Status::Ok);
+        test.run_jobs();
+        assert_eq!(test.post(&path, MAINTENANCE).status, Status::Ok);
+        test.run_jobs();
+
+        let sync_path = format!("/_matrix/client/r0/sync?access_token={}", access_token);
+        let sync = test.get(&sync_path).json().clone();
+
+        assert_eq!(sync.find_path(&["rooms", "invite"]).unwrap().as_object().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn broadcast_requires_message_content() {
+        let test = Test::new();
+        let admin_access_token = test.create_access_token_with_username("admin");
+        let path = format!("/ruma/admin/broadcasts?access_token={}", admin_access_token);
+
+        assert_eq!(test.post(&path, r#"{"content": {"body": 1}}"#).status, Status::BadRequest);
+    }
+
+    #[test]
+    fn non_admin_cannot_broadcast() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let path = format!("/ruma/admin/broadcasts?access_token={}", access_token);
+
+        assert_eq!(test.post(&path, MAINTENANCE).status, Status::Forbidden);
+    }
+}
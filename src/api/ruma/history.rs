@@ -0,0 +1,334 @@
+//! Endpoints for application services to import history.
+
+use std::convert::TryFrom;
+
+use bodyparser;
+use diesel::Connection;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use ruma_events::EventType;
+use ruma_identifiers::{EventId, RoomId, UserId};
+use serde_json::{Value, to_string};
+
+use app_service::AppService;
+use config::Config;
+use db::DB;
+use error::ApiError;
+use event::{Event, NewEvent};
+use middleware::{AppServiceAuth, JsonRequest, MiddlewareChain, RoomIdParam};
+use modifier::SerializableResponse;
+use room::Room;
+use stream_position::StreamPosition;
+
+/// The most events one request may import.
+const MAX_BATCH_SIZE: usize = 1000;
+
+/// The POST `/rooms/:room_id/batch_send` endpoint.
+///
+/// Imports a batch of historical message events, oldest first, with the senders and timestamps
+/// they originally had, e.g. from the network a bridge connects to. The batch goes just before
+/// the event given as `before`, which must be the earliest event in the room, so long histories
+/// are imported newest batch first, each before the first event of the last. The events are
+/// reached by paginating back with `/messages` but never come down `/sync`. Senders must be
+/// users the application service manages; they don't have to be members of the room.
+pub struct BatchSend;
+
+#[derive(Clone, Debug, Deserialize)]
+struct BatchSendRequest {
+    events: Vec<HistoricalEvent>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct HistoricalEvent {
+    content: Value,
+    origin_server_ts: i64,
+    sender: String,
+    state_key: Option<String>,
+    #[serde(rename = "type")]
+    event_type: String,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchSendResponse {
+    event_ids: Vec<String>,
+}
+
+middleware_chain!(BatchSend, [JsonRequest, RoomIdParam, AppServiceAuth]);
+
+impl Handler for BatchSend {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let batch_send_request = match request.get::<bodyparser::Struct<BatchSendRequest>>() {
+            Ok(Some(batch_send_request)) => batch_send_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a room_id")
+            .clone();
+
+        let app_service = request.extensions.get::<AppService>()
+            .expect("AppServiceAuth should ensure an application service")
+            .clone();
+
+        let url = request.url.clone().into_generic_url();
+        let before = url.query_pairs()
+            .find(|&(ref key, _)| key == "before")
+            .map(|(_, value)| value.into_owned());
+
+        let before = match before.map(|before| EventId::try_from(&before[..])) {
+            Some(Ok(before)) => before,
+            Some(Err(_)) => {
+                let error = ApiError::invalid_param("before", "Must be an event ID.");
+
+                return Err(IronError::new(error.clone(), error));
+            }
+            None => {
+                let error = ApiError::missing_param("before");
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let events = batch_send_request.events;
+
+        if events.is_empty() || events.len() > MAX_BATCH_SIZE {
+            let error = ApiError::invalid_param(
+                "events",
+                &format!("Must have between 1 and {} events.", MAX_BATCH_SIZE),
+            );
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        let mut new_events = Vec::new();
+
+        for event in &events {
+            let new_event = new_historical_event(&config, &app_service, &room_id, event)?;
+
+            new_events.push((new_event, event.origin_server_ts));
+        }
+
+        connection.transaction::<(), ApiError, _>(|| {
+            Room::find(&connection, &room_id)?;
+
+            let before = Event::find_in_room(&connection, &room_id, &before)?;
+            let earlier = Event::find_page_by_room(
+                &connection,
+                &room_id,
+                before.ordering - 1,
+                None,
+                true,
+                1,
+            )?;
+
+            if !earlier.is_empty() {
+                return Err(ApiError::invalid_param(
+                    "before",
+                    "Must be the earliest event in the room.",
+                ));
+            }
+
+            let lowest = StreamPosition::reserve_history(&connection, new_events.len() as i64)?;
+
+            for (index, &(ref new_event, origin_server_ts)) in new_events.iter().enumerate() {
+                Event::backfill(&connection, new_event, origin_server_ts, lowest + index as i64)?;
+            }
+
+            Ok(())
+        }).map_err(ApiError::from)?;
+
+        let event_ids = new_events
+            .iter()
+            .map(|&(ref new_event, _)| new_event.id.to_string())
+            .collect();
+
+        let response = BatchSendResponse {
+            event_ids: event_ids,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// Checks one of the events of a batch and turns it into a new event in the room.
+fn new_historical_event(
+    config: &Config,
+    app_service: &AppService,
+    room_id: &RoomId,
+    event: &HistoricalEvent,
+) -> Result<NewEvent, ApiError> {
+    if event.state_key.is_some() {
+        return Err(
+            ApiError::invalid_param("events", "Historical events can't change room state.")
+        );
+    }
+
+    if event.event_type == EventType::RoomRedaction.to_string() {
+        return Err(ApiError::invalid_param("events", "Historical events can't be redactions."));
+    }
+
+    if !event.content.is_object() {
+        return Err(ApiError::invalid_param("events", "Event content must be a JSON object."));
+    }
+
+    let sender = UserId::try_from(&event.sender[..])
+        .map_err(|_| ApiError::invalid_param("events", "Senders must be user IDs."))?;
+
+    if !app_service.manages_user(&sender, &config.domain) {
+        return Err(ApiError::unauthorized(Some(
+            &format!("{} is not managed by the application service.", sender)
+        )));
+    }
+
+    Ok(NewEvent {
+        content: to_string(&event.content)?,
+        event_type: event.event_type.clone(),
+        extra_content: None,
+        id: EventId::new(&config.domain)?,
+        room_id: room_id.clone(),
+        state_key: None,
+        user_id: sender,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::{Value, to_string};
+
+    use test::{Response, Test};
+
+    const HISTORY: &'static str = r#"{"events": [
+        {
+            "type": "m.room.message",
+            "sender": "@bridge_alice:ruma.test",
+            "origin_server_ts": 1262304000000,
+            "content": {"msgtype": "m.text", "body": "First"}
+        },
+        {
+            "type": "m.room.message",
+            "sender": "@bridge_bob:ruma.test",
+            "origin_server_ts": 1262304060000,
+            "content": {"msgtype": "m.text", "body": "Second"}
+        }
+    ]}"#;
+
+    /// Returns the ID of the earliest event in the room.
+    fn first_event_id(test: &Test, access_token: &str, room_id: &str) -> String {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from={}&dir=f&limit=1&access_token={}",
+            room_id,
+            i64::min_value(),
+            access_token
+        );
+        let messages = test.get(&path).json().clone();
+
+        let chunk = messages.find("chunk").unwrap().as_array().unwrap();
+
+        chunk[0].find("event_id").unwrap().as_str().unwrap().to_string()
+    }
+
+    fn batch_send(test: &Test, room_id: &str, before: &str, body: &str) -> Response {
+        let path = format!(
+            "/ruma/rooms/{}/batch_send?before={}&access_token=bridge_as_token",
+            room_id,
+            before
+        );
+
+        test.post(&path, body)
+    }
+
+    #[test]
+    fn history_goes_before_the_room_without_reaching_sync() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let sync_path = format!("/_matrix/client/r0/sync?access_token={}", access_token);
+        let next_batch = test.get(&sync_path).json().find("next_batch").unwrap()
+            .as_str().unwrap().to_string();
+
+        let before = first_event_id(&test, &access_token, &room_id);
+        let response = batch_send(&test, &room_id, &before, HISTORY);
+        let event_ids = response.json().find("event_ids").unwrap().as_array().unwrap().clone();
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(event_ids.len(), 2);
+
+        let sync = test.get(&format!("{}&since={}", sync_path, next_batch)).json().clone();
+
+        assert!(!to_string(&sync).unwrap().contains(event_ids[0].as_str().unwrap()));
+
+        let first = first_event_id(&test, &access_token, &room_id);
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from={}&dir=f&limit=3&access_token={}",
+            room_id,
+            i64::min_value(),
+            access_token
+        );
+        let messages = test.get(&messages_path).json().clone();
+        let chunk = messages.find("chunk").unwrap().as_array().unwrap();
+
+        assert_eq!(first, event_ids[0].as_str().unwrap());
+        assert_eq!(chunk[0].find_path(&["content", "body"]).unwrap().as_str().unwrap(), "First");
+        assert_eq!(chunk[0].find("origin_server_ts").and_then(Value::as_i64), Some(1262304000000));
+        assert_eq!(chunk[1].find("sender").unwrap().as_str().unwrap(), "@bridge_bob:ruma.test");
+        assert_eq!(chunk[2].find("event_id").unwrap().as_str().unwrap(), before);
+    }
+
+    #[test]
+    fn history_must_go_before_the_earliest_event() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let before = first_event_id(&test, &access_token, &room_id);
+
+        assert_eq!(batch_send(&test, &room_id, &before, HISTORY).status, Status::Ok);
+        assert_eq!(batch_send(&test, &room_id, &before, HISTORY).status, Status::BadRequest);
+
+        let before = first_event_id(&test, &access_token, &room_id);
+
+        assert_eq!(batch_send(&test, &room_id, &before, HISTORY).status, Status::Ok);
+    }
+
+    #[test]
+    fn senders_must_be_managed_by_the_app_service() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let before = first_event_id(&test, &access_token, &room_id);
+
+        let body = r#"{"events": [{
+            "type": "m.room.message",
+            "sender": "@carl:ruma.test",
+            "origin_server_ts": 1262304000000,
+            "content": {"msgtype": "m.text", "body": "Not mine"}
+        }]}"#;
+
+        assert_eq!(batch_send(&test, &room_id, &before, body).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn only_app_services_can_import_history() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let before = first_event_id(&test, &access_token, &room_id);
+
+        let path = format!(
+            "/ruma/rooms/{}/batch_send?before={}&access_token={}",
+            room_id,
+            before,
+            access_token
+        );
+
+        assert_eq!(test.post(&path, HISTORY).status, Status::Forbidden);
+    }
+}
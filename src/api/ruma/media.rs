@@ -0,0 +1,219 @@
+//! Endpoints for administering the content repository.
+
+use bodyparser;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+
+use db::DB;
+use error::ApiError;
+use media::{Media, ServerMediaStorage};
+use middleware::{AccessTokenAuth, AdminAuth, JsonRequest, MediaIdParam, MiddlewareChain};
+
+/// The DELETE `/admin/media/:server_name/:media_id` endpoint.
+///
+/// The file's content and thumbnails are only deleted from storage once no other media ID refers to
+/// the same content.
+pub struct DeleteMedia;
+
+middleware_chain!(DeleteMedia, [MediaIdParam, AccessTokenAuth, AdminAuth]);
+
+impl Handler for DeleteMedia {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let media_id = request.extensions.get::<MediaIdParam>()
+            .expect("MediaIdParam should ensure a media ID").clone();
+
+        let connection = DB::from_request(request)?;
+        let storage = ServerMediaStorage::from_request(request)?;
+
+        match Media::find(&connection, &media_id)? {
+            Some(media) => media.delete(&connection, &**storage)?,
+            None => {
+                let error = ApiError::not_found(
+                    Some(&format!("No file with media ID {} was found.", media_id))
+                );
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        }
+
+        Ok(Response::with(Status::Ok))
+    }
+}
+
+/// The PUT `/admin/media/:server_name/:media_id/quarantined` endpoint.
+///
+/// Quarantined files stay in storage, but downloading them or their thumbnails fails with
+/// `M_NOT_FOUND` until the quarantine is lifted.
+pub struct PutMediaQuarantined;
+
+#[derive(Clone, Debug, Deserialize)]
+struct PutMediaQuarantinedRequest {
+    quarantined: bool,
+}
+
+middleware_chain!(PutMediaQuarantined, [JsonRequest, MediaIdParam, AccessTokenAuth, AdminAuth]);
+
+impl Handler for PutMediaQuarantined {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let quarantined_request =
+            match request.get::<bodyparser::Struct<PutMediaQuarantinedRequest>>() {
+                Ok(Some(quarantined_request)) => quarantined_request,
+                Ok(None) | Err(_) => {
+                    let error = ApiError::bad_json(None);
+
+                    return Err(IronError::new(error.clone(), error));
+                }
+            };
+
+        let media_id = request.extensions.get::<MediaIdParam>()
+            .expect("MediaIdParam should ensure a media ID").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let mut media = match Media::find(&connection, &media_id)? {
+            Some(media) => media,
+            None => {
+                let error = ApiError::not_found(
+                    Some(&format!("No file with media ID {} was found.", media_id))
+                );
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        media.set_quarantined(&connection, quarantined_request.quarantined)?;
+
+        Ok(Response::with(Status::Ok))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    /// Uploads a text file as carl and returns its MXC URI without the `mxc://` prefix.
+    fn upload(test: &Test) -> String {
+        let access_token = test.create_access_token();
+
+        let response = test.post_with_content_type(
+            &format!("/_matrix/media/r0/upload?access_token={}", access_token),
+            "text/plain",
+            "Hello, world!",
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        response.json()
+            .find("content_uri")
+            .and_then(Value::as_str)
+            .unwrap()
+            .trim_left_matches("mxc://")
+            .to_string()
+    }
+
+    #[test]
+    fn deleting_duplicate_media_keeps_the_other_copies() {
+        let test = Test::new();
+        let first = upload(&test);
+        let second = upload(&test);
+        let admin_access_token = test.create_access_token_with_username("admin");
+        let delete_path = |media: &str| {
+            format!("/ruma/admin/media/{}?access_token={}", media, admin_access_token)
+        };
+
+        assert!(first != second);
+        assert_eq!(test.delete(&delete_path(&first)).status, Status::Ok);
+        assert_eq!(
+            test.get(&format!("/_matrix/media/r0/download/{}", first)).status,
+            Status::NotFound
+        );
+
+        let response = test.get(&format!("/_matrix/media/r0/download/{}", second));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.body, "Hello, world!");
+
+        assert_eq!(test.delete(&delete_path(&second)).status, Status::Ok);
+        assert_eq!(
+            test.get(&format!("/_matrix/media/r0/download/{}", second)).status,
+            Status::NotFound
+        );
+        assert_eq!(test.delete(&delete_path(&second)).status, Status::NotFound);
+
+        // Uploading the content again stores it again.
+        let third = upload(&test);
+        let response = test.get(&format!("/_matrix/media/r0/download/{}", third));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.body, "Hello, world!");
+    }
+
+    #[test]
+    fn only_admins_can_delete_media() {
+        let test = Test::new();
+        let media = upload(&test);
+        let alice_token = test.create_access_token_with_username("alice");
+
+        let response = test.delete(
+            &format!("/ruma/admin/media/{}?access_token={}", media, alice_token)
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn quarantined_media_cannot_be_downloaded() {
+        let test = Test::new();
+        let media = upload(&test);
+        let admin_access_token = test.create_access_token_with_username("admin");
+        let path = format!(
+            "/ruma/admin/media/{}/quarantined?access_token={}",
+            media,
+            admin_access_token
+        );
+        let download_path = format!("/_matrix/media/r0/download/{}", media);
+
+        assert_eq!(test.put(&path, r#"{"quarantined": true}"#).status, Status::Ok);
+        assert_eq!(test.get(&download_path).status, Status::NotFound);
+
+        assert_eq!(test.put(&path, r#"{"quarantined": false}"#).status, Status::Ok);
+
+        let response = test.get(&download_path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.body, "Hello, world!");
+    }
+
+    #[test]
+    fn only_admins_can_quarantine_media() {
+        let test = Test::new();
+        let media = upload(&test);
+        let alice_token = test.create_access_token_with_username("alice");
+
+        let response = test.put(
+            &format!("/ruma/admin/media/{}/quarantined?access_token={}", media, alice_token),
+            r#"{"quarantined": true}"#,
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn quarantining_unknown_media_is_not_found() {
+        let test = Test::new();
+        let admin_access_token = test.create_access_token_with_username("admin");
+
+        let response = test.put(
+            &format!(
+                "/ruma/admin/media/ruma.test/unknown/quarantined?access_token={}",
+                admin_access_token
+            ),
+            r#"{"quarantined": true}"#,
+        );
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+}
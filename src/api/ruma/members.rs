@@ -0,0 +1,235 @@
+//! Endpoints for exporting room members.
+
+use std::collections::HashMap;
+
+use iron::{Chain, Handler, IronError, IronResult, Request, Response};
+use iron::headers::ContentType;
+use iron::mime::{Mime, SubLevel, TopLevel};
+use iron::modifiers::Header;
+use iron::status::Status;
+
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
+use modifier::SerializableResponse;
+use profile::Profile;
+use room::Room;
+use room_membership::RoomMembership;
+use user::User;
+
+/// The GET `/rooms/:room_id/members/export` endpoint.
+///
+/// Returns every membership in the room as JSON, or as CSV if the `format` query parameter is
+/// `csv`. Only users with at least the power level required to kick may export a room.
+pub struct ExportMembers;
+
+#[derive(Debug, Serialize)]
+struct ExportMembersResponse {
+    members: Vec<ExportedMember>,
+    room_id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedMember {
+    avatar_url: Option<String>,
+    displayname: Option<String>,
+    membership: String,
+    user_id: String,
+}
+
+middleware_chain!(ExportMembers, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for ExportMembers {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let url = request.url.clone().into_generic_url();
+        let csv = match url.query_pairs().find(|&(ref key, _)| key == "format") {
+            Some((_, ref format)) if format == "csv" => true,
+            Some((_, ref format)) if format == "json" => false,
+            None => false,
+            Some(_) => {
+                let error = ApiError::invalid_param("format", "Must be either csv or json.");
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let connection = DB::from_request(request)?;
+
+        let room = Room::find(&connection, &room_id)?;
+        let power_levels = room.current_power_levels(&connection)?;
+        let user_power_level = power_levels.users.get(&user.id).unwrap_or(&power_levels.users_default);
+
+        if *user_power_level < power_levels.kick {
+            let error = ApiError::unauthorized(
+                Some("Only moderators can export the members of a room.")
+            );
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let memberships = RoomMembership::find_by_room(&connection, &room_id)?;
+        let user_ids: Vec<_> = memberships.iter().map(|membership| membership.user_id.clone()).collect();
+        let mut profiles: HashMap<String, Profile> = Profile::find_by_uids(&connection, &user_ids)?
+            .into_iter()
+            .map(|profile| (profile.id.to_string(), profile))
+            .collect();
+
+        let members: Vec<ExportedMember> = memberships.into_iter().map(|membership| {
+            let user_id = membership.user_id.to_string();
+            let profile = profiles.remove(&user_id);
+
+            ExportedMember {
+                avatar_url: profile.as_ref().and_then(|profile| profile.avatar_url.clone()),
+                displayname: profile.and_then(|profile| profile.displayname),
+                membership: membership.membership,
+                user_id: user_id,
+            }
+        }).collect();
+
+        if csv {
+            let mime = Mime(TopLevel::Text, SubLevel::Ext("csv".to_string()), vec![]);
+
+            return Ok(Response::with((Status::Ok, Header(ContentType(mime)), to_csv(&members))));
+        }
+
+        let response = ExportMembersResponse {
+            members: members,
+            room_id: room_id.to_string(),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// Renders the members as CSV with a header row.
+fn to_csv(members: &[ExportedMember]) -> String {
+    let mut csv = "user_id,membership,displayname,avatar_url\r\n".to_string();
+
+    for member in members {
+        let fields = [
+            &member.user_id[..],
+            &member.membership[..],
+            member.displayname.as_ref().map(|s| &s[..]).unwrap_or(""),
+            member.avatar_url.as_ref().map(|s| &s[..]).unwrap_or(""),
+        ];
+
+        let escaped: Vec<String> = fields.iter().map(|field| escape_csv_field(field)).collect();
+
+        csv.push_str(&escaped.join(","));
+        csv.push_str("\r\n");
+    }
+
+    csv
+}
+
+/// Quotes a CSV field if it contains a delimiter, quote, or line break.
+fn escape_csv_field(field: &str) -> String {
+    if field.contains(|c: char| c == ',' || c == '"' || c == '\r' || c == '\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::Test;
+
+    #[test]
+    fn export_members_as_json() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&access_token);
+
+        test.join_room(&alice_access_token, &room_id);
+
+        let path = format!(
+            "/ruma/rooms/{}/members/export?access_token={}",
+            room_id,
+            access_token
+        );
+        let response = test.get(&path);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let members = response.json().find("members").unwrap().as_array().unwrap();
+        let user_ids: Vec<&str> = members
+            .iter()
+            .map(|member| member.find("user_id").unwrap().as_str().unwrap())
+            .collect();
+
+        assert_eq!(members.len(), 2);
+        assert!(user_ids.contains(&"@carl:ruma.test"));
+        assert!(user_ids.contains(&"@alice:ruma.test"));
+    }
+
+    #[test]
+    fn export_members_as_csv() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_public_room(&access_token);
+
+        let displayname_path = format!(
+            "/_matrix/client/r0/profile/@carl:ruma.test/displayname?access_token={}",
+            access_token
+        );
+        test.put(&displayname_path, r#"{"displayname":"Carl, \"the\" creator"}"#);
+
+        let path = format!(
+            "/ruma/rooms/{}/members/export?access_token={}&format=csv",
+            room_id,
+            access_token
+        );
+        let response = test.get(&path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.body,
+            "user_id,membership,displayname,avatar_url\r\n\
+             @carl:ruma.test,join,\"Carl, \"\"the\"\" creator\",\r\n"
+        );
+    }
+
+    #[test]
+    fn export_members_requires_moderator() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&access_token);
+
+        test.join_room(&alice_access_token, &room_id);
+
+        let path = format!(
+            "/ruma/rooms/{}/members/export?access_token={}",
+            room_id,
+            alice_access_token
+        );
+
+        assert_eq!(test.get(&path).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn export_members_rejects_unknown_format() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_public_room(&access_token);
+
+        let path = format!(
+            "/ruma/rooms/{}/members/export?access_token={}&format=xml",
+            room_id,
+            access_token
+        );
+
+        assert_eq!(test.get(&path).status, Status::BadRequest);
+    }
+}
@@ -0,0 +1,27 @@
+//! Ruma-specific API endpoints that are not part of the Matrix spec.
+//!
+//! These are mounted under `/ruma/`.
+
+pub use self::broadcasts::PostBroadcast;
+pub use self::history::BatchSend;
+pub use self::media::{DeleteMedia, PutMediaQuarantined};
+pub use self::members::ExportMembers;
+pub use self::rooms::{ExportRoom, GetRoomComplexity, ImportRoom, PutRoomPowerLevel};
+pub use self::stats::GetStats;
+pub use self::users::{
+    DeleteUserVelocityLimits,
+    GetUserAccessTokens,
+    GetUserVelocityLimits,
+    PostUserThreepid,
+    PutUserLocked,
+    PutUserVelocityLimits,
+    RevokeUserAccessTokens,
+};
+
+mod broadcasts;
+mod history;
+mod media;
+mod members;
+mod rooms;
+mod stats;
+mod users;
@@ -0,0 +1,456 @@
+//! Administrative endpoints for rooms.
+
+use bodyparser;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+
+use clock::ServerClock;
+use config::Config;
+use db::DB;
+use error::ApiError;
+use middleware::{
+    AccessTokenAuth,
+    AdminAuth,
+    JsonRequest,
+    MiddlewareChain,
+    RoomIdParam,
+    UserIdParam,
+};
+use modifier::SerializableResponse;
+use power_levels::ServerPowerLevelsCache;
+use room::Room;
+use room_export::RoomExport;
+use room_import::RoomImport;
+use user::User;
+
+/// The GET `/admin/rooms/:room_id/complexity` endpoint.
+pub struct GetRoomComplexity;
+
+#[derive(Debug, Serialize)]
+struct GetRoomComplexityResponse {
+    complexity: f64,
+    room_id: String,
+}
+
+middleware_chain!(GetRoomComplexity, [RoomIdParam, AccessTokenAuth, AdminAuth]);
+
+impl Handler for GetRoomComplexity {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+
+        let connection = DB::from_request(request)?;
+
+        let room = Room::find(&connection, &room_id)?;
+
+        let response = GetRoomComplexityResponse {
+            complexity: room.complexity(&connection)?,
+            room_id: room_id.to_string(),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The PUT `/admin/rooms/:room_id/power_levels/:user_id` endpoint.
+///
+/// Sets a user's power level in a local room regardless of the admin's own power level, so rooms
+/// without any remaining moderators can still be administered. A power level of 0 demotes the
+/// user.
+pub struct PutRoomPowerLevel;
+
+#[derive(Clone, Debug, Deserialize)]
+struct PutRoomPowerLevelRequest {
+    power_level: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct PutRoomPowerLevelResponse {
+    event_id: String,
+}
+
+middleware_chain!(
+    PutRoomPowerLevel,
+    [JsonRequest, RoomIdParam, UserIdParam, AccessTokenAuth, AdminAuth]
+);
+
+impl Handler for PutRoomPowerLevel {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let power_level_request = request.get::<bodyparser::Struct<PutRoomPowerLevelRequest>>();
+
+        let power_level_request = match power_level_request {
+            Ok(Some(power_level_request)) => power_level_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+
+        let user_id = request.extensions.get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId").clone();
+
+        let admin = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let clock = ServerClock::from_request(request)?;
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+        let power_levels_cache = ServerPowerLevelsCache::from_request(request)?;
+
+        let room = Room::find(&connection, &room_id)?;
+
+        let event_id = room.force_power_level(
+            &connection,
+            &power_levels_cache,
+            &config.domain,
+            &admin.id,
+            &user_id,
+            power_level_request.power_level,
+            clock.now_millis(),
+        )?;
+
+        let response = PutRoomPowerLevelResponse {
+            event_id: event_id.to_string(),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/rooms/:room_id/export` endpoint.
+///
+/// Returns the room's current state and full history, along with the `mxc://` URIs of the media
+/// its events refer to. Only admins and users with at least the power level required to kick may
+/// export a room.
+pub struct ExportRoom;
+
+middleware_chain!(ExportRoom, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for ExportRoom {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let config = Config::from_request(request)?;
+        let clock = ServerClock::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        let room = Room::find(&connection, &room_id)?;
+
+        if !config.admins.contains(&user.id) {
+            let power_levels = room.current_power_levels(&connection)?;
+            let user_power_level = power_levels.users
+                .get(&user.id)
+                .unwrap_or(&power_levels.users_default);
+
+            if *user_power_level < power_levels.kick {
+                let error = ApiError::unauthorized(
+                    Some("Only moderators and admins can export a room.")
+                );
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        }
+
+        let export = RoomExport::collect(&connection, &room_id, clock.now_millis())?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(export))))
+    }
+}
+
+/// The POST `/admin/rooms/import` endpoint.
+///
+/// Recreates a room from the body of a `/rooms/:room_id/export` response under a new room ID,
+/// with the admin as its creator. Exports too large to send in one request can be imported with
+/// the `import-room` command instead.
+pub struct ImportRoom;
+
+middleware_chain!(ImportRoom, [JsonRequest, AccessTokenAuth, AdminAuth]);
+
+impl Handler for ImportRoom {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let export = match request.get::<bodyparser::Struct<RoomExport>>() {
+            Ok(Some(export)) => export,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let admin = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let config = Config::from_request(request)?;
+        let clock = ServerClock::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        let import = RoomImport::import(
+            &connection,
+            &config,
+            &admin.id,
+            &export,
+            clock.now_millis(),
+        )?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(import))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::Test;
+
+    #[test]
+    fn complexity_grows_with_state_and_members() {
+        let test = Test::new();
+        let admin_access_token = test.create_access_token_with_username("admin");
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&access_token);
+        let path = format!(
+            "/ruma/admin/rooms/{}/complexity?access_token={}",
+            room_id,
+            admin_access_token
+        );
+
+        let response = test.get(&path);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let before = response.json().find("complexity").unwrap().as_f64().unwrap();
+
+        assert!(before > 0.0);
+
+        test.join_room(&alice_access_token, &room_id);
+
+        let after = test.get(&path).json().find("complexity").unwrap().as_f64().unwrap();
+
+        assert!(after > before);
+    }
+
+    #[test]
+    fn non_admin_cannot_get_room_complexity() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_public_room(&access_token);
+        let path = format!(
+            "/ruma/admin/rooms/{}/complexity?access_token={}",
+            room_id,
+            access_token
+        );
+
+        assert_eq!(test.get(&path).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn admin_can_take_over_room() {
+        let test = Test::new();
+        let admin_access_token = test.create_access_token_with_username("admin");
+        let access_token = test.create_access_token();
+        let room_id = test.create_public_room(&access_token);
+
+        let power_levels_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+            room_id,
+            access_token
+        );
+        let power_levels = r#"{
+            "ban": 50,
+            "events": {},
+            "events_default": 0,
+            "invite": 50,
+            "kick": 50,
+            "redact": 50,
+            "state_default": 50,
+            "users": { "@carl:ruma.test": 100 },
+            "users_default": 0
+        }"#;
+
+        assert_eq!(test.put(&power_levels_path, power_levels).status, Status::Ok);
+
+        test.join_room(&admin_access_token, &room_id);
+
+        let name_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.name?access_token={}",
+            room_id,
+            admin_access_token
+        );
+
+        assert_eq!(test.put(&name_path, r#"{"name":"Taken over"}"#).status, Status::Forbidden);
+
+        let path = format!(
+            "/ruma/admin/rooms/{}/power_levels/@admin:ruma.test?access_token={}",
+            room_id,
+            admin_access_token
+        );
+
+        let response = test.put(&path, r#"{"power_level":100}"#);
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().find("event_id").is_some());
+        assert_eq!(test.put(&name_path, r#"{"name":"Taken over"}"#).status, Status::Ok);
+    }
+
+    #[test]
+    fn non_admin_cannot_set_room_power_level() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_public_room(&access_token);
+        let path = format!(
+            "/ruma/admin/rooms/{}/power_levels/@carl:ruma.test?access_token={}",
+            room_id,
+            access_token
+        );
+
+        assert_eq!(test.put(&path, r#"{"power_level":100}"#).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn export_room_history() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let message_path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/1?access_token={}",
+            room_id,
+            access_token
+        );
+        let message = r#"{"msgtype": "m.image", "body": "cat.png", "url": "mxc://ruma.test/cat"}"#;
+
+        assert_eq!(test.put(&message_path, message).status, Status::Ok);
+
+        let path = format!("/ruma/rooms/{}/export?access_token={}", room_id, access_token);
+        let response = test.get(&path);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let events = response.json().find("events").unwrap().as_array().unwrap();
+
+        assert_eq!(events[0].find("type").unwrap().as_str().unwrap(), "m.room.create");
+        assert_eq!(
+            events.last().unwrap().find_path(&["content", "body"]).unwrap().as_str().unwrap(),
+            "cat.png"
+        );
+        assert!(response.json().find("state").unwrap().as_array().unwrap().len() > 0);
+        assert_eq!(
+            response.json().find("media").unwrap().as_array().unwrap()[0].as_str().unwrap(),
+            "mxc://ruma.test/cat"
+        );
+    }
+
+    #[test]
+    fn admin_can_export_any_room() {
+        let test = Test::new();
+        let admin_access_token = test.create_access_token_with_username("admin");
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let path = format!("/ruma/rooms/{}/export?access_token={}", room_id, admin_access_token);
+
+        assert_eq!(test.get(&path).status, Status::Ok);
+    }
+
+    #[test]
+    fn members_without_power_cannot_export_room() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let alice_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_public_room(&access_token);
+
+        test.join_room(&alice_access_token, &room_id);
+
+        let path = format!("/ruma/rooms/{}/export?access_token={}", room_id, alice_access_token);
+
+        assert_eq!(test.get(&path).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn import_exported_room() {
+        let test = Test::new();
+        let admin_access_token = test.create_access_token_with_username("admin");
+        let access_token = test.create_access_token();
+        let room_id = test.create_room_with_params(&access_token, r#"{"name": "Book club"}"#);
+
+        let message_path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/1?access_token={}",
+            room_id,
+            access_token
+        );
+
+        assert_eq!(
+            test.put(&message_path, r#"{"msgtype": "m.text", "body": "Chapter one"}"#).status,
+            Status::Ok
+        );
+
+        let export_path = format!("/ruma/rooms/{}/export?access_token={}", room_id, access_token);
+        let export = test.get(&export_path).json().to_string();
+        let sent_at = test.get(&export_path).json().find("events").unwrap().as_array().unwrap()
+            .last().unwrap().find("origin_server_ts").unwrap().as_i64().unwrap();
+
+        let import_path = format!("/ruma/admin/rooms/import?access_token={}", admin_access_token);
+        let response = test.post(&import_path, &export);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let new_room_id = response.json().find("room_id").unwrap().as_str().unwrap().to_string();
+
+        assert!(new_room_id != room_id);
+
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?dir=b&limit=10&access_token={}",
+            new_room_id,
+            admin_access_token
+        );
+        let messages = test.get(&messages_path).json().clone();
+        let chunk = messages.find("chunk").unwrap().as_array().unwrap();
+        let message = chunk.iter()
+            .find(|event| event.find("type").unwrap().as_str().unwrap() == "m.room.message")
+            .unwrap();
+
+        assert_eq!(message.find("sender").unwrap().as_str().unwrap(), "@carl:ruma.test");
+        assert_eq!(message.find("origin_server_ts").unwrap().as_i64().unwrap(), sent_at);
+
+        let name_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.name?access_token={}",
+            new_room_id,
+            admin_access_token
+        );
+
+        assert_eq!(
+            test.get(&name_path).json().find("name").unwrap().as_str().unwrap(),
+            "Book club"
+        );
+
+        let sync_path = format!("/_matrix/client/r0/sync?access_token={}", access_token);
+        let sync = test.get(&sync_path).json().clone();
+
+        assert!(sync.find_path(&["rooms", "join", &new_room_id]).is_none());
+    }
+
+    #[test]
+    fn non_admin_cannot_import_room() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+
+        let export_path = format!("/ruma/rooms/{}/export?access_token={}", room_id, access_token);
+        let export = test.get(&export_path).json().to_string();
+        let import_path = format!("/ruma/admin/rooms/import?access_token={}", access_token);
+
+        assert_eq!(test.post(&import_path, &export).status, Status::Forbidden);
+    }
+}
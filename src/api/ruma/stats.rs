@@ -0,0 +1,58 @@
+//! Endpoints for server statistics.
+
+use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::status::Status;
+
+use db::DB;
+use middleware::{AccessTokenAuth, AdminAuth, MiddlewareChain};
+use modifier::SerializableResponse;
+use stats::Stats;
+
+/// The GET `/admin/stats` endpoint.
+pub struct GetStats;
+
+middleware_chain!(GetStats, [AccessTokenAuth, AdminAuth]);
+
+impl Handler for GetStats {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let connection = DB::from_request(request)?;
+
+        let stats = Stats::collect(&connection)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(stats))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::Test;
+
+    #[test]
+    fn admin_can_get_stats() {
+        let test = Test::new();
+        let access_token = test.create_access_token_with_username("admin");
+        let carl_access_token = test.create_access_token();
+
+        test.create_room(&carl_access_token);
+
+        let response = test.get(&format!("/ruma/admin/stats?access_token={}", access_token));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().find("total_users").unwrap().as_i64().unwrap(), 2);
+        assert_eq!(response.json().find("total_rooms").unwrap().as_i64().unwrap(), 1);
+        assert_eq!(response.json().find("daily_active_users").unwrap().as_i64().unwrap(), 2);
+        assert!(response.json().find("database_size").unwrap().as_i64().unwrap() > 0);
+    }
+
+    #[test]
+    fn non_admin_cannot_get_stats() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+
+        let response = test.get(&format!("/ruma/admin/stats?access_token={}", access_token));
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
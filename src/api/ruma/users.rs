@@ -0,0 +1,525 @@
+//! Administrative endpoints for users.
+
+use bodyparser;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+
+use access_token::AccessToken;
+use config::Config;
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, AdminAuth, JsonRequest, MiddlewareChain, UserIdParam};
+use modifier::SerializableResponse;
+use user::User;
+use user_threepid::{NewUserThreepid, UserThreepid};
+use velocity_limit::{UserVelocityLimits, VelocityLimits};
+
+/// The PUT `/admin/users/:user_id/locked` endpoint.
+///
+/// Locked users keep their account, but every authenticated request they make is rejected with
+/// `M_USER_LOCKED` until they are unlocked.
+pub struct PutUserLocked;
+
+#[derive(Clone, Debug, Deserialize)]
+struct PutUserLockedRequest {
+    locked: bool,
+}
+
+middleware_chain!(PutUserLocked, [JsonRequest, UserIdParam, AccessTokenAuth, AdminAuth]);
+
+impl Handler for PutUserLocked {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let locked_request = match request.get::<bodyparser::Struct<PutUserLockedRequest>>() {
+            Ok(Some(locked_request)) => locked_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let user_id = request.extensions.get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let mut user = User::find_by_uid(&connection, &user_id)?;
+
+        user.set_locked(&connection, locked_request.locked)?;
+
+        Ok(Response::with(Status::Ok))
+    }
+}
+
+/// The POST `/admin/users/:user_id/threepids` endpoint.
+///
+/// Binds a third-party identifier, such as an email address, to a user so they can log in with
+/// it. Ruma doesn't talk to identity servers, so admins vouch for the address instead.
+pub struct PostUserThreepid;
+
+#[derive(Clone, Debug, Deserialize)]
+struct PostUserThreepidRequest {
+    address: String,
+    medium: String,
+}
+
+middleware_chain!(PostUserThreepid, [JsonRequest, UserIdParam, AccessTokenAuth, AdminAuth]);
+
+impl Handler for PostUserThreepid {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let threepid_request = match request.get::<bodyparser::Struct<PostUserThreepidRequest>>() {
+            Ok(Some(threepid_request)) => threepid_request,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let user_id = request.extensions.get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let user = User::find_by_uid(&connection, &user_id)?;
+
+        UserThreepid::create(&connection, NewUserThreepid {
+            user_id: user.id,
+            medium: threepid_request.medium,
+            address: threepid_request.address,
+        })?;
+
+        Ok(Response::with(Status::Ok))
+    }
+}
+
+/// The GET `/admin/users/:user_id/access_tokens` endpoint.
+///
+/// Lists a user's access tokens, including revoked ones, so admins can see which sessions exist
+/// when an account is compromised. The tokens themselves are never returned.
+pub struct GetUserAccessTokens;
+
+#[derive(Debug, Serialize)]
+struct GetUserAccessTokensResponse {
+    access_tokens: Vec<AccessTokenInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct AccessTokenInfo {
+    created_at: i64,
+    id: i64,
+    last_used_at: Option<i64>,
+    last_used_ip: Option<String>,
+    revoked: bool,
+}
+
+middleware_chain!(GetUserAccessTokens, [UserIdParam, AccessTokenAuth, AdminAuth]);
+
+impl Handler for GetUserAccessTokens {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user_id = request.extensions.get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let user = User::find_by_uid(&connection, &user_id)?;
+
+        let access_tokens = AccessToken::find_by_user(&connection, &user.id)?
+            .into_iter()
+            .map(|access_token| {
+                AccessTokenInfo {
+                    created_at: access_token.created_at_millis(),
+                    id: access_token.id,
+                    last_used_at: access_token.last_used_at,
+                    last_used_ip: access_token.last_used_ip,
+                    revoked: access_token.revoked,
+                }
+            })
+            .collect();
+
+        let response = GetUserAccessTokensResponse {
+            access_tokens: access_tokens,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The POST `/admin/users/:user_id/access_tokens/revoke` endpoint.
+///
+/// Revokes the given access tokens of a user, signing out those sessions while leaving the user's
+/// other sessions alone.
+pub struct RevokeUserAccessTokens;
+
+#[derive(Clone, Debug, Deserialize)]
+struct RevokeUserAccessTokensRequest {
+    ids: Vec<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct RevokeUserAccessTokensResponse {
+    revoked: usize,
+}
+
+middleware_chain!(
+    RevokeUserAccessTokens,
+    [JsonRequest, UserIdParam, AccessTokenAuth, AdminAuth]
+);
+
+impl Handler for RevokeUserAccessTokens {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let revoke_request =
+            match request.get::<bodyparser::Struct<RevokeUserAccessTokensRequest>>() {
+                Ok(Some(revoke_request)) => revoke_request,
+                Ok(None) | Err(_) => {
+                    let error = ApiError::bad_json(None);
+
+                    return Err(IronError::new(error.clone(), error));
+                }
+            };
+
+        let user_id = request.extensions.get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let user = User::find_by_uid(&connection, &user_id)?;
+
+        let revoked = AccessToken::revoke_by_ids(&connection, &user.id, &revoke_request.ids)?;
+
+        let response = RevokeUserAccessTokensResponse {
+            revoked: revoked,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/admin/users/:user_id/velocity_limits` endpoint.
+///
+/// Shows how many invites a user may send per hour and rooms they may create per day, and
+/// whether those limits were set for the user or are the server's.
+pub struct GetUserVelocityLimits;
+
+/// The PUT `/admin/users/:user_id/velocity_limits` endpoint.
+///
+/// Sets limits for a user in place of the server's. A missing or null limit means the user is not
+/// limited at all.
+pub struct PutUserVelocityLimits;
+
+/// The DELETE `/admin/users/:user_id/velocity_limits` endpoint.
+///
+/// Removes the limits set for a user, so the server's apply to them again.
+pub struct DeleteUserVelocityLimits;
+
+#[derive(Debug, Serialize)]
+struct GetUserVelocityLimitsResponse {
+    invites_per_hour: Option<u64>,
+    overridden: bool,
+    rooms_created_per_day: Option<u64>,
+}
+
+middleware_chain!(GetUserVelocityLimits, [UserIdParam, AccessTokenAuth, AdminAuth]);
+
+impl Handler for GetUserVelocityLimits {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user_id = request.extensions.get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId").clone();
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+
+        let user = User::find_by_uid(&connection, &user_id)?;
+
+        let overridden = UserVelocityLimits::find(&connection, &user.id)?.is_some();
+        let limits = VelocityLimits::for_user(&connection, &config, &user.id)?;
+
+        let response = GetUserVelocityLimitsResponse {
+            invites_per_hour: limits.invites_per_hour,
+            overridden: overridden,
+            rooms_created_per_day: limits.rooms_created_per_day,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+middleware_chain!(
+    PutUserVelocityLimits,
+    [JsonRequest, UserIdParam, AccessTokenAuth, AdminAuth]
+);
+
+impl Handler for PutUserVelocityLimits {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let limits = match request.get::<bodyparser::Struct<VelocityLimits>>() {
+            Ok(Some(limits)) => limits,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let user_id = request.extensions.get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let user = User::find_by_uid(&connection, &user_id)?;
+
+        UserVelocityLimits::set(&connection, &user.id, &limits)?;
+
+        Ok(Response::with(Status::Ok))
+    }
+}
+
+middleware_chain!(DeleteUserVelocityLimits, [UserIdParam, AccessTokenAuth, AdminAuth]);
+
+impl Handler for DeleteUserVelocityLimits {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user_id = request.extensions.get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let user = User::find_by_uid(&connection, &user_id)?;
+
+        UserVelocityLimits::remove(&connection, &user.id)?;
+
+        Ok(Response::with(Status::Ok))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use iron::status::Status;
+
+    use test::Test;
+
+    #[test]
+    fn locked_users_are_rejected_until_unlocked() {
+        let test = Test::new();
+        let admin_access_token = test.create_access_token_with_username("admin");
+        let access_token = test.create_access_token();
+        let lock_path = format!(
+            "/ruma/admin/users/@carl:ruma.test/locked?access_token={}",
+            admin_access_token
+        );
+        let sync_path = format!("/_matrix/client/r0/sync?access_token={}", access_token);
+
+        assert_eq!(test.put(&lock_path, r#"{"locked":true}"#).status, Status::Ok);
+
+        let response = test.get(&sync_path);
+
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(response.json().find("errcode").unwrap().as_str().unwrap(), "M_USER_LOCKED");
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"auth": {"type": "m.login.password", "user": "carl", "password": "secret"}}"#,
+        );
+
+        assert_eq!(response.status, Status::Unauthorized);
+
+        assert_eq!(test.put(&lock_path, r#"{"locked":false}"#).status, Status::Ok);
+        assert_eq!(test.get(&sync_path).status, Status::Ok);
+    }
+
+    #[test]
+    fn non_admin_cannot_lock_users() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let path = format!(
+            "/ruma/admin/users/@carl:ruma.test/locked?access_token={}",
+            access_token
+        );
+
+        assert_eq!(test.put(&path, r#"{"locked":true}"#).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn threepid_cannot_be_bound_twice() {
+        let test = Test::new();
+        let admin_access_token = test.create_access_token_with_username("admin");
+        test.create_access_token();
+        test.create_access_token_with_username("alice");
+
+        let response = test.post(
+            &format!(
+                "/ruma/admin/users/@carl:ruma.test/threepids?access_token={}",
+                admin_access_token
+            ),
+            r#"{"medium":"email","address":"carl@example.com"}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            &format!(
+                "/ruma/admin/users/@alice:ruma.test/threepids?access_token={}",
+                admin_access_token
+            ),
+            r#"{"medium":"email","address":"Carl@Example.com"}"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn non_admin_cannot_bind_threepids() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let path = format!(
+            "/ruma/admin/users/@carl:ruma.test/threepids?access_token={}",
+            access_token
+        );
+        let body = r#"{"medium":"email","address":"carl@example.com"}"#;
+
+        assert_eq!(test.post(&path, body).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn admin_can_list_and_revoke_access_tokens() {
+        let test = Test::new();
+        let admin_access_token = test.create_access_token_with_username("admin");
+        let first_access_token = test.create_access_token();
+        let second_access_token = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"auth": {"type": "m.login.password", "user": "carl", "password": "secret"}}"#,
+        ).json().find("access_token").unwrap().as_str().unwrap().to_string();
+        let list_path = format!(
+            "/ruma/admin/users/@carl:ruma.test/access_tokens?access_token={}",
+            admin_access_token
+        );
+        let first_sync_path =
+            format!("/_matrix/client/r0/sync?access_token={}", first_access_token);
+        let second_sync_path =
+            format!("/_matrix/client/r0/sync?access_token={}", second_access_token);
+
+        assert_eq!(test.get(&first_sync_path).status, Status::Ok);
+
+        let response = test.get(&list_path);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+        let access_tokens = json.find("access_tokens").unwrap().as_array().unwrap();
+
+        assert_eq!(access_tokens.len(), 2);
+        assert!(access_tokens.iter().all(|access_token| access_token.find("token").is_none()));
+        assert!(access_tokens[0].find("created_at").unwrap().is_number());
+        assert!(access_tokens[0].find("last_used_at").unwrap().is_number());
+        assert_eq!(access_tokens[0].find("revoked").unwrap().as_bool(), Some(false));
+
+        let first_id = access_tokens[0].find("id").unwrap().as_u64().unwrap();
+        let response = test.post(
+            &format!(
+                "/ruma/admin/users/@carl:ruma.test/access_tokens/revoke?access_token={}",
+                admin_access_token
+            ),
+            &format!(r#"{{"ids":[{}]}}"#, first_id),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().find("revoked").unwrap().as_u64(), Some(1));
+        assert_eq!(test.get(&first_sync_path).status, Status::Forbidden);
+        assert_eq!(test.get(&second_sync_path).status, Status::Ok);
+
+        let response = test.get(&list_path);
+        let json = response.json();
+        let access_tokens = json.find("access_tokens").unwrap().as_array().unwrap();
+
+        assert_eq!(access_tokens[0].find("revoked").unwrap().as_bool(), Some(true));
+        assert_eq!(access_tokens[1].find("revoked").unwrap().as_bool(), Some(false));
+    }
+
+    #[test]
+    fn non_admin_cannot_list_access_tokens() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let path = format!(
+            "/ruma/admin/users/@carl:ruma.test/access_tokens?access_token={}",
+            access_token
+        );
+
+        assert_eq!(test.get(&path).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn invites_are_limited_per_user() {
+        let test = Test::new();
+        let admin_access_token = test.create_access_token_with_username("admin");
+        let access_token = test.create_access_token();
+        test.create_access_token_with_username("alice");
+        test.create_access_token_with_username("bob");
+        let room_id = test.create_room(&access_token);
+        let limits_path = format!(
+            "/ruma/admin/users/@carl:ruma.test/velocity_limits?access_token={}",
+            admin_access_token
+        );
+        let invite_path = format!(
+            "/_matrix/client/r0/rooms/{}/invite?access_token={}",
+            room_id,
+            access_token
+        );
+
+        assert_eq!(test.put(&limits_path, r#"{"invites_per_hour":1}"#).status, Status::Ok);
+
+        let response = test.get(&limits_path);
+
+        assert_eq!(response.json().find("invites_per_hour").unwrap().as_u64(), Some(1));
+        assert_eq!(response.json().find("overridden").unwrap().as_bool(), Some(true));
+
+        let response = test.post(&invite_path, r#"{"user_id":"@alice:ruma.test"}"#);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(&invite_path, r#"{"user_id":"@bob:ruma.test"}"#);
+
+        assert_eq!(response.status, Status::TooManyRequests);
+        assert_eq!(response.json().find("errcode").unwrap().as_str().unwrap(), "M_LIMIT_EXCEEDED");
+
+        test.advance_clock(Duration::hours(1));
+
+        let response = test.post(&invite_path, r#"{"user_id":"@bob:ruma.test"}"#);
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn room_creation_limit_can_be_removed() {
+        let test = Test::new();
+        let admin_access_token = test.create_access_token_with_username("admin");
+        let access_token = test.create_access_token();
+        let limits_path = format!(
+            "/ruma/admin/users/@carl:ruma.test/velocity_limits?access_token={}",
+            admin_access_token
+        );
+        let create_room_path =
+            format!("/_matrix/client/r0/createRoom?access_token={}", access_token);
+
+        assert_eq!(test.put(&limits_path, r#"{"rooms_created_per_day":1}"#).status, Status::Ok);
+        assert_eq!(test.post(&create_room_path, "{}").status, Status::Ok);
+        assert_eq!(test.post(&create_room_path, "{}").status, Status::TooManyRequests);
+
+        assert_eq!(test.delete(&limits_path).status, Status::Ok);
+        assert_eq!(
+            test.get(&limits_path).json().find("overridden").unwrap().as_bool(),
+            Some(false)
+        );
+        assert_eq!(test.post(&create_room_path, "{}").status, Status::Ok);
+    }
+
+    #[test]
+    fn non_admin_cannot_set_velocity_limits() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let path = format!(
+            "/ruma/admin/users/@carl:ruma.test/velocity_limits?access_token={}",
+            access_token
+        );
+
+        assert_eq!(test.put(&path, r#"{"invites_per_hour":1000}"#).status, Status::Forbidden);
+    }
+}
@@ -0,0 +1,301 @@
+//! Endpoints for managing delayed events.
+//!
+//! Events are scheduled by passing a `delay` query parameter to the r0 `send` and `state`
+//! endpoints. These endpoints let the sender see, cancel, restart, or immediately send them.
+
+use bodyparser;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use router::Router;
+use serde_json::{Value, from_str};
+
+use clock::ServerClock;
+use config::Config;
+use db::DB;
+use delayed_event::DelayedEvent;
+use error::ApiError;
+use membership_cache::ServerMembershipCache;
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
+use modifier::SerializableResponse;
+use power_levels::ServerPowerLevelsCache;
+use user::User;
+
+/// The GET `/delayed_events` endpoint.
+pub struct GetDelayedEvents;
+
+#[derive(Debug, Serialize)]
+struct GetDelayedEventsResponse {
+    delayed_events: Vec<DelayedEventInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct DelayedEventInfo {
+    content: Value,
+    delay: i64,
+    delay_id: String,
+    room_id: String,
+    running_since: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_key: Option<String>,
+    #[serde(rename = "type")]
+    event_type: String,
+}
+
+middleware_chain!(GetDelayedEvents, [AccessTokenAuth]);
+
+impl Handler for GetDelayedEvents {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let mut delayed_events = Vec::new();
+
+        for delayed_event in DelayedEvent::find_by_user(&connection, &user.id)? {
+            delayed_events.push(DelayedEventInfo {
+                content: from_str(&delayed_event.content).map_err(ApiError::from)?,
+                delay: delayed_event.delay,
+                delay_id: delayed_event.id,
+                room_id: delayed_event.room_id.to_string(),
+                running_since: delayed_event.send_at - delayed_event.delay,
+                state_key: delayed_event.state_key,
+                event_type: delayed_event.event_type,
+            });
+        }
+
+        let response = GetDelayedEventsResponse {
+            delayed_events: delayed_events,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The POST `/delayed_events/:delay_id` endpoint.
+pub struct UpdateDelayedEvent;
+
+#[derive(Clone, Debug, Deserialize)]
+struct UpdateDelayedEventRequest {
+    action: String,
+}
+
+middleware_chain!(UpdateDelayedEvent, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for UpdateDelayedEvent {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let delay_id = request.extensions.get::<Router>()
+            .expect("Params object is missing")
+            .find("delay_id")
+            .ok_or(ApiError::missing_param("delay_id"))?
+            .to_string();
+
+        let action = match request.get::<bodyparser::Struct<UpdateDelayedEventRequest>>() {
+            Ok(Some(update_request)) => update_request.action,
+            Ok(None) | Err(_) => {
+                let error = ApiError::bad_json(None);
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let clock = ServerClock::from_request(request)?;
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+        let membership_cache = ServerMembershipCache::from_request(request)?;
+        let power_levels_cache = ServerPowerLevelsCache::from_request(request)?;
+
+        let mut delayed_event = DelayedEvent::find(&connection, &delay_id, &user.id)?;
+
+        match &action[..] {
+            "cancel" => delayed_event.cancel(&connection)?,
+            "restart" => delayed_event.restart(&connection, clock.now_millis())?,
+            "send" => {
+                delayed_event.send(
+                    &connection,
+                    &membership_cache,
+                    &power_levels_cache,
+                    &config.domain,
+                    clock.now_millis(),
+                )?;
+            }
+            _ => {
+                let error = ApiError::invalid_param(
+                    "action",
+                    "Must be one of cancel, restart, or send.",
+                );
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        }
+
+        Ok(Response::with((Status::Ok, "{}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    fn send_delayed_message(test: &Test, access_token: &str, room_id: &str, delay: i64) -> String {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/1?access_token={}&delay={}",
+            room_id,
+            access_token,
+            delay
+        );
+
+        let response = test.put(&path, r#"{"msgtype":"m.text","body":"Later"}"#);
+
+        assert_eq!(response.status, Status::Ok);
+
+        response.json().find("delay_id").unwrap().as_str().unwrap().to_string()
+    }
+
+    fn pending_delay_ids(test: &Test, access_token: &str) -> Vec<String> {
+        let path = format!(
+            "/_matrix/client/unstable/org.matrix.msc4140/delayed_events?access_token={}",
+            access_token
+        );
+
+        test.get(&path)
+            .json()
+            .find("delayed_events")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event.find("delay_id").unwrap().as_str().unwrap().to_string())
+            .collect()
+    }
+
+    /// Returns the bodies of the messages in the room, oldest first.
+    fn message_bodies(test: &Test, access_token: &str, room_id: &str) -> Vec<String> {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from=0&dir=f&limit=100&access_token={}",
+            room_id,
+            access_token
+        );
+
+        test.get(&path)
+            .json()
+            .find("chunk")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|event| event.find("type").and_then(Value::as_str) == Some("m.room.message"))
+            .map(|event| {
+                event.find_path(&["content", "body"]).and_then(Value::as_str).unwrap().to_string()
+            })
+            .collect()
+    }
+
+    fn update(test: &Test, access_token: &str, delay_id: &str, action: &str) -> Status {
+        let path = format!(
+            "/_matrix/client/unstable/org.matrix.msc4140/delayed_events/{}?access_token={}",
+            delay_id,
+            access_token
+        );
+
+        test.post(&path, &format!(r#"{{"action":"{}"}}"#, action)).status
+    }
+
+    #[test]
+    fn delayed_event_is_sent_when_due() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let delay_id = send_delayed_message(&test, &access_token, &room_id, 10000);
+
+        assert_eq!(pending_delay_ids(&test, &access_token), vec![delay_id]);
+
+        test.advance_clock(Duration::seconds(5));
+        test.run_jobs();
+
+        assert_eq!(pending_delay_ids(&test, &access_token).len(), 1);
+        assert!(message_bodies(&test, &access_token, &room_id).is_empty());
+
+        test.advance_clock(Duration::seconds(5));
+        test.run_jobs();
+
+        assert!(pending_delay_ids(&test, &access_token).is_empty());
+        assert_eq!(message_bodies(&test, &access_token, &room_id), vec!["Later"]);
+    }
+
+    #[test]
+    fn cancel_delayed_event() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let delay_id = send_delayed_message(&test, &access_token, &room_id, 10000);
+
+        assert_eq!(update(&test, &access_token, &delay_id, "cancel"), Status::Ok);
+        assert!(pending_delay_ids(&test, &access_token).is_empty());
+        assert_eq!(update(&test, &access_token, &delay_id, "send"), Status::NotFound);
+    }
+
+    #[test]
+    fn restart_delayed_event() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let delay_id = send_delayed_message(&test, &access_token, &room_id, 10000);
+
+        test.advance_clock(Duration::seconds(8));
+        assert_eq!(update(&test, &access_token, &delay_id, "restart"), Status::Ok);
+
+        test.advance_clock(Duration::seconds(8));
+        test.run_jobs();
+
+        assert_eq!(pending_delay_ids(&test, &access_token), vec![delay_id]);
+    }
+
+    #[test]
+    fn send_delayed_event_immediately() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let delay_id = send_delayed_message(&test, &access_token, &room_id, 10000);
+
+        assert_eq!(update(&test, &access_token, &delay_id, "send"), Status::Ok);
+        assert!(pending_delay_ids(&test, &access_token).is_empty());
+        assert_eq!(message_bodies(&test, &access_token, &room_id), vec!["Later"]);
+    }
+
+    #[test]
+    fn other_users_cannot_manage_delayed_events() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let other_access_token = test.create_access_token_with_username("alice");
+        let room_id = test.create_room(&access_token);
+        let delay_id = send_delayed_message(&test, &access_token, &room_id, 10000);
+
+        assert_eq!(update(&test, &other_access_token, &delay_id, "cancel"), Status::NotFound);
+        assert!(pending_delay_ids(&test, &other_access_token).is_empty());
+        assert_eq!(pending_delay_ids(&test, &access_token), vec![delay_id]);
+    }
+
+    #[test]
+    fn delay_must_be_in_range() {
+        let test = Test::new();
+        let access_token = test.create_access_token();
+        let room_id = test.create_room(&access_token);
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/1?access_token={}&delay=-1",
+            room_id,
+            access_token
+        );
+
+        let response = test.put(&path, r#"{"msgtype":"m.text","body":"Never"}"#);
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+}
@@ -0,0 +1,8 @@
+//! API endpoints for unstable features that are not yet part of a released version of the spec.
+//!
+//! These are mounted under `/_matrix/client/unstable/` using the namespace of the proposal that
+//! describes them.
+
+pub use self::delayed_events::{GetDelayedEvents, UpdateDelayedEvent};
+
+mod delayed_events;
@@ -0,0 +1,34 @@
+//! Application services, such as bridges, that act for the users in a namespace of their own.
+
+use iron::typemap::Key;
+use ruma_identifiers::UserId;
+
+/// An application service registered in the `app_services` configuration attribute.
+#[derive(Clone, Debug, Deserialize, RustcDecodable)]
+pub struct AppService {
+    /// A name for the application service, unique among those on this server.
+    pub id: String,
+    /// The access token the application service authenticates its requests with.
+    pub as_token: String,
+    /// The localpart of the user the application service acts as by default.
+    pub sender_localpart: String,
+    /// The prefix of the localparts of the users the application service manages, e.g. `irc_`.
+    pub user_prefix: String,
+}
+
+impl AppService {
+    /// Whether or not the local user is the application service's sender or in its namespace.
+    pub fn manages_user(&self, user_id: &UserId, domain: &str) -> bool {
+        if user_id.hostname().to_string() != domain {
+            return false;
+        }
+
+        let localpart = user_id.localpart();
+
+        localpart == self.sender_localpart || localpart.starts_with(&self.user_prefix)
+    }
+}
+
+impl Key for AppService {
+    type Value = AppService;
+}
@@ -1,68 +1,16 @@
-//! User-interactive authentication.
+//! Checking the credentials users give to prove who they are.
+
+use std::convert::TryFrom;
 
 use diesel::pg::PgConnection;
-use iron::Response;
-use iron::modifier::Modifier;
 use ruma_identifiers::UserId;
-use serde::{Serialize, Serializer};
+use serde_json::Value;
 
-use error::{ApiError, ApiErrorCode};
+use config::Config;
+use error::ApiError;
+use sms::to_msisdn;
 use user::User;
-
-/// A set of authorization flows the user can follow to authenticate a request.
-#[derive(Debug, Serialize)]
-pub struct InteractiveAuth {
-    flows: Vec<Flow>,
-}
-
-impl InteractiveAuth {
-    /// Creates a new `InteractiveAuth` from the given flows.
-    pub fn new(flows: Vec<Flow>) -> Self {
-        InteractiveAuth {
-            flows: flows,
-        }
-    }
-}
-
-impl<'a> Modifier<Response> for &'a InteractiveAuth {
-    fn modify(self, response: &mut Response) {
-        response.status = Some(ApiErrorCode::Forbidden.status_code());
-        response.body = Some(Box::new(r#"{"flows":[{"stages":["m.login.dummy"]}]}"#));
-    }
-}
-
-/// A list of `AuthType`s that satisfy authentication requirements.
-#[derive(Debug, Serialize)]
-pub struct Flow {
-    #[serde(rename="stages")]
-    auth_types: Vec<AuthType>,
-}
-
-impl Flow {
-    /// Creates a new `Flow` from the given auth types.
-    pub fn new(auth_types: Vec<AuthType>) -> Self {
-        Flow {
-            auth_types: auth_types,
-        }
-    }
-}
-
-/// An individiual authentication mechanism to be used in a `Flow`.
-#[derive(Clone, Debug)]
-pub enum AuthType {
-    /// m.login.password
-    Password,
-}
-
-impl Serialize for AuthType {
-    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error> where S: Serializer {
-        let value = match *self {
-            AuthType::Password => "m.login.password",
-        };
-
-        serializer.serialize_str(value)
-    }
-}
+use user_threepid::UserThreepid;
 
 /// Authentication parameters submitted by the user in a request.
 #[derive(Clone, Debug)]
@@ -82,9 +30,74 @@ pub struct PasswordAuthParams {
 
 impl AuthParams {
     /// Attempts to authenticate as a user with the supplied credentials.
-    pub fn authenticate(&self, connection: &PgConnection) -> Result<User, ApiError> {
+    pub fn authenticate(&self, connection: &PgConnection, config: &Config)
+    -> Result<User, ApiError> {
         let &AuthParams::Password(ref credentials) = self;
 
-        User::verify(connection, &credentials.user_id, &credentials.password)
+        User::verify(
+            connection,
+            &credentials.user_id,
+            &credentials.password,
+            &config.argon2_params,
+        )
+    }
+}
+
+impl PasswordAuthParams {
+    /// Reads the user and password from an `m.login.password` auth object.
+    ///
+    /// The user is given either as `user`, a user ID or a localpart on this homeserver, or as an
+    /// `identifier`. Returns `None` if either is missing or the user can't be found.
+    pub fn from_json(json: &Value, config: &Config, connection: &PgConnection)
+    -> Option<PasswordAuthParams> {
+        let user_id = match json.find("identifier") {
+            Some(identifier) => get_user_id_from_identifier(identifier, config, connection),
+            None => match json.find("user").and_then(|username_json| username_json.as_str()) {
+                Some(username) => parse_user_id(username, config),
+                None => Err(()),
+            },
+        };
+        let password = json.find("password").and_then(|password_json| password_json.as_str());
+
+        match (user_id, password) {
+            (Ok(user_id), Some(password)) => Some(PasswordAuthParams {
+                password: password.to_string(),
+                user_id: user_id,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Resolves an `m.id.user`, `m.id.thirdparty`, or `m.id.phone` user identifier to a user ID.
+fn get_user_id_from_identifier(identifier: &Value, config: &Config, connection: &PgConnection)
+-> Result<UserId, ()> {
+    let field = |name| identifier.find(name).and_then(|value| value.as_str()).ok_or(());
+
+    match field("type")? {
+        "m.id.user" => parse_user_id(field("user")?, config),
+        "m.id.thirdparty" => {
+            match UserThreepid::find_user_id(connection, field("medium")?, field("address")?) {
+                Ok(Some(user_id)) => Ok(user_id),
+                _ => Err(()),
+            }
+        }
+        "m.id.phone" => {
+            let msisdn = to_msisdn(field("country")?, field("phone")?).map_err(|_| ())?;
+
+            match UserThreepid::find_user_id(connection, "msisdn", &msisdn) {
+                Ok(Some(user_id)) => Ok(user_id),
+                _ => Err(()),
+            }
+        }
+        _ => Err(()),
+    }
+}
+
+/// Parses either a full user ID or a localpart on this homeserver.
+fn parse_user_id(username: &str, config: &Config) -> Result<UserId, ()> {
+    match UserId::try_from(username) {
+        Ok(user_id) => Ok(user_id),
+        Err(_) => UserId::try_from(&format!("@{}:{}", username, &config.domain)).map_err(|_| ()),
     }
 }
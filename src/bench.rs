@@ -0,0 +1,412 @@
+//! A load-generating benchmark harness for a running Ruma server.
+//!
+//! The harness registers a throwaway user, sets up a room with some history, and then measures
+//! the latency of the hottest client API endpoints. Results can be saved as a baseline and later
+//! runs compared against it, so regressions in the database layer show up as a failing run.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hyper::Client;
+use hyper::header::ContentType;
+use hyper::method::Method;
+use rand::{Rng, thread_rng};
+use serde_json::{Value, from_str, to_string_pretty};
+
+use error::CliError;
+
+/// The number of messages sent to the benchmark room before measuring, so pagination has data.
+const HISTORY_SIZE: usize = 100;
+
+/// A counter used to make transaction IDs unique across threads.
+static TRANSACTION_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// An endpoint to benchmark.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Scenario {
+    /// `POST /login`.
+    Login,
+    /// `GET /rooms/:room_id/messages`, paginating backwards from the end of the room.
+    Messages,
+    /// `PUT /rooms/:room_id/send/m.room.message/:transaction_id`.
+    SendMessage,
+    /// `GET /sync` with a `since` token and no timeout.
+    IncrementalSync,
+}
+
+/// How to run the benchmarks.
+#[derive(Clone, Debug)]
+pub struct BenchOptions {
+    /// The base URL of the server, e.g. `http://localhost:3000`.
+    pub url: String,
+    /// The number of requests to make for each scenario.
+    pub requests: usize,
+    /// The number of requests to have in flight at once.
+    pub concurrency: usize,
+    /// The scenarios to run.
+    pub scenarios: Vec<Scenario>,
+}
+
+/// Latency measurements for one scenario, in milliseconds.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ScenarioResult {
+    /// The number of requests that returned a successful status code.
+    pub successes: usize,
+    /// The number of requests that failed.
+    pub failures: usize,
+    /// The mean latency.
+    pub mean: f64,
+    /// The median latency.
+    pub p50: f64,
+    /// The 95th percentile latency.
+    pub p95: f64,
+    /// The 99th percentile latency.
+    pub p99: f64,
+    /// The slowest request.
+    pub max: f64,
+}
+
+/// The state shared by every request in a benchmark run.
+struct Fixture {
+    access_token: String,
+    client: Client,
+    password: String,
+    room_id: String,
+    since: String,
+    url: String,
+    username: String,
+}
+
+impl FromStr for Scenario {
+    type Err = CliError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "login" => Ok(Scenario::Login),
+            "messages" => Ok(Scenario::Messages),
+            "send" => Ok(Scenario::SendMessage),
+            "sync" => Ok(Scenario::IncrementalSync),
+            _ => Err(CliError::new(format!(
+                "Unknown scenario {}. Valid scenarios are login, messages, send, and sync.",
+                s
+            ))),
+        }
+    }
+}
+
+impl Scenario {
+    /// All scenarios, in the order they are run by default.
+    pub fn all() -> Vec<Scenario> {
+        vec![Scenario::Login, Scenario::SendMessage, Scenario::IncrementalSync, Scenario::Messages]
+    }
+
+    /// The name used for the scenario on the command line and in saved results.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Scenario::Login => "login",
+            Scenario::Messages => "messages",
+            Scenario::SendMessage => "send",
+            Scenario::IncrementalSync => "sync",
+        }
+    }
+}
+
+impl ScenarioResult {
+    fn from_samples(mut samples: Vec<Duration>, failures: usize) -> Self {
+        samples.sort();
+
+        let millis: Vec<f64> = samples.iter().map(|duration| {
+            duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0
+        }).collect();
+
+        let percentile = |p: f64| -> f64 {
+            if millis.is_empty() {
+                return 0.0;
+            }
+
+            let index = ((millis.len() - 1) as f64 * p).round() as usize;
+
+            millis[index]
+        };
+
+        ScenarioResult {
+            successes: millis.len(),
+            failures: failures,
+            mean: if millis.is_empty() {
+                0.0
+            } else {
+                millis.iter().fold(0.0, |sum, ms| sum + ms) / millis.len() as f64
+            },
+            p50: percentile(0.5),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+            max: millis.last().cloned().unwrap_or(0.0),
+        }
+    }
+}
+
+impl Fixture {
+    /// Registers a new user and creates a room with some history for it.
+    fn set_up(url: &str, scenarios: &[Scenario]) -> Result<Fixture, CliError> {
+        let mut fixture = Fixture {
+            access_token: String::new(),
+            client: Client::new(),
+            password: thread_rng().gen_ascii_chars().take(16).collect(),
+            room_id: String::new(),
+            since: String::new(),
+            url: url.trim_right_matches('/').to_string(),
+            username: format!(
+                "bench_{}",
+                thread_rng().gen_ascii_chars().take(8).collect::<String>().to_lowercase()
+            ),
+        };
+
+        let registration = fixture.request_json(
+            Method::Post,
+            "/register",
+            &format!(
+                r#"{{"auth":{{"type":"m.login.dummy"}},"username":"{}","password":"{}"}}"#,
+                fixture.username,
+                fixture.password
+            ),
+        )?;
+        fixture.access_token = json_string(&registration, "access_token")?;
+
+        let room = fixture.request_json(
+            Method::Post,
+            &fixture.authenticated("/createRoom"),
+            r#"{"visibility":"private"}"#,
+        )?;
+        fixture.room_id = json_string(&room, "room_id")?;
+
+        for _ in 0..HISTORY_SIZE {
+            if !fixture.request(Scenario::SendMessage)? {
+                return Err(CliError::new("Failed to send messages to the benchmark room."));
+            }
+        }
+
+        // Incremental syncs start from, and pagination goes backwards from, the end of the room.
+        if scenarios.contains(&Scenario::IncrementalSync) ||
+            scenarios.contains(&Scenario::Messages) {
+            let sync = fixture.request_json(Method::Get, &fixture.authenticated("/sync"), "")?;
+            fixture.since = json_string(&sync, "next_batch")?;
+        }
+
+        Ok(fixture)
+    }
+
+    /// Adds the fixture's access token to a client API path.
+    fn authenticated(&self, path: &str) -> String {
+        let separator = if path.contains('?') { '&' } else { '?' };
+
+        format!("{}{}access_token={}", path, separator, self.access_token)
+    }
+
+    /// Makes a request for the given scenario and returns whether or not it succeeded.
+    fn request(&self, scenario: Scenario) -> Result<bool, CliError> {
+        let (method, path, body) = match scenario {
+            Scenario::Login => (
+                Method::Post,
+                "/login".to_string(),
+                format!(
+                    r#"{{"auth":{{"type":"m.login.password","user":"{}","password":"{}"}}}}"#,
+                    self.username,
+                    self.password
+                ),
+            ),
+            Scenario::Messages => (
+                Method::Get,
+                self.authenticated(&format!(
+                    "/rooms/{}/messages?from={}&dir=b&limit=20",
+                    self.room_id,
+                    self.since
+                )),
+                String::new(),
+            ),
+            Scenario::SendMessage => (
+                Method::Put,
+                self.authenticated(&format!(
+                    "/rooms/{}/send/m.room.message/bench{}",
+                    self.room_id,
+                    TRANSACTION_COUNTER.fetch_add(1, Ordering::SeqCst)
+                )),
+                r#"{"msgtype":"m.text","body":"Benchmark message"}"#.to_string(),
+            ),
+            Scenario::IncrementalSync => (
+                Method::Get,
+                self.authenticated(&format!("/sync?since={}&timeout=0", self.since)),
+                String::new(),
+            ),
+        };
+
+        let response = self.client
+            .request(method, &format!("{}/_matrix/client/r0{}", self.url, path))
+            .header(ContentType::json())
+            .body(&body[..])
+            .send()?;
+
+        Ok(response.status.is_success())
+    }
+
+    /// Makes a request that must succeed and returns the JSON response body.
+    fn request_json(&self, method: Method, path: &str, body: &str) -> Result<Value, CliError> {
+        let mut response = self.client
+            .request(method, &format!("{}/_matrix/client/r0{}", self.url, path))
+            .header(ContentType::json())
+            .body(body)
+            .send()?;
+
+        let mut contents = String::new();
+        response.read_to_string(&mut contents)?;
+
+        if !response.status.is_success() {
+            return Err(CliError::new(format!(
+                "Benchmark setup request to {} failed with {}: {}",
+                path,
+                response.status,
+                contents
+            )));
+        }
+
+        from_str(&contents).map_err(CliError::from)
+    }
+}
+
+/// Runs the benchmarks and returns the results for each scenario.
+pub fn run(options: &BenchOptions) -> Result<BTreeMap<String, ScenarioResult>, CliError> {
+    let fixture = Arc::new(Fixture::set_up(&options.url, &options.scenarios)?);
+    let concurrency = if options.concurrency == 0 { 1 } else { options.concurrency };
+    let mut results = BTreeMap::new();
+
+    for &scenario in &options.scenarios {
+        println!("Running {} {} requests...", options.requests, scenario.name());
+
+        let mut threads = Vec::with_capacity(concurrency);
+
+        for worker in 0..concurrency {
+            let fixture = fixture.clone();
+            let requests = options.requests / concurrency +
+                if worker < options.requests % concurrency { 1 } else { 0 };
+
+            threads.push(thread::spawn(move || {
+                let mut samples = Vec::with_capacity(requests);
+                let mut failures = 0;
+
+                for _ in 0..requests {
+                    let start = Instant::now();
+
+                    match fixture.request(scenario) {
+                        Ok(true) => samples.push(start.elapsed()),
+                        Ok(false) | Err(_) => failures += 1,
+                    }
+                }
+
+                (samples, failures)
+            }));
+        }
+
+        let mut samples = Vec::with_capacity(options.requests);
+        let mut failures = 0;
+
+        for thread in threads {
+            match thread.join() {
+                Ok((thread_samples, thread_failures)) => {
+                    samples.extend(thread_samples);
+                    failures += thread_failures;
+                }
+                Err(_) => return Err(CliError::new("A benchmark thread panicked.")),
+            }
+        }
+
+        results.insert(
+            scenario.name().to_string(),
+            ScenarioResult::from_samples(samples, failures),
+        );
+    }
+
+    Ok(results)
+}
+
+/// Prints a table of results.
+pub fn print_results(results: &BTreeMap<String, ScenarioResult>) {
+    println!(
+        "{:<10} {:>8} {:>8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "scenario", "ok", "failed", "mean ms", "p50 ms", "p95 ms", "p99 ms", "max ms"
+    );
+
+    for (name, result) in results {
+        println!(
+            "{:<10} {:>8} {:>8} {:>10.2} {:>10.2} {:>10.2} {:>10.2} {:>10.2}",
+            name,
+            result.successes,
+            result.failures,
+            result.mean,
+            result.p50,
+            result.p95,
+            result.p99,
+            result.max
+        );
+    }
+}
+
+/// Writes results to a file so they can be used as a baseline for later runs.
+pub fn save_results(
+    path: &str,
+    results: &BTreeMap<String, ScenarioResult>,
+) -> Result<(), CliError> {
+    let json = to_string_pretty(results)?;
+    let mut file = File::create(path)?;
+
+    file.write_all(json.as_bytes())?;
+
+    Ok(())
+}
+
+/// Compares results against a saved baseline.
+///
+/// Returns a description of each scenario whose median latency grew by more than `tolerance`
+/// (e.g. 0.2 for 20%) or which failed requests that succeeded in the baseline.
+pub fn compare_results(
+    path: &str,
+    results: &BTreeMap<String, ScenarioResult>,
+    tolerance: f64,
+) -> Result<Vec<String>, CliError> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+
+    let baseline: BTreeMap<String, ScenarioResult> = from_str(&contents)?;
+    let mut regressions = Vec::new();
+
+    for (name, result) in results {
+        if let Some(previous) = baseline.get(name) {
+            if result.p50 > previous.p50 * (1.0 + tolerance) {
+                regressions.push(format!(
+                    "{}: median latency went from {:.2} ms to {:.2} ms",
+                    name,
+                    previous.p50,
+                    result.p50
+                ));
+            }
+
+            if result.failures > 0 && previous.failures == 0 {
+                regressions.push(format!("{}: {} requests failed", name, result.failures));
+            }
+        }
+    }
+
+    Ok(regressions)
+}
+
+/// Extracts a string field from a JSON object.
+fn json_string(json: &Value, field: &str) -> Result<String, CliError> {
+    json.find(field)
+        .and_then(|value| value.as_str())
+        .map(|value| value.to_string())
+        .ok_or(CliError::new(format!("Response did not contain {}.", field)))
+}
@@ -0,0 +1,177 @@
+//! Benchmarks for the code on Ruma's hot paths, run with `cargo bench`.
+//!
+//! The endpoint benchmarks go through the whole Iron stack against the test database, the same
+//! way the tests do, so they catch regressions in the database layer as well as in the handlers.
+//! `ruma bench` measures a running server instead.
+
+use std::convert::TryFrom;
+
+use diesel::pg::data_types::PgTimestamp;
+use libtest::Bencher;
+use ruma_identifiers::{EventId, RoomId, UserId};
+use serde_json::{Value, from_str, to_string};
+
+use crypto::{Argon2Params, hash_password, verify_password};
+use event::Event;
+use filter::Filter;
+use test::Test;
+
+/// The password that is hashed.
+const PASSWORD: &'static str = "correct horse battery staple";
+
+/// The number of messages in the room the sync and pagination benchmarks read from.
+const MESSAGES: usize = 50;
+
+/// Builds a message event as loaded from the database, sent by alice unless it's one of every
+/// tenth message, which spam sends.
+fn message_event(index: usize) -> Event {
+    let sender = if index % 10 == 0 { "@spam:ruma.test" } else { "@alice:ruma.test" };
+
+    Event {
+        id: EventId::try_from(&format!("${}:ruma.test", index)[..]).unwrap(),
+        ordering: index as i64,
+        room_id: RoomId::try_from("!bench:ruma.test").unwrap(),
+        user_id: UserId::try_from(sender).unwrap(),
+        event_type: "m.room.message".to_string(),
+        state_key: None,
+        content: format!(r#"{{"body":"Message {}","msgtype":"m.text"}}"#, index),
+        extra_content: None,
+        replaces_state: None,
+        created_at: PgTimestamp(index as i64 * 1_000_000),
+    }
+}
+
+/// Creates a room as carl with `MESSAGES` messages in it, returning carl's access token, the
+/// room ID, and a sync token for the end of the room.
+fn room_with_messages(test: &Test) -> (String, String, String) {
+    let access_token = test.create_access_token();
+    let room_id = test.create_room(&access_token);
+
+    for index in 0..MESSAGES {
+        send_message(test, &access_token, &room_id, &format!("setup{}", index));
+    }
+
+    let since = test.get(&format!("/_matrix/client/r0/sync?access_token={}", access_token))
+        .json()
+        .find("next_batch")
+        .and_then(Value::as_str)
+        .unwrap()
+        .to_string();
+
+    (access_token, room_id, since)
+}
+
+/// Sends a text message as the user with the given access token.
+fn send_message(test: &Test, access_token: &str, room_id: &str, transaction_id: &str) {
+    let response = test.put(
+        &format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/{}?access_token={}",
+            room_id,
+            transaction_id,
+            access_token
+        ),
+        r#"{"body":"Hello","msgtype":"m.text"}"#,
+    );
+
+    assert!(response.json().find("event_id").is_some());
+}
+
+#[bench]
+fn hash_password_with_default_params(b: &mut Bencher) {
+    let params = Argon2Params::default();
+
+    b.iter(|| hash_password(&params, PASSWORD).unwrap());
+}
+
+#[bench]
+fn verify_password_with_default_params(b: &mut Bencher) {
+    let hash = hash_password(&Argon2Params::default(), PASSWORD).unwrap();
+
+    b.iter(|| assert!(verify_password(&hash, PASSWORD).unwrap()));
+}
+
+#[bench]
+fn serialize_message_event(b: &mut Bencher) {
+    let event = message_event(1);
+
+    b.iter(|| to_string(&event.to_json().unwrap()).unwrap());
+}
+
+#[bench]
+fn filter_timeline(b: &mut Bencher) {
+    let filter: Filter = from_str(r#"{
+        "room": {
+            "timeline": {
+                "limit": 20,
+                "not_senders": ["@spam:ruma.test"],
+                "types": ["m.room.*"]
+            }
+        }
+    }"#).unwrap();
+    let room_id = RoomId::try_from("!bench:ruma.test").unwrap();
+    let events: Vec<Value> = (0..100)
+        .map(|index| message_event(index).to_json().unwrap())
+        .collect();
+
+    b.iter(|| filter.filter_timeline(&room_id, events.clone()));
+}
+
+#[bench]
+fn login(b: &mut Bencher) {
+    let test = Test::new();
+
+    test.create_access_token();
+
+    b.iter(|| {
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"auth": {"type": "m.login.password", "user": "carl", "password": "secret"}}"#,
+        );
+
+        assert!(response.json().find("access_token").is_some());
+    });
+}
+
+#[bench]
+fn send_event(b: &mut Bencher) {
+    let test = Test::new();
+    let access_token = test.create_access_token();
+    let room_id = test.create_room(&access_token);
+    let mut transaction = 0;
+
+    b.iter(|| {
+        transaction += 1;
+
+        send_message(&test, &access_token, &room_id, &transaction.to_string());
+    });
+}
+
+#[bench]
+fn incremental_sync(b: &mut Bencher) {
+    let test = Test::new();
+    let (access_token, room_id, since) = room_with_messages(&test);
+
+    send_message(&test, &access_token, &room_id, "new");
+
+    let path = format!(
+        "/_matrix/client/r0/sync?since={}&timeout=0&access_token={}",
+        since,
+        access_token
+    );
+
+    b.iter(|| assert!(test.get(&path).json().find("next_batch").is_some()));
+}
+
+#[bench]
+fn paginate_messages(b: &mut Bencher) {
+    let test = Test::new();
+    let (access_token, room_id, from) = room_with_messages(&test);
+    let path = format!(
+        "/_matrix/client/r0/rooms/{}/messages?from={}&dir=b&limit=20&access_token={}",
+        room_id,
+        from,
+        access_token
+    );
+
+    b.iter(|| assert!(test.get(&path).json().find("chunk").is_some()));
+}
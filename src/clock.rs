@@ -0,0 +1,97 @@
+//! Sources of the current time.
+//!
+//! Handlers ask the `Clock` attached to the request for the current time instead of calling
+//! `UTC::now` directly, which lets the test suite move time forward deterministically.
+
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Timelike, UTC};
+use iron::{Plugin, Request};
+use iron::typemap::Key;
+use persistent::Read;
+
+use error::ApiError;
+
+/// A source of the current time.
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> DateTime<UTC>;
+
+    /// Returns the current time as milliseconds since the Unix epoch.
+    fn now_millis(&self) -> i64 {
+        let now = self.now();
+
+        now.timestamp() * 1000 + (now.nanosecond() / 1_000_000) as i64
+    }
+}
+
+/// A `Clock` backed by the operating system's clock.
+#[derive(Debug)]
+pub struct SystemClock;
+
+/// A `Clock` that only moves forward when told to.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<DateTime<UTC>>,
+}
+
+/// An Iron plugin for attaching a `Clock` to an Iron request.
+pub struct ServerClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<UTC> {
+        UTC::now()
+    }
+}
+
+impl ManualClock {
+    /// Creates a new `ManualClock` stopped at the given time.
+    pub fn new(now: DateTime<UTC>) -> Self {
+        ManualClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    /// Moves the clock forward by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().expect("ManualClock mutex was poisoned");
+
+        *now = *now + duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<UTC> {
+        *self.now.lock().expect("ManualClock mutex was poisoned")
+    }
+}
+
+impl ServerClock {
+    /// Extract the `Clock` stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<Arc<Clock>>, ApiError> {
+        request.get::<Read<ServerClock>>().map_err(ApiError::from)
+    }
+}
+
+impl Key for ServerClock {
+    type Value = Arc<Clock>;
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, UTC};
+
+    use super::{Clock, ManualClock};
+
+    #[test]
+    fn manual_clock_only_moves_when_advanced() {
+        let start = UTC::now();
+        let clock = ManualClock::new(start);
+
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::seconds(30));
+
+        assert_eq!(clock.now(), start + Duration::seconds(30));
+    }
+}
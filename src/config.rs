@@ -1,49 +1,207 @@
 //! User-facing configuration.
 
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
+use std::fs::{File, OpenOptions, create_dir_all};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use base64::decode;
 use iron::{Plugin, Request};
 use iron::typemap::Key;
 use persistent::Read as PersistentRead;
+use ruma_identifiers::UserId;
 use serde_json;
 use serde_yaml;
 use toml;
+use url::Url;
 
+use app_service::AppService;
+use crypto::{Argon2Params, generate_macaroon_secret_key};
 use error::{ApiError, CliError};
+use http_client::{DEFAULT_OUTBOUND_DNS_CACHE_SECS, DEFAULT_OUTBOUND_HTTP_TIMEOUT_SECS};
+use media::{DEFAULT_MAX_IMAGE_PIXELS, DEFAULT_MAX_UPLOAD_SIZE};
+use middleware::DEFAULT_SLOW_REQUEST_THRESHOLDS;
+use receipt::DEFAULT_EPHEMERAL_BATCH_MILLIS;
+use room::{DEFAULT_ROOM_VERSION, KNOWN_ROOM_VERSIONS};
+use url_preview::{DEFAULT_URL_PREVIEW_IP_RANGE_BLACKLIST, IpRange};
 
 /// The user's configuration as loaded from the configuration file.
 ///
 /// Refer to `Config` for the description of the fields.
 #[derive(Deserialize, RustcDecodable)]
 struct RawConfig {
+    admins: Option<Vec<String>>,
+    allow_guest_access: Option<bool>,
+    allow_password_changes: Option<bool>,
+    allow_threepid_changes: Option<bool>,
+    app_services: Option<Vec<AppService>>,
+    argon2_lanes: Option<u32>,
+    argon2_memory_kib: Option<u32>,
+    argon2_passes: Option<u32>,
     bind_address: Option<String>,
     bind_port: Option<String>,
+    client_base_url: Option<String>,
+    default_room_version: Option<String>,
     domain: String,
+    encrypt_private_rooms: Option<bool>,
+    ephemeral_batch_millis: Option<u64>,
+    identity_server_url: Option<String>,
+    invites_per_hour: Option<u64>,
     macaroon_secret_key: String,
+    max_image_pixels: Option<u64>,
+    max_media_storage_per_user: Option<u64>,
+    max_reactions_per_user: Option<u64>,
+    max_relations_per_event: Option<u64>,
+    max_upload_size: Option<u64>,
+    media_directory: Option<String>,
+    outbound_dns_cache_secs: Option<u64>,
+    outbound_http_timeout_secs: Option<u64>,
+    outbound_https_only: Option<bool>,
+    outbound_tls_ca_file: Option<String>,
+    outbound_tls_verify: Option<bool>,
     postgres_url: String,
+    recaptcha_private_key: Option<String>,
+    recaptcha_public_key: Option<String>,
+    reject_unencrypted_messages: Option<bool>,
+    report_stats_url: Option<String>,
+    room_complexity_limit: Option<f64>,
+    rooms_created_per_day: Option<u64>,
+    slow_request_thresholds: Option<HashMap<String, u64>>,
+    sms_gateway_url: Option<String>,
+    strip_image_metadata: Option<bool>,
+    supported_room_versions: Option<Vec<String>>,
+    upload_content_types: Option<Vec<String>>,
+    url_preview_enabled: Option<bool>,
+    url_preview_ip_range_blacklist: Option<Vec<String>>,
 }
 
 /// Server configuration provided by the user.
 #[derive(Clone, Debug)]
 pub struct Config {
+    /// Users who may access the server's administrative endpoints.
+    pub admins: Vec<UserId>,
+    /// Whether or not clients may register guest accounts, which need no password but can only
+    /// join rooms that allow guest access. Defaults to false.
+    pub allow_guest_access: bool,
+    /// Whether or not users may change their passwords. Defaults to true.
+    pub allow_password_changes: bool,
+    /// Whether or not users may add and remove the email addresses and phone numbers on their
+    /// accounts. Defaults to true.
+    pub allow_threepid_changes: bool,
+    /// The application services, such as bridges, that may use the endpoints reserved for them.
+    pub app_services: Vec<AppService>,
+    /// The Argon2 parameters used to hash passwords. Defaults to `Argon2Params::default()`.
+    /// Stored hashes made with other parameters or algorithms are replaced on the user's next
+    /// successful login.
+    pub argon2_params: Argon2Params,
     /// The network address where the server should listen for connections. Defaults to 127.0.0.1.
     pub bind_address: String,
     /// The network port where the server should listen for connections. Defaults to 3000.
     pub bind_port: String,
+    /// The URL clients should use to reach the server, advertised by
+    /// `/.well-known/matrix/client` and in login responses. Needed when clients can't reach the
+    /// server at `domain`. Nothing is advertised if this is not set.
+    pub client_base_url: Option<String>,
+    /// The room version used for new rooms when the client doesn't ask for one. Must be one of
+    /// `supported_room_versions`. Defaults to `DEFAULT_ROOM_VERSION`.
+    pub default_room_version: String,
     /// The DNS name where clients can reach the server. Used as the hostname portion of user IDs.
     pub domain: String,
+    /// Whether or not rooms created with the private chat presets start out encrypted. Defaults to
+    /// false.
+    pub encrypt_private_rooms: bool,
+    /// How long read receipts and typing changes are collected before they are saved and syncs
+    /// see them, in milliseconds. Only the latest receipt of each user in each room is saved per
+    /// batch. 0 saves every change right away. Defaults to `DEFAULT_EPHEMERAL_BATCH_MILLIS`.
+    pub ephemeral_batch_millis: u64,
+    /// The URL of the identity server clients should use, advertised along with
+    /// `client_base_url`, which must be set too.
+    pub identity_server_url: Option<String>,
+    /// The most invites each user other than admins may send in an hour, counting invites sent
+    /// when creating rooms. Unlimited if this is not set. Admins can override it per user.
+    pub invites_per_hour: Option<u64>,
     /// The secret key used for generating
     /// [Macaroons](https://research.google.com/pubs/pub41892.html). Must be 32
     /// cryptographically random bytes, encoded as a Base64 string. Changing this value will
     /// invalidate any previously generated macaroons.
     pub macaroon_secret_key: Vec<u8>,
+    /// The most pixels an image may have for Ruma to decode it, for thumbnails and URL previews.
+    /// Defaults to `DEFAULT_MAX_IMAGE_PIXELS`.
+    pub max_image_pixels: u64,
+    /// The most bytes of files each user may have in the content repository, counting everything
+    /// they have uploaded. Unlimited if this is not set.
+    pub max_media_storage_per_user: Option<u64>,
+    /// The most reactions, *m.annotation* relations, each user may send to one event. Unlimited if
+    /// this is not set.
+    pub max_reactions_per_user: Option<u64>,
+    /// The most events that may relate to one event through `m.relates_to`, such as reactions,
+    /// edits, and threaded replies. Unlimited if this is not set.
+    pub max_relations_per_event: Option<u64>,
+    /// The largest file users may upload to the content repository, in bytes. Defaults to
+    /// `DEFAULT_MAX_UPLOAD_SIZE`.
+    pub max_upload_size: u64,
+    /// The directory uploaded files are kept in. Defaults to `media` in the working directory.
+    pub media_directory: String,
+    /// How long the addresses of hosts that outbound HTTP requests are sent to are reused before
+    /// they are looked up again, in seconds. Defaults to `DEFAULT_OUTBOUND_DNS_CACHE_SECS`.
+    pub outbound_dns_cache_secs: u64,
+    /// How long outbound HTTP requests, e.g. to push gateways and identity servers, may wait to
+    /// look up a host, connect, or send or receive data before giving up, in seconds. Defaults to
+    /// `DEFAULT_OUTBOUND_HTTP_TIMEOUT_SECS`.
+    pub outbound_http_timeout_secs: u64,
+    /// Whether or not outbound HTTP requests are refused unless they use HTTPS. Defaults to false.
+    pub outbound_https_only: bool,
+    /// A PEM file of CA certificates to trust for outbound HTTPS requests, in addition to the
+    /// system's.
+    pub outbound_tls_ca_file: Option<String>,
+    /// Whether or not outbound HTTPS requests verify that the server's certificate is trusted and
+    /// names the host. Defaults to true.
+    pub outbound_tls_verify: bool,
     /// A [PostgreSQL connection string](http://www.postgresql.org/docs/current/static/libpq-connect.html#LIBPQ-CONNSTRING)
     /// for Ruma's PostgreSQL database.
     pub postgres_url: String,
+    /// The secret key for checking reCAPTCHA responses. If this is set, registering an account
+    /// requires completing a reCAPTCHA.
+    pub recaptcha_private_key: Option<String>,
+    /// The site key clients use to show the reCAPTCHA. Must be set if and only if
+    /// `recaptcha_private_key` is.
+    pub recaptcha_public_key: Option<String>,
+    /// Whether or not plaintext *m.room.message* events from local clients are refused in rooms
+    /// that have enabled encryption. Defaults to true.
+    pub reject_unencrypted_messages: bool,
+    /// A URL to periodically send anonymous usage statistics to. Statistics are not reported
+    /// unless this is set.
+    pub report_stats_url: Option<String>,
+    /// The highest room complexity, as computed by `Room::complexity`, that users other than
+    /// admins may join. Any room can be joined if this is not set.
+    pub room_complexity_limit: Option<f64>,
+    /// The most rooms each user other than admins may create in a day. Unlimited if this is not
+    /// set. Admins can override it per user.
+    pub rooms_created_per_day: Option<u64>,
+    /// How long requests to each class of endpoint may take, in milliseconds, before they are
+    /// logged as slow. Defaults to `DEFAULT_SLOW_REQUEST_THRESHOLDS`.
+    pub slow_request_thresholds: HashMap<String, u64>,
+    /// A URL that SMS messages, such as phone number verification codes, are posted to as JSON.
+    /// Phone numbers can't be verified unless this is set.
+    pub sms_gateway_url: Option<String>,
+    /// Whether or not metadata such as EXIF, which can include where a photo was taken, is removed
+    /// from uploaded JPEG and PNG images before they are stored. Defaults to true.
+    pub strip_image_metadata: bool,
+    /// The room versions clients may create rooms with. Each must be one of
+    /// `KNOWN_ROOM_VERSIONS`, which is also the default.
+    pub supported_room_versions: Vec<String>,
+    /// The MIME types users may upload, which may end in `/*` to allow every subtype, e.g.
+    /// `image/*`. Any type may be uploaded if this is not set.
+    pub upload_content_types: Option<Vec<String>>,
+    /// Whether or not the server fetches web pages to preview them for clients. Defaults to false.
+    pub url_preview_enabled: bool,
+    /// The IP ranges, in CIDR notation, that web pages may not be fetched from for previews.
+    /// Defaults to `DEFAULT_URL_PREVIEW_IP_RANGE_BLACKLIST`.
+    pub url_preview_ip_range_blacklist: Vec<IpRange>,
 }
 
 impl Config {
@@ -93,6 +251,11 @@ impl Config {
             return Err(CliError::new("No configuration file was found."));
         }
 
+        Self::from_raw(config)
+    }
+
+    /// Validate a `RawConfig` and fill in defaults.
+    fn from_raw(config: RawConfig) -> Result<Config, CliError> {
         let macaroon_secret_key = match decode(&config.macaroon_secret_key) {
             Ok(bytes) => match bytes.len() {
                 32 => bytes,
@@ -129,15 +292,392 @@ impl Config {
             }
         };
 
+        let mut admins = Vec::new();
+
+        for admin in config.admins.unwrap_or(Vec::new()) {
+            match UserId::try_from(&admin) {
+                Ok(user_id) => admins.push(user_id),
+                Err(_) => {
+                    return Err(CliError::new(format!("{} in admins is not a valid user ID.", admin)));
+                }
+            }
+        }
+
+        let mut slow_request_thresholds: HashMap<String, u64> = DEFAULT_SLOW_REQUEST_THRESHOLDS
+            .iter()
+            .map(|&(class, threshold)| (class.to_string(), threshold))
+            .collect();
+
+        for (class, threshold) in config.slow_request_thresholds.unwrap_or(HashMap::new()) {
+            if !slow_request_thresholds.contains_key(&class) {
+                return Err(CliError::new(
+                    format!("{} in slow_request_thresholds is not a known endpoint class.", class)
+                ));
+            }
+
+            slow_request_thresholds.insert(class, threshold);
+        }
+
+        let supported_room_versions = config.supported_room_versions.unwrap_or_else(|| {
+            KNOWN_ROOM_VERSIONS.iter().map(|version| version.to_string()).collect()
+        });
+
+        for version in &supported_room_versions {
+            if !KNOWN_ROOM_VERSIONS.contains(&&version[..]) {
+                return Err(CliError::new(
+                    format!("{} in supported_room_versions is not a known room version.", version)
+                ));
+            }
+        }
+
+        let default_room_version = config.default_room_version
+            .unwrap_or(DEFAULT_ROOM_VERSION.to_string());
+
+        if !supported_room_versions.contains(&default_room_version) {
+            return Err(CliError::new("default_room_version must be in supported_room_versions."));
+        }
+
+        let ip_range_blacklist = config.url_preview_ip_range_blacklist.unwrap_or_else(|| {
+            DEFAULT_URL_PREVIEW_IP_RANGE_BLACKLIST.iter().map(|range| range.to_string()).collect()
+        });
+
+        let mut ip_ranges = Vec::new();
+
+        for range in ip_range_blacklist {
+            match IpRange::parse(&range) {
+                Some(ip_range) => ip_ranges.push(ip_range),
+                None => return Err(CliError::new(
+                    format!("{} in url_preview_ip_range_blacklist is not a valid IP range.", range)
+                )),
+            }
+        }
+
+        let default_argon2_params = Argon2Params::default();
+        let argon2_params = Argon2Params {
+            lanes: config.argon2_lanes.unwrap_or(default_argon2_params.lanes),
+            memory_kib: config.argon2_memory_kib.unwrap_or(default_argon2_params.memory_kib),
+            passes: config.argon2_passes.unwrap_or(default_argon2_params.passes),
+        };
+
+        argon2_params.validate().map_err(CliError::new)?;
+
+        let app_services = config.app_services.unwrap_or(Vec::new());
+
+        for (index, app_service) in app_services.iter().enumerate() {
+            if app_service.as_token.is_empty() {
+                return Err(CliError::new(
+                    format!("{} in app_services has no as_token.", app_service.id)
+                ));
+            }
+
+            let sender = format!("@{}:{}", app_service.sender_localpart, config.domain);
+
+            if UserId::try_from(&sender[..]).is_err() {
+                return Err(CliError::new(
+                    format!("{} in app_services has an invalid sender_localpart.", app_service.id)
+                ));
+            }
+
+            for other in &app_services[..index] {
+                if other.id == app_service.id || other.as_token == app_service.as_token {
+                    return Err(CliError::new(format!(
+                        "{} in app_services has the same id or as_token as another one.",
+                        app_service.id
+                    )));
+                }
+            }
+        }
+
+        for &(name, url) in &[
+            ("client_base_url", &config.client_base_url),
+            ("identity_server_url", &config.identity_server_url),
+        ] {
+            if let Some(ref url) = *url {
+                if Url::parse(url).is_err() {
+                    return Err(CliError::new(format!("{} must be a valid URL.", name)));
+                }
+            }
+        }
+
+        if config.identity_server_url.is_some() && config.client_base_url.is_none() {
+            return Err(CliError::new("identity_server_url requires client_base_url to be set."));
+        }
+
+        if config.recaptcha_private_key.is_some() != config.recaptcha_public_key.is_some() {
+            return Err(CliError::new(
+                "recaptcha_private_key and recaptcha_public_key must be set together."
+            ));
+        }
+
         Ok(Config {
+            admins: admins,
+            allow_guest_access: config.allow_guest_access.unwrap_or(false),
+            allow_password_changes: config.allow_password_changes.unwrap_or(true),
+            allow_threepid_changes: config.allow_threepid_changes.unwrap_or(true),
+            app_services: app_services,
+            argon2_params: argon2_params,
             bind_address: address,
             bind_port: port,
+            client_base_url: config.client_base_url,
+            default_room_version: default_room_version,
             domain: config.domain,
+            encrypt_private_rooms: config.encrypt_private_rooms.unwrap_or(false),
+            ephemeral_batch_millis: config.ephemeral_batch_millis
+                .unwrap_or(DEFAULT_EPHEMERAL_BATCH_MILLIS),
+            identity_server_url: config.identity_server_url,
+            invites_per_hour: config.invites_per_hour,
             macaroon_secret_key: macaroon_secret_key,
+            max_image_pixels: config.max_image_pixels.unwrap_or(DEFAULT_MAX_IMAGE_PIXELS),
+            max_media_storage_per_user: config.max_media_storage_per_user,
+            max_reactions_per_user: config.max_reactions_per_user,
+            max_relations_per_event: config.max_relations_per_event,
+            max_upload_size: config.max_upload_size.unwrap_or(DEFAULT_MAX_UPLOAD_SIZE),
+            media_directory: config.media_directory.unwrap_or("media".to_string()),
+            outbound_dns_cache_secs: config.outbound_dns_cache_secs
+                .unwrap_or(DEFAULT_OUTBOUND_DNS_CACHE_SECS),
+            outbound_http_timeout_secs: config.outbound_http_timeout_secs
+                .unwrap_or(DEFAULT_OUTBOUND_HTTP_TIMEOUT_SECS),
+            outbound_https_only: config.outbound_https_only.unwrap_or(false),
+            outbound_tls_ca_file: config.outbound_tls_ca_file,
+            outbound_tls_verify: config.outbound_tls_verify.unwrap_or(true),
             postgres_url: config.postgres_url,
+            recaptcha_private_key: config.recaptcha_private_key,
+            recaptcha_public_key: config.recaptcha_public_key,
+            reject_unencrypted_messages: config.reject_unencrypted_messages.unwrap_or(true),
+            report_stats_url: config.report_stats_url,
+            room_complexity_limit: config.room_complexity_limit,
+            rooms_created_per_day: config.rooms_created_per_day,
+            slow_request_thresholds: slow_request_thresholds,
+            sms_gateway_url: config.sms_gateway_url,
+            strip_image_metadata: config.strip_image_metadata.unwrap_or(true),
+            supported_room_versions: supported_room_versions,
+            upload_content_types: config.upload_content_types,
+            url_preview_enabled: config.url_preview_enabled.unwrap_or(false),
+            url_preview_ip_range_blacklist: ip_ranges,
         })
     }
 
+    /// Load the configuration from `RUMA_*` environment variables instead of a file, for running
+    /// in containers (e.g. `run --auto`).
+    ///
+    /// Each variable is the name of a configuration attribute in upper case, prefixed with
+    /// `RUMA_`. `RUMA_ADMINS`, `RUMA_SLOW_REQUEST_THRESHOLDS`, `RUMA_SUPPORTED_ROOM_VERSIONS`,
+    /// `RUMA_UPLOAD_CONTENT_TYPES`, and `RUMA_URL_PREVIEW_IP_RANGE_BLACKLIST` are
+    /// comma-separated, the second as `class=milliseconds` pairs, `RUMA_APP_SERVICES` is a JSON
+    /// array, and booleans must be `true` or `false`.
+    /// `RUMA_BIND_ADDRESS` defaults to 0.0.0.0 so the server is reachable from outside the
+    /// container. If `RUMA_MACAROON_SECRET_KEY` is not set, the key is read from the file
+    /// `macaroon_secret_key` in `RUMA_DATA_DIR` (default `/var/lib/ruma`), and generated there if
+    /// that file doesn't exist yet. `RUMA_MEDIA_DIRECTORY` defaults to `media` in `RUMA_DATA_DIR`.
+    pub fn from_env() -> Result<Config, CliError> {
+        let domain = env::var("RUMA_DOMAIN")
+            .map_err(|_| CliError::new("RUMA_DOMAIN must be set."))?;
+        let postgres_url = env::var("RUMA_POSTGRES_URL")
+            .map_err(|_| CliError::new("RUMA_POSTGRES_URL must be set."))?;
+
+        let data_dir = env::var("RUMA_DATA_DIR").unwrap_or("/var/lib/ruma".to_string());
+
+        let macaroon_secret_key = match env::var("RUMA_MACAROON_SECRET_KEY") {
+            Ok(key) => key,
+            Err(_) => {
+                Self::load_or_generate_secret(Path::new(&data_dir).join("macaroon_secret_key"))?
+            }
+        };
+
+        let media_directory = env::var("RUMA_MEDIA_DIRECTORY").unwrap_or_else(|_| {
+            Path::new(&data_dir).join("media").to_string_lossy().into_owned()
+        });
+
+        let admins = env::var("RUMA_ADMINS").ok().map(|admins| {
+            admins
+                .split(',')
+                .map(|admin| admin.trim().to_string())
+                .filter(|admin| !admin.is_empty())
+                .collect()
+        });
+
+        let app_services = match env::var("RUMA_APP_SERVICES") {
+            Ok(app_services) => Some(serde_json::from_str(&app_services).map_err(|_| {
+                CliError::new("RUMA_APP_SERVICES must be a JSON array of application services.")
+            })?),
+            Err(_) => None,
+        };
+
+        let slow_request_thresholds = match env::var("RUMA_SLOW_REQUEST_THRESHOLDS") {
+            Ok(thresholds) => {
+                let mut parsed = HashMap::new();
+
+                for pair in thresholds.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+                    let mut parts = pair.splitn(2, '=');
+                    let class = parts.next().unwrap_or("").trim();
+
+                    match parts.next().and_then(|threshold| threshold.trim().parse::<u64>().ok()) {
+                        Some(threshold) => parsed.insert(class.to_string(), threshold),
+                        None => return Err(CliError::new(format!(
+                            "{} in RUMA_SLOW_REQUEST_THRESHOLDS must have the form class=milliseconds.",
+                            pair
+                        ))),
+                    };
+                }
+
+                Some(parsed)
+            }
+            Err(_) => None,
+        };
+
+        let room_complexity_limit = match env::var("RUMA_ROOM_COMPLEXITY_LIMIT") {
+            Ok(limit) => Some(limit.parse::<f64>().map_err(|_| {
+                CliError::new("RUMA_ROOM_COMPLEXITY_LIMIT must be a number.")
+            })?),
+            Err(_) => None,
+        };
+
+        let argon2_lanes = Self::count_from_env("RUMA_ARGON2_LANES")?.map(|lanes| lanes as u32);
+        let argon2_memory_kib =
+            Self::count_from_env("RUMA_ARGON2_MEMORY_KIB")?.map(|memory_kib| memory_kib as u32);
+        let argon2_passes =
+            Self::count_from_env("RUMA_ARGON2_PASSES")?.map(|passes| passes as u32);
+
+        let invites_per_hour = Self::count_from_env("RUMA_INVITES_PER_HOUR")?;
+        let rooms_created_per_day = Self::count_from_env("RUMA_ROOMS_CREATED_PER_DAY")?;
+        let max_image_pixels = Self::count_from_env("RUMA_MAX_IMAGE_PIXELS")?;
+        let max_media_storage_per_user =
+            Self::count_from_env("RUMA_MAX_MEDIA_STORAGE_PER_USER")?;
+        let max_reactions_per_user = Self::count_from_env("RUMA_MAX_REACTIONS_PER_USER")?;
+        let max_relations_per_event = Self::count_from_env("RUMA_MAX_RELATIONS_PER_EVENT")?;
+        let max_upload_size = Self::count_from_env("RUMA_MAX_UPLOAD_SIZE")?;
+        let outbound_dns_cache_secs = Self::count_from_env("RUMA_OUTBOUND_DNS_CACHE_SECS")?;
+        let ephemeral_batch_millis = Self::count_from_env("RUMA_EPHEMERAL_BATCH_MILLIS")?;
+        let outbound_http_timeout_secs = Self::count_from_env("RUMA_OUTBOUND_HTTP_TIMEOUT_SECS")?;
+
+        let allow_guest_access = Self::bool_from_env("RUMA_ALLOW_GUEST_ACCESS")?;
+        let allow_password_changes = Self::bool_from_env("RUMA_ALLOW_PASSWORD_CHANGES")?;
+        let allow_threepid_changes = Self::bool_from_env("RUMA_ALLOW_THREEPID_CHANGES")?;
+        let encrypt_private_rooms = Self::bool_from_env("RUMA_ENCRYPT_PRIVATE_ROOMS")?;
+        let outbound_https_only = Self::bool_from_env("RUMA_OUTBOUND_HTTPS_ONLY")?;
+        let outbound_tls_verify = Self::bool_from_env("RUMA_OUTBOUND_TLS_VERIFY")?;
+        let reject_unencrypted_messages = Self::bool_from_env("RUMA_REJECT_UNENCRYPTED_MESSAGES")?;
+        let strip_image_metadata = Self::bool_from_env("RUMA_STRIP_IMAGE_METADATA")?;
+        let url_preview_enabled = Self::bool_from_env("RUMA_URL_PREVIEW_ENABLED")?;
+
+        let supported_room_versions = env::var("RUMA_SUPPORTED_ROOM_VERSIONS").ok().map(|versions| {
+            versions
+                .split(',')
+                .map(|version| version.trim().to_string())
+                .filter(|version| !version.is_empty())
+                .collect()
+        });
+
+        let upload_content_types = env::var("RUMA_UPLOAD_CONTENT_TYPES").ok().map(|types| {
+            types
+                .split(',')
+                .map(|content_type| content_type.trim().to_string())
+                .filter(|content_type| !content_type.is_empty())
+                .collect()
+        });
+
+        let url_preview_ip_range_blacklist =
+            env::var("RUMA_URL_PREVIEW_IP_RANGE_BLACKLIST").ok().map(|ranges| {
+                ranges
+                    .split(',')
+                    .map(|range| range.trim().to_string())
+                    .filter(|range| !range.is_empty())
+                    .collect()
+            });
+
+        Self::from_raw(RawConfig {
+            admins: admins,
+            allow_guest_access: allow_guest_access,
+            allow_password_changes: allow_password_changes,
+            allow_threepid_changes: allow_threepid_changes,
+            app_services: app_services,
+            argon2_lanes: argon2_lanes,
+            argon2_memory_kib: argon2_memory_kib,
+            argon2_passes: argon2_passes,
+            bind_address: Some(env::var("RUMA_BIND_ADDRESS").unwrap_or("0.0.0.0".to_string())),
+            bind_port: env::var("RUMA_BIND_PORT").ok(),
+            client_base_url: env::var("RUMA_CLIENT_BASE_URL").ok(),
+            default_room_version: env::var("RUMA_DEFAULT_ROOM_VERSION").ok(),
+            domain: domain,
+            encrypt_private_rooms: encrypt_private_rooms,
+            ephemeral_batch_millis: ephemeral_batch_millis,
+            identity_server_url: env::var("RUMA_IDENTITY_SERVER_URL").ok(),
+            invites_per_hour: invites_per_hour,
+            macaroon_secret_key: macaroon_secret_key,
+            max_image_pixels: max_image_pixels.unwrap_or(DEFAULT_MAX_IMAGE_PIXELS),
+            max_media_storage_per_user: max_media_storage_per_user,
+            max_reactions_per_user: max_reactions_per_user,
+            max_relations_per_event: max_relations_per_event,
+            max_upload_size: max_upload_size,
+            media_directory: Some(media_directory),
+            outbound_dns_cache_secs: outbound_dns_cache_secs,
+            outbound_http_timeout_secs: outbound_http_timeout_secs,
+            outbound_https_only: outbound_https_only,
+            outbound_tls_ca_file: env::var("RUMA_OUTBOUND_TLS_CA_FILE").ok(),
+            outbound_tls_verify: outbound_tls_verify,
+            postgres_url: postgres_url,
+            recaptcha_private_key: env::var("RUMA_RECAPTCHA_PRIVATE_KEY").ok(),
+            recaptcha_public_key: env::var("RUMA_RECAPTCHA_PUBLIC_KEY").ok(),
+            reject_unencrypted_messages: reject_unencrypted_messages,
+            report_stats_url: env::var("RUMA_REPORT_STATS_URL").ok(),
+            room_complexity_limit: room_complexity_limit,
+            rooms_created_per_day: rooms_created_per_day,
+            slow_request_thresholds: slow_request_thresholds,
+            sms_gateway_url: env::var("RUMA_SMS_GATEWAY_URL").ok(),
+            strip_image_metadata: strip_image_metadata,
+            supported_room_versions: supported_room_versions,
+            upload_content_types: upload_content_types,
+            url_preview_enabled: url_preview_enabled,
+            url_preview_ip_range_blacklist: url_preview_ip_range_blacklist,
+        })
+    }
+
+    /// Read an optional boolean from an environment variable, which must be "true" or "false".
+    fn bool_from_env(name: &str) -> Result<Option<bool>, CliError> {
+        match env::var(name) {
+            Ok(value) => match &value[..] {
+                "true" => Ok(Some(true)),
+                "false" => Ok(Some(false)),
+                _ => Err(CliError::new(format!("{} must be true or false.", name))),
+            },
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Read an optional non-negative integer from an environment variable.
+    fn count_from_env(name: &str) -> Result<Option<u64>, CliError> {
+        match env::var(name) {
+            Ok(value) => value
+                .parse::<u64>()
+                .map(Some)
+                .map_err(|_| CliError::new(format!("{} must be a non-negative integer.", name))),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Read a macaroon secret key from a file, or generate one and save it there so it survives
+    /// restarts.
+    fn load_or_generate_secret(path: PathBuf) -> Result<String, CliError> {
+        if path.is_file() {
+            info!("Using macaroon secret key from `{}`", path.display());
+
+            return Ok(Self::read_file_contents(&path.to_string_lossy()).trim().to_string());
+        }
+
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+
+        let key = generate_macaroon_secret_key()?;
+        let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?;
+
+        file.write_all(key.as_bytes())?;
+
+        info!("Generated a new macaroon secret key in `{}`", path.display());
+
+        Ok(key)
+    }
+
     /// Load the `RawConfig` from a JSON configuration file.
     fn load_json(filename: &str) -> Result<RawConfig, CliError> {
         let contents = Self::read_file_contents(filename);
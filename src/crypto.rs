@@ -1,11 +1,99 @@
 //! Cryptographic operations.
 
+use std::sync::{Arc, Mutex};
+
+use argon2rs::{Argon2, Variant};
 use argon2rs::verifier::Encoded;
-use base64::encode;
-use rand::{OsRng, Rng};
+use base64::{decode, encode};
+use bcrypt;
+use iron::{Plugin, Request};
+use iron::typemap::Key;
+use persistent::Read;
+use rand::{OsRng, Rng, SeedableRng, StdRng};
+use ring::{constant_time, digest, pbkdf2};
+use rustc_serialize::hex::ToHex;
 
 use error::{ApiError, CliError};
 
+/// A source of randomness for generated identifiers and tokens.
+pub trait Entropy: Send + Sync {
+    /// Returns a random string of ASCII letters and digits of the given length.
+    fn alphanumeric(&self, length: usize) -> String;
+
+    /// Returns a random string of ASCII digits of the given length.
+    fn digits(&self, length: usize) -> String;
+}
+
+/// An `Entropy` backed by the operating system's random number generator.
+pub struct OsEntropy {
+    rng: Mutex<OsRng>,
+}
+
+/// An `Entropy` that produces the same sequence of values for the same seed.
+pub struct SeededEntropy {
+    rng: Mutex<StdRng>,
+}
+
+/// An Iron plugin for attaching an `Entropy` to an Iron request.
+pub struct ServerEntropy;
+
+impl OsEntropy {
+    /// Creates a new `OsEntropy`.
+    pub fn new() -> Result<Self, CliError> {
+        Ok(OsEntropy {
+            rng: Mutex::new(OsRng::new()?),
+        })
+    }
+}
+
+impl Entropy for OsEntropy {
+    fn alphanumeric(&self, length: usize) -> String {
+        let mut rng = self.rng.lock().expect("OsEntropy mutex was poisoned");
+
+        rng.gen_ascii_chars().take(length).collect()
+    }
+
+    fn digits(&self, length: usize) -> String {
+        let mut rng = self.rng.lock().expect("OsEntropy mutex was poisoned");
+
+        (0..length).map(|_| (b'0' + rng.gen_range(0, 10)) as char).collect()
+    }
+}
+
+impl SeededEntropy {
+    /// Creates a new `SeededEntropy` from the given seed.
+    pub fn new(seed: &[usize]) -> Self {
+        SeededEntropy {
+            rng: Mutex::new(StdRng::from_seed(seed)),
+        }
+    }
+}
+
+impl Entropy for SeededEntropy {
+    fn alphanumeric(&self, length: usize) -> String {
+        let mut rng = self.rng.lock().expect("SeededEntropy mutex was poisoned");
+
+        rng.gen_ascii_chars().take(length).collect()
+    }
+
+    fn digits(&self, length: usize) -> String {
+        let mut rng = self.rng.lock().expect("SeededEntropy mutex was poisoned");
+
+        (0..length).map(|_| (b'0' + rng.gen_range(0, 10)) as char).collect()
+    }
+}
+
+impl ServerEntropy {
+    /// Extract the `Entropy` stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<Arc<Entropy>>, ApiError> {
+        request.get::<Read<ServerEntropy>>().map_err(ApiError::from)
+    }
+}
+
+impl Key for ServerEntropy {
+    type Value = Arc<Entropy>;
+}
+
 /// Generates a random 32-byte secret key for macaroons.
 pub fn generate_macaroon_secret_key() -> Result<String, CliError> {
     let mut rng = OsRng::new()?;
@@ -16,20 +104,168 @@ pub fn generate_macaroon_secret_key() -> Result<String, CliError> {
     Ok(encode(&key))
 }
 
+/// The cost parameters used when hashing passwords with Argon2.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Argon2Params {
+    /// The number of degrees of parallelism.
+    pub lanes: u32,
+    /// The amount of memory to use, in kibibytes.
+    pub memory_kib: u32,
+    /// The number of passes over the memory.
+    pub passes: u32,
+}
+
+/// A password hashing algorithm that stored hashes can be verified against.
+///
+/// Only Argon2 is used for new hashes. The others exist so that users imported from other
+/// servers can still log in, after which their hashes are replaced.
+pub trait PasswordHasher: Sync {
+    /// Whether or not the encoded hash was produced by this algorithm.
+    fn recognizes(&self, encoded_hash: &str) -> bool;
+
+    /// Whether or not the plaintext password matches the encoded hash.
+    fn verify(&self, encoded_hash: &str, plaintext_password: &str) -> Result<bool, ApiError>;
+}
+
+/// Argon2i hashes in the PHC string format, e.g. `$argon2i$m=4096,t=3,p=1$...`.
+pub struct Argon2Hasher;
+
+/// bcrypt hashes in the modular crypt format, e.g. `$2b$12$...`.
+pub struct BcryptHasher;
+
+/// PBKDF2-HMAC-SHA256 hashes in Django's format, `pbkdf2_sha256$<iterations>$<salt>$<hash>`.
+pub struct Pbkdf2Sha256Hasher;
+
+/// Every algorithm a stored password hash may use.
+static PASSWORD_HASHERS: &'static [&'static PasswordHasher] = &[
+    &Argon2Hasher,
+    &BcryptHasher,
+    &Pbkdf2Sha256Hasher,
+];
+
+impl Default for Argon2Params {
+    fn default() -> Self {
+        Argon2Params {
+            lanes: 1,
+            memory_kib: 4096,
+            passes: 3,
+        }
+    }
+}
+
+impl Argon2Params {
+    /// Checks that Argon2 accepts the parameters.
+    pub fn validate(&self) -> Result<(), String> {
+        Argon2::new(self.passes, self.lanes, self.memory_kib, Variant::Argon2i)
+            .map(|_| ())
+            .map_err(|error| format!("Invalid Argon2 parameters: {:?}", error))
+    }
+}
+
+impl PasswordHasher for Argon2Hasher {
+    fn recognizes(&self, encoded_hash: &str) -> bool {
+        encoded_hash.starts_with("$argon2")
+    }
+
+    fn verify(&self, encoded_hash: &str, plaintext_password: &str) -> Result<bool, ApiError> {
+        let encoded = Encoded::from_u8(encoded_hash.as_bytes()).map_err(ApiError::from)?;
+
+        Ok(encoded.verify(plaintext_password.as_bytes()))
+    }
+}
+
+impl PasswordHasher for BcryptHasher {
+    fn recognizes(&self, encoded_hash: &str) -> bool {
+        ["$2a$", "$2b$", "$2y$"].iter().any(|prefix| encoded_hash.starts_with(prefix))
+    }
+
+    fn verify(&self, encoded_hash: &str, plaintext_password: &str) -> Result<bool, ApiError> {
+        bcrypt::verify(plaintext_password, encoded_hash).map_err(|error| {
+            debug!("Failed to verify bcrypt hash: {:?}", error);
+
+            ApiError::unknown(None)
+        })
+    }
+}
+
+impl PasswordHasher for Pbkdf2Sha256Hasher {
+    fn recognizes(&self, encoded_hash: &str) -> bool {
+        encoded_hash.starts_with("pbkdf2_sha256$")
+    }
+
+    fn verify(&self, encoded_hash: &str, plaintext_password: &str) -> Result<bool, ApiError> {
+        let parts: Vec<&str> = encoded_hash.split('$').collect();
+
+        if parts.len() != 4 {
+            debug!("Malformed PBKDF2 hash");
+
+            return Err(ApiError::unknown(None));
+        }
+
+        let iterations: usize = parts[1].parse().map_err(|_| {
+            debug!("Malformed PBKDF2 iteration count");
+
+            ApiError::unknown(None)
+        })?;
+        let derived = decode(parts[3]).map_err(ApiError::from)?;
+
+        Ok(pbkdf2::verify(
+            &pbkdf2::HMAC_SHA256,
+            iterations,
+            parts[2].as_bytes(),
+            plaintext_password.as_bytes(),
+            &derived,
+        ).is_ok())
+    }
+}
+
 /// Hash a password with Argon2.
-pub fn hash_password(password: &str) -> Result<String, ApiError> {
+pub fn hash_password(params: &Argon2Params, password: &str) -> Result<String, ApiError> {
+    let argon2 = Argon2::new(params.passes, params.lanes, params.memory_kib, Variant::Argon2i)
+        .map_err(|error| {
+            debug!("Invalid Argon2 parameters: {:?}", error);
+
+            ApiError::unknown(None)
+        })?;
     let salt = generate_salt()?;
-    let encoded_hash = Encoded::default2i(password.as_bytes(), &salt, &[], &[]).to_u8();
+    let encoded_hash = Encoded::new(argon2, password.as_bytes(), &salt, &[], &[]).to_u8();
 
     String::from_utf8(encoded_hash).map_err(ApiError::from)
 }
 
-/// Verifies a password with Argon2.
-pub fn verify_password(encoded_hash: &[u8], plaintext_password: &str)
--> Result<bool, ApiError> {
-    let encoded = Encoded::from_u8(encoded_hash).map_err(ApiError::from)?;
+/// Verifies a password against a stored hash, using whichever algorithm produced it.
+pub fn verify_password(encoded_hash: &str, plaintext_password: &str) -> Result<bool, ApiError> {
+    match PASSWORD_HASHERS.iter().find(|hasher| hasher.recognizes(encoded_hash)) {
+        Some(hasher) => hasher.verify(encoded_hash, plaintext_password),
+        None => {
+            debug!("Unrecognized password hash format");
+
+            Err(ApiError::unknown(None))
+        }
+    }
+}
+
+/// Whether or not a stored hash should be replaced with one made by `hash_password`, because it
+/// uses another algorithm or different Argon2 parameters.
+pub fn needs_rehash(params: &Argon2Params, encoded_hash: &str) -> bool {
+    let costs = format!("$m={},t={},p={}$", params.memory_kib, params.passes, params.lanes);
+
+    !Argon2Hasher.recognizes(encoded_hash) || !encoded_hash.contains(&costs)
+}
+
+/// Compares a secret to the value a client gave for it in time that doesn't depend on how much of
+/// the value is right. Only the lengths may differ in timing.
+///
+/// Every comparison against a secret, such as a token or a client secret, should use this rather
+/// than `==`.
+pub fn secrets_match(secret: &str, given: &str) -> bool {
+    constant_time::verify_slices_are_equal(secret.as_bytes(), given.as_bytes()).is_ok()
+}
 
-    Ok(encoded.verify(plaintext_password.as_bytes()))
+/// Returns the hex-encoded SHA-256 digest of a secret, for storing and looking up secrets by a
+/// value that can't be used in their place.
+pub fn sha256_hex(secret: &str) -> String {
+    digest::digest(&digest::SHA256, secret.as_bytes()).as_ref().to_hex()
 }
 
 /// Generates a random salt for Argon2.
@@ -41,3 +277,73 @@ fn generate_salt() -> Result<[u8; 16], ApiError> {
 
     Ok(salt)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Argon2Params,
+        hash_password,
+        needs_rehash,
+        secrets_match,
+        sha256_hex,
+        verify_password,
+    };
+
+    #[test]
+    fn argon2_hashes_verify() {
+        let params = Argon2Params::default();
+        let hash = hash_password(&params, "secret").unwrap();
+
+        assert!(verify_password(&hash, "secret").unwrap());
+        assert!(!verify_password(&hash, "wrong").unwrap());
+        assert!(!needs_rehash(&params, &hash));
+    }
+
+    #[test]
+    fn argon2_hashes_with_other_parameters_need_rehashing() {
+        let params = Argon2Params::default();
+        let hash = hash_password(&Argon2Params { passes: 4, ..params }, "secret").unwrap();
+
+        assert!(verify_password(&hash, "secret").unwrap());
+        assert!(needs_rehash(&params, &hash));
+    }
+
+    #[test]
+    fn imported_bcrypt_hashes_verify() {
+        let hash = "$2b$04$a81emAWR0PvfPfrM.6Pq/.Ox9HeXzMjaAUQn4fXbNFWh42DCzODeG";
+
+        assert!(verify_password(hash, "secret").unwrap());
+        assert!(!verify_password(hash, "wrong").unwrap());
+        assert!(needs_rehash(&Argon2Params::default(), hash));
+    }
+
+    #[test]
+    fn imported_pbkdf2_hashes_verify() {
+        let hash = "pbkdf2_sha256$1000$saltysalt$Pm5/4MDf6CmvoAMz/RZz8+7xtCXLL8qaDnkvjnPhMec=";
+
+        assert!(verify_password(hash, "secret").unwrap());
+        assert!(!verify_password(hash, "wrong").unwrap());
+        assert!(needs_rehash(&Argon2Params::default(), hash));
+    }
+
+    #[test]
+    fn unrecognized_hashes_are_an_error() {
+        assert!(verify_password("plaintext", "plaintext").is_err());
+    }
+
+    #[test]
+    fn secrets_match_only_when_equal() {
+        assert!(secrets_match("s3cret", "s3cret"));
+        assert!(!secrets_match("s3cret", "s3creT"));
+        assert!(!secrets_match("s3cret", "s3cre"));
+        assert!(!secrets_match("s3cret", ""));
+    }
+
+    #[test]
+    fn sha256_hex_digests() {
+        assert_eq!(
+            sha256_hex("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
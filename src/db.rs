@@ -1,5 +1,10 @@
 //! Database-related functionality.
 
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use diesel::migrations::setup_database;
 use diesel::pg::PgConnection;
 use iron::{Plugin, Request};
 use iron::typemap::Key;
@@ -7,11 +12,44 @@ use persistent::Write;
 use r2d2::{Config as R2D2Config, InitializationError, Pool, PooledConnection};
 use r2d2_diesel::{ConnectionManager, Error as R2D2DieselError};
 
-use error::ApiError;
+use embedded_migrations::run as run_pending_migrations;
+use error::{ApiError, CliError};
+use middleware::RequestTimings;
 
 /// An Iron plugin for attaching a database connection pool to an Iron request.
 pub struct DB;
 
+/// A database connection checked out for a request.
+///
+/// Records how long the request waited for the connection and how long it held it in the
+/// request's `RequestTimings`, which are logged by the `LatencyBudget` middleware.
+pub struct RequestConnection {
+    checked_out_at: Instant,
+    connection: PooledConnection<ConnectionManager<PgConnection>>,
+    usage: Option<Arc<Mutex<DbUsage>>>,
+}
+
+/// The connection pool, as seen by a single request.
+///
+/// Can be sent to other threads, and the connections it hands out are recorded in the request's
+/// `RequestTimings` like those from `DB::from_request`.
+#[derive(Clone)]
+pub struct RequestConnectionPool {
+    pool: Pool<ConnectionManager<PgConnection>>,
+    usage: Option<Arc<Mutex<DbUsage>>>,
+}
+
+/// Database usage accumulated over the course of a request.
+#[derive(Clone, Debug, Default)]
+pub struct DbUsage {
+    /// The number of connections checked out from the pool.
+    pub checkouts: u32,
+    /// Total time spent holding connections.
+    pub held: Duration,
+    /// Total time spent waiting for the pool to provide a connection.
+    pub wait: Duration,
+}
+
 impl DB {
     /// Creates a connection pool for the PostgreSQL database at the given URL.
     pub fn create_connection_pool(
@@ -23,13 +61,77 @@ impl DB {
         Pool::new(r2d2_config, connection_manager)
     }
 
+    /// Creates the migrations table if necessary and runs any pending migrations.
+    pub fn set_up(connection: &PgConnection) -> Result<(), CliError> {
+        debug!("Setting up database.");
+        if let Err(error) = setup_database(connection) {
+            return Err(CliError::new(format!("{:?}", error)));
+        }
+
+        debug!("Running pending database migrations.");
+        if let Err(error) = run_pending_migrations(connection) {
+            return Err(CliError::new(format!("{:?}", error)));
+        }
+
+        Ok(())
+    }
+
     /// Extract a database conection from the pool stored in the request.
-    pub fn from_request(request: &mut Request)
-        -> Result<PooledConnection<ConnectionManager<PgConnection>>, ApiError>
-    {
+    pub fn from_request(request: &mut Request) -> Result<RequestConnection, ApiError> {
+        DB::pool_from_request(request)?.get()
+    }
+
+    /// Extract the pool stored in the request, for handlers that check out connections from
+    /// other threads.
+    pub fn pool_from_request(request: &mut Request) -> Result<RequestConnectionPool, ApiError> {
         let mutex = request.get::<Write<DB>>().map_err(ApiError::from)?;
-        let pool = mutex.lock().map_err(ApiError::from)?;
-        pool.get().map_err(ApiError::from)
+        let pool = mutex.lock().map_err(ApiError::from)?.clone();
+        let usage = request.extensions.get::<RequestTimings>().map(|timings| timings.db.clone());
+
+        Ok(RequestConnectionPool {
+            pool: pool,
+            usage: usage,
+        })
+    }
+}
+
+impl RequestConnectionPool {
+    /// Checks out a connection, recording its usage against the request.
+    pub fn get(&self) -> Result<RequestConnection, ApiError> {
+        let start = Instant::now();
+        let connection = self.pool.get().map_err(ApiError::from)?;
+        let checked_out_at = Instant::now();
+
+        if let Some(ref usage) = self.usage {
+            let mut usage = usage.lock().map_err(ApiError::from)?;
+
+            usage.checkouts += 1;
+            usage.wait += checked_out_at.duration_since(start);
+        }
+
+        Ok(RequestConnection {
+            checked_out_at: checked_out_at,
+            connection: connection,
+            usage: self.usage.clone(),
+        })
+    }
+}
+
+impl Deref for RequestConnection {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &PgConnection {
+        &self.connection
+    }
+}
+
+impl Drop for RequestConnection {
+    fn drop(&mut self) {
+        if let Some(ref usage) = self.usage {
+            if let Ok(mut usage) = usage.lock() {
+                usage.held += self.checked_out_at.elapsed();
+            }
+        }
     }
 }
 
@@ -0,0 +1,223 @@
+//! Events scheduled to be sent in the future.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use diesel::{
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LoadDsl,
+    OrderDsl,
+    SaveChangesDsl,
+    delete,
+    insert,
+};
+use diesel::pg::PgConnection;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::{EventId, RoomId, UserId};
+
+use config::Config;
+use error::ApiError;
+use event::NewEvent;
+use jobs::Job;
+use membership_cache::MembershipCache;
+use power_levels::PowerLevelsCache;
+use room::Room;
+use schema::delayed_events;
+
+/// The longest an event can be delayed, in milliseconds (30 days).
+pub const MAX_DELAY: i64 = 30 * 24 * 60 * 60 * 1000;
+
+/// An event waiting to be sent.
+#[derive(AsChangeset, Clone, Debug, Identifiable, Queryable)]
+#[table_name = "delayed_events"]
+pub struct DelayedEvent {
+    /// The opaque ID used to manage the delayed event.
+    pub id: String,
+    /// The room the event will be sent to.
+    pub room_id: RoomId,
+    /// The user who will send the event.
+    pub user_id: UserId,
+    /// The type of the event, e.g. *m.room.message*.
+    pub event_type: String,
+    /// The state key, if the event is a state event.
+    pub state_key: Option<String>,
+    /// JSON of the event's content.
+    pub content: String,
+    /// The delay requested by the client, in milliseconds.
+    pub delay: i64,
+    /// When the event will be sent, in milliseconds since the Unix epoch.
+    pub send_at: i64,
+}
+
+/// A new delayed event, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "delayed_events"]
+pub struct NewDelayedEvent {
+    /// The opaque ID used to manage the delayed event.
+    pub id: String,
+    /// The room the event will be sent to.
+    pub room_id: RoomId,
+    /// The user who will send the event.
+    pub user_id: UserId,
+    /// The type of the event, e.g. *m.room.message*.
+    pub event_type: String,
+    /// The state key, if the event is a state event.
+    pub state_key: Option<String>,
+    /// JSON of the event's content.
+    pub content: String,
+    /// The delay requested by the client, in milliseconds.
+    pub delay: i64,
+    /// When the event will be sent, in milliseconds since the Unix epoch.
+    pub send_at: i64,
+}
+
+/// A background job that sends delayed events once they are due.
+#[derive(Debug)]
+pub struct SendDelayedEvents {
+    membership_cache: Arc<MembershipCache>,
+    power_levels_cache: Arc<PowerLevelsCache>,
+}
+
+impl DelayedEvent {
+    /// Schedules a new delayed event.
+    pub fn create(connection: &PgConnection, new_delayed_event: &NewDelayedEvent)
+    -> Result<DelayedEvent, ApiError> {
+        insert(new_delayed_event)
+            .into(delayed_events::table)
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Looks up a delayed event by ID, if it belongs to the given user.
+    pub fn find(connection: &PgConnection, id: &str, user_id: &UserId)
+    -> Result<DelayedEvent, ApiError> {
+        delayed_events::table
+            .filter(delayed_events::id.eq(id))
+            .filter(delayed_events::user_id.eq(user_id))
+            .first(connection)
+            .map_err(|error| match error {
+                DieselError::NotFound => ApiError::not_found(
+                    Some(&format!("No delayed event with ID {} was found.", id))
+                ),
+                _ => ApiError::from(error),
+            })
+    }
+
+    /// Returns all of a user's pending delayed events, soonest first.
+    pub fn find_by_user(connection: &PgConnection, user_id: &UserId)
+    -> Result<Vec<DelayedEvent>, ApiError> {
+        delayed_events::table
+            .filter(delayed_events::user_id.eq(user_id))
+            .order(delayed_events::send_at.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Returns all delayed events due at or before `now`.
+    pub fn find_due(connection: &PgConnection, now: i64) -> Result<Vec<DelayedEvent>, ApiError> {
+        delayed_events::table
+            .filter(delayed_events::send_at.le(now))
+            .order(delayed_events::send_at.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Cancels the delayed event.
+    pub fn cancel(&self, connection: &PgConnection) -> Result<(), ApiError> {
+        delete(delayed_events::table.find(self.id.clone()))
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+
+    /// Resets the timer so the event is sent after its original delay, starting from `now`.
+    pub fn restart(&mut self, connection: &PgConnection, now: i64) -> Result<(), ApiError> {
+        self.send_at = now + self.delay;
+
+        self.save_changes::<DelayedEvent>(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+
+    /// Sends the event immediately and removes it from the schedule.
+    ///
+    /// Membership and power levels are checked again, since they may have changed since the event
+    /// was scheduled.
+    /// If the sender is no longer allowed to send the event, it is discarded.
+    pub fn send(
+        &self,
+        connection: &PgConnection,
+        membership_cache: &MembershipCache,
+        power_levels_cache: &PowerLevelsCache,
+        homeserver_domain: &str,
+        now: i64,
+    ) -> Result<EventId, ApiError> {
+        connection.transaction::<EventId, ApiError, _>(|| {
+            self.cancel(connection)?;
+
+            let room = Room::find(connection, &self.room_id)?;
+
+            let event_id = EventId::new(homeserver_domain)?;
+            let new_event = NewEvent {
+                content: self.content.clone(),
+                event_type: self.event_type.clone(),
+                extra_content: None,
+                id: event_id.clone(),
+                room_id: self.room_id.clone(),
+                state_key: self.state_key.clone(),
+                user_id: self.user_id.clone(),
+            };
+
+            room.send_event(connection, membership_cache, power_levels_cache, &new_event, now)?;
+
+            Ok(event_id)
+        }).map_err(ApiError::from)
+    }
+}
+
+impl SendDelayedEvents {
+    /// Creates the job, which checks memberships and power levels against the given caches.
+    pub fn new(
+        membership_cache: Arc<MembershipCache>,
+        power_levels_cache: Arc<PowerLevelsCache>,
+    ) -> Self {
+        SendDelayedEvents {
+            membership_cache: membership_cache,
+            power_levels_cache: power_levels_cache,
+        }
+    }
+}
+
+impl Job for SendDelayedEvents {
+    fn name(&self) -> &'static str {
+        "send_delayed_events"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn run(&self, connection: &PgConnection, config: &Config, now: i64) -> Result<(), ApiError> {
+        for delayed_event in DelayedEvent::find_due(connection, now)? {
+            let result = delayed_event.send(
+                connection,
+                &self.membership_cache,
+                &self.power_levels_cache,
+                &config.domain,
+                now,
+            );
+
+            if let Err(error) = result {
+                info!("Dropping delayed event {}: {}", delayed_event.id, error);
+
+                delayed_event.cancel(connection)?;
+            }
+        }
+
+        Ok(())
+    }
+}
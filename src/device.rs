@@ -0,0 +1,159 @@
+//! Devices that users have logged in from.
+
+use diesel::{
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LoadDsl,
+    OrderDsl,
+    delete,
+    insert,
+    update,
+};
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::UserId;
+
+use access_token::AccessToken;
+use crypto::Entropy;
+use device_list::DeviceLists;
+use error::ApiError;
+use lazy_loaded_member::LazyLoadedMember;
+use schema::devices;
+
+/// The length of generated device IDs.
+const DEVICE_ID_LENGTH: usize = 10;
+
+/// A device a user has logged in from.
+#[derive(Clone, Debug, Queryable)]
+pub struct Device {
+    /// Entry ID.
+    pub id: i64,
+    /// The device's ID, which is only unique among the user's devices.
+    pub device_id: String,
+    /// The user the device belongs to.
+    pub user_id: UserId,
+    /// A name for the device chosen by the user or their client.
+    pub display_name: Option<String>,
+    /// The time the device first logged in.
+    pub created_at: PgTimestamp,
+}
+
+/// A new device, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "devices"]
+pub struct NewDevice {
+    /// The device's ID, which is only unique among the user's devices.
+    pub device_id: String,
+    /// The user the device belongs to.
+    pub user_id: UserId,
+    /// A name for the device chosen by the user or their client.
+    pub display_name: Option<String>,
+}
+
+impl Device {
+    /// Generates an ID for a device whose client didn't pick one.
+    pub fn generate_id(entropy: &Entropy) -> String {
+        entropy.alphanumeric(DEVICE_ID_LENGTH)
+    }
+
+    /// Saves a new device.
+    pub fn create(connection: &PgConnection, new_device: &NewDevice) -> Result<Device, ApiError> {
+        connection.transaction::<Device, ApiError, _>(|| {
+            let device = insert(new_device)
+                .into(devices::table)
+                .get_result(connection)?;
+
+            DeviceLists::record_change(connection, &new_device.user_id)?;
+
+            Ok(device)
+        }).map_err(ApiError::from)
+    }
+
+    /// Returns one of the user's devices, if it exists.
+    pub fn find(connection: &PgConnection, user_id: &UserId, device_id: &str)
+    -> Result<Option<Device>, ApiError> {
+        let device = devices::table
+            .filter(devices::user_id.eq(user_id))
+            .filter(devices::device_id.eq(device_id))
+            .first(connection);
+
+        match device {
+            Ok(device) => Ok(Some(device)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(error) => Err(ApiError::from(error)),
+        }
+    }
+
+    /// Returns all of the user's devices, oldest first.
+    pub fn find_by_user(connection: &PgConnection, user_id: &UserId)
+    -> Result<Vec<Device>, ApiError> {
+        devices::table
+            .filter(devices::user_id.eq(user_id))
+            .order(devices::id.asc())
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Prepares a device for a new login.
+    ///
+    /// If the user already has a device with the ID, the access tokens previously issued to it are
+    /// revoked, since a device only ever has one active session. Otherwise the device is created.
+    pub fn find_or_create_for_login(connection: &PgConnection, new_device: &NewDevice)
+    -> Result<Device, ApiError> {
+        match Device::find(connection, &new_device.user_id, &new_device.device_id)? {
+            Some(device) => {
+                AccessToken::revoke_by_device(connection, &device.user_id, &device.device_id)?;
+
+                Ok(device)
+            }
+            None => Device::create(connection, new_device),
+        }
+    }
+
+    /// Changes the device's display name.
+    pub fn set_display_name(&mut self, connection: &PgConnection, display_name: Option<String>)
+    -> Result<(), ApiError> {
+        connection.transaction::<(), ApiError, _>(|| {
+            update(devices::table.find(self.id))
+                .set(devices::display_name.eq(display_name.clone()))
+                .execute(connection)?;
+
+            DeviceLists::record_change(connection, &self.user_id)
+        }).map_err(ApiError::from)?;
+
+        self.display_name = display_name;
+
+        Ok(())
+    }
+
+    /// Deletes the device and revokes the access tokens issued to it, logging it out.
+    pub fn delete(&self, connection: &PgConnection) -> Result<(), ApiError> {
+        connection.transaction::<(), ApiError, _>(|| {
+            AccessToken::revoke_by_device(connection, &self.user_id, &self.device_id)?;
+
+            LazyLoadedMember::clear(connection, &self.user_id, &self.device_id)?;
+
+            delete(devices::table.find(self.id)).execute(connection)?;
+
+            DeviceLists::record_change(connection, &self.user_id)
+        }).map_err(ApiError::from)
+    }
+
+    /// Deletes all of the user's devices except the one with the given ID, logging them out.
+    pub fn delete_others(connection: &PgConnection, user_id: &UserId, device_id: &str)
+    -> Result<(), ApiError> {
+        connection.transaction::<(), ApiError, _>(|| {
+            for device in Device::find_by_user(connection, user_id)? {
+                if device.device_id != device_id {
+                    device.delete(connection)?;
+                }
+            }
+
+            Ok(())
+        }).map_err(ApiError::from)
+    }
+}
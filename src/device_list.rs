@@ -0,0 +1,150 @@
+//! Changes to users' device lists, which end-to-end encryption clients track to know when to
+//! re-query device keys.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use diesel::{ExecuteDsl, ExpressionMethods, FilterDsl, LoadDsl, SelectDsl, insert};
+use diesel::pg::PgConnection;
+use diesel::pg::expression::dsl::any;
+use ruma_identifiers::UserId;
+
+use error::ApiError;
+use schema::{device_list_changes, events, room_memberships};
+
+/// A change to a user's device list, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "device_list_changes"]
+pub struct NewDeviceListChange {
+    /// The user whose devices changed.
+    pub user_id: UserId,
+}
+
+/// The users whose device lists a user should re-query or stop tracking.
+#[derive(Debug, Default, Serialize)]
+pub struct DeviceLists {
+    /// Users who share a room with the user and added or removed a device, or who started sharing
+    /// a room with them.
+    pub changed: Vec<String>,
+    /// Users who no longer share any room with the user.
+    pub left: Vec<String>,
+}
+
+impl DeviceLists {
+    /// Records that the user's device list changed, giving the change a position in the events
+    /// stream.
+    pub fn record_change(connection: &PgConnection, user_id: &UserId) -> Result<(), ApiError> {
+        let new_change = NewDeviceListChange {
+            user_id: user_id.clone(),
+        };
+
+        insert(&new_change)
+            .into(device_list_changes::table)
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+
+    /// Works out the device list changes a user should hear about after the position `after` up to
+    /// the position `up_to`.
+    pub fn find(connection: &PgConnection, user_id: &UserId, after: i64, up_to: i64)
+    -> Result<DeviceLists, ApiError> {
+        let user_id = user_id.to_string();
+
+        let joined_room_ids: Vec<String> = room_memberships::table
+            .filter(room_memberships::user_id.eq(&user_id))
+            .filter(room_memberships::membership.eq("join"))
+            .select(room_memberships::room_id)
+            .load(connection)?;
+
+        // Rooms the user joined or left in the range.
+        let own_changed_room_ids: Vec<String> = events::table
+            .filter(events::event_type.eq("m.room.member"))
+            .filter(events::state_key.eq(&user_id))
+            .filter(events::ordering.gt(after))
+            .filter(events::ordering.le(up_to))
+            .select(events::room_id)
+            .load(connection)?;
+
+        let mut room_ids = joined_room_ids.clone();
+
+        room_ids.extend(own_changed_room_ids);
+        room_ids.sort();
+        room_ids.dedup();
+
+        let members: Vec<(String, String)> = room_memberships::table
+            .filter(room_memberships::room_id.eq(any(room_ids.clone())))
+            .filter(room_memberships::membership.eq("join"))
+            .select((room_memberships::room_id, room_memberships::user_id))
+            .load(connection)?;
+
+        let mut members_by_room: HashMap<String, Vec<String>> = HashMap::new();
+        let mut peers = HashSet::new();
+
+        peers.insert(user_id.clone());
+
+        for (room_id, member) in members {
+            if joined_room_ids.contains(&room_id) {
+                peers.insert(member.clone());
+            }
+
+            members_by_room.entry(room_id).or_insert_with(Vec::new).push(member);
+        }
+
+        let member_changes: Vec<(String, Option<String>)> = events::table
+            .filter(events::event_type.eq("m.room.member"))
+            .filter(events::room_id.eq(any(room_ids)))
+            .filter(events::ordering.gt(after))
+            .filter(events::ordering.le(up_to))
+            .select((events::room_id, events::state_key))
+            .load(connection)?;
+
+        // Everyone whose membership changed relative to the user may need to be re-queried or
+        // forgotten, depending on whether they still share a room.
+        let mut candidates = BTreeSet::new();
+
+        for (room_id, state_key) in member_changes {
+            match state_key {
+                Some(ref target) if *target == user_id => {
+                    if let Some(members) = members_by_room.get(&room_id) {
+                        candidates.extend(members.iter().cloned());
+                    }
+                }
+                Some(target) => {
+                    candidates.insert(target);
+                }
+                None => {}
+            }
+        }
+
+        candidates.remove(&user_id);
+
+        let peer_ids: Vec<String> = peers.iter().cloned().collect();
+        let device_changes: Vec<String> = device_list_changes::table
+            .filter(device_list_changes::user_id.eq(any(peer_ids)))
+            .filter(device_list_changes::ordering.gt(after))
+            .filter(device_list_changes::ordering.le(up_to))
+            .select(device_list_changes::user_id)
+            .load(connection)?;
+
+        let mut changed: BTreeSet<String> = device_changes.into_iter().collect();
+        let mut left = BTreeSet::new();
+
+        for candidate in candidates {
+            if peers.contains(&candidate) {
+                changed.insert(candidate);
+            } else {
+                left.insert(candidate);
+            }
+        }
+
+        Ok(DeviceLists {
+            changed: changed.into_iter().collect(),
+            left: left.into_iter().collect(),
+        })
+    }
+
+    /// Whether or not there are no device list changes.
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.left.is_empty()
+    }
+}
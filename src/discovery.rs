@@ -0,0 +1,42 @@
+//! Telling clients where to find the homeserver and identity server, for deployments where they
+//! can't be reached at the server's domain.
+
+use config::Config;
+
+/// The URLs advertised to clients by `/.well-known/matrix/client` and in login responses.
+#[derive(Clone, Debug, Serialize)]
+pub struct ClientDiscovery {
+    /// Where clients can reach the homeserver.
+    #[serde(rename = "m.homeserver")]
+    pub homeserver: ServerInformation,
+    /// Where clients can reach the identity server, if one is configured.
+    #[serde(rename = "m.identity_server")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity_server: Option<ServerInformation>,
+}
+
+/// The location of a server.
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerInformation {
+    /// The URL clients should use for the server's API.
+    pub base_url: String,
+}
+
+impl ClientDiscovery {
+    /// Returns the URLs configured with `client_base_url` and `identity_server_url`, or `None`
+    /// if there is no `client_base_url`.
+    pub fn from_config(config: &Config) -> Option<ClientDiscovery> {
+        config.client_base_url.as_ref().map(|client_base_url| {
+            ClientDiscovery {
+                homeserver: ServerInformation {
+                    base_url: client_base_url.clone(),
+                },
+                identity_server: config.identity_server_url.as_ref().map(|identity_server_url| {
+                    ServerInformation {
+                        base_url: identity_server_url.clone(),
+                    }
+                }),
+            }
+        })
+    }
+}
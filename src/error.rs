@@ -10,6 +10,7 @@ use std::sync::PoisonError;
 use argon2rs::verifier::DecodeError;
 use base64::Base64Error;
 use diesel::result::{TransactionError, Error as DieselError};
+use image::ImageError;
 use iron::{IronError, Response};
 use iron::modifier::Modifier;
 use iron::status::Status;
@@ -51,12 +52,25 @@ pub enum ApiErrorCode {
     NotFound,
     /// Request did not contain valid JSON.
     NotJson,
+    /// The user has used up their share of a resource, such as storage in the content repository.
+    QuotaExceeded,
+    /// An event already has as many relations, or as many of the user's reactions, as the server
+    /// allows.
+    RelationLimitExceeded,
+    /// A third-party identifier could not be verified, e.g. the wrong validation token was given.
+    ThreepidAuthFailed,
+    /// The request or a value in it was too large.
+    TooLarge,
     /// Ruma does not implement the requested API.
     Unimplemented,
     /// Errors not fitting into another category.
     Unknown,
     /// The access token specified was not recognised.
     UnknownToken,
+    /// The requested room version is not supported by the server.
+    UnsupportedRoomVersion,
+    /// The account has been locked by a server administrator.
+    UserLocked,
 }
 
 /// An operator-facing error.
@@ -154,6 +168,43 @@ impl ApiError {
         }
     }
 
+    /// Create an error for requests that would take the user over their share of a resource.
+    pub fn quota_exceeded(message: Option<&str>) -> ApiError {
+        ApiError {
+            errcode: ApiErrorCode::QuotaExceeded,
+            error: message.unwrap_or("The request would exceed your quota.").to_string(),
+        }
+    }
+
+    /// Create an error for events that would give the event they relate to more relations or
+    /// reactions than the server allows.
+    pub fn relation_limit_exceeded(message: Option<&str>) -> ApiError {
+        ApiError {
+            errcode: ApiErrorCode::RelationLimitExceeded,
+            error: message.unwrap_or(
+                "The event already has as many relations as the server allows."
+            ).to_string(),
+        }
+    }
+
+    /// Create an error for third-party identifiers that could not be verified.
+    pub fn threepid_auth_failed(message: Option<&str>) -> ApiError {
+        ApiError {
+            errcode: ApiErrorCode::ThreepidAuthFailed,
+            error: message.unwrap_or(
+                "The third-party identifier could not be verified."
+            ).to_string(),
+        }
+    }
+
+    /// Create an error for requests with a value that is larger than the server allows.
+    pub fn too_large(message: Option<&str>) -> ApiError {
+        ApiError {
+            errcode: ApiErrorCode::TooLarge,
+            error: message.unwrap_or("The request is too large.").to_string(),
+        }
+    }
+
     /// Create an error for requests that did not provide required authentication parameters.
     pub fn unauthorized(message: Option<&str>) -> ApiError {
         ApiError {
@@ -178,6 +229,22 @@ impl ApiError {
         }
     }
 
+    /// Create an error for requests for a room version the server doesn't support.
+    pub fn unsupported_room_version(message: Option<&str>) -> ApiError {
+        ApiError {
+            errcode: ApiErrorCode::UnsupportedRoomVersion,
+            error: message.unwrap_or("Unsupported room version.").to_string(),
+        }
+    }
+
+    /// Create an error for requests made by or for a locked account.
+    pub fn user_locked(message: Option<&str>) -> ApiError {
+        ApiError {
+            errcode: ApiErrorCode::UserLocked,
+            error: message.unwrap_or("This account has been locked.").to_string(),
+        }
+    }
+
     /// Create a generic error for anything not specifically covered by the Matrix spec.
     pub fn unknown(message: Option<&str>) -> ApiError {
         ApiError {
@@ -243,6 +310,14 @@ impl From<TransactionError<ApiError>> for ApiError {
     }
 }
 
+impl From<ImageError> for ApiError {
+    fn from(error: ImageError) -> ApiError {
+        debug!("Converting to ApiError from: {:?}", error);
+
+        ApiError::unknown(None)
+    }
+}
+
 impl From<MacaroonsError> for ApiError {
     fn from(error: MacaroonsError) -> ApiError {
         debug!("Converting to ApiError from: {:?}", error);
@@ -326,9 +401,15 @@ impl ApiErrorCode {
             ApiErrorCode::MissingParam => Status::BadRequest,
             ApiErrorCode::NotFound => Status::NotFound,
             ApiErrorCode::NotJson => Status::BadRequest,
+            ApiErrorCode::QuotaExceeded => Status::Forbidden,
+            ApiErrorCode::RelationLimitExceeded => Status::BadRequest,
+            ApiErrorCode::ThreepidAuthFailed => Status::BadRequest,
+            ApiErrorCode::TooLarge => Status::PayloadTooLarge,
             ApiErrorCode::Unimplemented => Status::NotFound,
             ApiErrorCode::Unknown => Status::InternalServerError,
             ApiErrorCode::UnknownToken => Status::Unauthorized,
+            ApiErrorCode::UnsupportedRoomVersion => Status::BadRequest,
+            ApiErrorCode::UserLocked => Status::Unauthorized,
         }
     }
 }
@@ -346,9 +427,15 @@ impl Serialize for ApiErrorCode {
             ApiErrorCode::MissingParam => "M_MISSING_PARAM",
             ApiErrorCode::NotFound => "M_NOT_FOUND",
             ApiErrorCode::NotJson => "M_NOT_JSON",
+            ApiErrorCode::QuotaExceeded => "IO_RUMA_QUOTA_EXCEEDED",
+            ApiErrorCode::RelationLimitExceeded => "IO_RUMA_RELATION_LIMIT_EXCEEDED",
+            ApiErrorCode::ThreepidAuthFailed => "M_THREEPID_AUTH_FAILED",
+            ApiErrorCode::TooLarge => "M_TOO_LARGE",
             ApiErrorCode::Unimplemented => "IO_RUMA_UNIMPLEMENTED",
             ApiErrorCode::Unknown => "M_UNKNOWN",
             ApiErrorCode::UnknownToken => "M_UNKNOWN_TOKEN",
+            ApiErrorCode::UnsupportedRoomVersion => "M_UNSUPPORTED_ROOM_VERSION",
+            ApiErrorCode::UserLocked => "M_USER_LOCKED",
         };
 
         serializer.serialize_str(value)
@@ -1,11 +1,27 @@
 //! Matrix events.
 
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
 use std::convert::{TryInto, TryFrom};
 
-use diesel::{ExpressionMethods, FilterDsl, LoadDsl};
-use diesel::result::Error as DieselError;
+use diesel::{
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LimitDsl,
+    LoadDsl,
+    OrderDsl,
+    SelectDsl,
+    insert,
+    update,
+};
+use diesel::expression::dsl::{any, count_star, sql};
+use diesel::result::{DatabaseErrorKind, Error as DieselError, TransactionError};
 use diesel::pg::data_types::PgTimestamp;
 use diesel::pg::PgConnection;
+use diesel::types::{BigInt, Bool, Float, Text};
 use ruma_events::{
     CustomRoomEvent,
     CustomStateEvent,
@@ -32,10 +48,13 @@ use ruma_events::room::power_levels::PowerLevelsEvent;
 use ruma_events::room::third_party_invite::ThirdPartyInviteEvent;
 use ruma_events::room::topic::TopicEvent;
 use ruma_identifiers::{EventId, RoomId, UserId};
-use serde_json::{Value, from_str, from_value, to_string};
+use serde::Deserialize;
+use serde_json::{Value, from_str, from_value, to_string, to_value};
 
 use error::ApiError;
+use room::{ENCRYPTION_EVENT_TYPE, MAX_ROOM_NAME_LENGTH, MAX_ROOM_TOPIC_LENGTH};
 use schema::events;
+use transaction::Transaction;
 
 /// A new event, not yet saved.
 #[derive(Debug, Insertable)]
@@ -76,11 +95,725 @@ pub struct Event {
     pub content: String,
     /// Extra key-value pairs to be mixed into the top-level JSON representation of the event.
     pub extra_content: Option<String>,
+    /// The state event this one replaced, if it's a state event and the room had an earlier one
+    /// with the same type and state key. Set by the database.
+    pub replaces_state: Option<EventId>,
     /// The time the event was created.
     pub created_at: PgTimestamp,
 }
 
+/// An event that matched a full-text search.
+#[derive(Debug, Queryable)]
+pub struct SearchMatch {
+    /// The ID of the event.
+    pub event_id: EventId,
+    /// The user who sent the event.
+    pub user_id: UserId,
+    /// The ordering of the event.
+    pub ordering: i64,
+    /// How well the event matched, higher being better.
+    pub rank: f32,
+}
+
+/// An event in the format sent to clients.
+#[derive(Debug, Serialize)]
+struct ClientEvent {
+    content: Value,
+    event_id: String,
+    origin_server_ts: i64,
+    sender: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redacts: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_key: Option<String>,
+    #[serde(rename = "type")]
+    event_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unsigned: Option<Unsigned>,
+}
+
+/// The `unsigned` block of an event sent to clients.
+#[derive(Debug, Serialize)]
+struct Unsigned {
+    age: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prev_content: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redacted_because: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replaces_state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transaction_id: Option<String>,
+}
+
+/// What goes in the `unsigned` block of a batch of events served to one client, looked up all at
+/// once instead of for each event.
+#[derive(Debug)]
+pub struct UnsignedData {
+    /// The time the events are being served, in milliseconds since the Unix epoch.
+    now_millis: i64,
+    /// The content each state event replaced, by event ID.
+    prev_contents: HashMap<String, Value>,
+    /// The redaction of each redacted event, by event ID.
+    redactions: HashMap<String, Value>,
+    /// The transaction ID of each event the client sent with its own access token, by event ID.
+    transaction_ids: HashMap<String, String>,
+}
+
+sql_function!(
+    event_search_matches,
+    event_search_matches_t,
+    (content: Text, search_term: Text) -> Bool
+);
+sql_function!(event_search_rank, event_search_rank_t, (content: Text, search_term: Text) -> Float);
+sql_function!(event_relates_to, event_relates_to_t, (content: Text) -> Text);
+sql_function!(event_relation_type, event_relation_type_t, (content: Text) -> Text);
+
+/// Milliseconds between the Unix epoch and the PostgreSQL epoch, 2000-01-01.
+pub const POSTGRES_EPOCH_MILLIS: i64 = 946_684_800_000;
+
+macro_rules! room_event {
+    (
+        $ty:ident,
+        $event_id:ident,
+        $room_id:ident,
+        $user_id:ident,
+        $event_type:ident,
+        $content:ident
+    ) => {
+        $ty {
+            content: extract_event_content($content, &$event_type)?,
+            event_id: $event_id,
+            event_type: $event_type,
+            room_id: $room_id,
+            unsigned: None,
+            user_id: $user_id,
+        }.try_into()
+    };
+}
+
+macro_rules! state_event {
+    (
+        $ty:ident,
+        $event_id:ident,
+        $room_id:ident,
+        $user_id:ident,
+        $event_type:ident,
+        $state_key:ident,
+        $content:ident
+    ) => {
+        $ty {
+            content: extract_event_content($content, &$event_type)?,
+            event_id: $event_id,
+            event_type: $event_type,
+            prev_content: None,
+            room_id: $room_id,
+            state_key: $state_key.to_string(),
+            unsigned: None,
+            user_id: $user_id,
+        }.try_into()
+    };
+}
+
+impl NewEvent {
+    /// Builds a message event from content given by a client or an operator, checking that the
+    /// content has the structure its event type requires.
+    pub fn message_event(
+        event_id: EventId,
+        room_id: RoomId,
+        user_id: UserId,
+        event_type: EventType,
+        content: Value,
+    ) -> Result<NewEvent, ApiError> {
+        match event_type {
+            EventType::CallAnswer => {
+                room_event!(AnswerEvent, event_id, room_id, user_id, event_type, content)
+            }
+            EventType::CallCandidates => {
+                room_event!(CandidatesEvent, event_id, room_id, user_id, event_type, content)
+            }
+            EventType::CallHangup => {
+                room_event!(HangupEvent, event_id, room_id, user_id, event_type, content)
+            }
+            EventType::CallInvite => {
+                room_event!(InviteEvent, event_id, room_id, user_id, event_type, content)
+            }
+            EventType::RoomMessage => {
+                room_event!(MessageEvent, event_id, room_id, user_id, event_type, content)
+            }
+            EventType::Custom(custom_event_type) => {
+                CustomRoomEvent {
+                    content: content,
+                    event_id: event_id,
+                    event_type: EventType::Custom(custom_event_type),
+                    room_id: room_id,
+                    unsigned: None,
+                    user_id: user_id,
+                }.try_into()
+            }
+            _ => Err(ApiError::bad_event(
+                Some(&format!("Events of type {} cannot be created with this API.", event_type))
+            )),
+        }
+    }
+
+    /// Builds a state event from content given by a client or an operator, checking that the
+    /// content has the structure its event type requires and that the state key is empty for
+    /// the event types that must have one.
+    pub fn state_event(
+        event_id: EventId,
+        room_id: RoomId,
+        user_id: UserId,
+        event_type: EventType,
+        state_key: &str,
+        content: Value,
+    ) -> Result<NewEvent, ApiError> {
+        match event_type {
+            EventType::RoomAvatar |
+            EventType::RoomCanonicalAlias |
+            EventType::RoomGuestAccess |
+            EventType::RoomHistoryVisibility |
+            EventType::RoomJoinRules |
+            EventType::RoomPowerLevels => ensure_empty_state_key(&event_type, state_key)?,
+            EventType::RoomName => {
+                ensure_empty_state_key(&event_type, state_key)?;
+                ensure_max_length(&event_type, &content, "name", MAX_ROOM_NAME_LENGTH)?;
+            }
+            EventType::RoomTopic => {
+                ensure_empty_state_key(&event_type, state_key)?;
+                ensure_max_length(&event_type, &content, "topic", MAX_ROOM_TOPIC_LENGTH)?;
+            }
+            EventType::Custom(ref custom_event_type)
+                if custom_event_type == ENCRYPTION_EVENT_TYPE => {
+                ensure_empty_state_key(&event_type, state_key)?;
+                ensure_encryption_algorithm(&content)?;
+            }
+            _ => {}
+        }
+
+        match event_type {
+            EventType::RoomAvatar => {
+                state_event!(
+                    AvatarEvent,
+                    event_id,
+                    room_id,
+                    user_id,
+                    event_type,
+                    state_key,
+                    content
+                )
+            }
+            EventType::RoomCanonicalAlias => {
+                state_event!(
+                    CanonicalAliasEvent,
+                    event_id,
+                    room_id,
+                    user_id,
+                    event_type,
+                    state_key,
+                    content
+                )
+            }
+            EventType::RoomGuestAccess => {
+                state_event!(
+                    GuestAccessEvent,
+                    event_id,
+                    room_id,
+                    user_id,
+                    event_type,
+                    state_key,
+                    content
+                )
+            }
+            EventType::RoomHistoryVisibility => {
+                state_event!(
+                    HistoryVisibilityEvent,
+                    event_id,
+                    room_id,
+                    user_id,
+                    event_type,
+                    state_key,
+                    content
+                )
+            }
+            EventType::RoomJoinRules => {
+                state_event!(
+                    JoinRulesEvent,
+                    event_id,
+                    room_id,
+                    user_id,
+                    event_type,
+                    state_key,
+                    content
+                )
+            }
+            EventType::RoomName => {
+                state_event!(
+                    NameEvent,
+                    event_id,
+                    room_id,
+                    user_id,
+                    event_type,
+                    state_key,
+                    content
+                )
+            }
+            EventType::RoomPowerLevels => {
+                state_event!(
+                    PowerLevelsEvent,
+                    event_id,
+                    room_id,
+                    user_id,
+                    event_type,
+                    state_key,
+                    content
+                )
+            }
+            EventType::RoomThirdPartyInvite => {
+                state_event!(
+                    ThirdPartyInviteEvent,
+                    event_id,
+                    room_id,
+                    user_id,
+                    event_type,
+                    state_key,
+                    content
+                )
+            }
+            EventType::RoomTopic => {
+                state_event!(
+                    TopicEvent,
+                    event_id,
+                    room_id,
+                    user_id,
+                    event_type,
+                    state_key,
+                    content
+                )
+            }
+            EventType::Custom(custom_event_type) => {
+                CustomStateEvent {
+                    content: content,
+                    event_id: event_id,
+                    event_type: EventType::Custom(custom_event_type),
+                    prev_content: None,
+                    room_id: room_id,
+                    state_key: state_key.to_string(),
+                    unsigned: None,
+                    user_id: user_id,
+                }.try_into()
+            }
+            _ => Err(ApiError::bad_event(
+                Some(&format!("Events of type {} cannot be created with this API.", event_type))
+            )),
+        }
+    }
+}
+
 impl Event {
+    /// Looks up events by ID. Events that don't exist are skipped.
+    pub fn find_by_ids(connection: &PgConnection, event_ids: &[EventId])
+    -> Result<Vec<Event>, ApiError> {
+        let event_ids: Vec<String> = event_ids.iter().map(EventId::to_string).collect();
+
+        events::table
+            .filter(events::id.eq(any(event_ids)))
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Returns up to `limit` of the most recent events in a room with an ordering after `after`
+    /// (if given) and up to and including `up_to`, oldest first.
+    pub fn find_recent_by_room(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        after: Option<i64>,
+        up_to: i64,
+        limit: i64,
+    ) -> Result<Vec<Event>, ApiError> {
+        let mut events: Vec<Event> = events::table
+            .filter(events::room_id.eq(room_id))
+            .filter(events::ordering.gt(after.unwrap_or(0)))
+            .filter(events::ordering.le(up_to))
+            .order(events::ordering.desc())
+            .limit(limit)
+            .get_results(connection)?;
+
+        events.reverse();
+
+        Ok(events)
+    }
+
+    /// Returns up to `limit` events in a room, starting at the position `from` and moving
+    /// backwards or forwards in time, stopping at the position `to` if given.
+    ///
+    /// Positions sit between events: position N is just after the event with the ordering N. The
+    /// events are returned in the order they were paginated through.
+    pub fn find_page_by_room(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        from: i64,
+        to: Option<i64>,
+        backwards: bool,
+        limit: i64,
+    ) -> Result<Vec<Event>, ApiError> {
+        let query = events::table.filter(events::room_id.eq(room_id));
+
+        if backwards {
+            query
+                .filter(events::ordering.le(from))
+                .filter(events::ordering.gt(to.unwrap_or(i64::min_value())))
+                .order(events::ordering.desc())
+                .limit(limit)
+                .get_results(connection)
+                .map_err(ApiError::from)
+        } else {
+            query
+                .filter(events::ordering.gt(from))
+                .filter(events::ordering.le(to.unwrap_or(i64::max_value())))
+                .order(events::ordering.asc())
+                .limit(limit)
+                .get_results(connection)
+                .map_err(ApiError::from)
+        }
+    }
+
+    /// Returns up to `limit` events from any of the given rooms with an ordering before `before`,
+    /// newest first.
+    pub fn find_page_by_rooms(
+        connection: &PgConnection,
+        room_ids: &[RoomId],
+        before: i64,
+        limit: i64,
+    ) -> Result<Vec<Event>, ApiError> {
+        let room_ids: Vec<String> = room_ids.iter().map(RoomId::to_string).collect();
+
+        events::table
+            .filter(events::room_id.eq(any(room_ids)))
+            .filter(events::ordering.lt(before))
+            .order(events::ordering.desc())
+            .limit(limit)
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Returns the ordering of the most recent event up to and including `up_to` in each of the
+    /// given rooms. Rooms without any such events are left out.
+    pub fn latest_orderings_by_rooms(connection: &PgConnection, room_ids: &[RoomId], up_to: i64)
+    -> Result<HashMap<RoomId, i64>, ApiError> {
+        let room_ids: Vec<String> = room_ids.iter().map(RoomId::to_string).collect();
+
+        let latest: Vec<(RoomId, i64)> = events::table
+            .select(sql::<(Text, BigInt)>("DISTINCT ON (room_id) room_id, ordering"))
+            .filter(events::room_id.eq(any(room_ids)))
+            .filter(events::ordering.le(up_to))
+            .order(sql::<BigInt>("room_id, ordering DESC"))
+            .load(connection)?;
+
+        Ok(latest.into_iter().collect())
+    }
+
+    /// Counts the events in a room that relate to the event with the given ID through
+    /// `m.relates_to`.
+    pub fn count_relations(connection: &PgConnection, room_id: &RoomId, relates_to: &str)
+    -> Result<i64, ApiError> {
+        events::table
+            .select(count_star())
+            .filter(events::room_id.eq(room_id))
+            .filter(event_relates_to(events::content).eq(relates_to))
+            .first(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Counts the reactions, *m.annotation* relations, that a user sent to the event with the
+    /// given ID.
+    pub fn count_reactions_by_user(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        relates_to: &str,
+        user_id: &UserId,
+    ) -> Result<i64, ApiError> {
+        events::table
+            .select(count_star())
+            .filter(events::room_id.eq(room_id))
+            .filter(events::user_id.eq(user_id))
+            .filter(event_relates_to(events::content).eq(relates_to))
+            .filter(event_relation_type(events::content).eq("m.annotation"))
+            .first(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Returns the events of the given types in the given rooms whose message body, room name, or
+    /// room topic match the search term, in no particular order.
+    pub fn search(
+        connection: &PgConnection,
+        room_ids: &[RoomId],
+        event_types: &[String],
+        search_term: &str,
+    ) -> Result<Vec<SearchMatch>, ApiError> {
+        let room_ids: Vec<String> = room_ids.iter().map(RoomId::to_string).collect();
+
+        events::table
+            .filter(events::room_id.eq(any(room_ids)))
+            .filter(events::event_type.eq(any(event_types.to_vec())))
+            .filter(event_search_matches(events::content, search_term))
+            .select((
+                events::id,
+                events::user_id,
+                events::ordering,
+                event_search_rank(events::content, search_term),
+            ))
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Returns the state of a room just before the event with the ordering `before`, as the most
+    /// recent event for each event type and state key, oldest first.
+    ///
+    /// If `after` is given, only state that changed after that ordering is returned.
+    pub fn find_state_by_room(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        after: Option<i64>,
+        before: i64,
+    ) -> Result<Vec<Event>, ApiError> {
+        let events: Vec<Event> = events::table
+            .filter(events::room_id.eq(room_id))
+            .filter(events::state_key.is_not_null())
+            .filter(events::ordering.lt(before))
+            .order(events::ordering.asc())
+            .get_results(connection)?;
+
+        let mut current = HashMap::new();
+
+        for event in events {
+            current.insert((event.event_type.clone(), event.state_key.clone()), event);
+        }
+
+        let mut state: Vec<Event> = current
+            .into_iter()
+            .map(|(_, event)| event)
+            .filter(|event| after.map(|after| event.ordering > after).unwrap_or(true))
+            .collect();
+
+        state.sort_by_key(|event| event.ordering);
+
+        Ok(state)
+    }
+
+    /// Returns the most recent state event in a room with the given type and state key, before the
+    /// event with the ordering `before`.
+    pub fn find_state_event(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        event_type: &EventType,
+        state_key: &str,
+        before: i64,
+    ) -> Result<Option<Event>, ApiError> {
+        let event = events::table
+            .filter(events::room_id.eq(room_id))
+            .filter(events::event_type.eq(event_type.to_string()))
+            .filter(events::state_key.eq(state_key))
+            .filter(events::ordering.lt(before))
+            .order(events::ordering.desc())
+            .first(connection);
+
+        match event {
+            Ok(event) => Ok(Some(event)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(error) => Err(ApiError::from(error)),
+        }
+    }
+
+    /// Looks up an event by ID, if it was sent in the given room.
+    pub fn find_in_room(connection: &PgConnection, room_id: &RoomId, event_id: &EventId)
+    -> Result<Event, ApiError> {
+        events::table
+            .filter(events::room_id.eq(room_id))
+            .filter(events::id.eq(event_id.to_string()))
+            .first(connection)
+            .map_err(|error| match error {
+                DieselError::NotFound => ApiError::not_found(
+                    Some(&format!("No event with ID {} was found in the room.", event_id))
+                ),
+                _ => ApiError::from(error),
+            })
+    }
+
+    /// Saves a new event, returning whether or not it was new.
+    ///
+    /// Saving an event that was already saved, such as one delivered again by a retry, changes
+    /// nothing and leaves the saved event as it is. An event that reuses the ID of a different
+    /// event is rejected.
+    pub fn insert(connection: &PgConnection, new_event: &NewEvent) -> Result<bool, ApiError> {
+        // The nested transaction is a savepoint, so the caller's transaction survives a conflict.
+        let result = connection.transaction::<usize, DieselError, _>(|| {
+            insert(new_event).into(events::table).execute(connection)
+        });
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(TransactionError::UserReturnedError(
+                DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)
+            )) => {
+                let existing = Event::find_by_ids(connection, &[new_event.id.clone()])?;
+
+                match existing.first() {
+                    Some(existing) if existing.is_same_event(new_event)? => {
+                        debug!("Event {} was already saved.", new_event.id);
+
+                        Ok(false)
+                    }
+                    _ => Err(ApiError::bad_event(Some(
+                        &format!("A different event with the ID {} already exists.", new_event.id)
+                    ))),
+                }
+            }
+            Err(TransactionError::UserReturnedError(error)) => Err(ApiError::from(error)),
+            Err(TransactionError::CouldntCreateTransaction(error)) => Err(ApiError::from(error)),
+        }
+    }
+
+    /// Saves an event carried over from another room's history, with the time it was originally
+    /// sent, in milliseconds since the Unix epoch, instead of the current time.
+    ///
+    /// Importing an event that was already saved leaves it as it is.
+    pub fn import(connection: &PgConnection, new_event: &NewEvent, origin_server_ts: i64)
+    -> Result<(), ApiError> {
+        connection.transaction::<(), ApiError, _>(|| {
+            if Event::insert(connection, new_event)? {
+                Event::set_origin_server_ts(connection, &new_event.id, origin_server_ts)?;
+            }
+
+            Ok(())
+        }).map_err(ApiError::from)
+    }
+
+    /// Changes the time a saved event says it was sent, in milliseconds since the Unix epoch.
+    pub fn set_origin_server_ts(
+        connection: &PgConnection,
+        event_id: &EventId,
+        origin_server_ts: i64,
+    ) -> Result<(), ApiError> {
+        let created_at = PgTimestamp((origin_server_ts - POSTGRES_EPOCH_MILLIS) * 1000);
+
+        update(events::table.find(event_id.to_string()))
+            .set(events::created_at.eq(created_at))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Saves a historical event like `import`, but at the given ordering, which must be one
+    /// reserved with `StreamPosition::reserve_history`.
+    pub fn backfill(
+        connection: &PgConnection,
+        new_event: &NewEvent,
+        origin_server_ts: i64,
+        ordering: i64,
+    ) -> Result<(), ApiError> {
+        connection.transaction::<(), ApiError, _>(|| {
+            Event::import(connection, new_event, origin_server_ts)?;
+
+            update(events::table.find(new_event.id.to_string()))
+                .set(events::ordering.eq(ordering))
+                .execute(connection)?;
+
+            Ok(())
+        }).map_err(ApiError::from)
+    }
+
+    /// Removes the event's content, except for the keys the redaction algorithm keeps for its
+    /// event type. The event itself stays in the room's history.
+    pub fn redact(&self, connection: &PgConnection) -> Result<(), ApiError> {
+        let content: Value = from_str(&self.content)?;
+        let kept_keys = redaction_kept_keys(&self.event_type);
+
+        let redacted_content: HashMap<String, Value> = match content.as_object() {
+            Some(object) => {
+                object.iter()
+                    .filter(|&(key, _)| kept_keys.contains(&&key[..]))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect()
+            }
+            None => HashMap::new(),
+        };
+
+        update(events::table.find(self.id.to_string()))
+            .set((
+                events::content.eq(to_string(&redacted_content)?),
+                events::extra_content.eq(None::<String>),
+            ))
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+
+    /// Whether or not `new_event` describes this event: the same sender and room, type, state key,
+    /// and content.
+    fn is_same_event(&self, new_event: &NewEvent) -> Result<bool, ApiError> {
+        let content: Value = from_str(&self.content)?;
+        let new_content: Value = from_str(&new_event.content)?;
+
+        Ok(self.room_id == new_event.room_id &&
+            self.user_id == new_event.user_id &&
+            self.event_type == new_event.event_type &&
+            self.state_key == new_event.state_key &&
+            content == new_content)
+    }
+
+    /// Converts the event to the JSON format sent to clients, without an `unsigned` block.
+    pub fn to_json(&self) -> Result<Value, ApiError> {
+        Ok(to_value(&self.to_client_event(None)?))
+    }
+
+    /// Converts the event to the JSON format sent to clients, with its `unsigned` block filled in
+    /// from `unsigned_data`, which must have been loaded for a batch including this event.
+    pub fn to_client_json(&self, unsigned_data: &UnsignedData) -> Result<Value, ApiError> {
+        let event_id = self.id.to_string();
+
+        let unsigned = Unsigned {
+            age: max(unsigned_data.now_millis - self.origin_server_ts(), 0),
+            prev_content: unsigned_data.prev_contents.get(&event_id).cloned(),
+            redacted_because: unsigned_data.redactions.get(&event_id).cloned(),
+            replaces_state: self.replaces_state.as_ref().map(EventId::to_string),
+            transaction_id: unsigned_data.transaction_ids.get(&event_id).cloned(),
+        };
+
+        Ok(to_value(&self.to_client_event(Some(unsigned))?))
+    }
+
+    /// The time the event was sent, in milliseconds since the Unix epoch.
+    pub fn origin_server_ts(&self) -> i64 {
+        self.created_at.0 / 1000 + POSTGRES_EPOCH_MILLIS
+    }
+
+    /// Converts the event to the format sent to clients.
+    fn to_client_event(&self, unsigned: Option<Unsigned>) -> Result<ClientEvent, ApiError> {
+        let redacts = match self.extra_content {
+            Some(ref extra_content) if self.event_type == EventType::RoomRedaction.to_string() => {
+                let extra_content: Value = from_str(extra_content)?;
+
+                extra_content.find("redacts").and_then(Value::as_str).map(str::to_string)
+            }
+            _ => None,
+        };
+
+        let client_event = ClientEvent {
+            content: from_str(&self.content)?,
+            event_id: self.id.to_string(),
+            origin_server_ts: self.origin_server_ts(),
+            redacts: redacts,
+            sender: self.user_id.to_string(),
+            state_key: self.state_key.clone(),
+            event_type: self.event_type.clone(),
+            unsigned: unsigned,
+        };
+
+        Ok(client_event)
+    }
+
     /// Return room join rules for given `room_id`.
     pub fn find_room_join_rules_by_room_id(connection: &PgConnection, room_id: RoomId)
         -> Result<JoinRulesEvent, ApiError>
@@ -97,6 +830,190 @@ impl Event {
     }
 }
 
+impl UnsignedData {
+    /// Looks up the `unsigned` data of the given events as served at `now_millis` to the client
+    /// with the given access token, which only gets transaction IDs for the events it sent itself.
+    pub fn load<'a, I>(
+        connection: &PgConnection,
+        events: I,
+        access_token_id: Option<i64>,
+        now_millis: i64,
+    ) -> Result<UnsignedData, ApiError> where I: IntoIterator<Item = &'a Event> {
+        let events: Vec<&Event> = events.into_iter().collect();
+
+        let transaction_ids = match access_token_id {
+            Some(access_token_id) if !events.is_empty() => {
+                let event_ids: Vec<EventId> = events.iter().map(|event| event.id.clone()).collect();
+
+                Transaction::find_by_event_ids(connection, access_token_id, &event_ids)?
+                    .into_iter()
+                    .map(|transaction| {
+                        (transaction.event_id.to_string(), transaction.transaction_id)
+                    })
+                    .collect()
+            }
+            _ => HashMap::new(),
+        };
+
+        Ok(UnsignedData {
+            now_millis: now_millis,
+            prev_contents: load_prev_contents(connection, &events)?,
+            redactions: load_redactions(connection, &events)?,
+            transaction_ids: transaction_ids,
+        })
+    }
+}
+
+/// Looks up the content each of the given state events replaced, by event ID. Events that didn't
+/// replace a state event are left out.
+fn load_prev_contents(connection: &PgConnection, events: &[&Event])
+-> Result<HashMap<String, Value>, ApiError> {
+    let replaced_ids: Vec<EventId> = events.iter()
+        .filter_map(|event| event.replaces_state.clone())
+        .collect();
+
+    if replaced_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let replaced: HashMap<String, Event> = Event::find_by_ids(connection, &replaced_ids)?
+        .into_iter()
+        .map(|event| (event.id.to_string(), event))
+        .collect();
+
+    let mut prev_contents = HashMap::new();
+
+    for event in events {
+        let replaced_event = event.replaces_state
+            .as_ref()
+            .and_then(|replaced_id| replaced.get(&replaced_id.to_string()));
+
+        if let Some(replaced_event) = replaced_event {
+            prev_contents.insert(event.id.to_string(), from_str(&replaced_event.content)?);
+        }
+    }
+
+    Ok(prev_contents)
+}
+
+/// Looks up the redaction of each of the given events that was redacted, by event ID.
+fn load_redactions(connection: &PgConnection, events: &[&Event])
+-> Result<HashMap<String, Value>, ApiError> {
+    let after = match events.iter().map(|event| event.ordering).min() {
+        Some(after) => after,
+        None => return Ok(HashMap::new()),
+    };
+
+    let event_ids: HashSet<String> = events.iter().map(|event| event.id.to_string()).collect();
+    let room_ids: Vec<String> = events.iter()
+        .map(|event| event.room_id.to_string())
+        .collect::<HashSet<String>>()
+        .into_iter()
+        .collect();
+
+    // Redactions always come after the events they redact.
+    let redactions: Vec<Event> = events::table
+        .filter(events::room_id.eq(any(room_ids)))
+        .filter(events::event_type.eq(EventType::RoomRedaction.to_string()))
+        .filter(events::ordering.gt(after))
+        .get_results(connection)?;
+
+    let mut redacted_because = HashMap::new();
+
+    for redaction in redactions {
+        let redacts = match redaction.extra_content {
+            Some(ref extra_content) => {
+                let extra_content: Value = from_str(extra_content)?;
+
+                extra_content.find("redacts").and_then(Value::as_str).map(str::to_string)
+            }
+            None => None,
+        };
+
+        if let Some(redacts) = redacts {
+            if event_ids.contains(&redacts) {
+                redacted_because.insert(redacts, redaction.to_json()?);
+            }
+        }
+    }
+
+    Ok(redacted_because)
+}
+
+/// Enforces an empty state key for an event type that requires it.
+fn ensure_empty_state_key(event_type: &EventType, state_key: &str) -> Result<(), ApiError> {
+    if state_key == "" {
+        Ok(())
+    } else {
+        Err(ApiError::bad_event(
+            Some(&format!("Events of type {} must have an empty state key.", event_type))
+        ))
+    }
+}
+
+/// Enforces an `algorithm` in the content of an *m.room.encryption* event, since clients can't
+/// encrypt messages without one.
+fn ensure_encryption_algorithm(content: &Value) -> Result<(), ApiError> {
+    match content.find("algorithm").and_then(Value::as_str) {
+        Some(algorithm) if !algorithm.is_empty() => Ok(()),
+        _ => Err(ApiError::bad_event(
+            Some("Events of type m.room.encryption must have an algorithm.")
+        )),
+    }
+}
+
+/// Rejects content whose string `field` is longer than `max_length` bytes, so huge names and
+/// topics aren't stored and sent to every member.
+fn ensure_max_length(event_type: &EventType, content: &Value, field: &str, max_length: usize)
+-> Result<(), ApiError> {
+    match content.find(field).and_then(Value::as_str) {
+        Some(text) if text.len() > max_length => Err(ApiError::too_large(Some(&format!(
+            "The {} of events of type {} must be at most {} bytes.",
+            field,
+            event_type,
+            max_length
+        )))),
+        _ => Ok(()),
+    }
+}
+
+/// Convert the JSON content into the correct type for the event's `content` field.
+fn extract_event_content<T: Deserialize>(content: Value, event_type: &EventType)
+-> Result<T, ApiError> {
+    from_value(content).map_err(|_| {
+        ApiError::bad_event(
+            Some(
+                &format!(
+                    "Event content did not match expected structure for event of type {}.",
+                    event_type
+                )
+            )
+        )
+    })
+}
+
+/// The content keys that survive a redaction of an event of the given type.
+fn redaction_kept_keys(event_type: &str) -> &'static [&'static str] {
+    match EventType::from(event_type) {
+        EventType::RoomAliases => &["aliases"],
+        EventType::RoomCreate => &["creator"],
+        EventType::RoomHistoryVisibility => &["history_visibility"],
+        EventType::RoomJoinRules => &["join_rule"],
+        EventType::RoomMember => &["membership"],
+        EventType::RoomPowerLevels => &[
+            "ban",
+            "events",
+            "events_default",
+            "kick",
+            "redact",
+            "state_default",
+            "users",
+            "users_default",
+        ],
+        _ => &[],
+    }
+}
+
 macro_rules! impl_try_from_room_event_for_new_event {
     ($ty:ty) => {
         impl TryFrom<$ty> for NewEvent {
@@ -0,0 +1,152 @@
+//! Automatic redaction of events after a deadline.
+//!
+//! An event expires either at the time given in its content's `m.self_destruct_after` field or,
+//! if the room has an `m.room.retention` state event, `max_lifetime` milliseconds after it was
+//! sent, whichever comes first. Expired events keep their place in the room's history, but their
+//! content is removed as it would be by a redaction.
+
+use std::cmp::min;
+use std::time::Duration;
+
+use diesel::{
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LoadDsl,
+    OrderDsl,
+    delete,
+    insert,
+    update,
+};
+use diesel::pg::PgConnection;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::{EventId, RoomId};
+use serde_json::{Value, from_str};
+
+use config::Config;
+use error::ApiError;
+use event::{Event, NewEvent};
+use jobs::Job;
+use schema::{event_expirations, events};
+
+/// The content field holding the time an event should expire, in milliseconds since the epoch.
+pub const SELF_DESTRUCT_AFTER: &'static str = "m.self_destruct_after";
+
+/// The state event type holding a room's default event lifetime.
+pub const RETENTION_EVENT_TYPE: &'static str = "m.room.retention";
+
+/// A scheduled expiration for an event.
+#[derive(Debug, Insertable, Queryable)]
+#[table_name = "event_expirations"]
+pub struct EventExpiration {
+    /// The event that will expire.
+    pub event_id: EventId,
+    /// The room the event belongs to.
+    pub room_id: RoomId,
+    /// When the event expires, in milliseconds since the Unix epoch.
+    pub expires_at: i64,
+}
+
+/// A background job that redacts events once they expire.
+#[derive(Debug)]
+pub struct ExpireEvents;
+
+impl EventExpiration {
+    /// Schedules the expiration of a newly sent event, if it or its room asks for one.
+    ///
+    /// State events never expire, since removing their content would change the room's state.
+    pub fn schedule(connection: &PgConnection, new_event: &NewEvent, now: i64)
+    -> Result<Option<EventExpiration>, ApiError> {
+        if new_event.state_key.is_some() {
+            return Ok(None);
+        }
+
+        let content: Value = from_str(&new_event.content)?;
+        let requested = content.find(SELF_DESTRUCT_AFTER).and_then(|value| value.as_i64());
+        let room_default = EventExpiration::room_max_lifetime(connection, &new_event.room_id)?
+            .map(|max_lifetime| now + max_lifetime);
+
+        let expires_at = match (requested, room_default) {
+            (Some(requested), Some(room_default)) => min(requested, room_default),
+            (Some(expires_at), None) | (None, Some(expires_at)) => expires_at,
+            (None, None) => return Ok(None),
+        };
+
+        let expiration = EventExpiration {
+            event_id: new_event.id.clone(),
+            room_id: new_event.room_id.clone(),
+            expires_at: expires_at,
+        };
+
+        insert(&expiration)
+            .into(event_expirations::table)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(Some(expiration))
+    }
+
+    /// Returns all expirations due at or before `now`.
+    pub fn find_due(connection: &PgConnection, now: i64)
+    -> Result<Vec<EventExpiration>, ApiError> {
+        event_expirations::table
+            .filter(event_expirations::expires_at.le(now))
+            .order(event_expirations::expires_at.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Removes the event's content and the expiration itself.
+    pub fn expire(&self, connection: &PgConnection) -> Result<(), ApiError> {
+        connection.transaction::<(), ApiError, _>(|| {
+            update(events::table.find(self.event_id.to_string()))
+                .set((events::content.eq("{}"), events::extra_content.eq(None::<String>)))
+                .execute(connection)?;
+
+            delete(event_expirations::table.find(self.event_id.to_string()))
+                .execute(connection)?;
+
+            Ok(())
+        }).map_err(ApiError::from)
+    }
+
+    /// Looks up the `max_lifetime` of the room's current `m.room.retention` event.
+    fn room_max_lifetime(connection: &PgConnection, room_id: &RoomId)
+    -> Result<Option<i64>, ApiError> {
+        let event = match events::table
+            .filter(events::room_id.eq(room_id.to_string()))
+            .filter(events::event_type.eq(RETENTION_EVENT_TYPE))
+            .filter(events::state_key.eq(""))
+            .order(events::ordering.desc())
+            .first::<Event>(connection)
+        {
+            Ok(event) => event,
+            Err(DieselError::NotFound) => return Ok(None),
+            Err(error) => return Err(ApiError::from(error)),
+        };
+
+        let content: Value = from_str(&event.content)?;
+
+        Ok(content.find("max_lifetime").and_then(|value| value.as_i64()))
+    }
+}
+
+impl Job for ExpireEvents {
+    fn name(&self) -> &'static str {
+        "expire_events"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn run(&self, connection: &PgConnection, _config: &Config, now: i64) -> Result<(), ApiError> {
+        for expiration in EventExpiration::find_due(connection, now)? {
+            expiration.expire(connection)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+//! Sending events from the command line, for operators repairing rooms.
+//!
+//! Events go through the same checks as events clients send: the content must have the structure
+//! its type requires, and the sender must be a member of the room with the power level to send
+//! it. A room whose power levels lock everyone out can be fixed by sending new power levels as a
+//! user who still has enough power, without touching the database by hand.
+
+use std::convert::TryFrom;
+
+use diesel::Connection;
+use diesel::pg::PgConnection;
+use ruma_events::EventType;
+use ruma_identifiers::{EventId, RoomId, UserId};
+use serde_json::{Value, from_str};
+
+use clock::{Clock, SystemClock};
+use config::Config;
+use error::CliError;
+use event::NewEvent;
+use membership_cache::MembershipCache;
+use power_levels::PowerLevelsCache;
+use room::Room;
+use user::User;
+
+/// The event to send with the `send-event` command.
+#[derive(Debug)]
+pub struct EventInjectionOptions<'a> {
+    /// The ID of the room to send the event in.
+    pub room_id: &'a str,
+    /// The local user to send the event as.
+    pub sender: &'a str,
+    /// The type of the event, e.g. *m.room.power_levels*.
+    pub event_type: &'a str,
+    /// The state key, for state events.
+    pub state_key: Option<&'a str>,
+    /// The event's content, as a JSON object.
+    pub content: &'a str,
+}
+
+/// Sends an event to a room in the database described by `config`, returning its ID.
+pub fn send_event(config: &Config, options: &EventInjectionOptions) -> Result<EventId, CliError> {
+    let room_id = RoomId::try_from(options.room_id)
+        .map_err(|_| CliError::new(format!("{} is not a valid room ID.", options.room_id)))?;
+
+    let sender = UserId::try_from(options.sender)
+        .map_err(|_| CliError::new(format!("{} is not a valid user ID.", options.sender)))?;
+
+    let content: Value = from_str(options.content)
+        .map_err(|_| CliError::new("The content must be valid JSON."))?;
+
+    if !content.is_object() {
+        return Err(CliError::new("The content must be a JSON object."));
+    }
+
+    let connection = PgConnection::establish(&config.postgres_url)?;
+
+    User::find_by_uid(&connection, &sender)?;
+
+    let room = Room::find(&connection, &room_id)?;
+
+    let event_id = EventId::new(&config.domain)?;
+    let event_type = EventType::from(options.event_type);
+
+    let new_event = match options.state_key {
+        Some(state_key) => NewEvent::state_event(
+            event_id.clone(),
+            room_id,
+            sender,
+            event_type,
+            state_key,
+            content,
+        )?,
+        None => NewEvent::message_event(event_id.clone(), room_id, sender, event_type, content)?,
+    };
+
+    room.send_event(
+        &connection,
+        &MembershipCache::new(),
+        &PowerLevelsCache::new(),
+        &new_event,
+        SystemClock.now_millis(),
+    )?;
+
+    Ok(event_id)
+}
@@ -0,0 +1,684 @@
+//! Filters that control which events are returned to a client.
+//!
+//! The structure of these types mirrors the filter definitions in the client-server spec, so a
+//! filter can be deserialized directly from the JSON a client uploads or passes to `/sync`.
+
+use std::collections::HashSet;
+
+use diesel::{ExpressionMethods, FilterDsl, LoadDsl, insert};
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::{Value, from_str};
+
+use error::ApiError;
+use schema::filters;
+
+/// A filter a user uploaded so they can refer to it by ID.
+#[derive(Debug, Queryable)]
+pub struct StoredFilter {
+    /// The filter's ID, which clients see as a string.
+    pub id: i64,
+    /// The user who uploaded the filter.
+    pub user_id: UserId,
+    /// The filter as the JSON the client uploaded.
+    pub content: String,
+    /// The time the filter was uploaded.
+    pub created_at: PgTimestamp,
+}
+
+/// An uploaded filter, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "filters"]
+pub struct NewStoredFilter {
+    /// The user who uploaded the filter.
+    pub user_id: UserId,
+    /// The filter as the JSON the client uploaded.
+    pub content: String,
+}
+
+/// A filter describing which events should be returned to a client.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Filter {
+    /// Account data that isn't associated with a room.
+    pub account_data: Option<EventFilter>,
+    /// Which fields of each event to include.
+    pub event_fields: Option<Vec<String>>,
+    /// The format to use for events, either "client" or "federation".
+    pub event_format: Option<String>,
+    /// Presence updates.
+    pub presence: Option<EventFilter>,
+    /// Data associated with rooms.
+    pub room: Option<RoomFilter>,
+}
+
+/// A filter for events that aren't associated with a room.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct EventFilter {
+    /// The maximum number of events to return.
+    pub limit: Option<u64>,
+    /// Senders to exclude.
+    pub not_senders: Option<Vec<String>>,
+    /// Event types to exclude. A '*' matches any sequence of characters.
+    pub not_types: Option<Vec<String>>,
+    /// If present, only these senders are included.
+    pub senders: Option<Vec<String>>,
+    /// If present, only these event types are included. A '*' matches any sequence of characters.
+    pub types: Option<Vec<String>>,
+}
+
+/// Filters for the data associated with rooms.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RoomFilter {
+    /// Per-room account data.
+    pub account_data: Option<RoomEventFilter>,
+    /// Ephemeral events, such as typing notifications and receipts.
+    pub ephemeral: Option<RoomEventFilter>,
+    /// Whether or not to include rooms the user has left.
+    pub include_leave: Option<bool>,
+    /// Rooms to exclude from every section.
+    pub not_rooms: Option<Vec<String>>,
+    /// If present, only these rooms are included in any section.
+    pub rooms: Option<Vec<String>>,
+    /// Room state events.
+    pub state: Option<RoomEventFilter>,
+    /// Timeline events.
+    pub timeline: Option<RoomEventFilter>,
+}
+
+/// A filter for events associated with a room.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RoomEventFilter {
+    /// If present, only include events that do (true) or don't (false) have a URL in their content.
+    pub contains_url: Option<bool>,
+    /// Whether to send membership events that were already sent to the client when lazy loading.
+    pub include_redundant_members: Option<bool>,
+    /// Whether to only send membership events for the senders of timeline events.
+    pub lazy_load_members: Option<bool>,
+    /// The maximum number of events to return.
+    pub limit: Option<u64>,
+    /// Rooms to exclude.
+    pub not_rooms: Option<Vec<String>>,
+    /// Senders to exclude.
+    pub not_senders: Option<Vec<String>>,
+    /// Event types to exclude. A '*' matches any sequence of characters.
+    pub not_types: Option<Vec<String>>,
+    /// If present, only these rooms are included.
+    pub rooms: Option<Vec<String>>,
+    /// If present, only these senders are included.
+    pub senders: Option<Vec<String>>,
+    /// If present, only these event types are included. A '*' matches any sequence of characters.
+    pub types: Option<Vec<String>>,
+}
+
+impl Filter {
+    /// Applies the top-level `account_data` filter to a user's global account data events.
+    pub fn filter_account_data(&self, events: Vec<Value>) -> Vec<Value> {
+        match self.account_data {
+            Some(ref filter) => filter.apply(events),
+            None => events,
+        }
+    }
+
+    /// Applies the top-level `presence` filter to presence events.
+    pub fn filter_presence(&self, events: Vec<Value>) -> Vec<Value> {
+        match self.presence {
+            Some(ref filter) => filter.apply(events),
+            None => events,
+        }
+    }
+
+    /// Whether or not a room should appear in the response at all.
+    pub fn includes_room(&self, room_id: &RoomId) -> bool {
+        match self.room {
+            Some(ref filter) => filter.includes_room(room_id),
+            None => true,
+        }
+    }
+
+    /// Whether or not rooms the user has left should be included.
+    pub fn includes_leave(&self) -> bool {
+        self.room.as_ref().and_then(|filter| filter.include_leave).unwrap_or(false)
+    }
+
+    /// Applies the `room.account_data` filter to a room's account data events.
+    pub fn filter_room_account_data(&self, room_id: &RoomId, events: Vec<Value>) -> Vec<Value> {
+        self.filter_room_section(room_id, events, |filter| filter.account_data.as_ref())
+    }
+
+    /// Applies the `room.ephemeral` filter to a room's ephemeral events.
+    pub fn filter_ephemeral(&self, room_id: &RoomId, events: Vec<Value>) -> Vec<Value> {
+        self.filter_room_section(room_id, events, |filter| filter.ephemeral.as_ref())
+    }
+
+    /// The maximum number of timeline events to return for each room, if the filter sets one.
+    pub fn timeline_limit(&self) -> Option<u64> {
+        self.room
+            .as_ref()
+            .and_then(|filter| filter.timeline.as_ref())
+            .and_then(|filter| filter.limit)
+    }
+
+    /// Whether or not the `room.state` filter enables `lazy_load_members`.
+    pub fn lazy_loads_members(&self) -> bool {
+        self.room
+            .as_ref()
+            .and_then(|filter| filter.state.as_ref())
+            .and_then(|filter| filter.lazy_load_members)
+            .unwrap_or(false)
+    }
+
+    /// Applies the `room.timeline` filter to a room's timeline events.
+    pub fn filter_timeline(&self, room_id: &RoomId, events: Vec<Value>) -> Vec<Value> {
+        self.filter_room_section(room_id, events, |filter| filter.timeline.as_ref())
+    }
+
+    /// Applies the `room.state` filter to a room's state events.
+    ///
+    /// `timeline` is the timeline being returned alongside the state, and `sent_members` holds
+    /// the user IDs whose membership events this client has already received for the room. Both
+    /// are only consulted when the filter enables `lazy_load_members`.
+    pub fn filter_state(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        events: Vec<Value>,
+        timeline: &[Value],
+        sent_members: &HashSet<String>,
+    ) -> Vec<Value> {
+        let room_filter = match self.room {
+            Some(ref room_filter) => room_filter,
+            None => return events,
+        };
+
+        if !room_filter.includes_room(room_id) {
+            return Vec::new();
+        }
+
+        let filter = match room_filter.state {
+            Some(ref filter) if filter.includes_room(room_id) => filter,
+            Some(_) => return Vec::new(),
+            None => return events,
+        };
+
+        let events = events.into_iter().filter(|event| filter.matches(event)).collect();
+
+        if !filter.lazy_load_members.unwrap_or(false) {
+            return limit(events, filter.limit);
+        }
+
+        // The syncing user always needs their own membership, even if they haven't sent anything.
+        let user_id = user_id.to_string();
+        let mut needed: HashSet<&str> = timeline
+            .iter()
+            .filter_map(|event| event.find("sender").and_then(|sender| sender.as_str()))
+            .collect();
+        needed.insert(&user_id);
+
+        let include_redundant_members = filter.include_redundant_members.unwrap_or(false);
+
+        let events = events.into_iter().filter(|event| {
+            let event_type = event.find("type").and_then(|event_type| event_type.as_str());
+
+            if event_type != Some("m.room.member") {
+                return true;
+            }
+
+            let state_key = match event.find("state_key").and_then(|state_key| state_key.as_str()) {
+                Some(state_key) => state_key,
+                None => return false,
+            };
+
+            needed.contains(state_key) &&
+                (include_redundant_members || !sent_members.contains(state_key))
+        }).collect();
+
+        limit(events, filter.limit)
+    }
+
+    /// Applies one of the room filter's sections, dropping everything if the room is excluded.
+    fn filter_room_section<F>(&self, room_id: &RoomId, events: Vec<Value>, section: F)
+    -> Vec<Value> where F: Fn(&RoomFilter) -> Option<&RoomEventFilter> {
+        let room_filter = match self.room {
+            Some(ref room_filter) => room_filter,
+            None => return events,
+        };
+
+        if !room_filter.includes_room(room_id) {
+            return Vec::new();
+        }
+
+        match section(room_filter) {
+            Some(filter) if filter.includes_room(room_id) => filter.apply(events),
+            Some(_) => Vec::new(),
+            None => events,
+        }
+    }
+}
+
+impl StoredFilter {
+    /// Saves an uploaded filter.
+    pub fn create(connection: &PgConnection, new_filter: &NewStoredFilter)
+    -> Result<StoredFilter, ApiError> {
+        insert(new_filter)
+            .into(filters::table)
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Looks up one of the user's filters by the ID it was given when uploaded.
+    ///
+    /// Filters belong to the user who uploaded them, so other users' filter IDs are not found.
+    pub fn find(connection: &PgConnection, user_id: &UserId, filter_id: &str)
+    -> Result<Option<StoredFilter>, ApiError> {
+        let id: i64 = match filter_id.parse() {
+            Ok(id) => id,
+            Err(_) => return Ok(None),
+        };
+
+        let filter = filters::table
+            .filter(filters::id.eq(id))
+            .filter(filters::user_id.eq(user_id))
+            .first(connection);
+
+        match filter {
+            Ok(filter) => Ok(Some(filter)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(error) => Err(ApiError::from(error)),
+        }
+    }
+
+    /// Parses the stored JSON into a `Filter`.
+    pub fn filter(&self) -> Result<Filter, ApiError> {
+        from_str(&self.content).map_err(ApiError::from)
+    }
+}
+
+impl EventFilter {
+    /// Whether or not an event matches the filter.
+    pub fn matches(&self, event: &Value) -> bool {
+        matches_types(&self.types, &self.not_types, event) &&
+            matches_senders(&self.senders, &self.not_senders, event)
+    }
+
+    /// Removes events that don't match the filter and applies the limit.
+    ///
+    /// Events are expected in chronological order, so the most recent events are kept.
+    pub fn apply(&self, events: Vec<Value>) -> Vec<Value> {
+        let events = events.into_iter().filter(|event| self.matches(event)).collect();
+
+        limit(events, self.limit)
+    }
+}
+
+impl RoomFilter {
+    /// Whether or not a room passes the `rooms` and `not_rooms` lists.
+    pub fn includes_room(&self, room_id: &RoomId) -> bool {
+        matches_rooms(&self.rooms, &self.not_rooms, room_id)
+    }
+}
+
+impl RoomEventFilter {
+    /// Whether or not a room passes the `rooms` and `not_rooms` lists.
+    pub fn includes_room(&self, room_id: &RoomId) -> bool {
+        matches_rooms(&self.rooms, &self.not_rooms, room_id)
+    }
+
+    /// Whether or not a sender passes the `senders` and `not_senders` lists.
+    pub fn includes_sender(&self, sender: &UserId) -> bool {
+        matches_sender(&self.senders, &self.not_senders, &sender.to_string())
+    }
+
+    /// Whether or not an event type passes the `types` and `not_types` patterns.
+    pub fn includes_type(&self, event_type: &str) -> bool {
+        matches_type(&self.types, &self.not_types, event_type)
+    }
+
+    /// Whether or not an event matches the filter, ignoring the room it belongs to.
+    pub fn matches(&self, event: &Value) -> bool {
+        if !matches_types(&self.types, &self.not_types, event) ||
+            !matches_senders(&self.senders, &self.not_senders, event) {
+            return false;
+        }
+
+        match self.contains_url {
+            Some(contains_url) => {
+                let has_url = event.find_path(&["content", "url"]).is_some();
+
+                has_url == contains_url
+            }
+            None => true,
+        }
+    }
+
+    /// Removes events that don't match the filter and applies the limit.
+    ///
+    /// Events are expected in chronological order, so the most recent events are kept.
+    pub fn apply(&self, events: Vec<Value>) -> Vec<Value> {
+        let events = events.into_iter().filter(|event| self.matches(event)).collect();
+
+        limit(events, self.limit)
+    }
+}
+
+/// Keeps the last `limit` events.
+fn limit(mut events: Vec<Value>, limit: Option<u64>) -> Vec<Value> {
+    if let Some(limit) = limit {
+        let limit = limit as usize;
+
+        if events.len() > limit {
+            let excess = events.len() - limit;
+
+            events.drain(..excess);
+        }
+    }
+
+    events
+}
+
+/// Checks a room against optional inclusion and exclusion lists.
+fn matches_rooms(rooms: &Option<Vec<String>>, not_rooms: &Option<Vec<String>>, room_id: &RoomId)
+-> bool {
+    let room_id = room_id.to_string();
+
+    if let Some(ref not_rooms) = *not_rooms {
+        if not_rooms.contains(&room_id) {
+            return false;
+        }
+    }
+
+    match *rooms {
+        Some(ref rooms) => rooms.contains(&room_id),
+        None => true,
+    }
+}
+
+/// Checks an event's sender against optional inclusion and exclusion lists.
+///
+/// Events without a sender, such as receipts and typing notifications, can't be excluded by
+/// sender.
+fn matches_senders(senders: &Option<Vec<String>>, not_senders: &Option<Vec<String>>, event: &Value)
+-> bool {
+    match event.find("sender").and_then(|sender| sender.as_str()) {
+        Some(sender) => matches_sender(senders, not_senders, sender),
+        None => true,
+    }
+}
+
+/// Checks a sender against optional inclusion and exclusion lists.
+fn matches_sender(senders: &Option<Vec<String>>, not_senders: &Option<Vec<String>>, sender: &str)
+-> bool {
+    if let Some(ref not_senders) = *not_senders {
+        if not_senders.iter().any(|not_sender| not_sender == sender) {
+            return false;
+        }
+    }
+
+    match *senders {
+        Some(ref senders) => senders.iter().any(|allowed| allowed == sender),
+        None => true,
+    }
+}
+
+/// Checks an event's type against optional inclusion and exclusion patterns.
+fn matches_types(types: &Option<Vec<String>>, not_types: &Option<Vec<String>>, event: &Value)
+-> bool {
+    let event_type = event.find("type").and_then(|event_type| event_type.as_str()).unwrap_or("");
+
+    matches_type(types, not_types, event_type)
+}
+
+/// Checks an event type against optional inclusion and exclusion patterns.
+fn matches_type(types: &Option<Vec<String>>, not_types: &Option<Vec<String>>, event_type: &str)
+-> bool {
+    if let Some(ref not_types) = *not_types {
+        if not_types.iter().any(|pattern| matches_pattern(pattern, event_type)) {
+            return false;
+        }
+    }
+
+    match *types {
+        Some(ref types) => types.iter().any(|pattern| matches_pattern(pattern, event_type)),
+        None => true,
+    }
+}
+
+/// Matches an event type against a pattern in which '*' matches any sequence of characters.
+fn matches_pattern(pattern: &str, event_type: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+
+    if !event_type.starts_with(first) {
+        return false;
+    }
+
+    let mut rest = &event_type[first.len()..];
+    let parts: Vec<&str> = parts.collect();
+
+    // Without a wildcard the pattern must match exactly.
+    if parts.is_empty() {
+        return rest.is_empty();
+    }
+
+    let (last, middle) = parts.split_last().expect("parts is not empty");
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::convert::TryFrom;
+
+    use ruma_identifiers::{RoomId, UserId};
+    use serde_json::{Value, from_str};
+
+    use super::{Filter, matches_pattern};
+
+    fn events(json: &str) -> Vec<Value> {
+        from_str(json).unwrap()
+    }
+
+    #[test]
+    fn wildcard_patterns() {
+        assert!(matches_pattern("m.room.message", "m.room.message"));
+        assert!(!matches_pattern("m.room", "m.room.message"));
+        assert!(matches_pattern("m.room.*", "m.room.message"));
+        assert!(matches_pattern("*.message", "m.room.message"));
+        assert!(matches_pattern("m.*.message", "m.room.message"));
+        assert!(!matches_pattern("m.*.topic", "m.room.message"));
+        assert!(matches_pattern("*", "m.typing"));
+    }
+
+    #[test]
+    fn account_data_types_and_limit() {
+        let filter: Filter = from_str(
+            r#"{"account_data":{"types":["m.*"],"not_types":["m.direct"],"limit":1}}"#
+        ).unwrap();
+
+        let filtered = filter.filter_account_data(events(r#"[
+            {"type":"m.push_rules","content":{}},
+            {"type":"m.direct","content":{}},
+            {"type":"org.example.custom","content":{}},
+            {"type":"m.ignored_user_list","content":{}}
+        ]"#));
+
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].find("type").unwrap().as_str().unwrap(), "m.ignored_user_list");
+    }
+
+    #[test]
+    fn ephemeral_respects_types_and_rooms() {
+        let filter: Filter = from_str(r#"{"room":{"ephemeral":{
+            "types":["m.receipt"],
+            "not_rooms":["!excluded:ruma.test"]
+        }}}"#).unwrap();
+
+        let included = RoomId::try_from("!included:ruma.test").unwrap();
+        let excluded = RoomId::try_from("!excluded:ruma.test").unwrap();
+        let ephemeral = r#"[
+            {"type":"m.typing","content":{"user_ids":[]}},
+            {"type":"m.receipt","content":{}}
+        ]"#;
+
+        let filtered = filter.filter_ephemeral(&included, events(ephemeral));
+
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].find("type").unwrap().as_str().unwrap(), "m.receipt");
+        assert!(filter.filter_ephemeral(&excluded, events(ephemeral)).is_empty());
+    }
+
+    #[test]
+    fn room_level_not_rooms_applies_to_every_section() {
+        let filter: Filter = from_str(r#"{"room":{"not_rooms":["!muted:ruma.test"]}}"#).unwrap();
+        let muted = RoomId::try_from("!muted:ruma.test").unwrap();
+
+        assert!(!filter.includes_room(&muted));
+        assert!(filter.filter_room_account_data(
+            &muted,
+            events(r#"[{"type":"m.tag","content":{}}]"#),
+        ).is_empty());
+    }
+
+    #[test]
+    fn missing_sections_include_everything() {
+        let filter = Filter::default();
+        let room_id = RoomId::try_from("!room:ruma.test").unwrap();
+
+        assert_eq!(filter.filter_ephemeral(
+            &room_id,
+            events(r#"[{"type":"m.typing","content":{}},{"type":"m.receipt","content":{}}]"#),
+        ).len(), 2);
+    }
+
+    const STATE: &'static str = r#"[
+        {"type":"m.room.create","state_key":"","sender":"@carl:ruma.test","content":{}},
+        {"type":"m.room.name","state_key":"","sender":"@carl:ruma.test","content":{}},
+        {"type":"m.room.member","state_key":"@alice:ruma.test","sender":"@alice:ruma.test",
+            "content":{"membership":"join"}},
+        {"type":"m.room.member","state_key":"@bob:ruma.test","sender":"@bob:ruma.test",
+            "content":{"membership":"join"}},
+        {"type":"m.room.member","state_key":"@carl:ruma.test","sender":"@carl:ruma.test",
+            "content":{"membership":"join"}}
+    ]"#;
+
+    const TIMELINE: &'static str = r#"[
+        {"type":"m.room.message","sender":"@bob:ruma.test","content":{"body":"hi"}}
+    ]"#;
+
+    fn state_keys(filter: &str, sent_members: &[&str]) -> Vec<String> {
+        let filter: Filter = from_str(filter).unwrap();
+        let room_id = RoomId::try_from("!room:ruma.test").unwrap();
+        let user_id = UserId::try_from("@alice:ruma.test").unwrap();
+        let sent_members: HashSet<String> = sent_members.iter().map(|s| s.to_string()).collect();
+
+        filter.filter_state(&room_id, &user_id, events(STATE), &events(TIMELINE), &sent_members)
+            .iter()
+            .map(|event| format!(
+                "{}|{}",
+                event.find("type").unwrap().as_str().unwrap(),
+                event.find("state_key").unwrap().as_str().unwrap()
+            ))
+            .collect()
+    }
+
+    #[test]
+    fn state_without_lazy_loading_includes_every_member() {
+        assert_eq!(state_keys(r#"{"room":{"state":{}}}"#, &["@bob:ruma.test"]), vec![
+            "m.room.create|",
+            "m.room.name|",
+            "m.room.member|@alice:ruma.test",
+            "m.room.member|@bob:ruma.test",
+            "m.room.member|@carl:ruma.test",
+        ]);
+    }
+
+    #[test]
+    fn state_include_redundant_members_is_ignored_without_lazy_loading() {
+        assert_eq!(
+            state_keys(
+                r#"{"room":{"state":{"include_redundant_members":false}}}"#,
+                &["@bob:ruma.test"],
+            ),
+            state_keys(r#"{"room":{"state":{}}}"#, &[])
+        );
+    }
+
+    #[test]
+    fn state_lazy_loading_only_includes_timeline_senders_and_self() {
+        assert_eq!(state_keys(r#"{"room":{"state":{"lazy_load_members":true}}}"#, &[]), vec![
+            "m.room.create|",
+            "m.room.name|",
+            "m.room.member|@alice:ruma.test",
+            "m.room.member|@bob:ruma.test",
+        ]);
+    }
+
+    #[test]
+    fn state_lazy_loading_omits_redundant_members_by_default() {
+        assert_eq!(state_keys(
+            r#"{"room":{"state":{"lazy_load_members":true}}}"#,
+            &["@bob:ruma.test"],
+        ), vec![
+            "m.room.create|",
+            "m.room.name|",
+            "m.room.member|@alice:ruma.test",
+        ]);
+    }
+
+    #[test]
+    fn state_lazy_loading_with_redundant_members() {
+        assert_eq!(state_keys(
+            r#"{"room":{"state":{"lazy_load_members":true,"include_redundant_members":true}}}"#,
+            &["@alice:ruma.test", "@bob:ruma.test"],
+        ), vec![
+            "m.room.create|",
+            "m.room.name|",
+            "m.room.member|@alice:ruma.test",
+            "m.room.member|@bob:ruma.test",
+        ]);
+    }
+
+    #[test]
+    fn state_types_apply_before_lazy_loading() {
+        assert_eq!(
+            state_keys(
+                r#"{"room":{"state":{"types":["m.room.member"],"lazy_load_members":true}}}"#,
+                &[],
+            ),
+            vec!["m.room.member|@alice:ruma.test", "m.room.member|@bob:ruma.test"]
+        );
+    }
+
+    #[test]
+    fn state_not_types_can_exclude_members_entirely() {
+        assert_eq!(
+            state_keys(
+                r#"{"room":{"state":{"not_types":["m.room.member"],"lazy_load_members":true}}}"#,
+                &[],
+            ),
+            vec!["m.room.create|", "m.room.name|"]
+        );
+    }
+
+    #[test]
+    fn state_not_types_wildcard_without_lazy_loading() {
+        assert_eq!(
+            state_keys(r#"{"room":{"state":{"not_types":["m.room.n*"]}}}"#, &[]),
+            vec![
+                "m.room.create|",
+                "m.room.member|@alice:ruma.test",
+                "m.room.member|@bob:ruma.test",
+                "m.room.member|@carl:ruma.test",
+            ]
+        );
+    }
+}
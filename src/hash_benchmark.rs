@@ -0,0 +1,136 @@
+//! Measures how long password hashing takes on this host, to help choose Argon2 parameters.
+//!
+//! Hashing happens on every login, so the parameters trade the cost of guessing a stolen hash
+//! against login latency. The benchmark times each memory size from the smallest up, finds the
+//! number of passes that fits the target latency, and recommends the largest memory size that
+//! still allows `MIN_PASSES` passes.
+
+use std::time::{Duration, Instant};
+
+use crypto::{Argon2Params, hash_password};
+use error::CliError;
+
+/// The smallest memory size tried, in kibibytes.
+const MIN_MEMORY_KIB: u32 = 4096;
+
+/// The fewest passes a recommended configuration may use.
+const MIN_PASSES: u32 = 3;
+
+/// The password that is hashed.
+const PASSWORD: &'static str = "correct horse battery staple";
+
+/// How to run the benchmark.
+#[derive(Clone, Debug)]
+pub struct HashBenchmarkOptions {
+    /// The number of lanes to hash with.
+    pub lanes: u32,
+    /// The largest memory size to try, in kibibytes.
+    pub max_memory_kib: u32,
+    /// The number of times each configuration is hashed, taking the mean. Must be at least 1.
+    pub rounds: u32,
+    /// The hashing time to aim for, in milliseconds.
+    pub target_ms: f64,
+}
+
+/// The time taken to hash a password with one set of parameters.
+#[derive(Clone, Debug)]
+pub struct HashBenchmarkResult {
+    /// The parameters that were measured.
+    pub params: Argon2Params,
+    /// The mean hashing time in milliseconds.
+    pub mean_ms: f64,
+}
+
+/// Measures hashing times, returning one result per memory size whose single pass fits in the
+/// target, each with as many passes as fit.
+pub fn run(options: &HashBenchmarkOptions) -> Result<Vec<HashBenchmarkResult>, CliError> {
+    let mut results = Vec::new();
+    let mut memory_kib = MIN_MEMORY_KIB;
+
+    while memory_kib <= options.max_memory_kib {
+        let single_pass = Argon2Params {
+            lanes: options.lanes,
+            memory_kib: memory_kib,
+            passes: 1,
+        };
+
+        let single_pass_ms = time(&single_pass, options.rounds)?;
+
+        if single_pass_ms > options.target_ms {
+            break;
+        }
+
+        let passes = ((options.target_ms / single_pass_ms) as u32).max(1);
+        let params = Argon2Params { passes: passes, ..single_pass };
+
+        results.push(HashBenchmarkResult {
+            mean_ms: if passes == 1 { single_pass_ms } else { time(&params, options.rounds)? },
+            params: params,
+        });
+
+        memory_kib = match memory_kib.checked_mul(2) {
+            Some(memory_kib) => memory_kib,
+            None => break,
+        };
+    }
+
+    Ok(results)
+}
+
+/// Chooses the parameters to recommend: the most memory that still allows `MIN_PASSES` passes
+/// within the target, or failing that, the most passes.
+pub fn recommend(results: &[HashBenchmarkResult], target_ms: f64)
+-> Option<&HashBenchmarkResult> {
+    let within_target: Vec<&HashBenchmarkResult> =
+        results.iter().filter(|result| result.mean_ms <= target_ms).collect();
+
+    match within_target.iter().filter(|result| result.params.passes >= MIN_PASSES).last() {
+        Some(result) => Some(*result),
+        None => within_target.into_iter().max_by_key(|result| result.params.passes),
+    }
+}
+
+/// Prints the results in a table, followed by the recommended configuration.
+pub fn print_results(results: &[HashBenchmarkResult], target_ms: f64) {
+    println!("{:>12} {:>8} {:>8} {:>10}", "memory KiB", "passes", "lanes", "mean ms");
+
+    for result in results {
+        println!(
+            "{:>12} {:>8} {:>8} {:>10.2}",
+            result.params.memory_kib,
+            result.params.passes,
+            result.params.lanes,
+            result.mean_ms
+        );
+    }
+
+    match recommend(results, target_ms) {
+        Some(result) => {
+            println!("");
+            println!("Recommended configuration for a {} ms target:", target_ms);
+            println!("  argon2_lanes: {}", result.params.lanes);
+            println!("  argon2_memory_kib: {}", result.params.memory_kib);
+            println!("  argon2_passes: {}", result.params.passes);
+        }
+        None => println!("No parameters hash within {} ms on this host.", target_ms),
+    }
+}
+
+/// Returns the mean time, in milliseconds, to hash a password with the parameters.
+fn time(params: &Argon2Params, rounds: u32) -> Result<f64, CliError> {
+    params.validate().map_err(CliError::new)?;
+
+    let mut total = Duration::new(0, 0);
+
+    for _ in 0..rounds {
+        let start = Instant::now();
+
+        hash_password(params, PASSWORD).map_err(|error| CliError::new(error.to_string()))?;
+
+        total += start.elapsed();
+    }
+
+    let total_ms = total.as_secs() as f64 * 1000.0 + total.subsec_nanos() as f64 / 1_000_000.0;
+
+    Ok(total_ms / rounds as f64)
+}
@@ -0,0 +1,592 @@
+//! The HTTP client Ruma uses to reach other services, such as push gateways, identity servers,
+//! and SMS gateways.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::io::{Error as IoError, ErrorKind, Read};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hyper::{Client, Result as HyperResult};
+use hyper::Error as HyperError;
+use hyper::client::RedirectPolicy;
+use hyper::client::Response as HyperResponse;
+use hyper::client::pool::{Config as PoolConfig, Pool};
+use hyper::header::{ContentType, Location};
+use hyper::net::{HttpStream, HttpsConnector, NetworkConnector, Openssl, OpensslClient, SslClient};
+use hyper::status::StatusCode;
+use openssl::ssl::{SSL_OP_NO_COMPRESSION, SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3};
+use openssl::ssl::{SslContext, SslMethod, SslStream};
+use url::{ParseError as UrlParseError, Url};
+
+use config::Config;
+use error::CliError;
+
+/// How long outbound requests may wait to look up a host, connect, or send or receive data if the
+/// configuration doesn't say, in seconds.
+pub const DEFAULT_OUTBOUND_HTTP_TIMEOUT_SECS: u64 = 10;
+
+/// How long the addresses of hosts are reused if the configuration doesn't say, in seconds.
+pub const DEFAULT_OUTBOUND_DNS_CACHE_SECS: u64 = 60;
+
+/// How many idle connections are kept open to each host for later requests.
+const MAX_IDLE_CONNECTIONS_PER_HOST: usize = 5;
+
+/// How many hosts' addresses are cached before the cache is emptied.
+const MAX_CACHED_HOSTS: usize = 1024;
+
+/// An HTTP client shared by everything in Ruma that makes outbound requests, so they all reuse
+/// connections and DNS lookups, and follow the same timeout and TLS policy.
+pub struct HttpClient {
+    client: Client,
+    https_only: bool,
+    resolver: Arc<Resolver>,
+    timeout: Duration,
+    tls_client: TlsClient,
+}
+
+/// Resolves hostnames, reusing the addresses found for a host until they expire.
+///
+/// Lookups that miss the cache block the calling thread until DNS answers or the timeout passes.
+#[derive(Debug)]
+pub struct Resolver {
+    cache: Mutex<HashMap<(String, u16), (Instant, Vec<SocketAddr>)>>,
+    timeout: Duration,
+    ttl: Duration,
+}
+
+/// How certificates of HTTPS servers are checked.
+#[derive(Clone)]
+struct TlsClient {
+    policy: Arc<TlsPolicy>,
+}
+
+/// Whether or not certificates are verified, along with the OpenSSL context to connect with.
+enum TlsPolicy {
+    /// Certificates must chain to a trusted CA and name the host being connected to.
+    Verify(OpensslClient),
+    /// Any certificate is accepted.
+    NoVerify(Openssl),
+}
+
+/// A hyper connector that looks up hosts with a `Resolver`.
+struct ResolvingConnector {
+    resolver: Arc<Resolver>,
+    timeout: Duration,
+}
+
+/// A hyper connector that connects to the given addresses whatever host it's asked for.
+struct PinnedConnector {
+    addresses: Vec<SocketAddr>,
+    timeout: Duration,
+}
+
+/// The status and body of a response to an outbound request.
+#[derive(Debug)]
+pub struct HttpResponse {
+    /// The response's status code.
+    pub status: StatusCode,
+    /// The response's body.
+    pub body: String,
+}
+
+/// The status, headers, and raw body of a response to a download.
+#[derive(Debug)]
+pub struct HttpDownload {
+    /// The response's status code.
+    pub status: StatusCode,
+    /// The MIME type of the body, if the response gave one.
+    pub content_type: Option<String>,
+    /// Where the response redirects to, if it's a redirect.
+    pub location: Option<String>,
+    /// The response's body.
+    pub body: Vec<u8>,
+}
+
+/// Why an outbound request failed.
+#[derive(Debug)]
+pub enum HttpClientError {
+    /// The host has an address the caller doesn't allow requests to.
+    Forbidden(IpAddr),
+    /// The URL isn't an HTTPS URL, and the configuration only allows HTTPS.
+    Insecure(String),
+    /// The request couldn't be sent or its response couldn't be read, e.g. because the host
+    /// couldn't be reached in time or its certificate couldn't be verified.
+    Request(HyperError),
+    /// The response's body was larger than the given number of bytes.
+    TooLarge(u64),
+    /// The host has no addresses.
+    Unresolvable(String),
+}
+
+impl HttpClient {
+    /// Creates an `HttpClient` with the timeout, DNS cache, and TLS policy from the configuration.
+    ///
+    /// Fails if the configured CA file can't be loaded.
+    pub fn new(config: &Config) -> Result<Self, CliError> {
+        let timeout = Duration::from_secs(config.outbound_http_timeout_secs);
+        let resolver = Arc::new(Resolver::new(
+            Duration::from_secs(config.outbound_dns_cache_secs),
+            timeout,
+        ));
+        let tls_client = TlsClient::new(
+            config.outbound_tls_ca_file.as_ref().map(String::as_str),
+            config.outbound_tls_verify,
+        )?;
+
+        Ok(HttpClient {
+            client: create_client(timeout, resolver.clone(), tls_client.clone()),
+            https_only: config.outbound_https_only,
+            resolver: resolver,
+            timeout: timeout,
+            tls_client: tls_client,
+        })
+    }
+
+    /// Sends a GET request to `url`.
+    pub fn get(&self, url: &str) -> Result<HttpResponse, HttpClientError> {
+        self.check_url(url)?;
+
+        let response = self.client.get(url).send().map_err(HttpClientError::Request)?;
+
+        read_response(response)
+    }
+
+    /// Sends a GET request to `url` without following redirects, and reads at most `max_size`
+    /// bytes of the response's body.
+    ///
+    /// Downloads are made on behalf of users, so the request is refused if any of the host's
+    /// addresses are rejected by `is_allowed`, and the caller checks each redirect the same way
+    /// before following it. The connection is made to the addresses that were checked rather than
+    /// ones looked up again, so a host can't pass the check and then resolve somewhere else.
+    pub fn download(
+        &self,
+        url: &str,
+        max_size: u64,
+        is_allowed: &Fn(&IpAddr) -> bool,
+    ) -> Result<HttpDownload, HttpClientError> {
+        self.check_url(url)?;
+
+        let parsed = Url::parse(url)
+            .map_err(|error| HttpClientError::Request(HyperError::Uri(error)))?;
+
+        let host = match parsed.host_str() {
+            Some(host) => host,
+            None => return Err(HttpClientError::Request(HyperError::Uri(UrlParseError::EmptyHost))),
+        };
+
+        let addresses = self.resolver
+            .resolve(host, parsed.port_or_known_default().unwrap_or(80))
+            .map_err(|_| HttpClientError::Unresolvable(host.to_string()))?;
+
+        if let Some(address) = addresses.iter().find(|address| !is_allowed(&address.ip())) {
+            return Err(HttpClientError::Forbidden(address.ip()));
+        }
+
+        // Pooled connections to the host may have been made to other addresses, so each download
+        // gets a client of its own.
+        let connector = HttpsConnector::with_connector(self.tls_client.clone(), PinnedConnector {
+            addresses: addresses,
+            timeout: self.timeout,
+        });
+
+        let mut client = Client::with_connector(connector);
+
+        client.set_read_timeout(Some(self.timeout));
+        client.set_write_timeout(Some(self.timeout));
+        client.set_redirect_policy(RedirectPolicy::FollowNone);
+
+        let mut response = client.get(url).send().map_err(HttpClientError::Request)?;
+
+        // Read one byte past the limit to tell whether the body is too large.
+        let mut body = Vec::new();
+
+        response
+            .by_ref()
+            .take(max_size + 1)
+            .read_to_end(&mut body)
+            .map_err(|error| HttpClientError::Request(HyperError::from(error)))?;
+
+        if body.len() as u64 > max_size {
+            return Err(HttpClientError::TooLarge(max_size));
+        }
+
+        Ok(HttpDownload {
+            status: response.status,
+            content_type: response.headers.get::<ContentType>()
+                .map(|content_type| content_type.to_string()),
+            location: response.headers.get::<Location>().map(|location| location.to_string()),
+            body: body,
+        })
+    }
+
+    /// Sends a POST request to `url` with the JSON `body`.
+    pub fn post_json(&self, url: &str, body: &str) -> Result<HttpResponse, HttpClientError> {
+        self.check_url(url)?;
+
+        let response = self.client
+            .post(url)
+            .header(ContentType::json())
+            .body(body)
+            .send()
+            .map_err(HttpClientError::Request)?;
+
+        read_response(response)
+    }
+
+    /// Refuses plain HTTP URLs if the configuration only allows HTTPS.
+    fn check_url(&self, url: &str) -> Result<(), HttpClientError> {
+        if self.https_only && !url.starts_with("https://") {
+            return Err(HttpClientError::Insecure(url.to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+impl Debug for HttpClient {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(
+            f,
+            "HttpClient {{ https_only: {}, tls_verify: {} }}",
+            self.https_only,
+            self.tls_client.verifies()
+        )
+    }
+}
+
+impl Resolver {
+    /// Creates a `Resolver` that reuses addresses for `ttl` and gives up on lookups that take
+    /// longer than `timeout`. Every lookup goes to DNS if `ttl` is zero.
+    pub fn new(ttl: Duration, timeout: Duration) -> Self {
+        Resolver {
+            cache: Mutex::new(HashMap::new()),
+            timeout: timeout,
+            ttl: ttl,
+        }
+    }
+
+    /// Returns the addresses of `host` on `port`, which may be an IP address in URL form instead
+    /// of a hostname.
+    pub fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, IoError> {
+        let host = host.trim_left_matches('[').trim_right_matches(']');
+
+        if let Ok(address) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(address, port)]);
+        }
+
+        let key = (host.to_lowercase(), port);
+
+        {
+            let cache = self.cache.lock().expect("Resolver mutex was poisoned");
+
+            if let Some(&(resolved_at, ref addresses)) = cache.get(&key) {
+                if resolved_at.elapsed() < self.ttl {
+                    return Ok(addresses.clone());
+                }
+            }
+        }
+
+        let name = host.to_string();
+        let addresses: Vec<SocketAddr> = with_timeout(self.timeout, move || {
+            (name.as_str(), port).to_socket_addrs().map(Iterator::collect)
+        })?;
+
+        if addresses.is_empty() {
+            return Err(IoError::new(ErrorKind::NotFound, format!("{} has no addresses", host)));
+        }
+
+        if self.ttl > Duration::from_secs(0) {
+            let mut cache = self.cache.lock().expect("Resolver mutex was poisoned");
+
+            // Expired entries are only replaced when their host is looked up again, so the cache
+            // is emptied before it can grow without bound.
+            if cache.len() >= MAX_CACHED_HOSTS {
+                cache.clear();
+            }
+
+            cache.insert(key, (Instant::now(), addresses.clone()));
+        }
+
+        Ok(addresses)
+    }
+}
+
+impl TlsClient {
+    /// Creates a `TlsClient` that trusts the system's CAs and those in `ca_file`, and verifies
+    /// certificates unless `verify` is false.
+    fn new(ca_file: Option<&str>, verify: bool) -> Result<Self, CliError> {
+        let mut context = SslContext::new(SslMethod::Sslv23)?;
+
+        context.set_options(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3 | SSL_OP_NO_COMPRESSION);
+        context.set_default_verify_paths()?;
+
+        if let Some(ca_file) = ca_file {
+            context.set_CA_file(ca_file).map_err(|error| {
+                CliError::new(format!("Failed to load outbound_tls_ca_file {}: {}", ca_file, error))
+            })?;
+        }
+
+        let policy = if verify {
+            TlsPolicy::Verify(OpensslClient::new(context))
+        } else {
+            warn!("Certificates of HTTPS servers will not be verified.");
+
+            TlsPolicy::NoVerify(Openssl {
+                context: Arc::new(context),
+            })
+        };
+
+        Ok(TlsClient {
+            policy: Arc::new(policy),
+        })
+    }
+
+    /// Whether or not certificates are verified.
+    fn verifies(&self) -> bool {
+        match *self.policy {
+            TlsPolicy::Verify(_) => true,
+            TlsPolicy::NoVerify(_) => false,
+        }
+    }
+}
+
+impl SslClient for TlsClient {
+    type Stream = SslStream<HttpStream>;
+
+    fn wrap_client(&self, stream: HttpStream, host: &str) -> HyperResult<Self::Stream> {
+        match *self.policy {
+            TlsPolicy::Verify(ref client) => client.wrap_client(stream, host),
+            TlsPolicy::NoVerify(ref client) => client.wrap_client(stream, host),
+        }
+    }
+}
+
+impl NetworkConnector for ResolvingConnector {
+    type Stream = HttpStream;
+
+    fn connect(&self, host: &str, port: u16, _scheme: &str) -> HyperResult<HttpStream> {
+        let addresses = self.resolver.resolve(host, port)?;
+
+        connect_to_any(&addresses, self.timeout)
+    }
+}
+
+impl NetworkConnector for PinnedConnector {
+    type Stream = HttpStream;
+
+    fn connect(&self, _host: &str, _port: u16, _scheme: &str) -> HyperResult<HttpStream> {
+        connect_to_any(&self.addresses, self.timeout)
+    }
+}
+
+impl Display for HttpClientError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            HttpClientError::Forbidden(ref address) => {
+                write!(f, "requests to {} are not allowed", address)
+            }
+            HttpClientError::Insecure(ref url) => {
+                write!(f, "{} is not an HTTPS URL, and only HTTPS is allowed", url)
+            }
+            HttpClientError::Request(ref error) => write!(f, "{}", error),
+            HttpClientError::TooLarge(max_size) => {
+                write!(f, "the response was larger than {} bytes", max_size)
+            }
+            HttpClientError::Unresolvable(ref host) => write!(f, "{} could not be resolved", host),
+        }
+    }
+}
+
+impl Error for HttpClientError {
+    fn description(&self) -> &str {
+        match *self {
+            HttpClientError::Forbidden(_) => "requests to the host are not allowed",
+            HttpClientError::Insecure(_) => "only HTTPS is allowed",
+            HttpClientError::Request(ref error) => error.description(),
+            HttpClientError::TooLarge(_) => "the response was too large",
+            HttpClientError::Unresolvable(_) => "the host could not be resolved",
+        }
+    }
+}
+
+/// Creates a hyper client that keeps idle connections open, looks up hosts with `resolver`,
+/// checks certificates with `tls_client`, and gives up on requests after `timeout`.
+fn create_client(timeout: Duration, resolver: Arc<Resolver>, tls_client: TlsClient) -> Client {
+    let connector = HttpsConnector::with_connector(tls_client, ResolvingConnector {
+        resolver: resolver,
+        timeout: timeout,
+    });
+
+    let mut client = Client::with_connector(Pool::with_connector(PoolConfig {
+        max_idle: MAX_IDLE_CONNECTIONS_PER_HOST,
+    }, connector));
+
+    client.set_read_timeout(Some(timeout));
+    client.set_write_timeout(Some(timeout));
+
+    client
+}
+
+/// Connects to the first of `addresses` that accepts the connection within `timeout`, and sets
+/// the connection to give up on reads and writes that take longer than `timeout`.
+fn connect_to_any(addresses: &[SocketAddr], timeout: Duration) -> HyperResult<HttpStream> {
+    let mut last_error = IoError::new(ErrorKind::NotFound, "there are no addresses to connect to");
+
+    for &address in addresses {
+        match with_timeout(timeout, move || TcpStream::connect(address)) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+
+                return Ok(HttpStream(stream));
+            }
+            Err(error) => last_error = error,
+        }
+    }
+
+    Err(HyperError::from(last_error))
+}
+
+/// Runs `operation` on a thread of its own, failing with `ErrorKind::TimedOut` if it hasn't
+/// finished within `timeout`.
+///
+/// The standard library can't limit how long DNS lookups or TCP connections take, so this keeps
+/// the caller from waiting on them indefinitely. An operation that times out is left to finish on
+/// its thread, and its result is dropped.
+fn with_timeout<T, F>(timeout: Duration, operation: F) -> Result<T, IoError>
+where T: Send + 'static, F: FnOnce() -> Result<T, IoError> + Send + 'static {
+    let (sender, receiver) = channel();
+
+    thread::spawn(move || {
+        let _ = sender.send(operation());
+    });
+
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(_) => Err(IoError::new(ErrorKind::TimedOut, "the operation timed out")),
+    }
+}
+
+/// Reads the whole body of a response.
+fn read_response(mut response: HyperResponse) -> Result<HttpResponse, HttpClientError> {
+    let mut body = String::new();
+
+    response
+        .read_to_string(&mut body)
+        .map_err(|error| HttpClientError::Request(HyperError::from(error)))?;
+
+    Ok(HttpResponse {
+        status: response.status,
+        body: body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use hyper::Client;
+
+    use super::{HttpClient, HttpClientError, Resolver, TlsClient, connect_to_any, with_timeout};
+
+    fn http_client(https_only: bool) -> HttpClient {
+        HttpClient {
+            client: Client::new(),
+            https_only: https_only,
+            resolver: Arc::new(Resolver::new(Duration::from_secs(60), Duration::from_secs(10))),
+            timeout: Duration::from_secs(10),
+            tls_client: TlsClient::new(None, true).unwrap(),
+        }
+    }
+
+    #[test]
+    fn plain_http_is_refused_when_only_https_is_allowed() {
+        let http_client = http_client(true);
+
+        match http_client.get("http://example.com/") {
+            Err(HttpClientError::Insecure(url)) => assert_eq!(url, "http://example.com/"),
+            other => panic!("expected the request to be refused, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn resolved_addresses_are_reused_until_they_expire() {
+        let resolver = Resolver::new(Duration::from_secs(60), Duration::from_secs(10));
+        let cached: SocketAddr = "203.0.113.1:443".parse().unwrap();
+        let stale: SocketAddr = "203.0.113.2:443".parse().unwrap();
+
+        {
+            let mut cache = resolver.cache.lock().unwrap();
+
+            cache.insert(("push.example.com".to_string(), 443), (Instant::now(), vec![cached]));
+            cache.insert(
+                ("localhost".to_string(), 443),
+                (Instant::now() - Duration::from_secs(61), vec![stale]),
+            );
+        }
+
+        assert_eq!(resolver.resolve("Push.Example.com", 443).unwrap(), vec![cached]);
+        assert!(!resolver.resolve("localhost", 443).unwrap().contains(&stale));
+    }
+
+    #[test]
+    fn ip_addresses_are_not_looked_up() {
+        let resolver = Resolver::new(Duration::from_secs(60), Duration::from_secs(10));
+
+        assert_eq!(
+            resolver.resolve("[::1]", 8448).unwrap(),
+            vec!["[::1]:8448".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(
+            resolver.resolve("127.0.0.1", 80).unwrap(),
+            vec!["127.0.0.1:80".parse::<SocketAddr>().unwrap()]
+        );
+        assert!(resolver.cache.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn downloads_from_disallowed_addresses_are_refused() {
+        let http_client = http_client(false);
+
+        // Nothing is listening, so the download would fail to connect if it got that far.
+        let result = http_client.download("http://127.0.0.1:9/", 1024, &|address| {
+            !address.is_loopback()
+        });
+
+        match result {
+            Err(HttpClientError::Forbidden(address)) => assert!(address.is_loopback()),
+            other => panic!("expected the download to be refused, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn connections_give_up_after_the_timeout() {
+        // Nothing answers connections to this documentation address, so only the timeout can end
+        // the attempt.
+        let addresses = ["192.0.2.1:80".parse::<SocketAddr>().unwrap()];
+        let started_at = Instant::now();
+
+        assert!(connect_to_any(&addresses, Duration::from_millis(100)).is_err());
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn slow_operations_time_out() {
+        let result = with_timeout(Duration::from_millis(10), || {
+            thread::sleep(Duration::from_secs(1));
+
+            Ok(())
+        });
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+}
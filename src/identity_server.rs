@@ -0,0 +1,142 @@
+//! Asking identity servers about the third-party identifiers they have validated.
+//!
+//! Clients can prove ownership of an email address to an identity server instead of to Ruma, and
+//! then give Ruma the identity server's session to add the address to an account.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use iron::{Plugin, Request};
+use iron::typemap::Key;
+use persistent::Read;
+use serde_json::{Value, from_str};
+use url::form_urlencoded::Serializer as FormSerializer;
+
+use error::ApiError;
+use http_client::HttpClient;
+
+/// A third-party identifier an identity server has validated.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidatedThreepid {
+    /// The kind of identifier, e.g. *email*.
+    pub medium: String,
+    /// The identifier itself, e.g. an email address.
+    pub address: String,
+}
+
+/// A way of asking identity servers about their validation sessions.
+pub trait IdentityServers: Send + Sync {
+    /// Returns the identifier validated in the session `sid` on the identity server `id_server`,
+    /// or `None` if the session doesn't exist or hasn't been validated.
+    fn validated_threepid(&self, id_server: &str, sid: &str, client_secret: &str)
+    -> Result<Option<ValidatedThreepid>, ApiError>;
+}
+
+/// `IdentityServers` that are asked over HTTPS with the identity service API.
+#[derive(Debug)]
+pub struct HttpIdentityServers {
+    http_client: Arc<HttpClient>,
+}
+
+/// `IdentityServers` whose validated sessions are set up ahead of time instead of asked for.
+#[derive(Debug)]
+pub struct FakeIdentityServers {
+    sessions: Mutex<HashMap<(String, String, String), ValidatedThreepid>>,
+}
+
+/// An Iron plugin for attaching `IdentityServers` to an Iron request.
+pub struct ServerIdentityServers;
+
+impl HttpIdentityServers {
+    /// Creates a new `HttpIdentityServers` that asks identity servers with the given client.
+    pub fn new(http_client: Arc<HttpClient>) -> Self {
+        HttpIdentityServers {
+            http_client: http_client,
+        }
+    }
+}
+
+impl IdentityServers for HttpIdentityServers {
+    fn validated_threepid(&self, id_server: &str, sid: &str, client_secret: &str)
+    -> Result<Option<ValidatedThreepid>, ApiError> {
+        if id_server.is_empty() || id_server.contains(|c| c == '/' || c == '?' || c == '#') {
+            return Err(ApiError::invalid_param("id_server", "Must be a hostname."));
+        }
+
+        let query = FormSerializer::new(String::new())
+            .append_pair("sid", sid)
+            .append_pair("client_secret", client_secret)
+            .finish();
+
+        let url = format!(
+            "https://{}/_matrix/identity/api/v1/3pid/getValidated3pid?{}",
+            id_server,
+            query
+        );
+
+        let response = self.http_client.get(&url).map_err(|error| {
+            ApiError::unknown(Some(&format!("Failed to reach the identity server: {}", error)))
+        })?;
+
+        if !response.status.is_success() {
+            return Ok(None);
+        }
+
+        let validated: Value = from_str(&response.body)?;
+        let field = |name| validated.find(name).and_then(Value::as_str).map(str::to_string);
+
+        match (field("medium"), field("address")) {
+            (Some(medium), Some(address)) => Ok(Some(ValidatedThreepid {
+                medium: medium,
+                address: address,
+            })),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl FakeIdentityServers {
+    /// Creates a new `FakeIdentityServers` with no validated sessions.
+    pub fn new() -> Self {
+        FakeIdentityServers {
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records that the session `sid` on `id_server` validated the identifier.
+    pub fn validate(
+        &self,
+        id_server: &str,
+        sid: &str,
+        client_secret: &str,
+        threepid: ValidatedThreepid,
+    ) {
+        let mut sessions = self.sessions.lock().expect("FakeIdentityServers mutex was poisoned");
+
+        sessions.insert(
+            (id_server.to_string(), sid.to_string(), client_secret.to_string()),
+            threepid,
+        );
+    }
+}
+
+impl IdentityServers for FakeIdentityServers {
+    fn validated_threepid(&self, id_server: &str, sid: &str, client_secret: &str)
+    -> Result<Option<ValidatedThreepid>, ApiError> {
+        let sessions = self.sessions.lock().expect("FakeIdentityServers mutex was poisoned");
+        let key = (id_server.to_string(), sid.to_string(), client_secret.to_string());
+
+        Ok(sessions.get(&key).cloned())
+    }
+}
+
+impl ServerIdentityServers {
+    /// Extract the `IdentityServers` stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<Arc<IdentityServers>>, ApiError> {
+        request.get::<Read<ServerIdentityServers>>().map_err(ApiError::from)
+    }
+}
+
+impl Key for ServerIdentityServers {
+    type Value = Arc<IdentityServers>;
+}
@@ -0,0 +1,98 @@
+//! Background jobs that run periodically alongside the web server.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use diesel::pg::PgConnection;
+use r2d2::Pool;
+use r2d2_diesel::ConnectionManager;
+
+use clock::Clock;
+use config::Config;
+use error::ApiError;
+
+/// A unit of work that runs on a schedule.
+pub trait Job: Send + Sync {
+    /// A short name for the job, used in logs.
+    fn name(&self) -> &'static str;
+
+    /// How long to wait between runs.
+    fn interval(&self) -> Duration;
+
+    /// Runs the job once.
+    ///
+    /// `now` comes from the server's `Clock` rather than the system clock so that tests can
+    /// control when time-based work becomes due.
+    fn run(&self, connection: &PgConnection, config: &Config, now: i64) -> Result<(), ApiError>;
+}
+
+/// The set of background jobs for a server, along with the resources they need to run.
+pub struct Jobs {
+    clock: Arc<Clock>,
+    config: Config,
+    connection_pool: Pool<ConnectionManager<PgConnection>>,
+    jobs: Vec<Arc<Job>>,
+}
+
+impl Jobs {
+    /// Creates an empty set of jobs.
+    pub fn new(
+        config: Config,
+        clock: Arc<Clock>,
+        connection_pool: Pool<ConnectionManager<PgConnection>>,
+    ) -> Self {
+        Jobs {
+            clock: clock,
+            config: config,
+            connection_pool: connection_pool,
+            jobs: Vec::new(),
+        }
+    }
+
+    /// Adds a job to the set.
+    pub fn register<J>(&mut self, job: J) where J: Job + 'static {
+        self.jobs.push(Arc::new(job));
+    }
+
+    /// Runs every job once on the current thread. Useful for testing.
+    pub fn run_once(&self) -> Result<(), ApiError> {
+        let connection = self.connection_pool.get()?;
+
+        for job in &self.jobs {
+            job.run(&*connection, &self.config, self.clock.now_millis())?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts a thread for each job that runs it repeatedly at its interval.
+    pub fn spawn(self) {
+        for job in self.jobs {
+            let clock = self.clock.clone();
+            let config = self.config.clone();
+            let connection_pool = self.connection_pool.clone();
+
+            info!("Starting background job {}.", job.name());
+
+            thread::spawn(move || {
+                loop {
+                    thread::sleep(job.interval());
+
+                    let connection = match connection_pool.get() {
+                        Ok(connection) => connection,
+                        Err(error) => {
+                            info!("Background job {} could not get a connection: {}", job.name(), error);
+
+                            continue;
+                        }
+                    };
+
+                    if let Err(error) = job.run(&*connection, &config, clock.now_millis()) {
+                        info!("Background job {} failed: {}", job.name(), error);
+                    }
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Membership events already sent to a device by syncs that lazy-load room members.
+
+use diesel::{Connection, ExecuteDsl, ExpressionMethods, FilterDsl, LoadDsl, delete, insert};
+use diesel::pg::PgConnection;
+use ruma_identifiers::{RoomId, UserId};
+
+use error::ApiError;
+use schema::lazy_loaded_members;
+
+/// A membership event a device has been sent for a room.
+#[derive(Debug, Queryable)]
+pub struct LazyLoadedMember {
+    /// Entry ID.
+    pub id: i64,
+    /// The user who was sent the event.
+    pub user_id: UserId,
+    /// The device that was sent the event.
+    pub device_id: String,
+    /// The room the membership is for.
+    pub room_id: RoomId,
+    /// The user whose membership the event describes.
+    pub member_id: String,
+    /// The ID of the membership event that was sent.
+    pub event_id: String,
+}
+
+/// A membership event sent to a device, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "lazy_loaded_members"]
+pub struct NewLazyLoadedMember {
+    /// The user who was sent the event.
+    pub user_id: UserId,
+    /// The device that was sent the event.
+    pub device_id: String,
+    /// The room the membership is for.
+    pub room_id: RoomId,
+    /// The user whose membership the event describes.
+    pub member_id: String,
+    /// The ID of the membership event that was sent.
+    pub event_id: String,
+}
+
+impl LazyLoadedMember {
+    /// Returns the membership events the device has been sent for the room.
+    pub fn find_by_room(
+        connection: &PgConnection,
+        user_id: &UserId,
+        device_id: &str,
+        room_id: &RoomId,
+    ) -> Result<Vec<LazyLoadedMember>, ApiError> {
+        lazy_loaded_members::table
+            .filter(lazy_loaded_members::user_id.eq(user_id))
+            .filter(lazy_loaded_members::device_id.eq(device_id))
+            .filter(lazy_loaded_members::room_id.eq(room_id))
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Records that membership events were sent to a device, replacing any earlier membership
+    /// events sent for the same members.
+    pub fn record(connection: &PgConnection, new_members: &[NewLazyLoadedMember])
+    -> Result<(), ApiError> {
+        connection.transaction::<(), ApiError, _>(|| {
+            for new_member in new_members {
+                let previous = lazy_loaded_members::table
+                    .filter(lazy_loaded_members::user_id.eq(&new_member.user_id))
+                    .filter(lazy_loaded_members::device_id.eq(&new_member.device_id))
+                    .filter(lazy_loaded_members::room_id.eq(&new_member.room_id))
+                    .filter(lazy_loaded_members::member_id.eq(&new_member.member_id));
+
+                delete(previous).execute(connection)?;
+                insert(new_member).into(lazy_loaded_members::table).execute(connection)?;
+            }
+
+            Ok(())
+        }).map_err(ApiError::from)
+    }
+
+    /// Forgets what was sent to the device, for when it starts over with an initial sync or is
+    /// deleted.
+    pub fn clear(connection: &PgConnection, user_id: &UserId, device_id: &str)
+    -> Result<(), ApiError> {
+        let sent = lazy_loaded_members::table
+            .filter(lazy_loaded_members::user_id.eq(user_id))
+            .filter(lazy_loaded_members::device_id.eq(device_id));
+
+        delete(sent).execute(connection).map(|_| ()).map_err(ApiError::from)
+    }
+}
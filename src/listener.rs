@@ -0,0 +1,358 @@
+//! The socket Ruma accepts connections on, and handing it to a new Ruma process so the server can
+//! be upgraded without refusing connections or cutting off requests in progress.
+//!
+//! Sending the running process `SIGUSR2` starts a new process with the same arguments, which
+//! inherits the listening socket instead of binding its own. Once the new process is serving, it
+//! sends the old one `SIGUSR1`, and the old process stops accepting connections, closes each
+//! connection it has after its current response, and exits when none are left.
+
+use std::env;
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::process::{Command, exit};
+use std::sync::Arc;
+use std::sync::atomic::{ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT, AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hyper::Result as HyperResult;
+use hyper::net::{HttpListener, HttpStream, NetworkListener, NetworkStream};
+use libc;
+
+use systemd::activated_listener;
+
+/// The environment variable that gives a new process the file descriptor of the listener it
+/// inherited.
+const LISTEN_FD_VAR: &'static str = "RUMA_LISTEN_FD";
+
+/// The environment variable that gives a new process the ID of the process it takes over from.
+const HANDOFF_PID_VAR: &'static str = "RUMA_HANDOFF_PID";
+
+/// How often the signal flags are checked.
+const SIGNAL_POLL_INTERVAL_MILLIS: u64 = 100;
+
+/// How long a new process may take to start serving before the handoff is abandoned.
+const MAX_SUCCESSOR_START_SECS: u64 = 60;
+
+/// How long the old process waits for its connections to close before exiting anyway.
+const MAX_DRAIN_SECS: u64 = 300;
+
+/// Set by the `SIGUSR2` handler when a handoff is requested.
+static HANDOFF_REQUESTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Set by the `SIGUSR1` handler when the new process is serving.
+static SUCCESSOR_READY: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Whether or not the listener has been handed off, so no more connections are accepted.
+static DRAINING: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// How many accepted connections are still open.
+static OPEN_CONNECTIONS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A hyper listener that stops accepting connections once the socket has been handed off, and
+/// counts the connections it has accepted until they're closed.
+#[derive(Clone)]
+pub struct HandoffListener {
+    listener: HttpListener,
+}
+
+/// A connection accepted by a `HandoffListener`. It is counted as open until it and all of its
+/// clones are dropped.
+#[derive(Clone)]
+pub struct CountedStream {
+    stream: HttpStream,
+    _open: Arc<OpenConnection>,
+}
+
+/// Removes a connection from the count of open connections when dropped.
+struct OpenConnection;
+
+/// Opens the listener to serve requests on: the one handed over by a previous Ruma process, if
+/// there was one, the one passed by systemd socket activation, if there was one, or a new one
+/// bound to `address`.
+pub fn open_listener(address: &str) -> IoResult<TcpListener> {
+    if let Some(fd) = env::var(LISTEN_FD_VAR).ok().and_then(|fd| fd.parse::<RawFd>().ok()) {
+        env::remove_var(LISTEN_FD_VAR);
+        set_inheritable(fd, false)?;
+
+        info!("Using the listener handed over by the previous Ruma process.");
+
+        return Ok(unsafe { TcpListener::from_raw_fd(fd) });
+    }
+
+    if let Some(fd) = activated_listener() {
+        set_inheritable(fd, false)?;
+
+        info!("Using the listener passed by systemd socket activation.");
+
+        return Ok(unsafe { TcpListener::from_raw_fd(fd) });
+    }
+
+    TcpListener::bind(address)
+}
+
+/// Tells the process that handed over the listener that this one is serving, so it can stop
+/// accepting connections and drain.
+///
+/// Does nothing if the listener wasn't handed over.
+pub fn notify_predecessor() {
+    let pid = match env::var(HANDOFF_PID_VAR).ok().and_then(|pid| pid.parse::<libc::pid_t>().ok()) {
+        Some(pid) => pid,
+        None => return,
+    };
+
+    env::remove_var(HANDOFF_PID_VAR);
+
+    if unsafe { libc::kill(pid, libc::SIGUSR1) } == -1 {
+        warn!(
+            "Failed to tell the previous Ruma process {} to drain: {}",
+            pid,
+            IoError::last_os_error()
+        );
+    }
+}
+
+/// Hands the listener with the file descriptor `fd` to a new process when Ruma gets `SIGUSR2`,
+/// then drains and exits.
+pub fn watch_for_handoff(fd: RawFd) {
+    unsafe {
+        libc::signal(libc::SIGUSR1, record_signal as libc::sighandler_t);
+        libc::signal(libc::SIGUSR2, record_signal as libc::sighandler_t);
+    }
+
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_millis(SIGNAL_POLL_INTERVAL_MILLIS));
+
+            if !HANDOFF_REQUESTED.swap(false, Ordering::SeqCst) {
+                continue;
+            }
+
+            match hand_off(fd) {
+                Ok(()) => drain(),
+                Err(error) => warn!("Failed to hand the listener to a new process: {}", error),
+            }
+        }
+    });
+}
+
+/// Whether or not the listener has been handed off, in which case connections should be closed
+/// after their current response.
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+impl HandoffListener {
+    /// Creates a `HandoffListener` accepting connections from `listener`.
+    pub fn new(listener: TcpListener) -> Self {
+        HandoffListener {
+            listener: HttpListener::from(listener),
+        }
+    }
+}
+
+impl NetworkListener for HandoffListener {
+    type Stream = CountedStream;
+
+    fn accept(&mut self) -> HyperResult<CountedStream> {
+        // The socket is shared with the new process, which accepts its connections from here on.
+        while is_draining() {
+            thread::park();
+        }
+
+        let stream = self.listener.accept()?;
+
+        OPEN_CONNECTIONS.fetch_add(1, Ordering::SeqCst);
+
+        Ok(CountedStream {
+            stream: stream,
+            _open: Arc::new(OpenConnection),
+        })
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl Read for CountedStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for CountedStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.stream.flush()
+    }
+}
+
+impl NetworkStream for CountedStream {
+    fn peer_addr(&mut self) -> IoResult<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn set_read_timeout(&self, duration: Option<Duration>) -> IoResult<()> {
+        self.stream.set_read_timeout(duration)
+    }
+
+    fn set_write_timeout(&self, duration: Option<Duration>) -> IoResult<()> {
+        self.stream.set_write_timeout(duration)
+    }
+
+    fn close(&mut self, how: Shutdown) -> IoResult<()> {
+        self.stream.close(how)
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Records `SIGUSR1` and `SIGUSR2` for the thread started by `watch_for_handoff`, since little
+/// else is safe to do in a signal handler.
+extern "C" fn record_signal(signal: libc::c_int) {
+    match signal {
+        libc::SIGUSR1 => SUCCESSOR_READY.store(true, Ordering::SeqCst),
+        libc::SIGUSR2 => HANDOFF_REQUESTED.store(true, Ordering::SeqCst),
+        _ => {}
+    }
+}
+
+/// Starts a new process with the same arguments that inherits the listener, and waits until it's
+/// serving. The new process is killed if it doesn't start serving in time.
+fn hand_off(fd: RawFd) -> IoResult<()> {
+    info!("Starting a new Ruma process to hand the listener to.");
+
+    SUCCESSOR_READY.store(false, Ordering::SeqCst);
+
+    let args: Vec<_> = env::args_os().skip(1).collect();
+
+    set_inheritable(fd, true)?;
+
+    let successor = Command::new(env::current_exe()?)
+        .args(&args)
+        .env(LISTEN_FD_VAR, fd.to_string())
+        .env(HANDOFF_PID_VAR, unsafe { libc::getpid() }.to_string())
+        .spawn();
+
+    set_inheritable(fd, false)?;
+
+    let mut successor = successor?;
+    let started_at = Instant::now();
+
+    while started_at.elapsed() < Duration::from_secs(MAX_SUCCESSOR_START_SECS) {
+        if SUCCESSOR_READY.load(Ordering::SeqCst) {
+            info!("The new Ruma process {} is serving.", successor.id());
+
+            return Ok(());
+        }
+
+        thread::sleep(Duration::from_millis(SIGNAL_POLL_INTERVAL_MILLIS));
+    }
+
+    let _ = successor.kill();
+    let _ = successor.wait();
+
+    Err(IoError::new(ErrorKind::TimedOut, "the new process didn't start serving in time"))
+}
+
+/// Stops accepting connections, waits for the open ones to close, and exits.
+fn drain() -> ! {
+    info!("Draining connections before exiting.");
+
+    DRAINING.store(true, Ordering::SeqCst);
+
+    let started_at = Instant::now();
+
+    while OPEN_CONNECTIONS.load(Ordering::SeqCst) > 0 {
+        if started_at.elapsed() >= Duration::from_secs(MAX_DRAIN_SECS) {
+            warn!(
+                "Exiting with {} connections still open.",
+                OPEN_CONNECTIONS.load(Ordering::SeqCst)
+            );
+
+            break;
+        }
+
+        thread::sleep(Duration::from_millis(SIGNAL_POLL_INTERVAL_MILLIS));
+    }
+
+    info!("Finished draining connections.");
+
+    exit(0);
+}
+
+/// Sets whether or not the file descriptor is kept open in processes Ruma starts.
+fn set_inheritable(fd: RawFd, inheritable: bool) -> IoResult<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+
+    if flags == -1 {
+        return Err(IoError::last_os_error());
+    }
+
+    let flags = if inheritable { flags & !libc::FD_CLOEXEC } else { flags | libc::FD_CLOEXEC };
+
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } == -1 {
+        return Err(IoError::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::Ordering;
+
+    use hyper::net::NetworkListener;
+    use libc;
+
+    use super::{HandoffListener, OPEN_CONNECTIONS, set_inheritable};
+
+    #[test]
+    fn accepted_connections_are_counted_until_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut listener = HandoffListener::new(listener);
+
+        let _client = TcpStream::connect(address).unwrap();
+        let stream = listener.accept().unwrap();
+        let clone = stream.clone();
+
+        assert_eq!(OPEN_CONNECTIONS.load(Ordering::SeqCst), 1);
+
+        drop(stream);
+
+        assert_eq!(OPEN_CONNECTIONS.load(Ordering::SeqCst), 1);
+
+        drop(clone);
+
+        assert_eq!(OPEN_CONNECTIONS.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn listeners_are_only_inherited_during_a_handoff() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let fd = listener.as_raw_fd();
+        let close_on_exec = || unsafe { libc::fcntl(fd, libc::F_GETFD) } & libc::FD_CLOEXEC != 0;
+
+        assert!(close_on_exec());
+
+        set_inheritable(fd, true).unwrap();
+
+        assert!(!close_on_exec());
+
+        set_inheritable(fd, false).unwrap();
+
+        assert!(close_on_exec());
+    }
+}
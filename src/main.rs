@@ -1,29 +1,38 @@
 //! Ruma is a Matrix homeserver client API.
 
 #![feature(proc_macro, try_from)]
+#![cfg_attr(test, feature(test))]
 #![deny(missing_docs)]
 
 extern crate argon2rs;
 extern crate base64;
+extern crate bcrypt;
 extern crate bodyparser;
 extern crate chrono;
 extern crate clap;
 extern crate env_logger;
+extern crate hyper;
+extern crate image;
+extern crate libc;
 #[macro_use] extern crate diesel;
 #[macro_use] extern crate diesel_codegen;
 #[macro_use] extern crate iron;
 #[cfg(test)] extern crate iron_test;
+#[cfg(test)] extern crate test as libtest;
 #[macro_use] extern crate log;
 #[macro_use] extern crate slog;
 #[macro_use] extern crate slog_scope;
 extern crate slog_term;
 extern crate macaroons;
 extern crate mount;
+extern crate num_cpus;
+extern crate openssl;
 extern crate plugin;
 extern crate persistent;
 extern crate r2d2;
 extern crate r2d2_diesel;
 extern crate rand;
+extern crate ring;
 extern crate router;
 extern crate ruma_events;
 extern crate ruma_identifiers;
@@ -34,11 +43,21 @@ extern crate serde_json;
 extern crate serde_yaml;
 extern crate toml;
 extern crate unicase;
+extern crate url;
 
-use clap::{App, AppSettings, SubCommand, Arg};
+use clap::{App, AppSettings, ArgMatches, SubCommand, Arg};
 
+use std::path::Path;
+use std::process::exit;
+
+use bench::{BenchOptions, Scenario};
 use config::Config;
 use crypto::generate_macaroon_secret_key;
+use event_injection::{EventInjectionOptions, send_event};
+use hash_benchmark::HashBenchmarkOptions;
+use room_export::export_room;
+use room_import::import_room;
+use seed::{SeedOptions, seed};
 use server::Server;
 
 #[macro_use]
@@ -46,25 +65,71 @@ pub mod middleware;
 pub mod access_token;
 /// API endpoints as Iron handlers.
 pub mod api {
+    pub mod media;
     pub mod r0;
+    pub mod ruma;
+    pub mod unstable;
 }
 pub mod account_data;
+pub mod app_service;
 pub mod authentication;
+pub mod bench;
+pub mod clock;
 pub mod config;
 pub mod crypto;
 pub mod db;
+pub mod delayed_event;
+pub mod device;
+pub mod device_list;
+pub mod discovery;
 pub mod error;
 pub mod event;
+pub mod event_expiration;
+pub mod event_injection;
+pub mod filter;
+pub mod hash_benchmark;
+pub mod http_client;
+pub mod identity_server;
+pub mod jobs;
+pub mod listener;
+pub mod lazy_loaded_member;
+pub mod media;
+pub mod membership_cache;
 pub mod modifier;
+pub mod power_levels;
+pub mod presence;
 pub mod profile;
+pub mod public_room;
+pub mod push_rule;
+pub mod pusher;
+pub mod receipt;
 pub mod room;
 pub mod room_alias;
+pub mod room_export;
+pub mod room_import;
 pub mod schema;
+pub mod seed;
 pub mod server;
+pub mod server_notice;
+pub mod sms;
+pub mod stream_position;
+pub mod stats;
 pub mod swagger;
+pub mod systemd;
 pub mod room_membership;
+pub mod room_tag;
+#[cfg(test)] mod benches;
 #[cfg(test)] pub mod test;
+pub mod threepid_validation;
+pub mod to_device;
+pub mod transaction;
+pub mod typing;
+pub mod uia;
+pub mod url_preview;
 pub mod user;
+pub mod user_directory;
+pub mod user_threepid;
+pub mod velocity_limit;
 
 use slog::DrainExt;
 embed_migrations!();
@@ -82,6 +147,137 @@ fn main() {
         .about("A Matrix homeserver client API")
         .setting(AppSettings::GlobalVersion)
         .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("Benchmarks the hottest endpoints of a running Ruma server")
+                .arg(Arg::with_name("url")
+                     .short("u")
+                     .long("url")
+                     .value_name("URL")
+                     .help("The base URL of the server (defaults to http://localhost:3000)")
+                     .takes_value(true)
+                     )
+                .arg(Arg::with_name("requests")
+                     .short("n")
+                     .long("requests")
+                     .value_name("N")
+                     .help("The number of requests to make for each scenario (defaults to 1000)")
+                     .takes_value(true)
+                     )
+                .arg(Arg::with_name("concurrency")
+                     .long("concurrency")
+                     .value_name("C")
+                     .help("The number of requests to have in flight at once (defaults to 10)")
+                     .takes_value(true)
+                     )
+                .arg(Arg::with_name("scenario")
+                     .long("scenario")
+                     .value_name("SCENARIO")
+                     .help("Only run the given scenario: login, messages, send, or sync")
+                     .takes_value(true)
+                     .multiple(true)
+                     )
+                .arg(Arg::with_name("save")
+                     .long("save")
+                     .value_name("FILE")
+                     .help("Save the results to a file for use as a baseline")
+                     .takes_value(true)
+                     )
+                .arg(Arg::with_name("compare")
+                     .long("compare")
+                     .value_name("FILE")
+                     .help("Compare the results to a saved baseline, failing if any scenario regressed")
+                     .takes_value(true)
+                     )
+                .arg(Arg::with_name("tolerance")
+                     .long("tolerance")
+                     .value_name("PERCENT")
+                     .help("How much slower the median may get before --compare fails (defaults to 20)")
+                     .takes_value(true)
+                     )
+        )
+        .subcommand(
+            SubCommand::with_name("export-room")
+                .about("Exports a room's state and full history to a directory of JSON files")
+                .arg(Arg::with_name("config")
+                     .short("c")
+                     .long("config")
+                     .value_name("FILE")
+                     .help("Define a custom config file (defaults to `ruma.[json|toml|yaml]`)")
+                     .takes_value(true)
+                     )
+                .arg(Arg::with_name("room")
+                     .short("r")
+                     .long("room")
+                     .value_name("ROOM_ID")
+                     .help("The ID of the room to export")
+                     .takes_value(true)
+                     .required(true)
+                     )
+                .arg(Arg::with_name("output")
+                     .short("o")
+                     .long("output")
+                     .value_name("DIR")
+                     .help("The directory to write the export to, created if needed")
+                     .takes_value(true)
+                     .required(true)
+                     )
+        )
+        .subcommand(
+            SubCommand::with_name("hash-benchmark")
+                .about("Measures password hashing on this host to help choose Argon2 parameters")
+                .arg(Arg::with_name("target")
+                     .short("t")
+                     .long("target")
+                     .value_name("MS")
+                     .help("The hashing time to aim for, in milliseconds (defaults to 500)")
+                     .takes_value(true)
+                     )
+                .arg(Arg::with_name("lanes")
+                     .long("lanes")
+                     .value_name("N")
+                     .help("The number of lanes to hash with (defaults to 1)")
+                     .takes_value(true)
+                     )
+                .arg(Arg::with_name("max-memory")
+                     .long("max-memory")
+                     .value_name("KIB")
+                     .help("The largest memory size to try, in kibibytes (defaults to 1048576)")
+                     .takes_value(true)
+                     )
+                .arg(Arg::with_name("rounds")
+                     .long("rounds")
+                     .value_name("N")
+                     .help("The number of hashes to average for each configuration (defaults to 3)")
+                     .takes_value(true)
+                     )
+        )
+        .subcommand(
+            SubCommand::with_name("import-room")
+                .about("Recreates a room from a directory written by export-room")
+                .arg(Arg::with_name("config")
+                     .short("c")
+                     .long("config")
+                     .value_name("FILE")
+                     .help("Define a custom config file (defaults to `ruma.[json|toml|yaml]`)")
+                     .takes_value(true)
+                     )
+                .arg(Arg::with_name("input")
+                     .short("i")
+                     .long("input")
+                     .value_name("DIR")
+                     .help("The directory containing the export")
+                     .takes_value(true)
+                     .required(true)
+                     )
+                .arg(Arg::with_name("creator")
+                     .long("creator")
+                     .value_name("USER_ID")
+                     .help("The local user who will own the new room")
+                     .takes_value(true)
+                     .required(true)
+                     )
+        )
         .subcommand(
             SubCommand::with_name("run")
                 .about("Runs the Ruma server")
@@ -92,18 +288,235 @@ fn main() {
                      .help("Define a custom config file (defaults to `ruma.[json|toml|yaml]`)")
                      .takes_value(true)
                      )
+                .arg(Arg::with_name("auto")
+                     .long("auto")
+                     .help("Read the configuration from RUMA_* environment variables instead of a file")
+                     .conflicts_with("config")
+                     )
         )
         .subcommand(
             SubCommand::with_name("secret")
                 .about("Generates a random value to be used as a macaroon secret key")
         )
+        .subcommand(
+            SubCommand::with_name("seed")
+                .about("Fills the database with generated users, rooms, and messages for benchmarking")
+                .arg(Arg::with_name("config")
+                     .short("c")
+                     .long("config")
+                     .value_name("FILE")
+                     .help("Define a custom config file (defaults to `ruma.[json|toml|yaml]`)")
+                     .takes_value(true)
+                     )
+                .arg(Arg::with_name("users")
+                     .long("users")
+                     .value_name("N")
+                     .help("The number of users to create (defaults to 100)")
+                     .takes_value(true)
+                     )
+                .arg(Arg::with_name("rooms")
+                     .long("rooms")
+                     .value_name("M")
+                     .help("The number of rooms to create (defaults to 10)")
+                     .takes_value(true)
+                     )
+                .arg(Arg::with_name("events")
+                     .long("events")
+                     .value_name("K")
+                     .help("The number of messages to send across all rooms (defaults to 1000)")
+                     .takes_value(true)
+                     )
+                .arg(Arg::with_name("seed")
+                     .long("seed")
+                     .value_name("SEED")
+                     .help("Seed for the random number generator, for reproducible datasets")
+                     .takes_value(true)
+                     )
+        )
+        .subcommand(
+            SubCommand::with_name("send-event")
+                .about("Sends an event to a room as a local user, with the checks clients get")
+                .arg(Arg::with_name("config")
+                     .short("c")
+                     .long("config")
+                     .value_name("FILE")
+                     .help("Define a custom config file (defaults to `ruma.[json|toml|yaml]`)")
+                     .takes_value(true)
+                     )
+                .arg(Arg::with_name("room")
+                     .short("r")
+                     .long("room")
+                     .value_name("ROOM_ID")
+                     .help("The ID of the room to send the event in")
+                     .takes_value(true)
+                     .required(true)
+                     )
+                .arg(Arg::with_name("sender")
+                     .long("sender")
+                     .value_name("USER_ID")
+                     .help("The local user to send the event as")
+                     .takes_value(true)
+                     .required(true)
+                     )
+                .arg(Arg::with_name("type")
+                     .short("t")
+                     .long("type")
+                     .value_name("EVENT_TYPE")
+                     .help("The type of the event, e.g. m.room.power_levels")
+                     .takes_value(true)
+                     .required(true)
+                     )
+                .arg(Arg::with_name("state-key")
+                     .long("state-key")
+                     .value_name("KEY")
+                     .help("The state key, to send a state event (use \"\" for an empty key)")
+                     .takes_value(true)
+                     )
+                .arg(Arg::with_name("content")
+                     .long("content")
+                     .value_name("JSON")
+                     .help("The event's content, as a JSON object")
+                     .takes_value(true)
+                     .required(true)
+                     )
+        )
         .get_matches();
 
 
     info!("Beginning to process argument parsing");
     match matches.subcommand() {
-        ("run", Some(subcmd)) => {
+        ("bench", Some(subcmd)) => {
+            let options = match bench_options(subcmd) {
+                Ok(options) => options,
+                Err(error) => {
+                    println!("{}", error);
+
+                    return;
+                }
+            };
+
+            let results = match bench::run(&options) {
+                Ok(results) => results,
+                Err(error) => {
+                    println!("Failed to run benchmarks: {}", error);
+                    exit(1);
+                }
+            };
+
+            bench::print_results(&results);
+
+            if let Some(path) = subcmd.value_of("save") {
+                if let Err(error) = bench::save_results(path, &results) {
+                    println!("Failed to save results: {}", error);
+                    exit(1);
+                }
+            }
+
+            if let Some(path) = subcmd.value_of("compare") {
+                let tolerance = match subcmd.value_of("tolerance").unwrap_or("20").parse::<f64>() {
+                    Ok(percent) => percent / 100.0,
+                    Err(_) => {
+                        println!("--tolerance must be a number.");
+                        exit(1);
+                    }
+                };
+
+                match bench::compare_results(path, &results, tolerance) {
+                    Ok(ref regressions) if regressions.is_empty() => {
+                        println!("No regressions compared to {}.", path);
+                    }
+                    Ok(regressions) => {
+                        for regression in regressions {
+                            println!("Regression in {}", regression);
+                        }
+
+                        exit(1);
+                    }
+                    Err(error) => {
+                        println!("Failed to compare results: {}", error);
+                        exit(1);
+                    }
+                }
+            }
+        }
+        ("export-room", Some(subcmd)) => {
             let config = match Config::from_file(subcmd.value_of("config")) {
+                Ok(config) => config,
+                Err(error) => {
+                    println!("Failed to load configuration file: {}", error);
+
+                    return;
+                }
+            };
+
+            let room_id = subcmd.value_of("room").expect("clap should require --room");
+            let output = subcmd.value_of("output").expect("clap should require --output");
+
+            match export_room(&config, room_id, Path::new(output)) {
+                Ok(export) => println!(
+                    "Exported {} events and {} media references to {}.",
+                    export.events.len(),
+                    export.media.len(),
+                    output
+                ),
+                Err(error) => {
+                    println!("Failed to export the room: {}", error);
+                    exit(1);
+                }
+            }
+        }
+        ("import-room", Some(subcmd)) => {
+            let config = match Config::from_file(subcmd.value_of("config")) {
+                Ok(config) => config,
+                Err(error) => {
+                    println!("Failed to load configuration file: {}", error);
+
+                    return;
+                }
+            };
+
+            let input = subcmd.value_of("input").expect("clap should require --input");
+            let creator = subcmd.value_of("creator").expect("clap should require --creator");
+
+            match import_room(&config, Path::new(input), creator) {
+                Ok(import) => println!(
+                    "Imported {} events into {}, leaving out {}.",
+                    import.imported,
+                    import.room_id,
+                    import.skipped
+                ),
+                Err(error) => {
+                    println!("Failed to import the room: {}", error);
+                    exit(1);
+                }
+            }
+        }
+        ("hash-benchmark", Some(subcmd)) => {
+            let options = match hash_benchmark_options(subcmd) {
+                Ok(options) => options,
+                Err(error) => {
+                    println!("{}", error);
+
+                    return;
+                }
+            };
+
+            match hash_benchmark::run(&options) {
+                Ok(results) => hash_benchmark::print_results(&results, options.target_ms),
+                Err(error) => {
+                    println!("Failed to run the hash benchmark: {}", error);
+                    exit(1);
+                }
+            }
+        }
+        ("run", Some(subcmd)) => {
+            let config = if subcmd.is_present("auto") {
+                Config::from_env()
+            } else {
+                Config::from_file(subcmd.value_of("config"))
+            };
+
+            let config = match config {
                 Ok(config) => config,
                 Err(error) => {
                     info!("Either no file was found or it failed to open");
@@ -138,6 +551,144 @@ fn main() {
                 println!("Failed to generate macaroon secret key: {}", error)
             },
         },
+        ("seed", Some(subcmd)) => {
+            let config = match Config::from_file(subcmd.value_of("config")) {
+                Ok(config) => config,
+                Err(error) => {
+                    println!("Failed to load configuration file: {}", error);
+
+                    return;
+                }
+            };
+
+            let options = match seed_options(subcmd) {
+                Ok(options) => options,
+                Err(error) => {
+                    println!("{}", error);
+
+                    return;
+                }
+            };
+
+            match seed(&config, options) {
+                Ok(summary) => println!(
+                    "Created {} users, {} rooms, {} memberships, and {} messages.",
+                    summary.users,
+                    summary.rooms,
+                    summary.memberships,
+                    summary.events
+                ),
+                Err(error) => println!("Failed to seed the database: {}", error),
+            }
+        }
+        ("send-event", Some(subcmd)) => {
+            let config = match Config::from_file(subcmd.value_of("config")) {
+                Ok(config) => config,
+                Err(error) => {
+                    println!("Failed to load configuration file: {}", error);
+
+                    return;
+                }
+            };
+
+            let options = EventInjectionOptions {
+                room_id: subcmd.value_of("room").expect("clap should require --room"),
+                sender: subcmd.value_of("sender").expect("clap should require --sender"),
+                event_type: subcmd.value_of("type").expect("clap should require --type"),
+                state_key: subcmd.value_of("state-key"),
+                content: subcmd.value_of("content").expect("clap should require --content"),
+            };
+
+            match send_event(&config, &options) {
+                Ok(event_id) => println!("Sent {}.", event_id),
+                Err(error) => {
+                    println!("Failed to send the event: {}", error);
+                    exit(1);
+                }
+            }
+        }
         _ => println!("{}", matches.usage()),
     };
 }
+
+/// Parses the arguments to the `bench` subcommand.
+fn bench_options(matches: &ArgMatches) -> Result<BenchOptions, String> {
+    fn count(matches: &ArgMatches, name: &str, default: usize) -> Result<usize, String> {
+        match matches.value_of(name) {
+            Some(value) => value.parse().map_err(|_| {
+                format!("--{} must be a positive integer.", name)
+            }),
+            None => Ok(default),
+        }
+    }
+
+    let scenarios = match matches.values_of("scenario") {
+        Some(values) => {
+            let mut scenarios = Vec::new();
+
+            for value in values {
+                scenarios.push(value.parse::<Scenario>().map_err(|error| error.to_string())?);
+            }
+
+            scenarios
+        }
+        None => Scenario::all(),
+    };
+
+    Ok(BenchOptions {
+        url: matches.value_of("url").unwrap_or("http://localhost:3000").to_string(),
+        requests: count(matches, "requests", 1000)?,
+        concurrency: count(matches, "concurrency", 10)?,
+        scenarios: scenarios,
+    })
+}
+
+/// Parses the arguments to the `hash-benchmark` subcommand.
+fn hash_benchmark_options(matches: &ArgMatches) -> Result<HashBenchmarkOptions, String> {
+    fn count(matches: &ArgMatches, name: &str, default: u32) -> Result<u32, String> {
+        match matches.value_of(name) {
+            Some(value) => match value.parse() {
+                Ok(0) | Err(_) => Err(format!("--{} must be a positive integer.", name)),
+                Ok(value) => Ok(value),
+            },
+            None => Ok(default),
+        }
+    }
+
+    let target_ms = match matches.value_of("target") {
+        Some(value) => match value.parse::<f64>() {
+            Ok(target_ms) if target_ms > 0.0 => target_ms,
+            _ => return Err("--target must be a positive number.".to_string()),
+        },
+        None => 500.0,
+    };
+
+    Ok(HashBenchmarkOptions {
+        lanes: count(matches, "lanes", 1)?,
+        max_memory_kib: count(matches, "max-memory", 1048576)?,
+        rounds: count(matches, "rounds", 3)?,
+        target_ms: target_ms,
+    })
+}
+
+/// Parses the arguments to the `seed` subcommand.
+fn seed_options(matches: &ArgMatches) -> Result<SeedOptions, String> {
+    fn count(matches: &ArgMatches, name: &str, default: usize) -> Result<usize, String> {
+        match matches.value_of(name) {
+            Some(value) => value.parse().map_err(|_| {
+                format!("--{} must be a non-negative integer.", name)
+            }),
+            None => Ok(default),
+        }
+    }
+
+    Ok(SeedOptions {
+        users: count(matches, "users", 100)?,
+        rooms: count(matches, "rooms", 10)?,
+        events: count(matches, "events", 1000)?,
+        seed: match matches.value_of("seed") {
+            Some(_) => Some(count(matches, "seed", 0)?),
+            None => None,
+        },
+    })
+}
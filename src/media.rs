@@ -0,0 +1,1228 @@
+//! Files uploaded to the content repository, such as images shared in rooms and avatars.
+//!
+//! Each file is identified by a media ID unique on the server, and referred to by clients with an
+//! MXC URI, `mxc://<server name>/<media ID>`. The metadata lives in the `media` table, and the
+//! content in a `MediaStorage`, along with the thumbnails generated from it.
+//!
+//! Content is stored under the SHA-256 digest of its bytes rather than the media ID, so a file
+//! uploaded many times is only stored once. Each media ID holds a reference to the stored content,
+//! which is deleted along with its thumbnails when the last media ID referring to it is deleted.
+//!
+//! Admins can quarantine a file, e.g. one that breaks the server's rules, so that it can no longer
+//! be downloaded or thumbnailed without being deleted.
+
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::fmt::{Debug, Error as FmtError, Formatter};
+use std::fs::{File, create_dir_all, remove_dir_all, remove_file, rename};
+use std::io::{Cursor, ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+
+use diesel::{
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LoadDsl,
+    SelectDsl,
+    delete,
+    insert,
+    update,
+};
+use diesel::expression::dsl::sql;
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::types::BigInt;
+use diesel::result::Error as DieselError;
+use image::{DynamicImage, FilterType, GenericImage, ImageFormat, load_from_memory};
+use iron::{Plugin, Request};
+use iron::typemap::Key;
+use persistent::Read as PersistentRead;
+use ring::digest;
+use ruma_identifiers::UserId;
+use rustc_serialize::hex::ToHex;
+
+use crypto::Entropy;
+use error::ApiError;
+use schema::media;
+
+/// The largest file that can be uploaded if the configuration doesn't say, in bytes.
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 10 * 1024 * 1024;
+
+/// The most pixels an image may have for Ruma to decode it if the configuration doesn't say.
+pub const DEFAULT_MAX_IMAGE_PIXELS: u64 = 32 * 1024 * 1024;
+
+/// The largest width or height a thumbnail can be requested at, in pixels.
+pub const MAX_THUMBNAIL_SIZE: u32 = 1024;
+
+/// The length of generated media IDs.
+const MEDIA_ID_LENGTH: usize = 24;
+
+/// The bytes every PNG file starts with.
+const PNG_SIGNATURE: &'static [u8] = b"\x89PNG\r\n\x1a\n";
+
+/// The header of the APP1 segment holding a JPEG's EXIF data.
+const EXIF_HEADER: &'static [u8] = b"Exif\0\0";
+
+/// The EXIF tag saying how a photo must be rotated or flipped to be shown upright.
+const EXIF_ORIENTATION_TAG: u16 = 0x0112;
+
+/// The PNG chunks removed by `strip_image_metadata`: text, EXIF, and the last-modified time.
+const PNG_METADATA_CHUNKS: [&'static [u8]; 5] = [b"tEXt", b"zTXt", b"iTXt", b"eXIf", b"tIME"];
+
+/// Distinguishes the temporary files `FileMediaStorage` writes content to before moving it into
+/// place.
+static TEMPORARY_FILE_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// An uploaded file's metadata.
+#[derive(Clone, Debug, Queryable)]
+pub struct Media {
+    /// The media ID.
+    pub id: String,
+    /// The user who uploaded the file.
+    pub user_id: UserId,
+    /// The file's MIME type, as given by the uploader.
+    pub content_type: String,
+    /// The file name given by the uploader.
+    pub upload_name: Option<String>,
+    /// The file's size in bytes.
+    pub size: i64,
+    /// The time the file was uploaded.
+    pub created_at: PgTimestamp,
+    /// Whether or not an admin has quarantined the file, so it can't be downloaded.
+    pub quarantined: bool,
+    /// The hex-encoded SHA-256 digest of the file's content, which is stored under it.
+    pub content_hash: String,
+}
+
+/// A new uploaded file's metadata, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "media"]
+pub struct NewMedia {
+    /// The media ID.
+    pub id: String,
+    /// The user who uploaded the file.
+    pub user_id: UserId,
+    /// The file's MIME type, as given by the uploader.
+    pub content_type: String,
+    /// The file name given by the uploader.
+    pub upload_name: Option<String>,
+    /// The file's size in bytes.
+    pub size: i64,
+    /// The hex-encoded SHA-256 digest of the file's content, which is stored under it.
+    pub content_hash: String,
+}
+
+/// The content of a stored file, opened for reading.
+pub struct MediaContent {
+    /// The content's MIME type.
+    pub content_type: String,
+    /// A reader for the content.
+    pub reader: Box<Read + Send>,
+}
+
+/// How a thumbnail is fit to the requested size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThumbnailMethod {
+    /// Scale the image down until it covers the requested size, then crop off what's outside it.
+    Crop,
+    /// Scale the image down until it fits within the requested size.
+    Scale,
+}
+
+/// A place to keep the content of uploaded files and their thumbnails.
+///
+/// Uploaded files are kept under the digests of their content, and thumbnails under keys made of
+/// the digest followed by `-` and a description of the thumbnail.
+pub trait MediaStorage: Send + Sync {
+    /// Saves the content with the given key.
+    fn put(&self, key: &str, content: &[u8]) -> Result<(), ApiError>;
+
+    /// Opens the content with the given key for reading, or returns `None` if it isn't stored.
+    fn open(&self, key: &str) -> Result<Option<Box<Read + Send>>, ApiError>;
+
+    /// Deletes the content with the given key and the thumbnails generated from it, if they're
+    /// stored.
+    fn delete(&self, key: &str) -> Result<(), ApiError>;
+
+    /// Reads all of the content with the given key, or returns `None` if it isn't stored.
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, ApiError> {
+        let mut reader = match self.open(key)? {
+            Some(reader) => reader,
+            None => return Ok(None),
+        };
+
+        let mut content = Vec::new();
+
+        reader.read_to_end(&mut content)?;
+
+        Ok(Some(content))
+    }
+}
+
+/// A `MediaStorage` that keeps each file in a directory on disk, named by its key.
+#[derive(Debug)]
+pub struct FileMediaStorage {
+    directory: PathBuf,
+}
+
+/// A `MediaStorage` that keeps files in memory, which is lost when the server stops.
+#[derive(Debug)]
+pub struct MemoryMediaStorage {
+    files: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+/// An Iron plugin for attaching a `MediaStorage` to an Iron request.
+pub struct ServerMediaStorage;
+
+impl Media {
+    /// Saves an uploaded file with a new media ID and returns its metadata.
+    ///
+    /// The content is only written to storage if no other file with the same content is stored.
+    /// If a `quota` is given, the upload fails if it would take the user's files past that many
+    /// bytes. The check and the insert happen under a lock on the user's storage, so concurrent
+    /// uploads can't all pass the check and go over the quota together.
+    pub fn create(
+        connection: &PgConnection,
+        storage: &MediaStorage,
+        entropy: &Entropy,
+        user_id: &UserId,
+        content_type: String,
+        upload_name: Option<String>,
+        content: &[u8],
+        quota: Option<u64>,
+    ) -> Result<Media, ApiError> {
+        let new_media = NewMedia {
+            id: entropy.alphanumeric(MEDIA_ID_LENGTH),
+            user_id: user_id.clone(),
+            content_type: content_type,
+            upload_name: upload_name,
+            size: content.len() as i64,
+            content_hash: digest::digest(&digest::SHA256, content).as_ref().to_hex(),
+        };
+
+        // The row is only kept if the content was stored.
+        connection.transaction::<Media, ApiError, _>(|| {
+            if let Some(quota) = quota {
+                Media::lock_storage_used_by(connection, user_id)?;
+
+                let used = Media::storage_used_by(connection, user_id)? as u64;
+
+                if used + content.len() as u64 > quota {
+                    return Err(ApiError::quota_exceeded(Some(&format!(
+                        "Users may store at most {} bytes of files, and {} bytes are in use.",
+                        quota,
+                        used
+                    ))));
+                }
+            }
+
+            Media::lock_content(connection, &new_media.content_hash)?;
+
+            let media: Media = insert(&new_media)
+                .into(media::table)
+                .get_result(connection)?;
+
+            if storage.open(&media.content_hash)?.is_none() {
+                storage.put(&media.content_hash, content)?;
+            }
+
+            Ok(media)
+        }).map_err(ApiError::from)
+    }
+
+    /// Deletes the file's media ID, and its content and thumbnails if no other media ID refers to
+    /// the same content.
+    pub fn delete(&self, connection: &PgConnection, storage: &MediaStorage)
+    -> Result<(), ApiError> {
+        connection.transaction::<(), ApiError, _>(|| {
+            Media::lock_content(connection, &self.content_hash)?;
+
+            delete(media::table.find(&self.id)).execute(connection)?;
+
+            let references: i64 = media::table
+                .select(sql::<BigInt>("COUNT(*)"))
+                .filter(media::content_hash.eq(&self.content_hash))
+                .first(connection)?;
+
+            if references == 0 {
+                storage.delete(&self.content_hash)?;
+            }
+
+            Ok(())
+        }).map_err(ApiError::from)
+    }
+
+    /// Looks up an uploaded file's metadata by its media ID.
+    pub fn find(connection: &PgConnection, media_id: &str) -> Result<Option<Media>, ApiError> {
+        match media::table.find(media_id).get_result(connection) {
+            Ok(media) => Ok(Some(media)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(error) => Err(ApiError::from(error)),
+        }
+    }
+
+    /// The total size of the files the user has uploaded, in bytes, including quarantined ones.
+    pub fn storage_used_by(connection: &PgConnection, user_id: &UserId) -> Result<i64, ApiError> {
+        media::table
+            .select(sql::<BigInt>("COALESCE(SUM(size), 0)::BIGINT"))
+            .filter(media::user_id.eq(user_id))
+            .first(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Quarantines the file, or lifts its quarantine.
+    pub fn set_quarantined(&mut self, connection: &PgConnection, quarantined: bool)
+    -> Result<(), ApiError> {
+        update(media::table.find(&self.id))
+            .set(media::quarantined.eq(quarantined))
+            .execute(connection)?;
+
+        self.quarantined = quarantined;
+
+        Ok(())
+    }
+
+    /// The file's MXC URI on the server with the given domain.
+    pub fn content_uri(&self, domain: &str) -> String {
+        format!("mxc://{}/{}", domain, self.id)
+    }
+
+    /// Opens the file's content for reading, or returns `None` if it's missing from storage or
+    /// quarantined.
+    pub fn open(&self, storage: &MediaStorage) -> Result<Option<MediaContent>, ApiError> {
+        if self.quarantined {
+            return Ok(None);
+        }
+
+        Ok(storage.open(&self.content_hash)?.map(|reader| {
+            MediaContent {
+                content_type: self.content_type.clone(),
+                reader: reader,
+            }
+        }))
+    }
+
+    /// Returns a thumbnail of the file no larger than the given size, or `None` if the file isn't
+    /// an image that can be decoded, has more than `max_pixels` pixels, or is quarantined.
+    ///
+    /// Each thumbnail is generated the first time it's requested and kept in `storage` for later
+    /// requests, shared by every file with the same content and type. Images are never scaled up.
+    /// Thumbnails of PNGs are PNGs, to keep their transparency, and the rest are JPEGs.
+    pub fn thumbnail(
+        &self,
+        storage: &MediaStorage,
+        width: u32,
+        height: u32,
+        method: ThumbnailMethod,
+        max_pixels: u64,
+    ) -> Result<Option<MediaContent>, ApiError> {
+        if self.quarantined || !self.content_type.to_lowercase().starts_with("image/") {
+            return Ok(None);
+        }
+
+        let (content_type, format, extension) = if self.content_type.to_lowercase() == "image/png" {
+            ("image/png", ImageFormat::PNG, "png")
+        } else {
+            ("image/jpeg", ImageFormat::JPEG, "jpg")
+        };
+
+        let method_name = match method {
+            ThumbnailMethod::Crop => "crop",
+            ThumbnailMethod::Scale => "scale",
+        };
+        let key = format!(
+            "{}-{}x{}-{}.{}",
+            self.content_hash,
+            width,
+            height,
+            method_name,
+            extension
+        );
+
+        if let Some(reader) = storage.open(&key)? {
+            return Ok(Some(MediaContent {
+                content_type: content_type.to_string(),
+                reader: reader,
+            }));
+        }
+
+        let original = match storage.get(&self.content_hash)? {
+            Some(original) => original,
+            None => return Ok(None),
+        };
+
+        let image = match decode_image(&original, max_pixels) {
+            Ok(image) => image,
+            Err(error) => {
+                debug!("Failed to decode media {} for a thumbnail: {}", self.id, error);
+
+                return Ok(None);
+            }
+        };
+
+        let thumbnail = match method {
+            ThumbnailMethod::Crop => crop_image(image, width, height),
+            ThumbnailMethod::Scale => scale_image(&image, width, height),
+        };
+
+        let mut content = Vec::new();
+
+        thumbnail.save(&mut content, format)?;
+        storage.put(&key, &content)?;
+
+        Ok(Some(MediaContent {
+            content_type: content_type.to_string(),
+            reader: Box::new(Cursor::new(content)),
+        }))
+    }
+
+    /// Takes a lock on the content with the given digest until the end of the transaction, so
+    /// that it isn't deleted while another file is saved with the same content.
+    fn lock_content(connection: &PgConnection, content_hash: &str) -> Result<(), ApiError> {
+        connection
+            .execute(&format!("SELECT pg_advisory_xact_lock({})", lock_key(content_hash)))
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Takes a lock on the user's share of storage until the end of the transaction, so that
+    /// concurrent uploads are checked against the quota one at a time.
+    fn lock_storage_used_by(connection: &PgConnection, user_id: &UserId) -> Result<(), ApiError> {
+        // User IDs start with '@', so this never collides with the name of a content lock.
+        let key = lock_key(&format!("storage {}", user_id));
+
+        connection
+            .execute(&format!("SELECT pg_advisory_xact_lock({})", key))
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+}
+
+impl Debug for MediaContent {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        write!(formatter, "MediaContent {{ content_type: {:?} }}", self.content_type)
+    }
+}
+
+impl FileMediaStorage {
+    /// Creates a new `FileMediaStorage` keeping files in the given directory.
+    pub fn new<P>(directory: P) -> Self where P: Into<PathBuf> {
+        FileMediaStorage {
+            directory: directory.into(),
+        }
+    }
+
+    /// The path of the file for the given key.
+    ///
+    /// Thumbnails are kept in a directory next to the content they were made from, named after
+    /// its digest followed by `.thumbnails`, so they can be deleted along with it without listing
+    /// every stored file.
+    fn path(&self, key: &str) -> PathBuf {
+        match key.find('-') {
+            Some(index) => {
+                self.directory
+                    .join(format!("{}.thumbnails", &key[..index]))
+                    .join(&key[index + 1..])
+            }
+            None => self.directory.join(key),
+        }
+    }
+}
+
+impl MediaStorage for FileMediaStorage {
+    fn put(&self, key: &str, content: &[u8]) -> Result<(), ApiError> {
+        let path = self.path(key);
+        let directory = path.parent().unwrap_or(self.directory.as_path()).to_path_buf();
+
+        create_dir_all(&directory)?;
+
+        // The content is written to a temporary file first and renamed into place once it's on
+        // disk, so a crash or a concurrent reader never sees a partially written file.
+        let temporary_path = directory.join(format!(
+            ".{}.{}.tmp",
+            path.file_name().and_then(|name| name.to_str()).unwrap_or(key),
+            TEMPORARY_FILE_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let result = File::create(&temporary_path).and_then(|mut file| {
+            file.write_all(content)?;
+            file.sync_all()
+        }).and_then(|_| rename(&temporary_path, &path));
+
+        if let Err(error) = result {
+            let _ = remove_file(&temporary_path);
+
+            return Err(ApiError::from(error));
+        }
+
+        File::open(&directory)?.sync_all()?;
+
+        Ok(())
+    }
+
+    fn open(&self, key: &str) -> Result<Option<Box<Read + Send>>, ApiError> {
+        match File::open(self.path(key)) {
+            Ok(file) => Ok(Some(Box::new(file))),
+            Err(ref error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(ApiError::from(error)),
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<(), ApiError> {
+        match remove_file(self.path(key)) {
+            Ok(()) => {}
+            Err(ref error) if error.kind() == ErrorKind::NotFound => {}
+            Err(error) => return Err(ApiError::from(error)),
+        }
+
+        match remove_dir_all(self.directory.join(format!("{}.thumbnails", key))) {
+            Ok(()) => Ok(()),
+            Err(ref error) if error.kind() == ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(ApiError::from(error)),
+        }
+    }
+}
+
+impl MemoryMediaStorage {
+    /// Creates a new `MemoryMediaStorage` with no files.
+    pub fn new() -> Self {
+        MemoryMediaStorage {
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl MediaStorage for MemoryMediaStorage {
+    fn put(&self, key: &str, content: &[u8]) -> Result<(), ApiError> {
+        let mut files = self.files.lock().expect("MemoryMediaStorage mutex was poisoned");
+
+        files.insert(key.to_string(), content.to_vec());
+
+        Ok(())
+    }
+
+    fn open(&self, key: &str) -> Result<Option<Box<Read + Send>>, ApiError> {
+        let files = self.files.lock().expect("MemoryMediaStorage mutex was poisoned");
+
+        Ok(files.get(key).map(|content| {
+            Box::new(Cursor::new(content.clone())) as Box<Read + Send>
+        }))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), ApiError> {
+        let mut files = self.files.lock().expect("MemoryMediaStorage mutex was poisoned");
+        let thumbnail_prefix = format!("{}-", key);
+
+        files.retain(|name, _| name != key && !name.starts_with(&thumbnail_prefix));
+
+        Ok(())
+    }
+}
+
+impl ServerMediaStorage {
+    /// Extract the `MediaStorage` stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<Arc<MediaStorage>>, ApiError> {
+        request.get::<PersistentRead<ServerMediaStorage>>().map_err(ApiError::from)
+    }
+}
+
+impl Key for ServerMediaStorage {
+    type Value = Arc<MediaStorage>;
+}
+
+/// Whether or not a MIME type is SVG, which can't be uploaded because SVG images can run scripts.
+/// Parameters such as `charset` are ignored.
+pub fn content_type_is_svg(content_type: &str) -> bool {
+    content_type.split(';').next().unwrap_or("").trim().to_lowercase() == "image/svg+xml"
+}
+
+/// Whether or not a MIME type is allowed by a list of types, which may end in `/*` to allow every
+/// subtype, e.g. `image/*`. Parameters such as `charset` are ignored.
+pub fn content_type_allowed(allowed: &[String], content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+
+    allowed.iter().any(|allowed| {
+        let allowed = allowed.to_lowercase();
+
+        if allowed.ends_with("/*") {
+            essence.starts_with(&allowed[..allowed.len() - 1])
+        } else {
+            essence == allowed
+        }
+    })
+}
+
+/// The key of an advisory lock with the given name, such as the key of stored content: the first
+/// eight bytes of the SHA-256 digest of the name, so the same name gets the same key in every
+/// process.
+fn lock_key(name: &str) -> i64 {
+    let digest = digest::digest(&digest::SHA256, name.as_bytes());
+
+    digest.as_ref()[..8].iter().fold(0, |key, &byte| (key << 8) | byte as i64)
+}
+
+/// Decodes a PNG, JPEG, or GIF image, failing if it has more than `max_pixels` pixels.
+///
+/// The dimensions are read from the image's header first, so a small file that decodes to a huge
+/// image is refused before any memory is allocated for its pixels. Images in other formats are
+/// refused, since their size can't be checked that way.
+pub fn decode_image(content: &[u8], max_pixels: u64) -> Result<DynamicImage, String> {
+    let (width, height) = match image_dimensions(content) {
+        Some(dimensions) => dimensions,
+        None => return Err("The image's size can't be read from its header.".to_string()),
+    };
+
+    if width == 0 || height == 0 || width as u64 * height as u64 > max_pixels {
+        return Err(
+            format!("The image is {}x{}, which is more than {} pixels.", width, height, max_pixels)
+        );
+    }
+
+    load_from_memory(content).map_err(|error| error.to_string())
+}
+
+/// Reads the width and height of a PNG, JPEG, or GIF image from its header, or returns `None` if
+/// it's in another format or malformed.
+///
+/// For GIFs, the size is the largest of the logical screen and the first frame, since the frame
+/// may be bigger than the screen it's meant to be drawn on.
+fn image_dimensions(content: &[u8]) -> Option<(u32, u32)> {
+    if content.starts_with(PNG_SIGNATURE) {
+        png_dimensions(content)
+    } else if content.starts_with(&[0xFF, 0xD8]) {
+        jpeg_dimensions(content)
+    } else if content.starts_with(b"GIF87a") || content.starts_with(b"GIF89a") {
+        gif_dimensions(content)
+    } else {
+        None
+    }
+}
+
+/// Reads a PNG's size from its IHDR chunk, which must come first.
+fn png_dimensions(content: &[u8]) -> Option<(u32, u32)> {
+    let header = PNG_SIGNATURE.len();
+
+    if content.len() < header + 16 || &content[header + 4..header + 8] != b"IHDR" {
+        return None;
+    }
+
+    Some((big_endian_u32(&content[header + 8..]), big_endian_u32(&content[header + 12..])))
+}
+
+/// Reads a JPEG's size from its first start of frame segment.
+fn jpeg_dimensions(content: &[u8]) -> Option<(u32, u32)> {
+    let mut position = 2;
+
+    loop {
+        if position + 4 > content.len() || content[position] != 0xFF {
+            return None;
+        }
+
+        let marker = content[position + 1];
+
+        match marker {
+            0xFF => {
+                position += 1;
+
+                continue;
+            }
+            // The image data started without a frame header.
+            0xDA | 0xD9 => return None,
+            _ => {}
+        }
+
+        let length = (content[position + 2] as usize) << 8 | content[position + 3] as usize;
+
+        // Start of frame markers, leaving out DHT (0xC4), JPG (0xC8), and DAC (0xCC).
+        let is_frame = match marker {
+            0xC0...0xC3 | 0xC5...0xC7 | 0xC9...0xCB | 0xCD...0xCF => true,
+            _ => false,
+        };
+
+        if is_frame {
+            // The length is followed by the sample precision, the height, and the width.
+            if length < 7 || position + 9 > content.len() {
+                return None;
+            }
+
+            let height = (content[position + 5] as u32) << 8 | content[position + 6] as u32;
+            let width = (content[position + 7] as u32) << 8 | content[position + 8] as u32;
+
+            return Some((width, height));
+        }
+
+        if length < 2 {
+            return None;
+        }
+
+        position += 2 + length;
+    }
+}
+
+/// Reads a GIF's logical screen size and the size of its first frame, skipping any extension
+/// blocks before the frame.
+fn gif_dimensions(content: &[u8]) -> Option<(u32, u32)> {
+    // The header is followed by the logical screen descriptor, and then the global color table if
+    // the descriptor's flags say there is one.
+    if content.len() < 13 {
+        return None;
+    }
+
+    let screen_width = little_endian_u16(&content[6..]);
+    let screen_height = little_endian_u16(&content[8..]);
+    let flags = content[10];
+    let mut position = 13;
+
+    if flags & 0x80 != 0 {
+        position += 3 << ((flags & 0x07) + 1);
+    }
+
+    loop {
+        match content.get(position) {
+            // An image descriptor: its left and top offsets, then its width and height.
+            Some(&0x2C) => {
+                if position + 9 > content.len() {
+                    return None;
+                }
+
+                let right = little_endian_u16(&content[position + 1..]) +
+                    little_endian_u16(&content[position + 5..]);
+                let bottom = little_endian_u16(&content[position + 3..]) +
+                    little_endian_u16(&content[position + 7..]);
+
+                return Some((max(screen_width, right), max(screen_height, bottom)));
+            }
+            // An extension: its label, then data sub-blocks ending with an empty one.
+            Some(&0x21) => {
+                position += 2;
+
+                loop {
+                    match content.get(position) {
+                        Some(&0) => {
+                            position += 1;
+
+                            break;
+                        }
+                        Some(&size) => position += 1 + size as usize,
+                        None => return None,
+                    }
+                }
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Reads a big-endian 32-bit integer from the start of `bytes`, which must be long enough.
+fn big_endian_u32(bytes: &[u8]) -> u32 {
+    bytes[..4].iter().fold(0, |value, &byte| (value << 8) | byte as u32)
+}
+
+/// Reads a little-endian 16-bit integer from the start of `bytes`, which must be long enough.
+fn little_endian_u16(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8
+}
+
+/// Removes metadata, such as EXIF with the location a photo was taken at, from a JPEG or PNG image
+/// without re-encoding it. Other content, and images that can't be parsed, are returned as they
+/// are.
+///
+/// The format is worked out from the content rather than the type the uploader gave. Segments and
+/// chunks that affect how the image looks, such as ICC color profiles, are kept.
+pub fn strip_image_metadata(content: Vec<u8>) -> Vec<u8> {
+    let stripped = if content.starts_with(&[0xFF, 0xD8]) {
+        strip_jpeg_metadata(&content)
+    } else if content.starts_with(PNG_SIGNATURE) {
+        strip_png_metadata(&content)
+    } else {
+        None
+    };
+
+    stripped.unwrap_or(content)
+}
+
+/// Removes the APP1 (EXIF and XMP), APP3 to APP13 (including IPTC), APP15, and comment segments
+/// before the image data of a JPEG, or returns `None` if it's malformed.
+///
+/// EXIF data with an orientation is replaced with EXIF holding only the orientation, so photos
+/// taken with the camera on its side are still shown upright.
+fn strip_jpeg_metadata(content: &[u8]) -> Option<Vec<u8>> {
+    let mut stripped = content[..2].to_vec();
+    let mut position = 2;
+
+    loop {
+        if position + 2 > content.len() || content[position] != 0xFF {
+            return None;
+        }
+
+        let marker = content[position + 1];
+
+        match marker {
+            // Markers may be padded with any number of 0xFF bytes.
+            0xFF => {
+                position += 1;
+
+                continue;
+            }
+            // The image data starts after the start of scan segment and runs to the end.
+            0xDA | 0xD9 => {
+                stripped.extend_from_slice(&content[position..]);
+
+                return Some(stripped);
+            }
+            _ => {}
+        }
+
+        if position + 4 > content.len() {
+            return None;
+        }
+
+        let length = (content[position + 2] as usize) << 8 | content[position + 3] as usize;
+        let end = position + 2 + length;
+
+        if length < 2 || end > content.len() {
+            return None;
+        }
+
+        let is_metadata = match marker {
+            0xE1 | 0xE3...0xED | 0xEF | 0xFE => true,
+            _ => false,
+        };
+
+        if !is_metadata {
+            stripped.extend_from_slice(&content[position..end]);
+        } else if marker == 0xE1 {
+            if let Some(orientation) = exif_orientation(&content[position + 4..end]) {
+                stripped.extend_from_slice(&orientation_exif_segment(orientation));
+            }
+        }
+
+        position = end;
+    }
+}
+
+/// Reads the orientation from the first IFD of a JPEG's EXIF data, the content of its APP1
+/// segment. Returns `None` if the segment isn't EXIF, is malformed, or has no orientation.
+fn exif_orientation(segment: &[u8]) -> Option<u16> {
+    if !segment.starts_with(EXIF_HEADER) {
+        return None;
+    }
+
+    // The EXIF data is a TIFF file, whose header gives the byte order and the first IFD's offset.
+    let tiff = &segment[EXIF_HEADER.len()..];
+
+    if tiff.len() < 8 {
+        return None;
+    }
+
+    let big_endian = match &tiff[..2] {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let read_u16 = |offset: usize| if big_endian {
+        (tiff[offset] as u16) << 8 | tiff[offset + 1] as u16
+    } else {
+        tiff[offset] as u16 | (tiff[offset + 1] as u16) << 8
+    };
+    let read_u32 = |offset: usize| if big_endian {
+        big_endian_u32(&tiff[offset..]) as usize
+    } else {
+        tiff[offset..offset + 4].iter().rev().fold(0, |value, &byte| (value << 8) | byte as usize)
+    };
+
+    let ifd = read_u32(4);
+
+    if ifd + 2 > tiff.len() {
+        return None;
+    }
+
+    // Each entry is a tag, a type, a count, and a value, which a single SHORT starts.
+    (0..read_u16(ifd) as usize)
+        .map(|index| ifd + 2 + index * 12)
+        .take_while(|&entry| entry + 12 <= tiff.len())
+        .find(|&entry| read_u16(entry) == EXIF_ORIENTATION_TAG)
+        .map(|entry| read_u16(entry + 8))
+        .and_then(|orientation| match orientation {
+            1...8 => Some(orientation),
+            _ => None,
+        })
+}
+
+/// Builds a JPEG APP1 segment with EXIF data holding only the given orientation.
+fn orientation_exif_segment(orientation: u16) -> Vec<u8> {
+    let mut segment = vec![0xFF, 0xE1, 0, 34];
+
+    segment.extend_from_slice(EXIF_HEADER);
+    // A big-endian TIFF header, with the first IFD right after it.
+    segment.extend_from_slice(b"MM\0\x2a\0\0\0\x08");
+    // One entry: the orientation, as one SHORT.
+    segment.extend_from_slice(&[0, 1, 0x01, 0x12, 0, 3, 0, 0, 0, 1]);
+    segment.extend_from_slice(&[(orientation >> 8) as u8, orientation as u8, 0, 0]);
+    // No IFD follows.
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+
+    segment
+}
+
+/// Removes the chunks in `PNG_METADATA_CHUNKS` from a PNG, or returns `None` if it's malformed.
+fn strip_png_metadata(content: &[u8]) -> Option<Vec<u8>> {
+    let mut stripped = PNG_SIGNATURE.to_vec();
+    let mut position = PNG_SIGNATURE.len();
+
+    while position < content.len() {
+        // Each chunk is its length, its type, its data, and a CRC of the type and data.
+        if position + 12 > content.len() {
+            return None;
+        }
+
+        let length = content[position..position + 4]
+            .iter()
+            .fold(0, |length, &byte| (length << 8) | byte as usize);
+        let end = position + 12 + length;
+
+        if end > content.len() {
+            return None;
+        }
+
+        let chunk_type = &content[position + 4..position + 8];
+
+        if !PNG_METADATA_CHUNKS.iter().any(|metadata_type| *metadata_type == chunk_type) {
+            stripped.extend_from_slice(&content[position..end]);
+        }
+
+        position = end;
+    }
+
+    Some(stripped)
+}
+
+/// Scales an image down to fit within the given size, keeping its aspect ratio.
+fn scale_image(image: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let (original_width, original_height) = image.dimensions();
+    let ratio = (width as f64 / original_width as f64)
+        .min(height as f64 / original_height as f64)
+        .min(1.0);
+
+    resize_image(image, ratio)
+}
+
+/// Scales an image down to cover the given size, keeping its aspect ratio, and crops it to that
+/// size around its center.
+fn crop_image(image: DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let (original_width, original_height) = image.dimensions();
+    let ratio = (width as f64 / original_width as f64)
+        .max(height as f64 / original_height as f64)
+        .min(1.0);
+
+    let mut scaled = resize_image(&image, ratio);
+    let (scaled_width, scaled_height) = scaled.dimensions();
+    let crop_width = min(width, scaled_width);
+    let crop_height = min(height, scaled_height);
+
+    scaled.crop(
+        (scaled_width - crop_width) / 2,
+        (scaled_height - crop_height) / 2,
+        crop_width,
+        crop_height,
+    )
+}
+
+/// Resizes an image by the given ratio, leaving it as it is if the ratio is 1.
+fn resize_image(image: &DynamicImage, ratio: f64) -> DynamicImage {
+    if ratio >= 1.0 {
+        return image.clone();
+    }
+
+    let (width, height) = image.dimensions();
+    let new_width = max((width as f64 * ratio).round() as u32, 1);
+    let new_height = max((height as f64 * ratio).round() as u32, 1);
+
+    image.resize_exact(new_width, new_height, FilterType::Triangle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::env::temp_dir;
+    use std::fs::{read_dir, remove_dir_all};
+    use std::io::Read;
+
+    use diesel::pg::data_types::PgTimestamp;
+    use image::{DynamicImage, GenericImage, ImageBuffer, ImageFormat, Rgb, load_from_memory};
+    use ruma_identifiers::UserId;
+
+    use super::{
+        DEFAULT_MAX_IMAGE_PIXELS,
+        FileMediaStorage,
+        Media,
+        MediaStorage,
+        MemoryMediaStorage,
+        PNG_SIGNATURE,
+        ThumbnailMethod,
+        content_type_is_svg,
+        decode_image,
+        exif_orientation,
+        image_dimensions,
+        orientation_exif_segment,
+        strip_image_metadata,
+    };
+
+    /// A 20x10 image encoded in the given format.
+    fn encode_image(format: ImageFormat) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(20, 10, Rgb([0, 0, 255])));
+        let mut content = Vec::new();
+
+        image.save(&mut content, format).unwrap();
+
+        content
+    }
+
+    /// Whether or not `needle` appears anywhere in `haystack`.
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    /// Stores a 200x100 PNG and returns its metadata.
+    fn store_image(storage: &MediaStorage) -> Media {
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(200, 100, Rgb([255, 0, 0])));
+        let mut content = Vec::new();
+
+        image.save(&mut content, ImageFormat::PNG).unwrap();
+        storage.put("image", &content).unwrap();
+
+        Media {
+            id: "image".to_string(),
+            user_id: UserId::try_from("@carl:ruma.test").unwrap(),
+            content_type: "image/png".to_string(),
+            upload_name: None,
+            size: content.len() as i64,
+            created_at: PgTimestamp(0),
+            quarantined: false,
+            content_hash: "image".to_string(),
+        }
+    }
+
+    fn thumbnail_dimensions(
+        media: &Media,
+        storage: &MediaStorage,
+        width: u32,
+        height: u32,
+        method: ThumbnailMethod,
+    ) -> (u32, u32) {
+        let mut thumbnail = media
+            .thumbnail(storage, width, height, method, DEFAULT_MAX_IMAGE_PIXELS)
+            .unwrap()
+            .unwrap();
+        let mut content = Vec::new();
+
+        thumbnail.reader.read_to_end(&mut content).unwrap();
+
+        load_from_memory(&content).unwrap().dimensions()
+    }
+
+    #[test]
+    fn thumbnails() {
+        let storage = MemoryMediaStorage::new();
+        let media = store_image(&storage);
+
+        assert_eq!(
+            thumbnail_dimensions(&media, &storage, 50, 50, ThumbnailMethod::Scale),
+            (50, 25)
+        );
+        assert_eq!(
+            thumbnail_dimensions(&media, &storage, 50, 50, ThumbnailMethod::Crop),
+            (50, 50)
+        );
+
+        // Images are never scaled up.
+        assert_eq!(
+            thumbnail_dimensions(&media, &storage, 800, 600, ThumbnailMethod::Scale),
+            (200, 100)
+        );
+
+        // Generated thumbnails are kept for later requests.
+        assert!(storage.get("image-50x50-crop.png").unwrap().is_some());
+    }
+
+    #[test]
+    fn deleting_content_deletes_its_thumbnails() {
+        let storage = MemoryMediaStorage::new();
+        let media = store_image(&storage);
+
+        storage.put("imagery", b"Other content").unwrap();
+        thumbnail_dimensions(&media, &storage, 50, 50, ThumbnailMethod::Scale);
+
+        storage.delete("image").unwrap();
+
+        assert!(storage.get("image").unwrap().is_none());
+        assert!(storage.get("image-50x50-scale.png").unwrap().is_none());
+        assert!(storage.get("imagery").unwrap().is_some());
+    }
+
+    #[test]
+    fn file_storage_keeps_thumbnails_beside_their_content() {
+        let directory = temp_dir().join("ruma-file-media-storage-test");
+        let _ = remove_dir_all(&directory);
+        let storage = FileMediaStorage::new(&directory);
+        let media = store_image(&storage);
+
+        storage.put("imagery", b"Other content").unwrap();
+        thumbnail_dimensions(&media, &storage, 50, 50, ThumbnailMethod::Scale);
+
+        assert!(directory.join("image.thumbnails/50x50-scale.png").is_file());
+
+        // No temporary files are left behind once content is stored.
+        assert_eq!(read_dir(&directory).unwrap().count(), 3);
+
+        storage.delete("image").unwrap();
+
+        assert!(storage.get("image").unwrap().is_none());
+        assert!(storage.get("image-50x50-scale.png").unwrap().is_none());
+        assert!(!directory.join("image.thumbnails").exists());
+        assert!(storage.get("imagery").unwrap().is_some());
+
+        remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn exif_is_stripped_from_jpegs() {
+        let jpeg = encode_image(ImageFormat::JPEG);
+        let exif = b"Exif\0\0GPS 51.5007 N 0.1246 W";
+        let mut with_exif = jpeg[..2].to_vec();
+
+        with_exif.extend_from_slice(&[0xFF, 0xE1, 0, exif.len() as u8 + 2]);
+        with_exif.extend_from_slice(exif);
+        with_exif.extend_from_slice(&jpeg[2..]);
+
+        let stripped = strip_image_metadata(with_exif);
+
+        assert!(!contains(&stripped, b"GPS"));
+        assert_eq!(stripped, jpeg);
+        assert_eq!(load_from_memory(&stripped).unwrap().dimensions(), (20, 10));
+    }
+
+    #[test]
+    fn exif_orientation_is_kept_in_jpegs() {
+        let jpeg = encode_image(ImageFormat::JPEG);
+        // Little-endian EXIF with a GPS IFD pointer and an orientation of 6, rotated 90 degrees.
+        let exif = b"Exif\0\0II\x2a\0\x08\0\0\0\x02\0\
+            \x25\x88\x04\0\x01\0\0\0\x26\0\0\0\
+            \x12\x01\x03\0\x01\0\0\0\x06\0\0\0\
+            \0\0\0\0GPS 51.5007 N 0.1246 W";
+        let mut with_exif = jpeg[..2].to_vec();
+
+        with_exif.extend_from_slice(&[0xFF, 0xE1, 0, exif.len() as u8 + 2]);
+        with_exif.extend_from_slice(exif);
+        with_exif.extend_from_slice(&jpeg[2..]);
+
+        assert_eq!(exif_orientation(exif), Some(6));
+
+        let stripped = strip_image_metadata(with_exif);
+        let mut expected = jpeg[..2].to_vec();
+
+        expected.extend_from_slice(&orientation_exif_segment(6));
+        expected.extend_from_slice(&jpeg[2..]);
+
+        assert!(!contains(&stripped, b"GPS"));
+        assert_eq!(stripped, expected);
+        assert_eq!(exif_orientation(&orientation_exif_segment(6)[4..]), Some(6));
+        assert_eq!(load_from_memory(&stripped).unwrap().dimensions(), (20, 10));
+    }
+
+    #[test]
+    fn svg_is_recognized() {
+        assert!(content_type_is_svg("image/svg+xml"));
+        assert!(content_type_is_svg("Image/SVG+XML; charset=utf-8"));
+        assert!(!content_type_is_svg("image/png"));
+    }
+
+    #[test]
+    fn text_chunks_are_stripped_from_pngs() {
+        let png = encode_image(ImageFormat::PNG);
+        let text = b"Author\0Carl";
+        // The signature and the IHDR chunk, which must come first.
+        let header_end = PNG_SIGNATURE.len() + 12 + 13;
+        let mut with_text = png[..header_end].to_vec();
+
+        with_text.extend_from_slice(&[0, 0, 0, text.len() as u8]);
+        with_text.extend_from_slice(b"tEXt");
+        with_text.extend_from_slice(text);
+        with_text.extend_from_slice(&[0, 0, 0, 0]);
+        with_text.extend_from_slice(&png[header_end..]);
+
+        let stripped = strip_image_metadata(with_text);
+
+        assert!(!contains(&stripped, b"Carl"));
+        assert_eq!(stripped, png);
+        assert_eq!(load_from_memory(&stripped).unwrap().dimensions(), (20, 10));
+    }
+
+    #[test]
+    fn other_content_is_not_changed() {
+        let truncated_jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1, 0x10];
+
+        assert_eq!(strip_image_metadata(b"Hello, world!".to_vec()), b"Hello, world!".to_vec());
+        assert_eq!(strip_image_metadata(truncated_jpeg.clone()), truncated_jpeg);
+    }
+
+    #[test]
+    fn quarantined_media_cannot_be_opened() {
+        let storage = MemoryMediaStorage::new();
+        let mut media = store_image(&storage);
+
+        media.quarantined = true;
+
+        assert!(media.open(&storage).unwrap().is_none());
+        assert!(
+            media.thumbnail(&storage, 50, 50, ThumbnailMethod::Scale, DEFAULT_MAX_IMAGE_PIXELS)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn image_dimensions_are_read_from_headers() {
+        assert_eq!(image_dimensions(&encode_image(ImageFormat::PNG)), Some((20, 10)));
+        assert_eq!(image_dimensions(&encode_image(ImageFormat::JPEG)), Some((20, 10)));
+
+        // A 20x10 logical screen with a 4-color global color table, a graphic control extension,
+        // and a 30x5 frame.
+        let mut gif = b"GIF89a\x14\x00\x0a\x00\x81\x00\x00".to_vec();
+
+        gif.extend_from_slice(&[0; 12]);
+        gif.extend_from_slice(b"\x21\xf9\x04\x00\x00\x00\x00\x00");
+        gif.extend_from_slice(b"\x2c\x00\x00\x00\x00\x1e\x00\x05\x00\x00");
+
+        assert_eq!(image_dimensions(&gif), Some((30, 10)));
+
+        assert_eq!(image_dimensions(b"Hello, world!"), None);
+        assert_eq!(image_dimensions(&[0xFF, 0xD8, 0xFF, 0xE1, 0x10]), None);
+    }
+
+    #[test]
+    fn images_over_the_pixel_limit_are_not_decoded() {
+        // Only the header of a 100000x100000 PNG, which would take 30 GB to decode.
+        let mut bomb = PNG_SIGNATURE.to_vec();
+
+        bomb.extend_from_slice(b"\x00\x00\x00\x0dIHDR\x00\x01\x86\xa0\x00\x01\x86\xa0");
+        bomb.extend_from_slice(b"\x08\x02\x00\x00\x00");
+
+        assert!(decode_image(&bomb, DEFAULT_MAX_IMAGE_PIXELS).is_err());
+
+        let image = encode_image(ImageFormat::PNG);
+
+        assert!(decode_image(&image, 200).is_ok());
+        assert!(decode_image(&image, 199).is_err());
+
+        let storage = MemoryMediaStorage::new();
+        let media = store_image(&storage);
+
+        assert!(media.thumbnail(&storage, 50, 50, ThumbnailMethod::Scale, 100).unwrap().is_none());
+    }
+}
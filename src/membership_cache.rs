@@ -0,0 +1,199 @@
+//! A process-local cache of which users have joined which rooms.
+//!
+//! Checking that a user has joined a room is the most common authorization check Ruma makes, so
+//! positive answers are kept in memory. Only joins are cached: a user who isn't joined is always
+//! looked up again, so the cache can never refuse a user who has just joined.
+//!
+//! Entries are evicted in two ways. Membership changes made by this process evict their entry
+//! straight away. Changes made by any other process are picked up by the `CatchUpMembershipCache`
+//! job, which evicts the entry for every `m.room.member` event since it last ran.
+//!
+//! Filling the cache is optimistic: a lookup only stores its answer if nothing was evicted while
+//! it was reading from the database, since its answer may already be out of date.
+
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use diesel::{ExpressionMethods, FilterDsl, LoadDsl, SelectDsl};
+use diesel::pg::PgConnection;
+use iron::{Plugin, Request};
+use iron::typemap::Key;
+use persistent::Read;
+use ruma_events::EventType;
+use ruma_identifiers::{RoomId, UserId};
+
+use config::Config;
+use error::ApiError;
+use jobs::Job;
+use room_membership::RoomMembership;
+use schema::events;
+use stream_position::{Stream, StreamPosition};
+
+/// The most entries the cache holds before it is emptied and starts again.
+const MAX_ENTRIES: usize = 100_000;
+
+/// Remembers which users have joined which rooms.
+#[derive(Debug)]
+pub struct MembershipCache {
+    state: Mutex<CacheState>,
+}
+
+/// The contents of a `MembershipCache`.
+#[derive(Debug)]
+struct CacheState {
+    /// Incremented whenever an entry is evicted, so lookups can tell if they raced an eviction.
+    generation: u64,
+    /// The room and user of every membership known to be a join.
+    joined: HashSet<(RoomId, UserId)>,
+    /// The position in the event stream that evictions have caught up to, if they have run.
+    position: Option<i64>,
+}
+
+/// An Iron plugin for attaching a `MembershipCache` to an Iron request.
+pub struct ServerMembershipCache;
+
+/// A background job that evicts cache entries for membership changes made by other processes.
+#[derive(Debug)]
+pub struct CatchUpMembershipCache {
+    membership_cache: Arc<MembershipCache>,
+}
+
+impl MembershipCache {
+    /// Creates an empty `MembershipCache`.
+    pub fn new() -> Self {
+        MembershipCache {
+            state: Mutex::new(CacheState {
+                generation: 0,
+                joined: HashSet::new(),
+                position: None,
+            }),
+        }
+    }
+
+    /// Whether or not the user has joined the room, going to the database if the answer isn't
+    /// cached.
+    pub fn is_joined(&self, connection: &PgConnection, room_id: &RoomId, user_id: &UserId)
+    -> Result<bool, ApiError> {
+        let key = (room_id.clone(), user_id.clone());
+
+        let generation = {
+            let state = self.lock();
+
+            if state.joined.contains(&key) {
+                return Ok(true);
+            }
+
+            state.generation
+        };
+
+        let joined = match RoomMembership::find(connection, room_id, user_id)? {
+            Some(membership) => membership.membership == "join",
+            None => false,
+        };
+
+        if joined {
+            let mut state = self.lock();
+
+            if state.generation == generation {
+                if state.joined.len() >= MAX_ENTRIES {
+                    state.joined.clear();
+                }
+
+                state.joined.insert(key);
+            }
+        }
+
+        Ok(joined)
+    }
+
+    /// Forgets the user's membership in the room. Must be called after the membership changes.
+    pub fn invalidate(&self, room_id: &RoomId, user_id: &UserId) {
+        let mut state = self.lock();
+
+        state.generation += 1;
+        state.joined.remove(&(room_id.clone(), user_id.clone()));
+    }
+
+    /// Evicts the entry for every membership event committed since the last time this ran.
+    ///
+    /// The first run has nothing to compare against, so it empties the cache instead.
+    pub fn catch_up(&self, connection: &PgConnection) -> Result<(), ApiError> {
+        let current = StreamPosition::current(connection, Stream::Events)?;
+        let position = self.lock().position;
+
+        let changed = match position {
+            Some(position) => {
+                let changed: Vec<(RoomId, Option<String>)> = events::table
+                    .select((events::room_id, events::state_key))
+                    .filter(events::event_type.eq(EventType::RoomMember.to_string()))
+                    .filter(events::ordering.gt(position))
+                    .filter(events::ordering.le(current))
+                    .load(connection)?;
+
+                Some(changed)
+            }
+            None => None,
+        };
+
+        let mut state = self.lock();
+
+        state.generation += 1;
+
+        match changed {
+            Some(changed) => {
+                for (room_id, state_key) in changed {
+                    let user_id = state_key.and_then(|key| UserId::try_from(&key[..]).ok());
+
+                    if let Some(user_id) = user_id {
+                        state.joined.remove(&(room_id, user_id));
+                    }
+                }
+            }
+            None => state.joined.clear(),
+        }
+
+        state.position = Some(current);
+
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<CacheState> {
+        self.state.lock().expect("MembershipCache mutex was poisoned")
+    }
+}
+
+impl ServerMembershipCache {
+    /// Extract the `MembershipCache` stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<Arc<MembershipCache>>, ApiError> {
+        request.get::<Read<ServerMembershipCache>>().map_err(ApiError::from)
+    }
+}
+
+impl Key for ServerMembershipCache {
+    type Value = Arc<MembershipCache>;
+}
+
+impl CatchUpMembershipCache {
+    /// Creates a job that keeps the given cache up to date.
+    pub fn new(membership_cache: Arc<MembershipCache>) -> Self {
+        CatchUpMembershipCache {
+            membership_cache: membership_cache,
+        }
+    }
+}
+
+impl Job for CatchUpMembershipCache {
+    fn name(&self) -> &'static str {
+        "catch_up_membership_cache"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn run(&self, connection: &PgConnection, _config: &Config, _now: i64) -> Result<(), ApiError> {
+        self.membership_cache.catch_up(connection)
+    }
+}
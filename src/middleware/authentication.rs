@@ -6,17 +6,55 @@ use ruma_identifiers::UserId;
 use serde_json::Value;
 
 use access_token::AccessToken;
-use authentication::{AuthParams, InteractiveAuth, PasswordAuthParams};
+use app_service::AppService;
+use authentication::{AuthParams, PasswordAuthParams};
+use clock::ServerClock;
 use config::Config;
+use crypto::{ServerEntropy, secrets_match};
 use db::DB;
 use error::ApiError;
+use uia::{AuthResult, InteractiveAuth};
 use user::User;
 
 /// Handles access token authentication for all API endpoints that require it.
 #[derive(Debug)]
 pub struct AccessTokenAuth;
 
+/// Handles authentication for the endpoints reserved for application services, whose access
+/// token is the `as_token` of one of the `app_services` in the configuration.
+#[derive(Debug)]
+pub struct AppServiceAuth;
+
+/// Handles authentication like `AccessTokenAuth`, but also lets application services act as one
+/// of the users they manage, given by the `user_id` query parameter or else their sender.
+///
+/// Requests from application services have an `AppService` and a `User` but no `AccessToken`.
+#[derive(Debug)]
+pub struct AccessTokenOrAppServiceAuth;
+
+/// Restricts an endpoint to the users listed in the `admins` configuration attribute.
+///
+/// Must be linked after `AccessTokenAuth`.
+#[derive(Debug)]
+pub struct AdminAuth;
+
+/// Rejects guest users, for endpoints that create or administer rooms or change the account.
+///
+/// Must be linked after `AccessTokenAuth`.
+#[derive(Debug)]
+pub struct NonGuestAuth;
+
+/// Logs a user in with the `m.login.password` credentials in the request body, given either at
+/// the top level or in an `auth` object. The user may be identified by user ID, localpart, or a
+/// third-party identifier bound to their account.
+#[derive(Debug)]
+pub struct LoginAuth;
+
 /// Handles Matrix's interactive authentication protocol for all API endpoints that require it.
+///
+/// Once a flow is complete, the user who authenticated with a password, if any, is available to
+/// the handler. Must be linked after `JsonRequest`, and after `AccessTokenAuth` if the endpoint
+/// needs an access token.
 #[derive(Debug)]
 pub struct UIAuth {
     interactive_auth: InteractiveAuth,
@@ -33,13 +71,24 @@ impl UIAuth {
 
 impl BeforeMiddleware for AccessTokenAuth {
     fn before(&self, request: &mut Request) -> IronResult<()> {
+        let clock = ServerClock::from_request(request)?;
         let connection = DB::from_request(request)?;
         let url = request.url.clone().into_generic_url();
         let mut query_pairs = url.query_pairs();
 
         if let Some((_, ref token)) = query_pairs.find(|&(ref key, _)| key == "access_token") {
-            if let Ok(access_token) = AccessToken::find_valid_by_token(&connection, &token) {
+            if let Ok(mut access_token) = AccessToken::find_valid_by_token(&connection, &token) {
                 if let Ok(user) = User::find_by_access_token(&connection, &access_token) {
+                    if user.locked {
+                        let error = ApiError::user_locked(None);
+
+                        return Err(IronError::new(error.clone(), error));
+                    }
+
+                    let ip = request.remote_addr.ip().to_string();
+
+                    access_token.record_use(&connection, clock.now_millis(), &ip)?;
+
                     request.extensions.insert::<AccessToken>(access_token);
                     request.extensions.insert::<User>(user);
 
@@ -52,7 +101,123 @@ impl BeforeMiddleware for AccessTokenAuth {
     }
 }
 
-impl BeforeMiddleware for UIAuth {
+impl BeforeMiddleware for AppServiceAuth {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let config = Config::from_request(request)?;
+        let url = request.url.clone().into_generic_url();
+        let mut query_pairs = url.query_pairs();
+
+        if let Some((_, ref token)) = query_pairs.find(|&(ref key, _)| key == "access_token") {
+            let app_service = config.app_services
+                .iter()
+                .find(|app_service| secrets_match(&app_service.as_token, token));
+
+            if let Some(app_service) = app_service {
+                request.extensions.insert::<AppService>(app_service.clone());
+
+                return Ok(());
+            }
+        }
+
+        let error =
+            ApiError::unauthorized(Some("Only application services can use this endpoint."));
+
+        Err(IronError::new(error.clone(), error))
+    }
+}
+
+impl BeforeMiddleware for AccessTokenOrAppServiceAuth {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let config = Config::from_request(request)?;
+        let url = request.url.clone().into_generic_url();
+
+        let token = url.query_pairs()
+            .find(|&(ref key, _)| key == "access_token")
+            .map(|(_, value)| value.into_owned());
+
+        let app_service = token.and_then(|token| {
+            config.app_services
+                .iter()
+                .find(|app_service| secrets_match(&app_service.as_token, &token))
+                .cloned()
+        });
+
+        let app_service = match app_service {
+            Some(app_service) => app_service,
+            None => return AccessTokenAuth.before(request),
+        };
+
+        let user_id = url.query_pairs()
+            .find(|&(ref key, _)| key == "user_id")
+            .map(|(_, value)| value.into_owned())
+            .unwrap_or_else(|| format!("@{}:{}", app_service.sender_localpart, config.domain));
+
+        let user_id = match UserId::try_from(&user_id[..]) {
+            Ok(user_id) => user_id,
+            Err(_) => {
+                let error = ApiError::invalid_param("user_id", "Must be a user ID.");
+
+                return Err(IronError::new(error.clone(), error));
+            }
+        };
+
+        if !app_service.manages_user(&user_id, &config.domain) {
+            let error = ApiError::unauthorized(Some(
+                &format!("{} is not managed by the application service.", user_id)
+            ));
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let connection = DB::from_request(request)?;
+
+        let user = User::find_by_uid(&connection, &user_id)?;
+
+        if user.locked {
+            let error = ApiError::user_locked(None);
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        request.extensions.insert::<AppService>(app_service);
+        request.extensions.insert::<User>(user);
+
+        Ok(())
+    }
+}
+
+impl BeforeMiddleware for AdminAuth {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let config = Config::from_request(request)?;
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user");
+
+        if config.admins.contains(&user.id) {
+            return Ok(());
+        }
+
+        let error = ApiError::unauthorized(Some("Only server administrators can use this endpoint."));
+
+        Err(IronError::new(error.clone(), error))
+    }
+}
+
+impl BeforeMiddleware for NonGuestAuth {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user");
+
+        if !user.is_guest {
+            return Ok(());
+        }
+
+        let error = ApiError::guest_forbidden(Some("Guests cannot use this endpoint."));
+
+        Err(IronError::new(error.clone(), error))
+    }
+}
+
+impl BeforeMiddleware for LoginAuth {
     fn before(&self, request: &mut Request) -> IronResult<()> {
         let json = request
             .get::<bodyparser::Json>()
@@ -60,21 +225,30 @@ impl BeforeMiddleware for UIAuth {
             .expect("bodyparser did not find JSON in the body");
         let config = Config::from_request(request)?;
 
-        if let Some(auth_json) = json.find("auth") {
-            if is_m_login_password(auth_json) {
-                if let Ok((user_id, password)) = get_user_id_and_password(auth_json, &config) {
-                    let auth_params = AuthParams::Password(PasswordAuthParams {
-                        password: password,
-                        user_id: user_id,
-                    });
+        // The credentials are at the top level of the body, as in the spec, or in an `auth`
+        // object like the one interactive authentication uses.
+        let auth_json = json.find("auth").unwrap_or(&json);
 
-                    let connection = DB::from_request(request)?;
+        if is_m_login_password(auth_json) {
+            let connection = DB::from_request(request)?;
 
-                    if let Ok(user) =  auth_params.authenticate(&connection) {
-                        request.extensions.insert::<User>(user);
+            if let Some(credentials) = PasswordAuthParams::from_json(
+                auth_json,
+                &config,
+                &connection,
+            ) {
+                let auth_params = AuthParams::Password(credentials);
 
-                        return Ok(());
+                if let Ok(user) = auth_params.authenticate(&connection, &config) {
+                    if user.locked {
+                        let error = ApiError::user_locked(None);
+
+                        return Err(IronError::new(error.clone(), error));
                     }
+
+                    request.extensions.insert::<User>(user);
+
+                    return Ok(());
                 }
             }
         }
@@ -83,21 +257,36 @@ impl BeforeMiddleware for UIAuth {
     }
 }
 
-fn get_user_id_and_password(json: &Value, config: &Config) -> Result<(UserId, String), ()> {
-    let username = json.find("user").and_then(|username_json| username_json.as_str());
-    let password = json.find("password").and_then(|password_json| password_json.as_str());
-
-    match (username, password) {
-        (Some(username), Some(password)) => {
-            match UserId::try_from(username) {
-                Ok(user_id) => Ok((user_id, password.to_string())),
-                Err(_) => match UserId::try_from(&format!("@{}:{}", username, &config.domain)) {
-                    Ok(user_id) => Ok((user_id, password.to_string())),
-                    Err(_) => Err(()),
-                },
+impl BeforeMiddleware for UIAuth {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let json = request
+            .get::<bodyparser::Json>()
+            .expect("bodyparser failed to parse")
+            .expect("bodyparser did not find JSON in the body");
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+        let clock = ServerClock::from_request(request)?;
+        let entropy = ServerEntropy::from_request(request)?;
+        let user = request.extensions.get::<User>().cloned();
+
+        let result = self.interactive_auth.authenticate(
+            &connection,
+            &config,
+            &**entropy,
+            json.find("auth"),
+            user.as_ref(),
+            clock.now_millis(),
+        )?;
+
+        match result {
+            AuthResult::Complete(Some(user)) => {
+                request.extensions.insert::<User>(user);
+
+                Ok(())
             }
+            AuthResult::Complete(None) => Ok(()),
+            AuthResult::Incomplete(challenge) => Err(IronError::new(challenge.clone(), challenge)),
         }
-        _ => Err(()),
     }
 }
 
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use iron::{AfterMiddleware, BeforeMiddleware, IronError, IronResult, Request, Response};
+use iron::status::Status;
+use iron::typemap::Key;
+use mount::OriginalUrl;
+
+use db::DbUsage;
+use user::User;
+
+/// The endpoint classes that can be given a latency budget, and their default budgets in
+/// milliseconds.
+pub const DEFAULT_SLOW_REQUEST_THRESHOLDS: &'static [(&'static str, u64)] = &[
+    ("admin", 10000),
+    ("auth", 2000),
+    ("default", 500),
+];
+
+/// Logs the timings of every request at the debug level, or as a warning if the request took
+/// longer than the budget for its endpoint class.
+///
+/// Must be linked as both the first before middleware and the last after middleware of a chain.
+#[derive(Clone, Debug)]
+pub struct LatencyBudget {
+    thresholds: HashMap<String, u64>,
+}
+
+/// Timings collected while a request is handled.
+#[derive(Clone, Debug)]
+pub struct RequestTimings {
+    /// When the request entered the middleware chain.
+    pub start: Instant,
+    /// Database usage, updated by the connections handed out by `DB::from_request`.
+    pub db: Arc<Mutex<DbUsage>>,
+}
+
+impl LatencyBudget {
+    /// Creates a `LatencyBudget` from thresholds in milliseconds, keyed by endpoint class.
+    ///
+    /// Endpoint classes without a threshold fall back to the `default` threshold. If there is no
+    /// `default` threshold, requests in those classes are never logged.
+    pub fn new(thresholds: HashMap<String, u64>) -> Self {
+        LatencyBudget {
+            thresholds: thresholds,
+        }
+    }
+
+    /// Logs the request's timings, as a warning if it exceeded its budget.
+    fn check(&self, request: &Request, status: Option<Status>) {
+        let timings = match request.extensions.get::<RequestTimings>() {
+            Some(timings) => timings,
+            None => return,
+        };
+
+        let elapsed = millis(timings.start.elapsed());
+        let db = match timings.db.lock() {
+            Ok(db) => db.clone(),
+            Err(_) => return,
+        };
+
+        let relative_path = request.url.path().join("/");
+        let class = endpoint_class(&relative_path);
+
+        // Log the path without the query string, which may contain an access token.
+        let path = match request.extensions.get::<OriginalUrl>() {
+            Some(url) => url.path().join("/"),
+            None => relative_path.clone(),
+        };
+        let user_id = request.extensions.get::<User>()
+            .map(|user| user.id.to_string())
+            .unwrap_or("-".to_string());
+        let status = status.map(|status| status.to_string()).unwrap_or("-".to_string());
+        let summary = format!(
+            "{} /{} by {} returned {} in {}ms (database connections: {}, waiting: {}ms, held: {}ms)",
+            request.method,
+            path,
+            user_id,
+            status,
+            elapsed,
+            db.checkouts,
+            millis(db.wait),
+            millis(db.held)
+        );
+
+        match self.thresholds.get(class).or(self.thresholds.get("default")) {
+            Some(threshold) if elapsed > *threshold => {
+                warn!("Slow request over the {}ms {} budget: {}", threshold, class, summary);
+            }
+            _ => debug!("Request: {}", summary),
+        }
+    }
+}
+
+impl BeforeMiddleware for LatencyBudget {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        request.extensions.insert::<RequestTimings>(RequestTimings {
+            start: Instant::now(),
+            db: Arc::new(Mutex::new(DbUsage::default())),
+        });
+
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for LatencyBudget {
+    fn after(&self, request: &mut Request, response: Response) -> IronResult<Response> {
+        self.check(request, response.status);
+
+        Ok(response)
+    }
+
+    fn catch(&self, request: &mut Request, error: IronError) -> IronResult<Response> {
+        self.check(request, error.response.status);
+
+        Err(error)
+    }
+}
+
+impl Key for RequestTimings {
+    type Value = RequestTimings;
+}
+
+/// Determines the endpoint class of a path relative to its mount point.
+fn endpoint_class(path: &str) -> &'static str {
+    if path.starts_with("admin/") {
+        return "admin";
+    }
+
+    match path {
+        "account/deactivate" | "account/password" | "login" | "register" => "auth",
+        _ => "default",
+    }
+}
+
+/// Converts a `Duration` to whole milliseconds.
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000
+}
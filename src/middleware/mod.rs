@@ -4,17 +4,36 @@ use iron::Chain;
 mod authentication;
 mod cors;
 mod json;
+mod latency;
 mod path_params;
 
-pub use self::authentication::{AccessTokenAuth, UIAuth};
+pub use self::authentication::{
+    AccessTokenAuth,
+    AccessTokenOrAppServiceAuth,
+    AdminAuth,
+    AppServiceAuth,
+    LoginAuth,
+    NonGuestAuth,
+    UIAuth,
+};
 pub use self::cors::Cors;
 pub use self::json::JsonRequest;
+pub use self::latency::{DEFAULT_SLOW_REQUEST_THRESHOLDS, LatencyBudget, RequestTimings};
 pub use self::path_params::{
     DataTypeParam,
+    DeviceIdParam,
+    EventIdParam,
     EventTypeParam,
+    FilterIdParam,
+    MediaIdParam,
+    PushRuleIdParam,
+    PushRuleKindParam,
+    ReceiptTypeParam,
     UserIdParam,
     RoomIdParam,
+    RoomIdOrAliasParam,
     RoomAliasIdParam,
+    TagParam,
     TransactionIdParam,
 };
 
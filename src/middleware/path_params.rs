@@ -7,13 +7,17 @@ use iron::typemap::Key;
 use router::Router;
 use ruma_events::EventType;
 use ruma_identifiers::{
+    EventId,
     UserId,
     RoomAliasId,
     RoomId,
+    RoomIdOrAliasId,
 };
+use url::percent_encoding::percent_decode;
 
 use config::Config;
 use error::{ApiError, MapApiError};
+use push_rule::KINDS;
 
 /// Extracts a `RoomId` from the URL path parameter `room_id`.
 pub struct RoomIdParam;
@@ -38,6 +42,36 @@ impl BeforeMiddleware for RoomIdParam {
     }
 }
 
+/// Extracts a `RoomIdOrAliasId` from the URL path parameter `room_id_or_alias`.
+///
+/// Room aliases start with `#`, so clients have to percent-encode this parameter.
+pub struct RoomIdOrAliasParam;
+
+impl Key for RoomIdOrAliasParam {
+    type Value = RoomIdOrAliasId;
+}
+
+impl BeforeMiddleware for RoomIdOrAliasParam {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let params = request.extensions.get::<Router>().expect("Params object is missing").clone();
+
+        let room_id_or_alias = match params.find("room_id_or_alias") {
+            Some(room_id_or_alias) => {
+                let decoded = percent_decode(room_id_or_alias.as_bytes()).decode_utf8_lossy();
+
+                RoomIdOrAliasId::try_from(&decoded[..]).map_api_err(|err| {
+                    ApiError::invalid_param("room_id_or_alias", err.description())
+                })
+            }
+            None => Err(ApiError::missing_param("room_id_or_alias")),
+        }?;
+
+        request.extensions.insert::<RoomIdOrAliasParam>(room_id_or_alias);
+
+        Ok(())
+    }
+}
+
 /// Extracts a `UserId` from the URL path parameter `user_id`.
 pub struct UserIdParam;
 
@@ -126,6 +160,36 @@ impl BeforeMiddleware for RoomAliasIdParam {
     }
 }
 
+/// Extracts an `EventId` from the URL path parameter `event_id`.
+///
+/// Event IDs start with `$`, so clients may percent-encode this parameter.
+pub struct EventIdParam;
+
+impl Key for EventIdParam {
+    type Value = EventId;
+}
+
+impl BeforeMiddleware for EventIdParam {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let params = request.extensions.get::<Router>().expect("Params object is missing").clone();
+
+        let event_id = match params.find("event_id") {
+            Some(event_id) => {
+                let decoded = percent_decode(event_id.as_bytes()).decode_utf8_lossy();
+
+                EventId::try_from(&decoded[..]).map_api_err(|err| {
+                    ApiError::invalid_param("event_id", err.description())
+                })
+            }
+            None => Err(ApiError::missing_param("event_id")),
+        }?;
+
+        request.extensions.insert::<EventIdParam>(event_id);
+
+        Ok(())
+    }
+}
+
 /// Extracts `EventType` from the URL path paramater `event_type`.
 pub struct EventTypeParam;
 
@@ -149,6 +213,196 @@ impl BeforeMiddleware for EventTypeParam {
     }
 }
 
+/// Extracts the URL path paramater `device_id`.
+pub struct DeviceIdParam;
+
+impl Key for DeviceIdParam {
+    type Value = String;
+}
+
+impl BeforeMiddleware for DeviceIdParam {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let params = request.extensions.get::<Router>()
+            .expect("Params object is missing").clone();
+
+        let device_id = params.find("device_id")
+            .ok_or(ApiError::missing_param("device_id"))
+            .map_err(IronError::from)?;
+
+        request.extensions.insert::<DeviceIdParam>(device_id.to_string());
+
+        Ok(())
+    }
+}
+
+/// Extracts the URL path parameter `media_id`, after checking that the path parameter
+/// `server_name` is this server's domain. Media hosted on other servers is not found.
+pub struct MediaIdParam;
+
+impl Key for MediaIdParam {
+    type Value = String;
+}
+
+impl BeforeMiddleware for MediaIdParam {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let params = request.extensions.get::<Router>()
+            .expect("Params object is missing").clone();
+
+        let config = Config::from_request(request)?;
+
+        let server_name = params.find("server_name")
+            .ok_or(ApiError::missing_param("server_name"))
+            .map_err(IronError::from)?;
+
+        let media_id = params.find("media_id")
+            .ok_or(ApiError::missing_param("media_id"))
+            .map_err(IronError::from)?;
+
+        if server_name != config.domain {
+            let error = ApiError::not_found(
+                Some(&format!("Media from {} is not available on this server.", server_name))
+            );
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        request.extensions.insert::<MediaIdParam>(media_id.to_string());
+
+        Ok(())
+    }
+}
+
+/// Extracts the URL path paramater `filter_id`.
+pub struct FilterIdParam;
+
+impl Key for FilterIdParam {
+    type Value = String;
+}
+
+impl BeforeMiddleware for FilterIdParam {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let params = request.extensions.get::<Router>()
+            .expect("Params object is missing").clone();
+
+        let filter_id = params.find("filter_id")
+            .ok_or(ApiError::missing_param("filter_id"))
+            .map_err(IronError::from)?;
+
+        request.extensions.insert::<FilterIdParam>(filter_id.to_string());
+
+        Ok(())
+    }
+}
+
+/// Extracts the kind of push rule from the URL path parameter `kind`, after checking that the
+/// path parameter `scope` is *global*, the only scope.
+pub struct PushRuleKindParam;
+
+impl Key for PushRuleKindParam {
+    type Value = String;
+}
+
+impl BeforeMiddleware for PushRuleKindParam {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let params = request.extensions.get::<Router>()
+            .expect("Params object is missing").clone();
+
+        let scope = params.find("scope")
+            .ok_or(ApiError::missing_param("scope"))
+            .map_err(IronError::from)?;
+
+        if scope != "global" {
+            let error = ApiError::invalid_param("scope", "The only scope is global.");
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        let kind = params.find("kind")
+            .ok_or(ApiError::missing_param("kind"))
+            .map_err(IronError::from)?;
+
+        if !KINDS.contains(&kind) {
+            let error = ApiError::invalid_param("kind", "Not a kind of push rule.");
+
+            return Err(IronError::new(error.clone(), error));
+        }
+
+        request.extensions.insert::<PushRuleKindParam>(kind.to_string());
+
+        Ok(())
+    }
+}
+
+/// Extracts the URL path parameter `rule_id`.
+pub struct PushRuleIdParam;
+
+impl Key for PushRuleIdParam {
+    type Value = String;
+}
+
+impl BeforeMiddleware for PushRuleIdParam {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let params = request.extensions.get::<Router>()
+            .expect("Params object is missing").clone();
+
+        let rule_id = params.find("rule_id")
+            .ok_or(ApiError::missing_param("rule_id"))
+            .map_err(IronError::from)?;
+
+        let decoded = percent_decode(rule_id.as_bytes()).decode_utf8_lossy();
+
+        request.extensions.insert::<PushRuleIdParam>(decoded.to_string());
+
+        Ok(())
+    }
+}
+
+/// Extracts the URL path paramater `receipt_type`.
+pub struct ReceiptTypeParam;
+
+impl Key for ReceiptTypeParam {
+    type Value = String;
+}
+
+impl BeforeMiddleware for ReceiptTypeParam {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let params = request.extensions.get::<Router>()
+            .expect("Params object is missing").clone();
+
+        let receipt_type = params.find("receipt_type")
+            .ok_or(ApiError::missing_param("receipt_type"))
+            .map_err(IronError::from)?;
+
+        request.extensions.insert::<ReceiptTypeParam>(receipt_type.to_string());
+
+        Ok(())
+    }
+}
+
+/// Extracts the URL path parameter `tag`, percent-decoded, since tags may contain any character.
+pub struct TagParam;
+
+impl Key for TagParam {
+    type Value = String;
+}
+
+impl BeforeMiddleware for TagParam {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let params = request.extensions.get::<Router>()
+            .expect("Params object is missing").clone();
+
+        let tag = params.find("tag")
+            .ok_or(ApiError::missing_param("tag"))
+            .map_err(IronError::from)?;
+
+        let decoded = percent_decode(tag.as_bytes()).decode_utf8_lossy();
+
+        request.extensions.insert::<TagParam>(decoded.to_string());
+
+        Ok(())
+    }
+}
+
 /// Extracts the URL path paramater `transaction_id`.
 pub struct TransactionIdParam;
 
@@ -2,10 +2,14 @@
 
 use iron::Response;
 use iron::headers::ContentType;
+use iron::mime::Mime;
 use iron::modifier::Modifier;
+use iron::response::BodyReader;
 use serde::Serialize;
 use serde_json::to_string;
 
+use media::MediaContent;
+
 /// Set the response's Content-Type header to "application/json" and set its body to the `T`
 /// serialized to JSON.
 #[derive(Clone, Debug)]
@@ -17,3 +21,16 @@ impl<T> Modifier<Response> for SerializableResponse<T> where T: Serialize {
         response.body = Some(Box::new(to_string(&self.0).expect("could not serialize response data")));
     }
 }
+
+/// Set the response's Content-Type header to the content's MIME type and stream the content as its
+/// body.
+impl Modifier<Response> for MediaContent {
+    fn modify(self, response: &mut Response) {
+        let mime = self.content_type
+            .parse::<Mime>()
+            .unwrap_or_else(|_| "application/octet-stream".parse().expect("valid MIME type"));
+
+        response.headers.set(ContentType(mime));
+        response.body = Some(Box::new(BodyReader(self.reader)));
+    }
+}
@@ -0,0 +1,388 @@
+//! Authorization of room actions against the room's `m.room.power_levels` state.
+//!
+//! Every event sent to a room is checked against its power levels, so the `PowerLevelsCache`
+//! keeps each room's current power levels in memory. It is kept up to date the same way as the
+//! `MembershipCache`: power levels changed by this process evict their room straight away, and
+//! the `CatchUpPowerLevelsCache` job evicts rooms whose power levels were changed by any other
+//! process.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use diesel::{ExpressionMethods, FilterDsl, LoadDsl, SelectDsl};
+use diesel::pg::PgConnection;
+use iron::{Plugin, Request};
+use iron::typemap::Key;
+use persistent::Read;
+use ruma_events::EventType;
+use ruma_events::room::power_levels::PowerLevelsEventContent;
+use ruma_identifiers::{RoomId, UserId};
+
+use config::Config;
+use error::ApiError;
+use jobs::Job;
+use room::Room;
+use schema::events;
+use stream_position::{Stream, StreamPosition};
+
+/// The most rooms the cache holds power levels for before it is emptied and starts again.
+const MAX_ENTRIES: usize = 10_000;
+
+/// The power levels of a room, loaded once so that every check made while handling a request
+/// sees the same state without going back to the database.
+#[derive(Debug)]
+pub struct PowerLevels {
+    content: PowerLevelsEventContent,
+}
+
+/// Remembers the current power levels of each room.
+#[derive(Debug)]
+pub struct PowerLevelsCache {
+    state: Mutex<CacheState>,
+}
+
+/// The contents of a `PowerLevelsCache`.
+#[derive(Debug)]
+struct CacheState {
+    /// Incremented whenever an entry is evicted, so lookups can tell if they raced an eviction.
+    generation: u64,
+    /// The current power levels of each cached room.
+    rooms: HashMap<RoomId, PowerLevelsEventContent>,
+    /// The position in the event stream that evictions have caught up to, if they have run.
+    position: Option<i64>,
+}
+
+/// An Iron plugin for attaching a `PowerLevelsCache` to an Iron request.
+pub struct ServerPowerLevelsCache;
+
+/// A background job that evicts cache entries for power levels changed by other processes.
+#[derive(Debug)]
+pub struct CatchUpPowerLevelsCache {
+    power_levels_cache: Arc<PowerLevelsCache>,
+}
+
+impl PowerLevels {
+    /// Loads the room's current power levels, or the defaults from the specification if the room
+    /// has no power levels event.
+    pub fn load(connection: &PgConnection, room: &Room) -> Result<PowerLevels, ApiError> {
+        Ok(PowerLevels::from(room.current_power_levels(connection)?))
+    }
+
+    /// The power level of the given user.
+    pub fn user_level(&self, user_id: &UserId) -> u64 {
+        *self.content.users.get(user_id).unwrap_or(&self.content.users_default)
+    }
+
+    /// Whether or not the user may send an event of the given type.
+    ///
+    /// Event types without an explicit power level fall back to `state_default` for state events
+    /// and `events_default` for all other events.
+    pub fn can_send_event(&self, user_id: &UserId, event_type: &EventType, is_state: bool) -> bool {
+        let default_level = if is_state {
+            self.content.state_default
+        } else {
+            self.content.events_default
+        };
+        let required_level = *self.content.events.get(event_type).unwrap_or(&default_level);
+
+        self.user_level(user_id) >= required_level
+    }
+
+    /// Whether or not the user may invite others to the room.
+    pub fn can_invite(&self, user_id: &UserId) -> bool {
+        self.user_level(user_id) >= self.content.invite
+    }
+
+    /// Whether or not `sender` may kick `target` from the room.
+    pub fn can_kick(&self, sender: &UserId, target: &UserId) -> bool {
+        self.can_moderate(sender, target, self.content.kick)
+    }
+
+    /// Whether or not `sender` may ban or unban `target`.
+    pub fn can_ban(&self, sender: &UserId, target: &UserId) -> bool {
+        self.can_moderate(sender, target, self.content.ban)
+    }
+
+    /// Whether or not `sender` may redact an event sent by `event_sender`. Users can always
+    /// redact their own events.
+    pub fn can_redact(&self, sender: &UserId, event_sender: &UserId) -> bool {
+        sender == event_sender || self.user_level(sender) >= self.content.redact
+    }
+
+    /// Checks that `sender` may replace these power levels with `new_content`.
+    ///
+    /// Every level that changes must be no higher than the sender's own level, both before and
+    /// after the change, and the sender can't change the level of another user whose level is at
+    /// least as high as theirs.
+    pub fn ensure_can_change(&self, sender: &UserId, new_content: &PowerLevelsEventContent)
+    -> Result<(), ApiError> {
+        let sender_level = self.user_level(sender);
+        let old = &self.content;
+        let ensure_within_level = |old_level: Option<u64>, new_level: Option<u64>| {
+            if old_level == new_level {
+                return Ok(());
+            }
+
+            if old_level.unwrap_or(0) > sender_level || new_level.unwrap_or(0) > sender_level {
+                return Err(ApiError::unauthorized(
+                    Some("You can't change power levels above your own.")
+                ));
+            }
+
+            Ok(())
+        };
+
+        ensure_within_level(Some(old.ban), Some(new_content.ban))?;
+        ensure_within_level(Some(old.events_default), Some(new_content.events_default))?;
+        ensure_within_level(Some(old.invite), Some(new_content.invite))?;
+        ensure_within_level(Some(old.kick), Some(new_content.kick))?;
+        ensure_within_level(Some(old.redact), Some(new_content.redact))?;
+        ensure_within_level(Some(old.state_default), Some(new_content.state_default))?;
+        ensure_within_level(Some(old.users_default), Some(new_content.users_default))?;
+
+        for event_type in keys(&old.events, &new_content.events) {
+            ensure_within_level(
+                old.events.get(event_type).cloned(),
+                new_content.events.get(event_type).cloned(),
+            )?;
+        }
+
+        for user_id in keys(&old.users, &new_content.users) {
+            let old_level = old.users.get(user_id).cloned();
+            let new_level = new_content.users.get(user_id).cloned();
+
+            ensure_within_level(old_level, new_level)?;
+
+            if old_level != new_level && user_id != sender &&
+                old_level.unwrap_or(old.users_default) >= sender_level {
+                return Err(ApiError::unauthorized(
+                    Some("The user's power level is at least as high as yours.")
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Shared check for kicking and banning: the sender needs the required level, and a higher
+    /// level than the user they are acting on.
+    fn can_moderate(&self, sender: &UserId, target: &UserId, required_level: u64) -> bool {
+        let sender_level = self.user_level(sender);
+
+        sender_level >= required_level && sender_level > self.user_level(target)
+    }
+}
+
+impl From<PowerLevelsEventContent> for PowerLevels {
+    fn from(content: PowerLevelsEventContent) -> PowerLevels {
+        PowerLevels {
+            content: content,
+        }
+    }
+}
+
+impl PowerLevelsCache {
+    /// Creates an empty `PowerLevelsCache`.
+    pub fn new() -> Self {
+        PowerLevelsCache {
+            state: Mutex::new(CacheState {
+                generation: 0,
+                rooms: HashMap::new(),
+                position: None,
+            }),
+        }
+    }
+
+    /// Loads the room's current power levels, going to the database if they aren't cached.
+    pub fn load(&self, connection: &PgConnection, room: &Room) -> Result<PowerLevels, ApiError> {
+        let generation = {
+            let state = self.lock();
+
+            if let Some(content) = state.rooms.get(&room.id) {
+                return Ok(PowerLevels::from(content.clone()));
+            }
+
+            state.generation
+        };
+
+        let content = room.current_power_levels(connection)?;
+
+        {
+            let mut state = self.lock();
+
+            if state.generation == generation {
+                if state.rooms.len() >= MAX_ENTRIES {
+                    state.rooms.clear();
+                }
+
+                state.rooms.insert(room.id.clone(), content.clone());
+            }
+        }
+
+        Ok(PowerLevels::from(content))
+    }
+
+    /// Forgets the room's power levels. Must be called after the power levels change.
+    pub fn invalidate(&self, room_id: &RoomId) {
+        let mut state = self.lock();
+
+        state.generation += 1;
+        state.rooms.remove(room_id);
+    }
+
+    /// Evicts every room with a power levels event committed since the last time this ran.
+    ///
+    /// The first run has nothing to compare against, so it empties the cache instead.
+    pub fn catch_up(&self, connection: &PgConnection) -> Result<(), ApiError> {
+        let current = StreamPosition::current(connection, Stream::Events)?;
+        let position = self.lock().position;
+
+        let changed = match position {
+            Some(position) => {
+                let changed: Vec<RoomId> = events::table
+                    .select(events::room_id)
+                    .filter(events::event_type.eq(EventType::RoomPowerLevels.to_string()))
+                    .filter(events::ordering.gt(position))
+                    .filter(events::ordering.le(current))
+                    .load(connection)?;
+
+                Some(changed)
+            }
+            None => None,
+        };
+
+        let mut state = self.lock();
+
+        state.generation += 1;
+
+        match changed {
+            Some(changed) => {
+                for room_id in changed {
+                    state.rooms.remove(&room_id);
+                }
+            }
+            None => state.rooms.clear(),
+        }
+
+        state.position = Some(current);
+
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<CacheState> {
+        self.state.lock().expect("PowerLevelsCache mutex was poisoned")
+    }
+}
+
+impl ServerPowerLevelsCache {
+    /// Extract the `PowerLevelsCache` stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<Arc<PowerLevelsCache>>, ApiError> {
+        request.get::<Read<ServerPowerLevelsCache>>().map_err(ApiError::from)
+    }
+}
+
+impl Key for ServerPowerLevelsCache {
+    type Value = Arc<PowerLevelsCache>;
+}
+
+impl CatchUpPowerLevelsCache {
+    /// Creates a job that keeps the given cache up to date.
+    pub fn new(power_levels_cache: Arc<PowerLevelsCache>) -> Self {
+        CatchUpPowerLevelsCache {
+            power_levels_cache: power_levels_cache,
+        }
+    }
+}
+
+impl Job for CatchUpPowerLevelsCache {
+    fn name(&self) -> &'static str {
+        "catch_up_power_levels_cache"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn run(&self, connection: &PgConnection, _config: &Config, _now: i64) -> Result<(), ApiError> {
+        self.power_levels_cache.catch_up(connection)
+    }
+}
+
+/// The keys present in either of two maps.
+fn keys<'a, K, V>(old: &'a HashMap<K, V>, new: &'a HashMap<K, V>) -> HashSet<&'a K>
+where K: Eq + Hash {
+    old.keys().chain(new.keys()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+
+    use ruma_events::EventType;
+    use ruma_events::room::power_levels::PowerLevelsEventContent;
+    use ruma_identifiers::UserId;
+
+    use super::PowerLevels;
+
+    fn content() -> PowerLevelsEventContent {
+        let mut users = HashMap::new();
+
+        users.insert(UserId::try_from("@carl:ruma.test").unwrap(), 100);
+        users.insert(UserId::try_from("@mod:ruma.test").unwrap(), 50);
+
+        PowerLevelsEventContent {
+            ban: 50,
+            events: HashMap::new(),
+            events_default: 0,
+            invite: 0,
+            kick: 50,
+            redact: 50,
+            state_default: 50,
+            users: users,
+            users_default: 0,
+        }
+    }
+
+    #[test]
+    fn moderation_needs_a_higher_level_than_the_target() {
+        let power_levels = PowerLevels::from(content());
+        let carl = UserId::try_from("@carl:ruma.test").unwrap();
+        let moderator = UserId::try_from("@mod:ruma.test").unwrap();
+        let alice = UserId::try_from("@alice:ruma.test").unwrap();
+
+        assert!(power_levels.can_kick(&moderator, &alice));
+        assert!(power_levels.can_ban(&carl, &moderator));
+        assert!(!power_levels.can_ban(&moderator, &carl));
+        assert!(!power_levels.can_kick(&alice, &alice));
+        assert!(power_levels.can_redact(&alice, &alice));
+        assert!(!power_levels.can_redact(&alice, &moderator));
+        assert!(power_levels.can_send_event(&alice, &EventType::RoomMessage, false));
+        assert!(!power_levels.can_send_event(&alice, &EventType::RoomName, true));
+    }
+
+    #[test]
+    fn power_levels_cannot_be_raised_above_your_own() {
+        let power_levels = PowerLevels::from(content());
+        let carl = UserId::try_from("@carl:ruma.test").unwrap();
+        let moderator = UserId::try_from("@mod:ruma.test").unwrap();
+
+        let mut promote_self = content();
+        promote_self.users.insert(moderator.clone(), 100);
+
+        assert!(power_levels.ensure_can_change(&moderator, &promote_self).is_err());
+        assert!(power_levels.ensure_can_change(&carl, &promote_self).is_ok());
+
+        let mut demote_owner = content();
+        demote_owner.users.insert(carl.clone(), 0);
+
+        assert!(power_levels.ensure_can_change(&moderator, &demote_owner).is_err());
+
+        let mut lower_kick = content();
+        lower_kick.kick = 25;
+
+        assert!(power_levels.ensure_can_change(&moderator, &lower_kick).is_ok());
+    }
+}
@@ -0,0 +1,181 @@
+//! User presence.
+
+use std::collections::BTreeMap;
+
+use diesel::{
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LoadDsl,
+    OrderDsl,
+    SelectDsl,
+    delete,
+    insert,
+    update,
+};
+use diesel::expression::dsl::count_star;
+use diesel::pg::PgConnection;
+use diesel::pg::expression::dsl::any;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::UserId;
+use serde_json::Value;
+
+use error::ApiError;
+use schema::{presence, room_memberships};
+
+/// The presence states a user can set.
+pub const PRESENCE_STATES: &'static [&'static str] = &["offline", "online", "unavailable"];
+
+/// How long after their last activity an online user is still shown as currently active, in
+/// milliseconds.
+const CURRENTLY_ACTIVE_MILLIS: i64 = 5 * 60 * 1000;
+
+/// A user's presence.
+#[derive(Clone, Debug, Queryable)]
+pub struct Presence {
+    /// The user the presence belongs to.
+    pub user_id: UserId,
+    /// One of `PRESENCE_STATES`.
+    pub state: String,
+    /// A message set by the user, e.g. "At lunch".
+    pub status_msg: Option<String>,
+    /// When the user was last active, in milliseconds since the Unix epoch.
+    pub last_active_at: i64,
+    /// The position of the last change in the events stream.
+    pub ordering: i64,
+}
+
+/// A new presence for a user, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "presence"]
+pub struct NewPresence {
+    /// The user the presence belongs to.
+    pub user_id: UserId,
+    /// One of `PRESENCE_STATES`.
+    pub state: String,
+    /// A message set by the user, e.g. "At lunch".
+    pub status_msg: Option<String>,
+    /// When the user was last active, in milliseconds since the Unix epoch.
+    pub last_active_at: i64,
+}
+
+impl Presence {
+    /// Returns the user's presence, if they ever set one.
+    pub fn find(connection: &PgConnection, user_id: &UserId) -> Result<Option<Presence>, ApiError> {
+        match presence::table.find(user_id.to_string()).first(connection) {
+            Ok(presence) => Ok(Some(presence)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// Saves the user's presence, replacing any previous one.
+    ///
+    /// The replacement gets a new position in the events stream so that `/sync` picks it up.
+    pub fn upsert(connection: &PgConnection, new_presence: &NewPresence)
+    -> Result<Presence, ApiError> {
+        connection.transaction::<Presence, ApiError, _>(|| {
+            delete(presence::table.find(new_presence.user_id.to_string())).execute(connection)?;
+
+            insert(new_presence)
+                .into(presence::table)
+                .get_result(connection)
+                .map_err(ApiError::from)
+        }).map_err(ApiError::from)
+    }
+
+    /// Records that the user was active at `now`.
+    ///
+    /// This doesn't count as a change for `/sync`, since clients work out how long ago a user was
+    /// active whenever they get a presence event.
+    pub fn touch(connection: &PgConnection, user_id: &UserId, now: i64) -> Result<(), ApiError> {
+        update(presence::table.find(user_id.to_string()))
+            .set(presence::last_active_at.eq(now))
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+
+    /// Returns the presence changes after the position `after` up to the position `up_to` of the
+    /// user and everyone who shares a room with them.
+    pub fn find_visible(
+        connection: &PgConnection,
+        user_id: &UserId,
+        after: Option<i64>,
+        up_to: i64,
+    ) -> Result<Vec<Presence>, ApiError> {
+        let room_ids = room_memberships::table
+            .filter(room_memberships::user_id.eq(user_id))
+            .filter(room_memberships::membership.eq("join"))
+            .select(room_memberships::room_id);
+
+        let mut user_ids: Vec<String> = room_memberships::table
+            .filter(room_memberships::room_id.eq(any(room_ids)))
+            .filter(room_memberships::membership.eq("join"))
+            .select(room_memberships::user_id)
+            .load(connection)?;
+
+        user_ids.push(user_id.to_string());
+        user_ids.sort();
+        user_ids.dedup();
+
+        presence::table
+            .filter(presence::user_id.eq(any(user_ids)))
+            .filter(presence::ordering.gt(after.unwrap_or(0)))
+            .filter(presence::ordering.le(up_to))
+            .order(presence::ordering.asc())
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Whether or not `user_id` may see the presence of `other_user_id`, which requires them to be
+    /// the same user or to share a room.
+    pub fn is_visible_to(connection: &PgConnection, user_id: &UserId, other_user_id: &UserId)
+    -> Result<bool, ApiError> {
+        if user_id == other_user_id {
+            return Ok(true);
+        }
+
+        let room_ids = room_memberships::table
+            .filter(room_memberships::user_id.eq(user_id))
+            .filter(room_memberships::membership.eq("join"))
+            .select(room_memberships::room_id);
+
+        let shared_rooms: i64 = room_memberships::table
+            .filter(room_memberships::room_id.eq(any(room_ids)))
+            .filter(room_memberships::user_id.eq(other_user_id))
+            .filter(room_memberships::membership.eq("join"))
+            .select(count_star())
+            .first(connection)?;
+
+        Ok(shared_rooms > 0)
+    }
+
+    /// Whether or not the user is online and was active recently.
+    pub fn currently_active(&self, now: i64) -> bool {
+        self.state == "online" && now - self.last_active_at < CURRENTLY_ACTIVE_MILLIS
+    }
+
+    /// Formats the presence as an *m.presence* event.
+    pub fn to_event(&self, now: i64) -> Value {
+        let mut content = BTreeMap::new();
+
+        content.insert("currently_active".to_string(), Value::Bool(self.currently_active(now)));
+        content.insert("last_active_ago".to_string(), Value::I64(now - self.last_active_at));
+        content.insert("presence".to_string(), Value::String(self.state.clone()));
+
+        if let Some(ref status_msg) = self.status_msg {
+            content.insert("status_msg".to_string(), Value::String(status_msg.clone()));
+        }
+
+        let mut event = BTreeMap::new();
+
+        event.insert("content".to_string(), Value::Object(content));
+        event.insert("sender".to_string(), Value::String(self.user_id.to_string()));
+        event.insert("type".to_string(), Value::String("m.presence".to_string()));
+
+        Value::Object(event)
+    }
+}
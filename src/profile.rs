@@ -2,12 +2,14 @@
 
 use diesel::{
     ExpressionMethods,
+    FilterDsl,
     LoadDsl,
     FindDsl,
     SaveChangesDsl,
     insert,
 };
 use diesel::pg::PgConnection;
+use diesel::pg::expression::dsl::any;
 use diesel::result::Error as DieselError;
 use ruma_identifiers::UserId;
 
@@ -88,12 +90,20 @@ impl Profile {
         }
     }
 
-    /// Update `RoomMembership`'s due to changed `Profile`.
+    /// Sends a new *m.room.member* event with the user's current profile to every room they have
+    /// joined, so the other members see their new display name and avatar.
+    ///
+    /// Rooms the user was invited to, left, or was banned from are left alone, since a member
+    /// event with their profile would join them to the room.
     pub fn update_memberships(connection: &PgConnection, homeserver_domain: &str, user_id: UserId)
     -> Result<(), ApiError> {
         let mut room_memberships = RoomMembership::find_by_uid(connection, user_id.clone())?;
 
         for room_membership in room_memberships.iter_mut() {
+            if room_membership.membership != "join" {
+                continue;
+            }
+
             let options = RoomMembershipOptions {
                 room_id: room_membership.room_id.clone(),
                 user_id: user_id.clone(),
@@ -127,4 +137,15 @@ impl Profile {
             Err(err) => Err(ApiError::from(err)),
         }
     }
+
+    /// Return the `Profile`s that exist for the given `UserId`s.
+    pub fn find_by_uids(connection: &PgConnection, user_ids: &[UserId])
+    -> Result<Vec<Profile>, ApiError> {
+        let user_ids: Vec<String> = user_ids.iter().map(UserId::to_string).collect();
+
+        profiles::table
+            .filter(profiles::id.eq(any(user_ids)))
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
 }
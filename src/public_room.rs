@@ -0,0 +1,179 @@
+//! Summaries of the rooms listed in the public room directory.
+
+use diesel::{ExpressionMethods, FilterDsl, LimitDsl, LoadDsl, OffsetDsl, OrderDsl, SelectDsl};
+use diesel::expression::dsl::count_star;
+use diesel::pg::PgConnection;
+use diesel::types::{BigInt, Integer, Text};
+use ruma_events::EventType;
+use ruma_identifiers::RoomId;
+use serde_json::{Value, from_str};
+
+use error::ApiError;
+use event::Event;
+use room_alias::RoomAlias;
+use schema::rooms;
+
+sql_function!(
+    public_room_joined_members,
+    public_room_joined_members_t,
+    (room_id: Text) -> BigInt
+);
+sql_function!(
+    public_room_rank,
+    public_room_rank_t,
+    (room_id: Text, search_term: Text) -> Integer
+);
+
+/// A room as it appears in the public room directory.
+#[derive(Clone, Debug, Serialize)]
+pub struct PublicRoom {
+    /// The room's aliases.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    /// The URL of the room's avatar.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    /// The room's canonical alias.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canonical_alias: Option<String>,
+    /// Whether or not guests can join the room.
+    pub guest_can_join: bool,
+    /// The room's name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The number of members joined to the room.
+    pub num_joined_members: u64,
+    /// The room's ID.
+    pub room_id: String,
+    /// The room's topic.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// Whether or not the room's history can be read without joining.
+    pub world_readable: bool,
+}
+
+impl PublicRoom {
+    /// Returns one page of public rooms, starting `offset` rooms in, along with the number of
+    /// rooms on every page.
+    ///
+    /// Without a search term, rooms are ordered largest first. With one, only rooms whose name,
+    /// topic, or aliases contain the term are returned, ordered by how well they match: rooms with
+    /// the term as an alias come first, then rooms whose name starts with it, then the rest.
+    /// Rooms that match equally well are ordered largest first. Rooms that also have the same
+    /// number of members are ordered by ID so pages stay stable.
+    pub fn find_page(
+        connection: &PgConnection,
+        search_term: Option<&str>,
+        offset: i64,
+        limit: i64,
+    ) -> Result<(Vec<PublicRoom>, u64), ApiError> {
+        let (rooms, total): (Vec<(RoomId, i64)>, i64) = match search_term {
+            Some(search_term) => {
+                let rooms = rooms::table
+                    .filter(rooms::public.eq(true))
+                    .filter(public_room_rank(rooms::id, search_term).gt(0))
+                    .select((rooms::id, public_room_joined_members(rooms::id)))
+                    .order((
+                        public_room_rank(rooms::id, search_term).desc(),
+                        public_room_joined_members(rooms::id).desc(),
+                        rooms::id.asc(),
+                    ))
+                    .offset(offset)
+                    .limit(limit)
+                    .load(connection)?;
+                let total = rooms::table
+                    .filter(rooms::public.eq(true))
+                    .filter(public_room_rank(rooms::id, search_term).gt(0))
+                    .select(count_star())
+                    .first(connection)?;
+
+                (rooms, total)
+            }
+            None => {
+                let rooms = rooms::table
+                    .filter(rooms::public.eq(true))
+                    .select((rooms::id, public_room_joined_members(rooms::id)))
+                    .order((public_room_joined_members(rooms::id).desc(), rooms::id.asc()))
+                    .offset(offset)
+                    .limit(limit)
+                    .load(connection)?;
+                let total = rooms::table
+                    .filter(rooms::public.eq(true))
+                    .select(count_star())
+                    .first(connection)?;
+
+                (rooms, total)
+            }
+        };
+
+        let mut public_rooms = Vec::with_capacity(rooms.len());
+
+        for (room_id, num_joined_members) in rooms {
+            public_rooms.push(
+                PublicRoom::summarize(connection, &room_id, num_joined_members as u64)?
+            );
+        }
+
+        Ok((public_rooms, total as u64))
+    }
+
+    /// Builds the summary of a room from its current state.
+    fn summarize(connection: &PgConnection, room_id: &RoomId, num_joined_members: u64)
+    -> Result<PublicRoom, ApiError> {
+        let aliases = RoomAlias::find_by_room_id(connection, room_id)?
+            .into_iter()
+            .map(|room_alias| room_alias.alias.to_string())
+            .collect();
+
+        let name = current_state_field(connection, room_id, EventType::RoomName, "name")?;
+        let topic = current_state_field(connection, room_id, EventType::RoomTopic, "topic")?;
+        let avatar_url = current_state_field(connection, room_id, EventType::RoomAvatar, "url")?;
+        let canonical_alias =
+            current_state_field(connection, room_id, EventType::RoomCanonicalAlias, "alias")?;
+        let history_visibility = current_state_field(
+            connection,
+            room_id,
+            EventType::RoomHistoryVisibility,
+            "history_visibility",
+        )?;
+        let guest_access =
+            current_state_field(connection, room_id, EventType::RoomGuestAccess, "guest_access")?;
+
+        Ok(PublicRoom {
+            aliases: aliases,
+            avatar_url: avatar_url,
+            canonical_alias: canonical_alias,
+            guest_can_join: guest_access.as_ref().map_or(false, |access| access == "can_join"),
+            name: name,
+            num_joined_members: num_joined_members,
+            room_id: room_id.to_string(),
+            topic: topic,
+            world_readable: history_visibility
+                .as_ref()
+                .map_or(false, |visibility| visibility == "world_readable"),
+        })
+    }
+}
+
+/// Returns a string field from the content of the room's current state event of the given type,
+/// if the event exists and has a non-empty value for the field.
+fn current_state_field(
+    connection: &PgConnection,
+    room_id: &RoomId,
+    event_type: EventType,
+    field: &str,
+) -> Result<Option<String>, ApiError> {
+    let event = Event::find_state_event(connection, room_id, &event_type, "", i64::max_value())?;
+
+    let event = match event {
+        Some(event) => event,
+        None => return Ok(None),
+    };
+
+    let content: Value = from_str(&event.content)?;
+
+    match content.find(field).and_then(Value::as_str) {
+        Some(value) if !value.is_empty() => Ok(Some(value.to_string())),
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,1065 @@
+//! Push rules, which decide which events a user is notified about.
+//!
+//! Users only store the rules they add. The server's default rules are built for each user when
+//! their rules are loaded, with any changes the user made to whether they're enabled or to their
+//! actions applied on top.
+
+use std::collections::HashMap;
+
+use diesel::{
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    LoadDsl,
+    OrderDsl,
+    SelectDsl,
+    delete,
+    insert,
+    update,
+};
+use diesel::pg::PgConnection;
+use ruma_events::EventType;
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::{Value, from_str, to_string};
+
+use error::ApiError;
+use event::Event;
+use power_levels::PowerLevels;
+use receipt::{READ_RECEIPT, Receipt};
+use room::Room;
+use room_membership::RoomMembership;
+use schema::{default_push_rule_settings, push_rules};
+
+/// The kinds of push rules, in the order they're tried.
+pub const KINDS: &'static [&'static str] = &["override", "content", "room", "sender", "underride"];
+
+/// The default rule that turns off all notifications when enabled, which is always tried first.
+const MASTER_RULE_ID: &'static str = ".m.rule.master";
+
+/// The most unread events per room checked against a user's rules when counting notifications.
+const MAX_UNREAD_EVENTS: i64 = 1000;
+
+/// The power level needed to notify a room when the power levels don't say, per the spec.
+const DEFAULT_NOTIFICATION_LEVEL: u64 = 50;
+
+/// One of the server's default rules.
+///
+/// `$user_id` and `$localpart` in the conditions and pattern are replaced with the user's ID and
+/// localpart when the rules are loaded.
+struct DefaultRule {
+    kind: &'static str,
+    rule_id: &'static str,
+    enabled: bool,
+    conditions: &'static str,
+    pattern: Option<&'static str>,
+    actions: &'static str,
+}
+
+/// The server's default rules, in the order they're tried within each kind.
+const DEFAULT_RULES: &'static [DefaultRule] = &[
+    DefaultRule {
+        kind: "override",
+        rule_id: MASTER_RULE_ID,
+        enabled: false,
+        conditions: "[]",
+        pattern: None,
+        actions: r#"["dont_notify"]"#,
+    },
+    DefaultRule {
+        kind: "override",
+        rule_id: ".m.rule.suppress_notices",
+        enabled: true,
+        conditions: r#"[{"kind": "event_match", "key": "content.msgtype", "pattern": "m.notice"}]"#,
+        pattern: None,
+        actions: r#"["dont_notify"]"#,
+    },
+    DefaultRule {
+        kind: "override",
+        rule_id: ".m.rule.invite_for_me",
+        enabled: true,
+        conditions: r#"[
+            {"kind": "event_match", "key": "type", "pattern": "m.room.member"},
+            {"kind": "event_match", "key": "content.membership", "pattern": "invite"},
+            {"kind": "event_match", "key": "state_key", "pattern": "$user_id"}
+        ]"#,
+        pattern: None,
+        actions: r#"[
+            "notify",
+            {"set_tweak": "sound", "value": "default"},
+            {"set_tweak": "highlight", "value": false}
+        ]"#,
+    },
+    DefaultRule {
+        kind: "override",
+        rule_id: ".m.rule.member_event",
+        enabled: true,
+        conditions: r#"[{"kind": "event_match", "key": "type", "pattern": "m.room.member"}]"#,
+        pattern: None,
+        actions: r#"["dont_notify"]"#,
+    },
+    DefaultRule {
+        kind: "override",
+        rule_id: ".m.rule.contains_display_name",
+        enabled: true,
+        conditions: r#"[{"kind": "contains_display_name"}]"#,
+        pattern: None,
+        actions: r#"[
+            "notify",
+            {"set_tweak": "sound", "value": "default"},
+            {"set_tweak": "highlight"}
+        ]"#,
+    },
+    DefaultRule {
+        kind: "override",
+        rule_id: ".m.rule.tombstone",
+        enabled: true,
+        conditions: r#"[
+            {"kind": "event_match", "key": "type", "pattern": "m.room.tombstone"},
+            {"kind": "event_match", "key": "state_key", "pattern": ""}
+        ]"#,
+        pattern: None,
+        actions: r#"["notify", {"set_tweak": "highlight"}]"#,
+    },
+    DefaultRule {
+        kind: "override",
+        rule_id: ".m.rule.roomnotif",
+        enabled: true,
+        conditions: r#"[
+            {"kind": "event_match", "key": "content.body", "pattern": "@room"},
+            {"kind": "sender_notification_permission", "key": "room"}
+        ]"#,
+        pattern: None,
+        actions: r#"["notify", {"set_tweak": "highlight"}]"#,
+    },
+    DefaultRule {
+        kind: "content",
+        rule_id: ".m.rule.contains_user_name",
+        enabled: true,
+        conditions: "[]",
+        pattern: Some("$localpart"),
+        actions: r#"[
+            "notify",
+            {"set_tweak": "sound", "value": "default"},
+            {"set_tweak": "highlight"}
+        ]"#,
+    },
+    DefaultRule {
+        kind: "underride",
+        rule_id: ".m.rule.call",
+        enabled: true,
+        conditions: r#"[{"kind": "event_match", "key": "type", "pattern": "m.call.invite"}]"#,
+        pattern: None,
+        actions: r#"[
+            "notify",
+            {"set_tweak": "sound", "value": "ring"},
+            {"set_tweak": "highlight", "value": false}
+        ]"#,
+    },
+    DefaultRule {
+        kind: "underride",
+        rule_id: ".m.rule.encrypted_room_one_to_one",
+        enabled: true,
+        conditions: r#"[
+            {"kind": "room_member_count", "is": "2"},
+            {"kind": "event_match", "key": "type", "pattern": "m.room.encrypted"}
+        ]"#,
+        pattern: None,
+        actions: r#"["notify", {"set_tweak": "highlight", "value": false}]"#,
+    },
+    DefaultRule {
+        kind: "underride",
+        rule_id: ".m.rule.room_one_to_one",
+        enabled: true,
+        conditions: r#"[
+            {"kind": "room_member_count", "is": "2"},
+            {"kind": "event_match", "key": "type", "pattern": "m.room.message"}
+        ]"#,
+        pattern: None,
+        actions: r#"[
+            "notify",
+            {"set_tweak": "sound", "value": "default"},
+            {"set_tweak": "highlight", "value": false}
+        ]"#,
+    },
+    DefaultRule {
+        kind: "underride",
+        rule_id: ".m.rule.message",
+        enabled: true,
+        conditions: r#"[{"kind": "event_match", "key": "type", "pattern": "m.room.message"}]"#,
+        pattern: None,
+        actions: r#"["notify", {"set_tweak": "highlight", "value": false}]"#,
+    },
+    DefaultRule {
+        kind: "underride",
+        rule_id: ".m.rule.encrypted",
+        enabled: true,
+        conditions: r#"[{"kind": "event_match", "key": "type", "pattern": "m.room.encrypted"}]"#,
+        pattern: None,
+        actions: r#"["notify", {"set_tweak": "highlight", "value": false}]"#,
+    },
+];
+
+/// A push rule a user added.
+#[derive(Debug, Identifiable, Queryable)]
+#[table_name = "push_rules"]
+pub struct PushRule {
+    /// Entry ID.
+    pub id: i64,
+    /// The user the rule belongs to.
+    pub user_id: UserId,
+    /// The kind of the rule, e.g. *override*.
+    pub kind: String,
+    /// The ID of the rule, which for room and sender rules is the room or user it applies to.
+    pub rule_id: String,
+    /// Where the rule is tried among the user's rules of the same kind, lowest first.
+    pub position: i64,
+    /// JSON of the conditions of an override or underride rule.
+    pub conditions: String,
+    /// The glob pattern a content rule matches message bodies against.
+    pub pattern: Option<String>,
+    /// JSON of the actions to take when the rule matches.
+    pub actions: String,
+    /// Whether or not the rule is tried.
+    pub enabled: bool,
+}
+
+/// A new push rule, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "push_rules"]
+pub struct NewPushRule {
+    /// The user the rule belongs to.
+    pub user_id: UserId,
+    /// The kind of the rule, e.g. *override*.
+    pub kind: String,
+    /// The ID of the rule, which for room and sender rules is the room or user it applies to.
+    pub rule_id: String,
+    /// Where the rule is tried among the user's rules of the same kind, lowest first.
+    pub position: i64,
+    /// JSON of the conditions of an override or underride rule.
+    pub conditions: String,
+    /// The glob pattern a content rule matches message bodies against.
+    pub pattern: Option<String>,
+    /// JSON of the actions to take when the rule matches.
+    pub actions: String,
+}
+
+/// A user's changes to one of the default rules.
+#[derive(Debug, Insertable, Queryable)]
+#[table_name = "default_push_rule_settings"]
+struct DefaultPushRuleSetting {
+    user_id: UserId,
+    rule_id: String,
+    enabled: bool,
+    actions: String,
+}
+
+/// A push rule in the format sent to clients.
+#[derive(Clone, Debug, Serialize)]
+pub struct Rule {
+    /// The actions to take when the rule matches.
+    pub actions: Vec<Value>,
+    /// The conditions of an override or underride rule, all of which must hold.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conditions: Option<Vec<Value>>,
+    /// Whether or not the rule is one of the server's default rules.
+    #[serde(rename = "default")]
+    pub is_default: bool,
+    /// Whether or not the rule is tried.
+    pub enabled: bool,
+    /// The glob pattern of a content rule.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    /// The ID of the rule.
+    pub rule_id: String,
+}
+
+/// All of a user's push rules, by kind, in the order they're tried.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Ruleset {
+    /// Rules tried before all others.
+    #[serde(rename = "override")]
+    pub override_rules: Vec<Rule>,
+    /// Rules matching message bodies against a pattern.
+    pub content: Vec<Rule>,
+    /// Rules for every event in a room.
+    pub room: Vec<Rule>,
+    /// Rules for every event from a user.
+    pub sender: Vec<Rule>,
+    /// Rules tried after all others.
+    pub underride: Vec<Rule>,
+}
+
+/// What the room an event was sent in looks like to the user whose rules are evaluated.
+#[derive(Debug)]
+pub struct NotificationContext {
+    display_name: Option<String>,
+    member_count: u64,
+    notification_levels: HashMap<String, u64>,
+    power_levels: PowerLevels,
+}
+
+/// Whether an event should notify the user, and how.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Notification {
+    /// The actions of the rule that matched, as sent to clients.
+    pub actions: Vec<Value>,
+    /// Whether or not the user should be notified.
+    pub notify: bool,
+    /// Whether or not the notification should be highlighted.
+    pub highlight: bool,
+    /// The tweaks set by the matching rule, such as the sound to play.
+    pub tweaks: HashMap<String, Value>,
+}
+
+/// How many unread events in a room a user should be notified about.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct NotificationCounts {
+    /// How many of the notifications are highlighted.
+    pub highlight_count: u64,
+    /// How many unread events notify the user.
+    pub notification_count: u64,
+}
+
+impl PushRule {
+    /// Saves a rule, replacing the user's rule of the same kind and ID if there is one.
+    ///
+    /// The rule is placed just before or just after the user's rule with the ID `before` or
+    /// `after`. Otherwise a rule that already existed keeps its place, and a new one is tried
+    /// before the user's other rules of the same kind.
+    pub fn put(
+        connection: &PgConnection,
+        new_rule: &NewPushRule,
+        before: Option<&str>,
+        after: Option<&str>,
+    ) -> Result<(), ApiError> {
+        connection.transaction::<(), ApiError, _>(|| {
+            let mut rule_ids: Vec<String> = push_rules::table
+                .filter(push_rules::user_id.eq(&new_rule.user_id))
+                .filter(push_rules::kind.eq(&new_rule.kind))
+                .order(push_rules::position.asc())
+                .select(push_rules::rule_id)
+                .load(connection)?;
+
+            let previous_index = rule_ids.iter().position(|rule_id| *rule_id == new_rule.rule_id);
+
+            if let Some(index) = previous_index {
+                rule_ids.remove(index);
+            }
+
+            let index = match (before, after) {
+                (Some(before), _) => position_of(&rule_ids, "before", before)?,
+                (None, Some(after)) => position_of(&rule_ids, "after", after)? + 1,
+                (None, None) => previous_index.unwrap_or(0),
+            };
+
+            rule_ids.insert(index, new_rule.rule_id.clone());
+
+            let previous = push_rules::table
+                .filter(push_rules::user_id.eq(&new_rule.user_id))
+                .filter(push_rules::kind.eq(&new_rule.kind))
+                .filter(push_rules::rule_id.eq(&new_rule.rule_id));
+
+            delete(previous).execute(connection)?;
+
+            insert(new_rule).into(push_rules::table).execute(connection)?;
+
+            for (position, rule_id) in rule_ids.iter().enumerate() {
+                let rule = push_rules::table
+                    .filter(push_rules::user_id.eq(&new_rule.user_id))
+                    .filter(push_rules::kind.eq(&new_rule.kind))
+                    .filter(push_rules::rule_id.eq(rule_id));
+
+                update(rule)
+                    .set(push_rules::position.eq(position as i64))
+                    .execute(connection)?;
+            }
+
+            Ok(())
+        }).map_err(ApiError::from)
+    }
+
+    /// Deletes one of the user's rules.
+    pub fn delete(connection: &PgConnection, user_id: &UserId, kind: &str, rule_id: &str)
+    -> Result<(), ApiError> {
+        let rule = push_rules::table
+            .filter(push_rules::user_id.eq(user_id))
+            .filter(push_rules::kind.eq(kind))
+            .filter(push_rules::rule_id.eq(rule_id));
+
+        match delete(rule).execute(connection)? {
+            0 => Err(ApiError::not_found(Some("No push rule with that ID exists."))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Turns one of the user's rules, or one of the default rules, on or off.
+    pub fn set_enabled(
+        connection: &PgConnection,
+        user_id: &UserId,
+        kind: &str,
+        rule_id: &str,
+        enabled: bool,
+    ) -> Result<(), ApiError> {
+        let ruleset = Ruleset::load(connection, user_id)?;
+        let rule = ruleset.find(kind, rule_id)
+            .ok_or(ApiError::not_found(Some("No push rule with that ID exists.")))?;
+
+        PushRule::change(connection, user_id, kind, rule, enabled, &rule.actions)
+    }
+
+    /// Changes the actions of one of the user's rules, or of one of the default rules.
+    pub fn set_actions(
+        connection: &PgConnection,
+        user_id: &UserId,
+        kind: &str,
+        rule_id: &str,
+        actions: &[Value],
+    ) -> Result<(), ApiError> {
+        let ruleset = Ruleset::load(connection, user_id)?;
+        let rule = ruleset.find(kind, rule_id)
+            .ok_or(ApiError::not_found(Some("No push rule with that ID exists.")))?;
+
+        PushRule::change(connection, user_id, kind, rule, rule.enabled, actions)
+    }
+
+    /// Saves whether a rule is enabled and its actions, recording changes to a default rule
+    /// separately since the default rules themselves aren't stored.
+    fn change(
+        connection: &PgConnection,
+        user_id: &UserId,
+        kind: &str,
+        rule: &Rule,
+        enabled: bool,
+        actions: &[Value],
+    ) -> Result<(), ApiError> {
+        let actions = to_string(actions).map_err(ApiError::from)?;
+
+        if !rule.is_default {
+            let saved = push_rules::table
+                .filter(push_rules::user_id.eq(user_id))
+                .filter(push_rules::kind.eq(kind))
+                .filter(push_rules::rule_id.eq(&rule.rule_id));
+
+            return update(saved)
+                .set((push_rules::enabled.eq(enabled), push_rules::actions.eq(actions)))
+                .execute(connection)
+                .map(|_| ())
+                .map_err(ApiError::from);
+        }
+
+        let setting = DefaultPushRuleSetting {
+            user_id: user_id.clone(),
+            rule_id: rule.rule_id.clone(),
+            enabled: enabled,
+            actions: actions,
+        };
+
+        connection.transaction::<(), ApiError, _>(|| {
+            let previous = default_push_rule_settings::table
+                .filter(default_push_rule_settings::user_id.eq(user_id))
+                .filter(default_push_rule_settings::rule_id.eq(&rule.rule_id));
+
+            delete(previous).execute(connection)?;
+
+            insert(&setting).into(default_push_rule_settings::table).execute(connection)?;
+
+            Ok(())
+        }).map_err(ApiError::from)
+    }
+
+    /// Converts the saved rule into the format sent to clients.
+    fn to_rule(&self) -> Result<Rule, ApiError> {
+        let conditions = match &self.kind[..] {
+            "override" | "underride" => Some(from_str(&self.conditions).map_err(ApiError::from)?),
+            _ => None,
+        };
+
+        Ok(Rule {
+            actions: from_str(&self.actions).map_err(ApiError::from)?,
+            conditions: conditions,
+            is_default: false,
+            enabled: self.enabled,
+            pattern: self.pattern.clone(),
+            rule_id: self.rule_id.clone(),
+        })
+    }
+}
+
+impl Ruleset {
+    /// Loads the user's rules, merged with the default rules.
+    ///
+    /// The master rule comes before all of the user's override rules, and the other default rules
+    /// of each kind come after the user's.
+    pub fn load(connection: &PgConnection, user_id: &UserId) -> Result<Ruleset, ApiError> {
+        let saved: Vec<PushRule> = push_rules::table
+            .filter(push_rules::user_id.eq(user_id))
+            .order(push_rules::position.asc())
+            .load(connection)?;
+
+        let settings: Vec<DefaultPushRuleSetting> = default_push_rule_settings::table
+            .filter(default_push_rule_settings::user_id.eq(user_id))
+            .load(connection)?;
+
+        let mut ruleset = Ruleset::default();
+
+        for default_rule in DEFAULT_RULES.iter().filter(|rule| rule.rule_id == MASTER_RULE_ID) {
+            ruleset.override_rules.push(default_rule.to_rule(user_id, &settings)?);
+        }
+
+        for rule in &saved {
+            if let Some(rules) = ruleset.kind_mut(&rule.kind) {
+                rules.push(rule.to_rule()?);
+            }
+        }
+
+        for default_rule in DEFAULT_RULES.iter().filter(|rule| rule.rule_id != MASTER_RULE_ID) {
+            let rule = default_rule.to_rule(user_id, &settings)?;
+
+            if let Some(rules) = ruleset.kind_mut(default_rule.kind) {
+                rules.push(rule);
+            }
+        }
+
+        Ok(ruleset)
+    }
+
+    /// The rules of the given kind, or `None` if the kind isn't one of `KINDS`.
+    pub fn kind(&self, kind: &str) -> Option<&[Rule]> {
+        match kind {
+            "override" => Some(&self.override_rules),
+            "content" => Some(&self.content),
+            "room" => Some(&self.room),
+            "sender" => Some(&self.sender),
+            "underride" => Some(&self.underride),
+            _ => None,
+        }
+    }
+
+    /// Looks up a rule by kind and ID.
+    pub fn find(&self, kind: &str, rule_id: &str) -> Option<&Rule> {
+        self.kind(kind).and_then(|rules| rules.iter().find(|rule| rule.rule_id == rule_id))
+    }
+
+    /// Decides whether the user should be notified about an event, using the actions of the first
+    /// enabled rule that matches it.
+    pub fn evaluate(&self, event: &Event, context: &NotificationContext)
+    -> Result<Notification, ApiError> {
+        let mut json = event.to_json()?;
+
+        if let Value::Object(ref mut map) = json {
+            map.insert("room_id".to_string(), Value::String(event.room_id.to_string()));
+        }
+
+        for &kind in KINDS {
+            let rules = self.kind(kind).expect("KINDS should only contain known kinds");
+
+            for rule in rules.iter().filter(|rule| rule.enabled) {
+                if rule.matches(kind, &json, event, context) {
+                    return Ok(Notification::from_actions(&rule.actions));
+                }
+            }
+        }
+
+        Ok(Notification::default())
+    }
+
+    /// Counts the events in a room the user hasn't read that they should be notified about, up to
+    /// the position `up_to`.
+    ///
+    /// Events after the user's read receipt count as unread, or every event after `joined_at` if
+    /// they haven't sent one. Only the most recent `MAX_UNREAD_EVENTS` are checked.
+    pub fn count_unread(
+        &self,
+        connection: &PgConnection,
+        room_id: &RoomId,
+        user_id: &UserId,
+        joined_at: i64,
+        up_to: i64,
+    ) -> Result<NotificationCounts, ApiError> {
+        let read_up_to = read_position(connection, room_id, user_id, joined_at)?;
+
+        let unread: Vec<Event> = Event::find_recent_by_room(
+            connection,
+            room_id,
+            Some(read_up_to),
+            up_to,
+            MAX_UNREAD_EVENTS,
+        )?
+            .into_iter()
+            .filter(|event| event.user_id != *user_id)
+            .collect();
+
+        let mut counts = NotificationCounts::default();
+
+        if unread.is_empty() {
+            return Ok(counts);
+        }
+
+        let context = NotificationContext::load(connection, room_id, user_id)?;
+
+        for event in &unread {
+            let notification = self.evaluate(event, &context)?;
+
+            if notification.notify {
+                counts.notification_count += 1;
+            }
+
+            if notification.highlight {
+                counts.highlight_count += 1;
+            }
+        }
+
+        Ok(counts)
+    }
+
+    /// The mutable rules of the given kind, or `None` if the kind isn't one of `KINDS`.
+    fn kind_mut(&mut self, kind: &str) -> Option<&mut Vec<Rule>> {
+        match kind {
+            "override" => Some(&mut self.override_rules),
+            "content" => Some(&mut self.content),
+            "room" => Some(&mut self.room),
+            "sender" => Some(&mut self.sender),
+            "underride" => Some(&mut self.underride),
+            _ => None,
+        }
+    }
+}
+
+impl Rule {
+    /// Whether or not the rule, of the given kind, matches the event.
+    fn matches(&self, kind: &str, json: &Value, event: &Event, context: &NotificationContext)
+    -> bool {
+        match kind {
+            "content" => match self.pattern {
+                Some(ref pattern) => event_matches(json, "content.body", pattern),
+                None => false,
+            },
+            "room" => self.rule_id == event.room_id.to_string(),
+            "sender" => self.rule_id == event.user_id.to_string(),
+            _ => match self.conditions {
+                Some(ref conditions) => conditions.iter().all(|condition| {
+                    condition_holds(condition, json, event, context)
+                }),
+                None => true,
+            },
+        }
+    }
+}
+
+impl DefaultRule {
+    /// Builds the default rule for the user, applying the user's changes to it.
+    fn to_rule(&self, user_id: &UserId, settings: &[DefaultPushRuleSetting])
+    -> Result<Rule, ApiError> {
+        let user_id_string = user_id.to_string();
+        let substitute = |text: &str| {
+            text.replace("$user_id", &user_id_string).replace("$localpart", user_id.localpart())
+        };
+
+        let conditions = match self.kind {
+            "override" | "underride" => {
+                Some(from_str(&substitute(self.conditions)).map_err(ApiError::from)?)
+            }
+            _ => None,
+        };
+
+        let setting = settings.iter().find(|setting| setting.rule_id == self.rule_id);
+
+        let (enabled, actions) = match setting {
+            Some(setting) => (setting.enabled, &setting.actions[..]),
+            None => (self.enabled, self.actions),
+        };
+
+        Ok(Rule {
+            actions: from_str(actions).map_err(ApiError::from)?,
+            conditions: conditions,
+            is_default: true,
+            enabled: enabled,
+            pattern: self.pattern.map(|pattern| substitute(pattern)),
+            rule_id: self.rule_id.to_string(),
+        })
+    }
+}
+
+impl NotificationContext {
+    /// Looks up what the user's push rules need to know about the room.
+    pub fn load(connection: &PgConnection, room_id: &RoomId, user_id: &UserId)
+    -> Result<NotificationContext, ApiError> {
+        let room = Room::find(connection, room_id)?;
+        let member_count = RoomMembership::find_by_room(connection, room_id)?
+            .iter()
+            .filter(|membership| membership.membership == "join")
+            .count() as u64;
+
+        let member_event = Event::find_state_event(
+            connection,
+            room_id,
+            &EventType::RoomMember,
+            &user_id.to_string(),
+            i64::max_value(),
+        )?;
+
+        let display_name = match member_event {
+            Some(member_event) => {
+                let content: Value = from_str(&member_event.content).map_err(ApiError::from)?;
+
+                content.find("displayname").and_then(Value::as_str).map(str::to_string)
+            }
+            None => None,
+        };
+
+        let power_levels_event = Event::find_state_event(
+            connection,
+            room_id,
+            &EventType::RoomPowerLevels,
+            "",
+            i64::max_value(),
+        )?;
+
+        let mut notification_levels = HashMap::new();
+
+        // The typed power levels content doesn't know about notification levels.
+        if let Some(power_levels_event) = power_levels_event {
+            let content: Value = from_str(&power_levels_event.content).map_err(ApiError::from)?;
+
+            if let Some(&Value::Object(ref levels)) = content.find("notifications") {
+                for (key, level) in levels {
+                    if let Some(level) = level.as_u64() {
+                        notification_levels.insert(key.clone(), level);
+                    }
+                }
+            }
+        }
+
+        Ok(NotificationContext {
+            display_name: display_name,
+            member_count: member_count,
+            notification_levels: notification_levels,
+            power_levels: PowerLevels::load(connection, &room)?,
+        })
+    }
+}
+
+impl Notification {
+    /// Reads a rule's actions: `notify` and `coalesce` notify the user, and the `highlight` tweak
+    /// highlights the notification unless its value is `false`.
+    fn from_actions(actions: &[Value]) -> Notification {
+        let notify = actions.iter().any(|action| match action.as_str() {
+            Some("notify") | Some("coalesce") => true,
+            _ => false,
+        });
+
+        let mut tweaks = HashMap::new();
+
+        for action in actions {
+            if let Some(tweak) = action.find("set_tweak").and_then(Value::as_str) {
+                let value = action.find("value").cloned().unwrap_or(Value::Bool(true));
+
+                tweaks.insert(tweak.to_string(), value);
+            }
+        }
+
+        let highlight = tweaks.get("highlight").and_then(Value::as_bool).unwrap_or(false);
+
+        Notification {
+            actions: actions.to_vec(),
+            notify: notify,
+            highlight: notify && highlight,
+            tweaks: tweaks,
+        }
+    }
+}
+
+/// Returns the rooms the user has joined, along with the ordering of their join event.
+pub fn joined_rooms(connection: &PgConnection, user_id: &UserId)
+-> Result<Vec<(RoomId, i64)>, ApiError> {
+    let memberships: Vec<RoomMembership> =
+        RoomMembership::find_by_uid(connection, user_id.clone())?
+        .into_iter()
+        .filter(|membership| membership.membership == "join")
+        .collect();
+    let event_ids: Vec<_> = memberships.iter()
+        .map(|membership| membership.event_id.clone())
+        .collect();
+    let member_events = Event::find_by_ids(connection, &event_ids)?;
+
+    Ok(memberships
+        .into_iter()
+        .filter_map(|membership| {
+            member_events
+                .iter()
+                .find(|event| event.id == membership.event_id)
+                .map(|event| (membership.room_id, event.ordering))
+        })
+        .collect())
+}
+
+/// Returns the ordering of the last event in a room the user has read: the event of their read
+/// receipt, or their join event at `joined_at` if they haven't sent one.
+pub fn read_position(
+    connection: &PgConnection,
+    room_id: &RoomId,
+    user_id: &UserId,
+    joined_at: i64,
+) -> Result<i64, ApiError> {
+    let receipt = Receipt::find_by_user(connection, room_id, user_id, READ_RECEIPT)?;
+
+    Ok(match receipt {
+        Some(receipt) => Event::find_by_ids(connection, &[receipt.event_id])?
+            .first()
+            .map(|event| event.ordering)
+            .unwrap_or(joined_at),
+        None => joined_at,
+    })
+}
+
+/// Checks that `actions` is a list of actions as described in the spec.
+pub fn validate_actions(actions: &[Value]) -> Result<(), ApiError> {
+    for action in actions {
+        let valid = match *action {
+            Value::String(ref action) => {
+                action == "notify" || action == "dont_notify" || action == "coalesce"
+            }
+            Value::Object(_) => action.find("set_tweak").and_then(Value::as_str).is_some(),
+            _ => false,
+        };
+
+        if !valid {
+            return Err(ApiError::invalid_param("actions", "Contains an unknown action."));
+        }
+    }
+
+    Ok(())
+}
+
+/// The index of `rule_id` in `rule_ids`, failing if the rule named by `param_name` doesn't exist.
+fn position_of(rule_ids: &[String], param_name: &str, rule_id: &str) -> Result<usize, ApiError> {
+    rule_ids.iter().position(|id| id == rule_id).ok_or_else(|| {
+        ApiError::invalid_param(param_name, "Must be the ID of another rule of the same kind.")
+    })
+}
+
+/// Whether or not one condition of an override or underride rule holds for the event.
+///
+/// Conditions of unknown kinds never hold, so rules the server doesn't understand never match.
+fn condition_holds(condition: &Value, json: &Value, event: &Event, context: &NotificationContext)
+-> bool {
+    let field = |name| condition.find(name).and_then(Value::as_str);
+
+    match field("kind") {
+        Some("event_match") => match (field("key"), field("pattern")) {
+            (Some(key), Some(pattern)) => event_matches(json, key, pattern),
+            _ => false,
+        },
+        Some("contains_display_name") => {
+            let body = json.find_path(&["content", "body"]).and_then(Value::as_str);
+
+            match (body, context.display_name.as_ref()) {
+                (Some(body), Some(display_name)) => contains_word(body, display_name),
+                _ => false,
+            }
+        }
+        Some("room_member_count") => match field("is") {
+            Some(is) => member_count_matches(is, context.member_count),
+            None => false,
+        },
+        Some("sender_notification_permission") => match field("key") {
+            Some(key) => {
+                let required_level = *context.notification_levels
+                    .get(key)
+                    .unwrap_or(&DEFAULT_NOTIFICATION_LEVEL);
+
+                context.power_levels.user_level(&event.user_id) >= required_level
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// Whether or not the value of the dotted `key` in the event matches the glob `pattern`.
+///
+/// Message bodies match if any run of whole words in them matches, other values only if the whole
+/// value does.
+fn event_matches(json: &Value, key: &str, pattern: &str) -> bool {
+    let path: Vec<&str> = key.split('.').collect();
+    let value = match json.find_path(&path).and_then(Value::as_str) {
+        Some(value) => value,
+        None => return false,
+    };
+
+    if key == "content.body" {
+        glob_matches_words(pattern, value)
+    } else {
+        glob_matches(pattern, value)
+    }
+}
+
+/// Whether or not the glob `pattern`, where `*` matches any run of characters and `?` any one
+/// character, matches all of `text`, ignoring case.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+
+    glob_matches_from(&pattern, &text, 0, &|end| end == text.len())
+}
+
+/// Whether or not the glob `pattern` matches a run of whole words in `text`, ignoring case.
+fn glob_matches_words(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let at_word_end = |end: usize| end == text.len() || !is_word_char(text[end]);
+
+    (0..text.len() + 1).any(|start| {
+        (start == 0 || !is_word_char(text[start - 1])) &&
+            glob_matches_from(&pattern, &text, start, &at_word_end)
+    })
+}
+
+/// Whether or not the glob `pattern` matches the characters of `text` from `start` up to some
+/// position for which `can_end` holds.
+fn glob_matches_from(pattern: &[char], text: &[char], start: usize, can_end: &Fn(usize) -> bool)
+-> bool {
+    // The positions in the pattern reached by the characters consumed so far.
+    let mut states = vec![false; pattern.len() + 1];
+
+    states[0] = true;
+    skip_stars(pattern, &mut states);
+
+    let mut position = start;
+
+    loop {
+        if states[pattern.len()] && can_end(position) {
+            return true;
+        }
+
+        if position == text.len() {
+            return false;
+        }
+
+        let mut next = vec![false; pattern.len() + 1];
+
+        for (index, &pattern_char) in pattern.iter().enumerate() {
+            if !states[index] {
+                continue;
+            }
+
+            match pattern_char {
+                '*' => next[index] = true,
+                '?' => next[index + 1] = true,
+                pattern_char if pattern_char == text[position] => next[index + 1] = true,
+                _ => {}
+            }
+        }
+
+        skip_stars(pattern, &mut next);
+
+        if !next.iter().any(|&reached| reached) {
+            return false;
+        }
+
+        states = next;
+        position += 1;
+    }
+}
+
+/// Marks the positions after any `*` that was reached as reached too, since `*` can match nothing.
+fn skip_stars(pattern: &[char], states: &mut [bool]) {
+    for index in 0..pattern.len() {
+        if states[index] && pattern[index] == '*' {
+            states[index + 1] = true;
+        }
+    }
+}
+
+/// Whether or not `text` contains `word` as a whole word, ignoring case.
+fn contains_word(text: &str, word: &str) -> bool {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let word: Vec<char> = word.to_lowercase().chars().collect();
+
+    if word.is_empty() || word.len() > text.len() {
+        return false;
+    }
+
+    (0..text.len() - word.len() + 1).any(|start| {
+        let end = start + word.len();
+
+        text[start..end] == word[..] &&
+            (start == 0 || !is_word_char(text[start - 1])) &&
+            (end == text.len() || !is_word_char(text[end]))
+    })
+}
+
+/// Whether or not the character is part of a word, as `\w` is in regular expressions.
+fn is_word_char(character: char) -> bool {
+    character.is_alphanumeric() || character == '_'
+}
+
+/// Whether or not the room's member count satisfies the `is` field of a `room_member_count`
+/// condition, such as `2` or `>=10`.
+fn member_count_matches(is: &str, member_count: u64) -> bool {
+    let operator_length = is.find(|character: char| character.is_digit(10)).unwrap_or(is.len());
+    let (operator, count) = is.split_at(operator_length);
+
+    let count: u64 = match count.parse() {
+        Ok(count) => count,
+        Err(_) => return false,
+    };
+
+    match operator {
+        "" | "==" => member_count == count,
+        "<" => member_count < count,
+        ">" => member_count > count,
+        "<=" => member_count <= count,
+        ">=" => member_count >= count,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{contains_word, glob_matches, glob_matches_words, member_count_matches};
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_matches("m.notice", "m.notice"));
+        assert!(glob_matches("M.NOTICE", "m.notice"));
+        assert!(!glob_matches("m.notice", "m.notices"));
+        assert!(glob_matches("m.room.*", "m.room.message"));
+        assert!(glob_matches("m.room.?essage", "m.room.message"));
+        assert!(!glob_matches("m.room.?", "m.room.message"));
+        assert!(glob_matches("", ""));
+        assert!(!glob_matches("", "m.room.message"));
+    }
+
+    #[test]
+    fn glob_patterns_match_whole_words_in_bodies() {
+        assert!(glob_matches_words("carl", "hi carl!"));
+        assert!(glob_matches_words("carl", "Carl, hi"));
+        assert!(!glob_matches_words("carl", "carlos"));
+        assert!(!glob_matches_words("carl", "mccarl"));
+        assert!(glob_matches_words("carl*", "hi carlos"));
+        assert!(glob_matches_words("@room", "hey @room, look"));
+        assert!(!glob_matches_words("@room", "@roommates"));
+    }
+
+    #[test]
+    fn display_names_match_whole_words() {
+        assert!(contains_word("Hello Carl Sagan!", "carl sagan"));
+        assert!(!contains_word("Hello Carlos", "carl"));
+        assert!(!contains_word("Hello", ""));
+    }
+
+    #[test]
+    fn member_count_conditions() {
+        assert!(member_count_matches("2", 2));
+        assert!(member_count_matches("==2", 2));
+        assert!(!member_count_matches("2", 3));
+        assert!(member_count_matches(">2", 3));
+        assert!(member_count_matches(">=3", 3));
+        assert!(member_count_matches("<3", 2));
+        assert!(member_count_matches("<=2", 2));
+        assert!(!member_count_matches("!2", 3));
+        assert!(!member_count_matches("two", 2));
+    }
+}
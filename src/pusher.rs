@@ -0,0 +1,525 @@
+//! Pushers, which deliver notifications about new events to a user's devices.
+//!
+//! Ruma doesn't talk to mobile platforms itself. Each HTTP pusher names a push gateway that does,
+//! and a background job posts a notification to it for every event the user's push rules say
+//! they should be notified about.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use diesel::{
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LoadDsl,
+    OrderDsl,
+    delete,
+    insert,
+    update,
+};
+use diesel::pg::PgConnection;
+use ruma_events::EventType;
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::{Value, from_str, to_string, to_value};
+
+use config::Config;
+use error::ApiError;
+use event::Event;
+use http_client::HttpClient;
+use jobs::Job;
+use push_rule::{Notification, NotificationContext, Ruleset, joined_rooms};
+use schema::pushers;
+use stream_position::{Stream, StreamPosition};
+
+/// The most events considered for one pusher each time notifications are sent.
+const MAX_PUSHED_EVENTS: i64 = 100;
+
+/// A place to send push notifications for a user.
+#[derive(Clone, Debug, Identifiable, Queryable)]
+#[table_name = "pushers"]
+pub struct Pusher {
+    /// Entry ID.
+    pub id: i64,
+    /// The user the notifications are for.
+    pub user_id: UserId,
+    /// The kind of pusher. Only *http* is supported.
+    pub kind: String,
+    /// The ID of the application the notifications are for, e.g. *com.example.app.ios*.
+    pub app_id: String,
+    /// The identifier of the device to the push gateway.
+    pub pushkey: String,
+    /// The name of the application, shown to users.
+    pub app_display_name: String,
+    /// The name of the device, shown to users.
+    pub device_display_name: String,
+    /// The tag of the device's push rules, if any.
+    pub profile_tag: Option<String>,
+    /// The preferred language for notifications, e.g. *en-US*.
+    pub lang: String,
+    /// JSON of the pusher's data, including the URL of the push gateway.
+    pub data: String,
+    /// When the pusher was last set, in milliseconds since the Unix epoch.
+    pub updated_at: i64,
+    /// The ordering of the last event considered for a notification.
+    pub last_ordering: i64,
+}
+
+/// A new pusher, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "pushers"]
+pub struct NewPusher {
+    /// The user the notifications are for.
+    pub user_id: UserId,
+    /// The kind of pusher. Only *http* is supported.
+    pub kind: String,
+    /// The ID of the application the notifications are for, e.g. *com.example.app.ios*.
+    pub app_id: String,
+    /// The identifier of the device to the push gateway.
+    pub pushkey: String,
+    /// The name of the application, shown to users.
+    pub app_display_name: String,
+    /// The name of the device, shown to users.
+    pub device_display_name: String,
+    /// The tag of the device's push rules, if any.
+    pub profile_tag: Option<String>,
+    /// The preferred language for notifications, e.g. *en-US*.
+    pub lang: String,
+    /// JSON of the pusher's data, including the URL of the push gateway.
+    pub data: String,
+    /// When the pusher was set, in milliseconds since the Unix epoch.
+    pub updated_at: i64,
+    /// The ordering of the last event considered for a notification.
+    pub last_ordering: i64,
+}
+
+/// A way of delivering notifications to push gateways.
+pub trait PushGateway: Send + Sync {
+    /// Posts a notification to the push gateway at `url`, returning the pushkeys it rejected.
+    fn notify(&self, url: &str, notification: &Value) -> Result<Vec<String>, ApiError>;
+}
+
+/// A `PushGateway` that posts notifications over HTTP with the push gateway API.
+#[derive(Debug)]
+pub struct HttpPushGateway {
+    http_client: Arc<HttpClient>,
+}
+
+/// A `PushGateway` that keeps notifications instead of sending them.
+#[derive(Debug)]
+pub struct RecordingPushGateway {
+    notifications: Mutex<Vec<(String, Value)>>,
+}
+
+/// A background job that sends push notifications about new events.
+pub struct SendPushNotifications {
+    push_gateway: Arc<PushGateway>,
+}
+
+/// The body of a request to a push gateway.
+#[derive(Debug, Serialize)]
+struct PushGatewayRequest {
+    notification: PushNotification,
+}
+
+/// A notification about one event.
+///
+/// Pushers whose data sets the format *event_id_only* are sent only the IDs, the counts, and the
+/// devices.
+#[derive(Debug, Default, Serialize)]
+struct PushNotification {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<Value>,
+    counts: PushCounts,
+    devices: Vec<PushDevice>,
+    event_id: String,
+    prio: String,
+    room_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sender: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sender_display_name: Option<String>,
+    #[serde(rename = "type")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event_type: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct PushCounts {
+    unread: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct PushDevice {
+    app_id: String,
+    data: Value,
+    pushkey: String,
+    pushkey_ts: i64,
+    tweaks: HashMap<String, Value>,
+}
+
+impl Pusher {
+    /// Saves a pusher, replacing the user's pusher with the same app ID and pushkey.
+    ///
+    /// Unless `append` is set, the same pushkey is removed from every other user, since the
+    /// device now belongs to this one.
+    pub fn set(connection: &PgConnection, new_pusher: &NewPusher, append: bool)
+    -> Result<(), ApiError> {
+        connection.transaction::<(), ApiError, _>(|| {
+            let same_device = pushers::table
+                .filter(pushers::app_id.eq(&new_pusher.app_id))
+                .filter(pushers::pushkey.eq(&new_pusher.pushkey));
+
+            if append {
+                delete(same_device.filter(pushers::user_id.eq(&new_pusher.user_id)))
+                    .execute(connection)?;
+            } else {
+                delete(same_device).execute(connection)?;
+            }
+
+            insert(new_pusher).into(pushers::table).execute(connection)?;
+
+            Ok(())
+        }).map_err(ApiError::from)
+    }
+
+    /// Removes the user's pusher with the given app ID and pushkey, if there is one.
+    pub fn delete(connection: &PgConnection, user_id: &UserId, app_id: &str, pushkey: &str)
+    -> Result<(), ApiError> {
+        let pusher = pushers::table
+            .filter(pushers::user_id.eq(user_id))
+            .filter(pushers::app_id.eq(app_id))
+            .filter(pushers::pushkey.eq(pushkey));
+
+        delete(pusher).execute(connection).map(|_| ()).map_err(ApiError::from)
+    }
+
+    /// Returns the user's pushers, oldest first.
+    pub fn find_by_user(connection: &PgConnection, user_id: &UserId)
+    -> Result<Vec<Pusher>, ApiError> {
+        pushers::table
+            .filter(pushers::user_id.eq(user_id))
+            .order(pushers::id.asc())
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Returns the pushers that haven't considered every event up to the position `up_to`.
+    pub fn find_behind(connection: &PgConnection, up_to: i64) -> Result<Vec<Pusher>, ApiError> {
+        pushers::table
+            .filter(pushers::last_ordering.lt(up_to))
+            .order(pushers::id.asc())
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// The URL of the pusher's push gateway.
+    pub fn url(&self) -> Result<String, ApiError> {
+        let data: Value = from_str(&self.data)?;
+
+        match data.find("url").and_then(Value::as_str) {
+            Some(url) => Ok(url.to_string()),
+            None => Err(ApiError::unknown(Some("The pusher has no push gateway URL."))),
+        }
+    }
+
+    /// Records that the events up to `ordering` have been considered.
+    fn advance(&mut self, connection: &PgConnection, ordering: i64) -> Result<(), ApiError> {
+        update(pushers::table.find(self.id))
+            .set(pushers::last_ordering.eq(ordering))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        self.last_ordering = ordering;
+
+        Ok(())
+    }
+
+    /// Removes the pusher, e.g. because its push gateway rejected it.
+    fn remove(&self, connection: &PgConnection) -> Result<(), ApiError> {
+        delete(pushers::table.find(self.id))
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+}
+
+impl HttpPushGateway {
+    /// Creates a new `HttpPushGateway` that sends notifications with the given client.
+    pub fn new(http_client: Arc<HttpClient>) -> Self {
+        HttpPushGateway {
+            http_client: http_client,
+        }
+    }
+}
+
+impl PushGateway for HttpPushGateway {
+    fn notify(&self, url: &str, notification: &Value) -> Result<Vec<String>, ApiError> {
+        let body = to_string(notification)?;
+
+        let response = self.http_client.post_json(url, &body).map_err(|error| {
+            ApiError::unknown(Some(&format!("Failed to reach the push gateway: {}", error)))
+        })?;
+
+        if !response.status.is_success() {
+            return Err(ApiError::unknown(Some(
+                &format!("The push gateway responded with {}: {}", response.status, response.body)
+            )));
+        }
+
+        let response: Value = from_str(&response.body).unwrap_or(Value::Null);
+        let rejected = response.find("rejected")
+            .and_then(Value::as_array)
+            .map(|rejected| {
+                rejected.iter().filter_map(Value::as_str).map(str::to_string).collect()
+            })
+            .unwrap_or_else(Vec::new);
+
+        Ok(rejected)
+    }
+}
+
+impl RecordingPushGateway {
+    /// Creates a new `RecordingPushGateway` with no notifications.
+    pub fn new() -> Self {
+        RecordingPushGateway {
+            notifications: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the URL and body of every notification sent so far, oldest first.
+    pub fn notifications(&self) -> Vec<(String, Value)> {
+        self.notifications.lock().expect("RecordingPushGateway mutex was poisoned").clone()
+    }
+}
+
+impl PushGateway for RecordingPushGateway {
+    fn notify(&self, url: &str, notification: &Value) -> Result<Vec<String>, ApiError> {
+        let mut notifications =
+            self.notifications.lock().expect("RecordingPushGateway mutex was poisoned");
+
+        notifications.push((url.to_string(), notification.clone()));
+
+        Ok(Vec::new())
+    }
+}
+
+impl SendPushNotifications {
+    /// Creates the job, which delivers notifications through the given `PushGateway`.
+    pub fn new(push_gateway: Arc<PushGateway>) -> Self {
+        SendPushNotifications {
+            push_gateway: push_gateway,
+        }
+    }
+
+    /// Sends the notifications for the events after the pusher's last ordering, up to `up_to` or
+    /// `MAX_PUSHED_EVENTS` positions further, whichever comes first.
+    ///
+    /// Each event is only considered once. If the push gateway can't be reached, the pusher
+    /// stops at the event that failed and tries it again on the next run.
+    fn push(&self, connection: &PgConnection, pusher: &mut Pusher, up_to: i64)
+    -> Result<(), ApiError> {
+        let up_to = min(up_to, pusher.last_ordering + MAX_PUSHED_EVENTS);
+        let url = pusher.url()?;
+        let ruleset = Ruleset::load(connection, &pusher.user_id)?;
+        let joined_rooms = joined_rooms(connection, &pusher.user_id)?;
+
+        let mut events = Vec::new();
+
+        for &(ref room_id, _) in &joined_rooms {
+            events.extend(Event::find_page_by_room(
+                connection,
+                room_id,
+                pusher.last_ordering,
+                Some(up_to),
+                false,
+                MAX_PUSHED_EVENTS,
+            )?);
+        }
+
+        events.sort_by_key(|event| event.ordering);
+
+        let mut contexts: HashMap<String, NotificationContext> = HashMap::new();
+        let mut unread = None;
+
+        for event in events.iter().filter(|event| event.user_id != pusher.user_id) {
+            let room_key = event.room_id.to_string();
+
+            if !contexts.contains_key(&room_key) {
+                let context =
+                    NotificationContext::load(connection, &event.room_id, &pusher.user_id)?;
+
+                contexts.insert(room_key.clone(), context);
+            }
+
+            let notification = ruleset.evaluate(event, &contexts[&room_key])?;
+
+            if !notification.notify {
+                continue;
+            }
+
+            if unread.is_none() {
+                unread = Some(count_unread(connection, &ruleset, pusher, &joined_rooms, up_to)?);
+            }
+
+            let body = to_value(&PushGatewayRequest {
+                notification: push_notification(
+                    connection,
+                    pusher,
+                    event,
+                    notification,
+                    unread.unwrap_or(0),
+                )?,
+            });
+
+            let rejected = match self.push_gateway.notify(&url, &body) {
+                Ok(rejected) => rejected,
+                Err(error) => {
+                    pusher.advance(connection, event.ordering - 1)?;
+
+                    return Err(error);
+                }
+            };
+
+            if rejected.contains(&pusher.pushkey) {
+                info!("Push gateway {} rejected pushkey {}, removing it.", url, pusher.pushkey);
+
+                return pusher.remove(connection);
+            }
+        }
+
+        pusher.advance(connection, up_to)
+    }
+}
+
+impl Job for SendPushNotifications {
+    fn name(&self) -> &'static str {
+        "send_push_notifications"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn run(&self, connection: &PgConnection, _config: &Config, _now: i64) -> Result<(), ApiError> {
+        let position = StreamPosition::current(connection, Stream::Events)?;
+
+        for mut pusher in Pusher::find_behind(connection, position)? {
+            if let Err(error) = self.push(connection, &mut pusher, position) {
+                info!("Failed to send push notifications for pusher {}: {}", pusher.id, error);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Counts the user's unread notifications across all of their rooms, for the badge on their
+/// devices.
+fn count_unread(
+    connection: &PgConnection,
+    ruleset: &Ruleset,
+    pusher: &Pusher,
+    joined_rooms: &[(RoomId, i64)],
+    up_to: i64,
+) -> Result<u64, ApiError> {
+    let mut unread = 0;
+
+    for &(ref room_id, joined_at) in joined_rooms {
+        let counts = ruleset.count_unread(connection, room_id, &pusher.user_id, joined_at, up_to)?;
+
+        unread += counts.notification_count;
+    }
+
+    Ok(unread)
+}
+
+/// Builds the notification about an event for the push gateway.
+fn push_notification(
+    connection: &PgConnection,
+    pusher: &Pusher,
+    event: &Event,
+    notification: Notification,
+    unread: u64,
+) -> Result<PushNotification, ApiError> {
+    let mut data: Value = from_str(&pusher.data)?;
+    let event_id_only = data.find("format").and_then(Value::as_str) == Some("event_id_only");
+
+    // The push gateway already knows its own URL.
+    if let Value::Object(ref mut data) = data {
+        data.remove("url");
+    }
+
+    let prio = if notification.highlight || notification.tweaks.contains_key("sound") {
+        "high"
+    } else {
+        "low"
+    };
+
+    let mut push_notification = PushNotification {
+        counts: PushCounts {
+            unread: unread,
+        },
+        devices: vec![PushDevice {
+            app_id: pusher.app_id.clone(),
+            data: data,
+            pushkey: pusher.pushkey.clone(),
+            pushkey_ts: pusher.updated_at / 1000,
+            tweaks: notification.tweaks,
+        }],
+        event_id: event.id.to_string(),
+        prio: prio.to_string(),
+        room_id: event.room_id.to_string(),
+        ..PushNotification::default()
+    };
+
+    if event_id_only {
+        return Ok(push_notification);
+    }
+
+    push_notification.content = Some(from_str(&event.content)?);
+    push_notification.event_type = Some(event.event_type.clone());
+    push_notification.sender = Some(event.user_id.to_string());
+    push_notification.sender_display_name = state_content_string(
+        connection,
+        &event.room_id,
+        &EventType::RoomMember,
+        &event.user_id.to_string(),
+        "displayname",
+    )?;
+    push_notification.room_name =
+        state_content_string(connection, &event.room_id, &EventType::RoomName, "", "name")?;
+
+    Ok(push_notification)
+}
+
+/// Looks up a string field in the content of a room's current state event.
+fn state_content_string(
+    connection: &PgConnection,
+    room_id: &RoomId,
+    event_type: &EventType,
+    state_key: &str,
+    field: &str,
+) -> Result<Option<String>, ApiError> {
+    let event = Event::find_state_event(
+        connection,
+        room_id,
+        event_type,
+        state_key,
+        i64::max_value(),
+    )?;
+
+    match event {
+        Some(event) => {
+            let content: Value = from_str(&event.content)?;
+
+            Ok(content.find(field).and_then(Value::as_str).map(str::to_string))
+        }
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,235 @@
+//! Read receipts.
+//!
+//! Receipts can be collected in memory for a short while before they're saved, so that a client
+//! sending a receipt for every message it shows only has its latest one saved, and syncs are only
+//! woken up once for the whole batch.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use diesel::{
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    LoadDsl,
+    OrderDsl,
+    delete,
+    insert,
+};
+use diesel::pg::PgConnection;
+use diesel::result::Error as DieselError;
+use iron::{Plugin, Request};
+use iron::typemap::Key;
+use persistent::Read;
+use ruma_identifiers::{EventId, RoomId, UserId};
+
+use config::Config;
+use error::ApiError;
+use jobs::Job;
+use schema::receipts;
+
+/// How long receipts and typing changes are collected before they're saved if the configuration
+/// doesn't say, in milliseconds.
+pub const DEFAULT_EPHEMERAL_BATCH_MILLIS: u64 = 500;
+
+/// The only receipt type defined by the spec.
+pub const READ_RECEIPT: &'static str = "m.read";
+
+/// The latest receipt of one type a user has sent in a room.
+#[derive(Clone, Debug, Identifiable, Queryable)]
+#[table_name = "receipts"]
+pub struct Receipt {
+    /// Entry ID.
+    pub id: i64,
+    /// The room the receipt was sent in.
+    pub room_id: RoomId,
+    /// The user who sent the receipt.
+    pub user_id: UserId,
+    /// The type of the receipt, e.g. *m.read*.
+    pub receipt_type: String,
+    /// The event the receipt points at.
+    pub event_id: EventId,
+    /// When the receipt was sent, in milliseconds since the Unix epoch.
+    pub ts: i64,
+    /// The position of the receipt in the events stream.
+    pub ordering: i64,
+}
+
+/// A new receipt, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "receipts"]
+pub struct NewReceipt {
+    /// The room the receipt was sent in.
+    pub room_id: RoomId,
+    /// The user who sent the receipt.
+    pub user_id: UserId,
+    /// The type of the receipt, e.g. *m.read*.
+    pub receipt_type: String,
+    /// The event the receipt points at.
+    pub event_id: EventId,
+    /// When the receipt was sent, in milliseconds since the Unix epoch.
+    pub ts: i64,
+}
+
+/// Receipts waiting to be saved, keyed by their room, user, and type.
+#[derive(Debug)]
+pub struct PendingReceipts {
+    batch_millis: u64,
+    receipts: Mutex<HashMap<(RoomId, UserId, String), NewReceipt>>,
+}
+
+/// An Iron plugin for attaching `PendingReceipts` to an Iron request.
+pub struct ServerPendingReceipts;
+
+/// A background job that saves the receipts collected since it last ran.
+#[derive(Debug)]
+pub struct SaveReceipts {
+    pending: Arc<PendingReceipts>,
+}
+
+impl Receipt {
+    /// Saves the receipt, replacing the user's previous receipt of the same type in the room.
+    ///
+    /// The replacement gets a new position in the events stream so that `/sync` picks it up.
+    pub fn upsert(connection: &PgConnection, new_receipt: &NewReceipt)
+    -> Result<Receipt, ApiError> {
+        connection.transaction::<Receipt, ApiError, _>(|| {
+            let previous = receipts::table
+                .filter(receipts::room_id.eq(&new_receipt.room_id))
+                .filter(receipts::user_id.eq(&new_receipt.user_id))
+                .filter(receipts::receipt_type.eq(&new_receipt.receipt_type));
+
+            delete(previous).execute(connection)?;
+
+            insert(new_receipt)
+                .into(receipts::table)
+                .get_result(connection)
+                .map_err(ApiError::from)
+        }).map_err(ApiError::from)
+    }
+
+    /// Returns the user's latest receipt of the given type in a room, if they sent one.
+    pub fn find_by_user(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        user_id: &UserId,
+        receipt_type: &str,
+    ) -> Result<Option<Receipt>, ApiError> {
+        let receipt = receipts::table
+            .filter(receipts::room_id.eq(room_id))
+            .filter(receipts::user_id.eq(user_id))
+            .filter(receipts::receipt_type.eq(receipt_type))
+            .first(connection);
+
+        match receipt {
+            Ok(receipt) => Ok(Some(receipt)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(error) => Err(ApiError::from(error)),
+        }
+    }
+
+    /// Returns the receipts in a room sent after the position `after` up to the position `up_to`.
+    pub fn find_by_room(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        after: Option<i64>,
+        up_to: i64,
+    ) -> Result<Vec<Receipt>, ApiError> {
+        receipts::table
+            .filter(receipts::room_id.eq(room_id))
+            .filter(receipts::ordering.gt(after.unwrap_or(0)))
+            .filter(receipts::ordering.le(up_to))
+            .order(receipts::ordering.asc())
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+}
+
+impl PendingReceipts {
+    /// Creates a new `PendingReceipts` that collects receipts for `batch_millis` milliseconds, or
+    /// saves them right away if that's 0.
+    pub fn new(batch_millis: u64) -> Self {
+        PendingReceipts {
+            batch_millis: batch_millis,
+            receipts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Saves the receipt, or queues it to be saved with the next batch, replacing any queued
+    /// receipt of the same type from the user in the room.
+    pub fn add(&self, connection: &PgConnection, new_receipt: NewReceipt) -> Result<(), ApiError> {
+        if self.batch_millis == 0 {
+            return Receipt::upsert(connection, &new_receipt).map(|_| ());
+        }
+
+        let key = (
+            new_receipt.room_id.clone(),
+            new_receipt.user_id.clone(),
+            new_receipt.receipt_type.clone(),
+        );
+
+        self.lock().insert(key, new_receipt);
+
+        Ok(())
+    }
+
+    /// Saves every queued receipt.
+    ///
+    /// Receipts that fail to save are dropped rather than retried, since a newer receipt will
+    /// usually replace them soon.
+    pub fn save(&self, connection: &PgConnection) -> Result<(), ApiError> {
+        let receipts: Vec<NewReceipt> = self.lock().drain().map(|(_, receipt)| receipt).collect();
+
+        connection.transaction::<(), ApiError, _>(|| {
+            for receipt in &receipts {
+                Receipt::upsert(connection, receipt)?;
+            }
+
+            Ok(())
+        }).map_err(ApiError::from)
+    }
+
+    fn lock(&self) -> MutexGuard<HashMap<(RoomId, UserId, String), NewReceipt>> {
+        self.receipts.lock().expect("PendingReceipts mutex was poisoned")
+    }
+}
+
+impl ServerPendingReceipts {
+    /// Extract the `PendingReceipts` stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<Arc<PendingReceipts>>, ApiError> {
+        request.get::<Read<ServerPendingReceipts>>().map_err(ApiError::from)
+    }
+}
+
+impl Key for ServerPendingReceipts {
+    type Value = Arc<PendingReceipts>;
+}
+
+impl SaveReceipts {
+    /// Creates a job that saves the receipts queued in the given `PendingReceipts`.
+    pub fn new(pending: Arc<PendingReceipts>) -> Self {
+        SaveReceipts {
+            pending: pending,
+        }
+    }
+}
+
+impl Job for SaveReceipts {
+    fn name(&self) -> &'static str {
+        "save_receipts"
+    }
+
+    fn interval(&self) -> Duration {
+        match self.pending.batch_millis {
+            // Nothing is ever queued, so the job only needs to run now and then.
+            0 => Duration::from_secs(1),
+            batch_millis => Duration::from_millis(batch_millis),
+        }
+    }
+
+    fn run(&self, connection: &PgConnection, _config: &Config, _now: i64) -> Result<(), ApiError> {
+        self.pending.save(connection)
+    }
+}
@@ -1,14 +1,29 @@
 //! Matrix rooms.
 
-use std::collections::{HashMap, HashSet};
+use std::cmp::max;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::{TryFrom, TryInto};
 
-use diesel::{Connection, ExecuteDsl, ExpressionMethods, FilterDsl, FindDsl, LoadDsl, OrderDsl, insert};
+use diesel::{
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LoadDsl,
+    OrderDsl,
+    SelectDsl,
+    insert,
+    update,
+};
+use diesel::expression::dsl::{count_star, sql};
 use diesel::pg::PgConnection;
 use diesel::pg::data_types::PgTimestamp;
 use diesel::pg::expression::dsl::any;
 use diesel::result::Error as DieselError;
-use ruma_events::EventType;
+use diesel::types::BigInt;
+use ring::digest;
+use ruma_events::{CustomStateEvent, EventType};
 use ruma_events::room::create::{CreateEvent, CreateEventContent};
 use ruma_events::room::history_visibility::{
     HistoryVisibility,
@@ -24,30 +39,75 @@ use ruma_events::room::name::{NameEvent, NameEventContent};
 use ruma_events::room::power_levels::{PowerLevelsEvent, PowerLevelsEventContent};
 use ruma_events::room::topic::{TopicEvent, TopicEventContent};
 use ruma_identifiers::{EventId, RoomAliasId, RoomId, UserId};
+use serde_json::{Value, from_str, to_string};
 
 use error::ApiError;
 use event::{Event, NewEvent};
+use event_expiration::EventExpiration;
+use membership_cache::MembershipCache;
+use power_levels::{PowerLevels, PowerLevelsCache};
 use room_alias::{NewRoomAlias, RoomAlias};
 use room_membership::{RoomMembership, RoomMembershipOptions};
-use schema::{events, rooms, users};
+use schema::{events, room_memberships, rooms, users};
 use user::User;
 
 /// Options provided by the user to customize the room upon creation.
 pub struct CreationOptions {
     /// An initial alias for the room.
     pub alias: Option<String>,
+    /// Whether or not the room starts out with end-to-end encryption enabled.
+    pub encrypt: bool,
     /// Whehter or not the room should be federated.
     pub federate: bool,
     /// A list of users to invite to the room.
     pub invite_list: Option<Vec<String>>,
     /// An initial name for the room.
     pub name: Option<String>,
+    /// The room this room replaces and the ID of the *m.room.tombstone* event that replaced it,
+    /// for rooms created by an upgrade.
+    pub predecessor: Option<(RoomId, EventId)>,
     /// A convenience parameter for setting a few default state events.
     pub preset: RoomPreset,
     /// An initial topic for the room.
     pub topic: Option<String>,
 }
 
+/// The room versions Ruma implements. Rooms of all of these versions use event IDs of the form
+/// `$opaque_id:domain`, which later room versions replace with hashes of the event.
+pub const KNOWN_ROOM_VERSIONS: &'static [&'static str] = &["1", "2"];
+
+/// The room version used for new rooms if the configuration doesn't choose one.
+pub const DEFAULT_ROOM_VERSION: &'static str = "1";
+
+/// The longest a room's name can be, in bytes, per the spec.
+pub const MAX_ROOM_NAME_LENGTH: usize = 255;
+
+/// The longest a room's topic can be, in bytes. The spec doesn't limit topics, but there's no
+/// reason to store a topic longer than any client would show.
+pub const MAX_ROOM_TOPIC_LENGTH: usize = 4096;
+
+/// The state event type that enables end-to-end encryption in a room.
+pub const ENCRYPTION_EVENT_TYPE: &'static str = "m.room.encryption";
+
+/// The state event type that points a room's members to the room that replaced it.
+pub const TOMBSTONE_EVENT_TYPE: &'static str = "m.room.tombstone";
+
+/// The state event types copied to the new room when a room is upgraded.
+const UPGRADED_STATE_EVENT_TYPES: &'static [&'static str] = &[
+    "m.room.avatar",
+    "m.room.canonical_alias",
+    "m.room.guest_access",
+    "m.room.history_visibility",
+    "m.room.join_rules",
+    "m.room.name",
+    "m.room.power_levels",
+    "m.room.topic",
+    ENCRYPTION_EVENT_TYPE,
+];
+
+/// The encryption algorithm used for rooms that Ruma encrypts at creation.
+pub const MEGOLM_ALGORITHM: &'static str = "m.megolm.v1.aes-sha2";
+
 /// A new Matrix room, not yet saved.
 #[derive(Debug, Insertable)]
 #[table_name = "rooms"]
@@ -58,6 +118,8 @@ pub struct NewRoom {
     pub user_id: UserId,
     /// Whether or not the room is visible in the directory.
     pub public: bool,
+    /// The room's version, which decides the format of its events.
+    pub version: String,
 }
 
 /// A Matrix room.
@@ -71,6 +133,8 @@ pub struct Room {
     pub public: bool,
     /// The time the room was created.
     pub created_at: PgTimestamp,
+    /// The room's version, which decides the format of its events.
+    pub version: String,
 }
 
 /// A convenience parameter for setting a few default state events.
@@ -111,7 +175,7 @@ impl Room {
 
             let mut new_events = Vec::new();
 
-            let new_create_event: NewEvent = CreateEvent {
+            let mut new_create_event: NewEvent = CreateEvent {
                 content: CreateEventContent {
                     creator: new_room.user_id.clone(),
                     federate: creation_options.federate,
@@ -125,6 +189,21 @@ impl Room {
                 user_id: new_room.user_id.clone(),
             }.try_into()?;
 
+            // ruma-events doesn't know about room versions, so the version is added separately.
+            let mut create_content: BTreeMap<String, Value> = from_str(&new_create_event.content)?;
+
+            create_content.insert("room_version".to_string(), Value::String(room.version.clone()));
+
+            if let Some((ref room_id, ref event_id)) = creation_options.predecessor {
+                let mut predecessor = BTreeMap::new();
+
+                predecessor.insert("event_id".to_string(), Value::String(event_id.to_string()));
+                predecessor.insert("room_id".to_string(), Value::String(room_id.to_string()));
+                create_content.insert("predecessor".to_string(), Value::Object(predecessor));
+            }
+
+            new_create_event.content = to_string(&create_content)?;
+
             new_events.push(new_create_event);
 
             if let Some(ref name) = creation_options.name {
@@ -221,6 +300,26 @@ impl Room {
                 }
             }
 
+            if creation_options.encrypt {
+                let mut content = BTreeMap::new();
+                let algorithm = Value::String(MEGOLM_ALGORITHM.to_string());
+
+                content.insert("algorithm".to_string(), algorithm);
+
+                let new_encryption_event: NewEvent = CustomStateEvent {
+                    content: Value::Object(content),
+                    event_id: EventId::new(homeserver_domain)?,
+                    event_type: EventType::from(ENCRYPTION_EVENT_TYPE),
+                    prev_content: None,
+                    room_id: room.id.clone(),
+                    state_key: "".to_string(),
+                    unsigned: None,
+                    user_id: new_room.user_id.clone(),
+                }.try_into()?;
+
+                new_events.push(new_encryption_event);
+            }
+
             insert(&new_events)
                 .into(events::table)
                 .execute(connection)
@@ -287,15 +386,51 @@ impl Room {
         }).map_err(ApiError::from)
     }
 
+    /// Whether or not an *m.room.encryption* event has enabled encryption in the room.
+    pub fn is_encrypted(&self, connection: &PgConnection) -> Result<bool, ApiError> {
+        let encryption_event = Event::find_state_event(
+            connection,
+            &self.id,
+            &EventType::from(ENCRYPTION_EVENT_TYPE),
+            "",
+            i64::max_value(),
+        )?;
+
+        Ok(encryption_event.is_some())
+    }
+
+    /// Whether or not the room's *m.room.guest_access* event lets guests join.
+    pub fn guests_can_join(&self, connection: &PgConnection) -> Result<bool, ApiError> {
+        let guest_access_event = Event::find_state_event(
+            connection,
+            &self.id,
+            &EventType::RoomGuestAccess,
+            "",
+            i64::max_value(),
+        )?;
+
+        let guest_access = match guest_access_event {
+            Some(event) => from_str::<Value>(&event.content)?
+                .find("guest_access")
+                .and_then(Value::as_str)
+                .map(|guest_access| guest_access == "can_join")
+                .unwrap_or(false),
+            None => false,
+        };
+
+        Ok(guest_access)
+    }
+
     /// Looks up the most recent power levels event for the room.
     ///
     /// If the room does not have a power levels event, a default one is created according to the
-    /// specification.
+    /// specification, in which the room's creator has power level 100.
     pub fn current_power_levels(&self, connection: &PgConnection)
     -> Result<PowerLevelsEventContent, ApiError> {
         match events::table
             .filter(events::room_id.eq(self.id.clone()))
-            .filter(events::state_key.eq(EventType::RoomPowerLevels.to_string()))
+            .filter(events::event_type.eq(EventType::RoomPowerLevels.to_string()))
+            .filter(events::state_key.eq(""))
             .order(events::ordering.desc())
             .first::<Event>(connection)
         {
@@ -305,22 +440,410 @@ impl Room {
                 Ok(power_levels_event.content)
             }
             Err(error) => match error {
-                DieselError::NotFound => Ok(PowerLevelsEventContent {
-                    ban: 50,
-                    events: HashMap::new(),
-                    events_default: 0,
-                    invite: 50,
-                    kick: 50,
-                    redact: 50,
-                    state_default: 0,
-                    users: HashMap::new(),
-                    users_default: 0,
-                }),
+                DieselError::NotFound => {
+                    let mut users = HashMap::new();
+
+                    users.insert(self.user_id.clone(), 100);
+
+                    Ok(PowerLevelsEventContent {
+                        ban: 50,
+                        events: HashMap::new(),
+                        events_default: 0,
+                        invite: 50,
+                        kick: 50,
+                        redact: 50,
+                        state_default: 0,
+                        users: users,
+                        users_default: 0,
+                    })
+                }
                 _ => Err(error.into()),
             },
         }
     }
 
+    /// Overrides a user's power level by sending a new power levels event on behalf of `sender`,
+    /// bypassing the usual power level checks.
+    ///
+    /// This lets server admins take over local rooms that no longer have any moderators. Like
+    /// `send_event`, it holds the room's lock while it reads and replaces the power levels, and
+    /// evicts the room from the `PowerLevelsCache`.
+    pub fn force_power_level(
+        &self,
+        connection: &PgConnection,
+        power_levels_cache: &PowerLevelsCache,
+        homeserver_domain: &str,
+        sender: &UserId,
+        user_id: &UserId,
+        power_level: u64,
+        now: i64,
+    ) -> Result<EventId, ApiError> {
+        let event_id = connection.transaction::<EventId, ApiError, _>(|| {
+            Room::lock(connection, &self.id)?;
+
+            let mut power_levels = self.current_power_levels(connection)?;
+
+            power_levels.users.insert(user_id.clone(), power_level);
+
+            let event_id = EventId::new(homeserver_domain)?;
+
+            let new_power_levels_event: NewEvent = PowerLevelsEvent {
+                content: power_levels,
+                event_id: event_id.clone(),
+                event_type: EventType::RoomPowerLevels,
+                prev_content: None,
+                room_id: self.id.clone(),
+                state_key: "".to_string(),
+                unsigned: None,
+                user_id: sender.clone(),
+            }.try_into()?;
+
+            self.save_event(connection, &new_power_levels_event, now)?;
+
+            Ok(event_id)
+        }).map_err(ApiError::from)?;
+
+        power_levels_cache.invalidate(&self.id);
+
+        Ok(event_id)
+    }
+
+    /// Saves a new event in the room after checking that its sender is allowed to send it.
+    ///
+    /// This is the path shared by message events, state events, and delayed events. Sending new
+    /// power levels evicts the room from the `PowerLevelsCache`.
+    pub fn send_event(
+        &self,
+        connection: &PgConnection,
+        membership_cache: &MembershipCache,
+        power_levels_cache: &PowerLevelsCache,
+        new_event: &NewEvent,
+        now: i64,
+    ) -> Result<(), ApiError> {
+        connection.transaction::<(), ApiError, _>(|| {
+            Room::lock(connection, &self.id)?;
+
+            let event_type = EventType::from(&new_event.event_type[..]);
+
+            self.ensure_can_send(
+                connection,
+                membership_cache,
+                power_levels_cache,
+                &new_event.user_id,
+                &event_type,
+                new_event.state_key.is_some(),
+            )?;
+
+            if event_type == EventType::RoomPowerLevels {
+                let new_content: PowerLevelsEventContent = from_str(&new_event.content)?;
+
+                power_levels_cache.load(connection, self)?
+                    .ensure_can_change(&new_event.user_id, &new_content)?;
+            }
+
+            self.save_event(connection, new_event, now)
+        }).map_err(ApiError::from)?;
+
+        if new_event.event_type == EventType::RoomPowerLevels.to_string() {
+            power_levels_cache.invalidate(&self.id);
+        }
+
+        Ok(())
+    }
+
+    /// Saves a new event in the room without checking that its sender is allowed to send it, and
+    /// schedules its expiration if it or its room asks for one.
+    ///
+    /// The caller must hold the room's lock and evict the room from the `PowerLevelsCache` if the
+    /// event changes the power levels.
+    fn save_event(&self, connection: &PgConnection, new_event: &NewEvent, now: i64)
+    -> Result<(), ApiError> {
+        // A retried event was already scheduled to expire when it was first saved.
+        if Event::insert(connection, new_event)? {
+            EventExpiration::schedule(connection, new_event, now)?;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the room with a new room of the given version, created by `user_id`.
+    ///
+    /// The new room starts with a copy of the old room's name, topic, avatar, access rules, and
+    /// power levels, and takes over its local aliases and its place in the room directory. An
+    /// *m.room.tombstone* event then points the old room's members at the new room, and the old
+    /// room's power levels are raised so that only moderators can keep talking there, if the user
+    /// is allowed to change them.
+    pub fn upgrade(
+        &mut self,
+        connection: &PgConnection,
+        membership_cache: &MembershipCache,
+        power_levels_cache: &PowerLevelsCache,
+        homeserver_domain: &str,
+        user_id: &UserId,
+        new_version: &str,
+        now: i64,
+    ) -> Result<Room, ApiError> {
+        connection.transaction::<Room, ApiError, _>(|| {
+            Room::lock(connection, &self.id)?;
+
+            let tombstone_event_type = EventType::from(TOMBSTONE_EVENT_TYPE);
+
+            self.ensure_can_send(
+                connection,
+                membership_cache,
+                power_levels_cache,
+                user_id,
+                &tombstone_event_type,
+                true,
+            )?;
+
+            let federate = match Event::find_state_event(
+                connection,
+                &self.id,
+                &EventType::RoomCreate,
+                "",
+                i64::max_value(),
+            )? {
+                Some(event) => from_str::<Value>(&event.content)?
+                    .find("m.federate")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(true),
+                None => true,
+            };
+
+            let tombstone_id = EventId::new(homeserver_domain)?;
+
+            let new_room = NewRoom {
+                id: RoomId::new(homeserver_domain)?,
+                user_id: user_id.clone(),
+                public: self.public,
+                version: new_version.to_string(),
+            };
+
+            let creation_options = CreationOptions {
+                alias: None,
+                encrypt: false,
+                federate: federate,
+                invite_list: None,
+                name: None,
+                predecessor: Some((self.id.clone(), tombstone_id.clone())),
+                preset: RoomPreset::PrivateChat,
+                topic: None,
+            };
+
+            let room = Room::create(connection, &new_room, homeserver_domain, &creation_options)?;
+
+            RoomMembership::create(connection, homeserver_domain, RoomMembershipOptions {
+                room_id: room.id.clone(),
+                user_id: user_id.clone(),
+                sender: user_id.clone(),
+                membership: "join".to_string(),
+            })?;
+
+            let mut new_events = Vec::new();
+
+            for event_type in UPGRADED_STATE_EVENT_TYPES {
+                let event_type = EventType::from(*event_type);
+
+                let content = match event_type {
+                    // The default power levels are copied too, so the new room's creator doesn't
+                    // get more power than the old room's.
+                    EventType::RoomPowerLevels => {
+                        let content = self.current_power_levels(connection)?;
+
+                        Some(to_string(&content)?)
+                    }
+                    _ => Event::find_state_event(
+                        connection,
+                        &self.id,
+                        &event_type,
+                        "",
+                        i64::max_value(),
+                    )?.map(|event| event.content),
+                };
+
+                if let Some(content) = content {
+                    new_events.push(NewEvent {
+                        event_type: event_type.to_string(),
+                        extra_content: None,
+                        id: EventId::new(homeserver_domain)?,
+                        content: content,
+                        room_id: room.id.clone(),
+                        state_key: Some("".to_string()),
+                        user_id: user_id.clone(),
+                    });
+                }
+            }
+
+            insert(&new_events)
+                .into(events::table)
+                .execute(connection)
+                .map_err(ApiError::from)?;
+
+            RoomAlias::move_to_room(connection, homeserver_domain, &self.id, &room.id, user_id)?;
+
+            let mut tombstone_content = BTreeMap::new();
+
+            tombstone_content.insert(
+                "body".to_string(),
+                Value::String("This room has been replaced".to_string()),
+            );
+            tombstone_content.insert(
+                "replacement_room".to_string(),
+                Value::String(room.id.to_string()),
+            );
+
+            let tombstone: NewEvent = CustomStateEvent {
+                content: Value::Object(tombstone_content),
+                event_id: tombstone_id,
+                event_type: tombstone_event_type,
+                prev_content: None,
+                room_id: self.id.clone(),
+                state_key: "".to_string(),
+                unsigned: None,
+                user_id: user_id.clone(),
+            }.try_into()?;
+
+            self.send_event(connection, membership_cache, power_levels_cache, &tombstone, now)?;
+
+            let mut power_levels = self.current_power_levels(connection)?;
+            let old_power_levels = PowerLevels::from(power_levels.clone());
+            let restricted_level = max(50, power_levels.users_default + 1);
+
+            power_levels.events_default = max(power_levels.events_default, restricted_level);
+            power_levels.invite = max(power_levels.invite, restricted_level);
+
+            // Users who may replace the room but not change its power levels leave them as they
+            // were, rather than failing the whole upgrade.
+            if old_power_levels.can_send_event(user_id, &EventType::RoomPowerLevels, true) &&
+                old_power_levels.ensure_can_change(user_id, &power_levels).is_ok() {
+                let new_power_levels_event: NewEvent = PowerLevelsEvent {
+                    content: power_levels,
+                    event_id: EventId::new(homeserver_domain)?,
+                    event_type: EventType::RoomPowerLevels,
+                    prev_content: None,
+                    room_id: self.id.clone(),
+                    state_key: "".to_string(),
+                    unsigned: None,
+                    user_id: user_id.clone(),
+                }.try_into()?;
+
+                self.send_event(
+                    connection,
+                    membership_cache,
+                    power_levels_cache,
+                    &new_power_levels_event,
+                    now,
+                )?;
+            }
+
+            self.set_public(connection, false)?;
+
+            Ok(room)
+        }).map_err(ApiError::from)
+    }
+
+    /// Sends an *m.room.redaction* event for `event` and removes the redacted event's content.
+    ///
+    /// Users can always redact their own events, but need the room's `redact` power level to
+    /// redact anyone else's.
+    pub fn redact_event(
+        &self,
+        connection: &PgConnection,
+        membership_cache: &MembershipCache,
+        power_levels_cache: &PowerLevelsCache,
+        redaction: &NewEvent,
+        event: &Event,
+        now: i64,
+    ) -> Result<(), ApiError> {
+        connection.transaction::<(), ApiError, _>(|| {
+            Room::lock(connection, &self.id)?;
+
+            let power_levels = power_levels_cache.load(connection, self)?;
+
+            if !power_levels.can_redact(&redaction.user_id, &event.user_id) {
+                return Err(ApiError::unauthorized(
+                    Some("Insufficient power level to redact this event.")
+                ));
+            }
+
+            self.send_event(connection, membership_cache, power_levels_cache, redaction, now)?;
+
+            event.redact(connection)
+        }).map_err(ApiError::from)
+    }
+
+    /// Checks that the user has joined the room and has a high enough power level to send an
+    /// event of the given type.
+    pub fn ensure_can_send(
+        &self,
+        connection: &PgConnection,
+        membership_cache: &MembershipCache,
+        power_levels_cache: &PowerLevelsCache,
+        user_id: &UserId,
+        event_type: &EventType,
+        is_state: bool,
+    ) -> Result<(), ApiError> {
+        if !membership_cache.is_joined(connection, &self.id, user_id)? {
+            return Err(ApiError::unauthorized(
+                Some("You must join the room before sending events to it.")
+            ));
+        }
+
+        let power_levels = power_levels_cache.load(connection, self)?;
+
+        if !power_levels.can_send_event(user_id, event_type, is_state) {
+            return Err(ApiError::unauthorized(
+                Some("Insufficient power level to create this event.")
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Estimates how expensive the room is to take part in, from the size of its current state
+    /// and its number of joined members. Each 500 state events or members add 1 to the score.
+    pub fn complexity(&self, connection: &PgConnection) -> Result<f64, ApiError> {
+        let state_events: i64 = events::table
+            .select(sql::<BigInt>("COUNT(DISTINCT (event_type, state_key))"))
+            .filter(events::room_id.eq(self.id.clone()))
+            .filter(events::state_key.is_not_null())
+            .first(connection)?;
+
+        let joined_members: i64 = room_memberships::table
+            .select(count_star())
+            .filter(room_memberships::room_id.eq(self.id.clone()))
+            .filter(room_memberships::membership.eq("join"))
+            .first(connection)?;
+
+        Ok((state_events + joined_members) as f64 / 500.0)
+    }
+
+    /// Lists the room in the public room directory, or removes it from the directory.
+    pub fn set_public(&mut self, connection: &PgConnection, public: bool) -> Result<(), ApiError> {
+        update(rooms::table.find(&self.id))
+            .set(rooms::public.eq(public))
+            .execute(connection)?;
+
+        self.public = public;
+
+        Ok(())
+    }
+
+    /// Takes the room's lock, which is held until the current transaction ends, so processes
+    /// changing the same room take turns.
+    ///
+    /// Anything that checks the room's state and then adds an event based on it takes the lock
+    /// before checking, so the state can't change in between. Must be called in a transaction,
+    /// since outside of one the lock is released as soon as it is taken.
+    pub fn lock(connection: &PgConnection, room_id: &RoomId) -> Result<(), ApiError> {
+        connection
+            .execute(&format!("SELECT pg_advisory_xact_lock({})", room_lock_key(room_id)))
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
     /// Look up a `Room` given the `RoomId`.
     pub fn find(connection: &PgConnection, room_id: &RoomId)
     -> Result<Room, ApiError> {
@@ -338,3 +861,11 @@ impl Room {
             })
     }
 }
+
+/// The key of a room's advisory lock: the first eight bytes of the SHA-256 digest of its ID, so
+/// the same room gets the same key in every process.
+fn room_lock_key(room_id: &RoomId) -> i64 {
+    let digest = digest::digest(&digest::SHA256, room_id.to_string().as_bytes());
+
+    digest.as_ref()[..8].iter().fold(0, |key, &byte| (key << 8) | byte as i64)
+}
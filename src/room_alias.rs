@@ -11,6 +11,7 @@ use diesel::{
     ExecuteDsl,
     insert,
     delete,
+    update,
 };
 use diesel::pg::PgConnection;
 use diesel::pg::data_types::PgTimestamp;
@@ -60,6 +61,8 @@ impl RoomAlias {
     pub fn create(connection: &PgConnection, homeserver_domain: &str, new_room_alias: &NewRoomAlias)
     -> Result<RoomAlias, ApiError> {
         connection.transaction(|| {
+            Room::lock(connection, &new_room_alias.room_id)?;
+
             let room_result = rooms::table
                 .find(new_room_alias.room_id.to_string())
                 .first::<Room>(&*connection)
@@ -78,21 +81,13 @@ impl RoomAlias {
             let mut ids: Vec<RoomAliasId> = aliases.iter().map(|a| a.alias.clone()).collect();
             ids.push(new_room_alias.alias.clone());
 
-            let new_room_alias_event: NewEvent = AliasesEvent {
-                content: AliasesEventContent { aliases: ids },
-                event_id: EventId::new(homeserver_domain)?,
-                event_type: EventType::RoomAliases,
-                prev_content: None,
-                room_id: new_room_alias.room_id.clone(),
-                state_key: homeserver_domain.to_string(),
-                unsigned: None,
-                user_id: new_room_alias.user_id.clone(),
-            }.try_into()?;
-
-            insert(&new_room_alias_event)
-                .into(events::table)
-                .execute(connection)
-                .map_err(ApiError::from)?;
+            send_aliases_event(
+                connection,
+                homeserver_domain,
+                &new_room_alias.room_id,
+                &new_room_alias.user_id,
+                ids,
+            )?;
 
             insert(new_room_alias)
                 .into(room_aliases::table)
@@ -118,7 +113,7 @@ impl RoomAlias {
     }
 
     /// Return all aliases associated with the given `RoomId`.
-    fn find_by_room_id(connection: &PgConnection, room_id: &RoomId)
+    pub fn find_by_room_id(connection: &PgConnection, room_id: &RoomId)
     -> Result<Vec<RoomAlias>, ApiError> {
         let aliases: Vec<RoomAlias> = room_aliases::table
             .filter(room_aliases::room_id.eq(room_id))
@@ -128,15 +123,171 @@ impl RoomAlias {
         Ok(aliases)
     }
 
-    /// Deletes a room alias in the database.
-    pub fn delete(connection: &PgConnection, alias_id: &RoomAliasId, user_id: &UserId)
-                  -> Result<usize, ApiError> {
-        let alias = room_aliases::table
-            .filter(room_aliases::alias.eq(alias_id.to_string()))
-            .filter(room_aliases::user_id.eq(user_id.to_string()));
+    /// Deletes the alias and updates the room's `m.room.aliases` event to list the aliases that
+    /// remain. The caller must check that the user may delete it.
+    pub fn delete(&self, connection: &PgConnection, homeserver_domain: &str, user_id: &UserId)
+    -> Result<(), ApiError> {
+        connection.transaction(|| {
+            Room::lock(connection, &self.room_id)?;
+
+            delete(room_aliases::table.find(&self.alias)).execute(connection)?;
+
+            let ids = RoomAlias::find_by_room_id(connection, &self.room_id)?
+                .into_iter()
+                .map(|room_alias| room_alias.alias)
+                .collect();
+
+            send_aliases_event(connection, homeserver_domain, &self.room_id, user_id, ids)
+        }).map_err(ApiError::from)
+    }
+
+    /// Moves all of this server's aliases for the room `from` to the room `to`, for room
+    /// upgrades, and updates both rooms' `m.room.aliases` events.
+    pub fn move_to_room(
+        connection: &PgConnection,
+        homeserver_domain: &str,
+        from: &RoomId,
+        to: &RoomId,
+        user_id: &UserId,
+    ) -> Result<(), ApiError> {
+        connection.transaction(|| {
+            Room::lock(connection, from)?;
+            Room::lock(connection, to)?;
+
+            let ids: Vec<RoomAliasId> = RoomAlias::find_by_room_id(connection, from)?
+                .into_iter()
+                .map(|room_alias| room_alias.alias)
+                .collect();
+
+            if ids.is_empty() {
+                return Ok(());
+            }
+
+            update(room_aliases::table.filter(room_aliases::room_id.eq(from)))
+                .set(room_aliases::room_id.eq(to))
+                .execute(connection)?;
+
+            send_aliases_event(connection, homeserver_domain, from, user_id, Vec::new())?;
+            send_aliases_event(connection, homeserver_domain, to, user_id, ids)
+        }).map_err(ApiError::from)
+    }
+}
+
+/// Saves a new `m.room.aliases` event listing this server's aliases for the room.
+///
+/// The caller must take the room's lock before reading the aliases it passes in, so that another
+/// alias change can't land between the read and the event.
+fn send_aliases_event(
+    connection: &PgConnection,
+    homeserver_domain: &str,
+    room_id: &RoomId,
+    user_id: &UserId,
+    aliases: Vec<RoomAliasId>,
+) -> Result<(), ApiError> {
+    let aliases_event: NewEvent = AliasesEvent {
+        content: AliasesEventContent { aliases: aliases },
+        event_id: EventId::new(homeserver_domain)?,
+        event_type: EventType::RoomAliases,
+        prev_content: None,
+        room_id: room_id.clone(),
+        state_key: homeserver_domain.to_string(),
+        unsigned: None,
+        user_id: user_id.clone(),
+    }.try_into()?;
+
+    insert(&aliases_event)
+        .into(events::table)
+        .execute(connection)
+        .map(|_| ())
+        .map_err(ApiError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::thread;
+    use std::time::Duration;
+
+    use diesel::{Connection, ExecuteDsl, ExpressionMethods, FilterDsl, FindDsl, delete, insert};
+    use diesel::pg::PgConnection;
+    use ruma_events::EventType;
+    use ruma_identifiers::{RoomAliasId, RoomId, UserId};
+    use serde_json::{Value, from_str};
+
+    use event::Event;
+    use room::{NewRoom, Room};
+    use schema::{events, rooms};
+    use test::{DATABASE_URL, Test};
+    use super::{NewRoomAlias, RoomAlias};
+
+    #[test]
+    fn concurrent_deletes_leave_no_deleted_aliases_listed() {
+        // The test server's connections never commit, so concurrent writers need their own.
+        Test::new();
+
+        let connection = PgConnection::establish(DATABASE_URL).unwrap();
+        let room_id = RoomId::new("ruma.test").unwrap();
+        let user_id = UserId::try_from("@carl:ruma.test").unwrap();
+        let localpart = room_id.to_string()[1..].split(':').next().unwrap().to_lowercase();
+
+        insert(&NewRoom {
+            id: room_id.clone(),
+            user_id: user_id.clone(),
+            public: false,
+            version: "1".to_string(),
+        }).into(rooms::table).execute(&connection).unwrap();
+
+        let aliases: Vec<RoomAliasId> = ["first", "second"].iter().map(|name| {
+            let alias = RoomAliasId::try_from(
+                &format!("#{}-{}:ruma.test", name, localpart)[..]
+            ).unwrap();
+
+            RoomAlias::create(&connection, "ruma.test", &NewRoomAlias {
+                alias: alias.clone(),
+                room_id: room_id.clone(),
+                user_id: user_id.clone(),
+                servers: vec!["ruma.test".to_string()],
+            }).unwrap();
+
+            alias
+        }).collect();
+
+        // Both deletes wait on the room's lock while it's held here, and then race for it.
+        let lock_holder = PgConnection::establish(DATABASE_URL).unwrap();
+
+        lock_holder.execute("BEGIN").unwrap();
+        Room::lock(&lock_holder, &room_id).unwrap();
+
+        let writers: Vec<_> = aliases.into_iter().map(|alias| {
+            let user_id = user_id.clone();
+
+            thread::spawn(move || {
+                let connection = PgConnection::establish(DATABASE_URL).unwrap();
+                let room_alias = RoomAlias::find_by_alias(&connection, &alias).unwrap();
+
+                room_alias.delete(&connection, "ruma.test", &user_id).unwrap();
+            })
+        }).collect();
+
+        thread::sleep(Duration::from_millis(100));
+        lock_holder.execute("COMMIT").unwrap();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let aliases_event = Event::find_state_event(
+            &connection,
+            &room_id,
+            &EventType::RoomAliases,
+            "ruma.test",
+            i64::max_value(),
+        ).unwrap().unwrap();
+        let content: Value = from_str(&aliases_event.content).unwrap();
+
+        assert_eq!(content.find("aliases"), Some(&Value::Array(Vec::new())));
 
-        delete(alias)
-            .execute(connection)
-            .map_err(ApiError::from)
+        delete(events::table.filter(events::room_id.eq(&room_id))).execute(&connection).unwrap();
+        delete(rooms::table.find(room_id.to_string())).execute(&connection).unwrap();
     }
 }
@@ -0,0 +1,203 @@
+//! Exporting a room's full history, for compliance and backups.
+//!
+//! An export holds the room's current state and every event in the order the server received
+//! them, as clients see them. Redacted and expired events are exported as they are now, without
+//! their content. Media is referenced by `mxc://` URI: the URIs found in the events are listed
+//! so the files can be fetched alongside the archive. `room_import` recreates a room from an
+//! export.
+
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fs::{File, create_dir_all};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use diesel::Connection;
+use diesel::pg::PgConnection;
+use ruma_identifiers::RoomId;
+use serde_json::{Value, from_str, to_string_pretty};
+
+use clock::{Clock, SystemClock};
+use config::Config;
+use error::{ApiError, CliError};
+use event::Event;
+use room::Room;
+
+/// The number of events loaded from the database at a time.
+const PAGE_SIZE: i64 = 1000;
+
+/// The scheme of media URIs.
+const MXC_SCHEME: &'static str = "mxc://";
+
+/// A room's state and history, ready to be written out.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RoomExport {
+    /// The room's ID.
+    pub room_id: String,
+    /// The room's version.
+    pub version: String,
+    /// When the export was made, in milliseconds since the Unix epoch.
+    pub exported_at: i64,
+    /// The room's current state events.
+    pub state: Vec<Value>,
+    /// Every event in the room, oldest first.
+    pub events: Vec<Value>,
+    /// The `mxc://` URIs of the media the events refer to, sorted and without duplicates.
+    pub media: Vec<String>,
+}
+
+/// The part of an export written to `room.json`, without the events.
+#[derive(Debug, Serialize)]
+struct RoomArchive<'a> {
+    exported_at: i64,
+    media: &'a [String],
+    room_id: &'a str,
+    state: &'a [Value],
+    version: &'a str,
+}
+
+/// `room.json` as read back from an export directory.
+#[derive(Debug, Deserialize)]
+struct StoredRoomArchive {
+    exported_at: i64,
+    media: Vec<String>,
+    room_id: String,
+    state: Vec<Value>,
+    version: String,
+}
+
+impl RoomExport {
+    /// Collects the room's state and history.
+    ///
+    /// Everything is read in one transaction, so the state matches the last event exported.
+    pub fn collect(connection: &PgConnection, room_id: &RoomId, now: i64)
+    -> Result<RoomExport, ApiError> {
+        connection.transaction::<RoomExport, ApiError, _>(|| {
+            let room = Room::find(connection, room_id)?;
+
+            let mut events = Vec::new();
+            let mut media = BTreeSet::new();
+            let mut from = i64::min_value();
+
+            loop {
+                let page = Event::find_page_by_room(
+                    connection,
+                    room_id,
+                    from,
+                    None,
+                    false,
+                    PAGE_SIZE,
+                )?;
+
+                for event in &page {
+                    let json = event.to_json()?;
+
+                    if let Some(content) = json.find("content") {
+                        find_media(content, &mut media);
+                    }
+
+                    events.push(json);
+                }
+
+                match page.last() {
+                    Some(event) if page.len() as i64 == PAGE_SIZE => from = event.ordering,
+                    _ => break,
+                }
+            }
+
+            let mut state = Vec::new();
+
+            for event in Event::find_state_by_room(connection, room_id, None, i64::max_value())? {
+                state.push(event.to_json()?);
+            }
+
+            Ok(RoomExport {
+                room_id: room.id.to_string(),
+                version: room.version,
+                exported_at: now,
+                state: state,
+                events: events,
+                media: media.into_iter().collect(),
+            })
+        }).map_err(ApiError::from)
+    }
+
+    /// Writes the export to a directory, creating it if needed: the room's details, state, and
+    /// media URIs go in `room.json`, and the events in `events.json`.
+    pub fn write_to(&self, directory: &Path) -> Result<(), CliError> {
+        create_dir_all(directory)?;
+
+        let archive = RoomArchive {
+            exported_at: self.exported_at,
+            media: &self.media,
+            room_id: &self.room_id,
+            state: &self.state,
+            version: &self.version,
+        };
+
+        let mut room_file = File::create(directory.join("room.json"))?;
+        room_file.write_all(to_string_pretty(&archive)?.as_bytes())?;
+
+        let mut events_file = File::create(directory.join("events.json"))?;
+        events_file.write_all(to_string_pretty(&self.events)?.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Reads an export back from a directory written by `write_to`.
+    pub fn read_from(directory: &Path) -> Result<RoomExport, CliError> {
+        let mut contents = String::new();
+
+        File::open(directory.join("room.json"))?.read_to_string(&mut contents)?;
+
+        let archive: StoredRoomArchive = from_str(&contents)?;
+
+        contents.clear();
+        File::open(directory.join("events.json"))?.read_to_string(&mut contents)?;
+
+        Ok(RoomExport {
+            room_id: archive.room_id,
+            version: archive.version,
+            exported_at: archive.exported_at,
+            state: archive.state,
+            events: from_str(&contents)?,
+            media: archive.media,
+        })
+    }
+}
+
+/// Exports a room from the database described by `config` to `directory`, for the
+/// `export-room` command.
+pub fn export_room(config: &Config, room_id: &str, directory: &Path)
+-> Result<RoomExport, CliError> {
+    let room_id = RoomId::try_from(room_id)
+        .map_err(|_| CliError::new(format!("{} is not a valid room ID.", room_id)))?;
+
+    let connection = PgConnection::establish(&config.postgres_url)?;
+
+    let export = RoomExport::collect(&connection, &room_id, SystemClock.now_millis())?;
+
+    export.write_to(directory)?;
+
+    Ok(export)
+}
+
+/// Adds every `mxc://` URI in a JSON value to `media`.
+fn find_media(value: &Value, media: &mut BTreeSet<String>) {
+    match *value {
+        Value::String(ref string) if string.starts_with(MXC_SCHEME) => {
+            media.insert(string.clone());
+        }
+        Value::Array(ref values) => {
+            for value in values {
+                find_media(value, media);
+            }
+        }
+        Value::Object(ref map) => {
+            for value in map.values() {
+                find_media(value, media);
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,225 @@
+//! Recreating a room from an export, for moving communities between servers.
+//!
+//! The room gets a new ID on this server, with the importing user as its creator and only member.
+//! Messages and other timeline events keep their original senders and timestamps, but the senders
+//! don't become members: their events are history from before the room moved. Member events, and
+//! the state a new room sets up for itself like power levels and join rules, are left out because
+//! they describe the old room. The room's name, topic, and encryption carry over.
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::path::Path;
+
+use diesel::Connection;
+use diesel::pg::PgConnection;
+use ruma_events::EventType;
+use ruma_identifiers::{EventId, RoomId, UserId};
+use serde_json::{Value, to_string};
+
+use clock::{Clock, SystemClock};
+use config::Config;
+use error::{ApiError, CliError};
+use event::{Event, NewEvent};
+use room::{CreationOptions, NewRoom, Room, RoomPreset};
+use room_export::RoomExport;
+use room_membership::{RoomMembership, RoomMembershipOptions};
+use user::User;
+
+/// State event types the new room sets up itself instead of taking from the export.
+const REPLACED_STATE_TYPES: &'static [&'static str] = &[
+    "m.room.aliases",
+    "m.room.canonical_alias",
+    "m.room.create",
+    "m.room.encryption",
+    "m.room.guest_access",
+    "m.room.history_visibility",
+    "m.room.join_rules",
+    "m.room.name",
+    "m.room.power_levels",
+    "m.room.topic",
+];
+
+/// The outcome of importing a room.
+#[derive(Debug, Serialize)]
+pub struct RoomImport {
+    /// The new room's ID.
+    pub room_id: String,
+    /// The number of events carried over.
+    pub imported: usize,
+    /// The number of events left out.
+    pub skipped: usize,
+}
+
+impl RoomImport {
+    /// Creates a new room from the export, owned by `creator`.
+    ///
+    /// Everything happens in one transaction, so a failed import leaves no partial room behind.
+    pub fn import(
+        connection: &PgConnection,
+        config: &Config,
+        creator: &UserId,
+        export: &RoomExport,
+        now: i64,
+    ) -> Result<RoomImport, ApiError> {
+        if !config.supported_room_versions.contains(&export.version) {
+            return Err(ApiError::unsupported_room_version(Some(
+                &format!("This server does not support room version {}.", export.version)
+            )));
+        }
+
+        connection.transaction::<RoomImport, ApiError, _>(|| {
+            User::find_by_uid(connection, creator)?;
+
+            let room_id = RoomId::new(&config.domain)?;
+            let mut event_ids: HashMap<String, EventId> = HashMap::new();
+            let mut skipped = 0;
+
+            // The history goes in first, so the state the new room is created with is current.
+            for event in &export.events {
+                match import_event(config, &room_id, event, &event_ids, now)? {
+                    Some((old_event_id, new_event, origin_server_ts)) => {
+                        Event::import(connection, &new_event, origin_server_ts)?;
+
+                        event_ids.insert(old_event_id, new_event.id);
+                    }
+                    None => skipped += 1,
+                }
+            }
+
+            let state_content = |event_type: &str, key: &str| {
+                export.state
+                    .iter()
+                    .find(|event| {
+                        event.find("type").and_then(Value::as_str) == Some(event_type)
+                    })
+                    .and_then(|event| event.find_path(&["content", key]))
+                    .cloned()
+            };
+
+            let new_room = NewRoom {
+                id: room_id.clone(),
+                user_id: creator.clone(),
+                public: false,
+                version: export.version.clone(),
+            };
+
+            let creation_options = CreationOptions {
+                alias: None,
+                encrypt: state_content("m.room.encryption", "algorithm").is_some(),
+                federate: state_content("m.room.create", "m.federate")
+                    .and_then(|federate| federate.as_bool())
+                    .unwrap_or(true),
+                invite_list: None,
+                name: state_content("m.room.name", "name")
+                    .and_then(|name| name.as_str().map(str::to_string)),
+                predecessor: None,
+                preset: RoomPreset::PrivateChat,
+                topic: state_content("m.room.topic", "topic")
+                    .and_then(|topic| topic.as_str().map(str::to_string)),
+            };
+
+            Room::create(connection, &new_room, &config.domain, &creation_options)?;
+
+            RoomMembership::create(connection, &config.domain, RoomMembershipOptions {
+                room_id: room_id.clone(),
+                user_id: creator.clone(),
+                sender: creator.clone(),
+                membership: "join".to_string(),
+            })?;
+
+            Ok(RoomImport {
+                room_id: room_id.to_string(),
+                imported: event_ids.len(),
+                skipped: skipped,
+            })
+        }).map_err(ApiError::from)
+    }
+}
+
+/// Imports the export in `directory` into the database described by `config`, for the
+/// `import-room` command.
+pub fn import_room(config: &Config, directory: &Path, creator: &str)
+-> Result<RoomImport, CliError> {
+    let creator = UserId::try_from(creator)
+        .map_err(|_| CliError::new(format!("{} is not a valid user ID.", creator)))?;
+
+    let export = RoomExport::read_from(directory)?;
+
+    let connection = PgConnection::establish(&config.postgres_url)?;
+
+    let import = RoomImport::import(
+        &connection,
+        config,
+        &creator,
+        &export,
+        SystemClock.now_millis(),
+    )?;
+
+    Ok(import)
+}
+
+/// Converts an exported event into a new event in the room, along with its old ID and the time
+/// it was sent. Returns `None` for events that are left out: member events and replaced state,
+/// events that aren't complete, and redactions of events that weren't imported.
+fn import_event(
+    config: &Config,
+    room_id: &RoomId,
+    event: &Value,
+    event_ids: &HashMap<String, EventId>,
+    now: i64,
+) -> Result<Option<(String, NewEvent, i64)>, ApiError> {
+    let field = |name| event.find(name).and_then(Value::as_str);
+
+    let fields = (field("event_id"), field("type"), field("sender"));
+
+    let (old_event_id, event_type, sender) = match fields {
+        (Some(old_event_id), Some(event_type), Some(sender)) => (old_event_id, event_type, sender),
+        _ => return Ok(None),
+    };
+
+    let state_key = field("state_key");
+
+    if let Some(state_key) = state_key {
+        if !state_key.is_empty() || REPLACED_STATE_TYPES.contains(&event_type) {
+            return Ok(None);
+        }
+    }
+
+    let sender = match UserId::try_from(sender) {
+        Ok(sender) => sender,
+        Err(_) => return Ok(None),
+    };
+
+    let extra_content = if event_type == EventType::RoomRedaction.to_string() {
+        match field("redacts").and_then(|redacts| event_ids.get(redacts)) {
+            Some(redacts) => {
+                let mut extra_content = BTreeMap::new();
+                extra_content.insert("redacts".to_string(), Value::String(redacts.to_string()));
+
+                Some(to_string(&extra_content)?)
+            }
+            None => return Ok(None),
+        }
+    } else {
+        None
+    };
+
+    let content = match event.find("content") {
+        Some(content) => to_string(content)?,
+        None => "{}".to_string(),
+    };
+
+    let new_event = NewEvent {
+        content: content,
+        event_type: event_type.to_string(),
+        extra_content: extra_content,
+        id: EventId::new(&config.domain)?,
+        room_id: room_id.clone(),
+        state_key: state_key.map(str::to_string),
+        user_id: sender,
+    };
+
+    let origin_server_ts = event.find("origin_server_ts").and_then(Value::as_i64).unwrap_or(now);
+
+    Ok(Some((old_event_id.to_string(), new_event, origin_server_ts)))
+}
@@ -10,6 +10,7 @@ use diesel::{
     LoadDsl,
     FilterDsl,
     FindDsl,
+    OrderDsl,
     SaveChangesDsl,
     SelectDsl,
     insert,
@@ -32,6 +33,7 @@ use serde_json::{Value, from_value};
 
 use error::ApiError;
 use event::{NewEvent, Event};
+use power_levels::{PowerLevels, PowerLevelsCache};
 use profile::Profile;
 use room::Room;
 use schema::{events, room_memberships};
@@ -81,6 +83,8 @@ pub struct RoomMembership {
     pub membership: String,
     /// The time the room was created.
     pub created_at: PgTimestamp,
+    /// Whether or not the user forgot the room after leaving it, which hides it from them.
+    pub forgotten: bool,
 }
 
 impl Identifiable for RoomMembership {
@@ -100,49 +104,46 @@ impl RoomMembership {
     /// Creates a new `RoomMembership` in the database.
     pub fn create(connection: &PgConnection, homeserver_domain: &str, options: RoomMembershipOptions)
     -> Result<RoomMembership, ApiError> {
-        let join_rules_event = Event::find_room_join_rules_by_room_id(
-            &connection,
-            options.clone().room_id,
-        )?;
+        connection.transaction::<RoomMembership, ApiError, _>(|| {
+            Room::lock(connection, &options.room_id)?;
 
-        let room = Room::find(connection, &options.room_id)?;
+            let join_rules_event = Event::find_room_join_rules_by_room_id(
+                &connection,
+                options.clone().room_id,
+            )?;
 
-        // Only the creator of the room can join an invite-only room, without an invite.
-        if options.sender != room.user_id {
-            if options.membership == "join" && join_rules_event.content.join_rule == JoinRule::Invite {
-                return Err(ApiError::unauthorized(Some("You are not invited to this room")));
-            }
+            let room = Room::find(connection, &options.room_id)?;
 
-            let power_levels = room.current_power_levels(connection)?;
-            let user_power_level = power_levels
-                .users
-                .get(&options.sender)
-                .unwrap_or(&power_levels.users_default);
+            // Only the creator of the room can join an invite-only room, without an invite.
+            if options.sender != room.user_id {
+                if options.membership == "join" &&
+                    join_rules_event.content.join_rule == JoinRule::Invite {
+                    return Err(ApiError::unauthorized(Some("You are not invited to this room")));
+                }
+
+                let power_levels = PowerLevels::load(connection, &room)?;
 
-            if options.membership == "invite" {
-                if power_levels.invite > *user_power_level {
+                if options.membership == "invite" && !power_levels.can_invite(&options.sender) {
                     return Err(ApiError::unauthorized(Some("Insufficient power level to invite")));
                 }
             }
-        }
 
-        let profile = Profile::find_by_uid(connection, options.user_id.clone())?;
+            let profile = Profile::find_by_uid(connection, options.user_id.clone())?;
 
-        let new_member_event = RoomMembership::create_new_room_member_event(
-            homeserver_domain,
-            &options,
-            profile,
-        )?;
+            let new_member_event = RoomMembership::create_new_room_member_event(
+                homeserver_domain,
+                &options,
+                profile,
+            )?;
 
-        let new_room_membership = NewRoomMembership {
-            event_id: new_member_event.id.clone(),
-            room_id: options.room_id.clone(),
-            user_id: options.user_id.clone(),
-            sender: options.sender.clone(),
-            membership: options.membership.clone(),
-        };
+            let new_room_membership = NewRoomMembership {
+                event_id: new_member_event.id.clone(),
+                room_id: options.room_id.clone(),
+                user_id: options.user_id.clone(),
+                sender: options.sender.clone(),
+                membership: options.membership.clone(),
+            };
 
-        connection.transaction::<RoomMembership, ApiError, _>(|| {
             insert(&new_member_event)
                 .into(events::table)
                 .execute(connection)
@@ -171,6 +172,38 @@ impl RoomMembership {
         }
     }
 
+    /// Returns how much of a room's history a user may see.
+    ///
+    /// Joined users can see everything, so `None` is returned. Users who left or were banned can
+    /// see the room up to and including their membership event, whose ordering is returned, until
+    /// they forget it. Anyone else is refused.
+    pub fn visible_until(connection: &PgConnection, room_id: &RoomId, user_id: &UserId)
+    -> Result<Option<i64>, ApiError> {
+        match RoomMembership::find(connection, room_id, user_id)? {
+            Some(ref membership) if membership.membership == "join" => Ok(None),
+            Some(ref membership) if membership.forgotten => {
+                Err(ApiError::unauthorized(Some("You have forgotten the room.")))
+            }
+            Some(ref membership) if membership.membership == "leave" ||
+                membership.membership == "ban" => {
+                Ok(Event::find_by_ids(connection, &[membership.event_id.clone()])?
+                    .first()
+                    .map(|event| event.ordering))
+            }
+            _ => Err(ApiError::unauthorized(Some("You must have joined the room to see it."))),
+        }
+    }
+
+    /// Return all `RoomMembership`s for the given `RoomId`, in the order they were created.
+    pub fn find_by_room(connection: &PgConnection, room_id: &RoomId)
+    -> Result<Vec<RoomMembership>, ApiError> {
+        room_memberships::table
+            .filter(room_memberships::room_id.eq(room_id))
+            .order(room_memberships::created_at.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
     /// Return `RoomMembership`'s for given `UserId`.
     pub fn find_by_uid(connection: &PgConnection, user_id: UserId) -> Result<Vec<RoomMembership>, ApiError> {
         let room_memberships: Vec<RoomMembership> = room_memberships::table
@@ -184,19 +217,65 @@ impl RoomMembership {
         Ok(room_memberships)
     }
 
-    /// Update an existing `RoomMembership` entry or insert a new one.
-    pub fn upsert(connection: &PgConnection, domain: &str, options: RoomMembershipOptions)
-    -> Result<RoomMembership, ApiError> {
-        let room_membership = RoomMembership::find(
-            connection,
-            &options.room_id,
-            &options.user_id
-        )?;
+    /// Changes a user's membership in a room, after checking that the sender is allowed to make
+    /// the change.
+    ///
+    /// Joining when already joined and inviting a user who is already invited leave the existing
+    /// membership untouched.
+    pub fn transition(
+        connection: &PgConnection,
+        power_levels_cache: &PowerLevelsCache,
+        domain: &str,
+        options: RoomMembershipOptions,
+    ) -> Result<RoomMembership, ApiError> {
+        connection.transaction::<RoomMembership, ApiError, _>(|| {
+            Room::lock(connection, &options.room_id)?;
+
+            let room = Room::find(connection, &options.room_id)?;
+            let current = RoomMembership::find(connection, &options.room_id, &options.user_id)?;
+
+            ensure_transition_allowed(
+                connection,
+                power_levels_cache,
+                &room,
+                current.as_ref().map(|entry| &entry.membership[..]),
+                &options,
+            )?;
+
+            match current {
+                Some(mut entry) => {
+                    if entry.membership == options.membership && entry.membership != "leave" {
+                        return Ok(entry);
+                    }
+
+                    entry.update(connection, domain, options)
+                }
+                None => RoomMembership::create(connection, domain, options),
+            }
+        }).map_err(ApiError::from)
+    }
+
+    /// Hides a room the user has left or been banned from, so that it no longer appears in their
+    /// sync and its history is no longer visible to them. The room's events are kept.
+    pub fn forget(connection: &PgConnection, room_id: &RoomId, user_id: &UserId)
+    -> Result<(), ApiError> {
+        let membership = match RoomMembership::find(connection, room_id, user_id)? {
+            Some(membership) => membership,
+            None => return Err(ApiError::not_found(Some("You have never been in the room."))),
+        };
 
-        match room_membership {
-            Some(mut entry) => entry.update(connection, domain, options),
-            None => RoomMembership::create(connection, domain, options)
+        if membership.membership != "leave" && membership.membership != "ban" {
+            return Err(ApiError::invalid_param(
+                "room_id",
+                "You must leave the room before forgetting it.",
+            ));
         }
+
+        update(room_memberships::table.find(membership.event_id))
+            .set(room_memberships::forgotten.eq(true))
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
     }
 
     /// Update a `RoomMembership` entry using new `RoomMembershipOptions`.
@@ -214,8 +293,12 @@ impl RoomMembership {
 
         self.membership = options.membership.clone();
         self.sender = options.sender.clone();
+        // A new membership brings a forgotten room back.
+        self.forgotten = false;
 
         connection.transaction::<RoomMembership, ApiError, _>(|| {
+            Room::lock(connection, &self.room_id)?;
+
             insert(&event)
                 .into(events::table)
                 .execute(connection)
@@ -259,9 +342,9 @@ impl RoomMembership {
             invite_room_state: None,
             prev_content: None,
             room_id: options.room_id.clone(),
-            state_key: format!("@{}:{}", options.user_id.clone(), &homeserver_domain),
+            state_key: options.user_id.to_string(),
             unsigned: None,
-            user_id: options.user_id.clone(),
+            user_id: options.sender.clone(),
         }.try_into()?;
 
         Ok(new_member_event)
@@ -284,3 +367,83 @@ impl RoomMembership {
         events.into_iter() .map(TryInto::try_into). collect()
     }
 }
+
+/// Checks a membership change against the rules for each membership state.
+///
+/// Users can only join rooms themselves, and only public rooms unless they were invited. Inviting
+/// requires the `invite` power level. Kicking, banning, and unbanning other users require the
+/// `kick` or `ban` power level, as well as a higher power level than the affected user.
+fn ensure_transition_allowed(
+    connection: &PgConnection,
+    power_levels_cache: &PowerLevelsCache,
+    room: &Room,
+    current: Option<&str>,
+    options: &RoomMembershipOptions,
+) -> Result<(), ApiError> {
+    let is_self = options.sender == options.user_id;
+    let sender_joined = match RoomMembership::find(connection, &room.id, &options.sender)? {
+        Some(membership) => membership.membership == "join",
+        None => false,
+    };
+
+    let power_levels = power_levels_cache.load(connection, room)?;
+    let ensure_can_moderate = |allowed: bool| {
+        if !sender_joined {
+            Err(ApiError::unauthorized(Some("You must join the room first.")))
+        } else if !allowed {
+            Err(ApiError::unauthorized(Some("Insufficient power level.")))
+        } else {
+            Ok(())
+        }
+    };
+
+    match (&options.membership[..], current) {
+        ("join", _) if !is_self => {
+            Err(ApiError::unauthorized(Some("Users can only join rooms themselves.")))
+        }
+        ("join", Some("ban")) => Err(ApiError::unauthorized(Some("You are banned from this room"))),
+        ("join", Some("invite")) | ("join", Some("join")) => Ok(()),
+        ("join", _) => {
+            let join_rules_event = Event::find_room_join_rules_by_room_id(
+                connection,
+                room.id.clone(),
+            )?;
+
+            // Only the creator of the room can join an invite-only room without an invite.
+            if join_rules_event.content.join_rule == JoinRule::Invite &&
+                options.sender != room.user_id {
+                return Err(ApiError::unauthorized(Some("You are not invited to this room")));
+            }
+
+            Ok(())
+        }
+        ("invite", _) if !sender_joined => {
+            Err(ApiError::unauthorized(Some("The inviter hasn't joined the room yet")))
+        }
+        ("invite", Some("ban")) => {
+            Err(ApiError::unauthorized(Some("The invited user is banned from the room")))
+        }
+        ("invite", Some("join")) => {
+            Err(ApiError::unauthorized(Some("The invited user has already joined")))
+        }
+        ("invite", _) if !power_levels.can_invite(&options.sender) => {
+            Err(ApiError::unauthorized(Some("Insufficient power level to invite")))
+        }
+        ("invite", _) => Ok(()),
+        ("leave", Some("invite")) | ("leave", Some("join")) if is_self => Ok(()),
+        ("leave", _) if is_self => Err(ApiError::unauthorized(Some("You are not in this room."))),
+        ("leave", Some("ban")) => {
+            ensure_can_moderate(power_levels.can_ban(&options.sender, &options.user_id))
+        }
+        ("leave", Some("invite")) | ("leave", Some("join")) => {
+            ensure_can_moderate(power_levels.can_kick(&options.sender, &options.user_id))
+        }
+        ("leave", _) => Err(ApiError::unauthorized(Some("The user is not in this room."))),
+        ("ban", _) => {
+            ensure_can_moderate(power_levels.can_ban(&options.sender, &options.user_id))
+        }
+        (membership, _) => Err(ApiError::bad_json(
+            Some(&format!("Unknown membership state {}.", membership))
+        )),
+    }
+}
@@ -0,0 +1,112 @@
+//! Tags users put on rooms.
+
+use std::collections::BTreeMap;
+
+use diesel::{
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    LoadDsl,
+    OrderDsl,
+    delete,
+    insert,
+};
+use diesel::pg::PgConnection;
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::{Value, from_str};
+
+use error::ApiError;
+use schema::room_tags;
+
+/// The longest a tag can be, in bytes, per the spec.
+pub const MAX_TAG_LENGTH: usize = 255;
+
+/// A tag a user put on a room, such as *m.favourite* or *m.lowpriority*.
+#[derive(Debug, Queryable)]
+pub struct RoomTag {
+    /// Entry ID.
+    pub id: i64,
+    /// The user who tagged the room.
+    pub user_id: UserId,
+    /// The room that was tagged.
+    pub room_id: RoomId,
+    /// The name of the tag.
+    pub tag: String,
+    /// JSON of the tag's information, such as its `order`.
+    pub content: String,
+}
+
+/// A new room tag, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "room_tags"]
+pub struct NewRoomTag {
+    /// The user who tagged the room.
+    pub user_id: UserId,
+    /// The room that was tagged.
+    pub room_id: RoomId,
+    /// The name of the tag.
+    pub tag: String,
+    /// JSON of the tag's information, such as its `order`.
+    pub content: String,
+}
+
+impl RoomTag {
+    /// Saves a tag, replacing the user's tag with the same name on the room.
+    pub fn upsert(connection: &PgConnection, new_room_tag: &NewRoomTag) -> Result<(), ApiError> {
+        connection.transaction::<(), ApiError, _>(|| {
+            let previous = room_tags::table
+                .filter(room_tags::user_id.eq(&new_room_tag.user_id))
+                .filter(room_tags::room_id.eq(&new_room_tag.room_id))
+                .filter(room_tags::tag.eq(&new_room_tag.tag));
+
+            delete(previous).execute(connection)?;
+
+            insert(new_room_tag).into(room_tags::table).execute(connection)?;
+
+            Ok(())
+        }).map_err(ApiError::from)
+    }
+
+    /// Removes the user's tag with the given name from the room, if it has one.
+    pub fn delete(connection: &PgConnection, user_id: &UserId, room_id: &RoomId, tag: &str)
+    -> Result<(), ApiError> {
+        let room_tag = room_tags::table
+            .filter(room_tags::user_id.eq(user_id))
+            .filter(room_tags::room_id.eq(room_id))
+            .filter(room_tags::tag.eq(tag));
+
+        delete(room_tag).execute(connection).map(|_| ()).map_err(ApiError::from)
+    }
+
+    /// Removes all of the user's tags.
+    pub fn delete_by_user(connection: &PgConnection, user_id: &UserId) -> Result<(), ApiError> {
+        delete(room_tags::table.filter(room_tags::user_id.eq(user_id)))
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+
+    /// Returns the user's tags on the room as the content of an *m.tag* event: an object with
+    /// each tag's information under `tags`.
+    pub fn find_content(connection: &PgConnection, user_id: &UserId, room_id: &RoomId)
+    -> Result<Value, ApiError> {
+        let room_tags: Vec<RoomTag> = room_tags::table
+            .filter(room_tags::user_id.eq(user_id))
+            .filter(room_tags::room_id.eq(room_id))
+            .order(room_tags::tag.asc())
+            .load(connection)?;
+
+        let mut tags = BTreeMap::new();
+
+        for room_tag in room_tags {
+            tags.insert(room_tag.tag, from_str(&room_tag.content)?);
+        }
+
+        let mut content = BTreeMap::new();
+
+        content.insert("tags".to_string(), Value::Object(tags));
+
+        Ok(Value::Object(content))
+    }
+}
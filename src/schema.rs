@@ -8,10 +8,14 @@ table! {
     access_tokens {
         id -> BigSerial,
         user_id -> Text,
+        device_id -> Text,
         value -> Text,
+        value_digest -> Text,
         revoked -> Bool,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        last_used_at -> Nullable<BigInt>,
+        last_used_ip -> Nullable<Text>,
     }
 }
 
@@ -21,23 +25,147 @@ table! {
         user_id -> Text,
         data_type -> Text,
         content -> Text,
+        ordering -> BigInt,
+    }
+}
+
+table! {
+    broadcasts {
+        id -> BigSerial,
+        sender -> Text,
+        content -> Text,
+        last_user_id -> Nullable<Text>,
+        completed -> Bool,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    default_push_rule_settings (user_id, rule_id) {
+        user_id -> Text,
+        rule_id -> Text,
+        enabled -> Bool,
+        actions -> Text,
+    }
+}
+
+table! {
+    delayed_events {
+        id -> Text,
+        room_id -> Text,
+        user_id -> Text,
+        event_type -> Text,
+        state_key -> Nullable<Text>,
+        content -> Text,
+        delay -> BigInt,
+        send_at -> BigInt,
+    }
+}
+
+table! {
+    device_list_changes {
+        id -> BigSerial,
+        user_id -> Text,
+        ordering -> BigInt,
+    }
+}
+
+table! {
+    devices {
+        id -> BigSerial,
+        device_id -> Text,
+        user_id -> Text,
+        display_name -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    device_keys (user_id, device_id) {
+        user_id -> Text,
+        device_id -> Text,
+        key_json -> Text,
+        updated_at -> BigInt,
+    }
+}
+
+table! {
+    event_expirations (event_id) {
+        event_id -> Text,
+        room_id -> Text,
+        expires_at -> BigInt,
     }
 }
 
 table! {
     events {
         id -> Text,
-        ordering -> BigSerial,
+        ordering -> BigInt,
         room_id -> Text,
         user_id -> Text,
         event_type -> Text,
         state_key -> Nullable<Text>,
         content -> Text,
         extra_content -> Nullable<Text>,
+        replaces_state -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    filters {
+        id -> BigSerial,
+        user_id -> Text,
+        content -> Text,
         created_at -> Timestamp,
     }
 }
 
+table! {
+    lazy_loaded_members {
+        id -> BigSerial,
+        user_id -> Text,
+        device_id -> Text,
+        room_id -> Text,
+        member_id -> Text,
+        event_id -> Text,
+    }
+}
+
+table! {
+    media {
+        id -> Text,
+        user_id -> Text,
+        content_type -> Text,
+        upload_name -> Nullable<Text>,
+        size -> BigInt,
+        created_at -> Timestamp,
+        quarantined -> Bool,
+        content_hash -> Text,
+    }
+}
+
+table! {
+    one_time_keys {
+        id -> BigSerial,
+        user_id -> Text,
+        device_id -> Text,
+        algorithm -> Text,
+        key_id -> Text,
+        key_json -> Text,
+    }
+}
+
+table! {
+    presence (user_id) {
+        user_id -> Text,
+        state -> Text,
+        status_msg -> Nullable<Text>,
+        last_active_at -> BigInt,
+        ordering -> BigInt,
+    }
+}
+
 table! {
     profiles {
         id -> Text,
@@ -46,6 +174,49 @@ table! {
     }
 }
 
+table! {
+    push_rules {
+        id -> BigSerial,
+        user_id -> Text,
+        kind -> Text,
+        rule_id -> Text,
+        position -> BigInt,
+        conditions -> Text,
+        pattern -> Nullable<Text>,
+        actions -> Text,
+        enabled -> Bool,
+    }
+}
+
+table! {
+    pushers {
+        id -> BigSerial,
+        user_id -> Text,
+        kind -> Text,
+        app_id -> Text,
+        pushkey -> Text,
+        app_display_name -> Text,
+        device_display_name -> Text,
+        profile_tag -> Nullable<Text>,
+        lang -> Text,
+        data -> Text,
+        updated_at -> BigInt,
+        last_ordering -> BigInt,
+    }
+}
+
+table! {
+    receipts {
+        id -> BigSerial,
+        room_id -> Text,
+        user_id -> Text,
+        receipt_type -> Text,
+        event_id -> Text,
+        ts -> BigInt,
+        ordering -> BigInt,
+    }
+}
+
 table! {
     room_aliases (alias) {
         alias -> Text,
@@ -65,6 +236,17 @@ table! {
         sender -> Text,
         membership -> Text,
         created_at -> Timestamp,
+        forgotten -> Bool,
+    }
+}
+
+table! {
+    room_tags {
+        id -> BigSerial,
+        user_id -> Text,
+        room_id -> Text,
+        tag -> Text,
+        content -> Text,
     }
 }
 
@@ -74,6 +256,102 @@ table! {
         user_id -> Text,
         public -> Bool,
         created_at -> Timestamp,
+        version -> Text,
+    }
+}
+
+table! {
+    server_notices_rooms (user_id) {
+        user_id -> Text,
+        room_id -> Text,
+    }
+}
+
+table! {
+    stream_positions {
+        stream -> Text,
+        position -> BigInt,
+    }
+}
+
+table! {
+    threepid_validations {
+        id -> Text,
+        client_secret -> Text,
+        medium -> Text,
+        address -> Text,
+        token -> Text,
+        send_attempt -> BigInt,
+        validated -> Bool,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    to_device_messages {
+        id -> BigSerial,
+        user_id -> Text,
+        device_id -> Text,
+        sender -> Text,
+        event_type -> Text,
+        content -> Text,
+        ordering -> BigInt,
+    }
+}
+
+table! {
+    to_device_transactions {
+        id -> BigSerial,
+        access_token_id -> BigInt,
+        transaction_id -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    transactions {
+        id -> BigSerial,
+        access_token_id -> Nullable<BigInt>,
+        app_service_id -> Nullable<Text>,
+        transaction_id -> Text,
+        event_id -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    uia_sessions {
+        id -> Text,
+        endpoint -> Text,
+        user_id -> Nullable<Text>,
+        completed -> Array<Text>,
+        expires_at -> BigInt,
+    }
+}
+
+table! {
+    url_previews (url) {
+        url -> Text,
+        og -> Text,
+        expires_at -> BigInt,
+    }
+}
+
+table! {
+    user_threepids {
+        id -> BigSerial,
+        user_id -> Text,
+        medium -> Text,
+        address -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    user_velocity_limits (user_id) {
+        user_id -> Text,
+        invites_per_hour -> Nullable<BigInt>,
+        rooms_created_per_day -> Nullable<BigInt>,
     }
 }
 
@@ -82,11 +360,22 @@ table! {
         id -> Text,
         password_hash -> Text,
         active -> Bool,
+        locked -> Bool,
+        is_guest -> Bool,
         created_at -> Timestamp,
         updated_at -> Timestamp,
     }
 }
 
+table! {
+    velocity_actions {
+        id -> BigSerial,
+        user_id -> Text,
+        action -> Text,
+        occurred_at -> BigInt,
+    }
+}
+
 table! {
     room_account_data {
         id -> BigSerial,
@@ -94,5 +383,6 @@ table! {
         room_id -> Text,
         data_type -> Text,
         content -> Text,
+        ordering -> BigInt,
     }
 }
@@ -0,0 +1,253 @@
+//! Generation of large, realistic datasets for benchmarking.
+
+use std::cmp::{max, min};
+use std::convert::TryFrom;
+
+use diesel::{Connection, ExecuteDsl, insert};
+use diesel::pg::PgConnection;
+use rand::{Rng, SeedableRng, StdRng, thread_rng};
+use ruma_identifiers::{EventId, RoomId, UserId};
+
+use config::Config;
+use crypto::hash_password;
+use db::DB;
+use error::{ApiError, CliError};
+use event::NewEvent;
+use profile::Profile;
+use room::{CreationOptions, NewRoom, Room, RoomPreset};
+use room_membership::{RoomMembership, RoomMembershipOptions};
+use schema::{events, users};
+use user::NewUser;
+
+/// The password given to every generated user.
+pub const SEED_PASSWORD: &'static str = "seed";
+
+/// The number of rows inserted per statement when bulk loading users and events.
+const BATCH_SIZE: usize = 1000;
+
+/// Words used to build the bodies of generated messages.
+const WORDS: &'static [&'static str] = &[
+    "the", "a", "matrix", "room", "message", "sync", "server", "client", "hello", "world",
+    "rust", "ruma", "event", "state", "federation", "test", "quick", "brown", "fox", "jumps",
+    "over", "lazy", "dog", "lorem", "ipsum", "dolor", "sit", "amet", "today", "tomorrow",
+];
+
+/// How much data to generate.
+#[derive(Clone, Copy, Debug)]
+pub struct SeedOptions {
+    /// The number of users to create.
+    pub users: usize,
+    /// The number of rooms to create.
+    pub rooms: usize,
+    /// The number of message events to send, spread across all rooms.
+    pub events: usize,
+    /// A seed for the random number generator, to produce the same dataset on every run.
+    pub seed: Option<usize>,
+}
+
+/// Counts of the data that was generated.
+#[derive(Clone, Copy, Debug)]
+pub struct SeedSummary {
+    /// The number of users created.
+    pub users: usize,
+    /// The number of rooms created.
+    pub rooms: usize,
+    /// The number of room memberships created.
+    pub memberships: usize,
+    /// The number of message events created.
+    pub events: usize,
+}
+
+/// Fills the database described by `config` with generated users, rooms, and events.
+///
+/// Room sizes follow a long-tailed distribution: the first room has every user as a member, and
+/// each subsequent room is smaller, so benchmarks see a mix of huge and tiny rooms. Messages are
+/// sent by random members, so busier rooms are also the bigger ones.
+pub fn seed(config: &Config, options: SeedOptions) -> Result<SeedSummary, CliError> {
+    if options.users == 0 {
+        return Err(CliError::new("At least one user is required to seed the database."));
+    }
+
+    let connection = PgConnection::establish(&config.postgres_url)?;
+
+    DB::set_up(&connection)?;
+
+    let mut rng = match options.seed {
+        Some(seed) => StdRng::from_seed(&[seed]),
+        None => StdRng::from_seed(&[thread_rng().gen()]),
+    };
+
+    println!("Creating {} users...", options.users);
+    let user_ids = create_users(&connection, config, options.users)?;
+
+    println!("Creating {} rooms...", options.rooms);
+    let mut members = Vec::new();
+
+    for index in 0..options.rooms {
+        let room_members = create_room(&connection, config, &mut rng, &user_ids, index)?;
+
+        members.push(room_members);
+    }
+
+    let memberships: usize = members.iter().map(|room_members| room_members.1.len()).sum();
+
+    println!("Sending {} messages...", options.events);
+    create_events(&connection, config, &mut rng, &members, options.events)?;
+
+    Ok(SeedSummary {
+        users: user_ids.len(),
+        rooms: members.len(),
+        memberships: memberships,
+        events: if members.is_empty() { 0 } else { options.events },
+    })
+}
+
+/// Inserts the users and their profiles in batches, all sharing one password hash.
+fn create_users(connection: &PgConnection, config: &Config, count: usize)
+-> Result<Vec<UserId>, ApiError> {
+    // Hashing is deliberately slow, so hash the shared password once.
+    let password_hash = hash_password(&config.argon2_params, SEED_PASSWORD)?;
+    let mut user_ids = Vec::with_capacity(count);
+
+    for index in 0..count {
+        user_ids.push(UserId::try_from(&format!("@seed_user_{}:{}", index, config.domain))?);
+    }
+
+    for (batch_index, batch) in user_ids.chunks(BATCH_SIZE).enumerate() {
+        let new_users: Vec<NewUser> = batch.iter().map(|user_id| NewUser {
+            id: user_id.clone(),
+            password_hash: password_hash.clone(),
+            is_guest: false,
+        }).collect();
+
+        connection.transaction::<(), ApiError, _>(|| {
+            insert(&new_users).into(users::table).execute(connection)?;
+
+            for (offset, user_id) in batch.iter().enumerate() {
+                Profile::update_displayname(
+                    connection,
+                    user_id.clone(),
+                    Some(format!("Seed User {}", batch_index * BATCH_SIZE + offset)),
+                )?;
+            }
+
+            Ok(())
+        }).map_err(ApiError::from)?;
+    }
+
+    Ok(user_ids)
+}
+
+/// Creates a public room and joins a long-tailed number of users to it.
+///
+/// Returns the room's ID and the IDs of its members.
+fn create_room(
+    connection: &PgConnection,
+    config: &Config,
+    rng: &mut StdRng,
+    user_ids: &[UserId],
+    index: usize,
+) -> Result<(RoomId, Vec<UserId>), ApiError> {
+    let member_count = min(max(2, user_ids.len() / (index + 1)), user_ids.len());
+    let mut room_members: Vec<UserId> = user_ids.to_vec();
+
+    rng.shuffle(&mut room_members);
+    room_members.truncate(member_count);
+
+    let creator = room_members[0].clone();
+
+    let new_room = NewRoom {
+        id: RoomId::new(&config.domain)?,
+        user_id: creator.clone(),
+        public: true,
+        version: config.default_room_version.clone(),
+    };
+
+    let creation_options = CreationOptions {
+        alias: None,
+        encrypt: false,
+        federate: true,
+        invite_list: None,
+        name: Some(format!("Seed Room {}", index)),
+        predecessor: None,
+        preset: RoomPreset::PublicChat,
+        topic: Some(format!("Generated room {} with {} members", index, member_count)),
+    };
+
+    connection.transaction::<(), ApiError, _>(|| {
+        let room = Room::create(connection, &new_room, &config.domain, &creation_options)?;
+
+        for user_id in &room_members {
+            let options = RoomMembershipOptions {
+                room_id: room.id.clone(),
+                user_id: user_id.clone(),
+                sender: user_id.clone(),
+                membership: "join".to_string(),
+            };
+
+            RoomMembership::create(connection, &config.domain, options)?;
+        }
+
+        Ok(())
+    }).map_err(ApiError::from)?;
+
+    Ok((new_room.id, room_members))
+}
+
+/// Sends `count` text messages from random members of random rooms.
+fn create_events(
+    connection: &PgConnection,
+    config: &Config,
+    rng: &mut StdRng,
+    members: &[(RoomId, Vec<UserId>)],
+    count: usize,
+) -> Result<(), ApiError> {
+    // Choosing a random membership rather than a random room weights bigger rooms more heavily.
+    let memberships: Vec<(&RoomId, &UserId)> = members
+        .iter()
+        .flat_map(|&(ref room_id, ref user_ids)| {
+            user_ids.iter().map(move |user_id| (room_id, user_id))
+        })
+        .collect();
+
+    if memberships.is_empty() {
+        return Ok(());
+    }
+
+    let mut remaining = count;
+
+    while remaining > 0 {
+        let batch_size = min(remaining, BATCH_SIZE);
+        let mut new_events = Vec::with_capacity(batch_size);
+
+        for _ in 0..batch_size {
+            let &(room_id, user_id) = rng.choose(&memberships).expect("memberships is not empty");
+
+            new_events.push(NewEvent {
+                content: format!(r#"{{"body":"{}","msgtype":"m.text"}}"#, sentence(rng)),
+                event_type: "m.room.message".to_string(),
+                extra_content: None,
+                id: EventId::new(&config.domain)?,
+                room_id: room_id.clone(),
+                state_key: None,
+                user_id: user_id.clone(),
+            });
+        }
+
+        insert(&new_events).into(events::table).execute(connection)?;
+
+        remaining -= batch_size;
+    }
+
+    Ok(())
+}
+
+/// Builds a short sentence out of random words.
+fn sentence(rng: &mut StdRng) -> String {
+    let length = rng.gen_range(3, 20);
+
+    (0..length)
+        .map(|_| *rng.choose(WORDS).expect("WORDS is not empty"))
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
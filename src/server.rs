@@ -1,48 +1,182 @@
 //! Iron web server that serves the API.
-use diesel::migrations::setup_database;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::Duration;
+
+use diesel::Connection;
 use diesel::pg::PgConnection;
-use iron::{Chain, Iron, IronError, IronResult, Listening, Request, Response};
+use hyper::header::Connection as ConnectionHeader;
+use hyper::net::Fresh;
+use hyper::server::{
+    Handler as HyperHandler,
+    Listening,
+    Request as HyperRequest,
+    Response as HyperResponse,
+    Server as HyperServer,
+};
+use hyper::status::StatusCode;
+use iron::{Chain, Handler, IronError, IronResult, Protocol, Request, Response};
 use iron::error::HttpResult;
 use mount::Mount;
+use num_cpus;
 use persistent::{Read, Write};
-use r2d2::Config as R2D2Config;
-use r2d2_diesel::Error as R2D2DieselError;
+use r2d2::{Config as R2D2Config, Pool};
+use r2d2_diesel::{ConnectionManager, Error as R2D2DieselError};
 use router::Router;
 
+use api::media::{DownloadMedia, GetThumbnail, GetUrlPreview, UploadMedia};
 use api::r0::{
     AccountPassword,
+    AddThreepid,
+    BanFromRoom,
     CreateRoom,
     DeactivateAccount,
+    DeleteDevice,
+    DeletePushRule,
     DeleteRoomAlias,
+    DeleteTag,
+    DeleteThreepid,
+    ForgetRoom,
     GetAvatarUrl,
+    GetCapabilities,
+    GetContext,
+    GetDevice,
+    GetDevices,
+    GetFilter,
+    GetMessages,
+    GetNotifications,
+    GetPresenceStatus,
+    GetPublicRooms,
+    GetPushRule,
+    GetPushRuleActions,
+    GetPushRuleEnabled,
+    GetPushRules,
+    GetPushers,
     GetDisplayName,
     GetRoomAlias,
+    GetRoomAliases,
+    GetRoomVisibility,
+    GetStateEvent,
+    GetStateEvents,
+    GetTags,
+    GetThreepids,
+    GetWellKnownClient,
     InviteToRoom,
     JoinRoom,
+    JoinRoomByIdOrAlias,
+    KickFromRoom,
+    LeaveRoom,
     Login,
     Logout,
     Members,
+    PostFilter,
+    PostPublicRooms,
+    PostReadMarkers,
+    PostReceipt,
     Profile,
     PutAccountData,
     PutAvatarUrl,
+    PutDevice,
     PutDisplayName,
+    PutPresenceStatus,
+    PutPushRule,
+    PutPushRuleActions,
+    PutPushRuleEnabled,
     PutRoomAccountData,
     PutRoomAlias,
+    PutRoomVisibility,
+    PutTag,
+    PutTyping,
+    RedactEvent,
     Register,
+    RequestMsisdnToken,
+    Search,
+    SearchUserDirectory,
     SendMessageEvent,
+    SendToDevice,
+    ServerVersion,
+    SetPusher,
     StateMessageEvent,
+    SubmitMsisdnToken,
+    Sync,
+    UnbanFromRoom,
+    UpgradeRoom,
     Versions,
+    WhoAmI,
 };
+use api::ruma::{
+    BatchSend,
+    DeleteMedia,
+    DeleteUserVelocityLimits,
+    ExportMembers,
+    ExportRoom,
+    GetRoomComplexity,
+    GetStats,
+    GetUserAccessTokens,
+    GetUserVelocityLimits,
+    ImportRoom,
+    PostBroadcast,
+    PostUserThreepid,
+    PutMediaQuarantined,
+    PutRoomPowerLevel,
+    PutUserLocked,
+    PutUserVelocityLimits,
+    RevokeUserAccessTokens,
+};
+use api::unstable::{GetDelayedEvents, UpdateDelayedEvent};
+use clock::{Clock, ServerClock, SystemClock};
 use config::Config;
-use embedded_migrations::run as run_pending_migrations;
+use crypto::{Entropy, OsEntropy, ServerEntropy};
+use delayed_event::SendDelayedEvents;
 use error::{ApiError, CliError};
+use event_expiration::ExpireEvents;
 use db::DB;
-use middleware::{Cors, MiddlewareChain};
+use http_client::HttpClient;
+use jobs::Jobs;
+use listener::{
+    HandoffListener,
+    is_draining,
+    notify_predecessor,
+    open_listener,
+    watch_for_handoff,
+};
+use identity_server::{HttpIdentityServers, IdentityServers, ServerIdentityServers};
+use media::{FileMediaStorage, MediaStorage, ServerMediaStorage};
+use membership_cache::{CatchUpMembershipCache, MembershipCache, ServerMembershipCache};
+use middleware::{Cors, LatencyBudget, MiddlewareChain};
+use power_levels::{CatchUpPowerLevelsCache, PowerLevelsCache, ServerPowerLevelsCache};
+use pusher::{HttpPushGateway, PushGateway, SendPushNotifications};
+use receipt::{PendingReceipts, SaveReceipts, ServerPendingReceipts};
+use server_notice::SendBroadcasts;
+use sms::{DisabledSms, HttpSmsGateway, ServerSms, Sms};
+use stats::ReportStats;
 use swagger::mount_swagger;
+use systemd::notify_ready;
+use typing::{ExpireTyping, ServerTyping, Typing};
+use url_preview::{HttpUrlFetcher, ServerUrlFetcher, UrlFetcher};
+
+/// How long idle connections are kept open for another request, as Iron does by default.
+const KEEP_ALIVE_SECS: u64 = 5;
+
+/// How long reading a request may take, as Iron does by default.
+const READ_TIMEOUT_SECS: u64 = 30;
+
+/// How long writing a response may take, as Iron does by default.
+const WRITE_TIMEOUT_SECS: u64 = 1;
 
 /// Ruma's web server.
 pub struct Server<'a> {
     config: &'a Config,
+    connection_pool: Pool<ConnectionManager<PgConnection>>,
+    jobs: Jobs,
+    mount: Mount,
+}
+
+/// Serves the API's `Mount` with hyper the way `Iron::http` does, so that it can be served from a
+/// listener Ruma opened itself, such as one handed over by another Ruma process.
+struct IronHandler {
+    address: SocketAddr,
     mount: Mount,
 }
 
@@ -60,22 +194,74 @@ impl<'a> Server<'a> {
     pub fn new(config: &Config)
     -> Result<Server, CliError> {
 		info!("Creating new server");
-        Server::with_options(config, R2D2Config::default(), true)
+        let http_client = Arc::new(HttpClient::new(config)?);
+        let sms: Arc<Sms> = match config.sms_gateway_url {
+            Some(ref url) => Arc::new(HttpSmsGateway::new(url.clone(), http_client.clone())),
+            None => Arc::new(DisabledSms),
+        };
+        let url_fetcher = Arc::new(HttpUrlFetcher::new(
+            http_client.clone(),
+            config.url_preview_ip_range_blacklist.clone(),
+        ));
+
+        Server::with_options(
+            config,
+            R2D2Config::default(),
+            true,
+            Arc::new(SystemClock),
+            Arc::new(OsEntropy::new()?),
+            sms,
+            Arc::new(HttpIdentityServers::new(http_client.clone())),
+            Arc::new(HttpPushGateway::new(http_client.clone())),
+            http_client,
+            Arc::new(FileMediaStorage::new(config.media_directory.clone())),
+            url_fetcher,
+        )
     }
 
-    /// Create a new `Server` from a `Config`, an `r2d2::Config`, and the ability to disable
-    /// database creation and setup.
+    /// Create a new `Server` from a `Config`, an `r2d2::Config`, the ability to disable
+    /// database creation and setup, the sources of time and randomness to use, the way to send
+    /// SMS messages, the way to reach identity servers, the way to deliver push notifications,
+    /// the client for other outbound HTTP requests, where to keep uploaded files, and the way to
+    /// fetch web pages for previews.
     pub fn with_options(
         ruma_config: &Config,
         r2d2_config: R2D2Config<PgConnection, R2D2DieselError>,
         set_up_db: bool,
+        clock: Arc<Clock>,
+        entropy: Arc<Entropy>,
+        sms: Arc<Sms>,
+        identity_servers: Arc<IdentityServers>,
+        push_gateway: Arc<PushGateway>,
+        http_client: Arc<HttpClient>,
+        media_storage: Arc<MediaStorage>,
+        url_fetcher: Arc<UrlFetcher>,
     ) -> Result<Server, CliError> {
         info!("Forming a server instance from config options");
         let mut r0_router = Router::new();
 
         r0_router.post("/account/password", AccountPassword::chain(), "account_password");
         r0_router.post("/account/deactivate", DeactivateAccount::chain(), "deactivate_account");
+        r0_router.get("/account/3pid", GetThreepids::chain(), "get_threepids");
+        r0_router.post("/account/3pid", AddThreepid::chain(), "add_threepid");
+        r0_router.post("/account/3pid/delete", DeleteThreepid::chain(), "delete_threepid");
+        r0_router.post(
+            "/account/3pid/msisdn/requestToken",
+            RequestMsisdnToken::chain(),
+            "request_msisdn_token",
+        );
+        r0_router.post(
+            "/account/3pid/msisdn/submitToken",
+            SubmitMsisdnToken::chain(),
+            "submit_msisdn_token",
+        );
+        r0_router.get("/account/whoami", WhoAmI::chain(), "whoami");
+        r0_router.get("/capabilities", GetCapabilities::chain(), "get_capabilities");
         r0_router.post("/createRoom", CreateRoom::chain(), "create_room");
+        r0_router.get("/devices", GetDevices::chain(), "get_devices");
+        r0_router.get("/devices/:device_id", GetDevice::chain(), "get_device");
+        r0_router.put("/devices/:device_id", PutDevice::chain(), "put_device");
+        r0_router.delete("/devices/:device_id", DeleteDevice::chain(), "delete_device");
         r0_router.get("/directory/room/:room_alias", GetRoomAlias::chain(), "get_room_alias");
         r0_router.delete(
             "/directory/room/:room_alias",
@@ -83,10 +269,39 @@ impl<'a> Server<'a> {
             "delete_room_alias",
         );
         r0_router.put("/directory/room/:room_alias", PutRoomAlias::chain(), "put_room_alias");
+        r0_router.get("/rooms/:room_id/aliases", GetRoomAliases::chain(), "get_room_aliases");
+        r0_router.get(
+            "/directory/list/room/:room_id",
+            GetRoomVisibility::chain(),
+            "get_room_visibility",
+        );
+        r0_router.put(
+            "/directory/list/room/:room_id",
+            PutRoomVisibility::chain(),
+            "put_room_visibility",
+        );
         r0_router.post("/login", Login::chain(), "login");
         r0_router.post("/logout", Logout::chain(), "logout");
         r0_router.post("/register", Register::chain(), "register");
+        r0_router.post("/search", Search::chain(), "search");
+        r0_router.post(
+            "/user_directory/search",
+            SearchUserDirectory::chain(),
+            "search_user_directory",
+        );
+        r0_router.put(
+            "/sendToDevice/:event_type/:transaction_id",
+            SendToDevice::chain(),
+            "send_to_device",
+        );
+        r0_router.get("/sync", Sync::chain(), "sync");
         r0_router.post("/tokenrefresh", unimplemented, "token_refresh");
+        r0_router.post("/user/:user_id/filter", PostFilter::chain(), "post_filter");
+        r0_router.get(
+            "/user/:user_id/filter/:filter_id",
+            GetFilter::chain(),
+            "get_filter",
+        );
         r0_router.put(
             "/user/:user_id/account_data/:type",
             PutAccountData::chain(),
@@ -97,6 +312,13 @@ impl<'a> Server<'a> {
             PutRoomAccountData::chain(),
             "put_room_account_data",
         );
+        r0_router.get("/user/:user_id/rooms/:room_id/tags", GetTags::chain(), "get_tags");
+        r0_router.put("/user/:user_id/rooms/:room_id/tags/:tag", PutTag::chain(), "put_tag");
+        r0_router.delete(
+            "/user/:user_id/rooms/:room_id/tags/:tag",
+            DeleteTag::chain(),
+            "delete_tag",
+        );
         r0_router.put(
             "/rooms/:room_id/send/:event_type/:transaction_id",
             SendMessageEvent::chain(),
@@ -112,73 +334,402 @@ impl<'a> Server<'a> {
             StateMessageEvent::chain(),
             "state_message_event_with_key",
         );
+        r0_router.put(
+            "/rooms/:room_id/redact/:event_id/:transaction_id",
+            RedactEvent::chain(),
+            "redact_event",
+        );
         r0_router.post("/rooms/:room_id/join", JoinRoom::chain(), "join_room");
+        r0_router.post(
+            "/join/:room_id_or_alias",
+            JoinRoomByIdOrAlias::chain(),
+            "join_room_by_id_or_alias",
+        );
         r0_router.post("/rooms/:room_id/invite", InviteToRoom::chain(), "invite_to_room");
+        r0_router.put("/rooms/:room_id/typing/:user_id", PutTyping::chain(), "put_typing");
+        r0_router.post(
+            "/rooms/:room_id/receipt/:receipt_type/:event_id",
+            PostReceipt::chain(),
+            "post_receipt",
+        );
+        r0_router.post(
+            "/rooms/:room_id/read_markers",
+            PostReadMarkers::chain(),
+            "post_read_markers",
+        );
+        r0_router.post("/rooms/:room_id/leave", LeaveRoom::chain(), "leave_room");
+        r0_router.post("/rooms/:room_id/forget", ForgetRoom::chain(), "forget_room");
+        r0_router.post("/rooms/:room_id/upgrade", UpgradeRoom::chain(), "upgrade_room");
+        r0_router.post("/rooms/:room_id/kick", KickFromRoom::chain(), "kick_from_room");
+        r0_router.post("/rooms/:room_id/ban", BanFromRoom::chain(), "ban_from_room");
+        r0_router.post("/rooms/:room_id/unban", UnbanFromRoom::chain(), "unban_from_room");
         r0_router.get("/rooms/:room_id/members", Members::chain(), "members");
+        r0_router.get("/rooms/:room_id/messages", GetMessages::chain(), "get_messages");
+        r0_router.get(
+            "/rooms/:room_id/context/:event_id",
+            GetContext::chain(),
+            "get_context",
+        );
+        r0_router.get("/rooms/:room_id/state", GetStateEvents::chain(), "get_state_events");
+        r0_router.get(
+            "/rooms/:room_id/state/:event_type",
+            GetStateEvent::chain(),
+            "get_state_event",
+        );
+        r0_router.get(
+            "/rooms/:room_id/state/:event_type/:state_key",
+            GetStateEvent::chain(),
+            "get_state_event_with_key",
+        );
+        r0_router.get("/publicRooms", GetPublicRooms::chain(), "get_public_rooms");
+        r0_router.post("/publicRooms", PostPublicRooms::chain(), "post_public_rooms");
         r0_router.get("/profile/:user_id", Profile::chain(), "profile");
         r0_router.get("/profile/:user_id/avatar_url", GetAvatarUrl::chain(), "get_avatar_url");
         r0_router.get("/profile/:user_id/displayname", GetDisplayName::chain(), "get_display_name");
         r0_router.put("/profile/:user_id/avatar_url", PutAvatarUrl::chain(), "put_avatar_url");
         r0_router.put("/profile/:user_id/displayname", PutDisplayName::chain(), "put_display_name");
+        r0_router.get(
+            "/presence/:user_id/status",
+            GetPresenceStatus::chain(),
+            "get_presence_status",
+        );
+        r0_router.put(
+            "/presence/:user_id/status",
+            PutPresenceStatus::chain(),
+            "put_presence_status",
+        );
+        r0_router.get("/pushrules/", GetPushRules::chain(), "get_push_rules");
+        r0_router.get("/pushrules/:scope/", GetPushRules::chain(), "get_push_rules_in_scope");
+        r0_router.get(
+            "/pushrules/:scope/:kind/:rule_id",
+            GetPushRule::chain(),
+            "get_push_rule",
+        );
+        r0_router.put(
+            "/pushrules/:scope/:kind/:rule_id",
+            PutPushRule::chain(),
+            "put_push_rule",
+        );
+        r0_router.delete(
+            "/pushrules/:scope/:kind/:rule_id",
+            DeletePushRule::chain(),
+            "delete_push_rule",
+        );
+        r0_router.get(
+            "/pushrules/:scope/:kind/:rule_id/enabled",
+            GetPushRuleEnabled::chain(),
+            "get_push_rule_enabled",
+        );
+        r0_router.put(
+            "/pushrules/:scope/:kind/:rule_id/enabled",
+            PutPushRuleEnabled::chain(),
+            "put_push_rule_enabled",
+        );
+        r0_router.get(
+            "/pushrules/:scope/:kind/:rule_id/actions",
+            GetPushRuleActions::chain(),
+            "get_push_rule_actions",
+        );
+        r0_router.put(
+            "/pushrules/:scope/:kind/:rule_id/actions",
+            PutPushRuleActions::chain(),
+            "put_push_rule_actions",
+        );
+        r0_router.get("/pushers", GetPushers::chain(), "get_pushers");
+        r0_router.post("/pushers/set", SetPusher::chain(), "set_pusher");
+        r0_router.get("/notifications", GetNotifications::chain(), "get_notifications");
+
+        let mut unstable_router = Router::new();
+
+        unstable_router.get(
+            "/org.matrix.msc4140/delayed_events",
+            GetDelayedEvents::chain(),
+            "get_delayed_events",
+        );
+        unstable_router.post(
+            "/org.matrix.msc4140/delayed_events/:delay_id",
+            UpdateDelayedEvent::chain(),
+            "update_delayed_event",
+        );
+
+        let mut ruma_router = Router::new();
+
+        ruma_router.post("/admin/broadcasts", PostBroadcast::chain(), "post_broadcast");
+        ruma_router.delete(
+            "/admin/media/:server_name/:media_id",
+            DeleteMedia::chain(),
+            "delete_media",
+        );
+        ruma_router.put(
+            "/admin/media/:server_name/:media_id/quarantined",
+            PutMediaQuarantined::chain(),
+            "put_media_quarantined",
+        );
+        ruma_router.post("/admin/rooms/import", ImportRoom::chain(), "import_room");
+        ruma_router.get(
+            "/admin/rooms/:room_id/complexity",
+            GetRoomComplexity::chain(),
+            "get_room_complexity",
+        );
+        ruma_router.put(
+            "/admin/rooms/:room_id/power_levels/:user_id",
+            PutRoomPowerLevel::chain(),
+            "put_room_power_level",
+        );
+        ruma_router.get("/admin/stats", GetStats::chain(), "get_stats");
+        ruma_router.get(
+            "/admin/users/:user_id/access_tokens",
+            GetUserAccessTokens::chain(),
+            "get_user_access_tokens",
+        );
+        ruma_router.post(
+            "/admin/users/:user_id/access_tokens/revoke",
+            RevokeUserAccessTokens::chain(),
+            "revoke_user_access_tokens",
+        );
+        ruma_router.put(
+            "/admin/users/:user_id/locked",
+            PutUserLocked::chain(),
+            "put_user_locked",
+        );
+        ruma_router.post(
+            "/admin/users/:user_id/threepids",
+            PostUserThreepid::chain(),
+            "post_user_threepid",
+        );
+        ruma_router.get(
+            "/admin/users/:user_id/velocity_limits",
+            GetUserVelocityLimits::chain(),
+            "get_user_velocity_limits",
+        );
+        ruma_router.put(
+            "/admin/users/:user_id/velocity_limits",
+            PutUserVelocityLimits::chain(),
+            "put_user_velocity_limits",
+        );
+        ruma_router.delete(
+            "/admin/users/:user_id/velocity_limits",
+            DeleteUserVelocityLimits::chain(),
+            "delete_user_velocity_limits",
+        );
+        ruma_router.post("/rooms/:room_id/batch_send", BatchSend::chain(), "batch_send");
+        ruma_router.get(
+            "/rooms/:room_id/members/export",
+            ExportMembers::chain(),
+            "export_members",
+        );
+        ruma_router.get("/rooms/:room_id/export", ExportRoom::chain(), "export_room");
 
         let mut r0 = Chain::new(r0_router);
+        let mut media_router = Router::new();
+
+        media_router.get(
+            "/download/:server_name/:media_id",
+            DownloadMedia::chain(),
+            "download_media",
+        );
+        media_router.get(
+            "/download/:server_name/:media_id/:file_name",
+            DownloadMedia::chain(),
+            "download_media_with_file_name",
+        );
+        media_router.get(
+            "/thumbnail/:server_name/:media_id",
+            GetThumbnail::chain(),
+            "get_thumbnail",
+        );
+        media_router.get("/preview_url", GetUrlPreview::chain(), "get_url_preview");
+        media_router.post("/upload", UploadMedia::chain(), "upload_media");
+
+        let mut media = Chain::new(media_router);
+        let mut ruma = Chain::new(ruma_router);
+        let mut unstable = Chain::new(unstable_router);
 
         debug!("Connecting to PostgreSQL.");
         let connection_pool = DB::create_connection_pool(r2d2_config, &ruma_config.postgres_url)?;
         let connection = connection_pool.get()?;
 
         if set_up_db {
-            debug!("Setting up database.");
-            if let Err(error) =  setup_database(&*connection) {
-                return Err(CliError::new(format!("{:?}", error)));
-            }
-
-            debug!("Running pending database migrations.");
-            if let Err(error) = run_pending_migrations(&*connection) {
-                return Err(CliError::new(format!("{:?}", error)));
-            }
+            DB::set_up(&*connection)?;
         }
 
-        r0.link_before(Read::<Config>::one(ruma_config.clone()));
-        r0.link_before(Write::<DB>::one(connection_pool));
-        r0.link_after(Cors);
+        let membership_cache = Arc::new(MembershipCache::new());
+        let power_levels_cache = Arc::new(PowerLevelsCache::new());
+        let pending_receipts = Arc::new(PendingReceipts::new(ruma_config.ephemeral_batch_millis));
+        let typing = Arc::new(Typing::new(ruma_config.ephemeral_batch_millis));
+
+        let mut jobs = Jobs::new(ruma_config.clone(), clock.clone(), connection_pool.clone());
+
+        jobs.register(CatchUpMembershipCache::new(membership_cache.clone()));
+        jobs.register(CatchUpPowerLevelsCache::new(power_levels_cache.clone()));
+        jobs.register(
+            SendDelayedEvents::new(membership_cache.clone(), power_levels_cache.clone())
+        );
+        jobs.register(ExpireEvents);
+        jobs.register(ReportStats::new(http_client));
+        jobs.register(ExpireTyping::new(typing.clone()));
+        jobs.register(SaveReceipts::new(pending_receipts.clone()));
+        jobs.register(SendBroadcasts::new(membership_cache.clone(), power_levels_cache.clone()));
+        jobs.register(SendPushNotifications::new(push_gateway));
+
+        let latency_budget = LatencyBudget::new(ruma_config.slow_request_thresholds.clone());
+
+        for chain in &mut [&mut media, &mut r0, &mut ruma, &mut unstable] {
+            chain.link_before(latency_budget.clone());
+            chain.link_before(Read::<Config>::one(ruma_config.clone()));
+            chain.link_before(Read::<ServerClock>::one(clock.clone()));
+            chain.link_before(Read::<ServerEntropy>::one(entropy.clone()));
+            chain.link_before(Read::<ServerIdentityServers>::one(identity_servers.clone()));
+            chain.link_before(Read::<ServerMediaStorage>::one(media_storage.clone()));
+            chain.link_before(Read::<ServerMembershipCache>::one(membership_cache.clone()));
+            chain.link_before(Read::<ServerPendingReceipts>::one(pending_receipts.clone()));
+            chain.link_before(Read::<ServerPowerLevelsCache>::one(power_levels_cache.clone()));
+            chain.link_before(Read::<ServerSms>::one(sms.clone()));
+            chain.link_before(Read::<ServerTyping>::one(typing.clone()));
+            chain.link_before(Read::<ServerUrlFetcher>::one(url_fetcher.clone()));
+            chain.link_before(Write::<DB>::one(connection_pool.clone()));
+            chain.link_after(Cors);
+            chain.link_after(latency_budget.clone());
+        }
 
         let mut versions_router = Router::new();
 
-        versions_router.get("/versions", Versions::new(vec!["r0.0.1"]), "versions");
+        versions_router.get(
+            "/versions",
+            Versions::new(
+                vec!["r0.0.1", "r0.1.0", "r0.2.0", "r0.3.0", "r0.4.0", "r0.5.0"],
+                vec!["org.matrix.msc4140"],
+            ),
+            "versions",
+        );
 
         let mut versions = Chain::new(versions_router);
 
         versions.link_after(Cors);
 
+        let mut federation_router = Router::new();
+
+        federation_router.get("/version", ServerVersion, "server_version");
+
+        let mut federation = Chain::new(federation_router);
+
+        federation.link_after(Cors);
+
+        let mut well_known_router = Router::new();
+
+        well_known_router.get(
+            "/matrix/client",
+            GetWellKnownClient::new(ruma_config),
+            "well_known_client",
+        );
+
+        let mut well_known = Chain::new(well_known_router);
+
+        well_known.link_after(Cors);
+
         let mut mount = Mount::new();
 
         mount.mount("/_matrix/client/", versions);
         mount.mount("/_matrix/client/r0/", r0);
+        mount.mount("/_matrix/client/unstable/", unstable);
+        mount.mount("/_matrix/media/r0/", media);
+        mount.mount("/ruma/", ruma);
+        mount.mount("/.well-known/", well_known);
+        mount.mount("/_matrix/federation/v1/", federation);
 
         mount_swagger(&mut mount);
 
         Ok(Server {
             config: ruma_config,
+            connection_pool: connection_pool,
+            jobs: jobs,
             mount: mount,
         })
     }
 
     /// Run the server and block the current thread until stopped or interrupted.
+    ///
+    /// The listener is the one handed over by a previous Ruma process if there was one, and is
+    /// handed to a new process when Ruma gets `SIGUSR2`.
     pub fn run(self) -> HttpResult<Listening> {
         let address = format!("{}:{}", self.config.bind_address, self.config.bind_port);
+        let listener = open_listener(&address)?;
+        let fd = listener.as_raw_fd();
+        let local_address = listener.local_addr()?;
 
-        info!("Starting Ruma server on {}.", address);
+        info!("Starting Ruma server on {}.", local_address);
         info!("Blocking Iron instance listening...");
 
-        let iron = Iron::new(self.mount);
+        self.jobs.spawn();
+
+        let mut server = HyperServer::new(HandoffListener::new(listener));
+
+        server.keep_alive(Some(Duration::from_secs(KEEP_ALIVE_SECS)));
+        server.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS)));
+        server.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS)));
+
+        let handler = IronHandler {
+            address: local_address,
+            mount: self.mount,
+        };
+        let listening = server.handle_threads(handler, 8 * num_cpus::get())?;
+
+        let connection_pool = self.connection_pool;
+
+        notify_predecessor();
+        notify_ready(move || {
+            let connection = connection_pool.get().map_err(|error| {
+                format!("Failed to get a database connection: {}", error)
+            })?;
+
+            connection.execute("SELECT 1").map(|_| ()).map_err(|error| {
+                format!("Failed to query the database: {}", error)
+            })
+        });
+        watch_for_handoff(fd);
 
-        iron.http(&address[..])
+        Ok(listening)
     }
 
-    /// Moves out the server's `Mount`. Useful for testing.
-    pub fn into_mount(self) -> Mount {
-        self.mount
+    /// Moves out the server's `Mount` and background `Jobs` without starting either. Useful for
+    /// testing.
+    pub fn into_parts(self) -> (Mount, Jobs) {
+        (self.mount, self.jobs)
+    }
+}
+
+impl HyperHandler for IronHandler {
+    fn handle(&self, http_request: HyperRequest, mut http_response: HyperResponse<Fresh>) {
+        // In case the handler panics.
+        *http_response.status_mut() = StatusCode::InternalServerError;
+
+        let mut request = match Request::from_http(http_request, self.address, &Protocol::Http) {
+            Ok(request) => request,
+            Err(error) => {
+                error!("Failed to read request: {}", error);
+
+                *http_response.status_mut() = StatusCode::BadRequest;
+
+                if let Err(error) = http_response.send(b"") {
+                    error!("Failed to send response: {}", error);
+                }
+
+                return;
+            }
+        };
+
+        let mut response = self.mount.handle(&mut request).unwrap_or_else(|error| {
+            error!("Error handling {:?}: {:?}", request, error.error);
+
+            error.response
+        });
+
+        // Once the listener has been handed off, connections are closed so the process can exit.
+        if is_draining() {
+            response.headers.set(ConnectionHeader::close());
+        }
+
+        response.write_back(http_response);
     }
 }
 
@@ -0,0 +1,355 @@
+//! Server notices: messages from the homeserver itself to its users.
+//!
+//! Each local user gets a private room, created the first time a notice is sent to them, that
+//! the server notices user invites them to and tags with `m.server_notice`. A room the user has
+//! left or been banned from is replaced with a new one.
+//!
+//! Admins send a message to every user at once with a broadcast. Broadcasts are delivered by the
+//! `SendBroadcasts` job in batches of users, ordered by ID, so a server with many users isn't
+//! held up by a single request.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+
+use diesel::{
+    Connection,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LimitDsl,
+    LoadDsl,
+    OrderDsl,
+    SaveChangesDsl,
+    SelectDsl,
+    insert,
+    update,
+};
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::result::Error as DieselError;
+use ruma_events::EventType;
+use ruma_identifiers::{EventId, RoomId, UserId};
+use serde_json::{Value, to_string};
+
+use account_data::{NewRoomAccountData, RoomAccountData};
+use config::Config;
+use error::ApiError;
+use event::NewEvent;
+use jobs::Job;
+use membership_cache::MembershipCache;
+use power_levels::PowerLevelsCache;
+use room::{CreationOptions, NewRoom, Room, RoomPreset};
+use room_membership::{RoomMembership, RoomMembershipOptions};
+use schema::{broadcasts, server_notices_rooms, users};
+
+/// The localpart of the user that sends server notices. It can't be registered.
+pub const SERVER_NOTICES_LOCALPART: &'static str = "server-notices";
+
+/// The room tag that marks a user's server notices room.
+pub const SERVER_NOTICE_TAG: &'static str = "m.server_notice";
+
+/// The most users a broadcast is delivered to each time `SendBroadcasts` runs.
+const BROADCAST_BATCH_SIZE: i64 = 100;
+
+/// The room a user receives server notices in.
+#[derive(Debug, Clone, Insertable, Queryable)]
+#[table_name = "server_notices_rooms"]
+pub struct ServerNoticesRoom {
+    /// The user the notices are sent to.
+    pub user_id: UserId,
+    /// The room the notices are sent in.
+    pub room_id: RoomId,
+}
+
+/// A message being sent to every local user.
+#[derive(AsChangeset, Clone, Debug, Identifiable, Queryable)]
+#[table_name = "broadcasts"]
+pub struct Broadcast {
+    /// The broadcast's ID.
+    pub id: i64,
+    /// The admin who sent the broadcast.
+    pub sender: UserId,
+    /// JSON of the *m.room.message* content sent to each user.
+    pub content: String,
+    /// The last user the broadcast was delivered to, if it has been delivered to anyone.
+    pub last_user_id: Option<UserId>,
+    /// Whether or not the broadcast has been delivered to every user.
+    pub completed: bool,
+    /// The time the broadcast was sent.
+    pub created_at: PgTimestamp,
+}
+
+/// A new broadcast, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "broadcasts"]
+pub struct NewBroadcast {
+    /// The admin who sent the broadcast.
+    pub sender: UserId,
+    /// JSON of the *m.room.message* content sent to each user.
+    pub content: String,
+}
+
+/// A background job that delivers broadcasts to the next batch of users.
+#[derive(Debug)]
+pub struct SendBroadcasts {
+    membership_cache: Arc<MembershipCache>,
+    power_levels_cache: Arc<PowerLevelsCache>,
+}
+
+/// Returns the ID of the user that sends server notices on this homeserver.
+pub fn server_notices_user_id(homeserver_domain: &str) -> Result<UserId, ApiError> {
+    UserId::try_from(&format!("@{}:{}", SERVER_NOTICES_LOCALPART, homeserver_domain))
+        .map_err(ApiError::from)
+}
+
+impl ServerNoticesRoom {
+    /// Returns the user's server notices room, creating one if they don't have one they can
+    /// still read.
+    pub fn find_or_create(connection: &PgConnection, config: &Config, user_id: &UserId)
+    -> Result<ServerNoticesRoom, ApiError> {
+        connection.transaction::<ServerNoticesRoom, ApiError, _>(|| {
+            let existing = server_notices_rooms::table
+                .find(user_id.clone())
+                .first::<ServerNoticesRoom>(connection);
+
+            match existing {
+                Ok(notices_room) => {
+                    let membership = RoomMembership::find(
+                        connection,
+                        &notices_room.room_id,
+                        &notices_room.user_id,
+                    )?;
+
+                    let usable = membership
+                        .map(|membership| {
+                            membership.membership == "invite" || membership.membership == "join"
+                        })
+                        .unwrap_or(false);
+
+                    if usable {
+                        return Ok(notices_room);
+                    }
+
+                    let room_id = create_room(connection, config, user_id)?;
+
+                    update(server_notices_rooms::table.find(user_id.clone()))
+                        .set(server_notices_rooms::room_id.eq(room_id))
+                        .get_result(connection)
+                        .map_err(ApiError::from)
+                }
+                Err(DieselError::NotFound) => {
+                    let notices_room = ServerNoticesRoom {
+                        user_id: user_id.clone(),
+                        room_id: create_room(connection, config, user_id)?,
+                    };
+
+                    insert(&notices_room)
+                        .into(server_notices_rooms::table)
+                        .get_result(connection)
+                        .map_err(ApiError::from)
+                }
+                Err(error) => Err(ApiError::from(error)),
+            }
+        }).map_err(ApiError::from)
+    }
+
+    /// Sends an *m.room.message* event with the given content to the user's server notices room.
+    pub fn send_notice(
+        connection: &PgConnection,
+        config: &Config,
+        membership_cache: &MembershipCache,
+        power_levels_cache: &PowerLevelsCache,
+        user_id: &UserId,
+        content: &str,
+        now: i64,
+    ) -> Result<EventId, ApiError> {
+        connection.transaction::<EventId, ApiError, _>(|| {
+            let notices_room = ServerNoticesRoom::find_or_create(connection, config, user_id)?;
+            let room = Room::find(connection, &notices_room.room_id)?;
+
+            let event_id = EventId::new(&config.domain)?;
+            let new_event = NewEvent {
+                content: content.to_string(),
+                event_type: EventType::RoomMessage.to_string(),
+                extra_content: None,
+                id: event_id.clone(),
+                room_id: room.id.clone(),
+                state_key: None,
+                user_id: server_notices_user_id(&config.domain)?,
+            };
+
+            room.send_event(connection, membership_cache, power_levels_cache, &new_event, now)?;
+
+            Ok(event_id)
+        }).map_err(ApiError::from)
+    }
+}
+
+/// Creates a server notices room for the user, invites them, and tags it for them.
+fn create_room(connection: &PgConnection, config: &Config, user_id: &UserId)
+-> Result<RoomId, ApiError> {
+    let notices_user_id = server_notices_user_id(&config.domain)?;
+
+    let new_room = NewRoom {
+        id: RoomId::new(&config.domain)?,
+        user_id: notices_user_id.clone(),
+        public: false,
+        version: config.default_room_version.clone(),
+    };
+
+    let creation_options = CreationOptions {
+        alias: None,
+        encrypt: false,
+        federate: false,
+        invite_list: Some(vec![user_id.to_string()]),
+        name: Some("Server Notices".to_string()),
+        predecessor: None,
+        preset: RoomPreset::PrivateChat,
+        topic: None,
+    };
+
+    let room = Room::create(connection, &new_room, &config.domain, &creation_options)?;
+
+    let options = RoomMembershipOptions {
+        room_id: room.id.clone(),
+        user_id: notices_user_id.clone(),
+        sender: notices_user_id,
+        membership: "join".to_string(),
+    };
+
+    RoomMembership::create(connection, &config.domain, options)?;
+
+    let mut tags = BTreeMap::new();
+    tags.insert(SERVER_NOTICE_TAG.to_string(), Value::Object(BTreeMap::new()));
+
+    let mut content = BTreeMap::new();
+    content.insert("tags".to_string(), Value::Object(tags));
+
+    RoomAccountData::upsert(connection, &NewRoomAccountData {
+        user_id: user_id.clone(),
+        room_id: room.id.clone(),
+        data_type: "m.tag".to_string(),
+        content: to_string(&content)?,
+    })?;
+
+    Ok(room.id)
+}
+
+impl Broadcast {
+    /// Queues a new broadcast for delivery.
+    pub fn create(connection: &PgConnection, new_broadcast: &NewBroadcast)
+    -> Result<Broadcast, ApiError> {
+        insert(new_broadcast)
+            .into(broadcasts::table)
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Returns the broadcasts that haven't reached every user yet, oldest first.
+    pub fn find_pending(connection: &PgConnection) -> Result<Vec<Broadcast>, ApiError> {
+        broadcasts::table
+            .filter(broadcasts::completed.eq(false))
+            .order(broadcasts::id.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Delivers the broadcast to the next batch of active users, marking it completed once there
+    /// are none left.
+    ///
+    /// A user the notice can't be delivered to is logged and skipped, so one bad room doesn't
+    /// stop the rest of the broadcast.
+    pub fn send_batch(
+        &mut self,
+        connection: &PgConnection,
+        config: &Config,
+        membership_cache: &MembershipCache,
+        power_levels_cache: &PowerLevelsCache,
+        now: i64,
+    ) -> Result<(), ApiError> {
+        let user_ids: Vec<UserId> = match self.last_user_id {
+            Some(ref last_user_id) => {
+                users::table
+                    .select(users::id)
+                    .filter(users::active.eq(true))
+                    .filter(users::id.gt(last_user_id))
+                    .order(users::id.asc())
+                    .limit(BROADCAST_BATCH_SIZE)
+                    .get_results(connection)
+            }
+            None => {
+                users::table
+                    .select(users::id)
+                    .filter(users::active.eq(true))
+                    .order(users::id.asc())
+                    .limit(BROADCAST_BATCH_SIZE)
+                    .get_results(connection)
+            }
+        }.map_err(ApiError::from)?;
+
+        for user_id in &user_ids {
+            let result = ServerNoticesRoom::send_notice(
+                connection,
+                config,
+                membership_cache,
+                power_levels_cache,
+                user_id,
+                &self.content,
+                now,
+            );
+
+            if let Err(error) = result {
+                info!("Skipping broadcast {} for {}: {}", self.id, user_id, error);
+            }
+        }
+
+        if let Some(user_id) = user_ids.last() {
+            self.last_user_id = Some(user_id.clone());
+        }
+
+        self.completed = (user_ids.len() as i64) < BROADCAST_BATCH_SIZE;
+
+        self.save_changes::<Broadcast>(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+}
+
+impl SendBroadcasts {
+    /// Creates the job, which checks memberships and power levels against the given caches.
+    pub fn new(
+        membership_cache: Arc<MembershipCache>,
+        power_levels_cache: Arc<PowerLevelsCache>,
+    ) -> Self {
+        SendBroadcasts {
+            membership_cache: membership_cache,
+            power_levels_cache: power_levels_cache,
+        }
+    }
+}
+
+impl Job for SendBroadcasts {
+    fn name(&self) -> &'static str {
+        "send_broadcasts"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn run(&self, connection: &PgConnection, config: &Config, now: i64) -> Result<(), ApiError> {
+        for mut broadcast in Broadcast::find_pending(connection)? {
+            broadcast.send_batch(
+                connection,
+                config,
+                &self.membership_cache,
+                &self.power_levels_cache,
+                now,
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+//! Sending SMS messages and working with phone numbers.
+//!
+//! Ruma doesn't talk to mobile networks itself. Messages are handed to an HTTP gateway configured
+//! with `sms_gateway_url`, which is expected to deliver them.
+
+use std::sync::{Arc, Mutex};
+
+use iron::{Plugin, Request};
+use iron::typemap::Key;
+use persistent::Read;
+use serde_json::to_string;
+
+use error::ApiError;
+use http_client::HttpClient;
+
+/// Calling codes for the countries whose phone numbers Ruma understands, keyed by their ISO 3166-1
+/// alpha-2 code.
+const CALLING_CODES: &'static [(&'static str, &'static str)] = &[
+    ("AR", "54"),
+    ("AT", "43"),
+    ("AU", "61"),
+    ("BE", "32"),
+    ("BR", "55"),
+    ("CA", "1"),
+    ("CH", "41"),
+    ("CN", "86"),
+    ("DE", "49"),
+    ("DK", "45"),
+    ("ES", "34"),
+    ("FI", "358"),
+    ("FR", "33"),
+    ("GB", "44"),
+    ("IE", "353"),
+    ("IN", "91"),
+    ("IT", "39"),
+    ("JP", "81"),
+    ("KR", "82"),
+    ("MX", "52"),
+    ("NL", "31"),
+    ("NO", "47"),
+    ("NZ", "64"),
+    ("PL", "48"),
+    ("PT", "351"),
+    ("RU", "7"),
+    ("SE", "46"),
+    ("US", "1"),
+    ("ZA", "27"),
+];
+
+/// A way of sending SMS messages.
+pub trait Sms: Send + Sync {
+    /// Sends `body` to the phone number `to`, given as an MSISDN.
+    fn send(&self, to: &str, body: &str) -> Result<(), ApiError>;
+}
+
+/// An `Sms` that posts each message as JSON to an HTTP gateway.
+#[derive(Debug)]
+pub struct HttpSmsGateway {
+    http_client: Arc<HttpClient>,
+    url: String,
+}
+
+/// An `Sms` for servers without an SMS gateway, which refuses to send anything.
+#[derive(Debug)]
+pub struct DisabledSms;
+
+/// An `Sms` that keeps messages instead of sending them.
+#[derive(Debug)]
+pub struct RecordingSms {
+    messages: Mutex<Vec<(String, String)>>,
+}
+
+/// An Iron plugin for attaching an `Sms` to an Iron request.
+pub struct ServerSms;
+
+/// The body of a request to an SMS gateway.
+#[derive(Serialize)]
+struct SmsGatewayRequest<'a> {
+    body: &'a str,
+    to: &'a str,
+}
+
+impl HttpSmsGateway {
+    /// Creates a new `HttpSmsGateway` that posts to the given URL with the given client.
+    pub fn new(url: String, http_client: Arc<HttpClient>) -> Self {
+        HttpSmsGateway {
+            http_client: http_client,
+            url: url,
+        }
+    }
+}
+
+impl Sms for HttpSmsGateway {
+    fn send(&self, to: &str, body: &str) -> Result<(), ApiError> {
+        let request = to_string(&SmsGatewayRequest {
+            body: body,
+            to: to,
+        })?;
+
+        let response = self.http_client
+            .post_json(&self.url, &request)
+            .map_err(|error| ApiError::unknown(Some(&format!("Failed to send SMS: {}", error))))?;
+
+        if !response.status.is_success() {
+            error!("SMS gateway rejected a message with {}: {}", response.status, response.body);
+
+            return Err(ApiError::unknown(Some("The SMS could not be sent.")));
+        }
+
+        Ok(())
+    }
+}
+
+impl Sms for DisabledSms {
+    fn send(&self, _to: &str, _body: &str) -> Result<(), ApiError> {
+        Err(ApiError::unimplemented(Some("This homeserver is not configured to send SMS.")))
+    }
+}
+
+impl RecordingSms {
+    /// Creates a new `RecordingSms` with no messages.
+    pub fn new() -> Self {
+        RecordingSms {
+            messages: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the recipient and body of every message sent so far, oldest first.
+    pub fn messages(&self) -> Vec<(String, String)> {
+        self.messages.lock().expect("RecordingSms mutex was poisoned").clone()
+    }
+}
+
+impl Sms for RecordingSms {
+    fn send(&self, to: &str, body: &str) -> Result<(), ApiError> {
+        let mut messages = self.messages.lock().expect("RecordingSms mutex was poisoned");
+
+        messages.push((to.to_string(), body.to_string()));
+
+        Ok(())
+    }
+}
+
+impl ServerSms {
+    /// Extract the `Sms` stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<Arc<Sms>>, ApiError> {
+        request.get::<Read<ServerSms>>().map_err(ApiError::from)
+    }
+}
+
+impl Key for ServerSms {
+    type Value = Arc<Sms>;
+}
+
+/// Converts a phone number as typed by a user in the given country to an MSISDN: the calling code
+/// followed by the national number, as digits only.
+pub fn to_msisdn(country: &str, phone_number: &str) -> Result<String, ApiError> {
+    let country = country.to_uppercase();
+
+    let calling_code = match CALLING_CODES.iter().find(|&&(code, _)| code == country) {
+        Some(&(_, calling_code)) => calling_code,
+        None => return Err(ApiError::invalid_param("country", "Unknown country code.")),
+    };
+
+    let digits: String = phone_number.chars().filter(|c| c.is_digit(10)).collect();
+
+    let msisdn = if phone_number.trim().starts_with('+') {
+        digits
+    } else if country == "IT" {
+        // Italian numbers keep their leading zero after the calling code.
+        format!("{}{}", calling_code, digits)
+    } else {
+        format!("{}{}", calling_code, digits.trim_left_matches('0'))
+    };
+
+    if msisdn.len() <= calling_code.len() || msisdn.len() > 15 {
+        return Err(ApiError::invalid_param("phone_number", "Invalid phone number."));
+    }
+
+    Ok(msisdn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_msisdn;
+
+    #[test]
+    fn national_numbers_get_the_calling_code() {
+        assert_eq!(to_msisdn("gb", "07700 900123").unwrap(), "447700900123");
+        assert_eq!(to_msisdn("US", "(555) 010-0123").unwrap(), "15550100123");
+        assert_eq!(to_msisdn("IT", "06 1234 5678").unwrap(), "390612345678");
+    }
+
+    #[test]
+    fn international_numbers_are_kept() {
+        assert_eq!(to_msisdn("US", "+44 7700 900123").unwrap(), "447700900123");
+    }
+
+    #[test]
+    fn unknown_countries_and_empty_numbers_are_rejected() {
+        assert!(to_msisdn("XX", "07700 900123").is_err());
+        assert!(to_msisdn("GB", "").is_err());
+    }
+}
@@ -0,0 +1,144 @@
+//! Aggregate usage statistics for the server.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use diesel::{FilterDsl, LoadDsl, SelectDsl};
+use diesel::expression::dsl::{count_star, sql};
+use diesel::pg::PgConnection;
+use diesel::result::Error as DieselError;
+use diesel::types::{BigInt, Bool, Text};
+use serde_json::to_string;
+
+use config::Config;
+use error::ApiError;
+use http_client::HttpClient;
+use jobs::Job;
+use schema::{access_tokens, events, rooms, users};
+
+/// A snapshot of the server's usage.
+#[derive(Clone, Debug, Serialize)]
+pub struct Stats {
+    /// The number of users who sent an event or logged in during the last day.
+    pub daily_active_users: i64,
+    /// The number of events sent during the last day.
+    pub daily_events: i64,
+    /// The size of the database on disk, in bytes.
+    pub database_size: i64,
+    /// The number of users who sent an event or logged in during the last 30 days.
+    pub monthly_active_users: i64,
+    /// The number of rooms on the server.
+    pub total_rooms: i64,
+    /// The number of registered users, including deactivated ones.
+    pub total_users: i64,
+}
+
+/// The body of an anonymous statistics report.
+#[derive(Debug, Serialize)]
+struct StatsReport {
+    stats: Stats,
+    version: &'static str,
+}
+
+/// A background job that sends anonymous statistics to `report_stats_url` once a day.
+#[derive(Debug)]
+pub struct ReportStats {
+    http_client: Arc<HttpClient>,
+}
+
+impl Stats {
+    /// Gathers the current statistics from the database.
+    pub fn collect(connection: &PgConnection) -> Result<Stats, ApiError> {
+        Ok(Stats {
+            daily_active_users: active_users(connection, "1 day")?,
+            daily_events: events::table
+                .select(count_star())
+                .filter(sql::<Bool>("created_at > now() - interval '1 day'"))
+                .first(connection)?,
+            database_size: database_size(connection)?,
+            monthly_active_users: active_users(connection, "30 days")?,
+            total_rooms: rooms::table.select(count_star()).first(connection)?,
+            total_users: users::table.select(count_star()).first(connection)?,
+        })
+    }
+}
+
+impl ReportStats {
+    /// Creates a new `ReportStats` that sends reports with the given client.
+    pub fn new(http_client: Arc<HttpClient>) -> Self {
+        ReportStats {
+            http_client: http_client,
+        }
+    }
+}
+
+impl Job for ReportStats {
+    fn name(&self) -> &'static str {
+        "report_stats"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(24 * 60 * 60)
+    }
+
+    fn run(&self, connection: &PgConnection, config: &Config, _now: i64) -> Result<(), ApiError> {
+        let url = match config.report_stats_url {
+            Some(ref url) => url,
+            None => return Ok(()),
+        };
+
+        let report = StatsReport {
+            stats: Stats::collect(connection)?,
+            version: env!("CARGO_PKG_VERSION"),
+        };
+        let body = to_string(&report)?;
+
+        let response = self.http_client
+            .post_json(url, &body)
+            .map_err(|error| ApiError::unknown(Some(&format!("Failed to report stats: {}", error))))?;
+
+        if !response.status.is_success() {
+            return Err(ApiError::unknown(Some(
+                &format!("Stats report was rejected with {}: {}", response.status, response.body)
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Counts the distinct users who sent an event or created an access token within `interval`.
+fn active_users(connection: &PgConnection, interval: &str) -> Result<i64, ApiError> {
+    let condition = format!("created_at > now() - interval '{}'", interval);
+
+    let mut user_ids: Vec<String> = events::table
+        .select(sql::<Text>("DISTINCT user_id"))
+        .filter(sql::<Bool>(&condition))
+        .load(connection)?;
+
+    user_ids.extend(
+        access_tokens::table
+            .select(sql::<Text>("DISTINCT user_id"))
+            .filter(sql::<Bool>(&condition))
+            .load::<String>(connection)?
+    );
+
+    user_ids.sort();
+    user_ids.dedup();
+
+    Ok(user_ids.len() as i64)
+}
+
+/// Looks up the size of the current database.
+fn database_size(connection: &PgConnection) -> Result<i64, ApiError> {
+    // Diesel needs a table to select from, so borrow one that is never empty on a server with an
+    // administrator.
+    match users::table
+        .select(sql::<BigInt>("pg_database_size(current_database())"))
+        .first(connection)
+    {
+        Ok(size) => Ok(size),
+        Err(DieselError::NotFound) => Ok(0),
+        Err(error) => Err(ApiError::from(error)),
+    }
+}
@@ -0,0 +1,72 @@
+//! Positions in the server's streams, such as the ordering of events.
+//!
+//! Stream IDs are handed out by the `next_stream_id` database function as rows are inserted, not
+//! by Ruma itself. Only one transaction can hold the next ID of a stream at a time, so any number
+//! of processes can write to a stream without two rows sharing an ID, and a reader that has seen
+//! a position has also seen every row before it.
+
+use diesel::{FindDsl, LoadDsl, SelectDsl};
+use diesel::expression::dsl::sql;
+use diesel::pg::PgConnection;
+use diesel::types::BigInt;
+
+use error::ApiError;
+use schema::stream_positions;
+
+/// A sequence of rows ordered by stream ID.
+#[derive(Clone, Copy, Debug)]
+pub enum Stream {
+    /// Events, ordered by `events.ordering`.
+    Events,
+}
+
+/// The latest ID handed out in a stream.
+#[derive(Debug, Queryable)]
+pub struct StreamPosition {
+    /// The name of the stream.
+    pub stream: String,
+    /// The latest ID handed out in the stream, or 0 if there are none.
+    pub position: i64,
+}
+
+impl Stream {
+    /// The name of the stream in the database.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Stream::Events => "events",
+        }
+    }
+}
+
+impl StreamPosition {
+    /// Returns the latest ID in the stream whose row has been committed. Every row with a lower ID
+    /// has been committed too.
+    pub fn current(connection: &PgConnection, stream: Stream) -> Result<i64, ApiError> {
+        stream_positions::table
+            .find(stream.name())
+            .select(stream_positions::position)
+            .first(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Hands out the next ID in the stream without inserting a row, for changes that are only
+    /// kept in memory but still need a place in the stream, such as typing notifications.
+    pub fn advance(connection: &PgConnection, stream: Stream) -> Result<i64, ApiError> {
+        sql::<BigInt>(&format!("SELECT next_stream_id('{}')", stream.name()))
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Reserves `count` orderings for historical events, below every ordering reserved before,
+    /// and returns the lowest of them. The orderings are negative, so historical events come
+    /// before all other events but are never part of the events stream.
+    pub fn reserve_history(connection: &PgConnection, count: i64) -> Result<i64, ApiError> {
+        sql::<BigInt>(&format!(
+            "UPDATE stream_positions SET position = position - {} WHERE stream = 'history' \
+             RETURNING position",
+            count
+        ))
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+}
@@ -0,0 +1,125 @@
+//! Integration with the systemd service manager.
+
+use std::env;
+use std::fs::read_link;
+use std::io::Result as IoResult;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixDatagram;
+use std::thread;
+use std::time::Duration;
+
+/// The first file descriptor systemd passes sockets in, as `SD_LISTEN_FDS_START` in
+/// `sd_listen_fds(3)`.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Returns the file descriptor of the listening socket systemd passed to Ruma, if it was started
+/// by socket activation.
+///
+/// If systemd passed more than one socket, only the first is used. The variables describing the
+/// sockets are removed, so processes Ruma starts don't take them as their own.
+pub fn activated_listener() -> Option<RawFd> {
+    let pid = env::var("LISTEN_PID").ok();
+    let count = env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<RawFd>().ok());
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    // The sockets are for another process if LISTEN_PID names one.
+    if pid.is_none() || pid != own_pid() {
+        return None;
+    }
+
+    match count {
+        Some(count) if count > 0 => {
+            if count > 1 {
+                warn!("systemd passed {} sockets; only the first will be used.", count);
+            }
+
+            Some(LISTEN_FDS_START)
+        }
+        _ => None,
+    }
+}
+
+/// Tells systemd that the server has finished starting up and starts sending watchdog
+/// keep-alives, if systemd asked for them.
+///
+/// A keep-alive is only sent when `is_healthy` succeeds, so systemd restarts Ruma if it stops
+/// being able to serve requests, not only if it stops running.
+///
+/// Does nothing if Ruma was not started by systemd with `Type=notify`.
+pub fn notify_ready<F>(is_healthy: F) where F: Fn() -> Result<(), String> + Send + 'static {
+    match notify("READY=1") {
+        Ok(true) => info!("Notified systemd that Ruma is ready."),
+        Ok(false) => {}
+        Err(error) => warn!("Failed to notify systemd that Ruma is ready: {}", error),
+    }
+
+    if let Some(interval) = watchdog_interval() {
+        info!("Sending systemd watchdog keep-alives every {:?}.", interval);
+
+        thread::spawn(move || {
+            loop {
+                match is_healthy() {
+                    Ok(()) => {
+                        if let Err(error) = notify("WATCHDOG=1") {
+                            warn!("Failed to send systemd watchdog keep-alive: {}", error);
+                        }
+                    }
+                    Err(error) => {
+                        warn!("Withholding systemd watchdog keep-alive: {}", error);
+                    }
+                }
+
+                thread::sleep(interval);
+            }
+        });
+    }
+}
+
+/// Sends a state string to systemd over the socket in `NOTIFY_SOCKET`.
+///
+/// Returns `false` without sending anything if `NOTIFY_SOCKET` is not set.
+fn notify(state: &str) -> IoResult<bool> {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return Ok(false),
+    };
+
+    if path.starts_with('@') {
+        warn!("Sockets in the abstract namespace are not supported for systemd notifications.");
+
+        return Ok(false);
+    }
+
+    let socket = UnixDatagram::unbound()?;
+
+    socket.send_to(state.as_bytes(), path)?;
+
+    Ok(true)
+}
+
+/// Determines how often to send watchdog keep-alives: half of the `WATCHDOG_USEC` timeout, so
+/// one late keep-alive doesn't get the service restarted.
+fn watchdog_interval() -> Option<Duration> {
+    // If WATCHDOG_PID names another process, that process is responsible for the keep-alives.
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if own_pid() != Some(pid) {
+            return None;
+        }
+    }
+
+    let usec = match env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse::<u64>().ok()) {
+        Some(usec) if usec > 0 => usec,
+        _ => return None,
+    };
+    let interval = usec / 2;
+
+    Some(Duration::new(interval / 1_000_000, (interval % 1_000_000 * 1000) as u32))
+}
+
+/// The ID of this process, as systemd writes it in `LISTEN_PID` and `WATCHDOG_PID`.
+fn own_pid() -> Option<String> {
+    read_link("/proc/self").ok().and_then(|path| path.to_str().map(|pid| pid.to_string()))
+}
@@ -0,0 +1,157 @@
+//! Sessions for proving ownership of a third-party identifier before it is bound to a user.
+
+use diesel::{
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LoadDsl,
+    SaveChangesDsl,
+    delete,
+    insert,
+};
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::UserId;
+
+use crypto::secrets_match;
+use error::ApiError;
+use schema::threepid_validations;
+use user_threepid::{NewUserThreepid, UserThreepid};
+
+/// A validation session, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "threepid_validations"]
+pub struct NewThreepidValidation {
+    /// The session ID, given to the client as `sid`.
+    pub id: String,
+    /// A secret chosen by the client, which must accompany the session ID.
+    pub client_secret: String,
+    /// The kind of identifier being validated, e.g. *msisdn*.
+    pub medium: String,
+    /// The identifier being validated.
+    pub address: String,
+    /// The token sent to the identifier, which the user must give back.
+    pub token: String,
+    /// The highest `send_attempt` the client has requested the token with.
+    pub send_attempt: i64,
+}
+
+/// A validation session for a third-party identifier.
+#[derive(AsChangeset, Clone, Debug, Identifiable, Queryable)]
+#[table_name = "threepid_validations"]
+pub struct ThreepidValidation {
+    /// The session ID, given to the client as `sid`.
+    pub id: String,
+    /// A secret chosen by the client, which must accompany the session ID.
+    pub client_secret: String,
+    /// The kind of identifier being validated, e.g. *msisdn*.
+    pub medium: String,
+    /// The identifier being validated.
+    pub address: String,
+    /// The token sent to the identifier, which the user must give back.
+    pub token: String,
+    /// The highest `send_attempt` the client has requested the token with.
+    pub send_attempt: i64,
+    /// Whether or not the user has given back the correct token.
+    pub validated: bool,
+    /// The time the session was started.
+    pub created_at: PgTimestamp,
+}
+
+impl ThreepidValidation {
+    /// Starts a new validation session.
+    pub fn create(connection: &PgConnection, new_validation: &NewThreepidValidation)
+    -> Result<ThreepidValidation, ApiError> {
+        insert(new_validation)
+            .into(threepid_validations::table)
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Looks up the session a client already started for an identifier.
+    pub fn find_by_address(
+        connection: &PgConnection,
+        client_secret: &str,
+        medium: &str,
+        address: &str,
+    ) -> Result<Option<ThreepidValidation>, ApiError> {
+        let validations: Vec<ThreepidValidation> = threepid_validations::table
+            .filter(threepid_validations::medium.eq(medium))
+            .filter(threepid_validations::address.eq(address))
+            .load(connection)?;
+
+        Ok(validations.into_iter().find(|validation| {
+            secrets_match(&validation.client_secret, client_secret)
+        }))
+    }
+
+    /// Looks up a session by its ID, failing unless the client secret matches.
+    pub fn find(connection: &PgConnection, id: &str, client_secret: &str)
+    -> Result<ThreepidValidation, ApiError> {
+        let validation = threepid_validations::table
+            .filter(threepid_validations::id.eq(id))
+            .first::<ThreepidValidation>(connection);
+
+        let not_found = || ApiError::threepid_auth_failed(
+            Some("No validation session was found for that sid and client_secret.")
+        );
+
+        match validation {
+            Ok(validation) => if secrets_match(&validation.client_secret, client_secret) {
+                Ok(validation)
+            } else {
+                Err(not_found())
+            },
+            Err(DieselError::NotFound) => Err(not_found()),
+            Err(error) => Err(ApiError::from(error)),
+        }
+    }
+
+    /// Records that the client asked for the token to be sent again.
+    pub fn set_send_attempt(&mut self, connection: &PgConnection, send_attempt: i64)
+    -> Result<(), ApiError> {
+        self.send_attempt = send_attempt;
+
+        self.save_changes::<ThreepidValidation>(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+
+    /// Marks the session as validated if `token` is the one that was sent.
+    pub fn validate(&mut self, connection: &PgConnection, token: &str) -> Result<(), ApiError> {
+        if !secrets_match(&self.token, token.trim()) {
+            return Err(ApiError::threepid_auth_failed(Some("The token is incorrect.")));
+        }
+
+        self.validated = true;
+
+        self.save_changes::<ThreepidValidation>(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+
+    /// Binds the validated identifier to a user, ending the session.
+    pub fn bind(&self, connection: &PgConnection, user_id: UserId)
+    -> Result<UserThreepid, ApiError> {
+        if !self.validated {
+            return Err(ApiError::threepid_auth_failed(
+                Some("The third-party identifier has not been validated yet.")
+            ));
+        }
+
+        connection.transaction::<UserThreepid, ApiError, _>(|| {
+            delete(threepid_validations::table.find(self.id.clone()))
+                .execute(connection)
+                .map_err(ApiError::from)?;
+
+            UserThreepid::create(connection, NewUserThreepid {
+                user_id: user_id,
+                medium: self.medium.clone(),
+                address: self.address.clone(),
+            })
+        }).map_err(ApiError::from)
+    }
+}
@@ -0,0 +1,143 @@
+//! Messages sent directly to a user's devices, outside of any room.
+
+use std::collections::BTreeMap;
+
+use diesel::{
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    LoadDsl,
+    OrderDsl,
+    SelectDsl,
+    delete,
+    insert,
+};
+use diesel::expression::dsl::count_star;
+use diesel::pg::PgConnection;
+use ruma_identifiers::UserId;
+use serde_json::{Value, from_str};
+
+use error::ApiError;
+use schema::{to_device_messages, to_device_transactions};
+
+/// A message waiting to be delivered to a device.
+#[derive(Clone, Debug, Queryable)]
+pub struct ToDeviceMessage {
+    /// Entry ID.
+    pub id: i64,
+    /// The user the message is for.
+    pub user_id: UserId,
+    /// The device the message is for.
+    pub device_id: String,
+    /// The user who sent the message.
+    pub sender: UserId,
+    /// The type of the message, e.g. *m.room_key*.
+    pub event_type: String,
+    /// The message's content as JSON.
+    pub content: String,
+    /// The position of the message in the events stream.
+    pub ordering: i64,
+}
+
+/// A new message for a device, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "to_device_messages"]
+pub struct NewToDeviceMessage {
+    /// The user the message is for.
+    pub user_id: UserId,
+    /// The device the message is for.
+    pub device_id: String,
+    /// The user who sent the message.
+    pub sender: UserId,
+    /// The type of the message, e.g. *m.room_key*.
+    pub event_type: String,
+    /// The message's content as JSON.
+    pub content: String,
+}
+
+/// A transaction ID a client has already used to send messages to devices, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "to_device_transactions"]
+struct NewToDeviceTransaction {
+    access_token_id: i64,
+    transaction_id: String,
+}
+
+impl ToDeviceMessage {
+    /// Queues messages for delivery.
+    ///
+    /// Clients retry requests they didn't get a response to, so nothing is queued if the
+    /// transaction ID was already used with the access token.
+    pub fn send(
+        connection: &PgConnection,
+        access_token_id: i64,
+        transaction_id: &str,
+        new_messages: &[NewToDeviceMessage],
+    ) -> Result<(), ApiError> {
+        connection.transaction::<(), ApiError, _>(|| {
+            let previous: i64 = to_device_transactions::table
+                .filter(to_device_transactions::access_token_id.eq(access_token_id))
+                .filter(to_device_transactions::transaction_id.eq(transaction_id))
+                .select(count_star())
+                .first(connection)?;
+
+            if previous > 0 {
+                return Ok(());
+            }
+
+            let new_transaction = NewToDeviceTransaction {
+                access_token_id: access_token_id,
+                transaction_id: transaction_id.to_string(),
+            };
+
+            insert(&new_transaction).into(to_device_transactions::table).execute(connection)?;
+
+            for new_message in new_messages {
+                insert(new_message).into(to_device_messages::table).execute(connection)?;
+            }
+
+            Ok(())
+        }).map_err(ApiError::from)
+    }
+
+    /// Returns the messages for a device up to the position `up_to`, oldest first.
+    ///
+    /// Messages up to the position `acknowledged` have been delivered, since the client synced
+    /// from a later position, so they are deleted instead.
+    pub fn take(
+        connection: &PgConnection,
+        user_id: &UserId,
+        device_id: &str,
+        acknowledged: Option<i64>,
+        up_to: i64,
+    ) -> Result<Vec<ToDeviceMessage>, ApiError> {
+        if let Some(acknowledged) = acknowledged {
+            let delivered = to_device_messages::table
+                .filter(to_device_messages::user_id.eq(user_id))
+                .filter(to_device_messages::device_id.eq(device_id))
+                .filter(to_device_messages::ordering.le(acknowledged));
+
+            delete(delivered).execute(connection)?;
+        }
+
+        to_device_messages::table
+            .filter(to_device_messages::user_id.eq(user_id))
+            .filter(to_device_messages::device_id.eq(device_id))
+            .filter(to_device_messages::ordering.le(up_to))
+            .order(to_device_messages::ordering.asc())
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Formats the message as an event for the `to_device` section of `/sync`.
+    pub fn to_event(&self) -> Result<Value, ApiError> {
+        let mut event = BTreeMap::new();
+
+        event.insert("content".to_string(), from_str(&self.content)?);
+        event.insert("sender".to_string(), Value::String(self.sender.to_string()));
+        event.insert("type".to_string(), Value::String(self.event_type.clone()));
+
+        Ok(Value::Object(event))
+    }
+}
@@ -0,0 +1,105 @@
+//! Client transaction IDs, used to make sending events idempotent.
+
+use diesel::{ExpressionMethods, FilterDsl, LoadDsl, insert};
+use diesel::expression::dsl::any;
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::EventId;
+
+use error::ApiError;
+use schema::transactions;
+
+/// A transaction ID a client has already used, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "transactions"]
+pub struct NewTransaction {
+    /// The ID of the access token the event was sent with, unless an application service sent it.
+    pub access_token_id: Option<i64>,
+    /// The ID of the application service that sent the event, if any.
+    pub app_service_id: Option<String>,
+    /// The transaction ID chosen by the client.
+    pub transaction_id: String,
+    /// The event that was sent in the transaction.
+    pub event_id: EventId,
+}
+
+/// A transaction ID a client has already used.
+///
+/// Transaction IDs are scoped to the access token, so each of a user's devices has its own.
+/// Application services have no access tokens, so theirs are scoped to the application service.
+#[derive(Debug, Queryable)]
+pub struct Transaction {
+    /// The transaction's ID in the database.
+    pub id: i64,
+    /// The ID of the access token the event was sent with, unless an application service sent it.
+    pub access_token_id: Option<i64>,
+    /// The ID of the application service that sent the event, if any.
+    pub app_service_id: Option<String>,
+    /// The transaction ID chosen by the client.
+    pub transaction_id: String,
+    /// The event that was sent in the transaction.
+    pub event_id: EventId,
+    /// The time the transaction was first seen.
+    pub created_at: PgTimestamp,
+}
+
+impl Transaction {
+    /// Records that the client sent an event with the given transaction ID.
+    pub fn create(connection: &PgConnection, new_transaction: &NewTransaction)
+    -> Result<Transaction, ApiError> {
+        insert(new_transaction)
+            .into(transactions::table)
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Looks up a transaction ID previously used with the given access token.
+    pub fn find(connection: &PgConnection, access_token_id: i64, transaction_id: &str)
+    -> Result<Option<Transaction>, ApiError> {
+        let transaction = transactions::table
+            .filter(transactions::access_token_id.eq(access_token_id))
+            .filter(transactions::transaction_id.eq(transaction_id))
+            .first(connection);
+
+        match transaction {
+            Ok(transaction) => Ok(Some(transaction)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(error) => Err(ApiError::from(error)),
+        }
+    }
+
+    /// Returns the transactions in which the given events were sent with the given access token.
+    /// Events sent some other way are skipped.
+    pub fn find_by_event_ids(
+        connection: &PgConnection,
+        access_token_id: i64,
+        event_ids: &[EventId],
+    ) -> Result<Vec<Transaction>, ApiError> {
+        let event_ids: Vec<String> = event_ids.iter().map(EventId::to_string).collect();
+
+        transactions::table
+            .filter(transactions::access_token_id.eq(access_token_id))
+            .filter(transactions::event_id.eq(any(event_ids)))
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Looks up a transaction ID previously used by the given application service.
+    pub fn find_for_app_service(
+        connection: &PgConnection,
+        app_service_id: &str,
+        transaction_id: &str,
+    ) -> Result<Option<Transaction>, ApiError> {
+        let transaction = transactions::table
+            .filter(transactions::app_service_id.eq(app_service_id))
+            .filter(transactions::transaction_id.eq(transaction_id))
+            .first(connection);
+
+        match transaction {
+            Ok(transaction) => Ok(Some(transaction)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(error) => Err(ApiError::from(error)),
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Typing notifications.
+//!
+//! Who is typing is only kept in memory, since it stops mattering within seconds. Each change
+//! still takes an ID from the events stream, so that `/sync` can tell which rooms changed after
+//! its `since` token the same way it does for events. Changes can be batched, so that a room
+//! whose typing users change many times within the batch only takes one ID for all of them.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use diesel::pg::PgConnection;
+use iron::{Plugin, Request};
+use iron::typemap::Key;
+use persistent::Read;
+use ruma_identifiers::{RoomId, UserId};
+
+use config::Config;
+use error::ApiError;
+use jobs::Job;
+use stream_position::{Stream, StreamPosition};
+
+/// The users typing in each room.
+#[derive(Debug)]
+pub struct Typing {
+    batch_millis: u64,
+    rooms: Mutex<HashMap<RoomId, RoomTyping>>,
+}
+
+/// The users typing in a single room.
+#[derive(Debug)]
+struct RoomTyping {
+    /// The position in the events stream of the last change.
+    changed_at: i64,
+    /// Whether or not the users changed since the last batch, without taking a new position yet.
+    pending: bool,
+    /// When each user stops typing, in milliseconds since the Unix epoch.
+    users: HashMap<UserId, i64>,
+}
+
+/// An Iron plugin for attaching `Typing` to an Iron request.
+pub struct ServerTyping;
+
+/// A background job that stops users typing once their timeout has passed, and gives rooms whose
+/// typing users changed since it last ran a new position.
+#[derive(Debug)]
+pub struct ExpireTyping {
+    typing: Arc<Typing>,
+}
+
+impl Typing {
+    /// Creates a new `Typing` with nobody typing, which batches changes for `batch_millis`
+    /// milliseconds, or gives each change a new position right away if that's 0.
+    pub fn new(batch_millis: u64) -> Self {
+        Typing {
+            batch_millis: batch_millis,
+            rooms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Marks the user as typing in the room until `until`, in milliseconds since the Unix epoch.
+    pub fn start(&self, connection: &PgConnection, room_id: &RoomId, user_id: &UserId, until: i64)
+    -> Result<(), ApiError> {
+        let mut rooms = self.lock();
+        let room = rooms.entry(room_id.clone()).or_insert_with(|| RoomTyping {
+            changed_at: 0,
+            pending: false,
+            users: HashMap::new(),
+        });
+
+        // Extending a timeout isn't a change clients need to hear about.
+        if room.users.insert(user_id.clone(), until).is_none() {
+            self.changed(connection, room)?;
+        }
+
+        Ok(())
+    }
+
+    /// Marks the user as no longer typing in the room.
+    pub fn stop(&self, connection: &PgConnection, room_id: &RoomId, user_id: &UserId)
+    -> Result<(), ApiError> {
+        let mut rooms = self.lock();
+
+        if let Some(room) = rooms.get_mut(room_id) {
+            if room.users.remove(user_id).is_some() {
+                self.changed(connection, room)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the users typing in the room, if that changed after the position `since`. Without
+    /// `since`, returns the users typing if there are any.
+    pub fn typing_users(&self, room_id: &RoomId, since: Option<i64>) -> Option<Vec<UserId>> {
+        let rooms = self.lock();
+        let room = match rooms.get(room_id) {
+            Some(room) => room,
+            None => return None,
+        };
+
+        let changed = match since {
+            Some(since) => room.changed_at > since,
+            None => !room.users.is_empty(),
+        };
+
+        if !changed {
+            return None;
+        }
+
+        let mut user_ids: Vec<UserId> = room.users.keys().cloned().collect();
+
+        user_ids.sort_by(|a, b| a.to_string().cmp(&b.to_string()));
+
+        Some(user_ids)
+    }
+
+    /// Stops every user whose timeout is at or before `now`.
+    pub fn expire(&self, connection: &PgConnection, now: i64) -> Result<(), ApiError> {
+        let mut rooms = self.lock();
+
+        for room in rooms.values_mut() {
+            let expired: Vec<UserId> = room.users.iter()
+                .filter(|&(_, until)| *until <= now)
+                .map(|(user_id, _)| user_id.clone())
+                .collect();
+
+            if expired.is_empty() {
+                continue;
+            }
+
+            for user_id in &expired {
+                room.users.remove(user_id);
+            }
+
+            self.changed(connection, room)?;
+        }
+
+        Ok(())
+    }
+
+    /// Gives every room whose typing users changed during the current batch a new position.
+    pub fn save_batch(&self, connection: &PgConnection) -> Result<(), ApiError> {
+        let mut rooms = self.lock();
+
+        for room in rooms.values_mut().filter(|room| room.pending) {
+            room.changed_at = StreamPosition::advance(connection, Stream::Events)?;
+            room.pending = false;
+        }
+
+        Ok(())
+    }
+
+    /// Records that the room's typing users changed, either right away or with the next batch.
+    fn changed(&self, connection: &PgConnection, room: &mut RoomTyping) -> Result<(), ApiError> {
+        if self.batch_millis == 0 {
+            room.changed_at = StreamPosition::advance(connection, Stream::Events)?;
+        } else {
+            room.pending = true;
+        }
+
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<HashMap<RoomId, RoomTyping>> {
+        self.rooms.lock().expect("Typing mutex was poisoned")
+    }
+}
+
+impl ServerTyping {
+    /// Extract the `Typing` stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<Arc<Typing>>, ApiError> {
+        request.get::<Read<ServerTyping>>().map_err(ApiError::from)
+    }
+}
+
+impl Key for ServerTyping {
+    type Value = Arc<Typing>;
+}
+
+impl ExpireTyping {
+    /// Creates a job that expires typing notifications in the given store.
+    pub fn new(typing: Arc<Typing>) -> Self {
+        ExpireTyping {
+            typing: typing,
+        }
+    }
+}
+
+impl Job for ExpireTyping {
+    fn name(&self) -> &'static str {
+        "expire_typing"
+    }
+
+    fn interval(&self) -> Duration {
+        match self.typing.batch_millis {
+            0 => Duration::from_secs(1),
+            batch_millis => Duration::from_millis(batch_millis),
+        }
+    }
+
+    fn run(&self, connection: &PgConnection, _config: &Config, now: i64) -> Result<(), ApiError> {
+        self.typing.expire(connection, now)?;
+        self.typing.save_batch(connection)
+    }
+}
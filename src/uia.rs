@@ -0,0 +1,534 @@
+//! User-interactive authentication.
+//!
+//! Endpoints that need the user to prove who they are, or that they aren't a bot, list the flows
+//! of stages that satisfy them in an `InteractiveAuth`. The client completes one stage per
+//! request by sending an `auth` object in the body, and the stages completed so far are kept in
+//! a session until every stage of one flow is done. Until then, each request is answered with a
+//! 401 `AuthChallenge` listing the flows, the completed stages, and the session ID to send back.
+//!
+//! Sessions belong to the endpoint that started them, and to the user whose access token started
+//! them, and expire after `SESSION_LIFETIME` milliseconds.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fmt::Error as FmtError;
+use std::io::Read;
+
+use diesel::{
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LoadDsl,
+    SaveChangesDsl,
+    delete,
+    insert,
+};
+use diesel::pg::PgConnection;
+use diesel::result::Error as DieselError;
+use hyper::Client;
+use hyper::header::ContentType;
+use iron::Response;
+use iron::modifier::Modifier;
+use iron::status::Status;
+use ruma_identifiers::UserId;
+use serde::{Serialize, Serializer};
+use serde_json::{Value, from_str, to_string, to_value};
+use url::form_urlencoded::Serializer as FormSerializer;
+
+use authentication::{AuthParams, PasswordAuthParams};
+use config::Config;
+use crypto::Entropy;
+use error::ApiError;
+use schema::uia_sessions;
+use user::User;
+
+/// How long a session lasts after it is started, in milliseconds.
+pub const SESSION_LIFETIME: i64 = 15 * 60 * 1000;
+
+/// The length of generated session IDs.
+const SESSION_ID_LENGTH: usize = 24;
+
+/// Where reCAPTCHA responses are checked.
+const RECAPTCHA_VERIFY_URL: &'static str = "https://www.google.com/recaptcha/api/siteverify";
+
+/// The flows a user can follow to authenticate a request to one endpoint.
+#[derive(Clone, Debug)]
+pub struct InteractiveAuth {
+    endpoint: &'static str,
+    flows: Vec<Flow>,
+}
+
+/// A list of `AuthType`s that satisfy authentication requirements.
+#[derive(Clone, Debug, Serialize)]
+pub struct Flow {
+    #[serde(rename="stages")]
+    auth_types: Vec<AuthType>,
+}
+
+/// An individiual authentication mechanism to be used in a `Flow`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthType {
+    /// m.login.dummy, which always succeeds.
+    Dummy,
+    /// m.login.password
+    Password,
+    /// m.login.recaptcha, only offered if the server has reCAPTCHA keys.
+    Recaptcha,
+}
+
+/// The outcome of a request's `auth` object.
+#[derive(Debug)]
+pub enum AuthResult {
+    /// A flow has been completed. Holds the user who authenticated with a password, or the user
+    /// who made the request if no stage identified one.
+    Complete(Option<User>),
+    /// More stages are needed, or the stage attempted failed.
+    Incomplete(AuthChallenge),
+}
+
+/// The 401 response telling the client which stages remain.
+#[derive(Clone, Debug)]
+pub struct AuthChallenge {
+    completed: Vec<AuthType>,
+    error: Option<ApiError>,
+    flows: Vec<Flow>,
+    params: BTreeMap<String, Value>,
+    session: String,
+}
+
+/// A session, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "uia_sessions"]
+pub struct NewUiaSession {
+    /// The session ID, given to the client as `session`.
+    pub id: String,
+    /// The name of the endpoint that started the session.
+    pub endpoint: String,
+    /// The user whose access token started the session, if the endpoint needs one.
+    pub user_id: Option<UserId>,
+    /// When the session expires, in milliseconds since the Unix epoch.
+    pub expires_at: i64,
+}
+
+/// The stages a client has completed towards authenticating one request.
+#[derive(AsChangeset, Clone, Debug, Identifiable, Queryable)]
+#[table_name = "uia_sessions"]
+pub struct UiaSession {
+    /// The session ID, given to the client as `session`.
+    pub id: String,
+    /// The name of the endpoint that started the session.
+    pub endpoint: String,
+    /// The user whose access token started the session, or who authenticated with a password.
+    pub user_id: Option<UserId>,
+    /// The types of the stages completed so far.
+    pub completed: Vec<String>,
+    /// When the session expires, in milliseconds since the Unix epoch.
+    pub expires_at: i64,
+}
+
+/// Whether or not an attempted stage succeeded.
+enum StageOutcome {
+    Passed,
+    Failed(ApiError),
+}
+
+impl InteractiveAuth {
+    /// Creates a new `InteractiveAuth` for the named endpoint from the given flows.
+    ///
+    /// The name keeps a session started at one endpoint from being used at another.
+    pub fn new(endpoint: &'static str, flows: Vec<Flow>) -> Self {
+        InteractiveAuth {
+            endpoint: endpoint,
+            flows: flows,
+        }
+    }
+
+    /// The flows for registering a new account: a reCAPTCHA if the server has reCAPTCHA keys, or
+    /// a dummy stage otherwise.
+    pub fn registration(config: &Config) -> Self {
+        let auth_type = if config.recaptcha_private_key.is_some() {
+            AuthType::Recaptcha
+        } else {
+            AuthType::Dummy
+        };
+
+        InteractiveAuth::new("register", vec![Flow::new(vec![auth_type])])
+    }
+
+    /// Advances the session in `auth`, the `auth` object of the request body, by the stage it
+    /// attempts, starting a new session if it doesn't name one.
+    ///
+    /// `user` is the user whose access token authenticated the request, if any. A password stage
+    /// must authenticate as the same user.
+    pub fn authenticate(
+        &self,
+        connection: &PgConnection,
+        config: &Config,
+        entropy: &Entropy,
+        auth: Option<&Value>,
+        user: Option<&User>,
+        now: i64,
+    ) -> Result<AuthResult, ApiError> {
+        let user_id = user.map(|user| &user.id);
+
+        let auth = match auth {
+            Some(auth) => auth,
+            None => {
+                let session = UiaSession::create(connection, entropy, self.endpoint, user_id, now)?;
+
+                return Ok(AuthResult::Incomplete(self.challenge(config, &session, None)));
+            }
+        };
+
+        let mut session = match auth.find("session").and_then(Value::as_str) {
+            Some(session_id) => {
+                UiaSession::find(connection, session_id, self.endpoint, user_id, now)?
+            }
+            None => UiaSession::create(connection, entropy, self.endpoint, user_id, now)?,
+        };
+
+        let auth_type = match auth.find("type").and_then(Value::as_str) {
+            Some(auth_type) => auth_type,
+            None => return Ok(AuthResult::Incomplete(self.challenge(config, &session, None))),
+        };
+
+        let offered = self.flows(config)
+            .iter()
+            .flat_map(|flow| flow.auth_types.iter())
+            .find(|offered| offered.as_str() == auth_type)
+            .cloned();
+
+        let auth_type = match offered {
+            Some(auth_type) => auth_type,
+            None => {
+                let error = ApiError::invalid_param("type", "Must be one of the offered stages.");
+
+                return Ok(AuthResult::Incomplete(self.challenge(config, &session, Some(error))));
+            }
+        };
+
+        let outcome = match auth_type {
+            AuthType::Dummy => StageOutcome::Passed,
+            AuthType::Password => {
+                check_password(connection, config, auth, user_id, &mut session.user_id)?
+            }
+            AuthType::Recaptcha => check_recaptcha(config, auth)?,
+        };
+
+        if let StageOutcome::Failed(error) = outcome {
+            return Ok(AuthResult::Incomplete(self.challenge(config, &session, Some(error))));
+        }
+
+        if !session.completed.iter().any(|completed| completed == auth_type.as_str()) {
+            session.completed.push(auth_type.as_str().to_string());
+        }
+
+        let is_complete = self.flows(config).iter().any(|flow| {
+            flow.auth_types.iter().all(|auth_type| {
+                session.completed.iter().any(|completed| completed == auth_type.as_str())
+            })
+        });
+
+        if !is_complete {
+            session.save(connection)?;
+
+            return Ok(AuthResult::Incomplete(self.challenge(config, &session, None)));
+        }
+
+        session.delete(connection)?;
+
+        match session.user_id {
+            Some(ref user_id) if user.is_none() => {
+                Ok(AuthResult::Complete(Some(User::find_by_uid(connection, user_id)?)))
+            }
+            Some(_) | None => Ok(AuthResult::Complete(user.cloned())),
+        }
+    }
+
+    /// The flows this server can offer: those with a reCAPTCHA stage are left out unless the
+    /// server has reCAPTCHA keys.
+    fn flows(&self, config: &Config) -> Vec<Flow> {
+        self.flows
+            .iter()
+            .filter(|flow| {
+                config.recaptcha_private_key.is_some()
+                    || !flow.auth_types.contains(&AuthType::Recaptcha)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Describes the session's progress to the client.
+    fn challenge(&self, config: &Config, session: &UiaSession, error: Option<ApiError>)
+    -> AuthChallenge {
+        let flows = self.flows(config);
+        let mut params = BTreeMap::new();
+
+        let has_recaptcha = flows.iter().any(|flow| flow.auth_types.contains(&AuthType::Recaptcha));
+
+        if has_recaptcha {
+            if let Some(ref public_key) = config.recaptcha_public_key {
+                let mut recaptcha_params = BTreeMap::new();
+                recaptcha_params.insert(
+                    "public_key".to_string(),
+                    Value::String(public_key.clone()),
+                );
+
+                params.insert(
+                    AuthType::Recaptcha.as_str().to_string(),
+                    Value::Object(recaptcha_params),
+                );
+            }
+        }
+
+        AuthChallenge {
+            completed: session.completed
+                .iter()
+                .filter_map(|completed| AuthType::from_str(completed))
+                .collect(),
+            error: error,
+            flows: flows,
+            params: params,
+            session: session.id.clone(),
+        }
+    }
+}
+
+impl Flow {
+    /// Creates a new `Flow` from the given auth types.
+    pub fn new(auth_types: Vec<AuthType>) -> Self {
+        Flow {
+            auth_types: auth_types,
+        }
+    }
+}
+
+impl AuthType {
+    /// The name of the stage in the Matrix specification.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            AuthType::Dummy => "m.login.dummy",
+            AuthType::Password => "m.login.password",
+            AuthType::Recaptcha => "m.login.recaptcha",
+        }
+    }
+
+    /// Looks up a stage by its name in the Matrix specification.
+    pub fn from_str(name: &str) -> Option<AuthType> {
+        match name {
+            "m.login.dummy" => Some(AuthType::Dummy),
+            "m.login.password" => Some(AuthType::Password),
+            "m.login.recaptcha" => Some(AuthType::Recaptcha),
+            _ => None,
+        }
+    }
+}
+
+impl Serialize for AuthType {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error> where S: Serializer {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl Display for AuthChallenge {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match self.error {
+            Some(ref error) => write!(f, "{}", error),
+            None => write!(f, "Additional authentication is required."),
+        }
+    }
+}
+
+impl Error for AuthChallenge {
+    fn description(&self) -> &str {
+        match self.error {
+            Some(ref error) => error.description(),
+            None => "Additional authentication is required.",
+        }
+    }
+}
+
+impl Modifier<Response> for AuthChallenge {
+    fn modify(self, response: &mut Response) {
+        let mut body = BTreeMap::new();
+
+        body.insert("completed".to_string(), to_value(&self.completed));
+        body.insert("flows".to_string(), to_value(&self.flows));
+        body.insert("params".to_string(), Value::Object(self.params));
+        body.insert("session".to_string(), Value::String(self.session));
+
+        if let Some(Value::Object(error)) = self.error.as_ref().map(to_value) {
+            body.extend(error);
+        }
+
+        response.status = Some(Status::Unauthorized);
+        response.body = Some(Box::new(
+            to_string(&body).expect("AuthChallenge should always serialize")
+        ));
+    }
+}
+
+impl UiaSession {
+    /// Starts a new session, clearing out any that have expired.
+    pub fn create(
+        connection: &PgConnection,
+        entropy: &Entropy,
+        endpoint: &str,
+        user_id: Option<&UserId>,
+        now: i64,
+    ) -> Result<UiaSession, ApiError> {
+        delete(uia_sessions::table.filter(uia_sessions::expires_at.lt(now)))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        let new_session = NewUiaSession {
+            id: entropy.alphanumeric(SESSION_ID_LENGTH),
+            endpoint: endpoint.to_string(),
+            user_id: user_id.cloned(),
+            expires_at: now + SESSION_LIFETIME,
+        };
+
+        insert(&new_session)
+            .into(uia_sessions::table)
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Looks up an unexpired session started at the given endpoint by the given user.
+    pub fn find(
+        connection: &PgConnection,
+        id: &str,
+        endpoint: &str,
+        user_id: Option<&UserId>,
+        now: i64,
+    ) -> Result<UiaSession, ApiError> {
+        let session = uia_sessions::table
+            .find(id.to_string())
+            .first::<UiaSession>(connection);
+
+        let not_found = || ApiError::invalid_param(
+            "session",
+            "No authentication session with that ID was found.",
+        );
+
+        match session {
+            Ok(session) => {
+                let same_user = user_id
+                    .map(|user_id| session.user_id.as_ref() == Some(user_id))
+                    .unwrap_or(true);
+
+                if session.endpoint == endpoint && session.expires_at > now && same_user {
+                    Ok(session)
+                } else {
+                    Err(not_found())
+                }
+            }
+            Err(DieselError::NotFound) => Err(not_found()),
+            Err(error) => Err(ApiError::from(error)),
+        }
+    }
+
+    /// Saves the stages completed so far.
+    pub fn save(&self, connection: &PgConnection) -> Result<(), ApiError> {
+        self.save_changes::<UiaSession>(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+
+    /// Ends the session.
+    pub fn delete(&self, connection: &PgConnection) -> Result<(), ApiError> {
+        delete(uia_sessions::table.find(self.id.clone()))
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+}
+
+/// Checks the user's password, recording who they are in `session_user_id`.
+///
+/// A user who has already identified themselves, with an access token or an earlier password
+/// stage, must give their own password.
+fn check_password(
+    connection: &PgConnection,
+    config: &Config,
+    auth: &Value,
+    user_id: Option<&UserId>,
+    session_user_id: &mut Option<UserId>,
+) -> Result<StageOutcome, ApiError> {
+    let credentials = match PasswordAuthParams::from_json(auth, config, connection) {
+        Some(credentials) => credentials,
+        None => {
+            return Ok(StageOutcome::Failed(ApiError::unauthorized(
+                Some("A valid user and a password are required.")
+            )));
+        }
+    };
+
+    let user = match AuthParams::Password(credentials).authenticate(connection, config) {
+        Ok(user) => user,
+        Err(_) => {
+            return Ok(StageOutcome::Failed(ApiError::unauthorized(Some("Invalid password."))));
+        }
+    };
+
+    if user.locked {
+        return Err(ApiError::user_locked(None));
+    }
+
+    let is_other_user = user_id
+        .or(session_user_id.as_ref())
+        .map(|user_id| *user_id != user.id)
+        .unwrap_or(false);
+
+    if is_other_user {
+        return Ok(StageOutcome::Failed(ApiError::unauthorized(
+            Some("The password must be that of the user making the request.")
+        )));
+    }
+
+    *session_user_id = Some(user.id);
+
+    Ok(StageOutcome::Passed)
+}
+
+/// Checks the user's reCAPTCHA response with Google.
+fn check_recaptcha(config: &Config, auth: &Value) -> Result<StageOutcome, ApiError> {
+    let private_key = match config.recaptcha_private_key {
+        Some(ref private_key) => private_key,
+        None => return Err(ApiError::unimplemented(Some("reCAPTCHA is not configured."))),
+    };
+
+    let recaptcha_response = match auth.find("response").and_then(Value::as_str) {
+        Some(recaptcha_response) => recaptcha_response,
+        None => return Ok(StageOutcome::Failed(ApiError::missing_param("response"))),
+    };
+
+    let body = FormSerializer::new(String::new())
+        .append_pair("secret", private_key)
+        .append_pair("response", recaptcha_response)
+        .finish();
+
+    let mut response = Client::new()
+        .post(RECAPTCHA_VERIFY_URL)
+        .header(ContentType::form_url_encoded())
+        .body(&body[..])
+        .send()
+        .map_err(|error| {
+            ApiError::unknown(Some(&format!("Failed to verify reCAPTCHA response: {}", error)))
+        })?;
+
+    let mut contents = String::new();
+    response.read_to_string(&mut contents)?;
+
+    let verification: Value = from_str(&contents)?;
+
+    if verification.find("success").and_then(Value::as_bool).unwrap_or(false) {
+        Ok(StageOutcome::Passed)
+    } else {
+        Ok(StageOutcome::Failed(ApiError::unauthorized(
+            Some("The reCAPTCHA response was not valid.")
+        )))
+    }
+}
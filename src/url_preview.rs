@@ -0,0 +1,737 @@
+//! Previews of web pages, which clients show alongside the links in messages.
+//!
+//! A preview is an object of the page's [OpenGraph](http://ogp.me/) properties, such as
+//! `og:title` and `og:description`. The image it names is saved to the content repository and
+//! given by its MXC URI, so clients don't have to fetch it from the page's site. Pages are fetched
+//! by a `UrlFetcher`, which refuses to reach addresses in the configured IP ranges so users can't
+//! probe the server's internal network with previews. Each preview is kept in the `url_previews`
+//! table for an hour, so a page linked in many rooms is only fetched once.
+
+use std::ascii::AsciiExt;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use diesel::{Connection, ExecuteDsl, FindDsl, LoadDsl, delete, insert};
+use diesel::result::Error as DieselError;
+use image::GenericImage;
+use iron::{Plugin, Request};
+use iron::typemap::Key;
+use persistent::Read;
+use ruma_identifiers::UserId;
+use serde_json::{Value, from_str, to_string};
+use url::Url;
+
+use crypto::Entropy;
+use db::RequestConnectionPool;
+use error::ApiError;
+use http_client::{HttpClient, HttpClientError};
+use media::{Media, MediaStorage, content_type_is_svg, decode_image};
+use schema::url_previews;
+
+/// The IP ranges previews may not be fetched from if the configuration doesn't say: loopback,
+/// private, link-local, multicast, and other addresses that aren't on the public internet.
+pub const DEFAULT_URL_PREVIEW_IP_RANGE_BLACKLIST: &'static [&'static str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// How long a preview is kept before the page is fetched again, in milliseconds.
+const URL_PREVIEW_TTL_MILLIS: i64 = 60 * 60 * 1000;
+
+/// The largest page that will be fetched for a preview, in bytes.
+const MAX_PAGE_SIZE: u64 = 1024 * 1024;
+
+/// The most redirects followed when fetching a page or its image.
+const MAX_REDIRECTS: usize = 5;
+
+/// A preview of a web page, as saved.
+#[derive(Debug, Insertable, Queryable)]
+#[table_name = "url_previews"]
+pub struct UrlPreview {
+    /// The URL of the page.
+    pub url: String,
+    /// JSON of the preview's OpenGraph properties.
+    pub og: String,
+    /// The time the preview should be fetched again, in milliseconds since the Unix epoch.
+    pub expires_at: i64,
+}
+
+/// A range of IP addresses in CIDR notation, e.g. `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpRange {
+    address: IpAddr,
+    prefix_length: u32,
+}
+
+/// A web page or image fetched for a preview.
+#[derive(Debug)]
+pub struct FetchedUrl {
+    /// The URL the content was fetched from, after following redirects.
+    pub url: String,
+    /// The content's MIME type, if the site gave one.
+    pub content_type: Option<String>,
+    /// The content.
+    pub body: Vec<u8>,
+}
+
+/// A way of fetching the web pages and images previews are made from.
+pub trait UrlFetcher: Send + Sync {
+    /// Fetches `url`, following redirects, or returns `None` if the site responds with an error.
+    ///
+    /// Fails if the content is larger than `max_size` bytes.
+    fn fetch(&self, url: &str, max_size: u64) -> Result<Option<FetchedUrl>, ApiError>;
+}
+
+/// A `UrlFetcher` that fetches over HTTP, refusing hosts with blacklisted addresses.
+#[derive(Debug)]
+pub struct HttpUrlFetcher {
+    http_client: Arc<HttpClient>,
+    ip_range_blacklist: Vec<IpRange>,
+}
+
+/// A `UrlFetcher` that serves content set up ahead of time instead of fetching it.
+#[derive(Debug)]
+pub struct FakeUrlFetcher {
+    urls: Mutex<HashMap<String, (String, Vec<u8>)>>,
+}
+
+/// An Iron plugin for attaching a `UrlFetcher` to an Iron request.
+pub struct ServerUrlFetcher;
+
+impl UrlPreview {
+    /// Returns the preview of the page at `url`, fetching it if there's no preview that hasn't
+    /// expired yet.
+    ///
+    /// The page's image is saved to the content repository as an upload by `user_id`, and replaced
+    /// with its MXC URI on the server with the given domain. If the URL is itself an image, the
+    /// preview is just that image. Its size is only given if it has at most `max_image_pixels`
+    /// pixels.
+    ///
+    /// No database connection is held while the page and its image are fetched, so slow sites
+    /// can't tie up the connection pool.
+    pub fn find_or_fetch(
+        pool: &RequestConnectionPool,
+        url_fetcher: &UrlFetcher,
+        storage: &MediaStorage,
+        entropy: &Entropy,
+        domain: &str,
+        user_id: &UserId,
+        url: &str,
+        max_image_size: u64,
+        max_image_pixels: u64,
+        now_millis: i64,
+    ) -> Result<Value, ApiError> {
+        match Url::parse(url) {
+            Ok(ref parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => {}
+            _ => return Err(ApiError::invalid_param("url", "Must be an HTTP or HTTPS URL.")),
+        }
+
+        let cached = url_previews::table.find(url).get_result::<UrlPreview>(&*pool.get()?);
+
+        match cached {
+            Ok(ref preview) if preview.expires_at > now_millis => return Ok(from_str(&preview.og)?),
+            Ok(_) | Err(DieselError::NotFound) => {}
+            Err(error) => return Err(ApiError::from(error)),
+        }
+
+        let page = match url_fetcher.fetch(url, MAX_PAGE_SIZE)? {
+            Some(page) => page,
+            None => {
+                return Err(ApiError::not_found(
+                    Some(&format!("The page at {} could not be fetched.", url))
+                ));
+            }
+        };
+
+        let mut og = BTreeMap::new();
+        let page_content_type = page.content_type.clone().unwrap_or(String::new()).to_lowercase();
+
+        let image = if page_content_type.starts_with("image/") {
+            Some(page)
+        } else if page_content_type.starts_with("text/html") {
+            let html = String::from_utf8_lossy(&page.body);
+
+            for (property, content) in open_graph_properties(&html) {
+                og.insert(property, Value::String(content));
+            }
+
+            // Relative image URLs are relative to the page.
+            let image_url = og.remove("og:image")
+                .and_then(|image_url| image_url.as_str().map(str::to_string))
+                .and_then(|image_url| {
+                    Url::parse(&page.url).and_then(|base| base.join(&image_url)).ok()
+                });
+
+            match image_url {
+                // An image that can't be fetched only leaves the preview without one.
+                Some(image_url) => match url_fetcher.fetch(image_url.as_str(), max_image_size) {
+                    Ok(image) => image,
+                    Err(error) => {
+                        debug!("Failed to fetch the image {} for a preview: {}", image_url, error);
+
+                        None
+                    }
+                },
+                None => None,
+            }
+        } else {
+            None
+        };
+
+        let connection = pool.get()?;
+
+        if let Some(image) = image {
+            let content_type = image.content_type.unwrap_or(String::new());
+
+            // SVG images are refused here as they are for uploads, since they can run scripts.
+            if content_type.to_lowercase().starts_with("image/") &&
+                !content_type_is_svg(&content_type) {
+                match decode_image(&image.body, max_image_pixels) {
+                    Ok(decoded) => {
+                        let (width, height) = decoded.dimensions();
+
+                        og.insert("og:image:width".to_string(), Value::U64(width as u64));
+                        og.insert("og:image:height".to_string(), Value::U64(height as u64));
+                    }
+                    Err(error) => debug!("Failed to decode the image for {}: {}", url, error),
+                }
+
+                let media = Media::create(
+                    &connection,
+                    storage,
+                    entropy,
+                    user_id,
+                    content_type.clone(),
+                    None,
+                    &image.body,
+                    None,
+                )?;
+
+                og.insert("og:image".to_string(), Value::String(media.content_uri(domain)));
+                og.insert("og:image:type".to_string(), Value::String(content_type));
+                og.insert("matrix:image:size".to_string(), Value::U64(image.body.len() as u64));
+            }
+        }
+
+        let og = Value::Object(og);
+
+        let new_preview = UrlPreview {
+            url: url.to_string(),
+            og: to_string(&og)?,
+            expires_at: now_millis + URL_PREVIEW_TTL_MILLIS,
+        };
+
+        connection.transaction::<(), ApiError, _>(|| {
+            delete(url_previews::table.find(url)).execute(&*connection)?;
+
+            insert(&new_preview).into(url_previews::table).execute(&*connection)?;
+
+            Ok(())
+        }).map_err(ApiError::from)?;
+
+        Ok(og)
+    }
+}
+
+impl IpRange {
+    /// Parses an IP range in CIDR notation, or a single IP address, returning `None` if it isn't
+    /// valid.
+    pub fn parse(range: &str) -> Option<IpRange> {
+        let mut parts = range.trim().splitn(2, '/');
+
+        let address: IpAddr = match parts.next().and_then(|address| address.parse().ok()) {
+            Some(address) => address,
+            None => return None,
+        };
+
+        let max_prefix_length = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix_length = match parts.next() {
+            Some(prefix_length) => match prefix_length.parse::<u32>() {
+                Ok(prefix_length) if prefix_length <= max_prefix_length => prefix_length,
+                _ => return None,
+            },
+            None => max_prefix_length,
+        };
+
+        Some(IpRange {
+            address: address,
+            prefix_length: prefix_length,
+        })
+    }
+
+    /// Whether or not the address is in the range.
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.address, *address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                prefix_matches(&network.octets(), &address.octets(), self.prefix_length)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                prefix_matches(&network.octets(), &address.octets(), self.prefix_length)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl HttpUrlFetcher {
+    /// Creates a new `HttpUrlFetcher` that fetches with the given client, refusing hosts with
+    /// addresses in the given ranges.
+    pub fn new(http_client: Arc<HttpClient>, ip_range_blacklist: Vec<IpRange>) -> Self {
+        HttpUrlFetcher {
+            http_client: http_client,
+            ip_range_blacklist: ip_range_blacklist,
+        }
+    }
+}
+
+impl UrlFetcher for HttpUrlFetcher {
+    fn fetch(&self, url: &str, max_size: u64) -> Result<Option<FetchedUrl>, ApiError> {
+        let mut url = url.to_string();
+
+        let is_allowed = |address: &IpAddr| is_address_allowed(address, &self.ip_range_blacklist);
+
+        // Every redirect is checked too, so a public page can't redirect to a blacklisted one.
+        for _ in 0..MAX_REDIRECTS + 1 {
+            let parsed = Url::parse(&url).map_err(|_| {
+                ApiError::invalid_param("url", &format!("{} is not a valid URL.", url))
+            })?;
+
+            let download = self.http_client
+                .download(&url, max_size, &is_allowed)
+                .map_err(|error| match error {
+                    HttpClientError::Forbidden(_) => ApiError::unauthorized(
+                        Some(&format!("{} is on a network that can't be previewed.", url))
+                    ),
+                    HttpClientError::TooLarge(_) => {
+                        ApiError::too_large(Some(&format!("{} is too large to preview.", url)))
+                    }
+                    HttpClientError::Unresolvable(host) => {
+                        ApiError::not_found(Some(&format!("{} could not be resolved.", host)))
+                    }
+                    error => {
+                        ApiError::unknown(Some(&format!("Failed to fetch {}: {}", url, error)))
+                    }
+                })?;
+
+            if download.status.is_redirection() {
+                match download.location.and_then(|location| parsed.join(&location).ok()) {
+                    Some(location) => {
+                        url = location.into_string();
+
+                        continue;
+                    }
+                    None => return Ok(None),
+                }
+            }
+
+            if !download.status.is_success() {
+                return Ok(None);
+            }
+
+            return Ok(Some(FetchedUrl {
+                url: url,
+                content_type: download.content_type,
+                body: download.body,
+            }));
+        }
+
+        Err(ApiError::unknown(Some(&format!("{} redirected too many times.", url))))
+    }
+}
+
+impl FakeUrlFetcher {
+    /// Creates a new `FakeUrlFetcher` that serves nothing.
+    pub fn new() -> Self {
+        FakeUrlFetcher {
+            urls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Serves the content with the given MIME type at `url`, replacing what was served there.
+    pub fn serve(&self, url: &str, content_type: &str, body: &[u8]) {
+        let mut urls = self.urls.lock().expect("FakeUrlFetcher mutex was poisoned");
+
+        urls.insert(url.to_string(), (content_type.to_string(), body.to_vec()));
+    }
+}
+
+impl UrlFetcher for FakeUrlFetcher {
+    fn fetch(&self, url: &str, max_size: u64) -> Result<Option<FetchedUrl>, ApiError> {
+        let urls = self.urls.lock().expect("FakeUrlFetcher mutex was poisoned");
+
+        match urls.get(url) {
+            Some(&(_, ref body)) if body.len() as u64 > max_size => {
+                Err(ApiError::too_large(Some(&format!("{} is too large to preview.", url))))
+            }
+            Some(&(ref content_type, ref body)) => Ok(Some(FetchedUrl {
+                url: url.to_string(),
+                content_type: Some(content_type.clone()),
+                body: body.clone(),
+            })),
+            None => Ok(None),
+        }
+    }
+}
+
+impl ServerUrlFetcher {
+    /// Extract the `UrlFetcher` stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<Arc<UrlFetcher>>, ApiError> {
+        request.get::<Read<ServerUrlFetcher>>().map_err(ApiError::from)
+    }
+}
+
+impl Key for ServerUrlFetcher {
+    type Value = Arc<UrlFetcher>;
+}
+
+/// Whether or not an address is outside all of the blacklisted ranges.
+///
+/// IPv6 addresses that embed an IPv4 address are also checked as that IPv4 address.
+fn is_address_allowed(address: &IpAddr, ip_range_blacklist: &[IpRange]) -> bool {
+    let embedded = match *address {
+        IpAddr::V6(address) => address.to_ipv4().map(IpAddr::V4),
+        IpAddr::V4(_) => None,
+    };
+
+    !ip_range_blacklist.iter().any(|range| {
+        range.contains(address) || embedded.map_or(false, |embedded| range.contains(&embedded))
+    })
+}
+
+/// Whether or not the first `prefix_length` bits of two addresses are the same.
+fn prefix_matches(network: &[u8], address: &[u8], prefix_length: u32) -> bool {
+    let whole_bytes = (prefix_length / 8) as usize;
+    let remaining_bits = prefix_length % 8;
+
+    if network[..whole_bytes] != address[..whole_bytes] {
+        return false;
+    }
+
+    if remaining_bits == 0 {
+        return true;
+    }
+
+    let mask = 0xffu8 << (8 - remaining_bits);
+
+    network[whole_bytes] & mask == address[whole_bytes] & mask
+}
+
+/// Finds the OpenGraph properties in an HTML page's `<meta>` tags.
+///
+/// Pages without an `og:title` or `og:description` get them from their `<title>` and their
+/// *description* `<meta>` tag, if they have them. Only the first value of each property is kept.
+fn open_graph_properties(html: &str) -> BTreeMap<String, String> {
+    // ASCII lowercasing keeps byte offsets the same as in the original.
+    let lowercase = html.to_ascii_lowercase();
+    let mut properties = BTreeMap::new();
+    let mut description = None;
+    let mut position = 0;
+
+    while let Some(start) = lowercase[position..].find("<meta") {
+        let (attributes, end) = parse_attributes(html, position + start + "<meta".len());
+
+        position = end;
+
+        let content = match attributes.get("content") {
+            Some(content) => content.trim().to_string(),
+            None => continue,
+        };
+
+        match attributes.get("property").or(attributes.get("name")).map(String::as_str) {
+            Some(property) if property.starts_with("og:") => {
+                properties.entry(property.to_string()).or_insert(content);
+            }
+            Some("description") if description.is_none() => description = Some(content),
+            _ => {}
+        }
+    }
+
+    if !properties.contains_key("og:title") {
+        if let Some(title) = title(html, &lowercase) {
+            properties.insert("og:title".to_string(), title);
+        }
+    }
+
+    if !properties.contains_key("og:description") {
+        if let Some(description) = description {
+            properties.insert("og:description".to_string(), description);
+        }
+    }
+
+    properties
+}
+
+/// Parses the attributes of the tag whose name ends at byte `start` of `html`, returning them by
+/// lowercase name along with the byte offset just past the end of the tag.
+fn parse_attributes(html: &str, start: usize) -> (HashMap<String, String>, usize) {
+    let bytes = html.as_bytes();
+    let mut attributes = HashMap::new();
+    let mut position = start;
+
+    // Only ever stopping at ASCII characters keeps `position` on character boundaries.
+    let skip = |position: &mut usize, skip_byte: &Fn(u8) -> bool| {
+        while *position < bytes.len() && skip_byte(bytes[*position]) {
+            *position += 1;
+        }
+    };
+
+    loop {
+        skip(&mut position, &|byte| is_space(byte) || byte == b'/');
+
+        if position >= bytes.len() {
+            return (attributes, position);
+        }
+
+        if bytes[position] == b'>' {
+            return (attributes, position + 1);
+        }
+
+        let name_start = position;
+
+        skip(&mut position, &|byte| {
+            !is_space(byte) && byte != b'=' && byte != b'>' && byte != b'/'
+        });
+
+        let name = html[name_start..position].to_ascii_lowercase();
+        let mut value = String::new();
+
+        skip(&mut position, &is_space);
+
+        if position < bytes.len() && bytes[position] == b'=' {
+            position += 1;
+
+            skip(&mut position, &is_space);
+
+            if position < bytes.len() && (bytes[position] == b'"' || bytes[position] == b'\'') {
+                let quote = bytes[position];
+                let value_start = position + 1;
+
+                position = value_start;
+
+                skip(&mut position, &|byte| byte != quote);
+
+                value = decode_entities(&html[value_start..position]);
+
+                // A page can end inside a value, leaving no closing quote to step past.
+                if position < bytes.len() {
+                    position += 1;
+                }
+            } else {
+                let value_start = position;
+
+                skip(&mut position, &|byte| !is_space(byte) && byte != b'>');
+
+                value = decode_entities(&html[value_start..position]);
+            }
+        }
+
+        attributes.entry(name).or_insert(value);
+    }
+}
+
+/// Finds the text of an HTML page's `<title>`, given the page and its ASCII lowercase copy.
+fn title(html: &str, lowercase: &str) -> Option<String> {
+    let start = match lowercase.find("<title") {
+        Some(start) => start,
+        None => return None,
+    };
+
+    let start = match lowercase[start..].find('>') {
+        Some(end) => start + end + 1,
+        None => return None,
+    };
+
+    let end = match lowercase[start..].find("</title") {
+        Some(end) => start + end,
+        None => return None,
+    };
+
+    let title = decode_entities(&html[start..end])
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ");
+
+    if title.is_empty() {
+        None
+    } else {
+        Some(title)
+    }
+}
+
+/// Replaces HTML character references, such as `&amp;` and `&#39;`, with the characters they
+/// stand for. Named references other than the few common ones are left as they are.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let reference = rest.find(';').map(|end| (&rest[1..end], end));
+
+        let character = reference.and_then(|(name, _)| match name {
+            "amp" => Some('&'),
+            "apos" => Some('\''),
+            "gt" => Some('>'),
+            "lt" => Some('<'),
+            "nbsp" => Some('\u{a0}'),
+            "quot" => Some('"'),
+            _ if name.starts_with("#x") || name.starts_with("#X") => {
+                u32::from_str_radix(&name[2..], 16).ok().and_then(::std::char::from_u32)
+            }
+            _ if name.starts_with('#') => {
+                name[1..].parse::<u32>().ok().and_then(::std::char::from_u32)
+            }
+            _ => None,
+        });
+
+        match (character, reference) {
+            (Some(character), Some((_, end))) => {
+                decoded.push(character);
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    decoded.push_str(rest);
+
+    decoded
+}
+
+/// Whether or not the byte is HTML whitespace.
+fn is_space(byte: u8) -> bool {
+    byte == b' ' || byte == b'\t' || byte == b'\n' || byte == b'\r' || byte == 0x0c
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{IpRange, decode_entities, is_address_allowed, open_graph_properties};
+
+    fn blacklist() -> Vec<IpRange> {
+        ["10.0.0.0/8", "127.0.0.0/8", "::1/128"]
+            .iter()
+            .map(|range| IpRange::parse(range).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn ip_ranges() {
+        let range = IpRange::parse("172.16.0.0/12").unwrap();
+
+        assert!(range.contains(&"172.16.0.1".parse::<IpAddr>().unwrap()));
+        assert!(range.contains(&"172.31.255.255".parse::<IpAddr>().unwrap()));
+        assert!(!range.contains(&"172.32.0.1".parse::<IpAddr>().unwrap()));
+        assert!(!range.contains(&"::1".parse::<IpAddr>().unwrap()));
+
+        let range = IpRange::parse("fe80::/10").unwrap();
+
+        assert!(range.contains(&"fe80::1".parse::<IpAddr>().unwrap()));
+        assert!(range.contains(&"febf::1".parse::<IpAddr>().unwrap()));
+        assert!(!range.contains(&"fec0::1".parse::<IpAddr>().unwrap()));
+
+        assert_eq!(IpRange::parse("10.0.0.0/33"), None);
+        assert_eq!(IpRange::parse("example.com/8"), None);
+        assert!(IpRange::parse("10.0.0.1").unwrap().contains(&"10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn blacklisted_addresses_are_refused() {
+        let blacklist = blacklist();
+
+        for address in &["127.0.0.1", "10.1.2.3", "::1"] {
+            assert!(!is_address_allowed(&address.parse().unwrap(), &blacklist));
+        }
+
+        // IPv4 addresses can't sneak past the blacklist inside IPv6 addresses.
+        assert!(!is_address_allowed(&"::ffff:10.0.0.1".parse().unwrap(), &blacklist));
+
+        assert!(is_address_allowed(&"93.184.216.34".parse().unwrap(), &blacklist));
+    }
+
+    #[test]
+    fn open_graph_properties_come_from_meta_tags() {
+        let html = r#"<!DOCTYPE html>
+            <html>
+              <head>
+                <title>Ignored</title>
+                <META property="og:title" content="Ruma &amp; Matrix">
+                <meta content='A Matrix homeserver.' property=og:description />
+                <meta property="og:title" content="Also ignored">
+                <meta property="og:image" content="/logo.png">
+                <meta name="twitter:card" content="summary">
+              </head>
+            </html>"#;
+
+        let properties = open_graph_properties(html);
+
+        assert_eq!(properties.len(), 3);
+        assert_eq!(properties["og:title"], "Ruma & Matrix");
+        assert_eq!(properties["og:description"], "A Matrix homeserver.");
+        assert_eq!(properties["og:image"], "/logo.png");
+    }
+
+    #[test]
+    fn title_and_description_fall_back_to_html() {
+        let html = r#"<html><head>
+            <title>
+              Ruma &#8211; a
+              homeserver
+            </title>
+            <meta name="description" content="Written in Rust.">
+            </head></html>"#;
+
+        let properties = open_graph_properties(html);
+
+        assert_eq!(properties["og:title"], "Ruma \u{2013} a homeserver");
+        assert_eq!(properties["og:description"], "Written in Rust.");
+    }
+
+    #[test]
+    fn truncated_meta_tags() {
+        // Pages can end anywhere inside a tag.
+        for html in &[
+            r#"<meta property="og:title" content="abc"#,
+            r#"<meta property="og:title" content='abc"#,
+            r#"<meta property="og:title" content="#,
+            "<meta property",
+            "<meta",
+        ] {
+            open_graph_properties(html);
+        }
+
+        let properties = open_graph_properties(r#"<meta property="og:title" content="abc"#);
+
+        assert_eq!(properties["og:title"], "abc");
+    }
+
+    #[test]
+    fn entities() {
+        assert_eq!(decode_entities("a &lt;b&gt; &#x27;c&#39;"), "a <b> 'c'");
+        assert_eq!(decode_entities("fish & chips; &copy; &"), "fish & chips; &copy; &");
+    }
+}
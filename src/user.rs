@@ -2,11 +2,13 @@
 
 use diesel::{
     Connection,
+    ExecuteDsl,
     ExpressionMethods,
     FilterDsl,
+    FindDsl,
     LoadDsl,
-    SaveChangesDsl,
     insert,
+    update,
 };
 use diesel::pg::PgConnection;
 use diesel::pg::data_types::PgTimestamp;
@@ -15,7 +17,9 @@ use iron::typemap::Key;
 use ruma_identifiers::UserId;
 
 use access_token::AccessToken;
-use crypto::verify_password;
+use clock::Clock;
+use crypto::{Argon2Params, Entropy, hash_password, needs_rehash, verify_password};
+use device::{Device, NewDevice};
 use error::ApiError;
 use schema::users;
 
@@ -29,6 +33,14 @@ pub struct User {
     pub password_hash: String,
     /// Whether or not the user has the ability to login.
     pub active: bool,
+    /// Whether or not the user has been suspended by an admin.
+    ///
+    /// Unlike deactivation, locking is temporary: the account is kept intact and can be unlocked.
+    pub locked: bool,
+    /// Whether or not the user registered as a guest, without a password.
+    ///
+    /// Guests can only join rooms that allow guest access, and can't create or administer rooms.
+    pub is_guest: bool,
     /// The time the user was created.
     pub created_at: PgTimestamp,
     /// The time the user was last modified.
@@ -43,14 +55,19 @@ pub struct NewUser {
     pub id: UserId,
     /// The user's hashed password.
     pub password_hash: String,
+    /// Whether or not the user is a guest.
+    pub is_guest: bool,
 }
 
 impl User {
-    /// Creates a new user in the database.
+    /// Creates a new user in the database, along with the device they registered from.
     pub fn create(
         connection: &PgConnection,
         new_user: &NewUser,
+        new_device: &NewDevice,
         macaroon_secret_key: &Vec<u8>,
+        clock: &Clock,
+        entropy: &Entropy,
     ) -> Result<(User, AccessToken), ApiError> {
         let partial_key: String = macaroon_secret_key
             .clone()
@@ -71,7 +88,16 @@ impl User {
                 .get_result(connection)
                 .map_err(ApiError::from)?;
 
-            let access_token = AccessToken::create(connection, &user.id, macaroon_secret_key)?;
+            let device = Device::create(connection, new_device)?;
+
+            let access_token = AccessToken::create(
+                connection,
+                &user.id,
+                &device.device_id,
+                macaroon_secret_key,
+                clock,
+                entropy,
+            )?;
 
             Ok((user, access_token))
         }).map_err(ApiError::from)
@@ -89,15 +115,49 @@ impl User {
     }
 
     /// Verify that a `User` with the given `UserId` and plaintext password exists.
+    ///
+    /// Guests have no password, so they can never be verified.
+    /// If the stored hash was made with another algorithm or other Argon2 parameters, it is
+    /// replaced with one made with `argon2_params`, unless the password was changed in the
+    /// meantime.
     pub fn verify(
         connection: &PgConnection,
         id: &UserId,
         plaintext_password: &str,
+        argon2_params: &Argon2Params,
     ) -> Result<User, ApiError> {
-        let user = User::find_by_uid(connection, id)?;
+        let mut user = User::find_by_uid(connection, id)?;
+
+        if user.is_guest {
+            debug!("Refusing to verify guest {}", id);
+            return Err(ApiError::unauthorized(None));
+        }
 
-        if verify_password(user.password_hash.as_bytes(), plaintext_password)? {
+        if verify_password(&user.password_hash, plaintext_password)? {
             debug!("Successfully verified user {}", id);
+
+            if needs_rehash(argon2_params, &user.password_hash) {
+                info!("Rehashing the password of {}", id);
+
+                let password_hash = hash_password(argon2_params, plaintext_password)?;
+
+                // Only the hash that was verified is replaced, so a password changed by another
+                // request since it was read isn't overwritten with the old one.
+                let verified_user = users::table
+                    .filter(users::id.eq(id))
+                    .filter(users::password_hash.eq(&user.password_hash));
+                let updated = update(verified_user)
+                    .set(users::password_hash.eq(&password_hash))
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                if updated == 1 {
+                    user.password_hash = password_hash;
+                } else {
+                    debug!("The password of {} changed before it could be rehashed", id);
+                }
+            }
+
             Ok(user)
         } else {
             debug!("Failed to verify user {}", id);
@@ -126,12 +186,29 @@ impl User {
     pub fn deactivate(&mut self, connection: &PgConnection) -> Result<(), ApiError> {
         // don't actually remove the user though; keep details in the system (spec?)
         info!("Attempting to revoke login priviledges for {}", self.id);
+
+        update(users::table.find(self.id.clone()))
+            .set(users::active.eq(false))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
         self.active = false;
 
-        match self.save_changes::<User>(connection) {
-            Ok(_) => Ok(()),
-            Err(error) => Err(ApiError::from(error)),
-        }
+        Ok(())
+    }
+
+    /// Lock or unlock the user's account.
+    pub fn set_locked(&mut self, connection: &PgConnection, locked: bool) -> Result<(), ApiError> {
+        info!("Setting locked to {} for {}", locked, self.id);
+
+        update(users::table.find(self.id.clone()))
+            .set(users::locked.eq(locked))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        self.locked = locked;
+
+        Ok(())
     }
 }
 
@@ -0,0 +1,133 @@
+//! Searching for users by user ID and display name.
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use diesel::{ExpressionMethods, FilterDsl, LoadDsl, SelectDsl};
+use diesel::pg::PgConnection;
+use diesel::pg::expression::dsl::any;
+use diesel::types::{Bool, Float, Nullable, Text};
+use ruma_identifiers::UserId;
+
+use error::ApiError;
+use profile::Profile;
+use schema::{profiles, room_memberships, rooms, users};
+
+sql_function!(
+    user_directory_matches,
+    user_directory_matches_t,
+    (value: Nullable<Text>, search_term: Text) -> Bool
+);
+sql_function!(
+    user_directory_rank,
+    user_directory_rank_t,
+    (value: Nullable<Text>, search_term: Text) -> Float
+);
+
+/// A user as they appear in user directory search results.
+#[derive(Clone, Debug, Serialize)]
+pub struct UserDirectoryEntry {
+    /// The URL of the user's avatar.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    /// The user's display name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    /// The user's ID.
+    pub user_id: String,
+}
+
+impl UserDirectoryEntry {
+    /// Returns up to `limit` users whose ID or display name contains or resembles the search
+    /// term, best matches first, and whether or not there were more.
+    ///
+    /// Only active users who share a room with the searcher, or who are in a public room, can be
+    /// found.
+    pub fn search(connection: &PgConnection, searcher: &UserId, search_term: &str, limit: usize)
+    -> Result<(Vec<UserDirectoryEntry>, bool), ApiError> {
+        let user_ids = visible_user_ids(connection, searcher)?;
+
+        let id_matches: Vec<(UserId, f32)> = users::table
+            .filter(users::id.eq(any(user_ids.clone())))
+            .filter(user_directory_matches(users::id.nullable(), search_term))
+            .select((users::id, user_directory_rank(users::id.nullable(), search_term)))
+            .load(connection)?;
+
+        let display_name_matches: Vec<(UserId, f32)> = profiles::table
+            .filter(profiles::id.eq(any(user_ids)))
+            .filter(user_directory_matches(profiles::displayname, search_term))
+            .select((profiles::id, user_directory_rank(profiles::displayname, search_term)))
+            .load(connection)?;
+
+        let mut ranks: HashMap<UserId, f32> = HashMap::new();
+
+        for (user_id, rank) in id_matches.into_iter().chain(display_name_matches) {
+            let best_rank = ranks.entry(user_id).or_insert(rank);
+
+            if rank > *best_rank {
+                *best_rank = rank;
+            }
+        }
+
+        let mut matches: Vec<(UserId, f32)> = ranks.into_iter().collect();
+
+        // Users with the same rank are ordered by ID so results are stable.
+        matches.sort_by(|a, b| a.0.to_string().cmp(&b.0.to_string()));
+        matches.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+
+        let limited = matches.len() > limit;
+
+        matches.truncate(limit);
+
+        let user_ids: Vec<UserId> = matches.into_iter().map(|(user_id, _)| user_id).collect();
+        let profiles = Profile::find_by_uids(connection, &user_ids)?;
+
+        let entries = user_ids
+            .into_iter()
+            .map(|user_id| {
+                let profile = profiles.iter().find(|profile| profile.id == user_id);
+
+                UserDirectoryEntry {
+                    avatar_url: profile.and_then(|profile| profile.avatar_url.clone()),
+                    display_name: profile.and_then(|profile| profile.displayname.clone()),
+                    user_id: user_id.to_string(),
+                }
+            })
+            .collect();
+
+        Ok((entries, limited))
+    }
+}
+
+/// Returns the IDs of the active users who are joined to a room with the searcher or to a public
+/// room.
+fn visible_user_ids(connection: &PgConnection, searcher: &UserId)
+-> Result<Vec<String>, ApiError> {
+    let mut room_ids: Vec<String> = room_memberships::table
+        .filter(room_memberships::user_id.eq(searcher))
+        .filter(room_memberships::membership.eq("join"))
+        .select(room_memberships::room_id)
+        .load(connection)?;
+
+    let public_room_ids: Vec<String> = rooms::table
+        .filter(rooms::public.eq(true))
+        .select(rooms::id)
+        .load(connection)?;
+
+    room_ids.extend(public_room_ids);
+
+    let member_ids: HashSet<String> = room_memberships::table
+        .filter(room_memberships::room_id.eq(any(room_ids)))
+        .filter(room_memberships::membership.eq("join"))
+        .select(room_memberships::user_id)
+        .load::<String>(connection)?
+        .into_iter()
+        .collect();
+
+    users::table
+        .filter(users::id.eq(any(member_ids.into_iter().collect::<Vec<String>>())))
+        .filter(users::active.eq(true))
+        .select(users::id)
+        .load(connection)
+        .map_err(ApiError::from)
+}
@@ -0,0 +1,128 @@
+//! Third-party identifiers, such as email addresses, bound to users.
+
+use diesel::{
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    LoadDsl,
+    OrderDsl,
+    SelectDsl,
+    delete,
+    insert,
+};
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::UserId;
+
+use error::ApiError;
+use event::POSTGRES_EPOCH_MILLIS;
+use schema::user_threepids;
+
+/// A third-party identifier bound to a user, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "user_threepids"]
+pub struct NewUserThreepid {
+    /// The user the identifier belongs to.
+    pub user_id: UserId,
+    /// The kind of identifier, e.g. *email*.
+    pub medium: String,
+    /// The identifier itself, e.g. an email address.
+    pub address: String,
+}
+
+/// A third-party identifier bound to a user.
+#[derive(Debug, Queryable)]
+pub struct UserThreepid {
+    /// The identifier's ID in the database.
+    pub id: i64,
+    /// The user the identifier belongs to.
+    pub user_id: UserId,
+    /// The kind of identifier, e.g. *email*.
+    pub medium: String,
+    /// The identifier itself, e.g. an email address.
+    pub address: String,
+    /// The time the identifier was bound.
+    pub created_at: PgTimestamp,
+}
+
+impl UserThreepid {
+    /// Binds a third-party identifier to a user.
+    ///
+    /// Email addresses are stored in lowercase and phone numbers as digits only, so they match
+    /// regardless of how they are typed.
+    pub fn create(connection: &PgConnection, mut new_user_threepid: NewUserThreepid)
+    -> Result<UserThreepid, ApiError> {
+        new_user_threepid.address = normalize_address(
+            &new_user_threepid.medium,
+            &new_user_threepid.address,
+        );
+
+        let medium = &new_user_threepid.medium;
+        let address = &new_user_threepid.address;
+
+        if UserThreepid::find_user_id(connection, medium, address)?.is_some() {
+            return Err(ApiError::invalid_param("address", "The address is already in use."));
+        }
+
+        insert(&new_user_threepid)
+            .into(user_threepids::table)
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Looks up the user a third-party identifier is bound to.
+    pub fn find_user_id(connection: &PgConnection, medium: &str, address: &str)
+    -> Result<Option<UserId>, ApiError> {
+        let user_id = user_threepids::table
+            .select(user_threepids::user_id)
+            .filter(user_threepids::medium.eq(medium))
+            .filter(user_threepids::address.eq(normalize_address(medium, address)))
+            .first(connection);
+
+        match user_id {
+            Ok(user_id) => Ok(Some(user_id)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(error) => Err(ApiError::from(error)),
+        }
+    }
+
+    /// Returns the third-party identifiers bound to a user, oldest first.
+    pub fn find_by_user(connection: &PgConnection, user_id: &UserId)
+    -> Result<Vec<UserThreepid>, ApiError> {
+        user_threepids::table
+            .filter(user_threepids::user_id.eq(user_id))
+            .order(user_threepids::id.asc())
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Unbinds a third-party identifier from a user, returning whether or not it was bound to
+    /// them.
+    pub fn delete(connection: &PgConnection, user_id: &UserId, medium: &str, address: &str)
+    -> Result<bool, ApiError> {
+        let user_threepids = user_threepids::table
+            .filter(user_threepids::user_id.eq(user_id))
+            .filter(user_threepids::medium.eq(medium))
+            .filter(user_threepids::address.eq(normalize_address(medium, address)));
+
+        delete(user_threepids)
+            .execute(connection)
+            .map(|deleted| deleted > 0)
+            .map_err(ApiError::from)
+    }
+
+    /// The time the identifier was bound, in milliseconds since the Unix epoch.
+    pub fn created_at_millis(&self) -> i64 {
+        self.created_at.0 / 1000 + POSTGRES_EPOCH_MILLIS
+    }
+}
+
+/// Normalizes an address so the same identifier is always stored and looked up the same way.
+fn normalize_address(medium: &str, address: &str) -> String {
+    match medium {
+        "email" => address.trim().to_lowercase(),
+        "msisdn" => address.chars().filter(|c| c.is_digit(10)).collect(),
+        _ => address.trim().to_string(),
+    }
+}
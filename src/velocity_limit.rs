@@ -0,0 +1,203 @@
+//! Limits on how quickly users can invite people and create rooms, to stop spam campaigns.
+
+use diesel::{
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LoadDsl,
+    SelectDsl,
+    delete,
+    insert,
+};
+use diesel::expression::dsl::count_star;
+use diesel::pg::PgConnection;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::UserId;
+
+use config::Config;
+use error::ApiError;
+use schema::{user_velocity_limits, velocity_actions};
+
+/// Milliseconds in an hour.
+const HOUR_MILLIS: i64 = 60 * 60 * 1000;
+
+/// Milliseconds in a day.
+const DAY_MILLIS: i64 = 24 * HOUR_MILLIS;
+
+/// An action whose rate is limited.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VelocityAction {
+    /// Creating a room.
+    CreateRoom,
+    /// Inviting a user to a room.
+    Invite,
+}
+
+/// The limits that apply to a user.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VelocityLimits {
+    /// The most invites the user may send in an hour, or `None` for no limit.
+    pub invites_per_hour: Option<u64>,
+    /// The most rooms the user may create in a day, or `None` for no limit.
+    pub rooms_created_per_day: Option<u64>,
+}
+
+/// Limits an admin set for a user in place of the server's.
+#[derive(Debug, Insertable, Queryable)]
+#[table_name = "user_velocity_limits"]
+pub struct UserVelocityLimits {
+    /// The user the limits apply to.
+    pub user_id: UserId,
+    /// The most invites the user may send in an hour, or `None` for no limit.
+    pub invites_per_hour: Option<i64>,
+    /// The most rooms the user may create in a day, or `None` for no limit.
+    pub rooms_created_per_day: Option<i64>,
+}
+
+/// An action a user took, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "velocity_actions"]
+struct NewVelocityAction {
+    user_id: UserId,
+    action: String,
+    occurred_at: i64,
+}
+
+impl VelocityAction {
+    /// The name the action is stored under.
+    fn as_str(&self) -> &'static str {
+        match *self {
+            VelocityAction::CreateRoom => "create_room",
+            VelocityAction::Invite => "invite",
+        }
+    }
+
+    /// How far back, in milliseconds, actions count towards the limit.
+    fn window_millis(&self) -> i64 {
+        match *self {
+            VelocityAction::CreateRoom => DAY_MILLIS,
+            VelocityAction::Invite => HOUR_MILLIS,
+        }
+    }
+}
+
+impl VelocityLimits {
+    /// Returns the limits that apply to the user: the ones an admin set for them, if any, and
+    /// otherwise the server's.
+    pub fn for_user(connection: &PgConnection, config: &Config, user_id: &UserId)
+    -> Result<VelocityLimits, ApiError> {
+        match UserVelocityLimits::find(connection, user_id)? {
+            Some(user_limits) => Ok(VelocityLimits {
+                invites_per_hour: user_limits.invites_per_hour.map(|limit| limit as u64),
+                rooms_created_per_day: user_limits.rooms_created_per_day.map(|limit| limit as u64),
+            }),
+            None => Ok(VelocityLimits {
+                invites_per_hour: config.invites_per_hour,
+                rooms_created_per_day: config.rooms_created_per_day,
+            }),
+        }
+    }
+
+    /// Records that the user is taking the action `count` times at the time `now`, or fails with
+    /// `M_LIMIT_EXCEEDED` if that would put them over their limit. Admins are never limited.
+    pub fn check_and_record(
+        connection: &PgConnection,
+        config: &Config,
+        user_id: &UserId,
+        action: VelocityAction,
+        count: u64,
+        now: i64,
+    ) -> Result<(), ApiError> {
+        if count == 0 || config.admins.contains(user_id) {
+            return Ok(());
+        }
+
+        let limits = VelocityLimits::for_user(connection, config, user_id)?;
+
+        let limit = match action {
+            VelocityAction::CreateRoom => limits.rooms_created_per_day,
+            VelocityAction::Invite => limits.invites_per_hour,
+        };
+
+        connection.transaction::<(), ApiError, _>(|| {
+            let window_start = now - action.window_millis();
+
+            // Actions outside the window no longer count for anything.
+            let expired = velocity_actions::table
+                .filter(velocity_actions::user_id.eq(user_id))
+                .filter(velocity_actions::action.eq(action.as_str()))
+                .filter(velocity_actions::occurred_at.le(window_start));
+
+            delete(expired).execute(connection)?;
+
+            if let Some(limit) = limit {
+                let recent: i64 = velocity_actions::table
+                    .filter(velocity_actions::user_id.eq(user_id))
+                    .filter(velocity_actions::action.eq(action.as_str()))
+                    .select(count_star())
+                    .first(connection)?;
+
+                if recent as u64 + count > limit {
+                    let message = match action {
+                        VelocityAction::CreateRoom => "Too many rooms created today.",
+                        VelocityAction::Invite => "Too many invites sent in the last hour.",
+                    };
+
+                    return Err(ApiError::limited_rate(Some(message)));
+                }
+            }
+
+            for _ in 0..count {
+                let new_action = NewVelocityAction {
+                    user_id: user_id.clone(),
+                    action: action.as_str().to_string(),
+                    occurred_at: now,
+                };
+
+                insert(&new_action).into(velocity_actions::table).execute(connection)?;
+            }
+
+            Ok(())
+        }).map_err(ApiError::from)
+    }
+}
+
+impl UserVelocityLimits {
+    /// Returns the limits an admin set for the user, if any.
+    pub fn find(connection: &PgConnection, user_id: &UserId)
+    -> Result<Option<UserVelocityLimits>, ApiError> {
+        match user_velocity_limits::table.find(user_id).first(connection) {
+            Ok(user_limits) => Ok(Some(user_limits)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(error) => Err(ApiError::from(error)),
+        }
+    }
+
+    /// Sets the user's limits, replacing any set before.
+    pub fn set(connection: &PgConnection, user_id: &UserId, limits: &VelocityLimits)
+    -> Result<(), ApiError> {
+        let user_limits = UserVelocityLimits {
+            user_id: user_id.clone(),
+            invites_per_hour: limits.invites_per_hour.map(|limit| limit as i64),
+            rooms_created_per_day: limits.rooms_created_per_day.map(|limit| limit as i64),
+        };
+
+        connection.transaction::<(), ApiError, _>(|| {
+            UserVelocityLimits::remove(connection, user_id)?;
+
+            insert(&user_limits).into(user_velocity_limits::table).execute(connection)?;
+
+            Ok(())
+        }).map_err(ApiError::from)
+    }
+
+    /// Removes the user's limits, so the server's apply again.
+    pub fn remove(connection: &PgConnection, user_id: &UserId) -> Result<(), ApiError> {
+        delete(user_velocity_limits::table.find(user_id))
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+}